@@ -1,9 +1,13 @@
+use bytes::BytesMut;
 use clap::ArgMatches;
+use interledger_packet::Packet;
+use interledger_stream::StreamPacket;
 use reqwest::{
     self,
     blocking::{Client, Response},
 };
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use tungstenite::{connect, handshake::client::Request};
 use url::Url;
 
@@ -15,6 +19,8 @@ pub enum Error {
     UsageErr(&'static str),
     #[error("Invalid protocol in URL: {0}")]
     ProtocolErr(String),
+    #[error("Error decoding packet: {0}")]
+    DecodeErr(String),
     // Foreign errors
     #[error("Error sending HTTP request: {0}")]
     SendErr(#[from] reqwest::Error),
@@ -55,6 +61,8 @@ pub fn run(matches: &ArgMatches) -> Result<Response, Error> {
         ("rates", Some(rates_matches)) => match rates_matches.subcommand() {
             ("list", Some(submatches)) => client.get_rates(submatches),
             ("set-all", Some(submatches)) => client.put_rates(submatches),
+            ("list-overrides", Some(submatches)) => client.get_rate_overrides(submatches),
+            ("set-overrides", Some(submatches)) => client.put_rate_overrides(submatches),
             _ => Err(Error::UsageErr("ilp-cli help rates")),
         },
         ("routes", Some(routes_matches)) => match routes_matches.subcommand() {
@@ -77,6 +85,10 @@ pub fn run(matches: &ArgMatches) -> Result<Response, Error> {
             ("incoming", Some(submatches)) => client.ws_payments_incoming(submatches),
             _ => Err(Error::UsageErr("ilp-cli help payments")),
         },
+        ("packets", Some(packets_matches)) => match packets_matches.subcommand() {
+            ("decode", Some(submatches)) => decode_packet(submatches),
+            _ => Err(Error::UsageErr("ilp-cli help packets")),
+        },
         _ => Err(Error::UsageErr("ilp-cli help")),
     }
 }
@@ -252,6 +264,25 @@ impl NodeClient<'_> {
             .map_err(Error::SendErr)
     }
 
+    // GET /rates/overrides
+    fn get_rate_overrides(&self, _matches: &ArgMatches) -> Result<Response, Error> {
+        self.client
+            .get(&format!("{}/rates/overrides", self.url))
+            .send()
+            .map_err(Error::SendErr)
+    }
+
+    // PUT /rates/overrides
+    fn put_rate_overrides(&self, matches: &ArgMatches) -> Result<Response, Error> {
+        let (auth, rate_pairs) = unflatten_pairs(matches);
+        self.client
+            .put(&format!("{}/rates/overrides", self.url))
+            .bearer_auth(auth)
+            .json(&rate_pairs)
+            .send()
+            .map_err(Error::SendErr)
+    }
+
     // GET /routes
     fn get_routes(&self, _matches: &ArgMatches) -> Result<Response, Error> {
         self.client
@@ -365,6 +396,61 @@ impl NodeClient<'_> {
     }
 }
 
+// Decodes the packet locally, without contacting the node, and answers with its fields
+fn decode_packet(matches: &ArgMatches) -> Result<Response, Error> {
+    let packet = decode_bytes(matches.value_of("packet").unwrap())?; // infallible unwrap
+    let shared_secret = matches
+        .value_of("shared_secret")
+        .map(decode_bytes)
+        .transpose()?;
+
+    let output = if matches.is_present("stream") {
+        format_stream_packet(packet, shared_secret.as_deref())
+            .map_err(|err| Error::DecodeErr(err.to_string()))?
+    } else {
+        let packet = Packet::try_from(BytesMut::from(&packet[..]))
+            .map_err(|err| Error::DecodeErr(err.to_string()))?;
+        let (mut output, data) = match &packet {
+            Packet::Prepare(prepare) => (format!("{:#?}", prepare), prepare.data()),
+            Packet::Fulfill(fulfill) => (format!("{:#?}", fulfill), fulfill.data()),
+            Packet::Reject(reject) => (format!("{:#?}", reject), reject.data()),
+        };
+        // STREAM packets can only be found in the data with the shared secret
+        if let Some(shared_secret) = shared_secret.as_deref().filter(|_| !data.is_empty()) {
+            output.push('\n');
+            match format_stream_packet(data.to_vec(), Some(shared_secret)) {
+                Ok(stream_packet) => output.push_str(&stream_packet),
+                Err(err) => output.push_str(&format!("Data is not a STREAM packet: {}", err)),
+            }
+        }
+        output
+    };
+
+    Ok(Response::from(
+        http::Response::builder().body(output).unwrap(), // infallible unwrap
+    ))
+}
+
+fn format_stream_packet(
+    packet: Vec<u8>,
+    shared_secret: Option<&[u8]>,
+) -> Result<String, interledger_stream::StreamPacketError> {
+    let packet = BytesMut::from(&packet[..]);
+    let packet = match shared_secret {
+        Some(shared_secret) => StreamPacket::from_encrypted(shared_secret, packet)?,
+        None => StreamPacket::from_decrypted(packet)?,
+    };
+    Ok(packet.to_string())
+}
+
+// Packets and shared secrets are usually logged as hex, but base64 is accepted too
+fn decode_bytes(encoded: &str) -> Result<Vec<u8>, Error> {
+    hex::decode(encoded)
+        .or_else(|_| base64::decode(encoded))
+        .or_else(|_| base64::decode_config(encoded, base64::URL_SAFE_NO_PAD))
+        .map_err(|_| Error::DecodeErr(format!("{} is neither hex nor base64", encoded)))
+}
+
 // This function takes the map of arguments parsed by Clap
 // and extracts the values for each argument.
 fn extract_args<'a>(matches: &'a ArgMatches) -> (&'a str, HashMap<&'a str, &'a str>) {
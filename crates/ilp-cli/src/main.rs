@@ -75,7 +75,7 @@ mod interface_tests {
     #[test]
     fn accounts_create() {
         should_parse(&[
-            "ilp-cli accounts create alice --auth foo --asset-code XYZ --asset-scale 6 --ilp-address bar --max-packet-amount 100 --min-balance 0 --ilp-over-http-url qux --ilp-over-http-incoming-token baz --ilp-over-http-outgoing-token qaz --ilp-over-btp-url spam --ilp-over-btp-outgoing-token ham --ilp-over-btp-incoming-token eggs --settle-threshold 0 --settle-to 0 --routing-relation foobar --round-trip-time 1000 --amount-per-minute-limit 42 --packets-per-minute-limit 4 --settlement-engine-url if_you_can_read_this_congratulations_youve_scrolled_too_far_right", // maximal
+            "ilp-cli accounts create alice --auth foo --asset-code XYZ --asset-scale 6 --ilp-address bar --max-packet-amount 100 --min-balance 0 --ilp-over-http-url qux --ilp-over-http-incoming-token baz --ilp-over-http-outgoing-token qaz --ilp-over-btp-url spam --ilp-over-btp-outgoing-token ham --ilp-over-btp-incoming-token eggs --settle-threshold 0 --settle-to 0 --routing-relation foobar --round-trip-time 1000 --amount-per-minute-limit 42 --packets-per-minute-limit 4 --settlement-engine-url if_you_can_read_this_congratulations_youve_scrolled_too_far_right --spread 0.01 --ilp-over-http-fallback-url quux --ilp-over-http-alternate-urls corge,grault --translate-prefix-from garply --translate-prefix-to waldo --packets-burst-limit 8 --amount-burst-limit 84 --accept-route-prefixes example.,test. --reject-route-prefixes example.bad --max-routes 1000 --aggregate-route-prefixes example.peer", // maximal
             "ilp-cli accounts create alice --auth foo --asset-code ABC --asset-scale 3 --min-balance -1000 --settle-threshold -10", // negative numbers
         ]);
     }
@@ -84,7 +84,7 @@ mod interface_tests {
     fn accounts_update() {
         should_parse(&[
             "ilp-cli accounts update alice --auth foo --asset-code ABC --asset-scale 9", // minimal
-            "ilp-cli accounts update alice --auth foo --asset-code XYZ --asset-scale 6 --ilp-address bar --max-packet-amount 100 --min-balance 0 --ilp-over-http-url qux --ilp-over-http-incoming-token baz --ilp-over-http-outgoing-token qaz --ilp-over-btp-url spam --ilp-over-btp-outgoing-token ham --ilp-over-btp-incoming-token eggs --settle-threshold 0 --settle-to 0 --routing-relation foobar --round-trip-time 1000 --amount-per-minute-limit 42 --packets-per-minute-limit 4 --settlement-engine-url if_you_can_read_this_congratulations_youve_scrolled_too_far_right", // maximal
+            "ilp-cli accounts update alice --auth foo --asset-code XYZ --asset-scale 6 --ilp-address bar --max-packet-amount 100 --min-balance 0 --ilp-over-http-url qux --ilp-over-http-incoming-token baz --ilp-over-http-outgoing-token qaz --ilp-over-btp-url spam --ilp-over-btp-outgoing-token ham --ilp-over-btp-incoming-token eggs --settle-threshold 0 --settle-to 0 --routing-relation foobar --round-trip-time 1000 --amount-per-minute-limit 42 --packets-per-minute-limit 4 --settlement-engine-url if_you_can_read_this_congratulations_youve_scrolled_too_far_right --spread 0.01 --ilp-over-http-fallback-url quux --ilp-over-http-alternate-urls corge,grault --translate-prefix-from garply --translate-prefix-to waldo --packets-burst-limit 8 --amount-burst-limit 84 --accept-route-prefixes example.,test. --reject-route-prefixes example.bad --max-routes 1000 --aggregate-route-prefixes example.peer", // maximal
         ]);
     }
 
@@ -157,6 +157,22 @@ mod interface_tests {
         ]);
     }
 
+    #[test]
+    fn rates_list_overrides() {
+        should_parse(&[
+            "ilp-cli rates list-overrides", // minimal
+        ]);
+    }
+
+    #[test]
+    fn rates_set_overrides() {
+        should_parse(&[
+            "ilp-cli rates set-overrides --auth foo", // minimal
+            "ilp-cli rates set-overrides --auth foo --pair USDC/USD 1.0", // one
+            "ilp-cli rates set-overrides --auth foo --pair USDC/USD 1.0 --pair EUR/USD 1.1", // two
+        ]);
+    }
+
     #[test]
     fn routes_list() {
         should_parse(&[
@@ -198,6 +214,15 @@ mod interface_tests {
         ]);
     }
 
+    #[test]
+    fn packets_decode() {
+        should_parse(&[
+            "ilp-cli packets decode 0c7e0000000000000000323031353036313630303031303030303066687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f292511706565722e726f7574652e7570646174653221e55f8eabcd4e979ab9bf0ff00a224c000000340000003400000034000075300d6578616d706c652e616c69636501000100", // hex
+            "ilp-cli packets decode DH4AAAAAAAAAADIwMTUwNjE2MDAwMTAwMDAwZmh6rfhivXdsj8GLjp+OIAiXFIVu4jOzkCpZHQ1fKSURcGVlci5yb3V0ZS51cGRhdGUyIeVfjqvNTpeaub8P8AoiTAAAADQAAAA0AAAANAAAdTANZXhhbXBsZS5hbGljZQEAAQA=", // base64
+            "ilp-cli packets decode --stream 010c0101016301015903010203", // decrypted STREAM packet
+        ]);
+    }
+
     #[test]
     fn testnet_setup() {
         should_parse(&[
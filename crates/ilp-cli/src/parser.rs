@@ -13,13 +13,19 @@ pub fn build<'a, 'b>() -> App<'a, 'b> {
             accounts_update_settings(),
         ]),
         pay(),
-        rates().subcommands(vec![rates_list(), rates_set_all()]),
+        rates().subcommands(vec![
+            rates_list(),
+            rates_set_all(),
+            rates_list_overrides(),
+            rates_set_overrides(),
+        ]),
         routes().subcommands(vec![routes_list(), routes_set(), routes_set_all()]),
         settlement_engines().subcommands(vec![settlement_engines_set_all()]),
         status(),
         logs(),
         testnet().subcommands(vec![testnet_setup()]),
         payments().subcommands(vec![payments_incoming()]),
+        packets().subcommands(vec![packets_decode()]),
     ])
 }
 
@@ -146,6 +152,37 @@ fn accounts_create<'a, 'b>() -> App<'a, 'b> {
             Arg::with_name("settlement_engine_url")
                 .long("settlement-engine-url")
                 .takes_value(true),
+            Arg::with_name("spread").long("spread").takes_value(true),
+            Arg::with_name("ilp_over_http_fallback_url")
+                .long("ilp-over-http-fallback-url")
+                .takes_value(true),
+            Arg::with_name("ilp_over_http_alternate_urls")
+                .long("ilp-over-http-alternate-urls")
+                .takes_value(true),
+            Arg::with_name("translate_prefix_from")
+                .long("translate-prefix-from")
+                .takes_value(true),
+            Arg::with_name("translate_prefix_to")
+                .long("translate-prefix-to")
+                .takes_value(true),
+            Arg::with_name("packets_burst_limit")
+                .long("packets-burst-limit")
+                .takes_value(true),
+            Arg::with_name("amount_burst_limit")
+                .long("amount-burst-limit")
+                .takes_value(true),
+            Arg::with_name("accept_route_prefixes")
+                .long("accept-route-prefixes")
+                .takes_value(true),
+            Arg::with_name("reject_route_prefixes")
+                .long("reject-route-prefixes")
+                .takes_value(true),
+            Arg::with_name("max_routes")
+                .long("max-routes")
+                .takes_value(true),
+            Arg::with_name("aggregate_route_prefixes")
+                .long("aggregate-route-prefixes")
+                .takes_value(true),
         ])
 }
 
@@ -217,6 +254,37 @@ fn accounts_update<'a, 'b>() -> App<'a, 'b> {
             Arg::with_name("settlement_engine_url")
                 .long("settlement-engine-url")
                 .takes_value(true),
+            Arg::with_name("spread").long("spread").takes_value(true),
+            Arg::with_name("ilp_over_http_fallback_url")
+                .long("ilp-over-http-fallback-url")
+                .takes_value(true),
+            Arg::with_name("ilp_over_http_alternate_urls")
+                .long("ilp-over-http-alternate-urls")
+                .takes_value(true),
+            Arg::with_name("translate_prefix_from")
+                .long("translate-prefix-from")
+                .takes_value(true),
+            Arg::with_name("translate_prefix_to")
+                .long("translate-prefix-to")
+                .takes_value(true),
+            Arg::with_name("packets_burst_limit")
+                .long("packets-burst-limit")
+                .takes_value(true),
+            Arg::with_name("amount_burst_limit")
+                .long("amount-burst-limit")
+                .takes_value(true),
+            Arg::with_name("accept_route_prefixes")
+                .long("accept-route-prefixes")
+                .takes_value(true),
+            Arg::with_name("reject_route_prefixes")
+                .long("reject-route-prefixes")
+                .takes_value(true),
+            Arg::with_name("max_routes")
+                .long("max-routes")
+                .takes_value(true),
+            Arg::with_name("aggregate_route_prefixes")
+                .long("aggregate-route-prefixes")
+                .takes_value(true),
         ])
 }
 
@@ -342,6 +410,24 @@ fn rates_set_all<'a, 'b>() -> App<'a, 'b> {
         )
 }
 
+fn rates_list_overrides<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("list-overrides").about(
+        "List the fixed exchange rates of asset pairs, which take precedence over the other rates",
+    )
+}
+
+fn rates_set_overrides<'a, 'b>() -> App<'a, 'b> {
+    AuthorizedSubCommand::with_name("set-overrides")
+        .about("Overwrite the fixed exchange rates of asset pairs used by this node")
+        .arg(
+            Arg::with_name("halve")
+                .long("pair")
+                .number_of_values(2)
+                .multiple(true)
+                .help("A set of space-separated key/value pairs, representing an asset pair such as USDC/USD and the value of one unit of its first asset in the second one; may appear multiple times"),
+        )
+}
+
 fn routes<'a, 'b>() -> App<'a, 'b> {
     SubCommand::with_name("routes").about("Operations for interacting with the routing table")
 }
@@ -439,3 +525,26 @@ fn payments_incoming<'a, 'b>() -> App<'a, 'b> {
     AuthorizedSubCommand::with_name("incoming")
         .about("Open a persistent connection to a node for monitoring all incoming payments")
 }
+
+fn packets<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("packets").about("Inspect captured ILP and STREAM packets")
+}
+
+fn packets_decode<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("decode")
+        .about("Print the fields of an ILP packet, or of a STREAM packet with --stream")
+        .args(&[
+            Arg::with_name("packet")
+                .index(1)
+                .takes_value(true)
+                .required(true)
+                .help("The bytes of the packet, encoded as hex or base64"),
+            Arg::with_name("shared_secret")
+                .long("shared-secret")
+                .takes_value(true)
+                .help("The STREAM shared secret, encoded as hex or base64, used to decrypt STREAM packets, including those in the data of ILP packets"),
+            Arg::with_name("stream")
+                .long("stream")
+                .help("Decode a STREAM packet rather than an ILP packet (encrypted if a shared secret is given)"),
+        ])
+}
@@ -0,0 +1,109 @@
+use crate::node::{ConnectorStore, Embedding, InterledgerNode};
+use crate::pipeline::{BoxedOutgoingService, PipelineConfig};
+use crate::shutdown::ShutdownCoordinator;
+use interledger::service::OutgoingService;
+use interledger::store::account::Account;
+use tokio::sync::oneshot;
+
+/// Runs the connector inside of another Rust program, instead of as the `ilp-node` binary.
+///
+/// The node is configured with an [`InterledgerNode`](./struct.InterledgerNode.html), exactly
+/// like the binary, and then optionally given the middleware it should run, the service
+/// outgoing packets are sent to when an account has neither a BTP connection nor an HTTP URL,
+/// and the store it should use.
+///
+/// ```rust,ignore
+/// let node = NodeBuilder::new(config)
+///     .pipeline(pipeline)
+///     .outgoing_service(my_service)
+///     .start()
+///     .await?;
+/// // ...
+/// node.stop();
+/// node.stopped().await;
+/// ```
+pub struct NodeBuilder {
+    node: InterledgerNode,
+    fallback: Option<BoxedOutgoingService<Account>>,
+}
+
+impl NodeBuilder {
+    pub fn new(node: InterledgerNode) -> Self {
+        NodeBuilder {
+            node,
+            fallback: None,
+        }
+    }
+
+    /// Sets the middleware the packets go through, overriding the `pipeline` of the configuration
+    pub fn pipeline(mut self, pipeline: PipelineConfig) -> Self {
+        self.node.pipeline = pipeline;
+        self
+    }
+
+    /// Sends the packets for the accounts which have neither a BTP connection nor an HTTP URL
+    /// to the given service, instead of rejecting them as unreachable
+    pub fn outgoing_service<O>(mut self, service: O) -> Self
+    where
+        O: OutgoingService<Account> + Clone + Send + Sync + 'static,
+    {
+        self.fallback = Some(BoxedOutgoingService::new(service));
+        self
+    }
+
+    /// Starts the node on the store at the configured `database_url`.
+    ///
+    /// Unlike `InterledgerNode::serve`, this does not run the Prometheus metrics server.
+    pub async fn start(self) -> Result<RunningNode, ()> {
+        let (embedding, running) = self.embedding();
+        self.node.serve_embedded(None, embedding).await?;
+        Ok(running)
+    }
+
+    /// Starts the node on a store provided by the application
+    pub async fn start_with_store<S>(self, store: S) -> Result<RunningNode, ()>
+    where
+        S: ConnectorStore,
+    {
+        let (embedding, running) = self.embedding();
+        let ilp_address = self.node.initial_ilp_address();
+        self.node
+            .chain_services(store, ilp_address, None, embedding)
+            .await?;
+        Ok(running)
+    }
+
+    fn embedding(&self) -> (Embedding, RunningNode) {
+        let shutdown = ShutdownCoordinator::default();
+        let (stopped_tx, stopped_rx) = oneshot::channel();
+        let embedding = Embedding {
+            fallback: self.fallback.clone(),
+            shutdown: Some(shutdown.clone()),
+            stopped: Some(stopped_tx),
+        };
+        let running = RunningNode {
+            shutdown,
+            stopped: stopped_rx,
+        };
+        (embedding, running)
+    }
+}
+
+/// Handle to a node started by a [`NodeBuilder`](./struct.NodeBuilder.html)
+pub struct RunningNode {
+    shutdown: ShutdownCoordinator,
+    stopped: oneshot::Receiver<()>,
+}
+
+impl RunningNode {
+    /// Stops accepting new packets and starts draining the node, as the termination
+    /// signal does for the binary
+    pub fn stop(&self) {
+        self.shutdown.start();
+    }
+
+    /// Resolves once the node drained its in-flight packets and closed its connections
+    pub async fn stopped(self) {
+        let _ = self.stopped.await;
+    }
+}
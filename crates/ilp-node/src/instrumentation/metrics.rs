@@ -4,35 +4,55 @@ use interledger::{
         Account, IlpResult, IncomingRequest, IncomingService, OutgoingRequest, OutgoingService,
     },
 };
-use metrics::{self, labels, recorder, Key};
+use metrics::{self, labels, recorder, Key, Label};
 use std::time::Instant;
 
+/// Counts the fulfill or the reject, labeled with its error code, and the fulfilled amount
+fn record_result(prefix: &str, result: &IlpResult, amount: u64, labels: &[Label]) {
+    match result {
+        Ok(_) => {
+            recorder().increment_counter(
+                Key::from_name_and_labels(format!("{}.fulfill", prefix), labels.to_vec()),
+                1,
+            );
+            recorder().increment_counter(
+                Key::from_name_and_labels(format!("{}.fulfill.amount", prefix), labels.to_vec()),
+                amount,
+            );
+        }
+        Err(reject) => {
+            let mut labels = labels.to_vec();
+            labels.push(Label::new("error_code", reject.code().to_string()));
+            recorder().increment_counter(
+                Key::from_name_and_labels(format!("{}.reject", prefix), labels),
+                1,
+            );
+        }
+    }
+}
+
 pub async fn incoming_metrics<A: Account + CcpRoutingAccount>(
     request: IncomingRequest<A>,
     mut next: Box<dyn IncomingService<A> + Send>,
 ) -> IlpResult {
     let labels = labels!(
+        "from_username" => request.from.username().to_string(),
         "from_asset_code" => request.from.asset_code().to_string(),
         "from_routing_relation" => request.from.routing_relation().to_string(),
     );
+    let amount = request.prepare.amount();
     recorder().increment_counter(
         Key::from_name_and_labels("requests.incoming.prepare", labels.clone()),
         1,
     );
+    recorder().increment_counter(
+        Key::from_name_and_labels("requests.incoming.prepare.amount", labels.clone()),
+        amount,
+    );
     let start_time = Instant::now();
 
     let result = next.handle_request(request).await;
-    if result.is_ok() {
-        recorder().increment_counter(
-            Key::from_name_and_labels("requests.incoming.fulfill", labels.clone()),
-            1,
-        );
-    } else {
-        recorder().increment_counter(
-            Key::from_name_and_labels("requests.incoming.reject", labels.clone()),
-            1,
-        );
-    }
+    record_result("requests.incoming", &result, amount, &labels);
 
     recorder().record_histogram(
         Key::from_name_and_labels("requests.incoming.duration", labels),
@@ -46,11 +66,14 @@ pub async fn outgoing_metrics<A: Account + CcpRoutingAccount>(
     mut next: Box<dyn OutgoingService<A> + Send>,
 ) -> IlpResult {
     let labels = labels!(
+        "from_username" => request.from.username().to_string(),
+        "to_username" => request.to.username().to_string(),
         "from_asset_code" => request.from.asset_code().to_string(),
         "to_asset_code" => request.to.asset_code().to_string(),
         "from_routing_relation" => request.from.routing_relation().to_string(),
         "to_routing_relation" => request.to.routing_relation().to_string(),
     );
+    let amount = request.prepare.amount();
 
     // TODO replace these calls with the counter! macro if there's a way to easily pass in the already-created labels
     // right now if you pass the labels into one of the other macros, it gets a recursion limit error while expanding the macro
@@ -58,20 +81,14 @@ pub async fn outgoing_metrics<A: Account + CcpRoutingAccount>(
         Key::from_name_and_labels("requests.outgoing.prepare", labels.clone()),
         1,
     );
+    recorder().increment_counter(
+        Key::from_name_and_labels("requests.outgoing.prepare.amount", labels.clone()),
+        amount,
+    );
     let start_time = Instant::now();
 
     let result = next.send_request(request).await;
-    if result.is_ok() {
-        recorder().increment_counter(
-            Key::from_name_and_labels("requests.outgoing.fulfill", labels.clone()),
-            1,
-        );
-    } else {
-        recorder().increment_counter(
-            Key::from_name_and_labels("requests.outgoing.reject", labels.clone()),
-            1,
-        );
-    }
+    record_result("requests.outgoing", &result, amount, &labels);
 
     recorder().record_histogram(
         Key::from_name_and_labels("requests.outgoing.duration", labels),
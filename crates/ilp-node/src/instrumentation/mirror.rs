@@ -0,0 +1,442 @@
+use futures::Future;
+use interledger::{
+    packet::{Address, Prepare},
+    service::{Account, IlpResult, OutgoingRequest, OutgoingService, Username},
+};
+use rand::Rng;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    net::UdpSocket,
+    spawn,
+    sync::mpsc::{channel, error::TrySendError, Receiver},
+};
+use tracing::{error, info, trace, warn};
+use url::Url;
+
+/// The maximum number of records sent to the sink at once
+const MAX_BATCH_SIZE: usize = 100;
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+fn default_buffer_size() -> usize {
+    1024
+}
+
+/// Where the mirrored packet records are sent, as one JSON object per record
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MirrorSink {
+    /// Append the records to a file, one per line
+    File { path: String },
+    /// Send each record as a UDP datagram
+    Udp { address: SocketAddr },
+    /// Publish the records to a topic via a Kafka-compatible REST proxy
+    Kafka { url: Url, topic: String },
+}
+
+/// How much of each packet is mirrored
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MirrorMode {
+    /// Only the accounts, amounts, addresses, expiry and outcome
+    #[default]
+    Headers,
+    /// The headers and the data of the Prepare, Fulfill or Reject
+    Full,
+}
+
+/// Configuration for mirroring a sample of the forwarded packets to an analysis sink
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct MirrorConfig {
+    pub sink: MirrorSink,
+    /// Fraction (between 0 and 1) of the packets which are mirrored. Defaults to 1
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default)]
+    pub mode: MirrorMode,
+    /// The maximum number of records waiting to be sent. If the sink cannot keep up,
+    /// additional records are dropped rather than slowing down packet processing
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+}
+
+/// The details of a forwarded packet sent to the sink.
+///
+/// Fulfillments are never included, since they would allow anyone with
+/// access to the sink to claim the packets' funds.
+#[derive(Serialize, Debug)]
+struct MirroredPacket {
+    timestamp: u64,
+    prev_hop_account: Username,
+    prev_hop_asset_code: String,
+    prev_hop_asset_scale: u8,
+    prev_hop_amount: u64,
+    next_hop_account: Username,
+    next_hop_asset_code: String,
+    next_hop_asset_scale: u8,
+    next_hop_amount: u64,
+    destination: Address,
+    expires_at: u64,
+    execution_condition: String,
+    fulfilled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reject_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reject_triggered_by: Option<Address>,
+    /// Base64-encoded Prepare data (full mode only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prepare_data: Option<String>,
+    /// Base64-encoded Fulfill or Reject data (full mode only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_data: Option<String>,
+    /// Reject message (full mode only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reject_message: Option<String>,
+}
+
+fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl MirroredPacket {
+    fn new<A: Account>(
+        request: &RequestDetails<A>,
+        prepare: &Prepare,
+        result: &IlpResult,
+        mode: MirrorMode,
+    ) -> Self {
+        let full = mode == MirrorMode::Full;
+        let (reject_code, reject_triggered_by, response_data, reject_message) = match result {
+            Ok(fulfill) => (
+                None,
+                None,
+                full.then(|| base64::encode(fulfill.data())),
+                None,
+            ),
+            Err(reject) => (
+                Some(reject.code().to_string()),
+                reject.triggered_by(),
+                full.then(|| base64::encode(reject.data())),
+                full.then(|| String::from_utf8_lossy(reject.message()).to_string()),
+            ),
+        };
+        MirroredPacket {
+            timestamp: millis_since_epoch(SystemTime::now()),
+            prev_hop_account: request.from.username().clone(),
+            prev_hop_asset_code: request.from.asset_code().to_string(),
+            prev_hop_asset_scale: request.from.asset_scale(),
+            prev_hop_amount: request.original_amount,
+            next_hop_account: request.to.username().clone(),
+            next_hop_asset_code: request.to.asset_code().to_string(),
+            next_hop_asset_scale: request.to.asset_scale(),
+            next_hop_amount: prepare.amount(),
+            destination: prepare.destination(),
+            expires_at: millis_since_epoch(prepare.expires_at()),
+            execution_condition: hex::encode(prepare.execution_condition()),
+            fulfilled: result.is_ok(),
+            reject_code,
+            reject_triggered_by,
+            prepare_data: full.then(|| base64::encode(prepare.data())),
+            response_data,
+            reject_message,
+        }
+    }
+}
+
+/// The parts of the request which are kept until the response comes back
+struct RequestDetails<A> {
+    from: A,
+    to: A,
+    original_amount: u64,
+}
+
+type BoxedIlpFuture = Box<dyn Future<Output = IlpResult> + Send + 'static>;
+
+/// Create an Interledger service wrapper that mirrors a sample of the forwarded
+/// packets to the configured sink, for offline traffic analysis.
+///
+/// Records are handed to a background task through a bounded buffer, so mirroring
+/// does not add latency to the packets themselves.
+pub fn create_mirroring_wrapper<A: Account + 'static>(
+    config: Option<MirrorConfig>,
+) -> impl Fn(OutgoingRequest<A>, Box<dyn OutgoingService<A> + Send>) -> Pin<BoxedIlpFuture> + Clone
+{
+    let mirror = config.map(|config| {
+        let (sender, receiver) = channel(config.buffer_size.max(1));
+        info!(
+            "Mirroring {}% of forwarded packets to {:?}",
+            config.sample_rate * 100.0,
+            config.sink
+        );
+        spawn(send_to_sink(config.sink, receiver));
+        (sender, config.sample_rate.clamp(0.0, 1.0), config.mode)
+    });
+
+    move |request: OutgoingRequest<A>,
+          mut next: Box<dyn OutgoingService<A> + Send>|
+          -> Pin<BoxedIlpFuture> {
+        // Just pass the request on if mirroring is not configured or the packet was not sampled
+        let (mut sender, mode) = match mirror {
+            Some((ref sender, sample_rate, mode)) if rand::thread_rng().gen_bool(sample_rate) => {
+                (sender.clone(), mode)
+            }
+            _ => return Box::pin(async move { next.send_request(request).await }),
+        };
+
+        let details = RequestDetails {
+            from: request.from.clone(),
+            to: request.to.clone(),
+            original_amount: request.original_amount,
+        };
+        let prepare = request.prepare.clone();
+        Box::pin(async move {
+            let result = next.send_request(request).await;
+            let record = MirroredPacket::new(&details, &prepare, &result, mode);
+            match sender.try_send(record) {
+                Ok(_) => {}
+                Err(TrySendError::Full(_)) => {
+                    trace!("Mirroring buffer is full, dropping packet record")
+                }
+                Err(TrySendError::Closed(_)) => {
+                    trace!("Mirroring sink was closed, dropping packet record")
+                }
+            }
+            result
+        })
+    }
+}
+
+/// Receives the mirrored packet records and sends them to the sink in batches
+async fn send_to_sink(sink: MirrorSink, mut records: Receiver<MirroredPacket>) {
+    let mut sink = match ConnectedSink::connect(sink).await {
+        Ok(sink) => sink,
+        Err(err) => {
+            error!("Unable to set up packet mirroring sink: {}", err);
+            return;
+        }
+    };
+
+    while let Some(record) = records.recv().await {
+        let mut batch = vec![record];
+        while batch.len() < MAX_BATCH_SIZE {
+            match records.try_recv() {
+                Ok(record) => batch.push(record),
+                Err(_) => break,
+            }
+        }
+        if let Err(err) = sink.send(&batch).await {
+            warn!(
+                "Error sending {} mirrored packet records: {}",
+                batch.len(),
+                err
+            );
+        }
+    }
+}
+
+enum ConnectedSink {
+    File(tokio::fs::File),
+    Udp(UdpSocket, SocketAddr),
+    Kafka(Client, Url),
+}
+
+impl ConnectedSink {
+    async fn connect(sink: MirrorSink) -> Result<Self, String> {
+        match sink {
+            MirrorSink::File { path } => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await
+                .map(ConnectedSink::File)
+                .map_err(|err| format!("error opening {}: {}", path, err)),
+            MirrorSink::Udp { address } => {
+                let local: SocketAddr = if address.is_ipv4() {
+                    ([0, 0, 0, 0], 0).into()
+                } else {
+                    ([0; 16], 0).into()
+                };
+                UdpSocket::bind(local)
+                    .await
+                    .map(|socket| ConnectedSink::Udp(socket, address))
+                    .map_err(|err| format!("error binding UDP socket: {}", err))
+            }
+            MirrorSink::Kafka { url, topic } => {
+                let endpoint = url
+                    .join(&format!("topics/{}", topic))
+                    .map_err(|err| format!("invalid Kafka REST proxy URL: {}", err))?;
+                Ok(ConnectedSink::Kafka(Client::new(), endpoint))
+            }
+        }
+    }
+
+    async fn send(&mut self, batch: &[MirroredPacket]) -> Result<(), String> {
+        match self {
+            ConnectedSink::File(file) => {
+                let mut lines = Vec::new();
+                for record in batch {
+                    serde_json::to_writer(&mut lines, record).map_err(|err| err.to_string())?;
+                    lines.push(b'\n');
+                }
+                file.write_all(&lines)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                file.flush().await.map_err(|err| err.to_string())
+            }
+            ConnectedSink::Udp(socket, address) => {
+                for record in batch {
+                    let datagram = serde_json::to_vec(record).map_err(|err| err.to_string())?;
+                    socket
+                        .send_to(&datagram, &*address)
+                        .await
+                        .map_err(|err| err.to_string())?;
+                }
+                Ok(())
+            }
+            ConnectedSink::Kafka(client, endpoint) => {
+                let records: Vec<_> = batch
+                    .iter()
+                    .map(|record| serde_json::json!({ "value": record }))
+                    .collect();
+                let response = client
+                    .post(endpoint.as_str())
+                    .header("Content-Type", "application/vnd.kafka.json.v2+json")
+                    .body(serde_json::json!({ "records": records }).to_string())
+                    .send()
+                    .await
+                    .map_err(|err| err.to_string())?;
+                if response.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("Kafka REST proxy returned {}", response.status()))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger::packet::{FulfillBuilder, PrepareBuilder};
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    #[derive(Clone, Debug)]
+    struct TestAccount(Username);
+
+    impl Account for TestAccount {
+        fn id(&self) -> uuid::Uuid {
+            uuid::Uuid::nil()
+        }
+
+        fn username(&self) -> &Username {
+            &self.0
+        }
+
+        fn ilp_address(&self) -> &Address {
+            unimplemented!()
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+    }
+
+    fn test_prepare() -> Prepare {
+        PrepareBuilder {
+            destination: Address::from_str("example.bob").unwrap(),
+            amount: 100,
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+            execution_condition: &[1; 32],
+            data: b"prepare data",
+        }
+        .build()
+    }
+
+    fn test_details() -> RequestDetails<TestAccount> {
+        RequestDetails {
+            from: TestAccount(Username::from_str("alice").unwrap()),
+            to: TestAccount(Username::from_str("bob").unwrap()),
+            original_amount: 200,
+        }
+    }
+
+    #[test]
+    fn redacts_fulfillment_and_data() {
+        let result = Ok(FulfillBuilder {
+            fulfillment: &[7; 32],
+            data: b"fulfill data",
+        }
+        .build());
+
+        let record = MirroredPacket::new(
+            &test_details(),
+            &test_prepare(),
+            &result,
+            MirrorMode::Headers,
+        );
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["prev_hop_account"], "alice");
+        assert_eq!(json["prev_hop_amount"], 200);
+        assert_eq!(json["next_hop_amount"], 100);
+        assert_eq!(json["destination"], "example.bob");
+        assert_eq!(json["fulfilled"], true);
+        assert!(json.get("prepare_data").is_none());
+        assert!(json.get("response_data").is_none());
+        assert!(!json.to_string().contains(&base64::encode(&[7; 32])));
+        assert!(!json.to_string().contains(&hex::encode([7; 32])));
+
+        let record =
+            MirroredPacket::new(&test_details(), &test_prepare(), &result, MirrorMode::Full);
+        let json = serde_json::to_value(&record).unwrap();
+        assert_eq!(json["prepare_data"], base64::encode(b"prepare data"));
+        assert_eq!(json["response_data"], base64::encode(b"fulfill data"));
+    }
+
+    #[tokio::test]
+    async fn writes_records_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mirror.log");
+        let mut sink = ConnectedSink::connect(MirrorSink::File {
+            path: path.to_str().unwrap().to_string(),
+        })
+        .await
+        .unwrap();
+
+        let result = Ok(FulfillBuilder {
+            fulfillment: &[7; 32],
+            data: &[],
+        }
+        .build());
+        let record = MirroredPacket::new(
+            &test_details(),
+            &test_prepare(),
+            &result,
+            MirrorMode::Headers,
+        );
+        sink.send(&[record]).await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let json: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(json["next_hop_account"], "bob");
+    }
+}
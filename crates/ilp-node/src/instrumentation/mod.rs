@@ -8,3 +8,6 @@ pub mod prometheus;
 
 #[cfg(feature = "google-pubsub")]
 pub mod google_pubsub;
+
+#[cfg(feature = "packet-mirroring")]
+pub mod mirror;
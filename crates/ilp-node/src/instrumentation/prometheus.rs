@@ -1,18 +1,33 @@
 use crate::InterledgerNode;
+use interledger::{
+    errors::ApiError,
+    http::{bind_socket, serve_listener, HttpServerConfig},
+    packet::pool,
+};
+use metrics::Key;
 use metrics_core::{Builder, Drain, Observe};
+use metrics_runtime::{observers::PrometheusBuilder, Controller};
+use once_cell::sync::OnceCell;
+use secrecy::{ExposeSecret, SecretString};
 use serde::Deserialize;
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{net::SocketAddr, time::Duration};
 use tracing::{error, info};
 use warp::{
+    filters::BoxedFilter,
     http::{Response, StatusCode},
-    Filter,
+    Filter, Rejection,
 };
 
+/// Handle to the global metrics recorder, set once it is installed
+static CONTROLLER: OnceCell<Controller> = OnceCell::new();
+
 /// Configuration for [Prometheus](https://prometheus.io) metrics collection.
 #[derive(Deserialize, Clone, PartialEq, Debug)]
 pub struct PrometheusConfig {
-    /// IP address and port to host the Prometheus endpoint on.
-    pub bind_address: SocketAddr,
+    /// IP address and port to host a separate Prometheus endpoint on. The metrics are
+    /// always served on the `/metrics` endpoint of the node's HTTP API.
+    #[serde(default)]
+    pub bind_address: Option<SocketAddr>,
     /// Amount of time, in milliseconds, that the node will collect data points for the
     /// Prometheus histograms. Defaults to 300000ms (5 minutes).
     #[serde(default = "PrometheusConfig::default_histogram_window")]
@@ -34,7 +49,58 @@ impl PrometheusConfig {
     }
 }
 
-/// Starts a Prometheus metrics server that will listen on the configured address.
+/// Renders the metrics collected so far in the Prometheus exposition format
+fn render_metrics(controller: &Controller) -> Result<Response<String>, warp::http::Error> {
+    record_buffer_pool_stats();
+    let mut observer = PrometheusBuilder::default().build();
+    controller.observe(&mut observer);
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(observer.drain())
+}
+
+/// Reports the packet buffer pool's counters as gauges, so their rates can be compared
+/// with the node built with and without the `buffer-pool` feature
+fn record_buffer_pool_stats() {
+    let stats = pool::stats();
+    let recorder = metrics::recorder();
+    for (name, value) in &[
+        ("buffer_pool.hits", stats.hits),
+        ("buffer_pool.misses", stats.misses),
+        ("buffer_pool.recycled", stats.recycled),
+        ("buffer_pool.discarded", stats.discarded),
+        ("buffer_pool.pooled", stats.pooled),
+    ] {
+        recorder.update_gauge(Key::from_name(*name), *value as i64);
+    }
+}
+
+/// Returns the `/metrics` endpoint of the node's HTTP API, which requires the admin auth
+/// token since the metrics are labeled with the usernames of the accounts. It responds
+/// with a 404 if Prometheus was not configured.
+pub fn metrics_filter(admin_auth_token: String) -> BoxedFilter<(impl warp::Reply,)> {
+    warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(warp::header::<SecretString>("authorization"))
+        .and_then(move |authorization: SecretString| {
+            let admin_auth_header = format!("Bearer {}", admin_auth_token);
+            async move {
+                if authorization.expose_secret() != &admin_auth_header {
+                    return Err(Rejection::from(ApiError::unauthorized()));
+                }
+                match CONTROLLER.get() {
+                    Some(controller) => Ok(render_metrics(controller)),
+                    None => Err(warp::reject::not_found()),
+                }
+            }
+        })
+        .boxed()
+}
+
+/// Installs the metrics recorder and, if a bind address is configured, starts a Prometheus
+/// metrics server that will listen on it.
 ///
 /// # Errors
 /// This will fail if another Prometheus server is already running in this
@@ -61,24 +127,33 @@ pub async fn serve_prometheus(node: InterledgerNode) -> Result<(), ()> {
     // Try installing the global recorder
     match metrics::set_boxed_recorder(Box::new(receiver)) {
         Ok(_) => {
-            let observer = Arc::new(metrics_runtime::observers::PrometheusBuilder::default());
-
-            let filter = warp::get().and(warp::path::end()).map(move || {
-                let mut observer = observer.build();
-                controller.observe(&mut observer);
-                let prometheus_response = observer.drain();
-                Response::builder()
-                    .status(StatusCode::OK)
-                    .header("Content-Type", "text/plain; version=0.0.4")
-                    .body(prometheus_response)
-            });
+            let _ = CONTROLLER.set(controller.clone());
+            if let Some(bind_address) = prometheus.bind_address {
+                let filter = warp::get()
+                    .and(warp::path::end())
+                    .map(move || render_metrics(&controller));
 
-            info!(target: "interledger-node",
-                "Prometheus metrics server listening on: {}",
-                prometheus.bind_address
-            );
+                let listener =
+                    bind_socket(bind_address, node.http_server.reuse_port).map_err(|err| {
+                        error!(target: "interledger-node",
+                            "Error binding the Prometheus metrics server to {}: {}",
+                            bind_address, err
+                        )
+                    })?;
+                info!(target: "interledger-node",
+                    "Prometheus metrics server listening on: {}",
+                    bind_address
+                );
 
-            tokio::spawn(warp::serve(filter).bind(prometheus.bind_address));
+                tokio::spawn(async move {
+                    let config = HttpServerConfig::default();
+                    if let Err(err) =
+                        serve_listener(filter, listener, &config, futures::future::pending()).await
+                    {
+                        error!(target: "interledger-node", "Error serving the Prometheus metrics: {}", err);
+                    }
+                });
+            }
             Ok(())
         }
         Err(e) => {
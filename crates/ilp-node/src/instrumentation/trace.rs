@@ -1,3 +1,4 @@
+use futures::future::{BoxFuture, FutureExt};
 use interledger::{
     ccp::{CcpRoutingAccount, RoutingRelation},
     packet::{hex::HexString, ErrorCode, Fulfill, Reject},
@@ -5,11 +6,35 @@ use interledger::{
         Account, IlpResult, IncomingRequest, IncomingService, OutgoingRequest, OutgoingService,
     },
 };
+use serde::Deserialize;
 use std::str;
 use tracing::{debug_span, error_span, info, info_span};
 use tracing_futures::Instrument;
 use uuid::Uuid;
 
+/// Format of the node's logs
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Full,
+    /// One JSON object per line, including the fields of the spans it is in
+    Json,
+}
+
+/// Configuration for the node's logs
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct LoggingConfig {
+    /// Format of the logs. Defaults to `full`
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Whether to log when each span closes, with the time spent in it. This times each
+    /// packet and the layers of the service chain it went through
+    #[serde(default)]
+    pub span_timings: bool,
+}
+
 /// Add tracing context for the incoming request.
 /// This adds minimal information for the ERROR log
 /// level and more information for the DEBUG level.
@@ -120,6 +145,39 @@ pub async fn trace_outgoing<A: Account + CcpRoutingAccount>(
     trace_response(result)
 }
 
+/// Returns a wrapper for an incoming service which handles each request in a span named
+/// after the `layer`, nested in the packet's span. This shows which layer of the service chain
+/// a log line comes from and, if the span timings are logged, how long each packet spent in
+/// the layer (including the layers it passed the packet on to).
+pub fn trace_incoming_layer<A: Account + 'static>(
+    layer: &'static str,
+) -> impl Fn(IncomingRequest<A>, Box<dyn IncomingService<A> + Send>) -> BoxFuture<'static, IlpResult>
+       + Clone
+       + Send
+       + Sync {
+    move |request, mut next| {
+        let span = debug_span!(target: "interledger-node", "layer", name = layer);
+        async move { next.handle_request(request).await }
+            .instrument(span)
+            .boxed()
+    }
+}
+
+/// The outgoing counterpart of `trace_incoming_layer`
+pub fn trace_outgoing_layer<A: Account + 'static>(
+    layer: &'static str,
+) -> impl Fn(OutgoingRequest<A>, Box<dyn OutgoingService<A> + Send>) -> BoxFuture<'static, IlpResult>
+       + Clone
+       + Send
+       + Sync {
+    move |request, mut next| {
+        let span = debug_span!(target: "interledger-node", "layer", name = layer);
+        async move { next.send_request(request).await }
+            .instrument(span)
+            .boxed()
+    }
+}
+
 /// Log whether the response was a Fulfill or Reject
 fn trace_response(result: Result<Fulfill, Reject>) -> Result<Fulfill, Reject> {
     match result {
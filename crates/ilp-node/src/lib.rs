@@ -1,8 +1,18 @@
 #![type_length_limit = "10000000"]
+mod builder;
 mod instrumentation;
 mod node;
+mod pipeline;
+mod reload;
+mod shutdown;
+mod snapshot;
+mod subsystems;
 
+#[cfg(feature = "memory")]
+mod memory_store;
 #[cfg(feature = "redis")]
 mod redis_store;
 
+pub use builder::{NodeBuilder, RunningNode};
 pub use node::*;
+pub use pipeline::{IncomingStage, OutgoingStage, PipelineConfig, PipelineError};
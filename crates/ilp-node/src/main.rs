@@ -1,6 +1,11 @@
 #![type_length_limit = "10000000"]
 mod instrumentation;
 pub mod node;
+mod pipeline;
+mod reload;
+mod shutdown;
+mod snapshot;
+mod subsystems;
 
 use cfg_if::cfg_if;
 
@@ -8,12 +13,16 @@ cfg_if! {
     if #[cfg(feature = "monitoring")] {
         use tracing_subscriber::{
             filter::EnvFilter,
-            fmt::{time::ChronoUtc, Subscriber},
+            fmt::{format::FmtSpan, time::ChronoUtc, Subscriber},
         };
+        use instrumentation::trace::LogFormat;
         use node::LogWriter;
+        use std::sync::Arc;
     }
 }
 
+#[cfg(feature = "memory")]
+mod memory_store;
 #[cfg(feature = "redis")]
 mod redis_store;
 
@@ -76,15 +85,29 @@ async fn main() {
 
             let (nb_log_writer, _guard) = tracing_appender::non_blocking(log_writer.clone());
 
+            let span_events = if node.logging.span_timings {
+                FmtSpan::CLOSE
+            } else {
+                FmtSpan::NONE
+            };
             let tracing_builder = Subscriber::builder()
                 .with_timer(ChronoUtc::rfc3339())
                 .with_env_filter(EnvFilter::from_default_env())
                 .with_writer(nb_log_writer)
-                .with_filter_reloading();
+                .with_span_events(span_events);
 
-            log_writer.handle = Some(tracing_builder.reload_handle());
-
-            let _ = tracing_builder.try_init();
+            match node.logging.format {
+                LogFormat::Full => {
+                    let tracing_builder = tracing_builder.with_filter_reloading();
+                    log_writer.handle = Some(Arc::new(tracing_builder.reload_handle()));
+                    let _ = tracing_builder.try_init();
+                }
+                LogFormat::Json => {
+                    let tracing_builder = tracing_builder.json().with_filter_reloading();
+                    log_writer.handle = Some(Arc::new(tracing_builder.reload_handle()));
+                    let _ = tracing_builder.try_init();
+                }
+            }
 
             let log_writer = Some(log_writer);
         } else {
@@ -92,11 +115,8 @@ async fn main() {
         }
     }
 
-    node.serve(log_writer.clone()).await.unwrap();
-
-    // Add a future which is always pending. This will ensure main does not exist
-    // TODO: Is there a better way of doing this?
-    futures::future::pending().await
+    // Returns once the node was asked to terminate and drained its packets
+    node.serve_until_stopped(log_writer.clone()).await.unwrap();
 }
 
 fn cmdline_configuration<'b>(version: &'b str) -> clap::App<'static, 'b> {
@@ -151,6 +171,10 @@ fn cmdline_configuration<'b>(version: &'b str) -> clap::App<'static, 'b> {
             .takes_value(true)
             .default_value("")
             .help("Unique prefix that can be used to identify part of the db that this node will use. This can be used to enable multiple nodes to share the same database instance"),
+        Arg::with_name("migrate_database_prefix_from")
+            .long("migrate_database_prefix_from")
+            .takes_value(true)
+            .help("Prefix (possibly empty) the keys of this node used before. When the node starts, its keys are moved from this prefix to the database_prefix, so that an existing deployment can start sharing its database instance with other nodes"),
         Arg::with_name("http_bind_address")
             .long("http_bind_address")
             .takes_value(true)
@@ -159,6 +183,14 @@ fn cmdline_configuration<'b>(version: &'b str) -> clap::App<'static, 'b> {
             .long("settlement_api_bind_address")
             .takes_value(true)
             .help("IP address and port to listen for the Settlement Engine API"),
+        Arg::with_name("clearing_only")
+            .long("clearing_only")
+            .takes_value(true)
+            .help("Set to true to only clear and never settle. The settlement API is not served, the balances are only bounded by the accounts' min_balance, and the node refuses to start if settle_every, settlement_scheduler, settlement_reconciliation or any account's settlement engine is configured. Defaults to false."),
+        Arg::with_name("assign_child_addresses")
+            .long("assign_child_addresses")
+            .takes_value(true)
+            .help("Set to true to assign an address of the form <node_address>.child<N> to each child account on its first ILDCP request, instead of answering with the address configured on the account. Defaults to false."),
         Arg::with_name("default_spsp_account")
             .long("default_spsp_account")
             .takes_value(true)
@@ -167,6 +199,123 @@ fn cmdline_configuration<'b>(version: &'b str) -> clap::App<'static, 'b> {
             .long("route_broadcast_interval")
             .takes_value(true)
             .help("Interval, defined in milliseconds, on which the node will broadcast routing information to other nodes using CCP. Defaults to 30000ms (30 seconds)."),
+        Arg::with_name("route_loop_protection")
+            .long("route_loop_protection")
+            .takes_value(true)
+            .help("Set to true to reject packets which would loop instead of forwarding them: those addressed to the node's own address space which do not match any local account, and those which would be routed back to the account they came from. Defaults to false."),
+        Arg::with_name("reject_stream_data")
+            .long("reject_stream_data")
+            .takes_value(true)
+            .help("Set to true to reject the STREAM packets sent to the node which contain data, closing their connection with a ProtocolViolation error, instead of fulfilling them and dropping the data the sender believes was delivered. Defaults to false."),
+        Arg::with_name("stream_replay_protection")
+            .long("stream_replay_protection")
+            .takes_value(true)
+            .help("Set to true to remember the STREAM packets the node fulfilled until they expire, so that identical packets replayed in the meantime are rejected instead of fulfilled again. Defaults to false."),
+        Arg::with_name("stream_reject_dampening.max_failures")
+            .long("stream_reject_dampening.max_failures")
+            .takes_value(true)
+            .help("Number of STREAM packets from an account to a connection which may fail to decrypt before the next ones are rejected without decrypting them. Must be greater than 0. Setting any of the stream_reject_dampening options enables the dampening. Defaults to 5."),
+        Arg::with_name("stream_reject_dampening.window")
+            .long("stream_reject_dampening.window")
+            .takes_value(true)
+            .help("Time, in milliseconds, for which the failures to decrypt the packets from an account to a connection are remembered after the last one, and so for which its packets are rejected once it is dampened. Must be greater than 0. Defaults to 10000ms (10 seconds)."),
+        Arg::with_name("stream_keep_alive_timeout")
+            .long("stream_keep_alive_timeout")
+            .takes_value(true)
+            .help("Time, in milliseconds, after which a STREAM connection whose sender keeps it alive is deemed broken if no keep-alive packet arrived over it. Must be greater than 0. The connections are not monitored if this is not set."),
+        Arg::with_name("route_account_cache_ttl")
+            .long("route_account_cache_ttl")
+            .takes_value(true)
+            .help("Time, in milliseconds, for which the accounts packets are forwarded to are kept in memory instead of being loaded from the store for each packet. Changes to the accounts take up to this long to affect routing. Defaults to 0, which disables the cache."),
+        Arg::with_name("balance_batching.window")
+            .long("balance_batching.window")
+            .takes_value(true)
+            .help("Time, in milliseconds, during which the balance updates made once packets are fulfilled or rejected are collected into a batch, which is applied to Redis in a single round trip. Defaults to 1ms if batching is enabled by setting any of the balance_batching options."),
+        Arg::with_name("balance_batching.max_batch_size")
+            .long("balance_batching.max_batch_size")
+            .takes_value(true)
+            .help("Maximum number of balance updates in a batch. Defaults to 100 if batching is enabled by setting any of the balance_batching options."),
+        Arg::with_name("clock_skew_tolerance")
+            .long("clock_skew_tolerance")
+            .takes_value(true)
+            .help("Time, in milliseconds, after their expiry that incoming packets are still accepted, to tolerate peers whose clocks are behind the node's. Defaults to 0."),
+        Arg::with_name("btp_max_message_size")
+            .long("btp_max_message_size")
+            .takes_value(true)
+            .help("Size, in bytes, of the largest BTP message the node asks its peers to send. ILP packets which do not fit are split across multiple BTP messages when the peer supports it too. Defaults to 0, which disables fragmentation."),
+        Arg::with_name("idempotency_key_ttl")
+            .long("idempotency_key_ttl")
+            .takes_value(true)
+            .help("Time, in milliseconds, for which the responses to the idempotent settlement API requests are kept. Defaults to 86400000ms (24 hours)."),
+        Arg::with_name("settlement_scheduler.initial_backoff")
+            .long("settlement_scheduler.initial_backoff")
+            .takes_value(true)
+            .help("Delay, defined in milliseconds, before the first retry of a failed settlement. It doubles after every further failure. \
+                Setting any of the settlement_scheduler options makes the node queue the settlements, persist them and retry them if the settlement engine fails to accept them. Defaults to 1000ms (1 second)."),
+        Arg::with_name("settlement_scheduler.max_backoff")
+            .long("settlement_scheduler.max_backoff")
+            .takes_value(true)
+            .help("Maximum delay, defined in milliseconds, between two attempts of a settlement. Defaults to 300000ms (5 minutes)."),
+        Arg::with_name("settlement_scheduler.max_attempts")
+            .long("settlement_scheduler.max_attempts")
+            .takes_value(true)
+            .help("Number of failed attempts after which a settlement is refunded to the account's balance. Defaults to 10."),
+        Arg::with_name("settlement_reconciliation.interval")
+            .long("settlement_reconciliation.interval")
+            .takes_value(true)
+            .help("Interval, defined in milliseconds, at which the settlements recorded for each account are compared with the totals reported by its settlement engine. Must be greater than 0. \
+                The accounts whose engines do not implement GET /accounts/:id/settlements/totals are not reconciled. \
+                Setting any of the settlement_reconciliation options makes the node log the discrepancies and expose them at /settlement/discrepancies. Defaults to 3600000ms (1 hour)."),
+        Arg::with_name("settlement_reconciliation.tolerance")
+            .long("settlement_reconciliation.tolerance")
+            .takes_value(true)
+            .help("Largest discrepancy, denominated in the account's asset scale, which may be corrected automatically. Defaults to 0."),
+        Arg::with_name("settlement_reconciliation.auto_correct")
+            .long("settlement_reconciliation.auto_correct")
+            .takes_value(true)
+            .help("Set to true to credit the accounts with the incoming settlements the store missed, when they are within the tolerance and were found twice in a row. Defaults to false."),
+        Arg::with_name("webhooks.initial_backoff")
+            .long("webhooks.initial_backoff")
+            .takes_value(true)
+            .help("Delay, defined in milliseconds, before the first retry of a webhook notification the account's URL failed to accept. It doubles after every further failure. \
+                Setting any of the webhooks options makes the node notify the webhooks configured at /accounts/:username/webhook of their accounts' events. Defaults to 1000ms (1 second)."),
+        Arg::with_name("webhooks.max_attempts")
+            .long("webhooks.max_attempts")
+            .takes_value(true)
+            .help("Number of failed attempts after which a webhook notification is dropped. Defaults to 5."),
+        Arg::with_name("accounting_export.sink")
+            .long("accounting_export.sink")
+            .takes_value(true)
+            .help("Where the fulfilled packets, settlements and the amounts reserved for them are exported as double-entry ledger entries: the node's store or a file. If not set, no entries are exported."),
+        Arg::with_name("accounting_export.path")
+            .long("accounting_export.path")
+            .takes_value(true)
+            .help("File the ledger entries are appended to. Required by the file sink."),
+        Arg::with_name("accounting_export.format")
+            .long("accounting_export.format")
+            .takes_value(true)
+            .help("Format of the file the ledger entries are appended to. Defaults to csv."),
+        Arg::with_name("snapshot.path")
+            .long("snapshot.path")
+            .takes_value(true)
+            .help("File the node periodically saves its volatile state to (such as the exchange rates and the routes learned from peers), and restores it from when it starts. If not set, no snapshots are taken."),
+        Arg::with_name("snapshot.interval")
+            .long("snapshot.interval")
+            .takes_value(true)
+            .help("Interval, defined in milliseconds, on which the node saves a snapshot of its state, which it also saves when it shuts down. Must be greater than 0. Defaults to 30000ms (30 seconds)."),
+        Arg::with_name("snapshot.max_age")
+            .long("snapshot.max_age")
+            .takes_value(true)
+            .help("Snapshots older than this, defined in milliseconds, are not restored. By default, snapshots are restored regardless of their age."),
+        Arg::with_name("config_reload.path")
+            .long("config_reload.path")
+            .takes_value(true)
+            .help("Config file the node watches for changes to exchange_rate.spread, route_broadcast_interval and settle_every, which are applied without restarting. \
+                This is usually the file the node was started with. The other settings in it only take effect once the node restarts. If not set, no file is watched."),
+        Arg::with_name("config_reload.interval")
+            .long("config_reload.interval")
+            .takes_value(true)
+            .help("Interval, defined in milliseconds, on which the node checks whether the config_reload.path file changed. Must be greater than 0. Defaults to 5000ms (5 seconds)."),
         Arg::with_name("exchange_rate.provider")
             .long("exchange_rate.provider")
             .takes_value(true)
@@ -185,10 +334,87 @@ fn cmdline_configuration<'b>(version: &'b str) -> clap::App<'static, 'b> {
                 For example, take an incoming packet with an amount of 100. If the \
                 exchange rate is 1:0.5 and the spread is 0.01, the amount on the \
                     outgoing packet would be 198 (instead of 200 without the spread)."),
+        Arg::with_name("exchange_rate.max_age")
+            .long("exchange_rate.max_age")
+            .takes_value(true)
+            .help("Maximum age, defined in milliseconds, of the exchange rates. Packets which must be converted between assets are rejected if the rates are older. By default, rates are used regardless of their age."),
+        Arg::with_name("exchange_rate.dust_credit_interval")
+            .long("exchange_rate.dust_credit_interval")
+            .takes_value(true)
+            .help("Interval, defined in milliseconds, at which the dust lost by scaling down the packets between accounts of the same asset is credited back to the accounts which sent them. Must be greater than 0. By default, the dust is not kept track of."),
+        Arg::with_name("http_client.max_idle_connections_per_peer")
+            .long("http_client.max_idle_connections_per_peer")
+            .takes_value(true)
+            .help("Maximum number of idle connections the ILP over HTTP client keeps open to each peer. Unlimited by default."),
+        Arg::with_name("http_client.idle_timeout")
+            .long("http_client.idle_timeout")
+            .takes_value(true)
+            .help("Time, in milliseconds, the ILP over HTTP client keeps idle connections to peers open. Defaults to 90000ms (90 seconds)."),
+        Arg::with_name("http_client.http2_prior_knowledge")
+            .long("http_client.http2_prior_knowledge")
+            .takes_value(true)
+            .help("Set to true to send ILP over HTTP requests over HTTP/2 without negotiating it first. All peers' ILP over HTTP endpoints must support HTTP/2. Defaults to false."),
+        Arg::with_name("http_client.tcp_keepalive")
+            .long("http_client.tcp_keepalive")
+            .takes_value(true)
+            .help("Interval, defined in milliseconds, of the TCP keep-alive probes sent on the ILP over HTTP client's connections to peers. Disabled by default."),
+        Arg::with_name("http_client.hedge_delay")
+            .long("http_client.hedge_delay")
+            .takes_value(true)
+            .help("Time, defined in milliseconds, after which the requests without any amount addressed to the peer (peer.*) are also sent to the peer's ilp_over_http_fallback_url if its ilp_over_http_url has not responded. Disabled by default."),
+        Arg::with_name("http_client.max_retries")
+            .long("http_client.max_retries")
+            .takes_value(true)
+            .help("Maximum number of times an ILP over HTTP request which failed without any response is retried, on the account's next ilp_over_http_alternate_urls if it has any. Packets other than the requests without any amount addressed to the peer (peer.*) are only retried if the connection could not be established. Defaults to 2."),
+        Arg::with_name("http_client.retry_backoff")
+            .long("http_client.retry_backoff")
+            .takes_value(true)
+            .help("Time, defined in milliseconds, before the first retry of an ILP over HTTP request, doubled on each of the next ones. Defaults to 50ms."),
+        Arg::with_name("http_client.circuit_breaker_threshold")
+            .long("http_client.circuit_breaker_threshold")
+            .takes_value(true)
+            .help("Number of consecutive failed ILP over HTTP requests to an account after which the packets to it are rejected without being sent, until the circuit_breaker_reset_timeout elapsed. Disabled by default."),
+        Arg::with_name("http_client.circuit_breaker_reset_timeout")
+            .long("http_client.circuit_breaker_reset_timeout")
+            .takes_value(true)
+            .help("Time, defined in milliseconds, after which an open circuit breaker lets a trial ILP over HTTP request through. Defaults to 30000ms (30 seconds)."),
+        Arg::with_name("http_server.http2_keep_alive_interval")
+            .long("http_server.http2_keep_alive_interval")
+            .takes_value(true)
+            .help("Interval, defined in milliseconds, at which the HTTP server sends HTTP/2 pings to keep connections alive and detect dead ones. No pings are sent by default."),
+        Arg::with_name("http_server.http2_keep_alive_timeout")
+            .long("http_server.http2_keep_alive_timeout")
+            .takes_value(true)
+            .help("Time, in milliseconds, the HTTP server waits for the response to an HTTP/2 ping before closing the connection. Defaults to 20000ms (20 seconds)."),
+        Arg::with_name("http_server.http2_max_concurrent_streams")
+            .long("http_server.http2_max_concurrent_streams")
+            .takes_value(true)
+            .help("Maximum number of concurrent requests each peer may send over a single HTTP/2 connection. Unlimited by default."),
+        Arg::with_name("http_server.http1_keep_alive")
+            .long("http_server.http1_keep_alive")
+            .takes_value(true)
+            .help("Set to false to close HTTP/1.1 connections after each request. Defaults to true."),
+        Arg::with_name("http_server.tcp_keepalive")
+            .long("http_server.tcp_keepalive")
+            .takes_value(true)
+            .help("Interval, defined in milliseconds, of the TCP keep-alive probes sent on the HTTP server's connections. Disabled by default."),
+        Arg::with_name("http_server.reuse_port")
+            .long("http_server.reuse_port")
+            .takes_value(true)
+            .help("Set to true to bind the HTTP server, the settlement API and the Prometheus metrics server with SO_REUSEPORT, so a new node process can listen on the same addresses before the old one is terminated. Defaults to false."),
+        Arg::with_name("http_server.shutdown_grace_period")
+            .long("http_server.shutdown_grace_period")
+            .takes_value(true)
+            .help("Time, defined in milliseconds, the node waits after closing its WebSocket (BTP) connections once it received SIGTERM and drained the packets in progress. Defaults to 0."),
+        Arg::with_name("shutdown.drain_timeout")
+            .long("shutdown.drain_timeout")
+            .takes_value(true)
+            .help("Time, defined in milliseconds, the node waits for the packets it forwarded and the settlements in progress to complete after receiving SIGTERM, while rejecting new packets with T00 errors. Defaults to 30000ms (30 seconds)."),
         Arg::with_name("prometheus.bind_address")
             .long("prometheus.bind_address")
             .takes_value(true)
-            .help("IP address and port to host the Prometheus endpoint on."),
+            .help("IP address and port to host a separate Prometheus endpoint on. The metrics \
+                are always served on the /metrics endpoint of the node's HTTP API."),
         Arg::with_name("prometheus.histogram_window")
             .long("prometheus.histogram_window")
             .takes_value(true)
@@ -201,6 +427,14 @@ fn cmdline_configuration<'b>(version: &'b str) -> clap::App<'static, 'b> {
                 old data. For example, a value of 1000ms (1 second) would mean that the \
                 node forgets the oldest 1 second of histogram data points every second. \
                 Defaults to 10000ms (10 seconds)."),
+        Arg::with_name("logging.format")
+            .long("logging.format")
+            .takes_value(true)
+            .help("Format of the logs: full for human-readable lines, or json for one JSON object per line, which includes the fields of the spans (such as the packet's request id) the log is in. Defaults to full."),
+        Arg::with_name("logging.span_timings")
+            .long("logging.span_timings")
+            .takes_value(true)
+            .help("Set to true to log when each span closes, with the time spent in it. This shows how long each packet took to handle, and how long it spent in each layer of the service chain (router, exchange rate, balance and outgoing). Defaults to false."),
         Arg::with_name("settle_every")
             .long("settle_every")
             .takes_value(true)
@@ -0,0 +1,27 @@
+#![cfg(feature = "memory")]
+
+use crate::node::{Embedding, InterledgerNode, LogWriter};
+use interledger::{packet::Address, store::memory::MemoryStoreBuilder};
+use std::time::Duration;
+
+// Only the default database URL when the node is built without Redis
+#[cfg(not(feature = "redis"))]
+pub fn default_memory_url() -> String {
+    String::from("memory://")
+}
+
+// Like `serve_redis_node`, this is defined here to keep the conditionally-compiled code together.
+// Nothing is persisted, so all accounts and balances are lost when the node stops.
+pub async fn serve_memory_node(
+    node: InterledgerNode,
+    ilp_address: Address,
+    log_writer: Option<LogWriter>,
+    embedding: Embedding,
+) -> Result<(), ()> {
+    let store = MemoryStoreBuilder::new()
+        .node_ilp_address(ilp_address.clone())
+        .idempotency_key_ttl(Duration::from_millis(node.idempotency_key_ttl))
+        .build();
+    node.chain_services(store, ilp_address, log_writer, embedding)
+        .await
+}
@@ -2,23 +2,37 @@ use cfg_if::cfg_if;
 
 #[cfg(feature = "google-pubsub")]
 use crate::instrumentation::google_pubsub::{create_google_pubsub_wrapper, PubsubConfig};
+#[cfg(feature = "packet-mirroring")]
+use crate::instrumentation::mirror::{create_mirroring_wrapper, MirrorConfig};
+use crate::pipeline::{
+    BoxedIncomingService, BoxedOutgoingService, IncomingStage, OutgoingStage, PipelineConfig,
+};
+use crate::reload::{
+    spawn_config_watcher, ConfigReloadConfig, ReloadableConfig, ReloadableSettings,
+};
+use crate::shutdown::{
+    DrainIncomingService, DrainOutgoingService, ShutdownConfig, ShutdownCoordinator,
+};
+use crate::snapshot::{restore_snapshot, save_snapshot, spawn_snapshots, SnapshotConfig};
+use crate::subsystems::{Subsystem, Subsystems};
 
 cfg_if! {
     if #[cfg(feature = "monitoring")] {
         use interledger::errors::ApiError;
         use secrecy::{ExposeSecret, SecretString};
         use tracing::debug_span;
-        use tracing_appender::non_blocking::NonBlocking;
         use tracing_futures::Instrument;
         use tracing_subscriber::{
             filter::EnvFilter,
-            fmt::{format, time::ChronoUtc, Formatter},
-            reload::Handle,
+            reload::{self, Handle},
         };
         use crate::instrumentation::{
             metrics::{incoming_metrics, outgoing_metrics},
-            prometheus::{serve_prometheus, PrometheusConfig},
-            trace::{trace_forwarding, trace_incoming, trace_outgoing},
+            prometheus::{metrics_filter, serve_prometheus, PrometheusConfig},
+            trace::{
+                trace_forwarding, trace_incoming, trace_incoming_layer, trace_outgoing,
+                trace_outgoing_layer, LoggingConfig,
+            },
         };
         use interledger::service::IncomingService;
         use futures::FutureExt;
@@ -30,36 +44,63 @@ cfg_if! {
 use interledger::service::OutgoingService;
 
 use bytes::Bytes;
-use futures::TryFutureExt;
+use futures::{future, Stream, StreamExt, TryFutureExt};
 use hex::FromHex;
 use interledger::{
-    api::{NodeApi, NodeStore},
-    btp::{btp_service_as_filter, connect_client, BtpOutgoingService, BtpStore},
-    ccp::{CcpRouteManagerBuilder, CcpRoutingAccount, CcpRoutingStore, RoutingRelation},
+    api::{
+        spawn_accounting_exporter, spawn_webhook_dispatcher, AccountingSink, FileAccountingSink,
+        LedgerFileFormat, NodeApi, NodeStore, WebhookRetryPolicy, WebhookStore,
+    },
+    btp::{
+        btp_service_as_filter, connect_accounts, reconnect_clients, BtpConnections,
+        BtpOutgoingService, BtpStore, ReconnectBackoff,
+    },
+    ccp::{
+        CcpRouteManagerBuilder, CcpRoutingAccount, CcpRoutingStore, RoutingRelation,
+        DEFAULT_BROADCAST_INTERVAL,
+    },
     errors::*,
-    http::{HttpClientService, HttpServer as IlpOverHttpServer, HttpStore},
-    ildcp::IldcpService,
+    http::{
+        bind as bind_http, bind_socket, serve_listener as serve_http, HttpClientConfig,
+        HttpClientService, HttpServer as IlpOverHttpServer, HttpServerConfig, HttpStore,
+        WsClientService, WsServer as IlpOverWsServer,
+    },
+    ildcp::{AddressAssignmentStore, DynamicIldcpService, IldcpService},
     packet::Address,
     packet::{ErrorCode, RejectBuilder},
     rates::{ExchangeRateFetcher, ExchangeRateStore},
-    router::{Router, RouterStore},
+    router::{NextHop, Router, RouterStore},
     service::{
-        outgoing_service_fn, Account as AccountTrait, AccountStore, AddressStore, OutgoingRequest,
-        Username,
+        outgoing_service_fn, Account as AccountTrait, AccountEvent, AccountStore, AddressStore,
+        EventBus, OutgoingRequest, Username,
     },
     service_util::{
-        BalanceStore, EchoService, ExchangeRateService, ExpiryShortenerService,
-        MaxPacketAmountService, RateLimitService, RateLimitStore, ValidatorService,
+        start_balance_history, AddressTranslationService, BalanceHistoryStore, BalanceStore,
+        DedupService, DedupStore, DustLedger, DustStore, EchoService, ExchangeRateService,
+        ExpiryShortenerService, HealthPolicy, MaxPacketAmountService, PeerEvent, PeerMonitor,
+        PeerMonitorService, PeerScoreboard, PeerScoreboardService, PeerStatus, RateLimitService,
+        RateLimitStore, SchemePolicy, SchemePolicyService, ScoreboardPolicy,
+        TenantIsolationService, TenantPolicy, UsageService, UsageStore, ValidatorService,
     },
     settlement::{
         api::{create_settlements_filter, SettlementMessageService},
         core::{
-            idempotency::IdempotentStore,
-            types::{LeftoversStore, SettlementStore},
+            idempotency::{
+                spawn_idempotent_data_expiry, IdempotentStore, DEFAULT_IDEMPOTENCY_KEY_TTL,
+            },
+            types::{
+                LeftoversStore, SettlementAccount, SettlementHistoryStore, SettlementQueueStore,
+                SettlementStore,
+            },
+            Reconciler, ReconciliationPolicy, SettlementClient,
         },
     },
+    spsp::ContactStore,
     store::account::Account,
-    stream::{StreamNotificationsStore, StreamReceiverService},
+    stream::{
+        DampeningPolicy, KeepAliveMonitor, StreamNotificationsStore, StreamReceiveStore,
+        StreamReceiverService, StreamReplayStore, SubAccountStore, TagDispatchService, TagRoute,
+    },
 };
 use num_bigint::BigUint;
 use once_cell::sync::Lazy;
@@ -67,30 +108,130 @@ use serde::{de::Error as DeserializeError, Deserialize, Deserializer};
 #[cfg(feature = "balance-tracking")]
 use std::num::NonZeroU32;
 use std::{
+    collections::HashMap,
     convert::TryFrom,
     net::SocketAddr,
+    path::PathBuf,
     str::{self, FromStr},
-    time::Duration,
+    time::{Duration, Instant},
 };
-use tokio::spawn;
+use tokio::{spawn, sync::oneshot};
 use tracing::{debug, error, info};
 use url::Url;
 use uuid::Uuid;
 use warp::{self, Filter};
 
+#[cfg(feature = "memory")]
+use crate::memory_store::*;
 #[cfg(feature = "redis")]
 use crate::redis_store::*;
 #[cfg(feature = "balance-tracking")]
-use interledger::service_util::{start_delayed_settlement, BalanceService};
+use interledger::{
+    service_util::{start_delayed_settlement, BalanceService},
+    settlement::core::{RetryPolicy, SettlementScheduler},
+};
 
 #[doc(hidden)]
 pub use interledger::rates::ExchangeRateProvider;
 
+/// How often the expired idempotent responses are removed from the store
+const IDEMPOTENT_DATA_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the BTP connections are checked for the peers which reconnected
+const BTP_CONNECTIVITY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 static DEFAULT_ILP_ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("local.host").unwrap());
 
+/// All of the store traits the node's services need, implemented by the Redis and
+/// in-memory stores. Custom stores implementing them can be given to the
+/// `NodeBuilder`
+pub trait ConnectorStore:
+    NodeStore<Account = Account>
+    + AddressStore
+    + BtpStore<Account = Account>
+    + HttpStore<Account = Account>
+    + StreamNotificationsStore<Account = Account>
+    + BalanceStore
+    + SettlementStore<Account = Account>
+    + ExchangeRateStore
+    + RouterStore<Account = Account>
+    + CcpRoutingStore<Account = Account>
+    + RateLimitStore<Account = Account>
+    + UsageStore
+    + DedupStore
+    + DustStore
+    + BalanceHistoryStore
+    + ContactStore
+    + StreamReceiveStore
+    + StreamReplayStore
+    + WebhookStore
+    + AccountingSink
+    + SubAccountStore
+    + AddressAssignmentStore
+    + LeftoversStore<AccountId = Uuid, AssetType = BigUint>
+    + IdempotentStore
+    + SettlementQueueStore
+    + SettlementHistoryStore
+    + AccountStore<Account = Account>
+    + Clone
+    + Send
+    + Sync
+    + 'static
+{
+}
+
+impl<S> ConnectorStore for S where
+    S: NodeStore<Account = Account>
+        + AddressStore
+        + BtpStore<Account = Account>
+        + HttpStore<Account = Account>
+        + StreamNotificationsStore<Account = Account>
+        + BalanceStore
+        + SettlementStore<Account = Account>
+        + ExchangeRateStore
+        + RouterStore<Account = Account>
+        + CcpRoutingStore<Account = Account>
+        + RateLimitStore<Account = Account>
+        + UsageStore
+        + DedupStore
+        + DustStore
+        + BalanceHistoryStore
+        + ContactStore
+        + StreamReceiveStore
+        + StreamReplayStore
+        + WebhookStore
+        + AccountingSink
+        + SubAccountStore
+        + AddressAssignmentStore
+        + LeftoversStore<AccountId = Uuid, AssetType = BigUint>
+        + IdempotentStore
+        + SettlementQueueStore
+        + SettlementHistoryStore
+        + AccountStore<Account = Account>
+        + Clone
+        + Send
+        + Sync
+        + 'static
+{
+}
+
+/// How the node is run by the application embedding it, rather than by the `ilp-node` binary
+#[derive(Default)]
+pub(crate) struct Embedding {
+    /// Sends the packets to the accounts which have neither a BTP connection nor an HTTP URL,
+    /// instead of rejecting them
+    pub(crate) fallback: Option<BoxedOutgoingService<Account>>,
+    /// Shuts the node down when started, instead of the termination signal
+    pub(crate) shutdown: Option<ShutdownCoordinator>,
+    /// Notified once the node stopped
+    pub(crate) stopped: Option<oneshot::Sender<()>>,
+}
+
 fn default_settlement_api_bind_address() -> SocketAddr {
     SocketAddr::from(([127, 0, 0, 1], 7771))
 }
+fn default_idempotency_key_ttl() -> u64 {
+    DEFAULT_IDEMPOTENCY_KEY_TTL.as_millis() as u64
+}
 fn default_http_bind_address() -> SocketAddr {
     SocketAddr::from(([127, 0, 0, 1], 7770))
 }
@@ -102,6 +243,8 @@ fn default_http_bind_address() -> SocketAddr {
 fn default_database_url() -> String {
     #[cfg(feature = "redis")]
     return default_redis_url();
+    #[cfg(all(feature = "memory", not(feature = "redis")))]
+    return default_memory_url();
     panic!("no backing store configured")
 }
 
@@ -130,6 +273,28 @@ where
     })
 }
 
+fn deserialize_username<'de, D>(deserializer: D) -> Result<Username, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let username = String::deserialize(deserializer)?;
+    Username::from_str(&username)
+        .map_err(|err| DeserializeError::custom(format!("Invalid username: {:?}", err)))
+}
+
+/// Credits the payments an account receives over STREAM connections with a tag
+/// starting with `tag_prefix` to one of its sub-accounts
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct TagDispatchConfig {
+    /// Username of the account receiving payments on behalf of the sub-account
+    #[serde(deserialize_with = "deserialize_username")]
+    pub account: Username,
+    pub tag_prefix: String,
+    /// Username of the account the payments are credited to
+    #[serde(deserialize_with = "deserialize_username")]
+    pub sub_account: Username,
+}
+
 fn deserialize_optional_username<'de, D>(deserializer: D) -> Result<Option<Username>, D::Error>
 where
     D: Deserializer<'de>,
@@ -156,6 +321,7 @@ pub struct ExchangeRateConfig {
     pub poll_failure_tolerance: u32,
     /// API to poll for exchange rates. Currently the supported options are:
     /// - [CoinCap](https://docs.coincap.io)
+    /// - [CoinGecko](https://www.coingecko.com)
     /// - [CryptoCompare](https://cryptocompare.com) (note this requires an API key)
     /// If this value is not set, the node will not poll for exchange rates and will
     /// instead use the rates configured via the HTTP API.
@@ -169,6 +335,16 @@ pub struct ExchangeRateConfig {
     /// outgoing packet would be 198 (instead of 200 without the spread).
     #[serde(default)]
     pub spread: f64,
+    /// Maximum age, defined in milliseconds, of the exchange rates. Packets which
+    /// must be converted between assets are rejected if the rates are older.
+    /// By default, rates are used regardless of their age.
+    #[serde(default)]
+    pub max_age: Option<u64>,
+    /// Interval, defined in milliseconds, at which the dust lost by scaling down the packets
+    /// between accounts of the same asset is credited back to the accounts which sent them.
+    /// If not set, the dust is not kept track of.
+    #[serde(default)]
+    pub dust_credit_interval: Option<u64>,
 }
 
 impl Default for ExchangeRateConfig {
@@ -180,10 +356,157 @@ impl Default for ExchangeRateConfig {
             poll_failure_tolerance: Self::default_poll_failure_tolerance(),
             provider: Default::default(),
             spread: Self::default_spread(),
+            max_age: None,
+            dust_credit_interval: None,
         }
     }
 }
 
+/// Configuration for queueing the outgoing settlements and retrying the ones
+/// which the settlement engines failed to accept
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct SettlementSchedulerConfig {
+    /// Delay, in milliseconds, before the first retry of a failed settlement.
+    /// It doubles after every further failure. Defaults to 1000ms (1 second).
+    #[serde(default = "SettlementSchedulerConfig::default_initial_backoff")]
+    pub initial_backoff: u64,
+    /// Maximum delay, in milliseconds, between two attempts of a settlement.
+    /// Defaults to 300000ms (5 minutes).
+    #[serde(default = "SettlementSchedulerConfig::default_max_backoff")]
+    pub max_backoff: u64,
+    /// Number of failed attempts after which a settlement is refunded to the account's balance.
+    /// Defaults to 10.
+    #[serde(default = "SettlementSchedulerConfig::default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+impl SettlementSchedulerConfig {
+    fn default_initial_backoff() -> u64 {
+        1000
+    }
+    fn default_max_backoff() -> u64 {
+        300_000
+    }
+    fn default_max_attempts() -> u32 {
+        10
+    }
+}
+
+/// Configuration for notifying the accounts' webhooks of their balance, settlement
+/// and suspension events
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct WebhooksConfig {
+    /// Delay, in milliseconds, before the first retry of a failed notification.
+    /// It doubles after every further failure. Defaults to 1000ms (1 second).
+    #[serde(default = "WebhooksConfig::default_initial_backoff")]
+    pub initial_backoff: u64,
+    /// Number of failed attempts after which a notification is dropped. Defaults to 5.
+    #[serde(default = "WebhooksConfig::default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+impl WebhooksConfig {
+    fn default_initial_backoff() -> u64 {
+        1000
+    }
+    fn default_max_attempts() -> u32 {
+        5
+    }
+}
+
+/// Where the ledger entries exported from the balance changes are written
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountingSinkKind {
+    /// The node's store (a capped Redis stream for the Redis store)
+    Store,
+    /// A file the entries are appended to
+    File,
+}
+
+/// Configuration for exporting the balance changes as double-entry ledger entries
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct AccountingExportConfig {
+    /// Where the ledger entries are written
+    pub sink: AccountingSinkKind,
+    /// Path of the file the entries are appended to. Required by the `file` sink.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Format of the file the entries are appended to (`csv` or `json`). Defaults to `csv`.
+    #[serde(default)]
+    pub format: LedgerFileFormat,
+}
+
+/// Configuration for periodically reconciling the settlements recorded in the store with
+/// the totals reported by the settlement engines
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct SettlementReconciliationConfig {
+    /// Interval, in milliseconds, at which the settlements are reconciled.
+    /// Defaults to 3600000ms (1 hour).
+    #[serde(default = "SettlementReconciliationConfig::default_interval")]
+    pub interval: u64,
+    /// Largest discrepancy, in the account's asset scale, which may be corrected automatically.
+    /// Defaults to 0.
+    #[serde(default)]
+    pub tolerance: u64,
+    /// Whether the incoming settlements which the store missed are credited to the accounts
+    /// when they are within the tolerance, rather than only reported. Defaults to false.
+    #[serde(default)]
+    pub auto_correct: bool,
+}
+
+impl SettlementReconciliationConfig {
+    fn default_interval() -> u64 {
+        3_600_000
+    }
+}
+
+/// Configuration for periodically sampling the account balances, so that dashboards
+/// can chart them via the `/accounts/:username/balance/history` API
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct BalanceHistoryConfig {
+    /// Interval, in milliseconds, at which the balances are sampled.
+    /// Defaults to 60000ms (1 minute).
+    #[serde(default = "BalanceHistoryConfig::default_interval")]
+    pub interval: u64,
+    /// Number of samples kept per account, after which the oldest ones are dropped.
+    /// Defaults to 1440 (1 day of samples at the default interval).
+    #[serde(default = "BalanceHistoryConfig::default_capacity")]
+    pub capacity: usize,
+}
+
+impl BalanceHistoryConfig {
+    fn default_interval() -> u64 {
+        60_000
+    }
+    fn default_capacity() -> usize {
+        1440
+    }
+}
+
+/// Configuration for rejecting the STREAM packets to a connection without decrypting them,
+/// once too many of the packets an account sent to it failed to decrypt
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct StreamDampeningConfig {
+    /// Number of packets from an account to a connection which may fail to decrypt before
+    /// the next ones are rejected. Defaults to 5.
+    #[serde(default = "StreamDampeningConfig::default_max_failures")]
+    pub max_failures: u32,
+    /// Time, in milliseconds, for which the failures are remembered after the last one.
+    /// Defaults to 10000ms (10 seconds).
+    #[serde(default = "StreamDampeningConfig::default_window")]
+    pub window: u64,
+}
+
+impl StreamDampeningConfig {
+    fn default_max_failures() -> u32 {
+        5
+    }
+    fn default_window() -> u64 {
+        10_000
+    }
+}
+
 impl ExchangeRateConfig {
     pub(crate) fn default_poll_interval() -> u64 {
         60_000
@@ -211,7 +534,7 @@ pub struct InterledgerNode {
     pub secret_seed: [u8; 32],
     /// HTTP Authorization token for the node admin (sent as a Bearer token)
     pub admin_auth_token: String,
-    /// Data store URI (for example, "redis://127.0.0.1:6379" or "redis+unix:/tmp/redis.sock")
+    /// Data store URI (for example, "redis://127.0.0.1:6379", "redis+unix:/tmp/redis.sock" or "memory://")
     #[serde(
         default = "default_database_url",
         // temporary alias for backwards compatibility
@@ -221,6 +544,11 @@ pub struct InterledgerNode {
     /// Database prefix which can be used in case a db instance is shared by multiple nodes
     #[serde(default)]
     pub database_prefix: String,
+    /// Prefix (possibly empty) the keys of this node used before, which are moved under
+    /// `database_prefix` when the node starts. Used to migrate an existing deployment
+    /// to a shared db instance
+    #[serde(default)]
+    pub migrate_database_prefix_from: Option<String>,
     /// IP address and port to listen for HTTP connections
     /// This is used for both the API and ILP over HTTP packets
     #[serde(default = "default_http_bind_address")]
@@ -228,6 +556,16 @@ pub struct InterledgerNode {
     /// IP address and port to listen for the Settlement Engine API
     #[serde(default = "default_settlement_api_bind_address")]
     pub settlement_api_bind_address: SocketAddr,
+    /// Whether the node only clears and never settles. The settlement API is not served, the
+    /// balances are only bounded by the accounts' `min_balance`, and the node refuses to start
+    /// (and the API refuses changes) if settlement is configured anywhere
+    #[serde(default)]
+    pub clearing_only: bool,
+    /// Whether the node assigns an address of the form `<node_address>.child<N>` to each of
+    /// its child accounts on their first ILDCP request, instead of answering with the
+    /// address configured on the account
+    #[serde(default)]
+    pub assign_child_addresses: bool,
     /// When SPSP payments are sent to the root domain, the payment pointer is resolved
     /// to <domain>/.well-known/pay. This value determines which account those payments
     /// will be sent to.
@@ -239,20 +577,153 @@ pub struct InterledgerNode {
     #[serde(default)]
     /// Configuration for calculating exchange rates between various pairs.
     pub exchange_rate: ExchangeRateConfig,
+    /// Connection pooling and HTTP/2 settings of the ILP over HTTP client.
+    #[serde(default)]
+    pub http_client: HttpClientConfig,
+    /// Keep-alive and HTTP/2 settings of the HTTP server, which is used for both the API
+    /// and ILP over HTTP packets
+    #[serde(default)]
+    pub http_server: HttpServerConfig,
+    /// How long the node waits for the packets and settlements in progress when it is
+    /// asked to terminate
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
     /// Configuration for [Prometheus](https://prometheus.io) metrics collection.
     /// If this configuration is not provided, the node will not collect metrics.
     /// Needs the feature flag "monitoring" to be enabled
     #[cfg(feature = "monitoring")]
     #[serde(default)]
     pub prometheus: Option<PrometheusConfig>,
+    /// Configuration for the format of the logs and whether they include timings
+    #[cfg(feature = "monitoring")]
+    #[serde(default)]
+    pub logging: LoggingConfig,
     #[cfg(feature = "google-pubsub")]
     pub google_pubsub: Option<PubsubConfig>,
+    /// Configuration for mirroring a sample of the forwarded packets to an analysis sink.
+    /// If this configuration is not provided, packets are not mirrored.
+    /// Needs the feature flag "packet-mirroring" to be enabled
+    #[cfg(feature = "packet-mirroring")]
+    #[serde(default)]
+    pub packet_mirroring: Option<MirrorConfig>,
     /// The delay in seconds to settle peering account to `settle_to` level in addition to settling
     /// the account when it exceeds the settlement threshold.
     ///
     /// See further notes at `--help` output.
     #[cfg(feature = "balance-tracking")]
     pub settle_every: Option<NonZeroU32>,
+    /// Configuration for sending the settlements from a queue which persists them and retries
+    /// them with exponential backoff. If this configuration is not provided, the settlements are
+    /// sent as soon as they are triggered and refunded if the settlement engine fails to accept them.
+    /// The queue also redelivers the messages from the peers' engines which the engines failed to receive.
+    #[cfg(feature = "balance-tracking")]
+    #[serde(default)]
+    pub settlement_scheduler: Option<SettlementSchedulerConfig>,
+    /// Configuration for periodically comparing the settlements recorded for each account with
+    /// the totals reported by its settlement engine. The discrepancies are logged and exposed
+    /// by the `/settlement/discrepancies` API. If this configuration is not provided, the
+    /// settlements are not reconciled.
+    #[serde(default)]
+    pub settlement_reconciliation: Option<SettlementReconciliationConfig>,
+    /// Configuration for notifying the accounts' webhooks of their events.
+    /// If this configuration is not provided, no events are published nor notified.
+    #[serde(default)]
+    pub webhooks: Option<WebhooksConfig>,
+    /// Configuration for exporting the fulfilled packets, settlements and the amounts reserved
+    /// for them as double-entry ledger entries. If this configuration is not provided, no
+    /// entries are exported.
+    #[serde(default)]
+    pub accounting_export: Option<AccountingExportConfig>,
+    /// Address schemes (such as `g` or `test`) the node may forward packets to.
+    /// By default, packets to any scheme are forwarded.
+    #[serde(default)]
+    pub address_scheme_policy: SchemePolicy,
+    /// Tenants hosted by the node, whose accounts may only send packets to their own prefixes
+    /// and to the global ones. By default, no account is restricted.
+    #[serde(default)]
+    pub tenant_isolation: TenantPolicy,
+    /// Which of the node's services packets go through, in the order they go through them.
+    /// By default, all of them are enabled.
+    #[serde(default)]
+    pub pipeline: PipelineConfig,
+    /// When peers which misbehave, for example by rejecting too many packets or failing to
+    /// authenticate, are automatically throttled or suspended. By default, their behavior is
+    /// only tracked.
+    #[serde(default)]
+    pub peer_scoreboard: ScoreboardPolicy,
+    /// When the peers the node sends packets to are deemed unhealthy, in which case the
+    /// routes they advertised are withdrawn until they recover
+    #[serde(default)]
+    pub peer_health: HealthPolicy,
+    /// Prefixes which are routed to multiple next hops, for load balancing and failover.
+    /// These take precedence over the routing table entries for the same prefixes.
+    #[serde(default)]
+    pub next_hops: HashMap<String, Vec<NextHop>>,
+    /// Whether to reject packets which would loop, rather than forwarding them
+    #[serde(default)]
+    pub route_loop_protection: bool,
+    /// Whether to reject the STREAM packets sent to the node which contain data, closing their
+    /// connection, rather than fulfilling them and dropping the data
+    #[serde(default)]
+    pub reject_stream_data: bool,
+    /// Whether to remember the STREAM packets the node fulfilled until they expire, so that
+    /// the identical packets replayed in the meantime are rejected rather than fulfilled again
+    #[serde(default)]
+    pub stream_replay_protection: bool,
+    /// Configuration for rejecting the STREAM packets sent to the node without decrypting
+    /// them, once too many packets from the same account to the same connection failed to
+    /// decrypt. If this configuration is not provided, every packet is decrypted.
+    #[serde(default)]
+    pub stream_reject_dampening: Option<StreamDampeningConfig>,
+    /// Time, in milliseconds, after which a STREAM connection to the node's accounts whose
+    /// sender keeps it alive is deemed broken, if no keep-alive packet arrived over it.
+    /// If this is not provided, the connections are not monitored.
+    #[serde(default)]
+    pub stream_keep_alive_timeout: Option<u64>,
+    /// Time, in milliseconds, for which the router keeps the accounts it forwards packets to
+    /// in memory instead of loading them from the store for each packet. Disabled if 0
+    #[serde(default)]
+    pub route_account_cache_ttl: u64,
+    /// Time, in milliseconds, after their expiry that incoming packets are still accepted,
+    /// to tolerate peers whose clocks are behind the node's
+    #[serde(default)]
+    pub clock_skew_tolerance: u64,
+    /// Size, in bytes, of the largest BTP message the node asks its peers to send. ILP packets
+    /// which do not fit are split into multiple messages if the peer supports it. Disabled if 0
+    #[serde(default)]
+    pub btp_max_message_size: usize,
+    /// Time, in milliseconds, for which the responses to the idempotent settlement API
+    /// requests are kept, so that retried requests are not executed twice
+    #[serde(default = "default_idempotency_key_ttl")]
+    pub idempotency_key_ttl: u64,
+    /// Configuration for periodically saving the node's volatile state (such as the exchange rates
+    /// and the routes learned from peers) to a file, which is restored when the node starts.
+    /// If this configuration is not provided, no snapshots are taken.
+    #[serde(default)]
+    pub snapshot: Option<SnapshotConfig>,
+    /// Configuration for watching a config file and applying the changes to the spread, the
+    /// route broadcast interval and the settlement delay it contains while the node is running.
+    /// These settings can also be changed via the `/config` API.
+    /// If this configuration is not provided, no file is watched.
+    #[serde(default)]
+    pub config_reload: Option<ConfigReloadConfig>,
+    /// Configuration for keeping a history of each account's balance.
+    /// If this configuration is not provided, no history is kept.
+    #[serde(default)]
+    pub balance_history: Option<BalanceHistoryConfig>,
+    /// Connection tag prefixes mapped to the sub-accounts their payments are credited to
+    #[serde(default)]
+    pub tag_dispatch: Vec<TagDispatchConfig>,
+    /// Configuration for batching the balance updates made once packets are fulfilled or
+    /// rejected. If this configuration is not provided, each update is applied separately.
+    #[cfg(feature = "redis")]
+    #[serde(default)]
+    pub balance_batching: Option<BalanceBatchingConfig>,
+    /// Configuration for replicating the node's store to (or from) warm standby nodes.
+    /// If this configuration is not provided, the store is not replicated.
+    #[cfg(feature = "redis")]
+    #[serde(default)]
+    pub replication: Option<ReplicationConfig>,
 }
 
 impl InterledgerNode {
@@ -263,9 +734,32 @@ impl InterledgerNode {
     // TODO when a BTP connection is made, insert a outgoing HTTP entry into the Store to tell other
     // connector instances to forward packets for that account to us
     pub async fn serve(self, log_writer: Option<LogWriter>) -> Result<(), ()> {
+        self.serve_with_embedding(log_writer, Embedding::default())
+            .await
+    }
+
+    /// Runs the node like `serve`, but only returns once the node stopped, after it was
+    /// asked to terminate (`SIGTERM`) and drained its packets. Returns an error if the node
+    /// could not be started or its HTTP API failed.
+    pub async fn serve_until_stopped(self, log_writer: Option<LogWriter>) -> Result<(), ()> {
+        let (stopped_tx, stopped_rx) = oneshot::channel();
+        let embedding = Embedding {
+            stopped: Some(stopped_tx),
+            ..Default::default()
+        };
+        self.serve_with_embedding(log_writer, embedding).await?;
+        // The sender is dropped without notifying if the HTTP API failed
+        stopped_rx.await.map_err(|_| ())
+    }
+
+    async fn serve_with_embedding(
+        self,
+        log_writer: Option<LogWriter>,
+        embedding: Embedding,
+    ) -> Result<(), ()> {
         cfg_if! {
             if #[cfg(feature = "monitoring")] {
-                let f = futures::future::join(serve_prometheus(self.clone()), self.serve_node(log_writer)).then(
+                let f = futures::future::join(serve_prometheus(self.clone()), self.serve_embedded(log_writer, embedding)).then(
                     |r| async move {
                         if r.0.is_ok() || r.1.is_ok() {
                             Ok(())
@@ -275,19 +769,32 @@ impl InterledgerNode {
                     },
                 );
             } else {
-                let f = self.serve_node(log_writer);
+                let f = self.serve_embedded(log_writer, embedding);
             }
         }
 
         f.await
     }
 
-    async fn serve_node(self, log_writer: Option<LogWriter>) -> Result<(), ()> {
-        let ilp_address = if let Some(address) = &self.ilp_address {
-            address.clone()
-        } else {
-            DEFAULT_ILP_ADDRESS.clone()
-        };
+    /// The ILP address of the node, until it is configured by its parent
+    pub(crate) fn initial_ilp_address(&self) -> Address {
+        self.ilp_address
+            .clone()
+            .unwrap_or_else(|| DEFAULT_ILP_ADDRESS.clone())
+    }
+
+    /// Connects to the store at the `database_url` and runs the node on it
+    pub(crate) async fn serve_embedded(
+        self,
+        log_writer: Option<LogWriter>,
+        embedding: Embedding,
+    ) -> Result<(), ()> {
+        let ilp_address = self.initial_ilp_address();
+        // The stores are configured with it before the node's services are chained
+        if self.idempotency_key_ttl == 0 {
+            error!(target: "interledger-node", "idempotency_key_ttl must be greater than 0");
+            return Err(());
+        }
 
         // TODO: store a Url directly in InterledgerNode rather than a String?
         let database_url = match Url::parse(&self.database_url) {
@@ -303,7 +810,11 @@ impl InterledgerNode {
 
         match database_url.scheme() {
             #[cfg(feature = "redis")]
-            "redis" | "redis+unix" => serve_redis_node(self, ilp_address, log_writer).await,
+            "redis" | "redis+unix" => {
+                serve_redis_node(self, ilp_address, log_writer, embedding).await
+            }
+            #[cfg(feature = "memory")]
+            "memory" => serve_memory_node(self, ilp_address, log_writer, embedding).await,
             other => {
                 error!("unsupported data source scheme: {}", other);
                 Err(())
@@ -317,28 +828,10 @@ impl InterledgerNode {
         store: S,
         ilp_address: Address,
         _log_writer: Option<LogWriter>,
+        embedding: Embedding,
     ) -> Result<(), ()>
     where
-        S: NodeStore<Account = Account>
-            + AddressStore
-            + BtpStore<Account = Account>
-            + HttpStore<Account = Account>
-            + StreamNotificationsStore<Account = Account>
-            + BalanceStore
-            + SettlementStore<Account = Account>
-            + ExchangeRateStore
-            + BalanceStore
-            + SettlementStore<Account = Account>
-            + RouterStore<Account = Account>
-            + CcpRoutingStore<Account = Account>
-            + RateLimitStore<Account = Account>
-            + LeftoversStore<AccountId = Uuid, AssetType = BigUint>
-            + IdempotentStore
-            + AccountStore<Account = Account>
-            + Clone
-            + Send
-            + Sync
-            + 'static,
+        S: ConnectorStore,
     {
         debug!(target: "interledger-node",
             "Starting Interledger node with ILP address: {}",
@@ -348,6 +841,8 @@ impl InterledgerNode {
         let secret_seed = Bytes::copy_from_slice(&self.secret_seed[..]);
         let http_bind_address = self.http_bind_address;
         let settlement_api_bind_address = self.settlement_api_bind_address;
+        let clearing_only = self.clearing_only;
+        let assign_child_addresses = self.assign_child_addresses;
         let admin_auth_token = self.admin_auth_token.clone();
         let default_spsp_account = self.default_spsp_account.clone();
         let route_broadcast_interval = self.route_broadcast_interval;
@@ -355,15 +850,256 @@ impl InterledgerNode {
         let exchange_rate_poll_interval = self.exchange_rate.poll_interval;
         let exchange_rate_poll_failure_tolerance = self.exchange_rate.poll_failure_tolerance;
         let exchange_rate_spread = self.exchange_rate.spread;
+        let exchange_rate_max_age = self.exchange_rate.max_age.map(Duration::from_millis);
+        let dust_credit_interval = self
+            .exchange_rate
+            .dust_credit_interval
+            .map(Duration::from_millis);
+        let address_scheme_policy = self.address_scheme_policy.clone();
+        let tenant_isolation = self.tenant_isolation.clone();
+        let pipeline = self.pipeline.clone();
+        if let Err(err) = pipeline.validate() {
+            error!(target: "interledger-node", "Invalid pipeline configuration: {}", err);
+            return Err(());
+        }
+        let peer_scoreboard = PeerScoreboard::new(self.peer_scoreboard.clone());
+        let peer_monitor = PeerMonitor::new(self.peer_health.clone());
+        {
+            let peer_monitor = peer_monitor.clone();
+            tokio::spawn(async move { peer_monitor.start_health_checks().await });
+        }
+        // The settings which can be changed while the node is running are sent to the
+        // services through watch channels
+        let reloadable = ReloadableConfig::new(ReloadableSettings {
+            spread: exchange_rate_spread,
+            route_broadcast_interval: route_broadcast_interval
+                .unwrap_or(DEFAULT_BROADCAST_INTERVAL),
+            #[cfg(feature = "balance-tracking")]
+            settle_every: self.settle_every,
+            #[cfg(not(feature = "balance-tracking"))]
+            settle_every: None,
+        });
+        if let Some(config) = self.config_reload.clone() {
+            spawn_config_watcher(config, reloadable.clone())?;
+        }
+        // The balance, settlement and suspension events are only published if they are
+        // notified or exported
+        let events = if self.webhooks.is_some() || self.accounting_export.is_some() {
+            Some(EventBus::new())
+        } else {
+            None
+        };
+        if let (Some(config), Some(events)) = (self.webhooks, events.as_ref()) {
+            let policy = WebhookRetryPolicy {
+                initial_backoff: Duration::from_millis(config.initial_backoff),
+                max_attempts: config.max_attempts,
+            };
+            spawn_webhook_dispatcher(store.clone(), events, policy);
+            publish_suspensions(&peer_scoreboard, store.clone(), events.clone());
+        }
+        if let (Some(config), Some(events)) = (self.accounting_export.as_ref(), events.as_ref()) {
+            match config.sink {
+                AccountingSinkKind::Store => {
+                    spawn_accounting_exporter(store.clone(), store.clone(), events);
+                }
+                AccountingSinkKind::File => {
+                    let path = match config.path {
+                        Some(ref path) => path,
+                        None => {
+                            error!(target: "interledger-node", "The file accounting sink requires a path");
+                            return Err(());
+                        }
+                    };
+                    match FileAccountingSink::open(path, config.format) {
+                        Ok(sink) => {
+                            spawn_accounting_exporter(store.clone(), sink, events);
+                        }
+                        Err(err) => {
+                            error!(target: "interledger-node", "Unable to open the accounting ledger file {}: {}", path.display(), err);
+                            return Err(());
+                        }
+                    }
+                }
+            }
+        }
+        let next_hops = self.next_hops.clone();
+        let route_loop_protection = self.route_loop_protection;
+        let reject_stream_data = self.reject_stream_data;
+        let stream_replay_protection = self.stream_replay_protection;
+        let stream_dampening = match self.stream_reject_dampening {
+            Some(config) => {
+                if config.max_failures == 0 {
+                    error!(target: "interledger-node", "stream_reject_dampening.max_failures must be greater than 0");
+                    return Err(());
+                }
+                if config.window == 0 {
+                    error!(target: "interledger-node", "stream_reject_dampening.window must be greater than 0");
+                    return Err(());
+                }
+                Some(DampeningPolicy {
+                    max_failures: config.max_failures,
+                    window: Duration::from_millis(config.window),
+                })
+            }
+            None => None,
+        };
+        let keep_alive_monitor = match self.stream_keep_alive_timeout {
+            Some(0) => {
+                error!(target: "interledger-node", "stream_keep_alive_timeout must be greater than 0");
+                return Err(());
+            }
+            Some(timeout) => {
+                // The monitor logs and counts the broken connections, which the receiver
+                // has no other state to clean up for
+                let monitor = KeepAliveMonitor::new(Duration::from_millis(timeout), |_| {});
+                let checks = monitor.clone();
+                tokio::spawn(async move { checks.start_checks().await });
+                Some(monitor)
+            }
+            None => None,
+        };
+        let route_account_cache_ttl = Duration::from_millis(self.route_account_cache_ttl);
+        let clock_skew_tolerance = Duration::from_millis(self.clock_skew_tolerance);
+        let btp_max_message_size = self.btp_max_message_size;
+        let http_client_config = self.http_client.clone();
+        let http_server_config = self.http_server.clone();
+        let drain_timeout = Duration::from_millis(self.shutdown.drain_timeout);
+        // Without a drain timeout or another node taking over the listeners, the termination
+        // signal is left to terminate the process right away
+        let handle_sigterm = embedding.shutdown.is_none()
+            && (http_server_config.reuse_port || self.shutdown.drain_timeout > 0);
+        let embedded = embedding.shutdown.is_some();
+        let shutdown = embedding.shutdown.unwrap_or_default();
+        let stopped = embedding.stopped;
+        let tag_routes: Vec<TagRoute> = self
+            .tag_dispatch
+            .iter()
+            .map(|config| TagRoute {
+                account: config.account.clone(),
+                tag_prefix: config.tag_prefix.clone(),
+                sub_account: config.sub_account.clone(),
+            })
+            .collect();
         #[cfg(feature = "google-pubsub")]
         let google_pubsub = self.google_pubsub.clone();
+        #[cfg(feature = "packet-mirroring")]
+        let packet_mirroring = self.packet_mirroring.clone();
+
+        // Restore the state from before the restart before the node starts exchanging
+        // routes with its peers and polling for rates, so that those take precedence
+        let snapshot_config = self.snapshot.clone();
+        if let Some(ref snapshot) = snapshot_config {
+            if snapshot.interval == 0 {
+                error!(target: "interledger-node", "snapshot.interval must be greater than 0");
+                return Err(());
+            }
+            restore_snapshot(snapshot, store.clone()).await;
+            spawn_snapshots(snapshot.clone(), store.clone(), shutdown.clone());
+        }
+
+        if let Some(config) = self.balance_history {
+            if config.interval == 0 {
+                error!(target: "interledger-node", "balance_history.interval must be greater than 0");
+                return Err(());
+            }
+            if config.capacity == 0 {
+                error!(target: "interledger-node", "balance_history.capacity must be greater than 0");
+                return Err(());
+            }
+            start_balance_history(
+                store.clone(),
+                Duration::from_millis(config.interval),
+                config.capacity,
+            );
+        }
+
+        if clearing_only {
+            #[cfg(feature = "balance-tracking")]
+            {
+                if self.settle_every.is_some() || self.settlement_scheduler.is_some() {
+                    error!(target: "interledger-node", "settle_every and settlement_scheduler cannot be configured on a node which only clears");
+                    return Err(());
+                }
+            }
+            if self.settlement_reconciliation.is_some() {
+                error!(target: "interledger-node", "settlement_reconciliation cannot be configured on a node which only clears");
+                return Err(());
+            }
+            // Settling accounts would be left without anything to settle them
+            let settling_accounts: Vec<String> = store
+                .get_all_accounts()
+                .map_err(
+                    |err| error!(target: "interledger-node", "Error getting accounts: {}", err),
+                )
+                .await?
+                .iter()
+                .filter(|account| account.settlement_engine_details().is_some())
+                .map(|account| account.username().to_string())
+                .collect();
+            if !settling_accounts.is_empty() {
+                error!(target: "interledger-node",
+                    "The node only clears, but these accounts have a settlement engine: {}",
+                    settling_accounts.join(", ")
+                );
+                return Err(());
+            }
+        }
+
+        #[cfg(feature = "balance-tracking")]
+        let settlement_scheduler = match self.settlement_scheduler {
+            Some(config) => {
+                let policy = RetryPolicy {
+                    initial_backoff: Duration::from_millis(config.initial_backoff),
+                    max_backoff: Duration::from_millis(config.max_backoff),
+                    max_attempts: config.max_attempts,
+                };
+                let scheduler =
+                    SettlementScheduler::start(store.clone(), SettlementClient::default(), policy)
+                        .map_err(|err| error!(target: "interledger-node", "Error loading the pending settlements: {}", err))
+                        .await?;
+                Some(match events {
+                    Some(ref events) => scheduler.with_event_bus(events.clone()),
+                    None => scheduler,
+                })
+            }
+            None => None,
+        };
+
+        let reconciler = match self.settlement_reconciliation {
+            Some(config) => {
+                let policy = ReconciliationPolicy {
+                    tolerance: config.tolerance,
+                    auto_correct: config.auto_correct,
+                };
+                let reconciler = Reconciler::new(SettlementClient::default(), policy);
+                spawn_reconciliation(
+                    store.clone(),
+                    reconciler.clone(),
+                    Duration::from_millis(config.interval),
+                )?;
+                Some(reconciler)
+            }
+            None => None,
+        };
+
+        let dust_ledger = match dust_credit_interval {
+            Some(interval) => {
+                let dust_ledger = DustLedger::new(store.clone());
+                spawn_dust_credits(store.clone(), dust_ledger.clone(), interval)?;
+                Some(dust_ledger)
+            }
+            None => None,
+        };
+
+        // Switches for stopping and starting parts of the node at runtime
+        let mut subsystems = Subsystems::default();
 
         let btp_accounts = store
             .get_btp_outgoing_accounts()
             .map_err(|_| error!(target: "interledger-node", "Error getting accounts"))
             .await?;
 
-        let outgoing_service = outgoing_service_fn({
+        let reject_unroutable = outgoing_service_fn({
             let ilp_address = ilp_address.clone();
             move |request: OutgoingRequest<Account>| {
                 // Don't log anything for failed route updates sent to child accounts
@@ -388,55 +1124,185 @@ impl InterledgerNode {
                 .build())
             }
         });
+        let outgoing_service = embedding
+            .fallback
+            .unwrap_or_else(|| BoxedOutgoingService::new(reject_unroutable));
 
         // Connect to all of the accounts that have outgoing ilp_over_btp_urls configured
         // but don't fail if we are unable to connect
-        // TODO try reconnecting to those accounts later
-        let btp_client_service =
-            connect_client(ilp_address.clone(), btp_accounts, false, outgoing_service)
-                .map_err(|err| error!("{}", err))
-                .await?;
+        let btp_client_service = BtpOutgoingService::new(ilp_address.clone(), outgoing_service)
+            .with_fragmentation(btp_max_message_size);
+        connect_accounts(&btp_client_service, btp_accounts.clone(), false)
+            .map_err(|err| error!("{}", err))
+            .await?;
+        // Re-establish the connections to those accounts whenever they drop
+        reconnect_clients(
+            btp_client_service.clone(),
+            store.clone(),
+            btp_accounts,
+            ReconnectBackoff::default(),
+        );
         let btp_server_service =
-            BtpOutgoingService::new(ilp_address.clone(), btp_client_service.clone());
+            BtpOutgoingService::new(ilp_address.clone(), btp_client_service.clone())
+                .with_fragmentation(btp_max_message_size);
         let btp_server_service_clone = btp_server_service.clone();
+        // Closed once the node finished draining
+        let btp_server = btp_server_service.clone();
         let btp = btp_client_service.clone();
 
         // The BTP service is both an Incoming and Outgoing one so we pass it first as the Outgoing
         // service to others like the router and then call handle_incoming on it to set up the incoming handler
         let outgoing_service = btp_server_service.clone();
-        let outgoing_service = HttpClientService::new(store.clone(), outgoing_service);
+        let outgoing_service = HttpClientService::new(store.clone(), outgoing_service)
+            .with_config(&http_client_config);
+        // Sends the packets to the accounts with a `ws://` or `wss://` ILP over HTTP URL
+        // over a WebSocket connection instead
+        let outgoing_service = WsClientService::new(store.clone(), outgoing_service);
+        let ws_connections = outgoing_service.connections();
+        // Records the outcome of the packets sent to the peers, whichever link they are sent over
+        let outgoing_service =
+            PeerMonitorService::new(peer_monitor.clone(), store.clone(), outgoing_service);
+        monitor_btp_connectivity(
+            &peer_monitor,
+            vec![
+                btp_server_service.connections(),
+                btp_client_service.connections(),
+            ],
+        );
+        // Translates the destinations of the packets sent to the peers which expect them to be
+        // addressed under their own prefix, whichever link they are sent over
+        let outgoing_service = AddressTranslationService::new(store.clone(), outgoing_service);
+        #[cfg(feature = "monitoring")]
+        let outgoing_service = outgoing_service.wrap(trace_outgoing_layer("outgoing"));
 
         #[cfg(feature = "monitoring")]
         let outgoing_service = outgoing_service.wrap(outgoing_metrics);
 
-        // Note: the expiry shortener must come after the Validator so that the expiry duration
-        // is shortened before we check whether there is enough time left
-        let outgoing_service = ValidatorService::outgoing(store.clone(), outgoing_service);
-        let outgoing_service = ExpiryShortenerService::new(outgoing_service);
-        let outgoing_service =
-            StreamReceiverService::new(secret_seed.clone(), store.clone(), outgoing_service);
-
-        #[cfg(feature = "balance-tracking")]
-        let outgoing_service = match self.settle_every {
-            Some(seconds) => {
-                use futures::stream::StreamExt;
-                let delay = Duration::from_secs(seconds.get().into());
-                let (tx, rx) = tokio::sync::mpsc::channel(128);
+        // The stages are chained from the last one packets go through to the first one.
+        // See `PipelineConfig` for the orderings which are required
+        let mut outgoing_service: BoxedOutgoingService<Account> =
+            BoxedOutgoingService::new(outgoing_service);
+        for stage in pipeline.outgoing.iter().rev() {
+            outgoing_service = match stage {
+                OutgoingStage::PeerScoreboard => {
+                    BoxedOutgoingService::new(PeerScoreboardService::new(
+                        peer_scoreboard.clone(),
+                        store.clone(),
+                        outgoing_service,
+                    ))
+                }
+                OutgoingStage::Validator => BoxedOutgoingService::new(ValidatorService::outgoing(
+                    store.clone(),
+                    outgoing_service,
+                )),
+                OutgoingStage::ExpiryShortener => {
+                    BoxedOutgoingService::new(ExpiryShortenerService::new(outgoing_service))
+                }
+                OutgoingStage::StreamReceiver => {
+                    let mut receiver = StreamReceiverService::new(
+                        secret_seed.clone(),
+                        store.clone(),
+                        outgoing_service,
+                    )
+                    .with_reject_data(reject_stream_data)
+                    .with_receive_store(store.clone());
+                    if stream_replay_protection {
+                        receiver = receiver.with_replay_protection(store.clone());
+                    }
+                    if let Some(policy) = stream_dampening {
+                        receiver = receiver.with_reject_dampening(policy);
+                    }
+                    if let Some(ref monitor) = keep_alive_monitor {
+                        receiver = receiver.with_keep_alive_monitor(monitor.clone());
+                    }
+                    BoxedOutgoingService::new(receiver)
+                }
+                OutgoingStage::TagDispatch => BoxedOutgoingService::new(TagDispatchService::new(
+                    secret_seed.clone(),
+                    tag_routes.clone(),
+                    store.clone(),
+                    outgoing_service,
+                )),
+                #[cfg(feature = "balance-tracking")]
+                OutgoingStage::Balance => {
+                    let balance_service = match self.settle_every {
+                        Some(_) => {
+                            use futures::stream::StreamExt;
+                            let (tx, rx) = tokio::sync::mpsc::channel(128);
 
-                start_delayed_settlement(delay, rx.fuse(), store.clone());
+                            start_delayed_settlement(
+                                reloadable.settle_every_updates(),
+                                rx.fuse(),
+                                store.clone(),
+                                settlement_scheduler.clone(),
+                                events.clone(),
+                                subsystems
+                                    .register(Subsystem::SettlementPoller)
+                                    .running_flag(),
+                                shutdown.settlement_tasks(),
+                            );
 
-                BalanceService::new(store.clone(), Some(tx), outgoing_service)
-            }
-            None => BalanceService::new(store.clone(), None, outgoing_service),
-        };
+                            BalanceService::new(store.clone(), Some(tx), outgoing_service)
+                        }
+                        None => BalanceService::new(store.clone(), None, outgoing_service),
+                    };
+                    let balance_service = match settlement_scheduler {
+                        Some(ref scheduler) => {
+                            balance_service.with_settlement_scheduler(scheduler.clone())
+                        }
+                        None => balance_service,
+                    }
+                    .with_settlement_tasks(shutdown.settlement_tasks());
+                    let balance_service = if clearing_only {
+                        balance_service.with_clearing_only()
+                    } else {
+                        balance_service
+                    };
+                    let balance_service = match events {
+                        Some(ref events) => balance_service.with_event_bus(events.clone()),
+                        None => balance_service,
+                    };
+                    #[cfg(feature = "monitoring")]
+                    let balance_service = balance_service.wrap(trace_outgoing_layer("balance"));
+                    BoxedOutgoingService::new(balance_service)
+                }
+                #[cfg(not(feature = "balance-tracking"))]
+                OutgoingStage::Balance => outgoing_service,
+                OutgoingStage::Usage => {
+                    BoxedOutgoingService::new(UsageService::new(store.clone(), outgoing_service))
+                }
+                OutgoingStage::ExchangeRate => {
+                    let exchange_rate_service = ExchangeRateService::new(
+                        exchange_rate_spread,
+                        store.clone(),
+                        outgoing_service,
+                    )
+                    .with_max_rate_age(exchange_rate_max_age)
+                    .with_spread_updates(reloadable.spread_updates());
+                    let exchange_rate_service = match dust_ledger {
+                        Some(ref dust_ledger) => {
+                            exchange_rate_service.with_dust_ledger(dust_ledger.clone())
+                        }
+                        None => exchange_rate_service,
+                    };
+                    #[cfg(feature = "monitoring")]
+                    let exchange_rate_service =
+                        exchange_rate_service.wrap(trace_outgoing_layer("exchange_rate"));
+                    BoxedOutgoingService::new(exchange_rate_service)
+                }
+            };
+        }
 
-        let outgoing_service =
-            ExchangeRateService::new(exchange_rate_spread, store.clone(), outgoing_service);
+        // Counts the requests in flight, which are waited for when the node shuts down
+        let outgoing_service = DrainOutgoingService::new(shutdown.clone(), outgoing_service);
 
         #[cfg(feature = "google-pubsub")]
         let outgoing_service =
             outgoing_service.wrap(create_google_pubsub_wrapper(google_pubsub).await);
 
+        #[cfg(feature = "packet-mirroring")]
+        let outgoing_service = outgoing_service.wrap(create_mirroring_wrapper(packet_mirroring));
+
         // Add tracing to add the outgoing request details to the incoming span
         cfg_if! {
             if #[cfg(feature = "monitoring")] {
@@ -449,7 +1315,12 @@ impl InterledgerNode {
         }
 
         // Set up the Router and Routing Manager
-        let incoming_service = Router::new(store.clone(), outgoing_service_fwd);
+        let incoming_service = Router::new(store.clone(), outgoing_service_fwd)
+            .with_next_hops(next_hops)
+            .with_loop_protection(route_loop_protection)
+            .with_account_cache(route_account_cache_ttl);
+        #[cfg(feature = "monitoring")]
+        let incoming_service = incoming_service.wrap(trace_incoming_layer("router"));
 
         // Add tracing to track the outgoing request details
         #[cfg(feature = "monitoring")]
@@ -462,17 +1333,107 @@ impl InterledgerNode {
             incoming_service,
         );
         ccp_builder.ilp_address(ilp_address.clone());
-        if let Some(ms) = route_broadcast_interval {
-            ccp_builder.broadcast_interval(ms);
-        }
+        ccp_builder.broadcast_interval_updates(reloadable.route_broadcast_interval_updates());
+        ccp_builder.broadcast_enabled(
+            subsystems
+                .register(Subsystem::CcpBroadcaster)
+                .running_flag(),
+        );
 
         let incoming_service = ccp_builder.to_service();
-        let incoming_service = EchoService::new(store.clone(), incoming_service);
-        let incoming_service = SettlementMessageService::new(incoming_service);
-        let incoming_service = IldcpService::new(incoming_service);
-        let incoming_service = MaxPacketAmountService::new(store.clone(), incoming_service);
-        let incoming_service = ValidatorService::incoming(store.clone(), incoming_service);
-        let incoming_service = RateLimitService::new(store.clone(), incoming_service);
+        let route_expiries = incoming_service.route_expiries();
+        // Withdraws the routes advertised by the peers while the monitor deems them unhealthy
+        let mut health_changes = peer_monitor.health_changes();
+        let ccp = incoming_service.clone();
+        tokio::spawn(async move {
+            while let Some(change) = health_changes.next().await {
+                if let Err(err) = ccp
+                    .set_peer_healthy(change.account_id, change.healthy)
+                    .await
+                {
+                    error!(target: "interledger-node", "Error updating the routes of peer {}: {}", change.account_id, err)
+                }
+            }
+        });
+        record_peer_events(
+            &peer_scoreboard,
+            incoming_service.route_flaps(),
+            PeerEvent::RouteFlap,
+        );
+        record_peer_events(
+            &peer_scoreboard,
+            btp_server_service.connections().auth_failures(),
+            PeerEvent::AuthFailure,
+        );
+        let mut incoming_service: BoxedIncomingService<Account> =
+            BoxedIncomingService::new(incoming_service);
+        for stage in pipeline.incoming.iter().rev() {
+            incoming_service = match stage {
+                IncomingStage::Echo => {
+                    BoxedIncomingService::new(EchoService::new(store.clone(), incoming_service))
+                }
+                IncomingStage::SettlementMessages => {
+                    let settlement_message_service =
+                        SettlementMessageService::new(incoming_service);
+                    // The scheduler also retries the messages the engines failed to receive
+                    #[cfg(feature = "balance-tracking")]
+                    let settlement_message_service = match settlement_scheduler {
+                        Some(ref scheduler) => {
+                            settlement_message_service.with_outbox(scheduler.clone())
+                        }
+                        None => settlement_message_service,
+                    };
+                    BoxedIncomingService::new(settlement_message_service)
+                }
+                IncomingStage::Ildcp => {
+                    if assign_child_addresses {
+                        BoxedIncomingService::new(DynamicIldcpService::new(
+                            store.clone(),
+                            incoming_service,
+                        ))
+                    } else {
+                        BoxedIncomingService::new(IldcpService::new(incoming_service))
+                    }
+                }
+                IncomingStage::SchemePolicy => BoxedIncomingService::new(SchemePolicyService::new(
+                    address_scheme_policy.clone(),
+                    store.clone(),
+                    incoming_service,
+                )),
+                IncomingStage::TenantIsolation => {
+                    BoxedIncomingService::new(TenantIsolationService::new(
+                        tenant_isolation.clone(),
+                        store.clone(),
+                        incoming_service,
+                    ))
+                }
+                IncomingStage::MaxPacketAmount => BoxedIncomingService::new(
+                    MaxPacketAmountService::new(store.clone(), incoming_service),
+                ),
+                IncomingStage::Dedup => {
+                    BoxedIncomingService::new(DedupService::new(store.clone(), incoming_service))
+                }
+                IncomingStage::Validator => BoxedIncomingService::new(
+                    ValidatorService::incoming(store.clone(), incoming_service)
+                        .with_clock_skew_tolerance(clock_skew_tolerance),
+                ),
+                IncomingStage::RateLimit => BoxedIncomingService::new(RateLimitService::new(
+                    store.clone(),
+                    incoming_service,
+                )),
+                IncomingStage::PeerScoreboard => {
+                    BoxedIncomingService::new(PeerScoreboardService::new(
+                        peer_scoreboard.clone(),
+                        store.clone(),
+                        incoming_service,
+                    ))
+                }
+            };
+        }
+
+        // Rejects the packets received once the node is shutting down
+        let incoming_service =
+            DrainIncomingService::new(shutdown.clone(), ilp_address.clone(), incoming_service);
 
         // Add tracing to track the incoming request details
         #[cfg(feature = "monitoring")]
@@ -521,7 +1482,7 @@ impl InterledgerNode {
         // Node HTTP API
         let mut api = NodeApi::new(
             bytes::Bytes::copy_from_slice(secret_seed.as_ref()),
-            admin_auth_token,
+            admin_auth_token.clone(),
             store.clone(),
             incoming_service_api,
             outgoing_service.clone(),
@@ -531,6 +1492,21 @@ impl InterledgerNode {
             api.default_spsp_account(username);
         }
         api.node_version(env!("CARGO_PKG_VERSION").to_string());
+        api.clearing_only(clearing_only);
+        api.route_expiries(route_expiries);
+        api.peer_monitor(peer_monitor);
+        api.peer_scoreboard(peer_scoreboard.clone());
+        api.ws_connections(ws_connections.clone());
+        if let Some(reconciler) = reconciler {
+            api.reconciler(reconciler);
+        }
+        #[cfg(feature = "balance-tracking")]
+        let pending_settlements = {
+            if let Some(ref scheduler) = settlement_scheduler {
+                api.settlement_scheduler(scheduler.clone());
+            }
+            settlement_scheduler
+        };
 
         cfg_if! {
             if #[cfg(feature = "monitoring")] {
@@ -546,19 +1522,36 @@ impl InterledgerNode {
         }
 
         // add an API of ILP over HTTP and add rejection handler
+        let ilp_over_http = subsystems
+            .guard(Subsystem::HttpListener, "ilp")
+            .and(IlpOverHttpServer::new(incoming_service_http.clone(), store.clone()).as_filter());
+        let ilp_over_ws = subsystems.guard(Subsystem::HttpListener, "ilp/ws").and(
+            IlpOverWsServer::new(incoming_service_http, store.clone())
+                .with_connections(ws_connections.clone())
+                .as_filter(),
+        );
+        let ilp_over_btp =
+            subsystems
+                .guard(Subsystem::BtpListener, "ilp/btp")
+                .and(btp_service_as_filter(
+                    btp_server_service_clone,
+                    store.clone(),
+                ));
         let api = api
             .into_warp_filter()
-            .or(IlpOverHttpServer::new(incoming_service_http, store.clone()).as_filter())
-            .or(btp_service_as_filter(
-                btp_server_service_clone,
-                store.clone(),
-            ));
+            .or(ilp_over_http)
+            .or(ilp_over_ws)
+            .or(ilp_over_btp)
+            .or(subsystems.into_warp_filter(admin_auth_token.clone()))
+            .or(reloadable.into_warp_filter(admin_auth_token));
 
         // If monitoring is enabled, run a tracing subscriber
         // and expose a new endpoint at /tracing-level which allows
-        // changing the tracing level by administrators
+        // changing the tracing level by administrators, and one at
+        // /metrics which serves the Prometheus metrics
         cfg_if! {
             if #[cfg(feature = "monitoring")] {
+                let metrics = metrics_filter(self.admin_auth_token.clone());
                 let admin_only = warp::header::<SecretString>("authorization")
                     .and_then(move |authorization: SecretString| {
                         let admin_auth_header = format!("Bearer {}", self.admin_auth_token.clone());
@@ -595,7 +1588,7 @@ impl InterledgerNode {
                                             ApiError::bad_request().detail("could not parse body as log level")
                                         })?;
 
-                                    let curr_env = handle.with_current(|env| env.to_string()).unwrap();
+                                    let curr_env = handle.current().unwrap();
                                     let new_env = curr_env.parse::<EnvFilter>().unwrap().add_directive(new_level);
 
                                     handle.reload(new_env).map_err(|err| {
@@ -611,7 +1604,7 @@ impl InterledgerNode {
                             },
                         );
 
-                    api.or(adjust_tracing)
+                    api.or(adjust_tracing).or(metrics)
                 };
             }
         }
@@ -621,13 +1614,94 @@ impl InterledgerNode {
             .with(warp::log("interledger-api"))
             .boxed();
 
-        info!(target: "interledger-node", "Interledger.rs node HTTP API listening on: {}", http_bind_address);
-        spawn(warp::serve(api).bind(http_bind_address));
+        let listener = bind_http(http_bind_address, &http_server_config)
+            .map_err(|err| error!(target: "interledger-node", "Error binding the HTTP API to {}: {}", http_bind_address, err))?;
+        info!(target: "interledger-node", "Interledger.rs node HTTP API listening on: {}", listener.local_addr().unwrap_or(http_bind_address));
+        let settlement_api_listener = if clearing_only {
+            None
+        } else {
+            let listener = bind_socket(settlement_api_bind_address, http_server_config.reuse_port)
+                .map_err(|err| error!(target: "interledger-node", "Error binding the settlement API to {}: {}", settlement_api_bind_address, err))?;
+            Some(listener)
+        };
+        // Start draining the node once it is asked to terminate, unless the application
+        // embedding it decides when it stops
+        if handle_sigterm {
+            spawn({
+                let shutdown = shutdown.clone();
+                async move {
+                    shutdown_signal().await;
+                    shutdown.start();
+                }
+            });
+        }
+        let settlement_api_config = http_server_config.clone();
+        let settlement_api_shutdown = shutdown.clone();
+        let snapshot_store = store.clone();
+        spawn(async move {
+            let shutdown_grace_period =
+                Duration::from_millis(http_server_config.shutdown_grace_period);
+            match serve_http(api, listener, &http_server_config, shutdown.started()).await {
+                Ok(()) => {
+                    // The in-flight HTTP requests were answered, wait for the packets sent to
+                    // the peers and for the settlements in progress before closing the
+                    // BTP connections
+                    info!(target: "interledger-node", "HTTP API stopped accepting connections, draining for up to {:?}", drain_timeout);
+                    #[cfg(feature = "balance-tracking")]
+                    let settlements_pending = || {
+                        pending_settlements
+                            .as_ref()
+                            .map(|scheduler| !scheduler.pending_settlements().is_empty())
+                            .unwrap_or(false)
+                    };
+                    #[cfg(not(feature = "balance-tracking"))]
+                    let settlements_pending = || false;
+                    if shutdown.drain(drain_timeout, settlements_pending).await {
+                        info!(target: "interledger-node", "All packets and settlements in progress completed");
+                    }
+                    // Save the state the node learned since the last periodic snapshot
+                    if let Some(ref config) = snapshot_config {
+                        save_snapshot(config, &snapshot_store).await;
+                    }
+                    btp_server.close();
+                    btp.close();
+                    ws_connections.close_all();
+                    if !embedded {
+                        info!(target: "interledger-node", "Closed the BTP and ILP over WebSocket connections, stopping in {:?}", shutdown_grace_period);
+                        tokio::time::delay_for(shutdown_grace_period).await;
+                    }
+                    info!(target: "interledger-node", "The node stopped");
+                    if let Some(stopped) = stopped {
+                        let _ = stopped.send(());
+                    }
+                }
+                Err(err) => {
+                    error!(target: "interledger-node", "Error serving the HTTP API: {}", err);
+                }
+            }
+        });
 
         // Settlement API
-        let settlement_api = create_settlements_filter(store.clone(), outgoing_service.clone());
-        info!(target: "interledger-node", "Settlement API listening on: {}", settlement_api_bind_address);
-        spawn(warp::serve(settlement_api).bind(settlement_api_bind_address));
+        spawn_idempotent_data_expiry(store.clone(), IDEMPOTENT_DATA_EXPIRY_INTERVAL);
+        if let Some(listener) = settlement_api_listener {
+            let settlement_api =
+                create_settlements_filter(store.clone(), outgoing_service.clone(), events.clone());
+            info!(target: "interledger-node", "Settlement API listening on: {}", settlement_api_bind_address);
+            spawn(async move {
+                if let Err(err) = serve_http(
+                    settlement_api,
+                    listener,
+                    &settlement_api_config,
+                    settlement_api_shutdown.started(),
+                )
+                .await
+                {
+                    error!(target: "interledger-node", "Error serving the settlement API: {}", err);
+                }
+            });
+        } else {
+            info!(target: "interledger-node", "Not serving the settlement API since the node only clears");
+        }
 
         // Exchange Rate Polling
         if let Some(provider) = exchange_rate_provider {
@@ -646,15 +1720,180 @@ impl InterledgerNode {
     }
 }
 
+/// Resolves once the node is asked to terminate (SIGTERM), which is how a new version of the
+/// node started on the same (`SO_REUSEPORT` or socket-activated) listener hands off traffic.
+/// The node then drains the packets in progress before exiting
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            terminate.recv().await;
+        }
+        Err(err) => {
+            error!(target: "interledger-node", "Error listening for SIGTERM, the HTTP API will not shut down gracefully: {}", err);
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    if tokio::signal::ctrl_c().await.is_err() {
+        futures::future::pending::<()>().await;
+    }
+}
+
+/// Periodically reconciles the settlements of all of the accounts
+fn spawn_reconciliation<S>(store: S, reconciler: Reconciler, interval: Duration) -> Result<(), ()>
+where
+    S: NodeStore<Account = Account>
+        + SettlementStore<Account = Account>
+        + SettlementHistoryStore
+        + Send
+        + Sync
+        + 'static,
+{
+    if interval == Duration::from_millis(0) {
+        error!(target: "interledger-node", "settlement_reconciliation.interval must be greater than 0");
+        return Err(());
+    }
+    debug!(target: "interledger-node", "Reconciling the settlements every {:?}", interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match store.get_all_accounts().await {
+                Ok(accounts) => {
+                    reconciler.reconcile(&store, &accounts).await;
+                }
+                Err(err) => {
+                    error!(target: "interledger-node", "Error loading the accounts to reconcile their settlements: {}", err)
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Periodically credits the dust lost converting the accounts' packets back to their balances
+fn spawn_dust_credits<S>(store: S, dust_ledger: DustLedger, interval: Duration) -> Result<(), ()>
+where
+    S: NodeStore<Account = Account> + SettlementStore<Account = Account> + Send + Sync + 'static,
+{
+    if interval == Duration::from_millis(0) {
+        error!(target: "interledger-node", "exchange_rate.dust_credit_interval must be greater than 0");
+        return Err(());
+    }
+    debug!(target: "interledger-node", "Crediting the conversion dust every {:?}", interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match store.get_all_accounts().await {
+                Ok(accounts) => {
+                    let account_ids: Vec<Uuid> =
+                        accounts.iter().map(|account| account.id()).collect();
+                    dust_ledger.credit(&store, &account_ids).await;
+                }
+                Err(err) => {
+                    error!(target: "interledger-node", "Error loading the accounts to credit their conversion dust: {}", err)
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Records each of the usernames the stream yields as the given event in the scoreboard
+fn record_peer_events(
+    scoreboard: &PeerScoreboard,
+    usernames: impl Stream<Item = Username> + Send + 'static,
+    event: PeerEvent,
+) {
+    let scoreboard = scoreboard.clone();
+    tokio::spawn(usernames.for_each(move |username| {
+        scoreboard.record(&username, event);
+        future::ready(())
+    }));
+}
+
+/// Records the BTP connections which close or are re-established as the connectivity of
+/// their peers
+fn monitor_btp_connectivity(monitor: &PeerMonitor, connections: Vec<BtpConnections>) {
+    let disconnects = futures::stream::select_all(connections.iter().map(|c| c.disconnects()));
+    let monitor_clone = monitor.clone();
+    tokio::spawn(disconnects.for_each(move |account_id| {
+        monitor_clone.record_connectivity(account_id, false);
+        future::ready(())
+    }));
+
+    // The connections do not notify when they are opened, so they are checked periodically
+    let monitor = monitor.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(BTP_CONNECTIVITY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for (account_id, state) in connections.iter().flat_map(BtpConnections::all) {
+                if state.connected {
+                    monitor.record_connectivity(account_id, true);
+                }
+            }
+        }
+    });
+}
+
+/// Publishes the suspensions of peers by the scoreboard as events of their accounts
+fn publish_suspensions<S>(scoreboard: &PeerScoreboard, store: S, events: EventBus)
+where
+    S: AccountStore<Account = Account> + Clone + Send + Sync + 'static,
+{
+    let mut changes = scoreboard.status_changes();
+    tokio::spawn(async move {
+        while let Some(change) = changes.next().await {
+            let until = match change.status {
+                PeerStatus::Suspended { until } => until,
+                _ => continue,
+            };
+            match store.get_account_id_from_username(&change.username).await {
+                Ok(account_id) => events.publish(AccountEvent::AccountSuspended {
+                    account_id,
+                    reason: change.reason,
+                    duration_ms: until.saturating_duration_since(Instant::now()).as_millis() as u64,
+                }),
+                Err(err) => {
+                    error!(target: "interledger-node", "Error loading the suspended account {}: {}", change.username, err)
+                }
+            }
+        }
+    });
+}
+
 cfg_if! {
     if #[cfg(feature = "monitoring")] {
-        type TracingSubscriber =
-            Formatter<format::DefaultFields, format::Format<format::Full, ChronoUtc>, NonBlocking>;
+        /// Reloads the log filter, whichever format the subscriber uses
+        pub trait FilterHandle: Send + Sync {
+            fn current(&self) -> Result<String, reload::Error>;
+            fn reload(&self, filter: EnvFilter) -> Result<(), reload::Error>;
+        }
+
+        impl<S> FilterHandle for Handle<EnvFilter, S>
+        where
+            S: tracing::Subscriber + 'static,
+        {
+            fn current(&self) -> Result<String, reload::Error> {
+                self.with_current(|filter| filter.to_string())
+            }
+
+            fn reload(&self, filter: EnvFilter) -> Result<(), reload::Error> {
+                Handle::reload(self, filter)
+            }
+        }
 
         #[derive(Clone)]
         pub struct LogWriter {
             stdout:     Arc<Stdout>,
-            pub handle: Option<Handle<EnvFilter, TracingSubscriber>>,
+            pub handle: Option<Arc<dyn FilterHandle>>,
         }
 
         impl Default for LogWriter {
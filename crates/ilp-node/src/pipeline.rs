@@ -0,0 +1,400 @@
+use async_trait::async_trait;
+use interledger::service::{
+    Account, IlpResult, IncomingRequest, IncomingService, OutgoingRequest, OutgoingService,
+};
+use serde::Deserialize;
+use std::{collections::HashSet, fmt, hash::Hash};
+
+/// The services packets go through when they are received from a peer, in that order,
+/// before they are routed by CCP and the router
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IncomingStage {
+    /// Rejects the packets from the peers which are throttled or suspended
+    PeerScoreboard,
+    /// Enforces the accounts' packets and amount per minute limits
+    RateLimit,
+    /// Rejects the expired packets
+    Validator,
+    /// Answers the duplicates of the packets being or already forwarded with the original's
+    /// response. Not part of the default pipeline, since it looks up every packet in the store
+    Dedup,
+    /// Enforces the accounts' maximum packet amount
+    MaxPacketAmount,
+    /// Confines the tenants' accounts to their own prefixes
+    TenantIsolation,
+    /// Only forwards the packets to the allowed address schemes
+    SchemePolicy,
+    /// Answers the ILDCP requests of the child accounts
+    Ildcp,
+    /// Passes the messages of the peers' settlement engines to ours
+    SettlementMessages,
+    /// Answers the echo requests sent to the node
+    Echo,
+}
+
+/// The services packets go through after they were routed, in that order,
+/// before they are sent to the peer over HTTP or BTP
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutgoingStage {
+    /// Converts the amounts to the outgoing account's asset
+    ExchangeRate,
+    /// Counts the packets and amounts sent and received by each account
+    Usage,
+    /// Updates the balances and triggers the settlements. Ignored unless the node is
+    /// built with the `balance-tracking` feature
+    Balance,
+    /// Credits the tagged STREAM payments to the sub-accounts
+    TagDispatch,
+    /// Receives the STREAM payments to the node's own accounts
+    StreamReceiver,
+    /// Shortens the expiry of the packets which are forwarded
+    ExpiryShortener,
+    /// Rejects the packets which expire too soon and the invalid fulfillments
+    Validator,
+    /// Keeps track of the peers' fulfill and reject rates
+    PeerScoreboard,
+}
+
+/// Which of the node's services packets go through and in what order, so that
+/// deployments can leave the optional ones out. Checked by
+/// [`validate`](#method.validate) before the node starts.
+///
+/// The stages are listed in the order packets go through them. The transports,
+/// CCP and the router are always part of the pipeline.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PipelineConfig {
+    /// The services incoming packets go through before they are routed
+    #[serde(default = "PipelineConfig::default_incoming")]
+    pub incoming: Vec<IncomingStage>,
+    /// The services packets go through after they were routed
+    #[serde(default = "PipelineConfig::default_outgoing")]
+    pub outgoing: Vec<OutgoingStage>,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig {
+            incoming: Self::default_incoming(),
+            outgoing: Self::default_outgoing(),
+        }
+    }
+}
+
+/// Stages which must be part of the pipeline, since the node would be unsafe without them
+const REQUIRED_INCOMING: &[IncomingStage] = &[IncomingStage::Validator];
+const REQUIRED_OUTGOING: &[OutgoingStage] = &[
+    OutgoingStage::ExchangeRate,
+    OutgoingStage::Balance,
+    OutgoingStage::Validator,
+];
+
+/// Stages which packets must go through before others, and why
+const INCOMING_ORDER: &[(IncomingStage, IncomingStage, &str)] = &[
+    (
+        IncomingStage::PeerScoreboard,
+        IncomingStage::RateLimit,
+        "suspended peers must not use up their rate limit",
+    ),
+    (
+        IncomingStage::Validator,
+        IncomingStage::Dedup,
+        "expired packets must be rejected before the node handles them",
+    ),
+    (
+        IncomingStage::Validator,
+        IncomingStage::Ildcp,
+        "expired packets must be rejected before the node handles them",
+    ),
+    (
+        IncomingStage::Validator,
+        IncomingStage::SettlementMessages,
+        "expired packets must be rejected before the node handles them",
+    ),
+    (
+        IncomingStage::Validator,
+        IncomingStage::Echo,
+        "expired packets must be rejected before the node handles them",
+    ),
+];
+const OUTGOING_ORDER: &[(OutgoingStage, OutgoingStage, &str)] = &[
+    (
+        OutgoingStage::ExchangeRate,
+        OutgoingStage::Balance,
+        "the outgoing account must be credited with the converted amount",
+    ),
+    (
+        OutgoingStage::Balance,
+        OutgoingStage::TagDispatch,
+        "the payments to the node's own accounts must be credited",
+    ),
+    (
+        OutgoingStage::Balance,
+        OutgoingStage::StreamReceiver,
+        "the payments to the node's own accounts must be credited",
+    ),
+    (
+        OutgoingStage::TagDispatch,
+        OutgoingStage::StreamReceiver,
+        "the tagged payments must be seen before they are fulfilled",
+    ),
+    (
+        OutgoingStage::ExpiryShortener,
+        OutgoingStage::Validator,
+        "the shortened expiry must leave enough time to forward the packet",
+    ),
+    (
+        OutgoingStage::Validator,
+        OutgoingStage::PeerScoreboard,
+        "the scoreboard must see the invalid fulfillments",
+    ),
+];
+
+impl PipelineConfig {
+    fn default_incoming() -> Vec<IncomingStage> {
+        vec![
+            IncomingStage::PeerScoreboard,
+            IncomingStage::RateLimit,
+            IncomingStage::Validator,
+            IncomingStage::MaxPacketAmount,
+            IncomingStage::TenantIsolation,
+            IncomingStage::SchemePolicy,
+            IncomingStage::Ildcp,
+            IncomingStage::SettlementMessages,
+            IncomingStage::Echo,
+        ]
+    }
+
+    fn default_outgoing() -> Vec<OutgoingStage> {
+        vec![
+            OutgoingStage::ExchangeRate,
+            OutgoingStage::Usage,
+            OutgoingStage::Balance,
+            OutgoingStage::TagDispatch,
+            OutgoingStage::StreamReceiver,
+            OutgoingStage::ExpiryShortener,
+            OutgoingStage::Validator,
+            OutgoingStage::PeerScoreboard,
+        ]
+    }
+
+    /// Checks that no stage is listed twice, that the required ones are there
+    /// and that the stages packets must go through first come before the others
+    pub fn validate(&self) -> Result<(), PipelineError> {
+        validate_stages(&self.incoming, REQUIRED_INCOMING, INCOMING_ORDER)?;
+        validate_stages(&self.outgoing, REQUIRED_OUTGOING, OUTGOING_ORDER)
+    }
+}
+
+fn validate_stages<T>(
+    stages: &[T],
+    required: &[T],
+    order: &[(T, T, &'static str)],
+) -> Result<(), PipelineError>
+where
+    T: Copy + Eq + Hash + fmt::Debug,
+{
+    let mut seen = HashSet::new();
+    if let Some(stage) = stages.iter().find(|stage| !seen.insert(**stage)) {
+        return Err(PipelineError::Duplicate(stage_name(stage)));
+    }
+    if let Some(stage) = required.iter().find(|stage| !stages.contains(stage)) {
+        return Err(PipelineError::Missing(stage_name(stage)));
+    }
+    let position = |stage: T| stages.iter().position(|other| *other == stage);
+    for &(first, then, reason) in order {
+        if let (Some(first_position), Some(then_position)) = (position(first), position(then)) {
+            if first_position > then_position {
+                return Err(PipelineError::Order {
+                    first: stage_name(&first),
+                    then: stage_name(&then),
+                    reason,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The name of the stage in the configuration, e.g. `stream_receiver`
+fn stage_name<T: fmt::Debug>(stage: &T) -> String {
+    let mut name = String::new();
+    for c in format!("{:?}", stage).chars() {
+        if c.is_ascii_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+/// Why a pipeline configuration was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum PipelineError {
+    /// The stage is listed more than once
+    Duplicate(String),
+    /// The stage is required but not listed
+    Missing(String),
+    /// The stages are listed in an unsafe order
+    Order {
+        first: String,
+        then: String,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineError::Duplicate(stage) => write!(f, "{} is listed more than once", stage),
+            PipelineError::Missing(stage) => write!(f, "{} is required", stage),
+            PipelineError::Order {
+                first,
+                then,
+                reason,
+            } => write!(f, "{} must come before {}: {}", first, then, reason),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+trait CloneIncoming<A: Account>: IncomingService<A> + Send + Sync {
+    fn box_clone(&self) -> Box<dyn CloneIncoming<A>>;
+}
+
+impl<A, T> CloneIncoming<A> for T
+where
+    A: Account + 'static,
+    T: IncomingService<A> + Clone + Send + Sync + 'static,
+{
+    fn box_clone(&self) -> Box<dyn CloneIncoming<A>> {
+        Box::new(self.clone())
+    }
+}
+
+/// Incoming service whose type is erased, so that the stages can be chained
+/// in the order they are configured in
+pub(crate) struct BoxedIncomingService<A: Account>(Box<dyn CloneIncoming<A>>);
+
+impl<A: Account + 'static> BoxedIncomingService<A> {
+    pub(crate) fn new<I>(service: I) -> Self
+    where
+        I: IncomingService<A> + Clone + Send + Sync + 'static,
+    {
+        BoxedIncomingService(Box::new(service))
+    }
+}
+
+impl<A: Account> Clone for BoxedIncomingService<A> {
+    fn clone(&self) -> Self {
+        BoxedIncomingService(self.0.box_clone())
+    }
+}
+
+#[async_trait]
+impl<A: Account + 'static> IncomingService<A> for BoxedIncomingService<A> {
+    async fn handle_request(&mut self, request: IncomingRequest<A>) -> IlpResult {
+        self.0.handle_request(request).await
+    }
+}
+
+trait CloneOutgoing<A: Account>: OutgoingService<A> + Send + Sync {
+    fn box_clone(&self) -> Box<dyn CloneOutgoing<A>>;
+}
+
+impl<A, T> CloneOutgoing<A> for T
+where
+    A: Account + 'static,
+    T: OutgoingService<A> + Clone + Send + Sync + 'static,
+{
+    fn box_clone(&self) -> Box<dyn CloneOutgoing<A>> {
+        Box::new(self.clone())
+    }
+}
+
+/// Outgoing service whose type is erased, so that the stages can be chained
+/// in the order they are configured in
+pub(crate) struct BoxedOutgoingService<A: Account>(Box<dyn CloneOutgoing<A>>);
+
+impl<A: Account + 'static> BoxedOutgoingService<A> {
+    pub(crate) fn new<O>(service: O) -> Self
+    where
+        O: OutgoingService<A> + Clone + Send + Sync + 'static,
+    {
+        BoxedOutgoingService(Box::new(service))
+    }
+}
+
+impl<A: Account> Clone for BoxedOutgoingService<A> {
+    fn clone(&self) -> Self {
+        BoxedOutgoingService(self.0.box_clone())
+    }
+}
+
+#[async_trait]
+impl<A: Account + 'static> OutgoingService<A> for BoxedOutgoingService<A> {
+    async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
+        self.0.send_request(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_pipeline_is_valid() {
+        assert_eq!(PipelineConfig::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn optional_stages_can_be_left_out() {
+        let config: PipelineConfig = serde_json::from_value(serde_json::json!({
+            "incoming": ["validator", "echo"],
+        }))
+        .unwrap();
+        assert_eq!(
+            config.incoming,
+            vec![IncomingStage::Validator, IncomingStage::Echo]
+        );
+        assert_eq!(config.outgoing, PipelineConfig::default_outgoing());
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unsafe_pipelines() {
+        let mut config = PipelineConfig::default();
+        config
+            .outgoing
+            .retain(|stage| *stage != OutgoingStage::Validator);
+        assert_eq!(
+            config.validate(),
+            Err(PipelineError::Missing("validator".to_string()))
+        );
+
+        let mut config = PipelineConfig::default();
+        config.incoming.push(IncomingStage::RateLimit);
+        assert_eq!(
+            config.validate(),
+            Err(PipelineError::Duplicate("rate_limit".to_string()))
+        );
+
+        // Balance updates after the packets to local accounts were fulfilled
+        let config = PipelineConfig {
+            outgoing: vec![
+                OutgoingStage::ExchangeRate,
+                OutgoingStage::StreamReceiver,
+                OutgoingStage::Balance,
+                OutgoingStage::Validator,
+            ],
+            ..PipelineConfig::default()
+        };
+        let err = config.validate().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "balance must come before stream_receiver: the payments to the node's own accounts must be credited"
+        );
+    }
+}
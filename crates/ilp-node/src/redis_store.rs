@@ -1,16 +1,21 @@
 #![cfg(feature = "redis")]
 
-use crate::node::{InterledgerNode, LogWriter};
+use crate::node::{Embedding, InterledgerNode, LogWriter};
 use futures::TryFutureExt;
 pub use interledger::{
     api::{AccountDetails, NodeStore},
     packet::Address,
     service::Account,
-    store::redis::RedisStoreBuilder,
+    store::redis::{
+        replication::{follow_replication_events, ship_replication_events},
+        RedisStoreBuilder,
+    },
 };
 pub use redis_crate::{ConnectionInfo, IntoConnectionInfo};
 use ring::hmac;
-use tracing::error;
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::{error, info};
 
 static REDIS_SECRET_GENERATION_STRING: &str = "ilp_redis_secret";
 
@@ -18,6 +23,55 @@ pub fn default_redis_url() -> String {
     String::from("redis://127.0.0.1:6379")
 }
 
+fn default_balance_batch_window() -> u64 {
+    1
+}
+
+fn default_max_balance_batch_size() -> usize {
+    100
+}
+
+/// Configuration for batching the balance updates made once packets are fulfilled or rejected
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct BalanceBatchingConfig {
+    /// Time, in milliseconds, during which balance updates are collected into a batch
+    #[serde(default = "default_balance_batch_window")]
+    pub window: u64,
+    /// Maximum number of balance updates in a batch
+    #[serde(default = "default_max_balance_batch_size")]
+    pub max_batch_size: usize,
+}
+
+fn default_replication_stream_key() -> String {
+    String::from("replication")
+}
+
+/// Whether the node's store changes are shipped to standby nodes or applied from a primary
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicationRole {
+    Primary,
+    Standby,
+}
+
+/// Configuration for replicating a primary node's store to warm standby nodes
+/// over a Redis stream
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ReplicationConfig {
+    /// Whether this node is the primary or a standby
+    pub role: ReplicationRole,
+    /// URL of the Redis instance holding the stream. Defaults to the node's `database_url`
+    #[serde(default)]
+    pub stream_url: Option<String>,
+    /// Key of the stream
+    #[serde(default = "default_replication_stream_key")]
+    pub stream_key: String,
+    /// (Standby only) Id of the last stream entry which was applied to this node's store.
+    /// If not set, all of the entries in the stream are applied
+    #[serde(default)]
+    pub last_applied_id: Option<String>,
+}
+
 // This function could theoretically be defined as an inherent method on InterledgerNode itself.
 // However, we define it in this module in order to consolidate conditionally-compiled code
 // into as few discrete units as possible.
@@ -25,17 +79,59 @@ pub async fn serve_redis_node(
     node: InterledgerNode,
     ilp_address: Address,
     log_writer: Option<LogWriter>,
+    embedding: Embedding,
 ) -> Result<(), ()> {
     let redis_connection_info = node.database_url.clone().into_connection_info().unwrap();
     let redis_addr = redis_connection_info.addr.clone();
     let redis_secret = generate_redis_secret(&node.secret_seed);
-    let store = RedisStoreBuilder::new(redis_connection_info, redis_secret)
+    let mut builder = RedisStoreBuilder::new(redis_connection_info, redis_secret);
+    builder
         .with_db_prefix(node.database_prefix.as_str())
         .node_ilp_address(ilp_address.clone())
+        .idempotency_key_ttl(Duration::from_millis(node.idempotency_key_ttl));
+    if let Some(old_prefix) = &node.migrate_database_prefix_from {
+        builder.migrate_from_db_prefix(old_prefix);
+    }
+    if let Some(batching) = node.balance_batching {
+        builder.balance_batching(
+            Duration::from_millis(batching.window),
+            batching.max_batch_size,
+        );
+    }
+    let store = builder
         .connect()
         .map_err(move |err| error!(target: "interledger-node", "Error connecting to Redis: {:?} {:?}", redis_addr, err))
         .await?;
-    node.chain_services(store, ilp_address, log_writer).await
+
+    if let Some(replication) = &node.replication {
+        let stream_url = replication
+            .stream_url
+            .as_ref()
+            .unwrap_or(&node.database_url)
+            .as_str()
+            .into_connection_info()
+            .map_err(|err| error!(target: "interledger-node", "Invalid replication stream URL: {:?}", err))?;
+        let stream_key = replication.stream_key.clone();
+        match replication.role {
+            ReplicationRole::Primary => {
+                info!(target: "interledger-node", "Shipping store changes to standby nodes via stream: {}", stream_key);
+                ship_replication_events(&store, stream_url, stream_key).await?;
+            }
+            ReplicationRole::Standby => {
+                info!(target: "interledger-node", "Following store changes of the primary node via stream: {}", stream_key);
+                follow_replication_events(
+                    store.clone(),
+                    stream_url,
+                    stream_key,
+                    replication.last_applied_id.clone(),
+                )
+                .await?;
+            }
+        }
+    }
+
+    node.chain_services(store, ilp_address, log_writer, embedding)
+        .await
 }
 
 pub fn generate_redis_secret(secret_seed: &[u8; 32]) -> [u8; 32] {
@@ -0,0 +1,422 @@
+use config::{Config, ConfigError};
+use interledger::{errors::ApiError, service_util::is_valid_spread};
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::sync::watch;
+use tracing::{debug, error, info};
+use warp::{filters::BoxedFilter, Filter, Rejection};
+
+fn default_reload_interval() -> u64 {
+    5000
+}
+
+/// Configuration for watching a config file and applying the changes to the settings
+/// which can be changed while the node is running
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ConfigReloadConfig {
+    /// File which is watched, usually the one the node was started with
+    pub path: PathBuf,
+    /// Interval, in milliseconds, at which the file is checked for changes
+    #[serde(default = "default_reload_interval")]
+    pub interval: u64,
+}
+
+/// The settings which can be changed while the node is running
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct ReloadableSettings {
+    /// The `exchange_rate.spread` charged on the packets of the accounts without their own
+    pub spread: f64,
+    /// The `route_broadcast_interval`, in milliseconds, which also determines how long
+    /// our peers keep our routes without hearing from us
+    pub route_broadcast_interval: u64,
+    /// The `settle_every` delay, in seconds, if delayed settlement is enabled
+    pub settle_every: Option<NonZeroU32>,
+}
+
+/// Changes to some of the reloadable settings
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SettingsUpdate {
+    #[serde(default)]
+    pub spread: Option<f64>,
+    #[serde(default)]
+    pub route_broadcast_interval: Option<u64>,
+    #[serde(default)]
+    pub settle_every: Option<NonZeroU32>,
+}
+
+impl SettingsUpdate {
+    /// Reads the reloadable settings which are set in the config file
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let mut config = Config::new();
+        config.merge(config::File::from(path))?;
+        Ok(SettingsUpdate {
+            spread: optional(config.get("exchange_rate.spread"))?,
+            route_broadcast_interval: optional(config.get("route_broadcast_interval"))?,
+            settle_every: optional(config.get("settle_every"))?,
+        })
+    }
+}
+
+fn optional<T>(value: Result<T, ConfigError>) -> Result<Option<T>, ConfigError> {
+    match value {
+        Ok(value) => Ok(Some(value)),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+struct Channels {
+    settings: Mutex<ReloadableSettings>,
+    spread: watch::Sender<f64>,
+    spread_updates: watch::Receiver<f64>,
+    route_broadcast_interval: watch::Sender<u64>,
+    route_broadcast_interval_updates: watch::Receiver<u64>,
+    settle_every: watch::Sender<Duration>,
+    settle_every_updates: watch::Receiver<Duration>,
+}
+
+/// Sends the changes of the reloadable settings to the services using them, through a
+/// watch channel per setting. Cloning it is cheap and all clones share the same channels
+#[derive(Clone)]
+pub struct ReloadableConfig {
+    channels: Arc<Channels>,
+}
+
+impl ReloadableConfig {
+    pub fn new(settings: ReloadableSettings) -> Self {
+        let (spread, spread_updates) = watch::channel(settings.spread);
+        let (route_broadcast_interval, route_broadcast_interval_updates) =
+            watch::channel(settings.route_broadcast_interval);
+        let (settle_every, settle_every_updates) =
+            watch::channel(settle_delay(settings.settle_every));
+        ReloadableConfig {
+            channels: Arc::new(Channels {
+                settings: Mutex::new(settings),
+                spread,
+                spread_updates,
+                route_broadcast_interval,
+                route_broadcast_interval_updates,
+                settle_every,
+                settle_every_updates,
+            }),
+        }
+    }
+
+    pub fn settings(&self) -> ReloadableSettings {
+        *self.channels.settings.lock().unwrap()
+    }
+
+    pub fn spread_updates(&self) -> watch::Receiver<f64> {
+        self.channels.spread_updates.clone()
+    }
+
+    pub fn route_broadcast_interval_updates(&self) -> watch::Receiver<u64> {
+        self.channels.route_broadcast_interval_updates.clone()
+    }
+
+    pub fn settle_every_updates(&self) -> watch::Receiver<Duration> {
+        self.channels.settle_every_updates.clone()
+    }
+
+    /// Validates the update and sends the settings it changes to the services,
+    /// returning the settings in effect afterwards. Nothing is changed if it is invalid
+    pub fn apply(&self, update: SettingsUpdate) -> Result<ReloadableSettings, String> {
+        let mut settings = self.channels.settings.lock().unwrap();
+        let mut updated = *settings;
+        if let Some(spread) = update.spread {
+            if !is_valid_spread(spread) {
+                return Err(format!(
+                    "the spread must be at least 0 and below 1: {}",
+                    spread
+                ));
+            }
+            updated.spread = spread;
+        }
+        if let Some(interval) = update.route_broadcast_interval {
+            if interval == 0 {
+                return Err("route_broadcast_interval must be greater than 0".to_owned());
+            }
+            updated.route_broadcast_interval = interval;
+        }
+        if let Some(settle_every) = update.settle_every {
+            // The delayed settlements are only started if they are configured at startup
+            if settings.settle_every.is_none() {
+                return Err(
+                    "settle_every can only be changed if it was set when the node started"
+                        .to_owned(),
+                );
+            }
+            updated.settle_every = Some(settle_every);
+        }
+
+        if updated.spread != settings.spread {
+            info!(target: "interledger-node", "Changing the spread from {} to {}", settings.spread, updated.spread);
+            let _ = self.channels.spread.broadcast(updated.spread);
+        }
+        if updated.route_broadcast_interval != settings.route_broadcast_interval {
+            info!(target: "interledger-node",
+                "Changing the route broadcast interval from {}ms to {}ms",
+                settings.route_broadcast_interval, updated.route_broadcast_interval
+            );
+            let _ = self
+                .channels
+                .route_broadcast_interval
+                .broadcast(updated.route_broadcast_interval);
+        }
+        if updated.settle_every != settings.settle_every {
+            info!(target: "interledger-node", "Changing the settlement delay to {:?}", settle_delay(updated.settle_every));
+            let _ = self
+                .channels
+                .settle_every
+                .broadcast(settle_delay(updated.settle_every));
+        }
+        *settings = updated;
+        Ok(updated)
+    }
+
+    /// Admin-only routes to get the reloadable settings and to change them:
+    /// - `GET /config`
+    /// - `PUT /config`, with the settings to change
+    ///
+    /// The changes made through the API are lost when the node restarts
+    pub fn into_warp_filter(self, admin_auth_token: String) -> BoxedFilter<(impl warp::Reply,)> {
+        let admin_only = warp::header::<SecretString>("authorization")
+            .and_then(move |authorization: SecretString| {
+                let admin_auth_header = format!("Bearer {}", admin_auth_token);
+                async move {
+                    if authorization.expose_secret() == &admin_auth_header {
+                        Ok::<(), Rejection>(())
+                    } else {
+                        Err(Rejection::from(ApiError::unauthorized()))
+                    }
+                }
+            })
+            .untuple_one()
+            .boxed();
+        let with_config = warp::any().map(move || self.clone()).boxed();
+
+        let get_config = warp::get()
+            .and(warp::path("config"))
+            .and(warp::path::end())
+            .and(admin_only.clone())
+            .and(with_config.clone())
+            .map(|config: ReloadableConfig| warp::reply::json(&config.settings()));
+
+        let put_config = warp::put()
+            .and(warp::path("config"))
+            .and(warp::path::end())
+            .and(admin_only)
+            .and(with_config)
+            .and(warp::body::json())
+            .and_then(
+                |config: ReloadableConfig, update: SettingsUpdate| async move {
+                    let settings = config
+                        .apply(update)
+                        .map_err(|err| ApiError::bad_request().detail(err))?;
+                    Ok::<_, Rejection>(warp::reply::json(&settings))
+                },
+            );
+
+        get_config.or(put_config).boxed()
+    }
+}
+
+/// The delay of the delayed settlements, which are not started if it is not set
+fn settle_delay(settle_every: Option<NonZeroU32>) -> Duration {
+    settle_every
+        .map(|seconds| Duration::from_secs(seconds.get().into()))
+        .unwrap_or_default()
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Checks the config file for changes on the configured interval and applies the reloadable
+/// settings it contains. The changes to the other settings only take effect once the node
+/// restarts
+pub fn spawn_config_watcher(
+    config: ConfigReloadConfig,
+    reloadable: ReloadableConfig,
+) -> Result<(), ()> {
+    if config.interval == 0 {
+        error!(target: "interledger-node", "config_reload.interval must be greater than 0");
+        return Err(());
+    }
+    tokio::spawn(async move {
+        let mut last_modified = modified_at(&config.path);
+        let mut interval = tokio::time::interval(Duration::from_millis(config.interval));
+        loop {
+            interval.tick().await;
+            let modified = modified_at(&config.path);
+            if modified.is_none() || modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+            debug!(target: "interledger-node", "Reloading the config file {}", config.path.display());
+            let result = SettingsUpdate::from_file(&config.path)
+                .map_err(|err| err.to_string())
+                .and_then(|update| reloadable.apply(update));
+            if let Err(err) = result {
+                error!(target: "interledger-node",
+                    "Not applying the changes to the config file {}: {}",
+                    config.path.display(),
+                    err
+                );
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger::errors::default_rejection_handler;
+    use std::io::Write;
+    use warp::http::StatusCode;
+
+    const AUTH: &str = "Bearer admin";
+
+    fn reloadable() -> ReloadableConfig {
+        ReloadableConfig::new(ReloadableSettings {
+            spread: 0.0,
+            route_broadcast_interval: 30000,
+            settle_every: NonZeroU32::new(10),
+        })
+    }
+
+    #[test]
+    fn sends_the_changed_settings() {
+        let config = reloadable();
+        let spread = config.spread_updates();
+        let settle_every = config.settle_every_updates();
+        assert_eq!(*settle_every.borrow(), Duration::from_secs(10));
+
+        let settings = config
+            .apply(SettingsUpdate {
+                spread: Some(0.01),
+                settle_every: NonZeroU32::new(60),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(settings.spread, 0.01);
+        assert_eq!(settings.route_broadcast_interval, 30000);
+        assert_eq!(*spread.borrow(), 0.01);
+        assert_eq!(*settle_every.borrow(), Duration::from_secs(60));
+        assert_eq!(config.settings(), settings);
+    }
+
+    #[test]
+    fn rejects_invalid_updates_entirely() {
+        let config = reloadable();
+        let err = config
+            .apply(SettingsUpdate {
+                spread: Some(0.01),
+                route_broadcast_interval: Some(0),
+                ..Default::default()
+            })
+            .unwrap_err();
+        assert_eq!(err, "route_broadcast_interval must be greater than 0");
+        for spread in [-0.1, 1.0, f64::NAN].iter() {
+            assert!(config
+                .apply(SettingsUpdate {
+                    spread: Some(*spread),
+                    ..Default::default()
+                })
+                .is_err());
+        }
+        assert_eq!(config.settings().spread, 0.0);
+        assert_eq!(*config.spread_updates().borrow(), 0.0);
+
+        let without_delayed_settlement = ReloadableConfig::new(ReloadableSettings {
+            settle_every: None,
+            ..config.settings()
+        });
+        assert!(without_delayed_settlement
+            .apply(SettingsUpdate {
+                settle_every: NonZeroU32::new(60),
+                ..Default::default()
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn reads_the_reloadable_settings_from_a_file() {
+        let mut file = tempfile::Builder::new().suffix(".yml").tempfile().unwrap();
+        file.write_all(b"admin_auth_token: admin\nexchange_rate:\n  spread: 0.02\nroute_broadcast_interval: 5000\n")
+            .unwrap();
+        file.flush().unwrap();
+
+        let update = SettingsUpdate::from_file(file.path()).unwrap();
+        assert_eq!(
+            update,
+            SettingsUpdate {
+                spread: Some(0.02),
+                route_broadcast_interval: Some(5000),
+                settle_every: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn only_admin_can_change_the_settings() {
+        let config = reloadable();
+        let api = config
+            .clone()
+            .into_warp_filter("admin".to_owned())
+            .recover(default_rejection_handler);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/config")
+            .header("Authorization", "Bearer wrong")
+            .json(&serde_json::json!({ "spread": 0.05 }))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(config.settings().spread, 0.0);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/config")
+            .header("Authorization", AUTH)
+            .json(&serde_json::json!({ "spread": 0.05 }))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let settings: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(settings["spread"], 0.05);
+        assert_eq!(settings["settle_every"], 10);
+        assert_eq!(*config.spread_updates().borrow(), 0.05);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/config")
+            .header("Authorization", AUTH)
+            .json(&serde_json::json!({ "route_broadcast_interval": 0 }))
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/config")
+            .header("Authorization", AUTH)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let settings: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(settings["route_broadcast_interval"], 30000);
+    }
+}
@@ -0,0 +1,338 @@
+use async_trait::async_trait;
+use interledger::{
+    packet::{Address, ErrorCode, RejectBuilder},
+    service::{
+        Account, IlpResult, IncomingRequest, IncomingService, OutgoingRequest, OutgoingService,
+    },
+    service_util::SettlementTasks,
+};
+use serde::Deserialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{watch, Notify};
+use tracing::{debug, info, warn};
+
+/// How often the settlements in progress are checked while the node drains
+const SETTLEMENTS_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+fn default_drain_timeout() -> u64 {
+    30000
+}
+
+/// Configuration for shutting the node down without stranding the packets it is forwarding
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ShutdownConfig {
+    /// Time, in milliseconds, the node waits for the outgoing requests and the settlements
+    /// in progress to complete before exiting
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            drain_timeout: default_drain_timeout(),
+        }
+    }
+}
+
+/// Coordinates the shutdown of the node: once it starts, the incoming packets are rejected
+/// and the node waits for the outgoing requests already sent to be answered
+#[derive(Clone)]
+pub struct ShutdownCoordinator {
+    draining: Arc<AtomicBool>,
+    in_flight: Arc<AtomicUsize>,
+    /// Notified whenever the last outgoing request in flight completes
+    idle: Arc<Notify>,
+    /// The settlement tasks spawned by the balance service, which do not go through the
+    /// settlement scheduler
+    settlement_tasks: SettlementTasks,
+    started: Arc<watch::Sender<bool>>,
+    started_rx: watch::Receiver<bool>,
+}
+
+impl Default for ShutdownCoordinator {
+    fn default() -> Self {
+        let (started, started_rx) = watch::channel(false);
+        ShutdownCoordinator {
+            draining: Arc::new(AtomicBool::new(false)),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            idle: Arc::new(Notify::new()),
+            settlement_tasks: SettlementTasks::default(),
+            started: Arc::new(started),
+            started_rx,
+        }
+    }
+}
+
+impl ShutdownCoordinator {
+    /// Stops accepting new incoming packets
+    pub fn start(&self) {
+        if !self.draining.swap(true, Ordering::SeqCst) {
+            info!(target: "interledger-node", "Shutting down, rejecting new incoming packets");
+            let _ = self.started.broadcast(true);
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the shutdown started
+    pub async fn started(&self) {
+        let mut started = self.started_rx.clone();
+        while let Some(is_started) = started.recv().await {
+            if is_started {
+                return;
+            }
+        }
+    }
+
+    /// Number of outgoing requests which were sent and not answered yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// The settlement tasks which the node waits for, along with the pending settlements
+    pub fn settlement_tasks(&self) -> SettlementTasks {
+        self.settlement_tasks.clone()
+    }
+
+    fn track(&self) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {
+            coordinator: self.clone(),
+        }
+    }
+
+    /// Waits for the outgoing requests in flight to be answered, and then for the settlement
+    /// tasks to complete and `settlements_pending` to return false. Returns false if they
+    /// did not all complete before the timeout
+    pub async fn drain<F>(&self, timeout: Duration, settlements_pending: F) -> bool
+    where
+        F: Fn() -> bool,
+    {
+        let drained = async {
+            while self.in_flight() > 0 {
+                debug!(target: "interledger-node", "Waiting for {} outgoing requests to complete", self.in_flight());
+                self.idle.notified().await;
+            }
+            while self.settlement_tasks.in_progress() > 0 || settlements_pending() {
+                tokio::time::delay_for(SETTLEMENTS_CHECK_INTERVAL).await;
+            }
+        };
+        match tokio::time::timeout(timeout, drained).await {
+            Ok(()) => true,
+            Err(_) => {
+                warn!(target: "interledger-node",
+                    "Shutting down with {} outgoing requests in flight or settlements pending after {:?}",
+                    self.in_flight(),
+                    timeout
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Counts an outgoing request as in flight until it is dropped
+struct InFlightGuard {
+    coordinator: ShutdownCoordinator,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.coordinator.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.coordinator.idle.notify();
+        }
+    }
+}
+
+/// Incoming Service which rejects the packets with a `T00: Internal Error` once the node
+/// is shutting down, so that the peers retry them with another connector
+#[derive(Clone)]
+pub struct DrainIncomingService<I> {
+    coordinator: ShutdownCoordinator,
+    ilp_address: Address,
+    next: I,
+}
+
+impl<I> DrainIncomingService<I> {
+    pub fn new(coordinator: ShutdownCoordinator, ilp_address: Address, next: I) -> Self {
+        DrainIncomingService {
+            coordinator,
+            ilp_address,
+            next,
+        }
+    }
+}
+
+#[async_trait]
+impl<I, A> IncomingService<A> for DrainIncomingService<I>
+where
+    I: IncomingService<A> + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    async fn handle_request(&mut self, request: IncomingRequest<A>) -> IlpResult {
+        if self.coordinator.is_draining() {
+            return Err(RejectBuilder {
+                code: ErrorCode::T00_INTERNAL_ERROR,
+                message: b"Node is shutting down",
+                triggered_by: Some(&self.ilp_address),
+                data: &[],
+            }
+            .build());
+        }
+        self.next.handle_request(request).await
+    }
+}
+
+/// Outgoing Service which counts the requests in flight, which the node waits for
+/// before exiting
+#[derive(Clone)]
+pub struct DrainOutgoingService<O> {
+    coordinator: ShutdownCoordinator,
+    next: O,
+}
+
+impl<O> DrainOutgoingService<O> {
+    pub fn new(coordinator: ShutdownCoordinator, next: O) -> Self {
+        DrainOutgoingService { coordinator, next }
+    }
+}
+
+#[async_trait]
+impl<O, A> OutgoingService<A> for DrainOutgoingService<O>
+where
+    O: OutgoingService<A> + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
+        let _guard = self.coordinator.track();
+        self.next.send_request(request).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger::packet::{FulfillBuilder, PrepareBuilder};
+    use interledger::service::{incoming_service_fn, outgoing_service_fn, Username};
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+    use std::time::SystemTime;
+    use uuid::Uuid;
+
+    static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+    static NODE_ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("example.node").unwrap());
+
+    #[derive(Clone, Debug)]
+    struct TestAccount;
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            Uuid::nil()
+        }
+
+        fn username(&self) -> &Username {
+            &ALICE
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &NODE_ADDRESS
+        }
+    }
+
+    fn prepare() -> interledger::packet::Prepare {
+        PrepareBuilder {
+            destination: Address::from_str("example.bob").unwrap(),
+            amount: 100,
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+            execution_condition: &[0; 32],
+            data: &[],
+        }
+        .build()
+    }
+
+    #[tokio::test]
+    async fn rejects_incoming_packets_while_draining() {
+        let coordinator = ShutdownCoordinator::default();
+        let mut service = DrainIncomingService::new(
+            coordinator.clone(),
+            NODE_ADDRESS.clone(),
+            incoming_service_fn(|_| {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let request = || IncomingRequest {
+            from: TestAccount,
+            prepare: prepare(),
+        };
+        assert!(service.handle_request(request()).await.is_ok());
+
+        coordinator.start();
+        coordinator.started().await;
+        let reject = service.handle_request(request()).await.unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T00_INTERNAL_ERROR);
+        assert_eq!(reject.triggered_by(), Some(NODE_ADDRESS.clone()));
+    }
+
+    #[tokio::test]
+    async fn waits_for_outgoing_requests_in_flight() {
+        let coordinator = ShutdownCoordinator::default();
+        let service = DrainOutgoingService::new(
+            coordinator.clone(),
+            outgoing_service_fn(|_| {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let guard = coordinator.track();
+        assert!(!coordinator.drain(Duration::from_millis(10), || false).await);
+
+        let mut sender = service.clone();
+        sender
+            .send_request(OutgoingRequest {
+                from: TestAccount,
+                to: TestAccount,
+                original_amount: 100,
+                prepare: prepare(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(coordinator.in_flight(), 1);
+
+        tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            drop(guard);
+        });
+        assert!(coordinator.drain(Duration::from_secs(5), || false).await);
+        assert_eq!(coordinator.in_flight(), 0);
+        // Pending settlements are waited for too
+        assert!(!coordinator.drain(Duration::from_millis(10), || true).await);
+        // And so are the settlements which do not go through the scheduler
+        let task = coordinator.settlement_tasks().track();
+        assert!(!coordinator.drain(Duration::from_millis(10), || false).await);
+        drop(task);
+        assert!(coordinator.drain(Duration::from_millis(10), || false).await);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::shutdown::ShutdownCoordinator;
+use interledger::{
+    ccp::CcpRoutingStore,
+    rates::ExchangeRateStore,
+    service::{Account, AccountStore},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, error, info, trace, warn};
+use uuid::Uuid;
+
+/// Version of the snapshot format. Snapshots with another version are not restored
+const SNAPSHOT_VERSION: u32 = 1;
+
+fn default_snapshot_interval() -> u64 {
+    30000
+}
+
+/// Configuration for periodically saving the node's volatile state to a file,
+/// so that it can be restored when the node restarts
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct SnapshotConfig {
+    /// File the snapshots are written to and restored from
+    pub path: PathBuf,
+    /// Interval, in milliseconds, at which snapshots are taken
+    #[serde(default = "default_snapshot_interval")]
+    pub interval: u64,
+    /// Snapshots older than this, in milliseconds, are not restored
+    #[serde(default)]
+    pub max_age: Option<u64>,
+}
+
+/// The state which the node otherwise has to rebuild after a restart
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct NodeSnapshot {
+    version: u32,
+    /// Milliseconds since the Unix epoch
+    taken_at: u64,
+    exchange_rates: HashMap<String, f64>,
+    /// Routes learned from peers, mapped to the id of the account they go through
+    routes: HashMap<String, Uuid>,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn write_snapshot(path: &Path, snapshot: &NodeSnapshot) -> io::Result<()> {
+    let bytes = serde_cbor::to_vec(snapshot)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    // Replace the previous snapshot atomically, so that it is not lost
+    // if the node stops while the new one is being written
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(tmp_path, path)
+}
+
+fn read_snapshot(path: &Path) -> io::Result<Option<NodeSnapshot>> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let snapshot: NodeSnapshot = serde_cbor::from_slice(&bytes)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported snapshot version {}", snapshot.version),
+        ));
+    }
+    Ok(Some(snapshot))
+}
+
+async fn take_snapshot<S, A>(store: &S) -> Result<NodeSnapshot, ()>
+where
+    S: ExchangeRateStore + CcpRoutingStore<Account = A>,
+    A: Account,
+{
+    let exchange_rates = store
+        .get_all_exchange_rates()
+        .map_err(|err| error!(target: "interledger-node", "Error getting exchange rates to snapshot: {}", err))?;
+    let (local_routes, _) = store.get_local_and_configured_routes().await.map_err(
+        |err| error!(target: "interledger-node", "Error getting routes to snapshot: {}", err),
+    )?;
+    Ok(NodeSnapshot {
+        version: SNAPSHOT_VERSION,
+        taken_at: now_millis(),
+        exchange_rates,
+        routes: local_routes
+            .into_iter()
+            .map(|(prefix, account)| (prefix, account.id()))
+            .collect(),
+    })
+}
+
+/// Restores the exchange rates and routes from the last snapshot, if there is one.
+///
+/// The exchange rates are only restored if none were set since the node started, and
+/// routes are only added for prefixes which are not already routed (skipping those
+/// through accounts which no longer exist). Both are then kept up to date as usual,
+/// by polling the rates provider and by exchanging routes with the node's peers.
+pub async fn restore_snapshot<S, A>(config: &SnapshotConfig, mut store: S)
+where
+    S: ExchangeRateStore + CcpRoutingStore<Account = A> + AccountStore<Account = A>,
+    A: Account + Send + Sync + 'static,
+{
+    let snapshot = match read_snapshot(&config.path) {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => {
+            debug!(target: "interledger-node", "No snapshot to restore at {:?}", config.path);
+            return;
+        }
+        Err(err) => {
+            warn!(target: "interledger-node", "Not restoring invalid snapshot at {:?}: {}", config.path, err);
+            return;
+        }
+    };
+    let age = now_millis().saturating_sub(snapshot.taken_at);
+    if let Some(max_age) = config.max_age {
+        if age > max_age {
+            info!(target: "interledger-node", "Not restoring snapshot taken {}ms ago", age);
+            return;
+        }
+    }
+
+    let rates_restored = match store.get_all_exchange_rates() {
+        Ok(rates) if rates.is_empty() => {
+            let count = snapshot.exchange_rates.len();
+            store
+                .set_exchange_rates(snapshot.exchange_rates)
+                .map(|_| count)
+                .unwrap_or_else(|err| {
+                    error!(target: "interledger-node", "Error restoring exchange rates: {}", err);
+                    0
+                })
+        }
+        _ => 0,
+    };
+
+    let mut local_routes = match store.get_local_and_configured_routes().await {
+        Ok((local_routes, _)) => local_routes,
+        Err(err) => {
+            error!(target: "interledger-node", "Error getting the current routes to restore the snapshot: {}", err);
+            return;
+        }
+    };
+    let mut routes_restored = 0;
+    for (prefix, account_id) in snapshot.routes {
+        if local_routes.contains_key(&prefix) {
+            continue;
+        }
+        if let Ok(mut accounts) = store.get_accounts(vec![account_id]).await {
+            local_routes.insert(prefix, accounts.remove(0));
+            routes_restored += 1;
+        }
+    }
+    if routes_restored > 0 {
+        if let Err(err) = store.set_routes(local_routes).await {
+            error!(target: "interledger-node", "Error restoring routes: {}", err);
+            routes_restored = 0;
+        }
+    }
+
+    info!(target: "interledger-node",
+        "Restored {} exchange rates and {} routes from snapshot taken {}ms ago",
+        rates_restored, routes_restored, age
+    );
+}
+
+/// Snapshots the node's state and replaces the previous snapshot with it
+pub async fn save_snapshot<S, A>(config: &SnapshotConfig, store: &S)
+where
+    S: ExchangeRateStore + CcpRoutingStore<Account = A>,
+    A: Account,
+{
+    if let Ok(snapshot) = take_snapshot(store).await {
+        match write_snapshot(&config.path, &snapshot) {
+            Ok(()) => {
+                trace!(target: "interledger-node", "Wrote snapshot to {:?}", config.path)
+            }
+            Err(err) => {
+                error!(target: "interledger-node", "Error writing snapshot to {:?}: {}", config.path, err)
+            }
+        }
+    }
+}
+
+/// Spawns a task which snapshots the node's state at the configured interval, until the
+/// node starts shutting down. The node then saves a last snapshot once it drained, with
+/// [`save_snapshot`](./fn.save_snapshot.html)
+pub fn spawn_snapshots<S, A>(config: SnapshotConfig, store: S, shutdown: ShutdownCoordinator)
+where
+    S: ExchangeRateStore + CcpRoutingStore<Account = A> + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(config.interval));
+        // The first tick completes immediately, before the node has learned anything
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => save_snapshot(&config, &store).await,
+                _ = shutdown.started() => break,
+            }
+        }
+        debug!(target: "interledger-node", "Stopped taking periodic snapshots");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> NodeSnapshot {
+        NodeSnapshot {
+            version: SNAPSHOT_VERSION,
+            taken_at: now_millis(),
+            exchange_rates: vec![("ABC".to_string(), 1.5), ("XYZ".to_string(), 0.01)]
+                .into_iter()
+                .collect(),
+            routes: vec![("example.alice".to_string(), Uuid::new_v4())]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn writes_and_reads_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node.snapshot");
+        assert_eq!(read_snapshot(&path).unwrap(), None);
+
+        let snapshot = snapshot();
+        write_snapshot(&path, &snapshot).unwrap();
+        assert_eq!(read_snapshot(&path).unwrap(), Some(snapshot));
+        // Only the snapshot itself is left
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("node.snapshot");
+        fs::write(&path, b"not a snapshot").unwrap();
+        assert!(read_snapshot(&path).is_err());
+
+        let mut snapshot = snapshot();
+        snapshot.version = SNAPSHOT_VERSION + 1;
+        write_snapshot(&path, &snapshot).unwrap();
+        assert!(read_snapshot(&path).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use interledger::errors::{ApiError, ApiErrorType, ProblemType};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::info;
+use warp::{filters::path::Peek, filters::BoxedFilter, http::StatusCode, Filter, Rejection};
+
+const SUBSYSTEM_STOPPED_TYPE: ApiErrorType = ApiErrorType {
+    r#type: &ProblemType::Default,
+    title: "Subsystem Stopped",
+    status: StatusCode::SERVICE_UNAVAILABLE,
+};
+
+/// The parts of the node which can be stopped and started again while it is running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Subsystem {
+    /// Accepts the incoming BTP connections
+    BtpListener,
+    /// Accepts the ILP over HTTP requests
+    HttpListener,
+    /// Broadcasts our routes to the peers
+    CcpBroadcaster,
+    /// Settles the accounts whose settlement delay expired
+    SettlementPoller,
+}
+
+impl Subsystem {
+    fn as_str(self) -> &'static str {
+        match self {
+            Subsystem::BtpListener => "btp_listener",
+            Subsystem::HttpListener => "http_listener",
+            Subsystem::CcpBroadcaster => "ccp_broadcaster",
+            Subsystem::SettlementPoller => "settlement_poller",
+        }
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Subsystem {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "btp_listener" => Ok(Subsystem::BtpListener),
+            "http_listener" => Ok(Subsystem::HttpListener),
+            "ccp_broadcaster" => Ok(Subsystem::CcpBroadcaster),
+            "settlement_poller" => Ok(Subsystem::SettlementPoller),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether a subsystem is running and since when
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SubsystemStatus {
+    pub subsystem: Subsystem,
+    pub running: bool,
+    /// Milliseconds since the Unix epoch at which the subsystem was last started or stopped
+    pub since: u64,
+}
+
+/// Whether the path is `accounts/:username/<route>`
+fn is_account_route(path: &str, route: &str) -> bool {
+    let mut segments = path.trim_matches('/').splitn(3, '/');
+    segments.next() == Some("accounts")
+        && segments
+            .next()
+            .map_or(false, |username| !username.is_empty())
+        && segments.next() == Some(route)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Stops and starts a single subsystem. The subsystem checks the `running` flag
+/// before accepting new work, so the work already in progress is not interrupted
+#[derive(Debug, Clone)]
+pub struct SubsystemHandle {
+    subsystem: Subsystem,
+    running: Arc<AtomicBool>,
+    since: Arc<AtomicU64>,
+}
+
+impl SubsystemHandle {
+    fn new(subsystem: Subsystem) -> Self {
+        SubsystemHandle {
+            subsystem,
+            running: Arc::new(AtomicBool::new(true)),
+            since: Arc::new(AtomicU64::new(now_millis())),
+        }
+    }
+
+    /// The flag the subsystem checks, which is true while it is running
+    pub fn running_flag(&self) -> Arc<AtomicBool> {
+        self.running.clone()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    pub fn status(&self) -> SubsystemStatus {
+        SubsystemStatus {
+            subsystem: self.subsystem,
+            running: self.is_running(),
+            since: self.since.load(Ordering::SeqCst),
+        }
+    }
+
+    pub fn start(&self) -> SubsystemStatus {
+        self.set_running(true)
+    }
+
+    pub fn stop(&self) -> SubsystemStatus {
+        self.set_running(false)
+    }
+
+    fn set_running(&self, running: bool) -> SubsystemStatus {
+        if self.running.swap(running, Ordering::SeqCst) != running {
+            self.since.store(now_millis(), Ordering::SeqCst);
+            info!(target: "interledger-node", "{} {}", if running { "Started" } else { "Stopped" }, self.subsystem);
+        }
+        self.status()
+    }
+}
+
+/// The handles of the subsystems the node is running with
+#[derive(Debug, Clone, Default)]
+pub struct Subsystems {
+    handles: BTreeMap<Subsystem, SubsystemHandle>,
+}
+
+impl Subsystems {
+    /// Adds the subsystem, which starts out running, and returns its handle
+    pub fn register(&mut self, subsystem: Subsystem) -> SubsystemHandle {
+        self.handles
+            .entry(subsystem)
+            .or_insert_with(|| SubsystemHandle::new(subsystem))
+            .clone()
+    }
+
+    pub fn get(&self, subsystem: Subsystem) -> Option<&SubsystemHandle> {
+        self.handles.get(&subsystem)
+    }
+
+    pub fn statuses(&self) -> Vec<SubsystemStatus> {
+        self.handles.values().map(SubsystemHandle::status).collect()
+    }
+
+    /// Registers the listener for the `/accounts/:username/<route>` requests and returns
+    /// a filter which rejects them with a 503 Service Unavailable while it is stopped.
+    /// The other requests are passed through to the following filters
+    pub fn guard(&mut self, subsystem: Subsystem, route: &'static str) -> BoxedFilter<()> {
+        let handle = self.register(subsystem);
+        warp::path::peek()
+            .and_then(move |path: Peek| {
+                let result = if handle.is_running() {
+                    Ok(())
+                } else if is_account_route(path.as_str(), route) {
+                    Err(Rejection::from(
+                        ApiError::from_api_error_type(&SUBSYSTEM_STOPPED_TYPE)
+                            .detail(format!("The {} is stopped", subsystem)),
+                    ))
+                } else {
+                    Err(warp::reject::not_found())
+                };
+                async move { result }
+            })
+            .untuple_one()
+            .boxed()
+    }
+
+    /// Admin-only routes to list the subsystems and to stop and start them:
+    /// - `GET /subsystems`
+    /// - `PUT /subsystems/:subsystem/stop`
+    /// - `PUT /subsystems/:subsystem/start`
+    pub fn into_warp_filter(self, admin_auth_token: String) -> BoxedFilter<(impl warp::Reply,)> {
+        let admin_only = warp::header::<SecretString>("authorization")
+            .and_then(move |authorization: SecretString| {
+                let admin_auth_header = format!("Bearer {}", admin_auth_token);
+                async move {
+                    if authorization.expose_secret() == &admin_auth_header {
+                        Ok::<(), Rejection>(())
+                    } else {
+                        Err(Rejection::from(ApiError::unauthorized()))
+                    }
+                }
+            })
+            .untuple_one()
+            .boxed();
+        let subsystems = Arc::new(self);
+        let with_subsystems = warp::any().map(move || subsystems.clone()).boxed();
+
+        let get_subsystems = warp::get()
+            .and(warp::path("subsystems"))
+            .and(warp::path::end())
+            .and(admin_only.clone())
+            .and(with_subsystems.clone())
+            .map(|subsystems: Arc<Subsystems>| warp::reply::json(&subsystems.statuses()));
+
+        let set_running = warp::put()
+            .and(warp::path("subsystems"))
+            .and(warp::path::param::<String>())
+            .and(warp::path::param::<String>())
+            .and(warp::path::end())
+            .and(admin_only)
+            .and(with_subsystems)
+            .and_then(
+                |subsystem: String, action: String, subsystems: Arc<Subsystems>| async move {
+                    let handle = Subsystem::from_str(&subsystem)
+                        .ok()
+                        .and_then(|subsystem| subsystems.get(subsystem))
+                        .ok_or_else(|| {
+                            ApiError::not_found()
+                                .detail(format!("No subsystem named {}", subsystem))
+                        })?;
+                    let status = match action.as_str() {
+                        "start" => handle.start(),
+                        "stop" => handle.stop(),
+                        _ => return Err(Rejection::from(ApiError::not_found())),
+                    };
+                    Ok::<_, Rejection>(warp::reply::json(&status))
+                },
+            );
+
+        get_subsystems.or(set_running).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger::errors::default_rejection_handler;
+
+    const AUTH: &str = "Bearer admin";
+
+    fn subsystems() -> Subsystems {
+        let mut subsystems = Subsystems::default();
+        subsystems.register(Subsystem::BtpListener);
+        subsystems.register(Subsystem::CcpBroadcaster);
+        subsystems
+    }
+
+    #[test]
+    fn starts_and_stops_subsystems() {
+        let subsystems = subsystems();
+        let handle = subsystems.get(Subsystem::CcpBroadcaster).unwrap();
+        let flag = handle.running_flag();
+        assert!(handle.is_running());
+
+        let status = handle.stop();
+        assert!(!status.running);
+        assert!(!flag.load(Ordering::SeqCst));
+        // Stopping it again doesn't change anything
+        assert_eq!(handle.stop(), status);
+
+        assert!(handle.start().running);
+        assert!(flag.load(Ordering::SeqCst));
+        assert!(subsystems.get(Subsystem::SettlementPoller).is_none());
+    }
+
+    #[tokio::test]
+    async fn guard_rejects_requests_while_stopped() {
+        let mut subsystems = subsystems();
+        let filter = subsystems
+            .guard(Subsystem::HttpListener, "ilp")
+            .map(warp::reply)
+            .recover(default_rejection_handler);
+
+        let resp = warp::test::request()
+            .path("/accounts/alice/ilp")
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        subsystems.get(Subsystem::HttpListener).unwrap().stop();
+        let resp = warp::test::request()
+            .path("/accounts/alice/ilp")
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        // The other routes are left to the following filters
+        let resp = warp::test::request()
+            .path("/accounts/alice/balance")
+            .reply(&filter)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn only_admin_can_manage_subsystems() {
+        let subsystems = subsystems();
+        let api = subsystems
+            .clone()
+            .into_warp_filter("admin".to_owned())
+            .recover(default_rejection_handler);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/subsystems/ccp_broadcaster/stop")
+            .header("Authorization", "Bearer wrong")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert!(subsystems
+            .get(Subsystem::CcpBroadcaster)
+            .unwrap()
+            .is_running());
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/subsystems/ccp_broadcaster/stop")
+            .header("Authorization", AUTH)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let status: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(status["subsystem"], "ccp_broadcaster");
+        assert_eq!(status["running"], false);
+        assert!(!subsystems
+            .get(Subsystem::CcpBroadcaster)
+            .unwrap()
+            .is_running());
+
+        let resp = warp::test::request()
+            .method("GET")
+            .path("/subsystems")
+            .header("Authorization", AUTH)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let statuses: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(statuses.as_array().unwrap().len(), 2);
+
+        let resp = warp::test::request()
+            .method("PUT")
+            .path("/subsystems/settlement_poller/start")
+            .header("Authorization", AUTH)
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,189 @@
+//! Interoperability tests against the reference JavaScript connector.
+//!
+//! They require docker and network access, so they are only built with the
+//! `interop-tests` feature and are ignored unless explicitly requested:
+//! `cargo test -p ilp-node --features interop-tests --test interop_tests -- --ignored`
+#![type_length_limit = "10000000"]
+mod js_connector;
+#[path = "../redis/test_helpers.rs"]
+mod test_helpers;
+
+use crate::js_connector::*;
+use crate::test_helpers::*;
+use ilp_node::InterledgerNode;
+use serde_json::{self, json};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long the connector and the nodes may take to start and exchange routes
+const ROUTES_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn node_config(ilp_address: &str, http_port: u16) -> InterledgerNode {
+    serde_json::from_value(json!({
+        "ilp_address": ilp_address,
+        "admin_auth_token": "admin",
+        "database_url": "memory://",
+        "http_bind_address": format!("127.0.0.1:{}", http_port),
+        "settlement_api_bind_address": format!("127.0.0.1:{}", get_open_port()),
+        "secret_seed": random_secret(),
+        "route_broadcast_interval": 200,
+        "exchange_rate": {
+            "poll_interval": 60000
+        },
+    }))
+    .expect("Error creating node")
+}
+
+async fn get_routes(node_port: u16) -> HashMap<String, String> {
+    let response = reqwest::get(&format!("http://localhost:{}/routes", node_port))
+        .await
+        .and_then(|response| response.error_for_status());
+    match response {
+        Ok(response) => response.json().await.unwrap_or_default(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// Waits until the node has learned a route for the prefix from the JS connector
+async fn wait_for_route(node_port: u16, prefix: &str, connector: &JsConnector) {
+    let started_at = Instant::now();
+    loop {
+        if let Some(next_hop) = get_routes(node_port).await.get(prefix) {
+            assert_eq!(
+                next_hop, "js",
+                "{} is not routed through the JS connector",
+                prefix
+            );
+            return;
+        }
+        if started_at.elapsed() > ROUTES_TIMEOUT {
+            connector.print_logs();
+            panic!(
+                "Node on port {} did not learn a route to {}",
+                node_port, prefix
+            );
+        }
+        tokio::time::delay_for(Duration::from_millis(500)).await;
+    }
+}
+
+#[tokio::test]
+#[ignore]
+async fn stream_payments_through_js_connector() {
+    // Node A peers with the JS connector over BTP, and node B over ILP over HTTP.
+    // The nodes only learn routes to each other from the connector, via CCP
+    let node_a_http = get_open_port();
+    let node_b_http = get_open_port();
+    let connector_btp = get_open_port();
+    let connector_http = get_open_port();
+
+    let connector = JsConnector::start(
+        "example.js",
+        json!({
+            "node_a": btp_server_account(connector_btp, "btp_token"),
+            "node_b": http_account(
+                connector_http,
+                "b_to_js_token",
+                &format!("http://localhost:{}/accounts/js/ilp", node_b_http),
+                "js_to_b_token",
+            ),
+        }),
+    );
+
+    let node_a = node_config("example.a", node_a_http);
+    let node_b = node_config("example.b", node_b_http);
+    node_a.serve(None).await.unwrap();
+    node_b.serve(None).await.unwrap();
+
+    let user = |username: &str| {
+        json!({
+            "username": username,
+            "asset_code": "XYZ",
+            "asset_scale": 9,
+            "ilp_over_http_incoming_token": format!("{}_token", username),
+        })
+    };
+    create_account_on_node(node_a_http, user("alice"), "admin")
+        .await
+        .unwrap();
+    create_account_on_node(node_b_http, user("bob"), "admin")
+        .await
+        .unwrap();
+    create_account_on_node(
+        node_a_http,
+        json!({
+            "username": "js",
+            "asset_code": "XYZ",
+            "asset_scale": 9,
+            "ilp_over_btp_url": format!("btp+ws://localhost:{}", connector_btp),
+            "ilp_over_btp_outgoing_token": "btp_token",
+            "routing_relation": "Peer",
+        }),
+        "admin",
+    )
+    .await
+    .unwrap();
+    create_account_on_node(
+        node_b_http,
+        json!({
+            "username": "js",
+            "asset_code": "XYZ",
+            "asset_scale": 9,
+            "ilp_over_http_url": format!("http://localhost:{}", connector_http),
+            "ilp_over_http_incoming_token": "js_to_b_token",
+            "ilp_over_http_outgoing_token": "b_to_js_token",
+            "routing_relation": "Peer",
+        }),
+        "admin",
+    )
+    .await
+    .unwrap();
+
+    wait_for_route(node_a_http, "example.b", &connector).await;
+    wait_for_route(node_b_http, "example.a", &connector).await;
+
+    // A to B: out over BTP, and in over HTTP
+    let delivery = send_money_to_username(
+        node_a_http,
+        node_b_http,
+        1000,
+        "bob",
+        "alice",
+        "alice_token",
+    )
+    .await
+    .unwrap_or_else(|_| {
+        connector.print_logs();
+        panic!("Error sending payment from node A to node B")
+    });
+    assert_eq!(delivery.sent_amount, 1000);
+    assert_eq!(delivery.delivered_amount, 1000);
+
+    // B to A: out over HTTP, and in over BTP
+    let delivery =
+        send_money_to_username(node_b_http, node_a_http, 3000, "alice", "bob", "bob_token")
+            .await
+            .unwrap_or_else(|_| {
+                connector.print_logs();
+                panic!("Error sending payment from node B to node A")
+            });
+    assert_eq!(delivery.sent_amount, 3000);
+    assert_eq!(delivery.delivered_amount, 3000);
+
+    let alice = get_balance("alice", node_a_http, "admin").await.unwrap();
+    let bob = get_balance("bob", node_b_http, "admin").await.unwrap();
+    assert_eq!(
+        alice,
+        BalanceData {
+            asset_code: "XYZ".to_owned(),
+            balance: 2e-6
+        }
+    );
+    assert_eq!(
+        bob,
+        BalanceData {
+            asset_code: "XYZ".to_owned(),
+            balance: -2e-6
+        }
+    );
+}
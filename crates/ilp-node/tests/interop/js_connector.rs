@@ -0,0 +1,123 @@
+use serde_json::{json, Value};
+use std::env;
+use std::net::TcpListener;
+use std::process::Command;
+
+/// The release of the reference JavaScript connector the tests are pinned to
+const DEFAULT_IMAGE: &str = "interledgerjs/ilp-connector:v23.0.2";
+
+/// The image of the reference JavaScript connector, which can be overridden
+/// with `ILP_CONNECTOR_IMAGE` to test against another release
+fn image() -> String {
+    env::var("ILP_CONNECTOR_IMAGE").unwrap_or_else(|_| DEFAULT_IMAGE.to_string())
+}
+
+pub fn get_open_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .expect("Cannot find open port!")
+}
+
+/// A JavaScript connector running in a docker container, which is removed when this is dropped.
+///
+/// The container uses the host's network, so that the connector and the nodes
+/// under test can reach each other on localhost.
+pub struct JsConnector {
+    container_id: String,
+}
+
+impl JsConnector {
+    /// Starts the connector with the given `CONNECTOR_ACCOUNTS` configuration.
+    /// Packets are forwarded one-to-one without a spread, so that the amounts
+    /// sent through it can be compared exactly.
+    pub fn start(ilp_address: &str, accounts: Value) -> Self {
+        let env_vars = vec![
+            ("CONNECTOR_ILP_ADDRESS", ilp_address.to_string()),
+            ("CONNECTOR_ACCOUNTS", accounts.to_string()),
+            ("CONNECTOR_BACKEND", "one-to-one".to_string()),
+            ("CONNECTOR_SPREAD", "0".to_string()),
+            ("CONNECTOR_ROUTE_BROADCAST_INTERVAL", "1000".to_string()),
+            ("CONNECTOR_ROUTE_EXPIRY", "60000".to_string()),
+            ("DEBUG", "ilp*,connector*".to_string()),
+        ];
+        let mut command = Command::new("docker");
+        command.args(["run", "--detach", "--network", "host"]);
+        for (name, value) in env_vars {
+            command.arg("--env").arg(format!("{}={}", name, value));
+        }
+        let output = command
+            .arg(image())
+            .output()
+            .expect("Error running docker. The interop tests require docker to be installed");
+        assert!(
+            output.status.success(),
+            "Error starting the JS connector: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        JsConnector {
+            container_id: String::from_utf8_lossy(&output.stdout).trim().to_string(),
+        }
+    }
+
+    /// Prints the connector's logs, to debug failed tests
+    pub fn print_logs(&self) {
+        if let Ok(output) = Command::new("docker")
+            .args(["logs", &self.container_id])
+            .output()
+        {
+            eprintln!("{}", String::from_utf8_lossy(&output.stdout));
+            eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        }
+    }
+}
+
+impl Drop for JsConnector {
+    fn drop(&mut self) {
+        let _ = Command::new("docker")
+            .args(["rm", "--force", &self.container_id])
+            .output();
+    }
+}
+
+/// Account of the JS connector which peers with a node over BTP, with the connector as the server
+pub fn btp_server_account(port: u16, token: &str) -> Value {
+    json!({
+        "relation": "peer",
+        "assetCode": "XYZ",
+        "assetScale": 9,
+        "plugin": "ilp-plugin-btp",
+        "options": {
+            "listener": {
+                "port": port,
+                "secret": token,
+            }
+        }
+    })
+}
+
+/// Account of the JS connector which peers with a node over ILP over HTTP
+pub fn http_account(
+    incoming_port: u16,
+    incoming_token: &str,
+    outgoing_url: &str,
+    outgoing_token: &str,
+) -> Value {
+    json!({
+        "relation": "peer",
+        "assetCode": "XYZ",
+        "assetScale": 9,
+        "plugin": "ilp-plugin-http",
+        "options": {
+            "incoming": {
+                "port": incoming_port,
+                "secret": incoming_token,
+            },
+            "outgoing": {
+                "url": outgoing_url,
+                "secret": outgoing_token,
+            }
+        }
+    })
+}
@@ -157,4 +157,28 @@ async fn prometheus() {
     assert!(ret.contains("requests_outgoing_prepare"));
     assert!(ret.contains("requests_outgoing_reject"));
     assert!(ret.contains("requests_outgoing_duration"));
+
+    // The node's HTTP API serves the same metrics to the admin
+    let client = Client::new();
+    let res = client
+        .get(&format!("http://127.0.0.1:{}/metrics", node_a_http))
+        .header("Authorization", "Bearer wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 401);
+    let ret = client
+        .get(&format!("http://127.0.0.1:{}/metrics", node_a_http))
+        .header("Authorization", "Bearer admin")
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(ret.starts_with("# metrics snapshot"));
+    assert!(ret.contains("requests_incoming_fulfill_amount"));
+    assert!(ret.contains("requests_outgoing_prepare_amount"));
+    assert!(ret.contains(r#"from_username="alice_on_a""#));
+    assert!(ret.contains(r#"to_username="node_b""#));
 }
@@ -0,0 +1,182 @@
+//! Conversions between CSV files and account details, for importing and exporting
+//! accounts in bulk. The columns are the fields of the [`AccountDetails`](../struct.AccountDetails.html)
+//! and empty cells are treated as unset fields.
+
+use crate::AccountDetails;
+use serde_json::{Map, Number, Value};
+
+/// Columns of the exported files, in the order of the `AccountDetails` fields
+pub(crate) const COLUMNS: &[&str] = &[
+    "ilp_address",
+    "username",
+    "asset_code",
+    "asset_scale",
+    "max_packet_amount",
+    "min_balance",
+    "ilp_over_http_url",
+    "ilp_over_http_incoming_token",
+    "ilp_over_http_outgoing_token",
+    "ilp_over_btp_url",
+    "ilp_over_btp_outgoing_token",
+    "ilp_over_btp_incoming_token",
+    "settle_threshold",
+    "settle_to",
+    "routing_relation",
+    "round_trip_time",
+    "amount_per_minute_limit",
+    "packets_per_minute_limit",
+    "settlement_engine_url",
+    "spread",
+    "ilp_over_http_fallback_url",
+    "ilp_over_http_alternate_urls",
+    "translate_prefix_from",
+    "translate_prefix_to",
+    "packets_burst_limit",
+    "amount_burst_limit",
+    "accept_route_prefixes",
+    "reject_route_prefixes",
+    "max_routes",
+    "aggregate_route_prefixes",
+];
+
+/// Columns whose cells hold comma-separated lists
+const LIST_COLUMNS: &[&str] = &[
+    "ilp_over_http_alternate_urls",
+    "accept_route_prefixes",
+    "reject_route_prefixes",
+    "aggregate_route_prefixes",
+];
+
+/// Parses each of the file's rows into account details. Fails if the file itself is
+/// invalid (for example if its header has an unknown column), otherwise returns the
+/// outcome of each row.
+pub(crate) fn parse_accounts(body: &[u8]) -> Result<Vec<Result<AccountDetails, String>>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(body);
+    let headers = reader
+        .headers()
+        .map_err(|err| format!("invalid header: {}", err))?
+        .clone();
+    if let Some(unknown) = headers.iter().find(|header| !COLUMNS.contains(header)) {
+        return Err(format!("unknown column `{}`", unknown));
+    }
+
+    Ok(reader
+        .records()
+        .map(|record| {
+            let record = record.map_err(|err| err.to_string())?;
+            let mut fields = Map::new();
+            for (column, cell) in headers.iter().zip(record.iter()) {
+                if cell.is_empty() {
+                    continue;
+                }
+                // The other numeric fields also accept strings
+                let value = if column == "spread" {
+                    cell.parse()
+                        .ok()
+                        .and_then(Number::from_f64)
+                        .map(Value::Number)
+                        .ok_or_else(|| format!("invalid spread `{}`", cell))?
+                } else if LIST_COLUMNS.contains(&column) {
+                    Value::Array(
+                        cell.split(',')
+                            .map(str::trim)
+                            .filter(|item| !item.is_empty())
+                            .map(|item| Value::String(item.to_string()))
+                            .collect(),
+                    )
+                } else {
+                    Value::String(cell.to_string())
+                };
+                fields.insert(column.to_string(), value);
+            }
+            // Usernames and addresses can only be deserialized from borrowed strings
+            let fields = serde_json::to_vec(&fields).map_err(|err| err.to_string())?;
+            serde_json::from_slice(&fields).map_err(|err| err.to_string())
+        })
+        .collect())
+}
+
+/// Writes the accounts as a CSV file with a header row
+pub(crate) fn write_accounts(accounts: &[AccountDetails]) -> Result<Vec<u8>, String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer
+        .write_record(COLUMNS)
+        .map_err(|err| err.to_string())?;
+    for account in accounts {
+        let fields = serde_json::to_value(account).map_err(|err| err.to_string())?;
+        let row = COLUMNS.iter().map(|column| match fields.get(column) {
+            Some(Value::String(value)) => value.clone(),
+            Some(Value::Null) | None => String::new(),
+            Some(Value::Array(values)) => values
+                .iter()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+                .join(","),
+            Some(value) => value.to_string(),
+        });
+        writer.write_record(row).map_err(|err| err.to_string())?;
+    }
+    writer.into_inner().map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrecy::ExposeSecret;
+
+    #[test]
+    fn parses_rows() {
+        let csv = b"username,asset_code,asset_scale,spread,ilp_over_http_incoming_token,min_balance
+alice,XYZ,9,0.01,secret,
+bob,XYZ,nine,,,
+carol,XYZ,2,,,-100";
+        let rows = parse_accounts(&csv[..]).unwrap();
+        assert_eq!(rows.len(), 3);
+        let alice = rows[0].as_ref().unwrap();
+        assert_eq!(alice.username.to_string(), "alice");
+        assert_eq!(alice.asset_scale, 9);
+        assert_eq!(alice.spread, Some(0.01));
+        assert_eq!(alice.min_balance, None);
+        assert_eq!(
+            alice
+                .ilp_over_http_incoming_token
+                .as_ref()
+                .unwrap()
+                .expose_secret(),
+            "secret"
+        );
+        assert!(rows[1].is_err());
+        assert_eq!(rows[2].as_ref().unwrap().min_balance, Some(-100));
+    }
+
+    #[test]
+    fn rejects_unknown_columns() {
+        let err = parse_accounts(&b"username,asset_cod\nalice,XYZ"[..]).unwrap_err();
+        assert_eq!(err, "unknown column `asset_cod`");
+    }
+
+    #[test]
+    fn round_trips() {
+        let csv = b"username,asset_code,asset_scale,spread,settle_threshold,routing_relation,ilp_over_http_alternate_urls
+alice,XYZ,9,0.5,1000,Peer,\"http://a.example/ilp, http://b.example/ilp\"";
+        let accounts: Vec<AccountDetails> = parse_accounts(&csv[..])
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        let written = write_accounts(&accounts).unwrap();
+        let parsed = parse_accounts(&written).unwrap();
+        let alice = parsed[0].as_ref().unwrap();
+        assert_eq!(alice.username.to_string(), "alice");
+        assert_eq!(alice.spread, Some(0.5));
+        assert_eq!(alice.settle_threshold, Some(1000));
+        assert_eq!(alice.routing_relation.as_deref(), Some("Peer"));
+        assert_eq!(alice.max_packet_amount, u64::max_value());
+        assert_eq!(
+            alice.ilp_over_http_alternate_urls,
+            vec!["http://a.example/ilp", "http://b.example/ilp"]
+        );
+    }
+}
@@ -0,0 +1,562 @@
+//! Exports the operations which change the balances of the accounts as the entries of a
+//! double-entry ledger, so that external accounting systems can be fed without scraping
+//! the logs.
+//!
+//! The entries are built from the events of the node's [`EventBus`](../../interledger_service/struct.EventBus.html)
+//! and written to an [`AccountingSink`](./trait.AccountingSink.html), such as the store or
+//! a [`FileAccountingSink`](./struct.FileAccountingSink.html).
+
+use async_trait::async_trait;
+use interledger_errors::{AccountStoreError, AccountingSinkError};
+use interledger_service::{Account, AccountEvent, AccountStore, EventBus};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+/// Ledger account of the balance of one of the node's accounts
+const BALANCE_LEDGER_ACCOUNT: &str = "account";
+/// Ledger account of the amounts taken out of an account's balance to be settled
+const PENDING_SETTLEMENTS_LEDGER_ACCOUNT: &str = "pending_settlements";
+/// Ledger account of the amounts settled with an account's peer
+const SETTLEMENTS_LEDGER_ACCOUNT: &str = "settlements";
+/// Ledger account of the amounts the node converted from or to an asset
+const TRADING_LEDGER_ACCOUNT: &str = "trading";
+
+/// How long to wait before retrying to load the accounts of an event or to record an entry
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Names of the fields of the entries, in the order of [`LedgerEntry::fields`](./struct.LedgerEntry.html#method.fields)
+pub const LEDGER_ENTRY_FIELDS: [&str; 11] = [
+    "id",
+    "timestamp",
+    "kind",
+    "debit_account",
+    "debit_amount",
+    "debit_asset_code",
+    "debit_asset_scale",
+    "credit_account",
+    "credit_amount",
+    "credit_asset_code",
+    "credit_asset_scale",
+];
+
+/// The operation a ledger entry records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerEntryKind {
+    /// A packet was fulfilled. The incoming amount is debited from the account the packet
+    /// came from and the outgoing amount is credited to the account it was sent to. If
+    /// the two amounts differ, they are exchanged through the trading accounts of their assets
+    PacketFulfilled,
+    /// A settlement was sent to the account's peer, out of the amount reserved for it
+    SettlementSent,
+    /// A settlement was received from the account's peer
+    SettlementReceived,
+    /// An amount was reserved for a settlement, or put back after the settlement failed
+    Adjustment,
+}
+
+impl LedgerEntryKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LedgerEntryKind::PacketFulfilled => "packet_fulfilled",
+            LedgerEntryKind::SettlementSent => "settlement_sent",
+            LedgerEntryKind::SettlementReceived => "settlement_received",
+            LedgerEntryKind::Adjustment => "adjustment",
+        }
+    }
+}
+
+/// One side of a ledger entry
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LedgerPosting {
+    /// `account:<id>` for the balance of one of the node's accounts, `pending_settlements:<id>`
+    /// for the amounts reserved for the settlements with its peer, `settlements:<id>` for
+    /// the amounts settled with its peer and `trading:<asset code>:<asset scale>` for the
+    /// amounts the node converted from or to the asset
+    pub ledger_account: String,
+    /// Amount in the account's asset scale
+    pub amount: u64,
+    pub asset_code: String,
+    pub asset_scale: u8,
+}
+
+/// An amount moved from one ledger account to another. The debited account's balance
+/// decreases and the credited one's increases, so that the balance of `account:<id>` follows
+/// the one the store keeps for the account. The two sides always have the same amount and
+/// asset: the packets converted between assets (or forwarded with a spread) are recorded
+/// as two entries, through the trading accounts of the incoming and outgoing assets
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LedgerEntry {
+    pub id: Uuid,
+    /// When the operation happened, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub kind: LedgerEntryKind,
+    pub debit: LedgerPosting,
+    pub credit: LedgerPosting,
+}
+
+impl LedgerEntry {
+    /// Returns the values of the entry's fields, named by [`LEDGER_ENTRY_FIELDS`](./constant.LEDGER_ENTRY_FIELDS.html),
+    /// for the formats which do not nest values
+    pub fn fields(&self) -> [String; 11] {
+        [
+            self.id.to_string(),
+            self.timestamp.to_string(),
+            self.kind.as_str().to_string(),
+            self.debit.ledger_account.clone(),
+            self.debit.amount.to_string(),
+            self.debit.asset_code.clone(),
+            self.debit.asset_scale.to_string(),
+            self.credit.ledger_account.clone(),
+            self.credit.amount.to_string(),
+            self.credit.asset_code.clone(),
+            self.credit.asset_scale.to_string(),
+        ]
+    }
+}
+
+/// Where the ledger entries are exported to
+#[async_trait]
+pub trait AccountingSink {
+    /// Writes the entry after the ones recorded before it
+    async fn record_ledger_entry(&self, entry: &LedgerEntry) -> Result<(), AccountingSinkError>;
+}
+
+/// How a [`FileAccountingSink`](./struct.FileAccountingSink.html) writes the entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerFileFormat {
+    /// One row per entry, with the columns named by a header
+    Csv,
+    /// One JSON object per line
+    Json,
+}
+
+impl Default for LedgerFileFormat {
+    fn default() -> Self {
+        LedgerFileFormat::Csv
+    }
+}
+
+/// Appends the entries to a file. The writes are blocking, so they run on the blocking
+/// thread pool instead of the one which handles the packets
+pub struct FileAccountingSink {
+    file: Arc<Mutex<File>>,
+    format: LedgerFileFormat,
+}
+
+impl FileAccountingSink {
+    /// Opens the file for appending, creating it if it does not exist yet. The header of
+    /// the CSV format is written to the files which are empty
+    pub fn open(path: &Path, format: LedgerFileFormat) -> io::Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if format == LedgerFileFormat::Csv && file.metadata()?.len() == 0 {
+            file.write_all(&csv_row(&LEDGER_ENTRY_FIELDS)?)?;
+        }
+        Ok(FileAccountingSink {
+            file: Arc::new(Mutex::new(file)),
+            format,
+        })
+    }
+}
+
+fn csv_row<T: AsRef<[u8]>>(fields: &[T]) -> io::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(fields)?;
+    writer
+        .into_inner()
+        .map_err(|err| io::Error::new(err.error().kind(), err.to_string()))
+}
+
+#[async_trait]
+impl AccountingSink for FileAccountingSink {
+    async fn record_ledger_entry(&self, entry: &LedgerEntry) -> Result<(), AccountingSinkError> {
+        let line = match self.format {
+            LedgerFileFormat::Csv => csv_row(&entry.fields())?,
+            LedgerFileFormat::Json => {
+                let mut line = serde_json::to_vec(entry).map_err(io::Error::from)?;
+                line.push(b'\n');
+                line
+            }
+        };
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || {
+            // A single write per entry, so that no entry is interleaved with another one
+            file.lock().unwrap().write_all(&line)
+        })
+        .await
+        .map_err(|err| AccountingSinkError::Other(Box::new(err)))??;
+        Ok(())
+    }
+}
+
+/// The asset of an account, which the ledger entries are denominated in
+struct Asset {
+    code: String,
+    scale: u8,
+}
+
+/// Subscribes to the bus and records the ledger entries of the balance changes in the sink.
+/// The assets of the accounts are loaded from the store the first time they are needed.
+///
+/// The loads and writes which fail are retried until they succeed, so that the ledger stays
+/// balanced. The exporter subscribes to the bus as a lossless subscriber, so it only misses
+/// events if it falls behind by more than its buffer while retrying (which is logged and
+/// counted in the `event_bus.lossless.dropped` metric). The events of the accounts which
+/// were deleted in the meantime are skipped
+pub fn spawn_accounting_exporter<S, A, K>(
+    store: S,
+    sink: K,
+    events: &EventBus,
+) -> tokio::task::JoinHandle<()>
+where
+    S: AccountStore<Account = A> + Send + Sync + 'static,
+    A: Account,
+    K: AccountingSink + Send + Sync + 'static,
+{
+    let mut receiver = events.subscribe_lossless();
+    tokio::spawn(async move {
+        let mut assets: HashMap<Uuid, Asset> = HashMap::new();
+        'events: while let Some(event) = receiver.recv().await {
+            let mut missing = ledger_account_ids(&event);
+            missing.retain(|id| !assets.contains_key(id));
+            missing.dedup();
+            while !missing.is_empty() {
+                match store.get_accounts(missing.clone()).await {
+                    Ok(accounts) => {
+                        for account in accounts {
+                            assets.insert(
+                                account.id(),
+                                Asset {
+                                    code: account.asset_code().to_string(),
+                                    scale: account.asset_scale(),
+                                },
+                            );
+                        }
+                        break;
+                    }
+                    Err(AccountStoreError::AccountNotFound(_)) => {
+                        warn!("Not exporting {:?}, one of its accounts was deleted", event);
+                        continue 'events;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Error loading the accounts of {:?}, retrying: {}",
+                            event, err
+                        );
+                        tokio::time::delay_for(RETRY_DELAY).await;
+                    }
+                }
+            }
+
+            for entry in ledger_entries(&event, &assets, now_millis()) {
+                while let Err(err) = sink.record_ledger_entry(&entry).await {
+                    error!(
+                        "Error exporting ledger entry {:?}, retrying: {}",
+                        entry, err
+                    );
+                    tokio::time::delay_for(RETRY_DELAY).await;
+                }
+            }
+        }
+        debug!("Stopped exporting ledger entries because the event bus was dropped");
+    })
+}
+
+/// Returns the accounts the ledger entry of the event moves amounts of
+fn ledger_account_ids(event: &AccountEvent) -> Vec<Uuid> {
+    match *event {
+        AccountEvent::PacketFulfilled {
+            from_account_id,
+            to_account_id,
+            ..
+        } => vec![from_account_id, to_account_id],
+        AccountEvent::SettlementReserved { account_id, .. }
+        | AccountEvent::SettlementRefunded { account_id, .. }
+        | AccountEvent::SettlementSent { account_id, .. }
+        | AccountEvent::SettlementReceived { account_id, .. } => vec![account_id],
+        AccountEvent::BalanceChanged { .. } | AccountEvent::AccountSuspended { .. } => Vec::new(),
+    }
+}
+
+/// Turns the event into its ledger entries, if it changed a balance. Returns no entries if
+/// the asset of one of the event's accounts is unknown
+fn ledger_entries(
+    event: &AccountEvent,
+    assets: &HashMap<Uuid, Asset>,
+    timestamp: u64,
+) -> Vec<LedgerEntry> {
+    let posting = |ledger_account: &str, account_id: Uuid, amount: u64| {
+        assets.get(&account_id).map(|asset| LedgerPosting {
+            ledger_account: format!("{}:{}", ledger_account, account_id),
+            amount,
+            asset_code: asset.code.clone(),
+            asset_scale: asset.scale,
+        })
+    };
+    let entry = |kind, debit, credit| LedgerEntry {
+        id: Uuid::new_v4(),
+        timestamp,
+        kind,
+        debit,
+        credit,
+    };
+    let (kind, debit, credit) = match *event {
+        AccountEvent::PacketFulfilled {
+            from_account_id,
+            to_account_id,
+            incoming_amount,
+            outgoing_amount,
+        } => {
+            let (debit, credit) = match (
+                posting(BALANCE_LEDGER_ACCOUNT, from_account_id, incoming_amount),
+                posting(BALANCE_LEDGER_ACCOUNT, to_account_id, outgoing_amount),
+            ) {
+                (Some(debit), Some(credit)) => (debit, credit),
+                _ => return Vec::new(),
+            };
+            if debit.amount != credit.amount
+                || debit.asset_code != credit.asset_code
+                || debit.asset_scale != credit.asset_scale
+            {
+                let (debit_trading, credit_trading) = (trading(&debit), trading(&credit));
+                return vec![
+                    entry(LedgerEntryKind::PacketFulfilled, debit, debit_trading),
+                    entry(LedgerEntryKind::PacketFulfilled, credit_trading, credit),
+                ];
+            }
+            (LedgerEntryKind::PacketFulfilled, Some(debit), Some(credit))
+        }
+        AccountEvent::SettlementReserved { account_id, amount } => (
+            LedgerEntryKind::Adjustment,
+            posting(BALANCE_LEDGER_ACCOUNT, account_id, amount),
+            posting(PENDING_SETTLEMENTS_LEDGER_ACCOUNT, account_id, amount),
+        ),
+        AccountEvent::SettlementRefunded { account_id, amount } => (
+            LedgerEntryKind::Adjustment,
+            posting(PENDING_SETTLEMENTS_LEDGER_ACCOUNT, account_id, amount),
+            posting(BALANCE_LEDGER_ACCOUNT, account_id, amount),
+        ),
+        AccountEvent::SettlementSent { account_id, amount } => (
+            LedgerEntryKind::SettlementSent,
+            posting(PENDING_SETTLEMENTS_LEDGER_ACCOUNT, account_id, amount),
+            posting(SETTLEMENTS_LEDGER_ACCOUNT, account_id, amount),
+        ),
+        AccountEvent::SettlementReceived { account_id, amount } => (
+            LedgerEntryKind::SettlementReceived,
+            posting(SETTLEMENTS_LEDGER_ACCOUNT, account_id, amount),
+            posting(BALANCE_LEDGER_ACCOUNT, account_id, amount),
+        ),
+        AccountEvent::BalanceChanged { .. } | AccountEvent::AccountSuspended { .. } => {
+            return Vec::new()
+        }
+    };
+    match (debit, credit) {
+        (Some(debit), Some(credit)) => vec![entry(kind, debit, credit)],
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the posting to the trading account of the posting's asset, for the same amount
+fn trading(posting: &LedgerPosting) -> LedgerPosting {
+    LedgerPosting {
+        ledger_account: format!(
+            "{}:{}:{}",
+            TRADING_LEDGER_ACCOUNT, posting.asset_code, posting.asset_scale
+        ),
+        ..posting.clone()
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn assets(ids: &[(Uuid, &str)]) -> HashMap<Uuid, Asset> {
+        ids.iter()
+            .map(|(id, code)| {
+                (
+                    *id,
+                    Asset {
+                        code: code.to_string(),
+                        scale: 9,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn debits_the_incoming_account_and_credits_the_outgoing_one() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let event = AccountEvent::PacketFulfilled {
+            from_account_id: alice,
+            to_account_id: bob,
+            incoming_amount: 100,
+            outgoing_amount: 100,
+        };
+        let entries = ledger_entries(&event, &assets(&[(alice, "ABC"), (bob, "ABC")]), 1);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].kind, LedgerEntryKind::PacketFulfilled);
+        assert_eq!(
+            entries[0].debit.ledger_account,
+            format!("account:{}", alice)
+        );
+        assert_eq!(entries[0].credit.ledger_account, format!("account:{}", bob));
+        assert_eq!(entries[0].credit.amount, 100);
+
+        // Without the asset of an account, the entry cannot be built
+        assert!(ledger_entries(&event, &assets(&[(alice, "ABC")]), 1).is_empty());
+    }
+
+    #[test]
+    fn converted_packets_go_through_the_trading_accounts() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let event = AccountEvent::PacketFulfilled {
+            from_account_id: alice,
+            to_account_id: bob,
+            incoming_amount: 100,
+            outgoing_amount: 50,
+        };
+        let entries = ledger_entries(&event, &assets(&[(alice, "ABC"), (bob, "XYZ")]), 1);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].debit.ledger_account,
+            format!("account:{}", alice)
+        );
+        assert_eq!(entries[0].credit.ledger_account, "trading:ABC:9");
+        assert_eq!(entries[1].debit.ledger_account, "trading:XYZ:9");
+        assert_eq!(entries[1].credit.ledger_account, format!("account:{}", bob));
+        // Each entry balances in a single asset
+        for entry in entries.iter() {
+            assert_eq!(entry.kind, LedgerEntryKind::PacketFulfilled);
+            assert_eq!(entry.debit.amount, entry.credit.amount);
+            assert_eq!(entry.debit.asset_code, entry.credit.asset_code);
+        }
+        assert_eq!(entries[0].debit.amount, 100);
+        assert_eq!(entries[1].credit.amount, 50);
+
+        // The spread taken on packets of the same asset stays in its trading account
+        let entries = ledger_entries(&event, &assets(&[(alice, "ABC"), (bob, "ABC")]), 1);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].credit.ledger_account, "trading:ABC:9");
+        assert_eq!(entries[1].debit.ledger_account, "trading:ABC:9");
+    }
+
+    #[test]
+    fn settlements_go_through_the_pending_settlements() {
+        let alice = Uuid::new_v4();
+        let assets = assets(&[(alice, "ABC")]);
+        let accounts = |event| {
+            let entry = ledger_entries(&event, &assets, 1).pop().unwrap();
+            let debit = entry
+                .debit
+                .ledger_account
+                .split(':')
+                .next()
+                .unwrap()
+                .to_string();
+            let credit = entry
+                .credit
+                .ledger_account
+                .split(':')
+                .next()
+                .unwrap()
+                .to_string();
+            (entry.kind, debit, credit)
+        };
+        assert_eq!(
+            accounts(AccountEvent::SettlementReserved {
+                account_id: alice,
+                amount: 10
+            }),
+            (
+                LedgerEntryKind::Adjustment,
+                "account".to_string(),
+                "pending_settlements".to_string()
+            )
+        );
+        assert_eq!(
+            accounts(AccountEvent::SettlementSent {
+                account_id: alice,
+                amount: 10
+            }),
+            (
+                LedgerEntryKind::SettlementSent,
+                "pending_settlements".to_string(),
+                "settlements".to_string()
+            )
+        );
+        assert_eq!(
+            accounts(AccountEvent::SettlementReceived {
+                account_id: alice,
+                amount: 10
+            }),
+            (
+                LedgerEntryKind::SettlementReceived,
+                "settlements".to_string(),
+                "account".to_string()
+            )
+        );
+        assert!(ledger_entries(
+            &AccountEvent::BalanceChanged {
+                account_id: alice,
+                balance: 10
+            },
+            &assets,
+            1
+        )
+        .is_empty());
+    }
+
+    #[tokio::test]
+    async fn appends_entries_to_files() {
+        let alice = Uuid::new_v4();
+        let event = AccountEvent::SettlementReceived {
+            account_id: alice,
+            amount: 10,
+        };
+        let entry = ledger_entries(&event, &assets(&[(alice, "ABC")]), 1)
+            .pop()
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("ledger-{}.csv", Uuid::new_v4()));
+        for _ in 0..2 {
+            let sink = FileAccountingSink::open(&path, LedgerFileFormat::Csv).unwrap();
+            sink.record_ledger_entry(&entry).await.unwrap();
+        }
+        let csv = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("id,timestamp,kind,debit_account"));
+        assert!(lines[1].contains(",settlement_received,settlements:"));
+
+        let path = std::env::temp_dir().join(format!("ledger-{}.json", Uuid::new_v4()));
+        let sink = FileAccountingSink::open(&path, LedgerFileFormat::Json).unwrap();
+        sink.record_ledger_entry(&entry).await.unwrap();
+        let json = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let line: serde_json::Value = serde_json::from_str(json.trim_end()).unwrap();
+        assert_eq!(line["kind"], "settlement_received");
+        assert_eq!(
+            line["credit"]["ledger_account"],
+            format!("account:{}", alice)
+        );
+    }
+}
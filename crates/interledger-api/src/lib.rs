@@ -1,19 +1,26 @@
 #![type_length_limit = "1707074"]
+#![recursion_limit = "256"]
 use async_trait::async_trait;
 use bytes::Bytes;
 use interledger_btp::{BtpAccount, BtpOutgoingService};
-use interledger_ccp::CcpRoutingAccount;
+use interledger_ccp::{CcpRoutingAccount, RouteExpiries};
 use interledger_errors::NodeStoreError;
-use interledger_http::{HttpAccount, HttpStore};
+use interledger_http::{HttpAccount, HttpStore, WsConnections};
 use interledger_packet::Address;
 use interledger_rates::ExchangeRateStore;
 use interledger_router::RouterStore;
 use interledger_service::{
     Account, AccountStore, AddressStore, IncomingService, OutgoingService, Username,
 };
-use interledger_service_util::BalanceStore;
-use interledger_settlement::core::types::{SettlementAccount, SettlementStore};
-use interledger_stream::StreamNotificationsStore;
+use interledger_service_util::{
+    BalanceHistoryStore, BalanceStore, PeerMonitor, PeerScoreboard, UsageStore,
+};
+use interledger_settlement::core::{
+    types::{SettlementAccount, SettlementHistoryStore, SettlementStore},
+    Reconciler, SettlementScheduler,
+};
+use interledger_spsp::ContactStore;
+use interledger_stream::{StreamNotificationsStore, StreamReceiveStore};
 use secrecy::SecretString;
 use serde::{de, Deserialize, Serialize};
 use std::{boxed::*, collections::HashMap, fmt::Display, net::SocketAddr, str::FromStr};
@@ -21,7 +28,17 @@ use url::Url;
 use uuid::Uuid;
 use warp::{self, Filter};
 
+mod account_csv;
+pub mod accounting;
 mod routes;
+pub mod webhooks;
+pub use accounting::{
+    spawn_accounting_exporter, AccountingSink, FileAccountingSink, LedgerEntry, LedgerEntryKind,
+    LedgerFileFormat, LedgerPosting,
+};
+pub use webhooks::{
+    spawn_webhook_dispatcher, Webhook, WebhookEventType, WebhookRetryPolicy, WebhookStore,
+};
 
 // This enum and the following functions are used to allow clients to send either
 // numbers or strings and have them be properly deserialized into the appropriate
@@ -57,6 +74,29 @@ where
     }
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListOrStr {
+    List(Vec<String>),
+    Str(String),
+}
+
+/// Allows clients to send a list of strings either as an array or as a comma-separated string
+pub fn list_or_string<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    match ListOrStr::deserialize(deserializer)? {
+        ListOrStr::List(list) => Ok(list),
+        ListOrStr::Str(s) => Ok(s
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect()),
+    }
+}
+
 pub fn map_of_number_or_string<'de, D>(deserializer: D) -> Result<HashMap<String, f64>, D::Error>
 where
     D: de::Deserializer<'de>,
@@ -83,6 +123,19 @@ pub trait NodeStore: Clone + Send + Sync + 'static {
         account: AccountDetails,
     ) -> Result<Self::Account, NodeStoreError>;
 
+    /// Inserts the accounts in the given order and returns the outcome of each of them.
+    /// If `atomic`, either all of the accounts are inserted or none of them are: once one fails,
+    /// the others are reported as `AccountNotInserted`.
+    async fn insert_accounts(
+        &self,
+        accounts: Vec<AccountDetails>,
+        atomic: bool,
+    ) -> Vec<Result<Self::Account, NodeStoreError>>;
+
+    /// Returns the details of all the accounts, including their tokens, as `insert_accounts`
+    /// takes them. Used to migrate the accounts to another node.
+    async fn export_accounts(&self) -> Result<Vec<AccountDetails>, NodeStoreError>;
+
     /// Deletes the account corresponding to the provided id and returns it
     async fn delete_account(&self, id: Uuid) -> Result<Self::Account, NodeStoreError>;
 
@@ -121,10 +174,22 @@ pub trait NodeStore: Clone + Send + Sync + 'static {
         account_id: Uuid,
     ) -> Result<(), NodeStoreError>;
 
+    /// Gets the static routes, which take precedence over all of the other routes
+    async fn get_static_routes(&self) -> Result<HashMap<String, Uuid>, NodeStoreError>;
+
+    /// Deletes the static route for the given prefix, if there is one
+    async fn delete_static_route(&self, prefix: &str) -> Result<(), NodeStoreError>;
+
     /// Sets the default route ("") to be the provided account id
     /// (acts as a catch-all route if all other routes don't match)
     async fn set_default_route(&self, account_id: Uuid) -> Result<(), NodeStoreError>;
 
+    /// Gets the account id the default route was set to, if it was set
+    async fn get_default_route(&self) -> Result<Option<Uuid>, NodeStoreError>;
+
+    /// Deletes the default route, if it was set
+    async fn delete_default_route(&self) -> Result<(), NodeStoreError>;
+
     /// Sets the default settlement engines to be used for the provided asset codes
     async fn set_settlement_engines(
         &self,
@@ -177,6 +242,13 @@ pub struct AccountSettings {
     pub settle_to: Option<u64>,
 }
 
+impl AccountSettings {
+    /// Returns true if the settings configure when the account settles
+    pub(crate) fn configures_settlement(&self) -> bool {
+        self.settle_threshold.is_some() || self.settle_to.is_some()
+    }
+}
+
 /// EncryptedAccountSettings is created by encrypting the incoming and outgoing
 /// HTTP and BTP tokens of an AccountSettings object. The rest of the fields
 /// remain the same. It is intended to be consumed by the internal store
@@ -260,6 +332,58 @@ pub struct AccountDetails {
     /// for the account's asset code,  that will be used instead (even if the account is
     /// configured with a specific one)
     pub settlement_engine_url: Option<String>,
+    /// The spread, as a fraction, charged on the packets the account sends which are
+    /// converted to another asset. If none is provided, the node's spread is used
+    #[serde(default)]
+    pub spread: Option<f64>,
+    /// A second ILP over HTTP URL of the peer, to which the packets which are safe to deliver
+    /// twice are also sent if the `ilp_over_http_url` is slow to respond
+    #[serde(default)]
+    pub ilp_over_http_fallback_url: Option<String>,
+    /// Other ILP over HTTP URLs of the peer, to which the requests are retried in turn when
+    /// sending them to the `ilp_over_http_url` fails. May also be a comma-separated string
+    #[serde(default, deserialize_with = "list_or_string")]
+    pub ilp_over_http_alternate_urls: Vec<String>,
+    /// Prefix of the destinations which are rewritten to start with `translate_prefix_to`
+    /// in the packets sent to the account, for peers which expect the packets to be
+    /// addressed under their own prefix. Both prefixes must be provided together
+    #[serde(default)]
+    pub translate_prefix_from: Option<Address>,
+    /// The peer's own prefix, which replaces `translate_prefix_from` in the destinations
+    #[serde(default)]
+    pub translate_prefix_to: Option<Address>,
+    /// The number of packets the account may send at once, after which it is limited to the
+    /// `packets_per_minute_limit`. Defaults to the `packets_per_minute_limit`
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    pub packets_burst_limit: Option<u32>,
+    /// The amount the account may send at once, after which it is limited to the
+    /// `amount_per_minute_limit`. Defaults to the `amount_per_minute_limit`
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    pub amount_burst_limit: Option<u64>,
+    /// Prefixes of the routes accepted from the account's CCP broadcasts. All routes are
+    /// accepted if empty. May also be a comma-separated string
+    #[serde(default, deserialize_with = "list_or_string")]
+    pub accept_route_prefixes: Vec<String>,
+    /// Prefixes of the routes rejected from the account's CCP broadcasts, even if they are
+    /// also accepted. May also be a comma-separated string
+    #[serde(default, deserialize_with = "list_or_string")]
+    pub reject_route_prefixes: Vec<String>,
+    /// The maximum number of routes accepted from the account's CCP broadcasts
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    pub max_routes: Option<u32>,
+    /// Prefixes under which the routes learned from the account are re-advertised as a
+    /// single route for the prefix. May also be a comma-separated string
+    #[serde(default, deserialize_with = "list_or_string")]
+    pub aggregate_route_prefixes: Vec<String>,
+}
+
+impl AccountDetails {
+    /// Returns true if the details configure how or when the account settles
+    pub(crate) fn configures_settlement(&self) -> bool {
+        self.settle_threshold.is_some()
+            || self.settle_to.is_some()
+            || self.settlement_engine_url.is_some()
+    }
 }
 
 pub struct NodeApi<S, I, O, B, A: Account> {
@@ -275,9 +399,23 @@ pub struct NodeApi<S, I, O, B, A: Account> {
     // The BTP service is included here so that we can add a new client
     // connection when an account is added with BTP details
     btp: BtpOutgoingService<B, A>,
+    /// Used to close the ILP over WebSocket connections of the accounts which change
+    ws_connections: Option<WsConnections>,
     /// Server secret used to instantiate SPSP/Stream connections
     server_secret: Bytes,
     node_version: Option<String>,
+    /// Used to report the settlements waiting to be sent to the settlement engines
+    settlement_scheduler: Option<SettlementScheduler>,
+    /// Used to report the discrepancies between the store and the settlement engines
+    reconciler: Option<Reconciler>,
+    /// Used to report when the routes learned from peers expire
+    route_expiries: Option<RouteExpiries>,
+    /// Rejects the changes which would make the node settle, if it only clears
+    clearing_only: bool,
+    /// Used to report the health of the peers
+    peer_monitor: Option<PeerMonitor>,
+    /// Used to reinstate throttled or suspended peers
+    peer_scoreboard: Option<PeerScoreboard>,
 }
 
 impl<S, I, O, B, A> NodeApi<S, I, O, B, A>
@@ -287,7 +425,13 @@ where
         + AddressStore
         + HttpStore<Account = A>
         + BalanceStore
+        + BalanceHistoryStore
+        + UsageStore
+        + ContactStore
+        + WebhookStore
+        + StreamReceiveStore
         + SettlementStore<Account = A>
+        + SettlementHistoryStore
         + StreamNotificationsStore<Account = A>
         + RouterStore
         + ExchangeRateStore,
@@ -319,8 +463,15 @@ where
             incoming_handler,
             outgoing_handler,
             btp,
+            ws_connections: None,
             server_secret,
             node_version: None,
+            settlement_scheduler: None,
+            reconciler: None,
+            route_expiries: None,
+            clearing_only: false,
+            peer_monitor: None,
+            peer_scoreboard: None,
         }
     }
 
@@ -338,6 +489,50 @@ where
         self
     }
 
+    /// Sets the settlement scheduler whose queue is exposed by the API
+    pub fn settlement_scheduler(&mut self, scheduler: SettlementScheduler) -> &mut Self {
+        self.settlement_scheduler = Some(scheduler);
+        self
+    }
+
+    /// Sets the reconciler whose discrepancies are exposed by the API
+    pub fn reconciler(&mut self, reconciler: Reconciler) -> &mut Self {
+        self.reconciler = Some(reconciler);
+        self
+    }
+
+    /// Sets the handle used to report when the routes learned from peers expire
+    pub fn route_expiries(&mut self, route_expiries: RouteExpiries) -> &mut Self {
+        self.route_expiries = Some(route_expiries);
+        self
+    }
+
+    /// Sets the monitor whose record of the peers' health is exposed by the API
+    pub fn peer_monitor(&mut self, peer_monitor: PeerMonitor) -> &mut Self {
+        self.peer_monitor = Some(peer_monitor);
+        self
+    }
+
+    /// Sets the scoreboard whose throttled or suspended peers the admin can reinstate
+    pub fn peer_scoreboard(&mut self, peer_scoreboard: PeerScoreboard) -> &mut Self {
+        self.peer_scoreboard = Some(peer_scoreboard);
+        self
+    }
+
+    /// Sets the handle used to close the ILP over WebSocket connections of the accounts
+    /// which are updated or deleted, so that they are reopened with their new settings
+    pub fn ws_connections(&mut self, ws_connections: WsConnections) -> &mut Self {
+        self.ws_connections = Some(ws_connections);
+        self
+    }
+
+    /// Makes the API reject the accounts (and account settings) which configure settlement
+    /// as well as changes to the settlement engines, for nodes which only clear
+    pub fn clearing_only(&mut self, clearing_only: bool) -> &mut Self {
+        self.clearing_only = clearing_only;
+        self
+    }
+
     /// Returns a Warp Filter which exposes the accounts and admin APIs
     pub fn into_warp_filter(self) -> warp::filters::BoxedFilter<(impl warp::Reply,)> {
         routes::accounts_api(
@@ -347,12 +542,20 @@ where
             self.incoming_handler,
             self.outgoing_handler,
             self.btp,
+            self.ws_connections,
             self.store.clone(),
+            self.clearing_only,
+            self.peer_monitor,
+            self.peer_scoreboard,
         )
         .or(routes::node_settings_api(
             self.admin_api_token,
             self.node_version,
+            self.settlement_scheduler,
+            self.reconciler,
+            self.route_expiries,
             self.store,
+            self.clearing_only,
         ))
         .boxed()
     }
@@ -1,10 +1,15 @@
-use crate::{number_or_string, AccountDetails, AccountSettings, NodeStore};
+use super::check_clearing_only;
+use super::peer_verification::verify_peer;
+use crate::{
+    account_csv, number_or_string, optional_number_or_string, AccountDetails, AccountSettings,
+    NodeStore, Webhook, WebhookStore,
+};
 use bytes::Bytes;
 use futures::{Future, FutureExt, StreamExt, TryFutureExt};
 use interledger_btp::{connect_to_service_account, BtpAccount, BtpOutgoingService};
 use interledger_ccp::{CcpRoutingAccount, Mode, RouteControlRequest, RoutingRelation};
 use interledger_errors::*;
-use interledger_http::{deserialize_json, HttpAccount, HttpStore};
+use interledger_http::{deserialize_json, HttpAccount, HttpStore, WsConnections};
 use interledger_ildcp::IldcpRequest;
 use interledger_ildcp::IldcpResponse;
 use interledger_rates::ExchangeRateStore;
@@ -13,10 +18,20 @@ use interledger_service::{
     Account, AccountStore, AddressStore, IncomingService, OutgoingRequest, OutgoingService,
     Username,
 };
-use interledger_service_util::BalanceStore;
-use interledger_settlement::core::{types::SettlementAccount, SettlementClient};
-use interledger_spsp::{pay, SpspResponder};
-use interledger_stream::{PaymentNotification, StreamNotificationsStore};
+use interledger_service_util::{
+    BalanceHistoryStore, BalanceStore, PeerMonitor, PeerScoreboard, UsagePeriod, UsageStore,
+};
+use interledger_settlement::core::{
+    types::{SettlementAccount, SettlementHistoryStore},
+    SettlementClient,
+};
+use interledger_spsp::{
+    pay, pay_contact, pay_to_deliver, receipt_details, Contact, ContactStore, Error as SpspError,
+    SpspResponder,
+};
+use interledger_stream::{
+    Error as StreamError, PaymentNotification, StreamNotificationsStore, StreamReceiveStore,
+};
 use secrecy::{ExposeSecret, SecretString};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -24,7 +39,7 @@ use std::convert::TryFrom;
 use std::fmt::Debug;
 use tracing::{debug, error, trace};
 use uuid::Uuid;
-use warp::{self, reply::Json, Filter, Rejection};
+use warp::{self, reply::Json, Filter, Rejection, Reply};
 
 pub const BEARER_TOKEN_START: usize = 7;
 
@@ -42,8 +57,108 @@ struct SpspPayRequest {
         default = "get_default_max_slippage"
     )]
     slippage: f64,
+    /// Amount to deliver, in the receiver's units. If set, the `source_amount` is the most
+    /// which may be sent to deliver it
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    destination_amount: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ContactDetails {
+    payment_pointer: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ContactPayRequest {
+    #[serde(deserialize_with = "number_or_string")]
+    source_amount: u64,
+    #[serde(
+        deserialize_with = "number_or_string",
+        default = "get_default_max_slippage"
+    )]
+    slippage: f64,
+}
+
+#[derive(Deserialize, Debug)]
+struct UsageQuery {
+    /// Only reset the counters of this period, instead of all of them
+    period: Option<UsagePeriod>,
+}
+
+/// Time range of the balance and settlement history endpoints
+#[derive(Deserialize, Debug)]
+struct HistoryQuery {
+    /// Only return the entries recorded at or after this time, in milliseconds since the Unix epoch
+    #[serde(default)]
+    from: u64,
+    /// Only return the entries recorded at or before this time, in milliseconds since the Unix epoch
+    to: Option<u64>,
+}
+
+/// Format of the files the accounts are imported from and exported to
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum AccountsFormat {
+    /// An array of account details
+    Json,
+    /// A header row naming the account details' fields, followed by a row per account
+    Csv,
+}
+
+impl Default for AccountsFormat {
+    fn default() -> Self {
+        AccountsFormat::Json
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ExportQuery {
+    #[serde(default)]
+    format: AccountsFormat,
 }
 
+#[derive(Deserialize, Debug)]
+struct ImportQuery {
+    #[serde(default)]
+    format: AccountsFormat,
+    /// Import none of the accounts unless all of them can be
+    #[serde(default)]
+    atomic: bool,
+}
+
+/// Why a row of an imported file was not imported
+#[derive(Serialize, Debug)]
+struct ImportError {
+    /// Position of the row in the file, starting at 1 (not counting the CSV header)
+    row: usize,
+    /// Username of the row's account, if the row could be parsed
+    username: Option<String>,
+    error: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct SpspQuery {
+    /// Tags the generated connection, so the payments received over it can be told apart
+    tag: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ConnectionSettings {
+    /// The most the connections with the tag may receive in total, or `None` for any amount
+    #[serde(default, deserialize_with = "optional_number_or_string")]
+    receive_max: Option<u64>,
+}
+
+/// Whether the connection tag only contains characters valid in an ILP address segment,
+/// so that connections can be generated with it
+fn is_valid_connection_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '~' || c == '-')
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn accounts_api<I, O, S, A, B>(
     server_secret: Bytes,
     admin_api_token: String,
@@ -51,7 +166,11 @@ pub fn accounts_api<I, O, S, A, B>(
     incoming_handler: I,
     outgoing_handler: O,
     btp: BtpOutgoingService<B, A>,
+    ws_connections: Option<WsConnections>,
     store: S,
+    clearing_only: bool,
+    peer_monitor: Option<PeerMonitor>,
+    peer_scoreboard: Option<PeerScoreboard>,
 ) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
 where
     I: IncomingService<A> + Clone + Send + Sync + 'static,
@@ -62,6 +181,12 @@ where
         + AddressStore
         + HttpStore<Account = A>
         + BalanceStore
+        + BalanceHistoryStore
+        + SettlementHistoryStore
+        + UsageStore
+        + ContactStore
+        + WebhookStore
+        + StreamReceiveStore
         + StreamNotificationsStore<Account = A>
         + ExchangeRateStore
         + RouterStore,
@@ -177,6 +302,7 @@ where
             let handler = outgoing_handler_clone.clone();
             let btp = btp_clone.clone();
             async move {
+                check_clearing_only(clearing_only, account_details.configures_settlement())?;
                 let account = store.insert_account(account_details.clone()).await?;
 
                 connect_to_external_services(handler, account.clone(), store_clone, btp).await?;
@@ -184,6 +310,166 @@ where
             }
         });
 
+    // POST /accounts/verify
+    let post_accounts_verify = warp::post()
+        .and(warp::path("accounts"))
+        .and(warp::path("verify"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(deserialize_json())
+        .and(with_store.clone())
+        .and_then(|account_details: AccountDetails, store: S| async move {
+            let exists = store
+                .get_account_id_from_username(&account_details.username)
+                .await
+                .is_ok();
+            let report = verify_peer(&account_details, exists, &store.get_ilp_address()).await;
+            Ok::<Json, Rejection>(warp::reply::json(&report))
+        });
+
+    // POST /accounts/import
+    let btp_clone = btp.clone();
+    let outgoing_handler_clone = outgoing_handler.clone();
+    let post_accounts_import = warp::post()
+        .and(warp::path("accounts"))
+        .and(warp::path("import"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(warp::query::<ImportQuery>())
+        .and(warp::body::bytes())
+        .and(with_store.clone())
+        .and_then(move |query: ImportQuery, body: Bytes, store: S| {
+            let handler = outgoing_handler_clone.clone();
+            let btp = btp_clone.clone();
+            async move {
+                // Replied rather than rejected, since the `/accounts/:username` routes would
+                // otherwise take over the rejection
+                let rows = match parse_imported_accounts(query.format, &body) {
+                    Ok(rows) => rows,
+                    Err(err) => {
+                        return Ok::<_, Rejection>(
+                            ApiError::bad_request().detail(err).into_response(),
+                        )
+                    }
+                };
+
+                let mut errors = Vec::new();
+                let mut valid = Vec::new();
+                for (index, row) in rows.into_iter().enumerate() {
+                    let row_number = index + 1;
+                    match row {
+                        Ok(ref details) if clearing_only && details.configures_settlement() => {
+                            errors.push(ImportError {
+                                row: row_number,
+                                username: Some(details.username.to_string()),
+                                error: "settlement is disabled since the node only clears"
+                                    .to_string(),
+                            })
+                        }
+                        Ok(details) => valid.push((row_number, details)),
+                        Err(error) => errors.push(ImportError {
+                            row: row_number,
+                            username: None,
+                            error,
+                        }),
+                    }
+                }
+
+                let mut imported = Vec::new();
+                if query.atomic && !errors.is_empty() {
+                    for (row, details) in valid {
+                        let username = details.username.to_string();
+                        errors.push(ImportError {
+                            row,
+                            error: NodeStoreError::AccountNotInserted(username.clone()).to_string(),
+                            username: Some(username),
+                        });
+                    }
+                } else {
+                    let (rows, details): (Vec<usize>, Vec<AccountDetails>) =
+                        valid.into_iter().unzip();
+                    let usernames: Vec<String> = details
+                        .iter()
+                        .map(|details| details.username.to_string())
+                        .collect();
+                    let results = store.insert_accounts(details, query.atomic).await;
+                    for ((row, username), result) in rows.into_iter().zip(usernames).zip(results) {
+                        match result {
+                            Ok(account) => imported.push(account),
+                            Err(err) => errors.push(ImportError {
+                                row,
+                                username: Some(username),
+                                error: err.to_string(),
+                            }),
+                        }
+                    }
+                }
+                errors.sort_by_key(|error| error.row);
+
+                // A peer which can't be reached yet doesn't undo the import
+                for account in imported.iter() {
+                    if let Err(err) = connect_to_external_services(
+                        handler.clone(),
+                        account.clone(),
+                        store.clone(),
+                        btp.clone(),
+                    )
+                    .await
+                    {
+                        error!(
+                            "Error connecting imported account {} to external services: {:?}",
+                            account.username(),
+                            err
+                        );
+                    }
+                }
+                debug!(
+                    "Imported {} accounts, {} rows failed",
+                    imported.len(),
+                    errors.len()
+                );
+
+                let status = if query.atomic && !errors.is_empty() {
+                    http::StatusCode::BAD_REQUEST
+                } else {
+                    http::StatusCode::OK
+                };
+                Ok(warp::reply::with_status(
+                    warp::reply::json(&json!({ "imported": imported, "errors": errors })),
+                    status,
+                )
+                .into_response())
+            }
+        });
+
+    // GET /accounts/export
+    let get_accounts_export = warp::get()
+        .and(warp::path("accounts"))
+        .and(warp::path("export"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(warp::query::<ExportQuery>())
+        .and(with_store.clone())
+        .and_then(|query: ExportQuery, store: S| async move {
+            let mut accounts = store.export_accounts().await?;
+            accounts.sort_by_key(|account| account.username.to_string());
+            let (content_type, body) = match query.format {
+                AccountsFormat::Json => (
+                    "application/json",
+                    serde_json::to_vec(&accounts).map_err(|err| err.to_string()),
+                ),
+                AccountsFormat::Csv => ("text/csv", account_csv::write_accounts(&accounts)),
+            };
+            let body =
+                body.map_err(|err| Rejection::from(ApiError::internal_server_error().detail(err)))?;
+            Ok::<_, Rejection>(
+                http::Response::builder()
+                    .header("Content-Type", content_type)
+                    .body(body)
+                    .unwrap(),
+            )
+        });
+
     // GET /accounts
     let get_accounts = warp::get()
         .and(warp::path("accounts"))
@@ -197,6 +483,7 @@ where
 
     // PUT /accounts/:username
     let btp_clone = btp.clone();
+    let ws_connections_clone = ws_connections.clone();
     let outgoing_handler_clone = outgoing_handler.clone();
     let put_account = warp::put()
         .and(warp::path("accounts"))
@@ -208,15 +495,21 @@ where
         .and_then(move |id: Uuid, account_details: AccountDetails, store: S| {
             let outgoing_handler = outgoing_handler_clone.clone();
             let btp = btp_clone.clone();
-            if account_details.ilp_over_btp_incoming_token.is_some() {
-                // if the BTP token was provided, assume that it's different
-                // from the existing one and drop the connection
-                // the saved websocket connection
-                // a new one will be initialized in the `connect_to_external_services` call
-                btp.close_connection(&id);
-            }
+            let ws_connections = ws_connections_clone.clone();
             async move {
+                check_clearing_only(clearing_only, account_details.configures_settlement())?;
+                if account_details.ilp_over_btp_incoming_token.is_some() {
+                    // if the BTP token was provided, assume that it's different
+                    // from the existing one and drop the connection
+                    // the saved websocket connection
+                    // a new one will be initialized in the `connect_to_external_services` call
+                    btp.close_connection(&id);
+                }
                 let account = store.update_account(id, account_details).await?;
+                // The ILP over WebSocket connection is reopened with the new URL and token
+                if let Some(ref ws_connections) = ws_connections {
+                    ws_connections.close_connection(&id);
+                }
                 connect_to_external_services(outgoing_handler, account.clone(), store, btp).await?;
 
                 Ok::<Json, Rejection>(warp::reply::json(&account))
@@ -262,8 +555,155 @@ where
             }
         });
 
+    // GET /accounts/:username/balance/history
+    let get_account_balance_history = warp::get()
+        .and(warp::path("accounts"))
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("balance"))
+        .and(warp::path("history"))
+        .and(warp::path::end())
+        .and(warp::query::<HistoryQuery>())
+        .and(with_store.clone())
+        .and_then(|id: Uuid, query: HistoryQuery, store: S| async move {
+            let mut accounts = store.get_accounts(vec![id]).await?;
+            let account = accounts.pop().unwrap();
+            let samples = store
+                .get_balance_history(id, query.from, query.to.unwrap_or(u64::MAX))
+                .await?;
+
+            let scale = 10_u64.pow(account.asset_scale().into()) as f64;
+            let samples: Vec<_> = samples
+                .iter()
+                .map(|sample| {
+                    json!({
+                        "timestamp": sample.timestamp,
+                        // normalize to the base unit
+                        "balance": sample.balance as f64 / scale,
+                    })
+                })
+                .collect();
+            Ok::<Json, Rejection>(warp::reply::json(&json!({
+                "asset_code": account.asset_code(),
+                "samples": samples,
+            })))
+        });
+
+    // GET /accounts/:username/settlements
+    let get_account_settlements = warp::get()
+        .and(warp::path("accounts"))
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("settlements"))
+        .and(warp::path::end())
+        .and(warp::query::<HistoryQuery>())
+        .and(with_store.clone())
+        .and_then(|id: Uuid, query: HistoryQuery, store: S| async move {
+            let mut accounts = store.get_accounts(vec![id]).await?;
+            let account = accounts.pop().unwrap();
+            let settlements = store
+                .get_settlement_history(id, query.from, query.to.unwrap_or(u64::MAX))
+                .await?;
+
+            let scale = 10_u64.pow(account.asset_scale().into()) as f64;
+            let settlements: Vec<_> = settlements
+                .iter()
+                .map(|settlement| {
+                    json!({
+                        "timestamp": settlement.timestamp,
+                        "direction": settlement.direction,
+                        // normalize to the base unit
+                        "amount": settlement.amount as f64 / scale,
+                        "id": settlement.id,
+                    })
+                })
+                .collect();
+            Ok::<Json, Rejection>(warp::reply::json(&json!({
+                "asset_code": account.asset_code(),
+                "settlements": settlements,
+            })))
+        });
+
+    // GET /accounts/:username/usage
+    let get_account_usage = warp::get()
+        .and(warp::path("accounts"))
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("usage"))
+        .and(warp::path::end())
+        .and(with_store.clone())
+        .and_then(|id: Uuid, store: S| async move {
+            let daily = store.get_usage(id, UsagePeriod::Daily).await?;
+            let monthly = store.get_usage(id, UsagePeriod::Monthly).await?;
+            Ok::<Json, Rejection>(warp::reply::json(&json!({
+                "daily": daily,
+                "monthly": monthly,
+            })))
+        });
+
+    // GET /accounts/:username/health
+    let get_account_health = warp::get()
+        .and(warp::path("accounts"))
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("health"))
+        .and(warp::path::end())
+        .and_then(move |id: Uuid| {
+            let peer_monitor = peer_monitor.clone();
+            async move {
+                let peer_monitor = peer_monitor.ok_or_else(|| {
+                    ApiError::not_found().detail("the health of the peers is not monitored")
+                })?;
+                Ok::<Json, Rejection>(warp::reply::json(&peer_monitor.health(id)))
+            }
+        });
+
+    // POST /accounts/:username/reinstate
+    let post_account_reinstate = warp::post()
+        .and(warp::path("accounts"))
+        .and(warp::path::param::<Username>())
+        .and(warp::path("reinstate"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(with_store.clone())
+        .and_then(move |username: Username, store: S| {
+            let peer_scoreboard = peer_scoreboard.clone();
+            async move {
+                let peer_scoreboard = peer_scoreboard.ok_or_else(|| {
+                    ApiError::not_found().detail("the peers' behavior is not scored")
+                })?;
+                // Only the peers of existing accounts can be reinstated
+                store.get_account_id_from_username(&username).await?;
+                peer_scoreboard.reinstate(&username);
+                Ok::<_, Rejection>(warp::reply())
+            }
+        });
+
+    // DELETE /accounts/:username/usage
+    let delete_account_usage = warp::delete()
+        .and(warp::path("accounts"))
+        .and(account_username_to_id.clone())
+        .and(warp::path("usage"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(warp::query::<UsageQuery>())
+        .and(with_store.clone())
+        .and_then(|id: Uuid, query: UsageQuery, store: S| async move {
+            let periods = match query.period {
+                Some(period) => vec![period],
+                None => UsagePeriod::ALL.to_vec(),
+            };
+            for period in periods {
+                store.reset_usage(id, period).await?;
+            }
+
+            let daily = store.get_usage(id, UsagePeriod::Daily).await?;
+            let monthly = store.get_usage(id, UsagePeriod::Monthly).await?;
+            Ok::<Json, Rejection>(warp::reply::json(&json!({
+                "daily": daily,
+                "monthly": monthly,
+            })))
+        });
+
     // DELETE /accounts/:username
     let btp_clone = btp.clone();
+    let ws_connections_clone = ws_connections.clone();
     let delete_account = warp::delete()
         .and(warp::path("accounts"))
         .and(account_username_to_id.clone())
@@ -272,10 +712,15 @@ where
         .and(with_store.clone())
         .and_then(move |id: Uuid, store: S| {
             let btp = btp_clone.clone();
+            let ws_connections = ws_connections_clone.clone();
             async move {
                 let account = store.delete_account(id).await?;
                 // close the btp connection (if any)
                 btp.close_connection(&id);
+                btp.connections().remove_account(&id, account.username());
+                if let Some(ref ws_connections) = ws_connections {
+                    ws_connections.close_connection(&id);
+                }
                 Ok::<Json, Rejection>(warp::reply::json(&account))
             }
         });
@@ -291,15 +736,22 @@ where
         .and(with_store.clone())
         .and_then(move |id: Uuid, settings: AccountSettings, store: S| {
             let btp = btp.clone();
+            let ws_connections = ws_connections.clone();
             let outgoing_handler = outgoing_handler_clone.clone();
             async move {
+                check_clearing_only(clearing_only, settings.configures_settlement())?;
                 if settings.ilp_over_btp_incoming_token.is_some() {
                     // if the BTP token was provided, assume that it's different
                     // from the existing one and drop the connection
                     // the saved websocket connection
                     btp.close_connection(&id);
                 }
+                let http_changed = settings.ilp_over_http_url.is_some()
+                    || settings.ilp_over_http_outgoing_token.is_some();
                 let modified_account = store.modify_account_settings(id, settings).await?;
+                if let (true, Some(ws_connections)) = (http_changed, ws_connections.as_ref()) {
+                    ws_connections.close_connection(&id);
+                }
 
                 // Since the account was modified, we should also try to
                 // connect to the new account:
@@ -316,7 +768,7 @@ where
 
     // (Websocket) /accounts/:username/payments/incoming
     let incoming_payment_notifications = warp::path("accounts")
-        .and(admin_or_authorized_user_only)
+        .and(admin_or_authorized_user_only.clone())
         .and(warp::path("payments"))
         .and(warp::path("incoming"))
         .and(warp::path::end())
@@ -325,14 +777,33 @@ where
         .map(|id: Uuid, ws: warp::ws::Ws, store: S| {
             ws.on_upgrade(move |ws: warp::ws::WebSocket| {
                 let (ws_tx, ws_rx) = ws.split();
-                tokio::task::spawn(notify_user(ws_tx, id, store).map(|result| result.unwrap()));
+                tokio::task::spawn(
+                    notify_user(ws_tx, id, store, false).map(|result| result.unwrap()),
+                );
+                consume_msg_drain(ws_rx)
+            })
+        });
+
+    // (Websocket) /accounts/:username/payments
+    let fulfilled_payment_notifications = warp::path("accounts")
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("payments"))
+        .and(warp::path::end())
+        .and(warp::ws())
+        .and(with_store.clone())
+        .map(|id: Uuid, ws: warp::ws::Ws, store: S| {
+            ws.on_upgrade(move |ws: warp::ws::WebSocket| {
+                let (ws_tx, ws_rx) = ws.split();
+                tokio::task::spawn(
+                    notify_user(ws_tx, id, store, true).map(|result| result.unwrap()),
+                );
                 consume_msg_drain(ws_rx)
             })
         });
 
     // (Websocket) /payments/incoming
     let all_payment_notifications = warp::path("payments")
-        .and(admin_only)
+        .and(admin_only.clone())
         .and(warp::path("incoming"))
         .and(warp::path::end())
         .and(warp::ws())
@@ -347,38 +818,219 @@ where
 
     // POST /accounts/:username/payments
     let post_payments = warp::post()
+        .and(warp::path("accounts"))
+        .and(authorized_user_only.clone())
+        .and(warp::path("payments"))
+        .and(warp::path::end())
+        .and(deserialize_json())
+        .and(with_incoming_handler.clone())
+        .and(with_store.clone())
+        .and_then(
+            move |account: A, pay_request: SpspPayRequest, incoming_handler: I, store: S| {
+                async move {
+                    let payment = match pay_request.destination_amount {
+                        Some(destination_amount) => pay_to_deliver(
+                            incoming_handler,
+                            account.clone(),
+                            store,
+                            &pay_request.receiver,
+                            destination_amount,
+                            pay_request.source_amount,
+                            pay_request.slippage,
+                        )
+                        .left_future(),
+                        None => pay(
+                            incoming_handler,
+                            account.clone(),
+                            store,
+                            &pay_request.receiver,
+                            pay_request.source_amount,
+                            pay_request.slippage,
+                        )
+                        .right_future(),
+                    };
+                    let receipt = payment
+                        .map_err(|err| {
+                            error!("Error sending SPSP payment: {}", err);
+                            Rejection::from(spsp_pay_error(err))
+                        })
+                        .await?;
+
+                    debug!("Sent SPSP payment, receipt: {:?}", receipt);
+                    Ok::<Json, Rejection>(warp::reply::json(&json!(receipt)))
+                }
+            },
+        );
+
+    // GET /accounts/:username/contacts
+    let get_contacts = warp::get()
+        .and(warp::path("accounts"))
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("contacts"))
+        .and(warp::path::end())
+        .and(with_store.clone())
+        .and_then(|id: Uuid, store: S| async move {
+            let contacts = store.get_contacts(id).await?;
+            Ok::<Json, Rejection>(warp::reply::json(&contacts))
+        });
+
+    // PUT /accounts/:username/contacts/:name
+    let put_contact = warp::put()
+        .and(warp::path("accounts"))
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("contacts"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(deserialize_json())
+        .and(with_store.clone())
+        .and_then(
+            |id: Uuid, name: String, details: ContactDetails, store: S| async move {
+                let contact = Contact::new(name, details.payment_pointer).map_err(|err| {
+                    Rejection::from(ApiError::bad_request().detail(err.to_string()))
+                })?;
+                store.save_contact(id, contact.clone()).await?;
+                Ok::<Json, Rejection>(warp::reply::json(&contact))
+            },
+        );
+
+    // DELETE /accounts/:username/contacts/:name
+    let delete_contact = warp::delete()
+        .and(warp::path("accounts"))
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("contacts"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(with_store.clone())
+        .and_then(|id: Uuid, name: String, store: S| async move {
+            store.delete_contact(id, &name).await?;
+            Ok::<_, Rejection>(warp::reply())
+        });
+
+    // POST /accounts/:username/contacts/:name/payments
+    let post_contact_payments = warp::post()
         .and(warp::path("accounts"))
         .and(authorized_user_only)
+        .and(warp::path("contacts"))
+        .and(warp::path::param::<String>())
         .and(warp::path("payments"))
         .and(warp::path::end())
         .and(deserialize_json())
         .and(with_incoming_handler)
         .and(with_store.clone())
         .and_then(
-            move |account: A, pay_request: SpspPayRequest, incoming_handler: I, store: S| {
+            move |account: A,
+                  name: String,
+                  pay_request: ContactPayRequest,
+                  incoming_handler: I,
+                  store: S| {
                 async move {
-                    let receipt = pay(
+                    let receipt = pay_contact(
                         incoming_handler,
-                        account.clone(),
+                        account,
                         store,
-                        &pay_request.receiver,
+                        &name,
                         pay_request.source_amount,
                         pay_request.slippage,
                     )
                     .map_err(|err| {
-                        let msg = format!("Error sending SPSP payment: {}", err);
-                        error!("{}", msg);
-                        // TODO give a different error message depending on what type of error it is
-                        Rejection::from(ApiError::internal_server_error().detail(msg))
+                        error!("Error paying contact {}: {}", name, err);
+                        Rejection::from(spsp_pay_error(err))
                     })
                     .await?;
 
-                    debug!("Sent SPSP payment, receipt: {:?}", receipt);
+                    debug!("Paid contact {}, receipt: {:?}", name, receipt);
                     Ok::<Json, Rejection>(warp::reply::json(&json!(receipt)))
                 }
             },
         );
 
+    // GET /accounts/:username/webhook
+    let get_webhook = warp::get()
+        .and(warp::path("accounts"))
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("webhook"))
+        .and(warp::path::end())
+        .and(with_store.clone())
+        .and_then(|id: Uuid, store: S| async move {
+            let webhook = store.get_webhook(id).await?;
+            Ok::<Json, Rejection>(warp::reply::json(&webhook_response(&webhook)))
+        });
+
+    // PUT /accounts/:username/webhook
+    // Only the admin can set the URL, since the node POSTs the notifications to it from its
+    // own network
+    let put_webhook = warp::put()
+        .and(warp::path("accounts"))
+        .and(account_username_to_id.clone())
+        .and(warp::path("webhook"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(deserialize_json())
+        .and(with_store.clone())
+        .and_then(|id: Uuid, webhook: Webhook, store: S| async move {
+            if webhook.url.scheme() != "http" && webhook.url.scheme() != "https" {
+                return Err(Rejection::from(
+                    ApiError::bad_request().detail("the webhook url must be http or https"),
+                ));
+            }
+            if webhook.secret.is_empty() {
+                return Err(Rejection::from(
+                    ApiError::bad_request().detail("the webhook secret must not be empty"),
+                ));
+            }
+            store.set_webhook(id, webhook.clone()).await?;
+            Ok::<Json, Rejection>(warp::reply::json(&webhook_response(&webhook)))
+        });
+
+    // DELETE /accounts/:username/webhook
+    let delete_webhook = warp::delete()
+        .and(warp::path("accounts"))
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("webhook"))
+        .and(warp::path::end())
+        .and(with_store.clone())
+        .and_then(|id: Uuid, store: S| async move {
+            store.delete_webhook(id).await?;
+            Ok::<_, Rejection>(warp::reply())
+        });
+
+    // GET /accounts/:username/connections/:tag
+    let get_connection = warp::get()
+        .and(warp::path("accounts"))
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("connections"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(with_store.clone())
+        .and_then(|id: Uuid, tag: String, store: S| async move {
+            let receipts = store.get_connection_receipts(id, &tag).await?;
+            Ok::<Json, Rejection>(warp::reply::json(&receipts))
+        });
+
+    // PUT /accounts/:username/connections/:tag
+    let put_connection = warp::put()
+        .and(warp::path("accounts"))
+        .and(admin_or_authorized_user_only.clone())
+        .and(warp::path("connections"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(deserialize_json())
+        .and(with_store.clone())
+        .and_then(
+            |id: Uuid, tag: String, settings: ConnectionSettings, store: S| async move {
+                if !is_valid_connection_tag(&tag) {
+                    return Err(Rejection::from(ApiError::bad_request().detail(
+                        "the connection tag may only contain letters, digits, `_`, `~` and `-`",
+                    )));
+                }
+                store
+                    .set_connection_receive_max(id, &tag, settings.receive_max)
+                    .await?;
+                let receipts = store.get_connection_receipts(id, &tag).await?;
+                Ok::<Json, Rejection>(warp::reply::json(&receipts))
+            },
+        );
+
     // GET /accounts/:username/spsp
     let server_secret_clone = server_secret.clone();
     let get_spsp = warp::get()
@@ -386,34 +1038,47 @@ where
         .and(account_username_to_id)
         .and(warp::path("spsp"))
         .and(warp::path::end())
+        .and(warp::query::<SpspQuery>())
+        .and(warp::header::headers_cloned())
         .and(with_store.clone())
-        .and_then(move |id: Uuid, store: S| {
-            let server_secret_clone = server_secret_clone.clone();
-            async move {
-                let accounts = store.get_accounts(vec![id]).await?;
-                // TODO return the response without instantiating an SpspResponder (use a simple fn)
-                Ok::<_, Rejection>(
-                    SpspResponder::new(
+        .and_then(
+            move |id: Uuid, query: SpspQuery, headers: http::HeaderMap, store: S| {
+                let server_secret_clone = server_secret_clone.clone();
+                async move {
+                    let receipt_details = receipt_details(&headers).map_err(spsp_receipt_error)?;
+                    let accounts = store.get_accounts(vec![id]).await?;
+                    // TODO return the response without instantiating an SpspResponder (use a simple fn)
+                    let mut responder = SpspResponder::new(
                         accounts[0].ilp_address().clone(),
                         server_secret_clone.clone(),
-                    )
-                    .generate_http_response(),
-                )
-            }
-        });
-
-    // GET /.well-known/pay
-    // This is the endpoint a [Payment Pointer](https://github.com/interledger/rfcs/blob/master/0026-payment-pointers/0026-payment-pointers.md)
-    // with no path resolves to
+                    );
+                    if let Some(tag) = query.tag {
+                        responder = responder.with_connection_tag(tag);
+                    }
+                    Ok::<_, Rejection>(match receipt_details {
+                        Some(ref receipt_details) => {
+                            responder.generate_http_response_with_receipts(receipt_details)
+                        }
+                        None => responder.generate_http_response(),
+                    })
+                }
+            },
+        );
+
+    // GET /.well-known/pay
+    // This is the endpoint a [Payment Pointer](https://github.com/interledger/rfcs/blob/master/0026-payment-pointers/0026-payment-pointers.md)
+    // with no path resolves to
     let get_spsp_well_known = warp::get()
         .and(warp::path(".well-known"))
         .and(warp::path("pay"))
         .and(warp::path::end())
+        .and(warp::header::headers_cloned())
         .and(with_store)
-        .and_then(move |store: S| {
+        .and_then(move |headers: http::HeaderMap, store: S| {
             let default_spsp_account = default_spsp_account.clone();
             let server_secret_clone = server_secret.clone();
             async move {
+                let receipt_details = receipt_details(&headers).map_err(spsp_receipt_error)?;
                 if let Some(ref username) = default_spsp_account {
                     let id = store.get_account_id_from_username(&username).await?;
 
@@ -422,13 +1087,16 @@ where
 
                     let account = accounts.pop().unwrap();
                     // TODO return the response without instantiating an SpspResponder (use a simple fn)
-                    Ok::<_, Rejection>(
-                        SpspResponder::new(
-                            account.ilp_address().clone(),
-                            server_secret_clone.clone(),
-                        )
-                        .generate_http_response(),
-                    )
+                    let responder = SpspResponder::new(
+                        account.ilp_address().clone(),
+                        server_secret_clone.clone(),
+                    );
+                    Ok::<_, Rejection>(match receipt_details {
+                        Some(ref receipt_details) => {
+                            responder.generate_http_response_with_receipts(receipt_details)
+                        }
+                        None => responder.generate_http_response(),
+                    })
                 } else {
                     Err(Rejection::from(
                         ApiError::not_found().detail("no default spsp account was configured"),
@@ -439,16 +1107,116 @@ where
 
     get_spsp
         .or(get_spsp_well_known)
+        .or(get_accounts_export)
+        .or(post_accounts_import)
+        .or(post_accounts_verify)
         .or(post_accounts)
         .or(get_accounts)
         .or(put_account)
         .or(delete_account)
         .or(get_account)
         .or(get_account_balance)
+        .or(get_account_balance_history)
+        .or(get_account_settlements)
+        .or(get_account_usage)
+        .or(get_account_health)
+        .or(post_account_reinstate)
+        .or(delete_account_usage)
         .or(put_account_settings)
         .or(incoming_payment_notifications)
+        .or(fulfilled_payment_notifications)
         .or(all_payment_notifications)
         .or(post_payments)
+        .or(get_contacts)
+        .or(put_contact)
+        .or(delete_contact)
+        .or(post_contact_payments)
+        .or(get_webhook)
+        .or(put_webhook)
+        .or(get_connection)
+        .or(put_connection)
+        .or(delete_webhook)
+}
+
+/// Parses the file's rows into account details, returning an error for each row which is invalid
+fn parse_imported_accounts(
+    format: AccountsFormat,
+    body: &[u8],
+) -> Result<Vec<Result<AccountDetails, String>>, String> {
+    match format {
+        AccountsFormat::Csv => account_csv::parse_accounts(body),
+        AccountsFormat::Json => {
+            let rows: Vec<serde_json::Value> = serde_json::from_slice(body)
+                .map_err(|err| format!("expected an array of accounts: {}", err))?;
+            // Usernames and addresses can only be deserialized from borrowed strings
+            Ok(rows
+                .into_iter()
+                .map(|row| {
+                    let row = serde_json::to_vec(&row).map_err(|err| err.to_string())?;
+                    serde_json::from_slice(&row).map_err(|err| err.to_string())
+                })
+                .collect())
+        }
+    }
+}
+
+/// The webhook as returned by the API, which never returns its secret
+fn webhook_response(webhook: &Webhook) -> serde_json::Value {
+    json!({
+        "url": webhook.url,
+        "events": webhook.events,
+        "balance_thresholds": webhook.balance_thresholds,
+    })
+}
+
+/// The receiver's SPSP server could not be queried or returned an invalid response (502 Bad Gateway)
+const SPSP_QUERY_ERROR_TYPE: ApiErrorType = ApiErrorType {
+    r#type: &ProblemType::Default,
+    title: "Unable to query the receiver's SPSP server",
+    status: http::StatusCode::BAD_GATEWAY,
+};
+
+/// None of the packets probing the exchange rate reached the receiver (502 Bad Gateway)
+const RATE_PROBE_ERROR_TYPE: ApiErrorType = ApiErrorType {
+    r#type: &ProblemType::Default,
+    title: "Unable to probe the exchange rate to the receiver",
+    status: http::StatusCode::BAD_GATEWAY,
+};
+
+/// The maximum source amount was sent without delivering the destination amount
+/// (422 Unprocessable Entity)
+const AMOUNT_NOT_DELIVERED_ERROR_TYPE: ApiErrorType = ApiErrorType {
+    r#type: &ProblemType::Default,
+    title: "The source amount was not enough to deliver the destination amount",
+    status: http::StatusCode::UNPROCESSABLE_ENTITY,
+};
+
+/// Refuses the SPSP queries with malformed receipt headers (400 Bad Request)
+fn spsp_receipt_error(err: SpspError) -> Rejection {
+    Rejection::from(ApiError::bad_request().detail(err.to_string()))
+}
+
+/// Converts the error of a failed SPSP payment into the API error, telling apart the invalid
+/// requests, the receivers which could not be queried or probed, the amounts which could
+/// not be delivered and the other failed payments
+fn spsp_pay_error(err: SpspError) -> ApiError {
+    let detail = format!("Error sending SPSP payment: {}", err);
+    match err {
+        SpspError::InvalidPaymentPointerError(_) => ApiError::bad_request().detail(detail),
+        SpspError::ContactStoreError(ContactStoreError::ContactNotFound(_)) => {
+            ApiError::not_found().detail(detail)
+        }
+        SpspError::HttpError(_) | SpspError::InvalidSpspServerResponseError(_) => {
+            ApiError::from_api_error_type(&SPSP_QUERY_ERROR_TYPE).detail(detail)
+        }
+        SpspError::StreamError(StreamError::RateProbeFailed) => {
+            ApiError::from_api_error_type(&RATE_PROBE_ERROR_TYPE).detail(detail)
+        }
+        SpspError::StreamError(StreamError::DestinationAmountNotDelivered(..)) => {
+            ApiError::from_api_error_type(&AMOUNT_NOT_DELIVERED_ERROR_TYPE).detail(detail)
+        }
+        _ => ApiError::internal_server_error().detail(detail),
+    }
 }
 
 async fn consume_msg_drain(mut ws_rx: futures::stream::SplitStream<warp::ws::WebSocket>) {
@@ -460,10 +1228,13 @@ async fn consume_msg_drain(mut ws_rx: futures::stream::SplitStream<warp::ws::Web
     }
 }
 
+// If `fulfilled_only` is set, the notifications of closed connections are not forwarded,
+// so the client receives exactly one message per fulfilled packet.
 fn notify_user(
     ws_tx: futures::stream::SplitSink<warp::ws::WebSocket, warp::ws::Message>,
     id: Uuid,
     store: impl StreamNotificationsStore,
+    fulfilled_only: bool,
 ) -> impl Future<Output = Result<(), ()>> {
     let (tx, rx) = futures::channel::mpsc::unbounded::<PaymentNotification>();
     // the client is now subscribed
@@ -471,6 +1242,9 @@ fn notify_user(
 
     // Anytime something is written to tx, it will reach rx
     // and get converted to a warp::ws::Message
+    let rx = rx.filter(move |notification: &PaymentNotification| {
+        futures::future::ready(!(fulfilled_only && notification.connection_closed))
+    });
     let rx = rx.map(|notification: PaymentNotification| {
         let msg = warp::ws::Message::text(serde_json::to_string(&notification).unwrap());
         Ok(msg)
@@ -549,8 +1323,7 @@ where
     })?;
     debug!("Got ILDCP response from parent: {:?}", info);
     let ilp_address = info.ilp_address();
-
-    debug!("ILP address is now: {}", ilp_address);
+    debug!("Parent assigned the ILP address: {}", ilp_address);
     // TODO we may want to make this trigger the CcpRouteManager to request
     let prepare = RouteControlRequest {
         mode: Mode::Sync,
@@ -560,11 +1333,30 @@ where
     }
     .to_prepare();
 
-    // Set the parent to be the default route for everything
-    // that starts with their global prefix
-    store.set_default_route(parent.id()).await?;
-    // Update our store's address
-    store.set_ilp_address(ilp_address).await?;
+    // The address of the first parent is the node's primary address. When the node is
+    // multihomed, the addresses assigned by its other parents are secondary ones, which it
+    // recognizes as its own too. The primary address is only replaced if the node did not
+    // get one yet (it still has the default local.host address)
+    let node_address = store.get_ilp_address();
+    let has_other_parents = store.get_all_accounts().await?.iter().any(|account| {
+        account.routing_relation() == RoutingRelation::Parent && account.id() != parent.id()
+    });
+    if has_other_parents && ilp_address != node_address && node_address.scheme() != "local" {
+        let mut secondary_addresses = store.get_secondary_ilp_addresses();
+        if !secondary_addresses.contains(&ilp_address) {
+            debug!("Adding secondary ILP address: {}", ilp_address);
+            secondary_addresses.push(ilp_address);
+            store
+                .set_secondary_ilp_addresses(secondary_addresses)
+                .await?;
+        }
+    } else {
+        // Set the parent to be the default route for everything
+        // that starts with their global prefix
+        store.set_default_route(parent.id()).await?;
+        // Update our store's address
+        store.set_ilp_address(ilp_address).await?;
+    }
 
     // Get the parent's routes for us
     debug!("Asking for routes from {:?}", parent.clone());
@@ -748,6 +1540,121 @@ mod tests {
         assert_eq!(resp.status().as_u16(), 401);
     }
 
+    #[tokio::test]
+    async fn only_admin_or_user_can_get_accounts_balance_history() {
+        let api = test_accounts_api();
+        let resp = api_call(
+            &api,
+            "GET",
+            "/accounts/alice/balance/history",
+            "admin",
+            None,
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 200);
+
+        let resp = api_call(
+            &api,
+            "GET",
+            "/accounts/alice/balance/history?from=1000&to=2000",
+            "password",
+            None,
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 200);
+
+        let resp = api_call(
+            &api,
+            "GET",
+            "/accounts/alice/balance/history",
+            "wrong",
+            None,
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
+
+    #[tokio::test]
+    async fn only_admin_or_user_can_get_accounts_settlements() {
+        let api = test_accounts_api();
+        let resp = api_call(&api, "GET", "/accounts/alice/settlements", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["settlements"][0]["direction"], "incoming");
+
+        let resp = api_call(
+            &api,
+            "GET",
+            "/accounts/alice/settlements?from=1000&to=2000",
+            "password",
+            None,
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 200);
+
+        let resp = api_call(&api, "GET", "/accounts/alice/settlements", "wrong", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
+
+    #[tokio::test]
+    async fn only_admin_or_user_can_get_accounts_usage() {
+        let api = test_accounts_api();
+        let resp = api_call(&api, "GET", "/accounts/alice/usage", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+
+        let resp = api_call(&api, "GET", "/accounts/alice/usage", "password", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+
+        let resp = api_call(&api, "GET", "/accounts/alice/usage", "wrong", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
+
+    #[tokio::test]
+    async fn only_admin_or_user_can_get_accounts_health() {
+        let api = test_accounts_api();
+        let resp = api_call(&api, "GET", "/accounts/alice/health", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let health: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(health["healthy"], true);
+        assert_eq!(health["connectivity"], "unknown");
+
+        let resp = api_call(&api, "GET", "/accounts/alice/health", "password", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+
+        let resp = api_call(&api, "GET", "/accounts/alice/health", "wrong", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
+
+    #[tokio::test]
+    async fn only_admin_can_reinstate_peers() {
+        let api = test_accounts_api();
+        let resp = api_call(&api, "POST", "/accounts/alice/reinstate", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+
+        let resp = api_call(&api, "POST", "/accounts/alice/reinstate", "password", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
+
+    #[tokio::test]
+    async fn only_admin_can_reset_accounts_usage() {
+        let api = test_accounts_api();
+        let resp = api_call(&api, "DELETE", "/accounts/alice/usage", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+
+        let resp = api_call(
+            &api,
+            "DELETE",
+            "/accounts/alice/usage?period=monthly",
+            "admin",
+            None,
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 200);
+
+        let resp = api_call(&api, "DELETE", "/accounts/alice/usage", "password", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
+
     #[tokio::test]
     async fn only_admin_or_user_can_modify_accounts_settings() {
         let api = test_accounts_api();
@@ -783,6 +1690,31 @@ mod tests {
         assert_eq!(resp.status().as_u16(), 401);
     }
 
+    #[tokio::test]
+    async fn clearing_only_node_rejects_settlement_configuration() {
+        let api = test_clearing_only_accounts_api();
+        let resp = api_call(&api, "POST", "/accounts", "admin", DETAILS.clone()).await;
+        assert_eq!(resp.status().as_u16(), 200);
+
+        let mut details = DETAILS.clone().unwrap();
+        details["settle_threshold"] = serde_json::json!(1000);
+        let resp = api_call(&api, "POST", "/accounts", "admin", Some(details.clone())).await;
+        assert_eq!(resp.status().as_u16(), 400);
+        let resp = api_call(&api, "PUT", "/accounts/alice", "admin", Some(details)).await;
+        assert_eq!(resp.status().as_u16(), 400);
+
+        let settings = serde_json::json!({ "settle_to": 0 });
+        let resp = api_call(
+            &api,
+            "PUT",
+            "/accounts/alice/settings",
+            "admin",
+            Some(settings),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 400);
+    }
+
     #[tokio::test]
     async fn only_admin_or_user_can_send_payment() {
         let payment: Option<serde_json::Value> = Some(serde_json::json!({
@@ -798,10 +1730,10 @@ mod tests {
             payment.clone(),
         )
         .await;
-        // This should return an internal server error since we're making an invalid payment request
+        // This should return a bad request error since the receiver is not a valid payment pointer
         // We could have set up a mockito mock to set that pay is called correctly but we merely want
         // to check that authorization and paths work as expected
-        assert_eq!(resp.status().as_u16(), 500);
+        assert_eq!(resp.status().as_u16(), 400);
 
         // Note that the operator has indirect access to the user's token since they control the store
         let resp = api_call(
@@ -824,4 +1756,267 @@ mod tests {
         .await;
         assert_eq!(resp.status().as_u16(), 401);
     }
+
+    #[tokio::test]
+    async fn payment_to_unreachable_receiver_is_bad_gateway() {
+        // Nothing listens on port 1, so the SPSP query fails
+        let payment = serde_json::json!({
+            "receiver": "http://127.0.0.1:1/",
+            "source_amount" : 10,
+        });
+        let api = test_accounts_api();
+        let resp = api_call(
+            &api,
+            "POST",
+            "/accounts/alice/payments",
+            "password",
+            Some(payment),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 502);
+    }
+
+    #[tokio::test]
+    async fn manages_contacts() {
+        let api = test_accounts_api();
+        let resp = api_call(&api, "GET", "/accounts/alice/contacts", "password", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let resp = api_call(&api, "GET", "/accounts/alice/contacts", "wrong", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+
+        let contact = serde_json::json!({ "payment_pointer": "$example.com/bob" });
+        let resp = api_call(
+            &api,
+            "PUT",
+            "/accounts/alice/contacts/bob",
+            "password",
+            Some(contact.clone()),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let resp = api_call(
+            &api,
+            "PUT",
+            "/accounts/alice/contacts/b%20b",
+            "password",
+            Some(contact),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 400);
+        let resp = api_call(
+            &api,
+            "PUT",
+            "/accounts/alice/contacts/bob",
+            "password",
+            Some(serde_json::json!({ "payment_pointer": "bob" })),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 400);
+
+        let resp = api_call(
+            &api,
+            "DELETE",
+            "/accounts/alice/contacts/bob",
+            "admin",
+            None,
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 200);
+    }
+
+    #[tokio::test]
+    async fn manages_connection_receive_max() {
+        let api = test_accounts_api();
+        let resp = api_call(
+            &api,
+            "GET",
+            "/accounts/alice/connections/invoice-7",
+            "password",
+            None,
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let receipts: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(
+            receipts,
+            serde_json::json!({ "total_received": 100, "receive_max": 1000 })
+        );
+
+        let settings = serde_json::json!({ "receive_max": "1000" });
+        let resp = api_call(
+            &api,
+            "PUT",
+            "/accounts/alice/connections/invoice-7",
+            "wrong",
+            Some(settings.clone()),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 401);
+        let resp = api_call(
+            &api,
+            "PUT",
+            "/accounts/alice/connections/invoice-7",
+            "password",
+            Some(settings.clone()),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let resp = api_call(
+            &api,
+            "PUT",
+            "/accounts/alice/connections/invoice%207",
+            "password",
+            Some(settings),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 400);
+    }
+
+    #[tokio::test]
+    async fn exports_accounts() {
+        let api = test_accounts_api();
+        let resp = api_call(&api, "GET", "/accounts/export", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let accounts: Vec<serde_json::Value> = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(accounts[0]["username"], "alice");
+
+        let resp = api_call(&api, "GET", "/accounts/export?format=csv", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(resp.headers()["Content-Type"], "text/csv");
+        let body = std::str::from_utf8(resp.body()).unwrap();
+        assert!(body.starts_with("ilp_address,username,"));
+        assert!(body.contains("example.alice,alice,XYZ,9"));
+
+        let resp = api_call(&api, "GET", "/accounts/export", "password", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
+
+    #[tokio::test]
+    async fn imports_accounts() {
+        let api = test_accounts_api();
+        let accounts = serde_json::json!([DETAILS.clone().unwrap(), { "username": "bob" }]);
+        let resp = api_call(
+            &api,
+            "POST",
+            "/accounts/import",
+            "admin",
+            Some(accounts.clone()),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let report: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(report["imported"].as_array().unwrap().len(), 1);
+        assert_eq!(report["errors"][0]["row"], 2);
+
+        // Nothing is imported if a row is invalid
+        let resp = api_call(
+            &api,
+            "POST",
+            "/accounts/import?atomic=true",
+            "admin",
+            Some(accounts),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 400);
+        let report: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert!(report["imported"].as_array().unwrap().is_empty());
+        assert_eq!(report["errors"][0]["username"], "alice");
+        assert_eq!(report["errors"].as_array().unwrap().len(), 2);
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/accounts/import?format=csv&atomic=true")
+            .header("Authorization", "Bearer admin")
+            .body("username,asset_code,asset_scale\nalice,XYZ,9\nbob,XYZ,2")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let report: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(report["imported"].as_array().unwrap().len(), 2);
+
+        let resp = warp::test::request()
+            .method("POST")
+            .path("/accounts/import?format=csv")
+            .header("Authorization", "Bearer admin")
+            .body("username,currency\nalice,XYZ")
+            .reply(&api)
+            .await;
+        assert_eq!(resp.status().as_u16(), 400);
+    }
+
+    #[tokio::test]
+    async fn manages_webhook() {
+        let api = test_accounts_api();
+        let resp = api_call(&api, "GET", "/accounts/alice/webhook", "password", None).await;
+        assert_eq!(resp.status().as_u16(), 404);
+
+        let webhook = serde_json::json!({
+            "url": "https://example.com/hook",
+            "secret": "secret",
+            "events": ["balance_threshold_crossed"],
+            "balance_thresholds": [-1000, 0],
+        });
+        // Only the admin can set the webhook
+        let resp = api_call(
+            &api,
+            "PUT",
+            "/accounts/alice/webhook",
+            "password",
+            Some(webhook.clone()),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 401);
+        let resp = api_call(
+            &api,
+            "PUT",
+            "/accounts/alice/webhook",
+            "admin",
+            Some(webhook.clone()),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 200);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["url"], "https://example.com/hook");
+        assert!(body.get("secret").is_none());
+
+        let mut webhook = webhook;
+        webhook["events"] = serde_json::json!(["unknown"]);
+        let resp = api_call(
+            &api,
+            "PUT",
+            "/accounts/alice/webhook",
+            "admin",
+            Some(webhook),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 400);
+
+        let resp = api_call(&api, "DELETE", "/accounts/alice/webhook", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+    }
+
+    #[tokio::test]
+    async fn paying_unknown_contact_is_not_found() {
+        let payment = serde_json::json!({ "source_amount": 10 });
+        let api = test_accounts_api();
+        let resp = api_call(
+            &api,
+            "POST",
+            "/accounts/alice/contacts/carol/payments",
+            "password",
+            Some(payment.clone()),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 404);
+
+        let resp = api_call(
+            &api,
+            "POST",
+            "/accounts/alice/contacts/carol/payments",
+            "admin",
+            Some(payment),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
 }
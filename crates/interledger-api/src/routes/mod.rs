@@ -1,8 +1,23 @@
+use interledger_errors::ApiError;
+use warp::Rejection;
+
 mod accounts;
 mod node_settings;
+mod peer_verification;
 
 pub use accounts::accounts_api;
 pub use node_settings::node_settings_api;
 
 #[cfg(test)]
 pub mod test_helpers;
+
+/// Rejects the request if it would configure settlement on a node which only clears
+fn check_clearing_only(clearing_only: bool, configures_settlement: bool) -> Result<(), Rejection> {
+    if clearing_only && configures_settlement {
+        Err(ApiError::bad_request()
+            .detail("settlement is disabled since the node only clears")
+            .into())
+    } else {
+        Ok(())
+    }
+}
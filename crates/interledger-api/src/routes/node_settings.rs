@@ -1,37 +1,74 @@
+use super::check_clearing_only;
 use crate::{ExchangeRates, NodeStore};
 use bytes::Bytes;
 use futures::TryFutureExt;
+use interledger_ccp::RouteExpiries;
 use interledger_errors::*;
 use interledger_http::{deserialize_json, HttpAccount};
 use interledger_packet::Address;
 use interledger_rates::ExchangeRateStore;
 use interledger_router::RouterStore;
 use interledger_service::{Account, AccountStore, AddressStore, Username};
-use interledger_settlement::core::{types::SettlementAccount, SettlementClient};
+use interledger_settlement::core::{
+    types::SettlementAccount, Reconciler, SettlementClient, SettlementScheduler,
+};
 use secrecy::{ExposeSecret, SecretString};
 use serde::Serialize;
 use std::{
     collections::HashMap,
     str::{self, FromStr},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 use tracing::{error, trace};
 use url::Url;
 use uuid::Uuid;
-use warp::{self, reply::Json, Filter, Rejection};
+use warp::{self, http::StatusCode, reply::Json, Filter, Rejection};
 
 // TODO add more to this response
 #[derive(Clone, Serialize)]
 struct StatusResponse {
     status: String,
     ilp_address: Address,
+    /// Addresses assigned by the node's other parents, if it is multihomed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    secondary_ilp_addresses: Vec<Address>,
     #[serde(skip_serializing_if = "Option::is_none")]
     version: Option<String>,
 }
 
+/// Where a route in the routing table comes from
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum RouteSource {
+    /// Configured with `PUT /routes/static`
+    Static,
+    /// Configured with `PUT /routes/default`
+    Default,
+    /// The address of one of the node's accounts
+    Local,
+    /// Learned from a peer via CCP
+    Ccp,
+}
+
+#[derive(Serialize)]
+struct RouteDetails {
+    prefix: String,
+    username: String,
+    source: RouteSource,
+    /// When the route expires unless the peer advertising it sends another update, in
+    /// milliseconds since the Unix epoch. Only set for the routes learned from peers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
 pub fn node_settings_api<S, A>(
     admin_api_token: String,
     node_version: Option<String>,
+    settlement_scheduler: Option<SettlementScheduler>,
+    reconciler: Option<Reconciler>,
+    route_expiries: Option<RouteExpiries>,
     store: S,
+    clearing_only: bool,
 ) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone
 where
     S: NodeStore<Account = A>
@@ -69,6 +106,7 @@ where
             warp::reply::json(&StatusResponse {
                 status: "Ready".to_string(),
                 ilp_address: store.get_ilp_address(),
+                secondary_ilp_addresses: store.get_secondary_ilp_addresses(),
                 version: node_version.clone(),
             })
         });
@@ -95,6 +133,32 @@ where
             Ok::<_, Rejection>(warp::reply::json(&rates))
         });
 
+    // PUT /rates/overrides
+    // Body: Map of asset pair (such as "USDC/USD") -> fixed rate
+    let put_rate_overrides = warp::put()
+        .and(warp::path("rates"))
+        .and(warp::path("overrides"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(deserialize_json())
+        .and(with_store.clone())
+        .and_then(|overrides: ExchangeRates, store: S| async move {
+            store.set_rate_overrides(overrides.0)?;
+            let overrides = store.get_rate_overrides()?;
+            Ok::<_, Rejection>(warp::reply::json(&overrides))
+        });
+
+    // GET /rates/overrides
+    let get_rate_overrides = warp::get()
+        .and(warp::path("rates"))
+        .and(warp::path("overrides"))
+        .and(warp::path::end())
+        .and(with_store.clone())
+        .and_then(|store: S| async move {
+            let overrides = store.get_rate_overrides()?;
+            Ok::<_, Rejection>(warp::reply::json(&overrides))
+        });
+
     // GET /routes
     // Response: Map of ILP Address prefix -> Username
     let get_routes = warp::get()
@@ -119,6 +183,91 @@ where
             }
         });
 
+    // GET /routes/table
+    // Response: Array of the routes with the username they route to, their source and expiry
+    let get_routing_table = warp::get()
+        .and(warp::path("routes"))
+        .and(warp::path("table"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(with_store.clone())
+        .and_then(move |store: S| {
+            let route_expiries = route_expiries.clone();
+            async move {
+                let routes = store.routing_table();
+                let static_routes = store.get_static_routes().await?;
+                let default_route = store.get_default_route().await?;
+                let expiries = route_expiries
+                    .as_ref()
+                    .map(RouteExpiries::get)
+                    .unwrap_or_default();
+
+                let mut account_ids: Vec<Uuid> = routes.values().cloned().collect();
+                account_ids.sort();
+                account_ids.dedup();
+                let accounts: HashMap<Uuid, A> = store
+                    .get_accounts(account_ids)
+                    .await?
+                    .into_iter()
+                    .map(|account| (account.id(), account))
+                    .collect();
+
+                // The expiries are monotonic instants, so they are converted
+                // to wall-clock timestamps relative to the current time
+                let (now, now_timestamp) = (Instant::now(), SystemTime::now());
+                let mut table: Vec<RouteDetails> = routes
+                    .iter()
+                    .filter_map(|(prefix, account_id)| {
+                        let account = accounts.get(account_id)?;
+                        let source = if static_routes.get(prefix) == Some(account_id) {
+                            RouteSource::Static
+                        } else if prefix.is_empty() && default_route == Some(*account_id) {
+                            RouteSource::Default
+                        } else if account.ilp_address() as &str == prefix {
+                            RouteSource::Local
+                        } else {
+                            RouteSource::Ccp
+                        };
+                        let expires_at = expiries
+                            .get(prefix)
+                            .filter(|_| source == RouteSource::Ccp)
+                            .map(|expires_at| {
+                                let expires_at =
+                                    now_timestamp + expires_at.saturating_duration_since(now);
+                                expires_at
+                                    .duration_since(UNIX_EPOCH)
+                                    .unwrap_or_default()
+                                    .as_millis() as u64
+                            });
+                        Some(RouteDetails {
+                            prefix: prefix.clone(),
+                            username: account.username().to_string(),
+                            source,
+                            expires_at,
+                        })
+                    })
+                    .collect();
+                table.sort_by(|a, b| a.prefix.cmp(&b.prefix));
+
+                Ok::<Json, Rejection>(warp::reply::json(&table))
+            }
+        });
+
+    // GET /routes/index
+    // Response: How often the routes were looked up in the store's prefix index
+    let get_route_index_stats = warp::get()
+        .and(warp::path("routes"))
+        .and(warp::path("index"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(with_store.clone())
+        .and_then(|store: S| async move {
+            let stats = store.route_index_stats().ok_or_else(|| {
+                Rejection::from(ApiError::not_found().detail("the store does not index its routes"))
+            })?;
+            Ok::<Json, Rejection>(warp::reply::json(&stats))
+        });
+
     // PUT /routes/static
     // Body: Map of ILP Address prefix -> Username
     let put_static_routes = warp::put()
@@ -176,6 +325,81 @@ where
             }
         });
 
+    // DELETE /routes/static/:prefix
+    let delete_static_route = warp::delete()
+        .and(warp::path("routes"))
+        .and(warp::path("static"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(with_store.clone())
+        .and_then(|prefix: String, store: S| async move {
+            store.delete_static_route(&prefix).await?;
+            Ok::<_, Rejection>(StatusCode::NO_CONTENT)
+        });
+
+    // PUT /routes/default
+    // Body: Username
+    let put_default_route = warp::put()
+        .and(warp::path("routes"))
+        .and(warp::path("default"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(warp::body::bytes())
+        .and(with_store.clone())
+        .and_then(|body: Bytes, store: S| async move {
+            let username_str =
+                str::from_utf8(&body).map_err(|_| Rejection::from(ApiError::bad_request()))?;
+            let username = Username::from_str(username_str)
+                .map_err(|_| Rejection::from(ApiError::bad_request()))?;
+            let account_id = store.get_account_id_from_username(&username).await?;
+            store.set_default_route(account_id).await?;
+            Ok::<String, Rejection>(username.to_string())
+        });
+
+    // DELETE /routes/default
+    let delete_default_route = warp::delete()
+        .and(warp::path("routes"))
+        .and(warp::path("default"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .and(with_store.clone())
+        .and_then(|store: S| async move {
+            store.delete_default_route().await?;
+            Ok::<_, Rejection>(StatusCode::NO_CONTENT)
+        });
+
+    // GET /settlement/pending
+    // Response: The outgoing settlements which were not yet accepted by the settlement engines
+    let get_pending_settlements = warp::get()
+        .and(warp::path("settlement"))
+        .and(warp::path("pending"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .map(move || {
+            let pending = settlement_scheduler
+                .as_ref()
+                .map(SettlementScheduler::pending_settlements)
+                .unwrap_or_default();
+            warp::reply::json(&pending)
+        });
+
+    // GET /settlement/discrepancies
+    // Response: The differences between the recorded settlements and the settlement
+    // engines' totals, found by the last reconciliation
+    let get_settlement_discrepancies = warp::get()
+        .and(warp::path("settlement"))
+        .and(warp::path("discrepancies"))
+        .and(warp::path::end())
+        .and(admin_only.clone())
+        .map(move || {
+            let discrepancies = reconciler
+                .as_ref()
+                .map(Reconciler::discrepancies)
+                .unwrap_or_default();
+            warp::reply::json(&discrepancies)
+        });
+
     // PUT /settlement/engines
     let put_settlement_engines = warp::put()
         .and(warp::path("settlement"))
@@ -185,6 +409,7 @@ where
         .and(warp::body::json())
         .and(with_store)
         .and_then(move |asset_to_url_map: HashMap<String, Url>, store: S| async move {
+            check_clearing_only(clearing_only, true)?;
             let asset_to_url_map_clone = asset_to_url_map.clone();
             store
                 .set_settlement_engines(asset_to_url_map.clone()).await?;
@@ -222,9 +447,18 @@ where
     get_root
         .or(put_rates)
         .or(get_rates)
+        .or(put_rate_overrides)
+        .or(get_rate_overrides)
         .or(get_routes)
+        .or(get_routing_table)
+        .or(get_route_index_stats)
         .or(put_static_routes)
         .or(put_static_route)
+        .or(delete_static_route)
+        .or(put_default_route)
+        .or(delete_default_route)
+        .or(get_pending_settlements)
+        .or(get_settlement_discrepancies)
         .or(put_settlement_engines)
 }
 
@@ -255,6 +489,31 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn only_admin_can_put_rate_overrides() {
+        let api = test_node_settings_api();
+        let overrides = json!({"USDC/USD": 1.0});
+        let resp = api_call(
+            &api,
+            "PUT",
+            "/rates/overrides",
+            "admin",
+            Some(overrides.clone()),
+        )
+        .await;
+        assert_eq!(resp.status().as_u16(), 200);
+
+        let resp = api_call(&api, "PUT", "/rates/overrides", "wrong", Some(overrides)).await;
+        assert_eq!(resp.status().as_u16(), 401);
+
+        let resp = api_call(&api, "GET", "/rates/overrides", "", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(
+            serde_json::from_slice::<Value>(resp.body()).unwrap(),
+            json!({"USDC/USD": 1.0})
+        );
+    }
+
     #[tokio::test]
     async fn gets_routes() {
         let api = test_node_settings_api();
@@ -262,6 +521,55 @@ mod tests {
         assert_eq!(resp.status().as_u16(), 200);
     }
 
+    #[tokio::test]
+    async fn only_admin_can_get_routing_table() {
+        let api = test_node_settings_api();
+        let resp = api_call(&api, "GET", "/routes/table", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(
+            serde_json::from_slice::<Value>(resp.body()).unwrap(),
+            json!([])
+        );
+
+        let resp = api_call(&api, "GET", "/routes/table", "wrong", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
+
+    #[tokio::test]
+    async fn only_admin_can_delete_static_route() {
+        let api = test_node_settings_api();
+        let resp = api_call(&api, "DELETE", "/routes/static/g.node1", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 204);
+
+        let resp = api_call(&api, "DELETE", "/routes/static/g.node1", "wrong", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
+
+    #[tokio::test]
+    async fn only_admin_can_pin_default_route() {
+        let api = test_node_settings_api();
+        let api_put = |auth: &str| {
+            warp::test::request()
+                .method("PUT")
+                .path("/routes/default")
+                .body("alice")
+                .header("Authorization", format!("Bearer {}", auth))
+                .reply(&api)
+        };
+        let resp = api_put("admin").await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(resp.body(), &b"alice"[..]);
+
+        let resp = api_put("wrong").await;
+        assert_eq!(resp.status().as_u16(), 401);
+
+        let resp = api_call(&api, "DELETE", "/routes/default", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 204);
+
+        let resp = api_call(&api, "DELETE", "/routes/default", "wrong", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
+
     #[tokio::test]
     async fn only_admin_can_put_rates() {
         let api = test_node_settings_api();
@@ -324,4 +632,32 @@ mod tests {
         let resp = api_call(&api, "PUT", "/settlement/engines", "wrong", Some(engines)).await;
         assert_eq!(resp.status().as_u16(), 401);
     }
+
+    #[tokio::test]
+    async fn only_admin_can_get_pending_settlements() {
+        let api = test_node_settings_api();
+        let resp = api_call(&api, "GET", "/settlement/pending", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(
+            serde_json::from_slice::<Value>(resp.body()).unwrap(),
+            json!([])
+        );
+
+        let resp = api_call(&api, "GET", "/settlement/pending", "wrong", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
+
+    #[tokio::test]
+    async fn only_admin_can_get_settlement_discrepancies() {
+        let api = test_node_settings_api();
+        let resp = api_call(&api, "GET", "/settlement/discrepancies", "admin", None).await;
+        assert_eq!(resp.status().as_u16(), 200);
+        assert_eq!(
+            serde_json::from_slice::<Value>(resp.body()).unwrap(),
+            json!([])
+        );
+
+        let resp = api_call(&api, "GET", "/settlement/discrepancies", "wrong", None).await;
+        assert_eq!(resp.status().as_u16(), 401);
+    }
 }
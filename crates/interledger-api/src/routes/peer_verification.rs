@@ -0,0 +1,540 @@
+use crate::AccountDetails;
+use bytes::BytesMut;
+use interledger_btp::{probe_btp_server, BtpProbeError};
+use interledger_ildcp::{IldcpRequest, IldcpResponse};
+use interledger_packet::{Address, Packet, Prepare};
+use interledger_service::{IlpResult, Username};
+use interledger_service_util::{EchoRequestBuilder, ECHO_CONDITION};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Serialize;
+use std::{
+    convert::TryFrom,
+    str,
+    time::{Duration, SystemTime},
+};
+use tracing::debug;
+use url::Url;
+
+/// How long each step of the verification may take
+const VERIFICATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outcome of one of the checks of a peer's verification
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Passed,
+    Failed,
+    /// The check does not apply to the peer's configuration, or a check it depends on failed
+    Skipped,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Check {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Result of the dry-run of a candidate peer's account. The account may be created if
+/// the report is `ok`, i.e. if none of its checks failed
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PeerVerificationReport {
+    pub username: Username,
+    pub ok: bool,
+    pub checks: Vec<Check>,
+}
+
+/// The links the packets may be sent to the peer over
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Link {
+    Http,
+    Btp,
+}
+
+impl Link {
+    fn name(self) -> &'static str {
+        match self {
+            Link::Http => "ILP over HTTP",
+            Link::Btp => "BTP",
+        }
+    }
+
+    fn checks(self) -> [&'static str; 3] {
+        match self {
+            Link::Http => ["http_connect", "http_auth", "http_ildcp"],
+            Link::Btp => ["btp_connect", "btp_auth", "btp_ildcp"],
+        }
+    }
+}
+
+/// The step at which sending a packet over a link failed
+enum LinkError {
+    Connect(String),
+    Auth(String),
+    Exchange(String),
+}
+
+impl From<BtpProbeError> for LinkError {
+    fn from(err: BtpProbeError) -> Self {
+        match err {
+            BtpProbeError::CannotConnect(..) => LinkError::Connect(err.to_string()),
+            BtpProbeError::Unauthorized => LinkError::Auth(err.to_string()),
+            BtpProbeError::Timeout(_) | BtpProbeError::ConnectionFailed(_) => {
+                LinkError::Exchange(err.to_string())
+            }
+        }
+    }
+}
+
+async fn send_over_http(
+    url: &str,
+    token: Option<&SecretString>,
+    prepare: Prepare,
+) -> Result<IlpResult, LinkError> {
+    let url = Url::parse(url).map_err(|err| LinkError::Connect(err.to_string()))?;
+    let token = token.map(|token| token.expose_secret().as_str());
+    let response = reqwest::Client::new()
+        .post(url.as_ref())
+        .header("authorization", format!("Bearer {}", token.unwrap_or("")))
+        .header("content-type", "application/octet-stream")
+        .timeout(VERIFICATION_TIMEOUT)
+        .body(prepare.as_ref().to_vec())
+        .send()
+        .await
+        .map_err(|err| LinkError::Connect(err.to_string()))?;
+    let status = response.status();
+    if status == http::StatusCode::UNAUTHORIZED || status == http::StatusCode::FORBIDDEN {
+        return Err(LinkError::Auth(format!(
+            "The peer refused the outgoing token with status {}",
+            status
+        )));
+    }
+    if !status.is_success() {
+        return Err(LinkError::Exchange(format!(
+            "The peer responded with status {}",
+            status
+        )));
+    }
+    let body = response
+        .bytes()
+        .await
+        .map_err(|err| LinkError::Exchange(err.to_string()))?;
+    match Packet::try_from(BytesMut::from(body.as_ref())) {
+        Ok(Packet::Fulfill(fulfill)) => Ok(Ok(fulfill)),
+        Ok(Packet::Reject(reject)) => Ok(Err(reject)),
+        _ => Err(LinkError::Exchange(
+            "The peer did not respond with a Fulfill or Reject packet".to_string(),
+        )),
+    }
+}
+
+async fn send(
+    link: Link,
+    details: &AccountDetails,
+    prepare: Prepare,
+) -> Option<Result<IlpResult, LinkError>> {
+    match link {
+        Link::Http => {
+            let url = details.ilp_over_http_url.as_ref()?;
+            Some(send_over_http(url, details.ilp_over_http_outgoing_token.as_ref(), prepare).await)
+        }
+        Link::Btp => {
+            let url = details.ilp_over_btp_url.as_ref()?;
+            let result = match Url::parse(url) {
+                Ok(url) => {
+                    let token = details
+                        .ilp_over_btp_outgoing_token
+                        .as_ref()
+                        .map(|token| token.expose_secret().as_bytes())
+                        .unwrap_or_default();
+                    probe_btp_server(&url, token, prepare, VERIFICATION_TIMEOUT)
+                        .await
+                        .map_err(LinkError::from)
+                }
+                Err(err) => Err(LinkError::Connect(err.to_string())),
+            };
+            Some(result)
+        }
+    }
+}
+
+/// Connects to the peer over the link and exchanges ILDCP packets with it. Returns whether
+/// packets can be sent over the link
+async fn check_link(link: Link, details: &AccountDetails, checks: &mut Vec<Check>) -> bool {
+    let [connect, auth, ildcp] = link.checks();
+    let result = match send(link, details, IldcpRequest::new().to_prepare()).await {
+        Some(result) => result,
+        None => {
+            let detail = format!("No {} URL is configured", link.name());
+            checks.push(Check::new(connect, CheckStatus::Skipped, detail.clone()));
+            checks.push(Check::new(auth, CheckStatus::Skipped, detail.clone()));
+            checks.push(Check::new(ildcp, CheckStatus::Skipped, detail));
+            return false;
+        }
+    };
+    let skipped = |check| Check::new(check, CheckStatus::Skipped, "A previous check failed");
+    let fulfill = match result {
+        Err(LinkError::Connect(err)) => {
+            checks.push(Check::new(connect, CheckStatus::Failed, err));
+            checks.push(skipped(auth));
+            checks.push(skipped(ildcp));
+            return false;
+        }
+        Err(LinkError::Auth(err)) => {
+            checks.push(Check::new(connect, CheckStatus::Passed, "Connected"));
+            checks.push(Check::new(auth, CheckStatus::Failed, err));
+            checks.push(skipped(ildcp));
+            return false;
+        }
+        Err(LinkError::Exchange(err)) => {
+            checks.push(Check::new(connect, CheckStatus::Passed, "Connected"));
+            checks.push(Check::new(auth, CheckStatus::Passed, "Authenticated"));
+            checks.push(Check::new(ildcp, CheckStatus::Failed, err));
+            return true;
+        }
+        Ok(result) => {
+            checks.push(Check::new(connect, CheckStatus::Passed, "Connected"));
+            checks.push(Check::new(auth, CheckStatus::Passed, "Authenticated"));
+            result
+        }
+    };
+
+    let check = match fulfill {
+        Ok(fulfill) => match IldcpResponse::try_from(fulfill.into_data().freeze()) {
+            Ok(info) => {
+                let asset_code = str::from_utf8(info.asset_code()).unwrap_or_default();
+                if asset_code != details.asset_code || info.asset_scale() != details.asset_scale {
+                    Check::new(
+                        ildcp,
+                        CheckStatus::Failed,
+                        format!(
+                            "The peer's asset is {} with scale {}, but the account's is {} with scale {}",
+                            asset_code,
+                            info.asset_scale(),
+                            details.asset_code,
+                            details.asset_scale
+                        ),
+                    )
+                } else {
+                    Check::new(
+                        ildcp,
+                        CheckStatus::Passed,
+                        format!("The peer assigned the address {}", info.ilp_address()),
+                    )
+                }
+            }
+            Err(err) => Check::new(
+                ildcp,
+                CheckStatus::Failed,
+                format!("Invalid ILDCP response: {:?}", err),
+            ),
+        },
+        Err(reject) => Check::new(
+            ildcp,
+            CheckStatus::Failed,
+            format!(
+                "The peer rejected the ILDCP request with {}: {}",
+                reject.code(),
+                String::from_utf8_lossy(reject.message())
+            ),
+        ),
+    };
+    checks.push(check);
+    true
+}
+
+/// Verifies that the peer of the candidate account can exchange packets with the node in
+/// both directions, without creating the account:
+/// - the username is not taken, given whether an account with it `exists`
+/// - the peer can authenticate to the node, with an incoming token or over the BTP
+///   connection the node opens
+/// - the node can connect and authenticate to each of the peer's endpoints, and exchange
+///   ILDCP packets with it which agree on the account's asset
+/// - the peer answers an echo request sent to its address, if the account has one
+pub async fn verify_peer(
+    details: &AccountDetails,
+    exists: bool,
+    node_address: &Address,
+) -> PeerVerificationReport {
+    debug!("Verifying the candidate peer {}", details.username);
+    let mut checks = Vec::new();
+    checks.push(if exists {
+        Check::new(
+            "username",
+            CheckStatus::Failed,
+            format!("An account named {} already exists", details.username),
+        )
+    } else {
+        Check::new("username", CheckStatus::Passed, "The username is available")
+    });
+
+    let mut incoming = Vec::new();
+    if details.ilp_over_http_incoming_token.is_some() {
+        incoming.push("an ILP over HTTP token");
+    }
+    if details.ilp_over_btp_incoming_token.is_some() {
+        incoming.push("a BTP token");
+    }
+    if details.ilp_over_btp_url.is_some() {
+        incoming.push("the BTP connection the node opens");
+    }
+    checks.push(if incoming.is_empty() {
+        Check::new(
+            "incoming_auth",
+            CheckStatus::Failed,
+            "No incoming token is configured, so the peer cannot send packets to the node",
+        )
+    } else {
+        Check::new(
+            "incoming_auth",
+            CheckStatus::Passed,
+            format!("The peer can send packets with {}", incoming.join(" or ")),
+        )
+    });
+
+    let mut links = Vec::new();
+    for link in [Link::Http, Link::Btp].iter().copied() {
+        if check_link(link, details, &mut checks).await {
+            links.push(link);
+        }
+    }
+
+    let ping = match (&details.ilp_address, links.first()) {
+        (None, _) => Check::new(
+            "ping",
+            CheckStatus::Skipped,
+            "The account has no ILP address",
+        ),
+        (Some(_), None) => Check::new(
+            "ping",
+            CheckStatus::Skipped,
+            "No packets can be sent to the peer",
+        ),
+        (Some(destination), Some(link)) => {
+            let prepare = EchoRequestBuilder {
+                amount: 0,
+                expires_at: SystemTime::now() + VERIFICATION_TIMEOUT,
+                execution_condition: &ECHO_CONDITION,
+                destination,
+                source_address: node_address,
+            }
+            .build();
+            match send(*link, details, prepare).await {
+                Some(Ok(Ok(_))) => Check::new(
+                    "ping",
+                    CheckStatus::Passed,
+                    format!("{} answered the echo request", destination),
+                ),
+                Some(Ok(Err(reject))) => Check::new(
+                    "ping",
+                    CheckStatus::Failed,
+                    format!(
+                        "The echo request was rejected with {}: {}",
+                        reject.code(),
+                        String::from_utf8_lossy(reject.message())
+                    ),
+                ),
+                Some(Err(LinkError::Connect(err)))
+                | Some(Err(LinkError::Auth(err)))
+                | Some(Err(LinkError::Exchange(err))) => {
+                    Check::new("ping", CheckStatus::Failed, err)
+                }
+                None => unreachable!("only the configured links are used"),
+            }
+        }
+    };
+    checks.push(ping);
+
+    let ok = checks
+        .iter()
+        .all(|check| check.status != CheckStatus::Failed);
+    PeerVerificationReport {
+        username: details.username.clone(),
+        ok,
+        checks,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use interledger_ildcp::IldcpResponseBuilder;
+    use interledger_packet::{ErrorCode, Fulfill, FulfillBuilder, RejectBuilder};
+    use interledger_service_util::ECHO_FULFILLMENT;
+    use serde_json::json;
+    use std::str::FromStr;
+    use warp::Filter;
+
+    /// Serves ILP over HTTP like a peer using the `peer-token` token, which is the parent
+    /// of `example.node` and answers echo requests sent to `example.peer`
+    fn serve_peer() -> String {
+        let peer = warp::post()
+            .and(warp::header::<String>("authorization"))
+            .and(warp::body::bytes())
+            .map(|authorization: String, body: Bytes| {
+                if authorization != "Bearer peer-token" {
+                    return http::Response::builder()
+                        .status(401)
+                        .body(Vec::new())
+                        .unwrap();
+                }
+                let prepare = Prepare::try_from(BytesMut::from(body.as_ref())).unwrap();
+                let response = if prepare.destination().to_string() == "peer.config" {
+                    Fulfill::from(
+                        IldcpResponseBuilder {
+                            ilp_address: &Address::from_str("example.peer.node").unwrap(),
+                            asset_scale: 9,
+                            asset_code: "XYZ",
+                        }
+                        .build(),
+                    )
+                    .as_ref()
+                    .to_vec()
+                } else if prepare.destination().to_string() == "example.peer" {
+                    FulfillBuilder {
+                        fulfillment: &ECHO_FULFILLMENT,
+                        data: &[],
+                    }
+                    .build()
+                    .as_ref()
+                    .to_vec()
+                } else {
+                    RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: b"unknown destination",
+                        triggered_by: None,
+                        data: &[],
+                    }
+                    .build()
+                    .as_ref()
+                    .to_vec()
+                };
+                http::Response::builder()
+                    .status(200)
+                    .body(response)
+                    .unwrap()
+            });
+        let (addr, server) = warp::serve(peer).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}/ilp", addr)
+    }
+
+    fn details(value: serde_json::Value) -> AccountDetails {
+        serde_json::from_slice(&serde_json::to_vec(&value).unwrap()).unwrap()
+    }
+
+    fn statuses(report: &PeerVerificationReport) -> Vec<(&'static str, CheckStatus)> {
+        report
+            .checks
+            .iter()
+            .map(|check| (check.name, check.status))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn verifies_reachable_peer() {
+        let url = serve_peer();
+        let details = details(json!({
+            "username": "peer",
+            "ilp_address": "example.peer",
+            "asset_code": "XYZ",
+            "asset_scale": 9,
+            "ilp_over_http_url": url,
+            "ilp_over_http_outgoing_token": "peer-token",
+            "ilp_over_http_incoming_token": "node-token",
+        }));
+        let node_address = Address::from_str("example.node").unwrap();
+        let report = verify_peer(&details, false, &node_address).await;
+        assert!(report.ok, "{:?}", report);
+        assert_eq!(
+            statuses(&report),
+            vec![
+                ("username", CheckStatus::Passed),
+                ("incoming_auth", CheckStatus::Passed),
+                ("http_connect", CheckStatus::Passed),
+                ("http_auth", CheckStatus::Passed),
+                ("http_ildcp", CheckStatus::Passed),
+                ("btp_connect", CheckStatus::Skipped),
+                ("btp_auth", CheckStatus::Skipped),
+                ("btp_ildcp", CheckStatus::Skipped),
+                ("ping", CheckStatus::Passed),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_broken_peer() {
+        let url = serve_peer();
+        let node_address = Address::from_str("example.node").unwrap();
+
+        let report = verify_peer(
+            &details(json!({
+                "username": "peer",
+                "ilp_address": "example.peer",
+                "asset_code": "XYZ",
+                "asset_scale": 9,
+                "ilp_over_http_url": url,
+                "ilp_over_http_outgoing_token": "wrong-token",
+            })),
+            true,
+            &node_address,
+        )
+        .await;
+        assert!(!report.ok);
+        let statuses = statuses(&report);
+        assert_eq!(statuses[0], ("username", CheckStatus::Failed));
+        assert_eq!(statuses[1], ("incoming_auth", CheckStatus::Failed));
+        assert_eq!(statuses[3], ("http_auth", CheckStatus::Failed));
+        assert_eq!(statuses[8], ("ping", CheckStatus::Skipped));
+
+        // The peer's asset does not match the account's
+        let report = verify_peer(
+            &details(json!({
+                "username": "peer",
+                "asset_code": "ABC",
+                "asset_scale": 9,
+                "ilp_over_http_url": url,
+                "ilp_over_http_outgoing_token": "peer-token",
+                "ilp_over_http_incoming_token": "node-token",
+            })),
+            false,
+            &node_address,
+        )
+        .await;
+        assert!(!report.ok);
+        assert_eq!(report.checks[4].status, CheckStatus::Failed);
+        assert!(report.checks[4].detail.contains("XYZ"));
+        assert_eq!(report.checks[8].status, CheckStatus::Skipped);
+
+        // Nothing listens on the BTP URL
+        let report = verify_peer(
+            &details(json!({
+                "username": "peer",
+                "asset_code": "XYZ",
+                "asset_scale": 9,
+                "ilp_over_btp_url": "btp+ws://127.0.0.1:1/accounts/node/ilp/btp",
+            })),
+            false,
+            &node_address,
+        )
+        .await;
+        assert!(!report.ok);
+        assert_eq!(
+            (report.checks[5].name, report.checks[5].status),
+            ("btp_connect", CheckStatus::Failed)
+        );
+        // Packets from the peer are received over the BTP connection
+        assert_eq!(report.checks[1].status, CheckStatus::Passed);
+    }
+}
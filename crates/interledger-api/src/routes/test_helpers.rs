@@ -1,6 +1,6 @@
 use crate::{
     routes::{accounts_api, node_settings_api},
-    AccountDetails, AccountSettings, NodeStore,
+    AccountDetails, AccountSettings, NodeStore, Webhook, WebhookStore,
 };
 use async_trait::async_trait;
 use bytes::Bytes;
@@ -16,9 +16,18 @@ use interledger_router::RouterStore;
 use interledger_service::{
     incoming_service_fn, outgoing_service_fn, Account, AccountStore, AddressStore, Username,
 };
-use interledger_service_util::BalanceStore;
-use interledger_settlement::core::types::{SettlementAccount, SettlementEngineDetails};
-use interledger_stream::{PaymentNotification, StreamNotificationsStore};
+use interledger_service_util::{
+    AccountUsage, BalanceHistoryStore, BalanceSample, BalanceStore, HealthPolicy, PeerMonitor,
+    PeerScoreboard, ScoreboardPolicy, UsagePeriod, UsageStore,
+};
+use interledger_settlement::core::types::{
+    SettlementAccount, SettlementDirection, SettlementEngineDetails, SettlementHistoryStore,
+    SettlementRecord,
+};
+use interledger_spsp::{Contact, ContactStore};
+use interledger_stream::{
+    ConnectionReceipts, PaymentNotification, StreamNotificationsStore, StreamReceiveStore,
+};
 use once_cell::sync::Lazy;
 use secrecy::SecretString;
 use serde::{Deserialize, Serialize};
@@ -56,10 +65,23 @@ where
 
 pub fn test_node_settings_api(
 ) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    node_settings_api("admin".to_owned(), None, TestStore).recover(default_rejection_handler)
+    node_settings_api("admin".to_owned(), None, None, None, None, TestStore, false)
+        .recover(default_rejection_handler)
 }
 
 pub fn test_accounts_api(
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    build_test_accounts_api(false)
+}
+
+/// Builds the accounts API of a node which only clears, and never settles
+pub fn test_clearing_only_accounts_api(
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    build_test_accounts_api(true)
+}
+
+fn build_test_accounts_api(
+    clearing_only: bool,
 ) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
     let incoming = incoming_service_fn(|_request| {
         Err(RejectBuilder {
@@ -89,7 +111,11 @@ pub fn test_accounts_api(
         incoming,
         outgoing,
         btp,
+        None,
         store,
+        clearing_only,
+        Some(PeerMonitor::new(HealthPolicy::default())),
+        Some(PeerScoreboard::new(ScoreboardPolicy::default())),
     )
     .recover(default_rejection_handler)
 }
@@ -215,6 +241,19 @@ impl ExchangeRateStore for TestStore {
         ret.insert("XYZ".to_owned(), 2.0);
         Ok(ret)
     }
+
+    fn set_rate_overrides(
+        &self,
+        _overrides: HashMap<String, f64>,
+    ) -> Result<(), ExchangeRateStoreError> {
+        Ok(())
+    }
+
+    fn get_rate_overrides(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError> {
+        let mut ret = HashMap::new();
+        ret.insert("USDC/USD".to_owned(), 1.0);
+        Ok(ret)
+    }
 }
 
 impl RouterStore for TestStore {
@@ -234,6 +273,21 @@ impl NodeStore for TestStore {
         Ok(TestAccount)
     }
 
+    async fn insert_accounts(
+        &self,
+        accounts: Vec<AccountDetails>,
+        _atomic: bool,
+    ) -> Vec<Result<Self::Account, NodeStoreError>> {
+        accounts.into_iter().map(|_| Ok(TestAccount)).collect()
+    }
+
+    async fn export_accounts(&self) -> Result<Vec<AccountDetails>, NodeStoreError> {
+        Ok(vec![serde_json::from_slice(
+            &serde_json::to_vec(&DETAILS.clone().unwrap()).unwrap(),
+        )
+        .unwrap()])
+    }
+
     async fn delete_account(&self, _id: Uuid) -> Result<Self::Account, NodeStoreError> {
         Ok(TestAccount)
     }
@@ -273,8 +327,24 @@ impl NodeStore for TestStore {
         Ok(())
     }
 
+    async fn get_static_routes(&self) -> Result<HashMap<String, Uuid>, NodeStoreError> {
+        Ok(HashMap::new())
+    }
+
+    async fn delete_static_route(&self, _prefix: &str) -> Result<(), NodeStoreError> {
+        Ok(())
+    }
+
     async fn set_default_route(&self, _account_id: Uuid) -> Result<(), NodeStoreError> {
-        unimplemented!()
+        Ok(())
+    }
+
+    async fn get_default_route(&self) -> Result<Option<Uuid>, NodeStoreError> {
+        Ok(None)
+    }
+
+    async fn delete_default_route(&self) -> Result<(), NodeStoreError> {
+        Ok(())
     }
 
     async fn set_settlement_engines(
@@ -367,6 +437,160 @@ impl BalanceStore for TestStore {
     }
 }
 
+#[async_trait]
+impl UsageStore for TestStore {
+    async fn record_usage(
+        &self,
+        _from_account_id: Uuid,
+        _incoming_amount: u64,
+        _to_account_id: Uuid,
+        _outgoing_amount: u64,
+    ) -> Result<(), UsageStoreError> {
+        unimplemented!()
+    }
+
+    async fn get_usage(
+        &self,
+        _: Uuid,
+        period: UsagePeriod,
+    ) -> Result<AccountUsage, UsageStoreError> {
+        Ok(AccountUsage {
+            period: period.current_period_id(),
+            ..Default::default()
+        })
+    }
+
+    async fn reset_usage(&self, _: Uuid, _: UsagePeriod) -> Result<(), UsageStoreError> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ContactStore for TestStore {
+    async fn save_contact(&self, _: Uuid, _: Contact) -> Result<(), ContactStoreError> {
+        Ok(())
+    }
+
+    async fn get_contacts(&self, _: Uuid) -> Result<Vec<Contact>, ContactStoreError> {
+        Ok(vec![Contact {
+            name: "bob".to_string(),
+            payment_pointer: "$example.com/bob".to_string(),
+        }])
+    }
+
+    async fn get_contact(&self, _: Uuid, name: &str) -> Result<Contact, ContactStoreError> {
+        Err(ContactStoreError::ContactNotFound(name.to_string()))
+    }
+
+    async fn delete_contact(&self, _: Uuid, _: &str) -> Result<(), ContactStoreError> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StreamReceiveStore for TestStore {
+    async fn set_connection_receive_max(
+        &self,
+        _: Uuid,
+        _: &str,
+        _: Option<u64>,
+    ) -> Result<(), StreamReceiveStoreError> {
+        Ok(())
+    }
+
+    async fn get_connection_receipts(
+        &self,
+        _: Uuid,
+        _: &str,
+    ) -> Result<ConnectionReceipts, StreamReceiveStoreError> {
+        Ok(ConnectionReceipts {
+            total_received: 100,
+            receive_max: Some(1000),
+        })
+    }
+
+    async fn add_connection_receipt(
+        &self,
+        _: Uuid,
+        _: &str,
+        _: u64,
+    ) -> Result<(ConnectionReceipts, bool), StreamReceiveStoreError> {
+        unimplemented!()
+    }
+
+    async fn remove_connection_receipt(
+        &self,
+        _: Uuid,
+        _: &str,
+        _: u64,
+    ) -> Result<(), StreamReceiveStoreError> {
+        unimplemented!()
+    }
+}
+
+#[async_trait]
+impl WebhookStore for TestStore {
+    async fn set_webhook(&self, _: Uuid, _: Webhook) -> Result<(), WebhookStoreError> {
+        Ok(())
+    }
+
+    async fn get_webhook(&self, id: Uuid) -> Result<Webhook, WebhookStoreError> {
+        Err(WebhookStoreError::WebhookNotFound(id.to_string()))
+    }
+
+    async fn delete_webhook(&self, _: Uuid) -> Result<(), WebhookStoreError> {
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SettlementHistoryStore for TestStore {
+    async fn record_settlement(
+        &self,
+        _account_id: Uuid,
+        _record: SettlementRecord,
+    ) -> Result<(), SettlementHistoryStoreError> {
+        Ok(())
+    }
+
+    async fn get_settlement_history(
+        &self,
+        _: Uuid,
+        _from: u64,
+        _to: u64,
+    ) -> Result<Vec<SettlementRecord>, SettlementHistoryStoreError> {
+        Ok(vec![SettlementRecord {
+            timestamp: 1500,
+            direction: SettlementDirection::Incoming,
+            amount: 100,
+            id: None,
+        }])
+    }
+}
+
+#[async_trait]
+impl BalanceHistoryStore for TestStore {
+    async fn record_balance_samples(
+        &self,
+        _timestamp: u64,
+        _capacity: usize,
+    ) -> Result<(), BalanceHistoryStoreError> {
+        Ok(())
+    }
+
+    async fn get_balance_history(
+        &self,
+        _: Uuid,
+        _from: u64,
+        _to: u64,
+    ) -> Result<Vec<BalanceSample>, BalanceHistoryStoreError> {
+        Ok(vec![BalanceSample {
+            timestamp: 1500,
+            balance: 100,
+        }])
+    }
+}
+
 #[async_trait]
 impl HttpStore for TestStore {
     type Account = TestAccount;
@@ -0,0 +1,399 @@
+//! Webhooks which notify the owners of the accounts about their accounts' events, so that
+//! integrators do not need to poll the API.
+//!
+//! The events are taken from the node's [`EventBus`](../../interledger_service/struct.EventBus.html)
+//! and POSTed as JSON to the URL configured for the account, signed with its secret.
+
+use async_trait::async_trait;
+use interledger_errors::WebhookStoreError;
+use interledger_packet::hex::HexString;
+use interledger_service::{AccountEvent, EventBus};
+use reqwest::Client;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::RecvError;
+use tracing::{debug, trace, warn};
+use url::Url;
+use uuid::Uuid;
+
+/// Header carrying the hex encoded HMAC-SHA256 of the body, keyed with the webhook's secret
+pub const SIGNATURE_HEADER: &str = "Ilp-Webhook-Signature";
+
+/// How long the dispatcher uses the webhook it loaded for an account before loading it again
+const WEBHOOK_CACHE_TTL: Duration = Duration::from_secs(5);
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The events a webhook can be notified of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventType {
+    /// The account's balance went over or under one of the webhook's `balance_thresholds`
+    BalanceThresholdCrossed,
+    /// A settlement was sent to or received from the account's peer
+    SettlementCompleted,
+    /// The node stopped handling the account's packets for a while
+    AccountSuspended,
+}
+
+/// Where and how an account is notified of its events
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Webhook {
+    /// URL the notifications are POSTed to
+    pub url: Url,
+    /// Key of the HMAC the notifications are signed with
+    pub secret: String,
+    /// The events the webhook is notified of, or all of them if empty
+    #[serde(default)]
+    pub events: Vec<WebhookEventType>,
+    /// The balances whose crossing is notified, in the account's asset scale
+    #[serde(default)]
+    pub balance_thresholds: Vec<i64>,
+}
+
+impl Webhook {
+    fn is_notified_of(&self, event_type: WebhookEventType) -> bool {
+        self.events.is_empty() || self.events.contains(&event_type)
+    }
+}
+
+/// Store trait which persists the webhook of each account
+#[async_trait]
+pub trait WebhookStore {
+    /// Sets the account's webhook, replacing the previous one
+    async fn set_webhook(
+        &self,
+        account_id: Uuid,
+        webhook: Webhook,
+    ) -> Result<(), WebhookStoreError>;
+
+    /// Loads the account's webhook
+    async fn get_webhook(&self, account_id: Uuid) -> Result<Webhook, WebhookStoreError>;
+
+    /// Deletes the account's webhook
+    async fn delete_webhook(&self, account_id: Uuid) -> Result<(), WebhookStoreError>;
+}
+
+/// Body of the notifications
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WebhookNotification {
+    /// Unique id of the notification, which is the same in all of its delivery attempts
+    pub id: Uuid,
+    #[serde(rename = "type")]
+    pub event_type: WebhookEventType,
+    pub account_id: Uuid,
+    /// When the event happened, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Details of the event, depending on its type
+    pub data: serde_json::Value,
+}
+
+/// How the failed deliveries of notifications are retried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WebhookRetryPolicy {
+    /// Delay before the first retry. It doubles after every further failed attempt
+    pub initial_backoff: Duration,
+    /// Number of failed attempts after which the notification is dropped
+    pub max_attempts: u32,
+}
+
+impl Default for WebhookRetryPolicy {
+    fn default() -> Self {
+        WebhookRetryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_attempts: 5,
+        }
+    }
+}
+
+/// Subscribes to the bus and notifies the accounts' webhooks of their events
+pub fn spawn_webhook_dispatcher<S>(
+    store: S,
+    events: &EventBus,
+    policy: WebhookRetryPolicy,
+) -> tokio::task::JoinHandle<()>
+where
+    S: WebhookStore + Send + Sync + 'static,
+{
+    let mut receiver = events.subscribe();
+    let client = Client::builder().timeout(WEBHOOK_TIMEOUT).build().unwrap();
+    tokio::spawn(async move {
+        let mut webhooks: HashMap<Uuid, (Option<Webhook>, Instant)> = HashMap::new();
+        let mut balances: HashMap<Uuid, i64> = HashMap::new();
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        "Webhook dispatcher fell behind, {} events were not notified",
+                        skipped
+                    );
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let account_id = match notified_account_id(&event) {
+                Some(account_id) => account_id,
+                None => continue,
+            };
+
+            let cached = webhooks
+                .get(&account_id)
+                .filter(|(_, loaded_at)| loaded_at.elapsed() < WEBHOOK_CACHE_TTL)
+                .map(|(webhook, _)| webhook.clone());
+            let webhook = match cached {
+                Some(webhook) => webhook,
+                None => {
+                    let webhook = match store.get_webhook(account_id).await {
+                        Ok(webhook) => Some(webhook),
+                        Err(WebhookStoreError::WebhookNotFound(_)) => None,
+                        Err(err) => {
+                            warn!("Error loading webhook of account {}: {}", account_id, err);
+                            None
+                        }
+                    };
+                    webhooks.insert(account_id, (webhook.clone(), Instant::now()));
+                    webhook
+                }
+            };
+            let webhook = match webhook {
+                Some(webhook) => webhook,
+                None => {
+                    balances.remove(&account_id);
+                    continue;
+                }
+            };
+
+            for (event_type, data) in notifications(&event, &webhook, &mut balances) {
+                if !webhook.is_notified_of(event_type) {
+                    continue;
+                }
+                let notification = WebhookNotification {
+                    id: Uuid::new_v4(),
+                    event_type,
+                    account_id,
+                    timestamp: now_millis(),
+                    data,
+                };
+                tokio::spawn(deliver(
+                    client.clone(),
+                    webhook.clone(),
+                    notification,
+                    policy,
+                ));
+            }
+        }
+        debug!("Stopped dispatching webhooks because the event bus was dropped");
+    })
+}
+
+/// Returns the account whose webhook may be notified of the event, if it is one of the
+/// events webhooks are notified of
+fn notified_account_id(event: &AccountEvent) -> Option<Uuid> {
+    match *event {
+        AccountEvent::BalanceChanged { account_id, .. }
+        | AccountEvent::SettlementSent { account_id, .. }
+        | AccountEvent::SettlementReceived { account_id, .. }
+        | AccountEvent::AccountSuspended { account_id, .. } => Some(account_id),
+        // These are only exported to the accounting sink
+        AccountEvent::PacketFulfilled { .. }
+        | AccountEvent::SettlementReserved { .. }
+        | AccountEvent::SettlementRefunded { .. } => None,
+    }
+}
+
+/// Turns the event into the notifications of the account's webhook. Balance changes are
+/// only notified if they crossed one of the webhook's thresholds since the previous change
+fn notifications(
+    event: &AccountEvent,
+    webhook: &Webhook,
+    balances: &mut HashMap<Uuid, i64>,
+) -> Vec<(WebhookEventType, serde_json::Value)> {
+    match *event {
+        AccountEvent::BalanceChanged {
+            account_id,
+            balance,
+        } => {
+            let previous = match balances.insert(account_id, balance) {
+                Some(previous) => previous,
+                None => return Vec::new(),
+            };
+            crossed_thresholds(previous, balance, &webhook.balance_thresholds)
+                .into_iter()
+                .map(|(threshold, direction)| {
+                    (
+                        WebhookEventType::BalanceThresholdCrossed,
+                        json!({
+                            "threshold": threshold,
+                            "direction": direction,
+                            "previous_balance": previous,
+                            "balance": balance,
+                        }),
+                    )
+                })
+                .collect()
+        }
+        AccountEvent::SettlementSent { amount, .. } => vec![(
+            WebhookEventType::SettlementCompleted,
+            json!({ "direction": "outgoing", "amount": amount }),
+        )],
+        AccountEvent::SettlementReceived { amount, .. } => vec![(
+            WebhookEventType::SettlementCompleted,
+            json!({ "direction": "incoming", "amount": amount }),
+        )],
+        AccountEvent::AccountSuspended {
+            ref reason,
+            duration_ms,
+            ..
+        } => vec![(
+            WebhookEventType::AccountSuspended,
+            json!({ "reason": reason, "duration_ms": duration_ms }),
+        )],
+        AccountEvent::PacketFulfilled { .. }
+        | AccountEvent::SettlementReserved { .. }
+        | AccountEvent::SettlementRefunded { .. } => Vec::new(),
+    }
+}
+
+/// Returns the thresholds which lie between the two balances, with whether the balance
+/// went "up" or "down" through them
+fn crossed_thresholds(previous: i64, balance: i64, thresholds: &[i64]) -> Vec<(i64, &'static str)> {
+    thresholds
+        .iter()
+        .filter_map(|&threshold| {
+            if previous < threshold && balance >= threshold {
+                Some((threshold, "up"))
+            } else if previous >= threshold && balance < threshold {
+                Some((threshold, "down"))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Signs the body with the webhook's secret
+fn sign(secret: &str, body: &[u8]) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    format!("{:?}", HexString(hmac::sign(&key, body).as_ref()))
+}
+
+/// POSTs the notification until the webhook responds with a success status or the
+/// attempts are exhausted
+async fn deliver(
+    client: Client,
+    webhook: Webhook,
+    notification: WebhookNotification,
+    policy: WebhookRetryPolicy,
+) {
+    let body = serde_json::to_vec(&notification).unwrap();
+    let signature = sign(&webhook.secret, &body);
+    let mut backoff = policy.initial_backoff;
+    for attempt in 1..=policy.max_attempts {
+        let result = client
+            .post(webhook.url.as_ref())
+            .header("Content-Type", "application/json")
+            .header(SIGNATURE_HEADER, signature.as_str())
+            .body(body.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        match result {
+            Ok(_) => {
+                trace!(
+                    "Delivered webhook notification {} to {}",
+                    notification.id,
+                    webhook.url
+                );
+                return;
+            }
+            Err(err) if attempt < policy.max_attempts => {
+                debug!(
+                    "Delivering webhook notification {} to {} failed, retrying in {:?}: {}",
+                    notification.id, webhook.url, backoff, err
+                );
+                tokio::time::delay_for(backoff).await;
+                backoff *= 2;
+            }
+            Err(err) => warn!(
+                "Dropping webhook notification {} for account {} after {} attempts: {}",
+                notification.id, notification.account_id, attempt, err
+            ),
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhook(balance_thresholds: Vec<i64>) -> Webhook {
+        Webhook {
+            url: "http://example.com/hook".parse().unwrap(),
+            secret: "secret".to_string(),
+            events: Vec::new(),
+            balance_thresholds,
+        }
+    }
+
+    #[test]
+    fn detects_crossed_thresholds() {
+        assert_eq!(
+            crossed_thresholds(0, 100, &[50, 100, 150]),
+            vec![(50, "up"), (100, "up")]
+        );
+        assert_eq!(
+            crossed_thresholds(100, -10, &[0, 100]),
+            vec![(0, "down"), (100, "down")]
+        );
+        assert!(crossed_thresholds(10, 20, &[0, 100]).is_empty());
+    }
+
+    #[test]
+    fn notifies_balance_changes_crossing_thresholds() {
+        let webhook = webhook(vec![0]);
+        let account_id = Uuid::new_v4();
+        let mut balances = HashMap::new();
+        let change = |balance| AccountEvent::BalanceChanged {
+            account_id,
+            balance,
+        };
+
+        // The first balance is only remembered
+        assert!(notifications(&change(10), &webhook, &mut balances).is_empty());
+        assert!(notifications(&change(5), &webhook, &mut balances).is_empty());
+        let crossed = notifications(&change(-5), &webhook, &mut balances);
+        assert_eq!(crossed.len(), 1);
+        assert_eq!(crossed[0].0, WebhookEventType::BalanceThresholdCrossed);
+        assert_eq!(crossed[0].1["direction"], "down");
+        assert_eq!(crossed[0].1["previous_balance"], 5);
+    }
+
+    #[test]
+    fn filters_event_types() {
+        let mut webhook = webhook(Vec::new());
+        assert!(webhook.is_notified_of(WebhookEventType::AccountSuspended));
+        webhook.events = vec![WebhookEventType::SettlementCompleted];
+        assert!(!webhook.is_notified_of(WebhookEventType::AccountSuspended));
+        assert!(webhook.is_notified_of(WebhookEventType::SettlementCompleted));
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
@@ -1,11 +1,13 @@
+use super::fragmentation::fragmentation_protocol_data;
 use super::packet::*;
-use super::service::BtpOutgoingService;
+use super::service::{ilp_packet_to_ws_messages, parse_ilp_packet, BtpOutgoingService};
 use super::BtpAccount;
 use futures::{future::join_all, SinkExt, StreamExt, TryFutureExt};
 use interledger_errors::ApiError;
-use interledger_packet::Address;
+use interledger_packet::{Address, Packet, Prepare};
 use interledger_service::*;
 use rand::random;
+use std::time::Duration;
 use thiserror::Error;
 use tokio_tungstenite::connect_async;
 use tracing::{debug, error, trace};
@@ -26,6 +28,21 @@ where
     A: BtpAccount + Send + Sync + 'static,
 {
     let service = BtpOutgoingService::new(ilp_address, next_outgoing);
+    connect_accounts(&service, accounts, error_on_unavailable).await?;
+    Ok(service)
+}
+
+/// Connects the BtpOutgoingService to the accounts specified, like `connect_client` does
+/// for the service it creates.
+pub async fn connect_accounts<A, S>(
+    service: &BtpOutgoingService<S, A>,
+    accounts: Vec<A>,
+    error_on_unavailable: bool,
+) -> Result<(), BtpClientError>
+where
+    S: OutgoingService<A> + Clone + 'static,
+    A: BtpAccount + Send + Sync + 'static,
+{
     let mut connect_btp = Vec::new();
     for account in accounts {
         // Can we make this take a reference to a service?
@@ -39,7 +56,7 @@ where
     if res.into_iter().any(|r| r.is_err()) {
         return Err(BtpClientError::CannotConnectMultiple);
     }
-    Ok(service)
+    Ok(())
 }
 
 #[derive(Error, Debug)]
@@ -52,6 +69,19 @@ pub enum BtpClientError {
     CannotConnectMultiple,
 }
 
+/// Why a BTP server failed the test of [`probe_btp_server`](./fn.probe_btp_server.html)
+#[derive(Error, Debug)]
+pub enum BtpProbeError {
+    #[error("Cannot connect to BTP url: {0}. Got error {1}")]
+    CannotConnect(Url, String),
+    #[error("The BTP server did not accept the auth token")]
+    Unauthorized,
+    #[error("The BTP server did not respond within {0:?}")]
+    Timeout(Duration),
+    #[error("The BTP connection failed: {0}")]
+    ConnectionFailed(String),
+}
+
 impl From<BtpClientError> for warp::Rejection {
     fn from(src: BtpClientError) -> Self {
         let err = ApiError::internal_server_error().detail(src.to_string());
@@ -74,19 +104,11 @@ where
     A: BtpAccount + Send + Sync + 'static,
 {
     let account_id = account.id();
-    let mut url = account
-        .get_ilp_over_btp_url()
-        .expect("Accounts must have BTP URLs")
-        .clone();
-    if url.scheme().starts_with("btp+") {
-        // Re-parse the URL after stripping off the leading "btp+" prefix.
-        // We cannot use set_scheme here because the URL specification
-        // does not allow converting between "special" and "non-special"
-        // schemes, and "ws" is considered special.
-        // The unwrap cannot fail since we've already been given a valid
-        // URL, and in this branch we know it begins with "btp+".
-        url = Url::parse(&url.into_string()[4..]).unwrap();
-    }
+    let url = websocket_url(
+        account
+            .get_ilp_over_btp_url()
+            .expect("Accounts must have BTP URLs"),
+    );
     let token = account
         .get_ilp_over_btp_outgoing_token()
         .map(|s| s.to_vec())
@@ -111,21 +133,15 @@ where
     );
 
     // Send BTP authentication
+    let mut protocol_data = auth_protocol_data(token);
+    // Advertise fragmentation support, the server's answer comes with its auth response
+    if let Some(max_message_size) = service.max_message_size() {
+        protocol_data.push(fragmentation_protocol_data(max_message_size));
+    }
     let auth_packet = Message::binary(
         BtpPacket::Message(BtpMessage {
             request_id: random(),
-            protocol_data: vec![
-                ProtocolData {
-                    protocol_name: "auth".into(),
-                    content_type: ContentType::ApplicationOctetStream,
-                    data: vec![],
-                },
-                ProtocolData {
-                    protocol_name: "auth_token".into(),
-                    content_type: ContentType::TextPlainUtf8,
-                    data: token,
-                },
-            ],
+            protocol_data,
         })
         .to_bytes(),
     );
@@ -139,7 +155,7 @@ where
         Ok(_) => {
             debug!("Connected to account {}'s server", account.id());
             let connection = connection.filter_map(|v| async move { v.ok() });
-            service.add_connection(account, connection);
+            service.add_connection(account, connection, None);
             Ok(())
         }
         Err(err) => {
@@ -153,3 +169,118 @@ where
         }
     }
 }
+
+/// Strips the leading "btp+" off the scheme of the URL, if any
+fn websocket_url(url: &Url) -> Url {
+    if url.scheme().starts_with("btp+") {
+        // Re-parse the URL after stripping off the leading "btp+" prefix.
+        // We cannot use set_scheme here because the URL specification
+        // does not allow converting between "special" and "non-special"
+        // schemes, and "ws" is considered special.
+        // The unwrap cannot fail since we've already been given a valid
+        // URL, and in this branch we know it begins with "btp+".
+        Url::parse(&url.as_str()[4..]).unwrap()
+    } else {
+        url.clone()
+    }
+}
+
+fn auth_protocol_data(token: Vec<u8>) -> Vec<ProtocolData> {
+    vec![
+        ProtocolData {
+            protocol_name: "auth".into(),
+            content_type: ContentType::ApplicationOctetStream,
+            data: vec![],
+        },
+        ProtocolData {
+            protocol_name: "auth_token".into(),
+            content_type: ContentType::TextPlainUtf8,
+            data: token,
+        },
+    ]
+}
+
+/// Tests the BTP server at the URL without adding the connection to any service: connects,
+/// authenticates with the token, sends the Prepare and returns the Fulfill or Reject the
+/// server responds with. Each step must complete within the timeout.
+///
+/// Used to verify the configuration of a peer before creating its account.
+pub async fn probe_btp_server(
+    url: &Url,
+    token: &[u8],
+    prepare: Prepare,
+    timeout: Duration,
+) -> Result<IlpResult, BtpProbeError> {
+    let url = websocket_url(url);
+    let (mut connection, _) = tokio::time::timeout(timeout, connect_async(url.clone()))
+        .await
+        .map_err(|_| BtpProbeError::CannotConnect(url.clone(), "timed out".to_string()))?
+        .map_err(|err| BtpProbeError::CannotConnect(url.clone(), err.to_string()))?;
+
+    let auth_request_id = random();
+    let auth = async {
+        connection
+            .send(Message::binary(
+                BtpPacket::Message(BtpMessage {
+                    request_id: auth_request_id,
+                    protocol_data: auth_protocol_data(token.to_vec()),
+                })
+                .to_bytes(),
+            ))
+            .await
+            .map_err(|err| BtpProbeError::ConnectionFailed(err.to_string()))?;
+        // The server closes the connection if it does not accept the token
+        while let Some(message) = connection.next().await {
+            let data =
+                match message.map_err(|err| BtpProbeError::ConnectionFailed(err.to_string()))? {
+                    Message::Binary(data) => data,
+                    _ => continue,
+                };
+            match BtpPacket::from_bytes(&data) {
+                Ok(BtpPacket::Response(response)) if response.request_id == auth_request_id => {
+                    return Ok(())
+                }
+                Ok(BtpPacket::Error(error)) if error.request_id == auth_request_id => break,
+                _ => continue,
+            }
+        }
+        Err::<(), _>(BtpProbeError::Unauthorized)
+    };
+    tokio::time::timeout(timeout, auth)
+        .await
+        .map_err(|_| BtpProbeError::Timeout(timeout))??;
+
+    let request_id = random();
+    let exchange = async {
+        for message in ilp_packet_to_ws_messages(request_id, Packet::Prepare(prepare), None) {
+            connection
+                .send(message)
+                .await
+                .map_err(|err| BtpProbeError::ConnectionFailed(err.to_string()))?;
+        }
+        while let Some(message) = connection.next().await {
+            let message =
+                message.map_err(|err| BtpProbeError::ConnectionFailed(err.to_string()))?;
+            if !message.is_binary() {
+                continue;
+            }
+            match parse_ilp_packet(message, None) {
+                Ok(Some((id, Packet::Fulfill(fulfill)))) if id == request_id => {
+                    return Ok(Ok(fulfill))
+                }
+                Ok(Some((id, Packet::Reject(reject)))) if id == request_id => {
+                    return Ok(Err(reject))
+                }
+                _ => continue,
+            }
+        }
+        Err::<IlpResult, _>(BtpProbeError::ConnectionFailed(
+            "The connection was closed before the response was received".to_string(),
+        ))
+    };
+    let result = tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| BtpProbeError::Timeout(timeout))?;
+    let _ = connection.close(None).await;
+    result
+}
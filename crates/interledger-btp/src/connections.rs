@@ -0,0 +1,155 @@
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use interledger_service::Username;
+use parking_lot::{Mutex, RwLock};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::SystemTime,
+};
+use uuid::Uuid;
+
+/// How many of the addresses an account authenticated from are remembered
+const MAX_AUTHENTICATED_ADDRESSES: usize = 16;
+
+/// The state of an account's BTP connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BtpConnectionState {
+    /// Whether the account currently has an open connection
+    pub connected: bool,
+    /// When the connection was opened or the last message was received over it
+    pub last_seen: SystemTime,
+}
+
+/// Shared handle to the states of the BTP connections of a `BtpOutgoingService`,
+/// which other services can use to check whether an account is connected.
+///
+/// Each account has at most one active connection: when it connects again, the new
+/// connection takes over and the previous one is closed.
+#[derive(Debug, Clone, Default)]
+pub struct BtpConnections {
+    states: Arc<RwLock<HashMap<Uuid, BtpConnectionState>>>,
+    /// The accounts whose connections are being re-established
+    reconnecting: Arc<RwLock<HashSet<Uuid>>>,
+    /// Notified with the account's id whenever a connection closes
+    disconnect_listeners: Arc<Mutex<Vec<UnboundedSender<Uuid>>>>,
+    /// Notified with the username whenever an incoming connection fails to authenticate
+    auth_failure_listeners: Arc<Mutex<Vec<UnboundedSender<Username>>>>,
+    /// The addresses incoming connections authenticated from, indexed by the lowercased usernames
+    authenticated_addresses: Arc<RwLock<HashMap<String, HashSet<IpAddr>>>>,
+}
+
+impl BtpConnections {
+    /// Returns the state of the account's connection, or `None` if it never connected
+    pub fn get(&self, account_id: &Uuid) -> Option<BtpConnectionState> {
+        self.states.read().get(account_id).copied()
+    }
+
+    /// Returns whether the account currently has an open connection
+    pub fn is_connected(&self, account_id: &Uuid) -> bool {
+        self.get(account_id).map_or(false, |state| state.connected)
+    }
+
+    /// Returns the states of the connections of all the accounts which ever connected
+    pub fn all(&self) -> HashMap<Uuid, BtpConnectionState> {
+        self.states.read().clone()
+    }
+
+    /// Returns whether the account's connection is down and being re-established
+    pub fn is_reconnecting(&self, account_id: &Uuid) -> bool {
+        self.reconnecting.read().contains(account_id)
+    }
+
+    /// Returns a stream of the ids of the accounts whose connections close
+    pub fn disconnects(&self) -> UnboundedReceiver<Uuid> {
+        let (sender, receiver) = unbounded();
+        self.disconnect_listeners.lock().push(sender);
+        receiver
+    }
+
+    /// Returns a stream of the usernames for which incoming connections failed to authenticate.
+    ///
+    /// Only the failures of connections from addresses which previously authenticated as the
+    /// account are reported, so that anyone who knows a peer's username cannot get the peer
+    /// penalized by sending it invalid credentials.
+    pub fn auth_failures(&self) -> UnboundedReceiver<Username> {
+        let (sender, receiver) = unbounded();
+        self.auth_failure_listeners.lock().push(sender);
+        receiver
+    }
+
+    pub(crate) fn set_authenticated(&self, username: &Username, remote: Option<SocketAddr>) {
+        if let Some(remote) = remote {
+            let mut authenticated_addresses = self.authenticated_addresses.write();
+            let addresses = authenticated_addresses
+                .entry(username.to_lowercase())
+                .or_default();
+            if addresses.len() >= MAX_AUTHENTICATED_ADDRESSES && !addresses.contains(&remote.ip()) {
+                // Forget the addresses the account authenticated from before
+                addresses.clear();
+            }
+            addresses.insert(remote.ip());
+        }
+    }
+
+    /// Forgets the connection state and the authenticated addresses of a deleted account
+    pub fn remove_account(&self, account_id: &Uuid, username: &Username) {
+        self.states.write().remove(account_id);
+        self.reconnecting.write().remove(account_id);
+        self.authenticated_addresses
+            .write()
+            .remove(&username.to_lowercase());
+    }
+
+    pub(crate) fn set_auth_failed(&self, username: &Username, remote: Option<SocketAddr>) {
+        let authenticated_before = remote.map_or(false, |remote| {
+            self.authenticated_addresses
+                .read()
+                .get(&username.to_lowercase())
+                .map_or(false, |addresses| addresses.contains(&remote.ip()))
+        });
+        if !authenticated_before {
+            return;
+        }
+        self.auth_failure_listeners
+            .lock()
+            .retain(|listener| listener.unbounded_send(username.clone()).is_ok());
+    }
+
+    pub(crate) fn set_reconnecting(&self, account_id: Uuid, reconnecting: bool) {
+        if reconnecting {
+            self.reconnecting.write().insert(account_id);
+        } else {
+            self.reconnecting.write().remove(&account_id);
+        }
+    }
+
+    pub(crate) fn set_connected(&self, account_id: Uuid, connected: bool) {
+        self.states.write().insert(
+            account_id,
+            BtpConnectionState {
+                connected,
+                last_seen: SystemTime::now(),
+            },
+        );
+    }
+
+    pub(crate) fn set_seen(&self, account_id: &Uuid) {
+        if let Some(state) = self.states.write().get_mut(account_id) {
+            state.last_seen = SystemTime::now();
+        }
+    }
+
+    /// Marks the account as disconnected, keeping the time it was last seen
+    pub(crate) fn set_disconnected(&self, account_id: &Uuid) {
+        if let Some(state) = self.states.write().get_mut(account_id) {
+            if !state.connected {
+                return;
+            }
+            state.connected = false;
+        }
+        self.disconnect_listeners
+            .lock()
+            .retain(|listener| listener.unbounded_send(*account_id).is_ok());
+    }
+}
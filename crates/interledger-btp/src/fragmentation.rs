@@ -0,0 +1,330 @@
+//! Splitting of ILP packets which are too large for the peer's WebSocket messages
+//! across multiple BTP messages, and their reassembly on the receiving side.
+//!
+//! Fragmentation is negotiated when the connection is authenticated: each side which
+//! supports it adds an `ilp_fragmentation` protocol data entry, containing the size of the
+//! largest message it accepts as a big-endian u32, to the auth message (for the client) or
+//! the auth response (for the server). Packets are only ever fragmented if both sides
+//! advertised support, so peers which do not know about fragmentation are unaffected.
+//!
+//! A fragmented packet is sent as a series of BTP packets of the type and with the request
+//! id the packet would have been sent with, each carrying an `ilp_fragment` protocol data
+//! entry instead of the `ilp` one:
+//!
+//! ```text
+//! index (u16) | count (u16) | chunk of the ILP packet
+//! ```
+
+use super::packet::{ContentType, ProtocolData};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+pub const FRAGMENTATION_PROTOCOL: &str = "ilp_fragmentation";
+pub const FRAGMENT_PROTOCOL: &str = "ilp_fragment";
+
+/// Length of the index and count which precede each chunk
+const FRAGMENT_HEADER_LEN: usize = 4;
+/// Upper bound of the bytes a BTP packet carrying a single `ilp_fragment` entry adds to
+/// its chunk, for chunks shorter than 64KB
+const FRAGMENT_OVERHEAD: usize = 32;
+/// Smallest message size the peers may advertise, so that packets are not split
+/// into an unreasonable number of fragments
+pub const MIN_MESSAGE_SIZE: usize = 256;
+/// Largest packet which is reassembled. ILP packets are at most a few bytes over 32KB
+const MAX_PACKET_LEN: usize = 64 * 1024;
+/// Maximum number of packets of a connection which may be partially received at once.
+/// The oldest one is dropped to make room for a new one
+const MAX_PENDING_PACKETS: usize = 64;
+/// How long the fragments of a packet may take to arrive. Longer than the expiry of any
+/// packet the node would still forward
+const PENDING_PACKET_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq, Error)]
+pub enum FragmentError {
+    #[error("Fragment is shorter than its header")]
+    TooShort,
+    #[error("Invalid fragment index {0} of {1}")]
+    InvalidIndex(u16, u16),
+    #[error("Fragment count {0} does not match the {1} of the previous fragments")]
+    CountMismatch(u16, u16),
+    #[error("Duplicate fragment {0}")]
+    Duplicate(u16),
+    #[error("Reassembled packet would be larger than {0} bytes")]
+    TooLarge(usize),
+}
+
+/// The protocol data entry advertising support for fragmentation
+pub fn fragmentation_protocol_data(max_message_size: usize) -> ProtocolData {
+    let max_message_size = u32::try_from(max_message_size).unwrap_or(u32::MAX);
+    ProtocolData {
+        protocol_name: FRAGMENTATION_PROTOCOL.into(),
+        content_type: ContentType::ApplicationOctetStream,
+        data: max_message_size.to_be_bytes().to_vec(),
+    }
+}
+
+/// Returns the largest message size advertised in the protocol data, if any.
+/// Sizes which are invalid or smaller than `MIN_MESSAGE_SIZE` are ignored.
+pub fn parse_max_message_size(protocol_data: &[ProtocolData]) -> Option<usize> {
+    let data = &protocol_data
+        .iter()
+        .find(|proto| proto.protocol_name == FRAGMENTATION_PROTOCOL)?
+        .data;
+    let max_message_size = u32::from_be_bytes(<[u8; 4]>::try_from(&data[..]).ok()?) as usize;
+    if max_message_size >= MIN_MESSAGE_SIZE {
+        Some(max_message_size)
+    } else {
+        None
+    }
+}
+
+/// Returns whether an ILP packet of the given length must be fragmented to fit in a message
+pub fn needs_fragmentation(packet_len: usize, max_message_size: usize) -> bool {
+    packet_len + FRAGMENT_OVERHEAD > max_message_size
+}
+
+/// Splits the ILP packet into the `ilp_fragment` entries of BTP packets which are at most
+/// `max_message_size` bytes long
+pub fn fragment(packet: &[u8], max_message_size: usize) -> Vec<ProtocolData> {
+    let chunk_size = max_message_size.max(MIN_MESSAGE_SIZE) - FRAGMENT_OVERHEAD;
+    let chunks = packet.chunks(chunk_size);
+    let count = chunks.len() as u16;
+    chunks
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut data = Vec::with_capacity(FRAGMENT_HEADER_LEN + chunk.len());
+            data.extend_from_slice(&(index as u16).to_be_bytes());
+            data.extend_from_slice(&count.to_be_bytes());
+            data.extend_from_slice(chunk);
+            ProtocolData {
+                protocol_name: FRAGMENT_PROTOCOL.into(),
+                content_type: ContentType::ApplicationOctetStream,
+                data,
+            }
+        })
+        .collect()
+}
+
+/// The fragments received so far of a packet
+#[derive(Debug)]
+struct PendingPacket {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    len: usize,
+    /// When the first fragment was received
+    started_at: Instant,
+}
+
+/// Reassembles the fragmented packets received over a connection.
+///
+/// The packets whose fragments did not all arrive within `PENDING_PACKET_TIMEOUT` are
+/// dropped, and so is the oldest packet once `MAX_PENDING_PACKETS` are partially received,
+/// so that a peer which never sends the last fragments cannot exhaust the memory.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    /// Indexed by request id and whether the fragments are sent in BTP responses
+    pending: HashMap<(u32, bool), PendingPacket>,
+}
+
+impl Reassembler {
+    /// Adds the fragment, returning the reassembled ILP packet once all of its fragments
+    /// were received. The partially received packet is dropped if the fragment is invalid.
+    pub fn push(
+        &mut self,
+        request_id: u32,
+        is_response: bool,
+        fragment: &[u8],
+    ) -> Result<Option<Vec<u8>>, FragmentError> {
+        self.push_at(request_id, is_response, fragment, Instant::now())
+    }
+
+    fn push_at(
+        &mut self,
+        request_id: u32,
+        is_response: bool,
+        fragment: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, FragmentError> {
+        self.pending
+            .retain(|_, packet| now.duration_since(packet.started_at) < PENDING_PACKET_TIMEOUT);
+        let key = (request_id, is_response);
+        let result = self.add_fragment(key, fragment, now);
+        if result.is_err() {
+            self.pending.remove(&key);
+        }
+        result
+    }
+
+    fn add_fragment(
+        &mut self,
+        key: (u32, bool),
+        fragment: &[u8],
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, FragmentError> {
+        if fragment.len() < FRAGMENT_HEADER_LEN {
+            return Err(FragmentError::TooShort);
+        }
+        let index = u16::from_be_bytes([fragment[0], fragment[1]]);
+        let count = u16::from_be_bytes([fragment[2], fragment[3]]);
+        let chunk = &fragment[FRAGMENT_HEADER_LEN..];
+        if index >= count {
+            return Err(FragmentError::InvalidIndex(index, count));
+        }
+        if count == 1 {
+            return Ok(Some(chunk.to_vec()));
+        }
+
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING_PACKETS {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, packet)| packet.started_at)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.pending.remove(&oldest);
+            }
+        }
+        let packet = self.pending.entry(key).or_insert_with(|| PendingPacket {
+            chunks: vec![None; count as usize],
+            received: 0,
+            len: 0,
+            started_at: now,
+        });
+        if packet.chunks.len() != count as usize {
+            return Err(FragmentError::CountMismatch(
+                count,
+                packet.chunks.len() as u16,
+            ));
+        }
+        if packet.chunks[index as usize].is_some() {
+            return Err(FragmentError::Duplicate(index));
+        }
+        packet.len += chunk.len();
+        if packet.len > MAX_PACKET_LEN {
+            return Err(FragmentError::TooLarge(MAX_PACKET_LEN));
+        }
+        packet.chunks[index as usize] = Some(chunk.to_vec());
+        packet.received += 1;
+
+        if packet.received < packet.chunks.len() {
+            return Ok(None);
+        }
+        let packet = self.pending.remove(&key).expect("packet is pending");
+        let mut data = Vec::with_capacity(packet.len);
+        for chunk in packet.chunks.into_iter().flatten() {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(Some(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{BtpMessage, Serializable};
+
+    #[test]
+    fn negotiates_the_max_message_size() {
+        let protocol_data = vec![fragmentation_protocol_data(1000)];
+        assert_eq!(parse_max_message_size(&protocol_data), Some(1000));
+        assert_eq!(parse_max_message_size(&[]), None);
+        assert_eq!(
+            parse_max_message_size(&[fragmentation_protocol_data(MIN_MESSAGE_SIZE - 1)]),
+            None
+        );
+    }
+
+    #[test]
+    fn fragments_fit_in_the_messages() {
+        let packet: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let fragments = fragment(&packet, 1000);
+        assert_eq!(fragments.len(), 6);
+        for fragment in fragments.iter() {
+            let message = BtpMessage {
+                request_id: u32::MAX,
+                protocol_data: vec![fragment.clone()],
+            };
+            assert!(message.to_bytes().len() <= 1000);
+        }
+
+        // Fragments may arrive in any order
+        let mut reassembler = Reassembler::default();
+        let (last, others) = fragments.split_last().unwrap();
+        for fragment in others.iter().rev() {
+            assert_eq!(reassembler.push(1, false, &fragment.data), Ok(None));
+        }
+        assert_eq!(reassembler.push(1, false, &last.data), Ok(Some(packet)));
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_fragments() {
+        let fragments = fragment(&[0; 1000], MIN_MESSAGE_SIZE);
+        let mut reassembler = Reassembler::default();
+        assert_eq!(
+            reassembler.push(1, false, &[0, 1]),
+            Err(FragmentError::TooShort)
+        );
+        assert_eq!(
+            reassembler.push(1, false, &[0, 2, 0, 2]),
+            Err(FragmentError::InvalidIndex(2, 2))
+        );
+
+        assert_eq!(reassembler.push(1, false, &fragments[0].data), Ok(None));
+        assert_eq!(
+            reassembler.push(1, false, &fragments[0].data),
+            Err(FragmentError::Duplicate(0))
+        );
+        // The partially received packet was dropped
+        assert!(reassembler.pending.is_empty());
+
+        assert_eq!(reassembler.push(1, false, &fragments[0].data), Ok(None));
+        assert_eq!(
+            reassembler.push(1, false, &[0, 1, 0, 2]),
+            Err(FragmentError::CountMismatch(2, 5))
+        );
+
+        // Too many fragments to be reassembled into a single packet
+        let fragments = fragment(&[0; MAX_PACKET_LEN + 1], MIN_MESSAGE_SIZE);
+        let result = fragments
+            .iter()
+            .map(|fragment| reassembler.push(2, true, &fragment.data))
+            .find(Result::is_err);
+        assert_eq!(result, Some(Err(FragmentError::TooLarge(MAX_PACKET_LEN))));
+    }
+
+    #[test]
+    fn drops_the_oldest_and_expired_packets() {
+        let fragments = fragment(&[0; 1000], MIN_MESSAGE_SIZE);
+        let start = Instant::now();
+        let mut reassembler = Reassembler::default();
+        for request_id in 0..MAX_PENDING_PACKETS as u32 {
+            let now = start + Duration::from_millis(request_id as u64);
+            assert_eq!(
+                reassembler.push_at(request_id, false, &fragments[0].data, now),
+                Ok(None)
+            );
+        }
+
+        // The first packet makes room for a new one
+        let now = start + Duration::from_secs(1);
+        assert_eq!(
+            reassembler.push_at(1000, false, &fragments[0].data, now),
+            Ok(None)
+        );
+        assert_eq!(reassembler.pending.len(), MAX_PENDING_PACKETS);
+        assert!(!reassembler.pending.contains_key(&(0, false)));
+        assert!(reassembler.pending.contains_key(&(1, false)));
+
+        // The packets which are not complete in time are dropped
+        let now = start + PENDING_PACKET_TIMEOUT + Duration::from_millis(500);
+        assert_eq!(
+            reassembler.push_at(1001, false, &fragments[0].data, now),
+            Ok(None)
+        );
+        assert_eq!(reassembler.pending.len(), 2);
+        assert!(reassembler.pending.contains_key(&(1000, false)));
+    }
+}
@@ -11,13 +11,20 @@ use interledger_service::{Account, Username};
 use url::Url;
 
 mod client;
+mod connections;
 mod errors;
+mod fragmentation;
 mod packet;
+mod reconnect;
 mod server;
 mod service;
 mod wrapped_ws;
 
-pub use self::client::{connect_client, connect_to_service_account};
+pub use self::client::{
+    connect_accounts, connect_client, connect_to_service_account, probe_btp_server, BtpProbeError,
+};
+pub use self::connections::{BtpConnectionState, BtpConnections};
+pub use self::reconnect::{reconnect_clients, ReconnectBackoff};
 pub use self::server::btp_service_as_filter; // This is consumed only by the node.
 pub use self::service::{BtpOutgoingService, BtpService};
 
@@ -327,4 +334,374 @@ mod client_server {
 
         btp_service.close();
     }
+
+    #[tokio::test]
+    async fn new_connection_takes_over() {
+        let bind_addr = get_open_port();
+
+        let server_acc_id = Uuid::new_v4();
+        let server_store = TestStore {
+            accounts: Arc::new([TestAccount {
+                id: server_acc_id,
+                ilp_over_btp_incoming_token: Some("test_auth_token".to_string()),
+                ilp_over_btp_outgoing_token: None,
+                ilp_over_btp_url: None,
+            }]),
+        };
+        let server_address = Address::from_str("example.server").unwrap();
+        let btp_service = BtpOutgoingService::new(
+            server_address.clone(),
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&server_address),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        btp_service
+            .clone()
+            .handle_incoming(incoming_service_fn(|_| {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: b"test data",
+                }
+                .build())
+            }))
+            .await;
+        let connections = btp_service.connections();
+        assert!(connections.get(&server_acc_id).is_none());
+        let filter = btp_service_as_filter(btp_service.clone(), server_store);
+        tokio::spawn(warp::serve(filter).bind(bind_addr));
+
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_over_btp_url: Some(
+                Url::parse(&format!("btp+ws://{}/accounts/alice/ilp/btp", bind_addr)).unwrap(),
+            ),
+            ilp_over_btp_outgoing_token: Some("test_auth_token".to_string()),
+            ilp_over_btp_incoming_token: None,
+        };
+        let addr = Address::from_str("example.address").unwrap();
+        let connect = || {
+            let addr = addr.clone();
+            connect_client(
+                addr.clone(),
+                vec![account.clone()],
+                true,
+                outgoing_service_fn(move |_| {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F02_UNREACHABLE,
+                        message: &[],
+                        data: &[],
+                        triggered_by: Some(&addr),
+                    }
+                    .build())
+                }),
+            )
+        };
+
+        let first_client = connect().await.unwrap();
+        assert!(first_client.connections().is_connected(&account.id));
+        let mut second_client = connect().await.unwrap();
+
+        // The server closes the first connection once the second one authenticates
+        let mut attempts = 0;
+        while first_client.connections().is_connected(&account.id) {
+            attempts += 1;
+            assert!(attempts < 50, "the first connection was not closed");
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert!(connections.is_connected(&server_acc_id));
+        let last_seen = connections.get(&server_acc_id).unwrap().last_seen;
+
+        let res = second_client
+            .send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account.clone(),
+                original_amount: 100,
+                prepare: PrepareBuilder {
+                    destination: Address::from_str("example.destination").unwrap(),
+                    amount: 100,
+                    execution_condition: &[0; 32],
+                    expires_at: SystemTime::now() + Duration::from_secs(30),
+                    data: b"test data",
+                }
+                .build(),
+            })
+            .await;
+        assert!(res.is_ok());
+        assert!(connections.get(&server_acc_id).unwrap().last_seen > last_seen);
+
+        btp_service.close();
+        second_client.close();
+    }
+
+    #[tokio::test]
+    async fn reconnects_with_backoff() {
+        let bind_addr = get_open_port();
+
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_over_btp_url: Some(
+                Url::parse(&format!("btp+ws://{}/accounts/alice/ilp/btp", bind_addr)).unwrap(),
+            ),
+            ilp_over_btp_outgoing_token: Some("test_auth_token".to_string()),
+            ilp_over_btp_incoming_token: None,
+        };
+        let addr = Address::from_str("example.address").unwrap();
+        let addr_clone = addr.clone();
+        let mut btp_client = BtpOutgoingService::new(
+            addr,
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    data: &[],
+                    triggered_by: Some(&addr_clone),
+                }
+                .build())
+            }),
+        );
+        reconnect_clients(
+            btp_client.clone(),
+            TestStore {
+                accounts: Arc::new([account.clone()]),
+            },
+            vec![account.clone()],
+            ReconnectBackoff {
+                initial_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(100),
+                ..Default::default()
+            },
+        );
+        let request = OutgoingRequest {
+            from: account.clone(),
+            to: account.clone(),
+            original_amount: 100,
+            prepare: PrepareBuilder {
+                destination: Address::from_str("example.destination").unwrap(),
+                amount: 100,
+                execution_condition: &[0; 32],
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: b"test data",
+            }
+            .build(),
+        };
+
+        // The server is not up yet
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert!(btp_client.connections().is_reconnecting(&account.id));
+        let reject = btp_client.send_request(request.clone()).await.unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T01_PEER_UNREACHABLE);
+
+        let server_store = TestStore {
+            accounts: Arc::new([TestAccount {
+                id: Uuid::new_v4(),
+                ilp_over_btp_incoming_token: Some("test_auth_token".to_string()),
+                ilp_over_btp_outgoing_token: None,
+                ilp_over_btp_url: None,
+            }]),
+        };
+        let server_address = Address::from_str("example.server").unwrap();
+        let btp_service = BtpOutgoingService::new(
+            server_address.clone(),
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&server_address),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        btp_service
+            .clone()
+            .handle_incoming(incoming_service_fn(|_| {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: b"test data",
+                }
+                .build())
+            }))
+            .await;
+        let filter = btp_service_as_filter(btp_service.clone(), server_store);
+        tokio::spawn(warp::serve(filter).bind(bind_addr));
+
+        let mut attempts = 0;
+        while !btp_client.connections().is_connected(&account.id) {
+            attempts += 1;
+            assert!(attempts < 50, "the client did not reconnect");
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        assert!(!btp_client.connections().is_reconnecting(&account.id));
+        assert!(btp_client.send_request(request).await.is_ok());
+
+        btp_client.close();
+        btp_service.close();
+    }
+
+    #[tokio::test]
+    async fn stops_reconnecting_to_deleted_accounts() {
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_over_btp_url: Some(
+                Url::parse(&format!(
+                    "btp+ws://{}/accounts/alice/ilp/btp",
+                    get_open_port()
+                ))
+                .unwrap(),
+            ),
+            ilp_over_btp_outgoing_token: Some("test_auth_token".to_string()),
+            ilp_over_btp_incoming_token: None,
+        };
+        let addr = Address::from_str("example.address").unwrap();
+        let addr_clone = addr.clone();
+        let btp_client = BtpOutgoingService::new(
+            addr,
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    data: &[],
+                    triggered_by: Some(&addr_clone),
+                }
+                .build())
+            }),
+        );
+        // The account was deleted from the store since it was loaded
+        reconnect_clients(
+            btp_client.clone(),
+            TestStore {
+                accounts: Arc::new([]),
+            },
+            vec![account.clone()],
+            ReconnectBackoff {
+                initial_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(100),
+                ..Default::default()
+            },
+        );
+
+        // The first attempt is made after at most 10ms
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        assert!(!btp_client.connections().is_reconnecting(&account.id));
+        assert!(!btp_client.connections().is_connected(&account.id));
+
+        btp_client.close();
+    }
+
+    #[tokio::test]
+    async fn fragments_large_packets() {
+        let bind_addr = get_open_port();
+
+        let server_store = TestStore {
+            accounts: Arc::new([TestAccount {
+                id: Uuid::new_v4(),
+                ilp_over_btp_incoming_token: Some("test_auth_token".to_string()),
+                ilp_over_btp_outgoing_token: None,
+                ilp_over_btp_url: None,
+            }]),
+        };
+        let server_address = Address::from_str("example.server").unwrap();
+        let btp_service = BtpOutgoingService::new(
+            server_address.clone(),
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&server_address),
+                    data: &[],
+                }
+                .build())
+            }),
+        )
+        .with_fragmentation(1000);
+        // Echo the data of the Prepares in the Fulfills
+        btp_service
+            .clone()
+            .handle_incoming(incoming_service_fn(|request| {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: request.prepare.data(),
+                }
+                .build())
+            }))
+            .await;
+        let filter = btp_service_as_filter(btp_service.clone(), server_store);
+        tokio::spawn(warp::serve(filter).bind(bind_addr));
+
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_over_btp_url: Some(
+                Url::parse(&format!("btp+ws://{}/accounts/alice/ilp/btp", bind_addr)).unwrap(),
+            ),
+            ilp_over_btp_outgoing_token: Some("test_auth_token".to_string()),
+            ilp_over_btp_incoming_token: None,
+        };
+        let addr = Address::from_str("example.address").unwrap();
+        let addr_clone = addr.clone();
+        let btp_client = BtpOutgoingService::new(
+            addr,
+            outgoing_service_fn(move |_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    data: &[],
+                    triggered_by: Some(&addr_clone),
+                }
+                .build())
+            }),
+        )
+        .with_fragmentation(1000);
+        connect_accounts(&btp_client, vec![account.clone()], true)
+            .await
+            .unwrap();
+        // Wait for the client to get the server's auth response
+        let mut attempts = 0;
+        while btp_client.peer_max_message_size(&account.id).is_none() {
+            attempts += 1;
+            assert!(
+                attempts < 50,
+                "the server did not advertise its max message size"
+            );
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        let mut btp_client = btp_client
+            .handle_incoming(incoming_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    data: &[],
+                    triggered_by: None,
+                }
+                .build())
+            }))
+            .await;
+
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let fulfill = btp_client
+            .send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account.clone(),
+                original_amount: 100,
+                prepare: PrepareBuilder {
+                    destination: Address::from_str("example.destination").unwrap(),
+                    amount: 100,
+                    execution_condition: &[0; 32],
+                    expires_at: SystemTime::now() + Duration::from_secs(30),
+                    data: &data,
+                }
+                .build(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(fulfill.data(), &data[..]);
+
+        btp_client.close();
+        btp_service.close();
+    }
 }
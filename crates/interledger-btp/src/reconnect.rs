@@ -0,0 +1,207 @@
+use super::{
+    client::connect_to_service_account, service::BtpOutgoingService, BtpAccount, BtpStore,
+};
+use futures::StreamExt;
+use interledger_service::*;
+use rand::random;
+use std::{collections::HashSet, time::Duration};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// How long to wait between the attempts to re-establish a BTP connection.
+///
+/// The delay starts at `initial_delay` and is multiplied by `multiplier` after each
+/// failed attempt, up to `max_delay`. Each delay is shortened by a random fraction of
+/// up to `jitter` so that the clients of a restarted server do not all reconnect at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectBackoff {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    /// Between 0 (no jitter) and 1
+    pub jitter: f64,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        ReconnectBackoff {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.25,
+        }
+    }
+}
+
+impl ReconnectBackoff {
+    /// The delay before the given attempt, counting from 0
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.initial_delay.as_secs_f64() * self.multiplier.powi(attempt.min(64) as i32);
+        let delay = delay.min(self.max_delay.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0) * random::<f64>();
+        Duration::from_secs_f64(delay * (1.0 - jitter))
+    }
+}
+
+/// Keeps the BTP connections to the given accounts open: whenever one of them closes (or
+/// if it could not be opened in the first place), it is re-established, re-authenticating
+/// with the account's outgoing token, after waiting according to the `backoff`.
+///
+/// The account is loaded from the store again before each attempt, so that the changes of
+/// its URL or token are picked up. Once it was deleted (or no longer has an ILP over BTP
+/// URL), its connection is not re-established anymore.
+///
+/// While an account's connection is being re-established, the outgoing requests to it are
+/// rejected with `T01: Peer Unreachable`.
+///
+/// The returned task finishes once the service is closed.
+pub fn reconnect_clients<O, S, A>(
+    service: BtpOutgoingService<O, A>,
+    store: S,
+    accounts: Vec<A>,
+    backoff: ReconnectBackoff,
+) -> JoinHandle<()>
+where
+    O: OutgoingService<A> + Clone + Send + Sync + 'static,
+    S: BtpStore<Account = A> + Clone + Send + Sync + 'static,
+    A: BtpAccount + Send + Sync + Clone + 'static,
+{
+    let connections = service.connections();
+    // Subscribe before checking which accounts are connected so that no disconnect is missed
+    let mut disconnects = connections.disconnects();
+    let accounts: HashSet<_> = accounts
+        .into_iter()
+        .filter(|account| account.get_ilp_over_btp_url().is_some())
+        .map(|account| account.id())
+        .collect();
+
+    tokio::spawn(async move {
+        for account_id in accounts.iter() {
+            if !connections.is_connected(account_id) {
+                reconnect(service.clone(), store.clone(), *account_id, backoff);
+            }
+        }
+        while let Some(account_id) = disconnects.next().await {
+            if service.is_closed() {
+                break;
+            }
+            if accounts.contains(&account_id) {
+                reconnect(service.clone(), store.clone(), account_id, backoff);
+            }
+        }
+        debug!("Stopped reconnecting BTP connections");
+    })
+}
+
+/// Spawns a task which tries to connect to the account until it succeeds, the account is
+/// gone from the store or the service is closed
+fn reconnect<O, S, A>(
+    service: BtpOutgoingService<O, A>,
+    store: S,
+    account_id: Uuid,
+    backoff: ReconnectBackoff,
+) where
+    O: OutgoingService<A> + Clone + Send + Sync + 'static,
+    S: BtpStore<Account = A> + Clone + Send + Sync + 'static,
+    A: BtpAccount + Send + Sync + Clone + 'static,
+{
+    let connections = service.connections();
+    if connections.is_reconnecting(&account_id) {
+        return;
+    }
+    connections.set_reconnecting(account_id, true);
+
+    tokio::spawn(async move {
+        let mut attempt = 0;
+        let mut removed = false;
+        loop {
+            let delay = backoff.delay(attempt);
+            debug!(
+                "Reconnecting to account {} in {:?} (attempt {})",
+                account_id,
+                delay,
+                attempt + 1
+            );
+            tokio::time::delay_for(delay).await;
+            if service.is_closed() {
+                break;
+            }
+            let account = match store.get_btp_outgoing_accounts().await {
+                Ok(accounts) => accounts
+                    .into_iter()
+                    .find(|account| account.id() == account_id),
+                Err(err) => {
+                    warn!(
+                        "Error loading account {} to reconnect to it: {}",
+                        account_id, err
+                    );
+                    attempt = attempt.saturating_add(1);
+                    continue;
+                }
+            };
+            let account = match account {
+                Some(account) => account,
+                None => {
+                    info!(
+                        "Account {} was deleted or no longer has an ILP over BTP URL, not reconnecting to it",
+                        account_id
+                    );
+                    removed = true;
+                    break;
+                }
+            };
+            match connect_to_service_account(account.clone(), true, service.clone()).await {
+                Ok(_) => {
+                    info!("Reconnected to account {}", account.username());
+                    break;
+                }
+                Err(err) => {
+                    warn!(
+                        "Error reconnecting to account {}: {}",
+                        account.username(),
+                        err
+                    );
+                    attempt = attempt.saturating_add(1);
+                }
+            }
+        }
+        connections.set_reconnecting(account_id, false);
+        // The disconnects are ignored while reconnecting, so make sure we did not miss one
+        if !removed && !service.is_closed() && !connections.is_connected(&account_id) {
+            reconnect(service, store, account_id, backoff);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially() {
+        let backoff = ReconnectBackoff {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(backoff.delay(0), Duration::from_secs(1));
+        assert_eq!(backoff.delay(1), Duration::from_secs(2));
+        assert_eq!(backoff.delay(5), Duration::from_secs(32));
+        assert_eq!(backoff.delay(6), Duration::from_secs(60));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn jitter_shortens_the_delay() {
+        let backoff = ReconnectBackoff::default();
+        for attempt in 0..10 {
+            let delay = backoff.delay(attempt);
+            let max = ReconnectBackoff {
+                jitter: 0.0,
+                ..backoff
+            }
+            .delay(attempt);
+            assert!(delay <= max && delay >= max.mul_f64(0.75));
+        }
+    }
+}
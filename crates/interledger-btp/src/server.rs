@@ -1,9 +1,11 @@
+use super::fragmentation::{fragmentation_protocol_data, parse_max_message_size};
 use super::{packet::*, BtpAccount, BtpStore};
 use super::{service::BtpOutgoingService, wrapped_ws::WsWrap};
 use futures::{FutureExt, Sink, Stream};
 use futures::{SinkExt, StreamExt, TryFutureExt};
 use interledger_service::*;
 use secrecy::{ExposeSecret, SecretString};
+use std::net::SocketAddr;
 use std::time::Duration;
 use tracing::{debug, error, warn};
 use warp::{
@@ -35,18 +37,21 @@ where
         .and(warp::path("ilp"))
         .and(warp::path("btp"))
         .and(warp::path::end())
+        .and(warp::addr::remote())
         .and(warp::ws())
-        .map(move |username: Username, ws: Ws| {
-            // warp Websocket
-            let service_clone = service.clone();
-            let store_clone = store.clone();
-            ws.max_message_size(MAX_MESSAGE_SIZE)
-                .on_upgrade(|socket: WebSocket| {
-                    // wrapper over tungstenite Websocket
-                    add_connections(socket, username, service_clone, store_clone)
-                        .map(|result| result.unwrap())
-                })
-        })
+        .map(
+            move |username: Username, remote: Option<SocketAddr>, ws: Ws| {
+                // warp Websocket
+                let service_clone = service.clone();
+                let store_clone = store.clone();
+                ws.max_message_size(MAX_MESSAGE_SIZE)
+                    .on_upgrade(move |socket: WebSocket| {
+                        // wrapper over tungstenite Websocket
+                        add_connections(socket, username, remote, service_clone, store_clone)
+                            .map(|result| result.unwrap())
+                    })
+            },
+        )
         .boxed()
 }
 
@@ -57,6 +62,7 @@ where
 async fn add_connections<O, S, A>(
     socket: WebSocket,
     username: Username,
+    remote: Option<SocketAddr>,
     service: BtpOutgoingService<O, A>,
     store: S,
 ) -> Result<(), ()>
@@ -67,26 +73,42 @@ where
 {
     // We ignore all the errors
     let socket = socket.filter_map(|v| async move { v.ok() });
-    let (account, connection) =
-        match tokio::time::timeout(WEBSOCKET_TIMEOUT, validate_auth(store, username, socket)).await
-        {
-            Ok(res) => match res {
-                Ok(res) => res,
-                Err(_) => {
-                    warn!("Closing Websocket connection because of invalid credentials");
-                    return Ok(());
-                }
-            },
+    // We never accept messages larger than what the WebSocket accepts
+    let max_message_size = service
+        .max_message_size()
+        .map(|max_message_size| max_message_size.min(MAX_MESSAGE_SIZE));
+    let (account, connection, peer_max_message_size) = match tokio::time::timeout(
+        WEBSOCKET_TIMEOUT,
+        validate_auth(store, username.clone(), socket, max_message_size),
+    )
+    .await
+    {
+        Ok(res) => match res {
+            Ok(res) => res,
             Err(_) => {
-                warn!("Closing Websocket connection because of an error");
+                warn!("Closing Websocket connection because of invalid credentials");
+                service.connections().set_auth_failed(&username, remote);
                 return Ok(());
             }
-        };
+        },
+        Err(_) => {
+            warn!("Closing Websocket connection because of an error");
+            return Ok(());
+        }
+    };
+
+    service
+        .connections()
+        .set_authenticated(account.username(), remote);
 
     // We need to wrap our Warp connection in order to cast the Sink type
     // to tungstenite::Message. This probably can be implemented with SinkExt::with
     // but couldn't figure out how.
-    service.add_connection(account.clone(), WsWrap { connection });
+    service.add_connection(
+        account.clone(),
+        WsWrap { connection },
+        peer_max_message_size,
+    );
     debug!(
         "Added connection for account {}: (id: {})",
         account.username(),
@@ -99,13 +121,23 @@ where
 struct Auth {
     request_id: u32,
     token: SecretString,
+    /// The largest message the client accepts, if it supports fragmentation
+    max_message_size: Option<usize>,
 }
 
 async fn validate_auth<S, A>(
     store: S,
     username: Username,
     connection: impl Stream<Item = Message> + Sink<Message>,
-) -> Result<(A, impl Stream<Item = Message> + Sink<Message>), ()>
+    max_message_size: Option<usize>,
+) -> Result<
+    (
+        A,
+        impl Stream<Item = Message> + Sink<Message>,
+        Option<usize>,
+    ),
+    (),
+>
 where
     S: BtpStore<Account = A> + 'static,
     A: BtpAccount + 'static,
@@ -120,7 +152,10 @@ where
     let auth_response = Message::binary(
         BtpResponse {
             request_id: auth.request_id,
-            protocol_data: Vec::new(),
+            protocol_data: max_message_size
+                .map(fragmentation_protocol_data)
+                .into_iter()
+                .collect(),
         }
         .to_bytes(),
     );
@@ -130,7 +165,7 @@ where
         .map_err(|_| error!("warp::Error sending auth response"))
         .await?;
 
-    Ok((account, connection))
+    Ok((account, connection, auth.max_message_size))
 }
 
 /// Reads the first non-empty non-error binary message from the WebSocket and attempts to parse it as an AuthToken
@@ -180,6 +215,7 @@ fn parse_auth(ws_packet: Option<Message>) -> Option<Auth> {
                         return Some(Auth {
                             request_id,
                             token: SecretString::new(token),
+                            max_message_size: parse_max_message_size(&message.protocol_data),
                         });
                     } else {
                         warn!("BTP packet is missing auth token");
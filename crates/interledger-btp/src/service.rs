@@ -1,19 +1,28 @@
-use super::{packet::*, BtpAccount};
+use super::{
+    connections::BtpConnections,
+    fragmentation::{
+        fragment, needs_fragmentation, parse_max_message_size, Reassembler, FRAGMENT_PROTOCOL,
+    },
+    packet::*,
+    BtpAccount,
+};
 use async_trait::async_trait;
-use bytes::BytesMut;
 use futures::{
     channel::{
-        mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        mpsc::{unbounded, TrySendError, UnboundedReceiver, UnboundedSender},
         oneshot,
     },
     future, FutureExt, Sink, Stream, StreamExt,
 };
-use interledger_packet::{Address, ErrorCode, Fulfill, Packet, Prepare, Reject, RejectBuilder};
+use interledger_packet::{
+    pool, Address, ErrorCode, Fulfill, Packet, Prepare, Reject, RejectBuilder,
+};
 use interledger_service::*;
 use once_cell::sync::Lazy;
 use parking_lot::{Mutex, RwLock};
 use rand::random;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{convert::TryFrom, iter::IntoIterator, marker::PhantomData, sync::Arc, time::Duration};
 use stream_cancel::{Trigger, Valve};
 use tokio::time;
@@ -33,6 +42,38 @@ const SEND_MSG_TIMEOUT: Duration = Duration::from_secs(30);
 
 type IlpResultChannel = oneshot::Sender<Result<Fulfill, Reject>>;
 type IncomingRequestBuffer<A> = UnboundedReceiver<(A, u32, Prepare)>;
+/// The id and the close trigger of each account's active connection, indexed by account uid
+type ActiveConnections = Arc<Mutex<HashMap<Uuid, (Uuid, Trigger)>>>;
+
+/// The fragmentation state of a connection on which fragmentation is enabled
+#[derive(Clone, Default)]
+pub(crate) struct Fragmentation {
+    /// The largest message the peer accepts, or 0 until the peer advertises it
+    peer_max_message_size: Arc<AtomicUsize>,
+    reassembler: Arc<Mutex<Reassembler>>,
+}
+
+/// The sending side of an account's WebSocket connection
+#[derive(Clone)]
+struct Connection {
+    sender: UnboundedSender<Message>,
+    fragmentation: Option<Fragmentation>,
+}
+
+impl Connection {
+    /// Sends the packet, split into fragments if it is too large for the peer
+    fn send_packet(&self, request_id: u32, packet: Packet) -> Result<(), TrySendError<Message>> {
+        let max_message_size = self
+            .fragmentation
+            .as_ref()
+            .map(|fragmentation| fragmentation.peer_max_message_size.load(Ordering::Relaxed))
+            .filter(|max_message_size| *max_message_size > 0);
+        for message in ilp_packet_to_ws_messages(request_id, packet, max_message_size) {
+            self.sender.unbounded_send(message)?;
+        }
+        Ok(())
+    }
+}
 
 /// The BtpOutgoingService wraps all BTP/WebSocket connections that come
 /// in on the given address. It implements OutgoingService for sending
@@ -46,13 +87,17 @@ type IncomingRequestBuffer<A> = UnboundedReceiver<(A, u32, Prepare)>;
 pub struct BtpOutgoingService<O, A: Account> {
     ilp_address: Address,
     /// Outgoing messages for the receiver of the websocket indexed by account uid
-    connections: Arc<RwLock<HashMap<Uuid, UnboundedSender<Message>>>>,
+    connections: Arc<RwLock<HashMap<Uuid, Connection>>>,
+    active_connections: ActiveConnections,
+    connection_states: BtpConnections,
     pending_outgoing: Arc<Mutex<HashMap<u32, IlpResultChannel>>>,
     pending_incoming: Arc<Mutex<Option<IncomingRequestBuffer<A>>>>,
     incoming_sender: UnboundedSender<(A, u32, Prepare)>,
     next: O,
     close_all_connections: Arc<Mutex<Option<Trigger>>>,
     stream_valve: Arc<Valve>,
+    /// The largest message we accept, if fragmentation is enabled
+    max_message_size: Option<usize>,
 }
 
 /// Handle the packets based on whether they are an incoming request or a response to something we sent.
@@ -70,11 +115,14 @@ async fn handle_message<A: BtpAccount>(
     account: A,
     pending_requests: Arc<Mutex<HashMap<u32, IlpResultChannel>>>,
     incoming_sender: UnboundedSender<(A, u32, Prepare)>,
+    fragmentation: Option<Fragmentation>,
 ) {
     if message.is_binary() {
-        match parse_ilp_packet(message) {
+        match parse_ilp_packet(message, fragmentation.as_ref()) {
+            // A fragment of a packet or the negotiation of the fragmentation
+            Ok(None) => {}
             // Queues up the prepare packet
-            Ok((request_id, Packet::Prepare(prepare))) => {
+            Ok(Some((request_id, Packet::Prepare(prepare)))) => {
                 trace!(
                     "Got incoming Prepare packet on request ID: {} {:?}",
                     request_id,
@@ -85,7 +133,7 @@ async fn handle_message<A: BtpAccount>(
                     .map_err(|err| error!("Unable to buffer incoming request: {:?}", err));
             }
             // Sends the fulfill/reject to the outgoing service
-            Ok((request_id, Packet::Fulfill(fulfill))) => {
+            Ok(Some((request_id, Packet::Fulfill(fulfill)))) => {
                 trace!("Got fulfill response to request id {}", request_id);
                 if let Some(channel) = (*pending_requests.lock()).remove(&request_id) {
                     let _ = channel.send(Ok(fulfill)).map_err(|fulfill| error!("Error forwarding Fulfill packet back to the Future that sent the Prepare: {:?}", fulfill));
@@ -96,7 +144,7 @@ async fn handle_message<A: BtpAccount>(
                     );
                 }
             }
-            Ok((request_id, Packet::Reject(reject))) => {
+            Ok(Some((request_id, Packet::Reject(reject)))) => {
                 trace!("Got reject response to request id {}", request_id);
                 if let Some(channel) = (*pending_requests.lock()).remove(&request_id) {
                     let _ = channel.send(Err(reject)).map_err(|reject| error!("Error forwarding Reject packet back to the Future that sent the Prepare: {:?}", reject));
@@ -132,18 +180,53 @@ where
         BtpOutgoingService {
             ilp_address,
             connections: Arc::new(RwLock::new(HashMap::new())),
+            active_connections: Arc::new(Mutex::new(HashMap::new())),
+            connection_states: BtpConnections::default(),
             pending_outgoing: Arc::new(Mutex::new(HashMap::new())),
             pending_incoming: Arc::new(Mutex::new(Some(incoming_receiver))),
             incoming_sender,
             next,
             close_all_connections: Arc::new(Mutex::new(Some(close_all_connections))),
             stream_valve: Arc::new(stream_valve),
+            max_message_size: None,
         }
     }
 
+    /// Enables the fragmentation of ILP packets which are too large for the peers'
+    /// WebSocket messages, asking the peers to send messages of at most `max_message_size`
+    /// bytes. Packets are only fragmented on the connections with peers which enable it too.
+    /// Fragmentation stays disabled if `max_message_size` is 0.
+    pub fn with_fragmentation(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = Some(max_message_size).filter(|size| *size > 0);
+        self
+    }
+
+    /// The largest message we accept, if fragmentation is enabled
+    pub(crate) fn max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    /// The largest message the account's peer accepts, once it advertised it
+    #[cfg(test)]
+    pub(crate) fn peer_max_message_size(&self, account_id: &Uuid) -> Option<usize> {
+        self.connections
+            .read()
+            .get(account_id)?
+            .fragmentation
+            .as_ref()
+            .map(|fragmentation| fragmentation.peer_max_message_size.load(Ordering::Relaxed))
+            .filter(|max_message_size| *max_message_size > 0)
+    }
+
     /// Deletes the websocket associated with the provided `account_id`
     pub fn close_connection(&self, account_id: &Uuid) {
         self.connections.write().remove(account_id);
+        self.connection_states.set_disconnected(account_id);
+    }
+
+    /// Returns a handle to the states of the accounts' connections
+    pub fn connections(&self) -> BtpConnections {
+        self.connection_states.clone()
     }
 
     /// Close all of the open WebSocket connections
@@ -154,31 +237,78 @@ where
         self.close_all_connections.lock().take();
     }
 
+    /// Returns whether `close` was called, after which no more connections are accepted
+    pub fn is_closed(&self) -> bool {
+        self.close_all_connections.lock().is_none()
+    }
+
     // Set up a WebSocket connection so that outgoing Prepare packets can be sent to it,
     // incoming Prepare packets are buffered in a channel (until an IncomingService is added
     // via the handle_incoming method), and ILP Fulfill and Reject packets will be
     // sent back to the Future that sent the outgoing request originally.
+    // If the account already has a connection, the new one takes over and the old one is closed.
+    // The peer's max message size is passed if it advertised it during the authentication,
+    // otherwise it is picked up from its messages.
     pub(crate) fn add_connection(
         &self,
         account: A,
         ws_stream: impl Stream<Item = Message> + Sink<Message> + Send + 'static,
+        peer_max_message_size: Option<usize>,
     ) {
         let account_id = account.id();
+        let connection_id = Uuid::new_v4();
         // Set up a channel to forward outgoing packets to the WebSocket connection
         let (client_tx, client_rx) = unbounded();
         let (write, read) = ws_stream.split();
         let (close_connection, valve) = Valve::new();
+        let fragmentation = self.max_message_size.map(|_| {
+            let fragmentation = Fragmentation::default();
+            fragmentation
+                .peer_max_message_size
+                .store(peer_max_message_size.unwrap_or(0), Ordering::Relaxed);
+            fragmentation
+        });
+
+        // Save the sender side of the channel so we have a way to forward outgoing requests to the WebSocket
+        self.connections.write().insert(
+            account_id,
+            Connection {
+                sender: client_tx.clone(),
+                fragmentation: fragmentation.clone(),
+            },
+        );
+        // Dropping the trigger of the account's previous connection closes it
+        if let Some((previous_id, _)) = self
+            .active_connections
+            .lock()
+            .insert(account_id, (connection_id, close_connection))
+        {
+            debug!(
+                "Account {} reconnected, closing its previous connection {}",
+                account_id, previous_id
+            );
+        }
+        self.connection_states.set_connected(account_id, true);
 
         // tx -> rx -> write -> our peer
         // Responsible mainly for responding to Pings
+        let connections = self.connections.clone();
+        let active_connections = self.active_connections.clone();
+        let connection_states = self.connection_states.clone();
         let write_to_ws = client_rx.map(Ok).forward(write).then(move |_| {
             async move {
                 debug!(
                     "Finished forwarding to WebSocket stream for account: {}",
                     account_id
                 );
-                // When this is dropped, the read valve will close
-                drop(close_connection);
+                // When the trigger is dropped, the read valve will close
+                remove_connection(
+                    account_id,
+                    connection_id,
+                    &connections,
+                    &active_connections,
+                    &connection_states,
+                );
                 Ok::<(), ()>(())
             }
         });
@@ -188,31 +318,45 @@ where
         let pending_outgoing = self.pending_outgoing.clone();
         let incoming_sender = self.incoming_sender.clone();
         let client_tx_clone = client_tx.clone();
+        let connection_states = self.connection_states.clone();
         let handle_message_fn = move |msg: Message| {
+            connection_states.set_seen(&account_id);
             handle_message(
                 msg,
                 client_tx_clone.clone(),
                 account.clone(),
                 pending_outgoing.clone(),
                 incoming_sender.clone(),
+                fragmentation.clone(),
             )
         };
 
         // Close connections trigger
-        let read = valve.wrap(read); // close when `write_to_ws` calls `drop(connection)`
+        let read = valve.wrap(read); // close when the connection's trigger is dropped
         let read = self.stream_valve.wrap(read);
+        let connections = self.connections.clone();
+        let active_connections = self.active_connections.clone();
+        let connection_states = self.connection_states.clone();
         let read_from_ws = read.for_each(handle_message_fn).then(move |_| async move {
             debug!(
                 "Finished reading from WebSocket stream for account: {}",
                 account_id
             );
+            // Dropping the sender lets `write_to_ws` finish and close the connection
+            remove_connection(
+                account_id,
+                connection_id,
+                &connections,
+                &active_connections,
+                &connection_states,
+            );
             Ok::<(), ()>(())
         });
         tokio::spawn(read_from_ws);
 
-        // Send pings every PING_INTERVAL until the connection closes (when `drop(close_connection)` is called)
+        // Send pings every PING_INTERVAL until the connection closes (when its trigger is dropped)
         // or the Service is dropped (which will implicitly drop `close_all_connections`, closing the stream_valve)
-        let tx_clone = client_tx.clone();
+        let tx_clone = client_tx;
         let ping_interval = time::interval(Duration::from_secs(PING_INTERVAL));
         let repeat_until_service_drops = self.stream_valve.wrap(ping_interval);
         let send_pings = valve.wrap(repeat_until_service_drops).for_each(move |_| {
@@ -226,9 +370,6 @@ where
             future::ready(())
         });
         tokio::spawn(send_pings);
-
-        // Save the sender side of the channel so we have a way to forward outgoing requests to the WebSocket
-        self.connections.write().insert(account_id, client_tx);
     }
 
     /// Convert this BtpOutgoingService into a bidirectional BtpService by adding a handler for incoming requests.
@@ -267,14 +408,16 @@ where
                     Err(reject) => Packet::Reject(reject),
                 };
 
-                if let Some(connection) = connections_clone.clone().read().get(&account_id) {
-                    let message = ilp_packet_to_ws_message(request_id, packet);
-                    let _ = connection.unbounded_send(message).map_err(move |err| {
-                        error!(
-                            "Error sending response to account: {} {:?}",
-                            account_id, err
-                        )
-                    });
+                let connection = connections_clone.read().get(&account_id).cloned();
+                if let Some(connection) = connection {
+                    let _ = connection
+                        .send_packet(request_id, packet)
+                        .map_err(move |err| {
+                            error!(
+                                "Error sending response to account: {} {:?}",
+                                account_id, err
+                            )
+                        });
                 } else {
                     error!(
                         "Error sending response to account: {}, connection was closed. {:?}",
@@ -296,6 +439,26 @@ where
     }
 }
 
+/// Forgets the account's connection and marks the account as disconnected, unless
+/// another connection of the account has taken over in the meantime
+fn remove_connection(
+    account_id: Uuid,
+    connection_id: Uuid,
+    connections: &RwLock<HashMap<Uuid, Connection>>,
+    active_connections: &Mutex<HashMap<Uuid, (Uuid, Trigger)>>,
+    connection_states: &BtpConnections,
+) {
+    let mut active_connections = active_connections.lock();
+    if active_connections
+        .get(&account_id)
+        .map_or(false, |(active_id, _)| *active_id == connection_id)
+    {
+        active_connections.remove(&account_id);
+        connections.write().remove(&account_id);
+        connection_states.set_disconnected(&account_id);
+    }
+}
+
 #[async_trait]
 impl<O, A> OutgoingService<A> for BtpOutgoingService<O, A>
 where
@@ -304,8 +467,9 @@ where
 {
     /// Send an outgoing request to one of the open connections.
     ///
-    /// If there is no open connection for the Account specified in `request.to`, the
-    /// request will be passed through to the `next` handler.
+    /// If the connection for the Account specified in `request.to` is being re-established,
+    /// the request is rejected with `T01: Peer Unreachable`. If there is no open connection
+    /// for it, the request will be passed through to the `next` handler.
     async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
         let account_id = request.to.id();
 
@@ -328,10 +492,7 @@ where
 
             // Connection is an unbounded sender which sends to the rx that
             // forwards to the sink which sends the data over
-            match connection.unbounded_send(ilp_packet_to_ws_message(
-                request_id,
-                Packet::Prepare(request.prepare),
-            )) {
+            match connection.send_packet(request_id, Packet::Prepare(request.prepare)) {
                 Ok(_) => {
                     let (sender, receiver) = oneshot::channel();
                     (*self.pending_outgoing.lock()).insert(request_id, sender);
@@ -396,6 +557,18 @@ where
                     .build())
                 }
             }
+        } else if self.connection_states.is_reconnecting(&account_id) {
+            debug!(
+                "Connection to account {} is down, rejecting request until it is re-established",
+                request.to.username()
+            );
+            Err(RejectBuilder {
+                code: ErrorCode::T01_PEER_UNREACHABLE,
+                message: b"BTP connection is down",
+                triggered_by: Some(&self.ilp_address),
+                data: &[],
+            }
+            .build())
         } else {
             if request.to.get_ilp_over_btp_url().is_some()
                 || request.to.get_ilp_over_btp_outgoing_token().is_some()
@@ -430,6 +603,11 @@ where
     pub fn close_connection(&self, account_id: &Uuid) {
         self.outgoing.close_connection(account_id);
     }
+
+    /// Returns a handle to the states of the accounts' connections
+    pub fn connections(&self) -> BtpConnections {
+        self.outgoing.connections()
+    }
 }
 
 #[async_trait]
@@ -448,27 +626,18 @@ where
     }
 }
 
+/// Parses the ILP packet from the BTP packet, or returns `None` if the BTP packet only
+/// carries a fragment of it or the peer's max message size.
 #[allow(clippy::cognitive_complexity)]
-fn parse_ilp_packet(message: Message) -> Result<(u32, Packet), ()> {
+pub(crate) fn parse_ilp_packet(
+    message: Message,
+    fragmentation: Option<&Fragmentation>,
+) -> Result<Option<(u32, Packet)>, ()> {
     if let Message::Binary(data) = message {
-        let (request_id, ilp_data) = match BtpPacket::from_bytes(&data) {
-            Ok(BtpPacket::Message(message)) => {
-                let ilp_data = message
-                    .protocol_data
-                    .into_iter()
-                    .find(|proto| proto.protocol_name == "ilp")
-                    .ok_or(())?
-                    .data;
-                (message.request_id, ilp_data)
-            }
+        let (request_id, is_response, protocol_data) = match BtpPacket::from_bytes(&data) {
+            Ok(BtpPacket::Message(message)) => (message.request_id, false, message.protocol_data),
             Ok(BtpPacket::Response(response)) => {
-                let ilp_data = response
-                    .protocol_data
-                    .into_iter()
-                    .find(|proto| proto.protocol_name == "ilp")
-                    .ok_or(())?
-                    .data;
-                (response.request_id, ilp_data)
+                (response.request_id, true, response.protocol_data)
             }
             Ok(BtpPacket::Error(error)) => {
                 error!("Got BTP error: {:?}", error);
@@ -479,8 +648,51 @@ fn parse_ilp_packet(message: Message) -> Result<(u32, Packet), ()> {
                 return Err(());
             }
         };
-        if let Ok(packet) = Packet::try_from(BytesMut::from(ilp_data.as_slice())) {
-            Ok((request_id, packet))
+
+        let mut advertised_max_message_size = false;
+        if let Some(fragmentation) = fragmentation {
+            if let Some(max_message_size) = parse_max_message_size(&protocol_data) {
+                debug!(
+                    "Peer supports fragmentation with messages of up to {} bytes",
+                    max_message_size
+                );
+                fragmentation
+                    .peer_max_message_size
+                    .store(max_message_size, Ordering::Relaxed);
+                advertised_max_message_size = true;
+            }
+        }
+
+        let ilp_data = if let Some(proto) = find_protocol_data(&protocol_data, "ilp") {
+            proto.data.clone()
+        } else if let (Some(fragmentation), Some(proto)) = (
+            fragmentation,
+            find_protocol_data(&protocol_data, FRAGMENT_PROTOCOL),
+        ) {
+            let reassembled =
+                fragmentation
+                    .reassembler
+                    .lock()
+                    .push(request_id, is_response, &proto.data);
+            match reassembled {
+                Ok(Some(ilp_data)) => ilp_data,
+                Ok(None) => return Ok(None),
+                Err(err) => {
+                    warn!(
+                        "Dropping invalid fragment of request {}: {}",
+                        request_id, err
+                    );
+                    return Err(());
+                }
+            }
+        } else if advertised_max_message_size {
+            return Ok(None);
+        } else {
+            return Err(());
+        };
+
+        if let Ok(packet) = Packet::try_from(pool::copy_from_slice(&ilp_data)) {
+            Ok(Some((request_id, packet)))
         } else {
             Err(())
         }
@@ -490,32 +702,65 @@ fn parse_ilp_packet(message: Message) -> Result<(u32, Packet), ()> {
     }
 }
 
-fn ilp_packet_to_ws_message(request_id: u32, packet: Packet) -> Message {
+fn find_protocol_data<'a>(
+    protocol_data: &'a [ProtocolData],
+    name: &str,
+) -> Option<&'a ProtocolData> {
+    protocol_data
+        .iter()
+        .find(|proto| proto.protocol_name == name)
+}
+
+/// Serializes the packet into a WebSocket message, or, if it is too large for messages
+/// of `max_message_size` bytes, into one message per fragment
+pub(crate) fn ilp_packet_to_ws_messages(
+    request_id: u32,
+    packet: Packet,
+    max_message_size: Option<usize>,
+) -> Vec<Message> {
     let (data, is_response) = match packet {
-        Packet::Prepare(prepare) => (BytesMut::from(prepare).to_vec(), false),
-        Packet::Fulfill(fulfill) => (BytesMut::from(fulfill).to_vec(), true),
-        Packet::Reject(reject) => (BytesMut::from(reject).to_vec(), true),
+        // Copying out of the packet leaves its buffer to be recycled when it is dropped
+        Packet::Prepare(prepare) => (prepare.as_ref().to_vec(), false),
+        Packet::Fulfill(fulfill) => (fulfill.as_ref().to_vec(), true),
+        Packet::Reject(reject) => (reject.as_ref().to_vec(), true),
     };
-    let btp_packet = if is_response {
-        BtpMessage {
+    match max_message_size {
+        Some(max_message_size) if needs_fragmentation(data.len(), max_message_size) => {
+            fragment(&data, max_message_size)
+                .into_iter()
+                .map(|fragment| {
+                    Message::binary(btp_packet_bytes(request_id, is_response, vec![fragment]))
+                })
+                .collect()
+        }
+        _ => vec![Message::binary(btp_packet_bytes(
             request_id,
-            protocol_data: vec![ProtocolData {
+            is_response,
+            vec![ProtocolData {
                 protocol_name: "ilp".into(),
                 content_type: ContentType::ApplicationOctetStream,
                 data,
             }],
+        ))],
+    }
+}
+
+fn btp_packet_bytes(
+    request_id: u32,
+    is_response: bool,
+    protocol_data: Vec<ProtocolData>,
+) -> Vec<u8> {
+    if is_response {
+        BtpMessage {
+            request_id,
+            protocol_data,
         }
         .to_bytes()
     } else {
         BtpResponse {
             request_id,
-            protocol_data: vec![ProtocolData {
-                protocol_name: "ilp".into(),
-                content_type: ContentType::ApplicationOctetStream,
-                data,
-            }],
+            protocol_data,
         }
         .to_bytes()
-    };
-    Message::binary(btp_packet)
+    }
 }
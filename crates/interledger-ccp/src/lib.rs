@@ -11,6 +11,7 @@
 
 use async_trait::async_trait;
 use interledger_errors::CcpRoutingStoreError;
+use interledger_packet::matches_prefix;
 use interledger_service::Account;
 use std::collections::HashMap;
 use std::{fmt, str::FromStr};
@@ -25,7 +26,9 @@ mod server;
 mod test_helpers;
 
 pub use packet::{Mode, RouteControlRequest};
-pub use server::{CcpRouteManager, CcpRouteManagerBuilder};
+pub use server::{
+    CcpRouteManager, CcpRouteManagerBuilder, RouteExpiries, DEFAULT_BROADCAST_INTERVAL,
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -103,6 +106,38 @@ pub trait CcpRoutingAccount: Account {
         self.routing_relation() == RoutingRelation::Parent
             || self.routing_relation() == RoutingRelation::Peer
     }
+
+    /// Prefixes of the routes accepted from this account. All routes are accepted if empty
+    fn accept_route_prefixes(&self) -> &[String] {
+        &[]
+    }
+
+    /// Prefixes of the routes rejected from this account, even if they are also accepted
+    fn reject_route_prefixes(&self) -> &[String] {
+        &[]
+    }
+
+    /// The maximum number of routes accepted from this account, after which the routes
+    /// for new prefixes are ignored until the account withdraws some
+    fn max_routes(&self) -> Option<u32> {
+        None
+    }
+
+    /// Prefixes under which the routes learned from this account are re-advertised as a
+    /// single route for the prefix, instead of one route for each of them
+    fn aggregate_route_prefixes(&self) -> &[String] {
+        &[]
+    }
+
+    /// Indicates whether the route for the prefix passes this account's route filters
+    fn accepts_route(&self, prefix: &str) -> bool {
+        let accepted = self.accept_route_prefixes();
+        (accepted.is_empty() || accepted.iter().any(|p| matches_prefix(prefix, p)))
+            && !self
+                .reject_route_prefixes()
+                .iter()
+                .any(|p| matches_prefix(prefix, p))
+    }
 }
 
 // key = Bytes, key should be Address -- TODO
@@ -1,4 +1,4 @@
-use bytes::{Buf, BufMut, Bytes};
+use bytes::{BufMut, Bytes};
 use interledger_packet::{
     hex::HexString,
     oer::{self, BufOerExt, MutBufOerExt},
@@ -26,11 +26,15 @@ const FLAG_UTF8: u8 = 0x10;
 
 const ROUTING_TABLE_ID_LEN: usize = 16;
 
-/// All epochs are u32
-const EPOCH_LEN: usize = 4;
-
 const AUTH_LEN: usize = 32;
 
+fn read_routing_table_id(data: &mut &[u8]) -> Result<[u8; ROUTING_TABLE_ID_LEN], OerError> {
+    Ok(
+        <[u8; ROUTING_TABLE_ID_LEN]>::try_from(data.read_octets(ROUTING_TABLE_ID_LEN)?)
+            .expect("read_octets returns exactly ROUTING_TABLE_ID_LEN bytes"),
+    )
+}
+
 pub static CCP_RESPONSE: Lazy<Fulfill> = Lazy::new(|| {
     FulfillBuilder {
         fulfillment: &PEER_PROTOCOL_FULFILLMENT,
@@ -106,11 +110,6 @@ pub enum Mode {
     Sync = 1,
 }
 
-impl Mode {
-    /// Length of Mode on the wire (bytes)
-    const LEN: usize = 1;
-}
-
 impl TryFrom<u8> for Mode {
     type Error = CcpPacketError;
 
@@ -189,24 +188,12 @@ impl RouteControlRequest {
     }
 
     fn try_from_data(mut data: &[u8]) -> Result<Self, CcpPacketError> {
-        const MIN_LEN: usize = Mode::LEN
-            + ROUTING_TABLE_ID_LEN
-            // u32
-            + EPOCH_LEN
-            // zero features would be the minimum
-            + oer::MIN_VARUINT_LEN;
-
-        if data.remaining() < MIN_LEN {
-            return Err(OerError::UnexpectedEof.into());
-        }
-        let mode = Mode::try_from(data.get_u8())?;
-        let mut last_known_routing_table_id = [0; ROUTING_TABLE_ID_LEN];
-        data.copy_to_slice(&mut last_known_routing_table_id);
-        let last_known_epoch = data.get_u32();
-
-        // TODO: see discussion for Route::try_from(&mut &[u8])
-        let num_features = data.read_var_uint()?;
-        let mut features: Vec<String> = Vec::new();
+        let mode = Mode::try_from(data.read_u8()?)?;
+        let last_known_routing_table_id = read_routing_table_id(&mut data)?;
+        let last_known_epoch = data.read_u32()?;
+
+        let num_features = data.read_sequence_len(oer::EMPTY_VARLEN_OCTETS_LEN)?;
+        let mut features: Vec<String> = Vec::with_capacity(num_features);
         for _i in 0..num_features {
             features.push(str::from_utf8(data.read_var_octet_string()?)?.to_owned());
         }
@@ -262,23 +249,14 @@ impl TryFrom<&mut &[u8]> for RouteProp {
 
     // Note this takes a mutable ref to the slice so that it advances the cursor in the original slice
     fn try_from(data: &mut &[u8]) -> Result<Self, Self::Error> {
-        const FLAG_LEN: usize = 1;
-        const ID_LEN: usize = 2;
-
-        const MIN_LEN: usize = FLAG_LEN + ID_LEN + oer::EMPTY_VARLEN_OCTETS_LEN;
-
-        if data.remaining() < MIN_LEN {
-            return Err(OerError::UnexpectedEof.into());
-        }
-
-        let meta = data.get_u8();
+        let meta = data.read_u8()?;
 
         let is_optional = meta & FLAG_OPTIONAL != 0;
         let is_transitive = meta & FLAG_TRANSITIVE != 0;
         let is_partial = meta & FLAG_PARTIAL != 0;
         let is_utf8 = meta & FLAG_UTF8 != 0;
 
-        let id = data.get_u16();
+        let id = data.read_u16()?;
         let value = Bytes::copy_from_slice(data.read_var_octet_string()?);
 
         Ok(RouteProp {
@@ -293,6 +271,9 @@ impl TryFrom<&mut &[u8]> for RouteProp {
 }
 
 impl RouteProp {
+    /// Length of a RouteProp with an empty value on the wire (flags, id and value)
+    const MIN_LEN: usize = 1 + 2 + oer::EMPTY_VARLEN_OCTETS_LEN;
+
     pub fn write_to<B>(&self, buf: &mut B)
     where
         B: BufMut,
@@ -344,28 +325,18 @@ impl TryFrom<&mut &[u8]> for Route {
     fn try_from(data: &mut &[u8]) -> Result<Self, Self::Error> {
         let prefix = str::from_utf8(data.read_var_octet_string()?)?.to_string();
 
-        // FIXME: this is prone to pre-allocating a lot (see fuzzed test cases), like every
-        // instance of reading a `length` as var_uint and following it up with a preallocation in
-        // this crate. Other crates such as interledger-stream gets away from this by having an
-        // iterator which just iterates the values starting from the specific offset and `length`
-        // is checked against the number of elements iterator found. interledger-btp use just the
-        // default growing of the vec (unideal) and check against trailing bytes (missing here).
-        //
-        // Using the unideal version (growing vec) for now.
-        let path_len = data.read_var_uint()? as usize;
-        let mut path: Vec<String> = Vec::new();
+        // The lengths are checked against the rest of the data before preallocating, so a
+        // bogus length cannot make us allocate more than the data could possibly hold
+        let path_len = data.read_sequence_len(oer::EMPTY_VARLEN_OCTETS_LEN)?;
+        let mut path: Vec<String> = Vec::with_capacity(path_len);
         for _i in 0..path_len {
             path.push(str::from_utf8(data.read_var_octet_string()?)?.to_string());
         }
-        if data.remaining() < AUTH_LEN {
-            return Err(OerError::UnexpectedEof.into());
-        }
-        let mut auth = [0u8; AUTH_LEN];
-        data.copy_to_slice(&mut auth);
+        let auth = <[u8; AUTH_LEN]>::try_from(data.read_octets(AUTH_LEN)?)
+            .expect("read_octets returns exactly AUTH_LEN bytes");
 
-        // TODO: see discussion above
-        let prop_len = data.read_var_uint()? as usize;
-        let mut props = Vec::new();
+        let prop_len = data.read_sequence_len(RouteProp::MIN_LEN)?;
+        let mut props = Vec::with_capacity(prop_len);
         for _i in 0..prop_len {
             // For some reason we need to cast `data to `&mut &[u8]` again, otherwise
             // error[E0382]: use of moved value: `data`
@@ -386,6 +357,10 @@ impl TryFrom<&mut &[u8]> for Route {
 }
 
 impl Route {
+    /// Length of a Route with an empty prefix, path and props on the wire
+    const MIN_LEN: usize =
+        oer::EMPTY_VARLEN_OCTETS_LEN + oer::MIN_VARUINT_LEN + AUTH_LEN + oer::MIN_VARUINT_LEN;
+
     pub fn write_to<B>(&self, buf: &mut B)
     where
         B: BufMut,
@@ -471,39 +446,21 @@ impl RouteUpdateRequest {
     }
 
     fn try_from_data(mut data: &[u8]) -> Result<Self, CcpPacketError> {
-        const HOLD_DOWN_TIME_LEN: usize = 4;
-
-        const MIN_LEN: usize = ROUTING_TABLE_ID_LEN
-            // current_epoch_index, from_epoch_index, to_epoch_index
-            + EPOCH_LEN * 3
-            // hold_down_time
-            + HOLD_DOWN_TIME_LEN
-            + Address::MIN_LEN
-            + interledger_packet::oer::MIN_VARUINT_LEN
-            + interledger_packet::oer::MIN_VARUINT_LEN;
-
-        if data.remaining() < MIN_LEN {
-            return Err(OerError::UnexpectedEof.into());
-        }
-
-        let mut routing_table_id = [0u8; ROUTING_TABLE_ID_LEN];
-        data.copy_to_slice(&mut routing_table_id);
-        let current_epoch_index = data.get_u32();
-        let from_epoch_index = data.get_u32();
-        let to_epoch_index = data.get_u32();
-        let hold_down_time = data.get_u32();
+        let routing_table_id = read_routing_table_id(&mut data)?;
+        let current_epoch_index = data.read_u32()?;
+        let from_epoch_index = data.read_u32()?;
+        let to_epoch_index = data.read_u32()?;
+        let hold_down_time = data.read_u32()?;
         let speaker = Address::try_from(data.read_var_octet_string()?)?;
 
-        // TODO: see discussion for Route::try_from(&mut &[u8])
-        let new_routes_len = data.read_var_uint()? as usize;
-        let mut new_routes: Vec<Route> = Vec::new();
+        let new_routes_len = data.read_sequence_len(Route::MIN_LEN)?;
+        let mut new_routes: Vec<Route> = Vec::with_capacity(new_routes_len);
         for _i in 0..new_routes_len {
             new_routes.push(Route::try_from(&mut data)?);
         }
 
-        // TODO: see discussion for Route::try_from(&mut &[u8])
-        let withdrawn_routes_len = data.read_var_uint()? as usize;
-        let mut withdrawn_routes = Vec::new();
+        let withdrawn_routes_len = data.read_sequence_len(oer::EMPTY_VARLEN_OCTETS_LEN)?;
+        let mut withdrawn_routes = Vec::with_capacity(withdrawn_routes_len);
         for _i in 0..withdrawn_routes_len {
             withdrawn_routes.push(str::from_utf8(data.read_var_octet_string()?)?.to_string());
         }
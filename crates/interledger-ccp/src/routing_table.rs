@@ -3,7 +3,8 @@ use interledger_packet::hex::HexString;
 use once_cell::sync::Lazy;
 use ring::rand::{SecureRandom, SystemRandom};
 use std::collections::HashMap;
-use tracing::{debug, trace};
+use std::time::{Duration, Instant};
+use tracing::{debug, trace, warn};
 
 static RANDOM: Lazy<SystemRandom> = Lazy::new(SystemRandom::new);
 
@@ -46,6 +47,11 @@ pub struct RoutingTable<A> {
     id: [u8; 16],
     epoch: u32,
     prefix_map: PrefixMap<(A, Route)>,
+    /// When each route learned from a peer expires, unless the peer sends another update.
+    /// Routes without an expiry (such as the ones in our own tables) never expire
+    expiries: HashMap<String, Instant>,
+    /// The routes for new prefixes are ignored once the table has this many
+    max_routes: Option<usize>,
 }
 
 impl<A> RoutingTable<A>
@@ -57,6 +63,8 @@ where
             id,
             epoch: 0,
             prefix_map: PrefixMap::new(),
+            expiries: HashMap::new(),
+            max_routes: None,
         }
     }
 
@@ -79,6 +87,16 @@ where
         self.epoch
     }
 
+    /// Limits the number of routes the update requests may add to the table
+    pub(crate) fn set_max_routes(&mut self, max_routes: Option<usize>) {
+        self.max_routes = max_routes;
+    }
+
+    /// Whether the table has a route for exactly this prefix
+    pub(crate) fn has_route(&self, prefix: &str) -> bool {
+        self.prefix_map.map.contains_key(prefix)
+    }
+
     pub(crate) fn increment_epoch(&mut self) -> u32 {
         let epoch = self.epoch;
         self.epoch += 1;
@@ -93,6 +111,7 @@ where
 
     /// Remove the route for the given prefix. Returns true if that route existed before
     pub(crate) fn delete_route(&mut self, prefix: &str) -> bool {
+        self.expiries.remove(prefix);
         self.prefix_map.remove(prefix)
     }
 
@@ -107,6 +126,16 @@ where
         self.prefix_map.resolve(prefix)
     }
 
+    /// Iterates over the prefixes in the table and their routes
+    pub(crate) fn routes(&self) -> impl Iterator<Item = (&String, &(A, Route))> {
+        self.prefix_map.map.iter()
+    }
+
+    /// Get when the route for the given prefix expires, if it was learned from a peer
+    pub(crate) fn get_expiry(&self, prefix: &str) -> Option<Instant> {
+        self.expiries.get(prefix).cloned()
+    }
+
     pub(crate) fn get_simplified_table(&self) -> HashMap<String, A> {
         self.prefix_map
             .map
@@ -171,12 +200,34 @@ where
             }
         }
 
+        let mut ignored_routes = 0;
         for route in request.new_routes.into_iter() {
             let prefix = route.prefix.clone();
+            let table_full = self
+                .max_routes
+                .map(|max_routes| self.prefix_map.map.len() >= max_routes)
+                .unwrap_or(false);
+            if table_full && !self.has_route(&prefix) {
+                ignored_routes += 1;
+                continue;
+            }
             if self.add_route(account.clone(), route) {
                 changed_prefixes.push(prefix);
             }
         }
+        if ignored_routes > 0 {
+            warn!(
+                "Ignored {} routes in table {:?} which already has the maximum number of routes",
+                ignored_routes,
+                HexString(&self.id[..]),
+            );
+        }
+
+        // Every update (including heartbeats) confirms that the peer's whole table is still valid
+        let expires_at = Instant::now() + Duration::from_millis(u64::from(request.hold_down_time));
+        for prefix in self.prefix_map.map.keys() {
+            self.expiries.insert(prefix.clone(), expires_at);
+        }
 
         trace!(
             "Updated routing table {:?} to epoch: {}",
@@ -186,6 +237,32 @@ where
 
         Ok(changed_prefixes)
     }
+
+    /// Remove the routes which expired before `now` and return their prefixes.
+    ///
+    /// If any routes were removed, the epoch is reset so that the next update from the
+    /// peer is treated as a gap, which makes us request its whole table again.
+    pub(crate) fn remove_expired_routes(&mut self, now: Instant) -> Vec<String> {
+        let expired: Vec<String> = self
+            .expiries
+            .iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(prefix, _)| prefix.clone())
+            .collect();
+        for prefix in expired.iter() {
+            self.delete_route(prefix);
+        }
+
+        if !expired.is_empty() {
+            debug!(
+                "Routes in table {:?} expired: {}",
+                HexString(&self.id[..]),
+                expired.join(", ")
+            );
+            self.epoch = 0;
+        }
+        expired
+    }
 }
 
 impl<A> Default for RoutingTable<A>
@@ -306,6 +383,45 @@ mod table {
         assert_eq!(updated_routes.len(), 0);
     }
 
+    #[test]
+    fn expires_routes_after_hold_down_time() {
+        let mut table = RoutingTable::new(UPDATE_REQUEST_COMPLEX.routing_table_id);
+        let mut request = UPDATE_REQUEST_COMPLEX.clone();
+        request.from_epoch_index = 0;
+        request.to_epoch_index = 1;
+        request.hold_down_time = 30000;
+        table
+            .handle_update_request(ROUTING_ACCOUNT.clone(), request)
+            .unwrap();
+
+        assert!(table.remove_expired_routes(Instant::now()).is_empty());
+        assert_eq!(table.epoch, 1);
+
+        let mut expired =
+            table.remove_expired_routes(Instant::now() + Duration::from_millis(30001));
+        expired.sort();
+        assert_eq!(expired, vec!["example.prefix1", "example.prefix2"]);
+        assert!(table.get_route("example.prefix1").is_none());
+        assert!(table.expiries.is_empty());
+        assert_eq!(table.epoch, 0);
+    }
+
+    #[test]
+    fn ignores_routes_for_new_prefixes_over_the_limit() {
+        let mut table = RoutingTable::new(UPDATE_REQUEST_COMPLEX.routing_table_id);
+        table.set_max_routes(Some(1));
+        let mut request = UPDATE_REQUEST_COMPLEX.clone();
+        request.from_epoch_index = 0;
+        request.to_epoch_index = 1;
+        let updated_routes = table
+            .handle_update_request(ROUTING_ACCOUNT.clone(), request)
+            .unwrap();
+        assert_eq!(updated_routes, vec!["example.prefix1"]);
+        assert!(table.has_route("example.prefix1"));
+        assert!(!table.has_route("example.prefix2"));
+        assert_eq!(table.epoch, 1);
+    }
+
     #[test]
     fn converts_to_a_simplified_table() {
         let mut table = RoutingTable::new([0; 16]);
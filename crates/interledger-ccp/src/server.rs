@@ -7,27 +7,31 @@ use crate::{
     CcpRoutingAccount, CcpRoutingStore, RoutingRelation,
 };
 use async_trait::async_trait;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use futures::future::join_all;
 use interledger_errors::CcpRoutingStoreError;
-use interledger_packet::{hex::HexString, Address, ErrorCode, RejectBuilder};
+use interledger_packet::{
+    hex::HexString, matches_prefix, prefixes, Address, ErrorCode, RejectBuilder,
+};
 use interledger_service::{
     Account, AddressStore, IlpResult, IncomingRequest, IncomingService, OutgoingRequest,
-    OutgoingService,
+    OutgoingService, Username,
 };
 use parking_lot::{Mutex, RwLock};
 use ring::digest::{digest, SHA256};
 use std::cmp::Ordering as StdOrdering;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::{
     cmp::min,
     convert::TryFrom,
     str,
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicBool, AtomicU32, Ordering},
         Arc,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
+use tokio::sync::watch;
 use tracing::{debug, error, trace, warn};
 use uuid::Uuid;
 
@@ -43,7 +47,11 @@ use once_cell::sync::Lazy;
 // otherwise. we could make it longer and make sure the BTP server
 // comes after the expiry shortener
 const DEFAULT_ROUTE_EXPIRY_TIME: u32 = 30000;
-const DEFAULT_BROADCAST_INTERVAL: u64 = 30000;
+/// The interval (in milliseconds) on which routes are broadcast unless another one is set
+pub const DEFAULT_BROADCAST_INTERVAL: u64 = 30000;
+/// How often (in milliseconds) we check for routes whose peers stopped sending us updates
+#[cfg(not(test))]
+const ROUTE_EXPIRY_CHECK_INTERVAL: u64 = 1000;
 const DUMMY_ROUTING_TABLE_ID: [u8; 16] = [0; 16];
 
 fn hash(preimage: &[u8; 32]) -> [u8; 32] {
@@ -54,6 +62,16 @@ fn hash(preimage: &[u8; 32]) -> [u8; 32] {
 
 type NewAndWithdrawnRoutes = (Vec<Route>, Vec<String>);
 
+/// The time (in milliseconds) our peers should keep our routes for without hearing from us.
+/// This is twice the broadcast interval, so that a single lost heartbeat does not make
+/// our peers withdraw the routes, and never less than the default route expiry time.
+fn hold_down_time(broadcast_interval: u64) -> u32 {
+    let hold_down_time = broadcast_interval.saturating_mul(2);
+    hold_down_time
+        .max(u64::from(DEFAULT_ROUTE_EXPIRY_TIME))
+        .min(u64::from(u32::MAX)) as u32
+}
+
 /// Builder for [CcpRouteManager](./CcpRouteManager.html)
 /// See documentation on fields for more details.
 pub struct CcpRouteManagerBuilder<I, O, S> {
@@ -68,6 +86,8 @@ pub struct CcpRouteManagerBuilder<I, O, S> {
     store: S,
     ilp_address: Address,
     broadcast_interval: u64,
+    broadcast_interval_updates: Option<watch::Receiver<u64>>,
+    broadcast_enabled: Arc<AtomicBool>,
 }
 
 impl<I, O, S, A> CcpRouteManagerBuilder<I, O, S>
@@ -84,6 +104,8 @@ where
             outgoing,
             store,
             broadcast_interval: DEFAULT_BROADCAST_INTERVAL,
+            broadcast_interval_updates: None,
+            broadcast_enabled: Arc::new(AtomicBool::new(true)),
         }
     }
 
@@ -98,7 +120,25 @@ where
         self
     }
 
+    /// Set the channel through which the broadcast interval (in milliseconds) is changed while
+    /// the service is running. Its current value takes precedence over `broadcast_interval`
+    pub fn broadcast_interval_updates(&mut self, updates: watch::Receiver<u64>) -> &mut Self {
+        self.broadcast_interval_updates = Some(updates);
+        self
+    }
+
+    /// Set the flag which pauses the route broadcasts while it is false, e.g. so that
+    /// they can be stopped and resumed while the node is running
+    pub fn broadcast_enabled(&mut self, enabled: Arc<AtomicBool>) -> &mut Self {
+        self.broadcast_enabled = enabled;
+        self
+    }
+
     pub fn to_service(&self) -> CcpRouteManager<I, O, S, A> {
+        let broadcast_interval = match self.broadcast_interval_updates {
+            Some(ref updates) => *updates.borrow(),
+            None => self.broadcast_interval,
+        };
         #[allow(clippy::let_and_return)]
         let service = CcpRouteManager {
             ilp_address: Arc::new(RwLock::new(self.ilp_address.clone())),
@@ -111,15 +151,28 @@ where
             local_table: Arc::new(RwLock::new(RoutingTable::default())),
             incoming_tables: Arc::new(RwLock::new(HashMap::new())),
             unavailable_accounts: Arc::new(Mutex::new(HashMap::new())),
+            hold_down_time: Arc::new(AtomicU32::new(hold_down_time(broadcast_interval))),
+            broadcast_enabled: self.broadcast_enabled.clone(),
+            route_flap_listeners: Arc::new(Mutex::new(Vec::new())),
+            unhealthy_peers: Arc::new(RwLock::new(HashSet::new())),
         };
 
         #[cfg(not(test))]
         {
-            let broadcast_interval = self.broadcast_interval;
+            let broadcast_intervals = self
+                .broadcast_interval_updates
+                .clone()
+                .unwrap_or_else(|| watch::channel(broadcast_interval).1);
             let service_clone = service.clone();
             tokio::spawn(async move {
                 service_clone
-                    .start_broadcast_interval(broadcast_interval)
+                    .start_reloadable_broadcast_interval(broadcast_intervals)
+                    .await
+            });
+            let service_clone = service.clone();
+            tokio::spawn(async move {
+                service_clone
+                    .start_route_expiry_interval(ROUTE_EXPIRY_CHECK_INTERVAL)
                     .await
             });
         }
@@ -140,6 +193,19 @@ struct BackoffParams {
 
 /// The Routing Manager Service.
 ///
+/// Reports when the routes learned from peers expire, for example to show them in the
+/// node's API. The expiries are read from the route manager's tables on each call.
+#[derive(Clone)]
+pub struct RouteExpiries(Arc<dyn Fn() -> HashMap<String, Instant> + Send + Sync>);
+
+impl RouteExpiries {
+    /// Returns when each of the routes in use which were learned from peers expires,
+    /// unless the peer advertising it sends another update before then
+    pub fn get(&self) -> HashMap<String, Instant> {
+        (self.0)()
+    }
+}
+
 /// This implements the Connector-to-Connector Protocol (CCP)
 /// for exchanging route updates with peers. This service handles incoming CCP messages
 /// and sends updates to peers. It manages the routing table in the Store and updates it
@@ -175,6 +241,15 @@ pub struct CcpRouteManager<I, O, S, A: Account> {
     /// This maps the account ID to the number of route brodcast intervals
     /// we should wait before trying again
     unavailable_accounts: Arc<Mutex<HashMap<Uuid, BackoffParams>>>,
+    /// The hold down time (in milliseconds) included in the Route Update Requests we send,
+    /// which follows the broadcast interval
+    hold_down_time: Arc<AtomicU32>,
+    /// Route updates are only broadcast while this is true
+    broadcast_enabled: Arc<AtomicBool>,
+    /// Notified with the username of the peer whenever a peer withdraws routes
+    route_flap_listeners: Arc<Mutex<Vec<UnboundedSender<Username>>>>,
+    /// The accounts whose advertised routes are not used while their peers are unhealthy
+    unhealthy_peers: Arc<RwLock<HashSet<Uuid>>>,
 }
 
 impl<I, O, S, A> CcpRouteManager<I, O, S, A>
@@ -184,17 +259,146 @@ where
     S: AddressStore + CcpRoutingStore<Account = A> + Clone + Send + Sync + 'static,
     A: CcpRoutingAccount + Send + Sync + 'static,
 {
+    /// Returns a stream of the usernames of the peers which withdraw routes they advertised,
+    /// notified once for each Route Update Request withdrawing routes
+    pub fn route_flaps(&self) -> UnboundedReceiver<Username> {
+        let (sender, receiver) = unbounded();
+        self.route_flap_listeners.lock().push(sender);
+        receiver
+    }
+
+    /// Returns a handle reporting when the routes learned from peers expire
+    pub fn route_expiries(&self) -> RouteExpiries {
+        let local_table = self.local_table.clone();
+        let incoming_tables = self.incoming_tables.clone();
+        RouteExpiries(Arc::new(move || {
+            let local_table = local_table.read();
+            let incoming_tables = incoming_tables.read();
+            local_table
+                .get_simplified_table()
+                .into_iter()
+                .filter_map(|(prefix, account)| {
+                    let expires_at = incoming_tables.get(&account.id())?.get_expiry(&prefix)?;
+                    Some((prefix, expires_at))
+                })
+                .collect()
+        }))
+    }
+
+    /// Stops using the routes the peer advertised while it is unhealthy (for example, while
+    /// it is unreachable), or uses them again once it recovers, and updates the best routes
+    /// for the prefixes it advertised. Configured and local routes are not affected
+    pub async fn set_peer_healthy(
+        &self,
+        account_id: Uuid,
+        healthy: bool,
+    ) -> Result<(), CcpRoutingStoreError> {
+        let changed = if healthy {
+            self.unhealthy_peers.write().remove(&account_id)
+        } else {
+            self.unhealthy_peers.write().insert(account_id)
+        };
+        if !changed {
+            return Ok(());
+        }
+        let prefixes: Vec<String> = match self.incoming_tables.read().get(&account_id) {
+            Some(table) => table.get_simplified_table().keys().cloned().collect(),
+            None => return Ok(()),
+        };
+        if healthy {
+            debug!("Peer {} recovered, using its routes again", account_id);
+        } else {
+            warn!(
+                "Peer {} is unhealthy, withdrawing its routes for prefixes: {}",
+                account_id,
+                prefixes.join(", ")
+            );
+        }
+        if prefixes.is_empty() {
+            Ok(())
+        } else {
+            self.update_best_routes(Some(prefixes)).await
+        }
+    }
+
     /// Returns a future that will trigger this service to update its routes and broadcast
     /// updates to peers on the given interval. `interval` is in milliseconds
     pub async fn start_broadcast_interval(&self, interval: u64) {
+        self.start_reloadable_broadcast_interval(watch::channel(interval).1)
+            .await
+    }
+
+    /// Like `start_broadcast_interval`, but the interval (in milliseconds) is read from the
+    /// channel, so that it can be changed while the service is running. The hold down time
+    /// advertised to our peers is adjusted along with it
+    pub async fn start_reloadable_broadcast_interval(&self, mut updates: watch::Receiver<u64>) {
+        // The interval may have changed since the service was created
+        let mut current = *updates.borrow();
+        self.hold_down_time
+            .store(hold_down_time(current), Ordering::Relaxed);
         self.request_all_routes().await;
+        let mut interval = tokio::time::interval(Duration::from_millis(current));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if self.broadcast_enabled.load(Ordering::Relaxed) {
+                        // ensure we have the latest ILP Address from the store
+                        self.update_ilp_address();
+                        // Do not consume the result if an error since we want to keep the loop going
+                        let _ = self.broadcast_routes().await;
+                    }
+                }
+                Some(updated) = updates.recv() => {
+                    if updated != current {
+                        debug!("Route broadcast interval changed from {}ms to {}ms", current, updated);
+                        current = updated;
+                        self.hold_down_time.store(hold_down_time(current), Ordering::Relaxed);
+                        let period = Duration::from_millis(current);
+                        interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns a future that will withdraw the routes whose peers have stopped sending
+    /// us route updates, checking for them on the given interval (in milliseconds)
+    pub async fn start_route_expiry_interval(&self, interval: u64) {
         let mut interval = tokio::time::interval(Duration::from_millis(interval));
         loop {
             interval.tick().await;
-            // ensure we have the latest ILP Address from the store
-            self.update_ilp_address();
             // Do not consume the result if an error since we want to keep the loop going
-            let _ = self.broadcast_routes().await;
+            let _ = self.withdraw_expired_routes(Instant::now()).await;
+        }
+    }
+
+    /// Remove the routes which expired before `now` from the peers' incoming tables and
+    /// update our best routes for those prefixes, so that traffic stops flowing to peers
+    /// which are no longer sending us route updates
+    async fn withdraw_expired_routes(&self, now: Instant) -> Result<(), CcpRoutingStoreError> {
+        let expired_prefixes: Vec<String> = {
+            let mut incoming_tables = self.incoming_tables.write();
+            let mut expired_prefixes = Vec::new();
+            for (account_id, table) in incoming_tables.iter_mut() {
+                let expired = table.remove_expired_routes(now);
+                if !expired.is_empty() {
+                    warn!(
+                        "Account {} stopped sending route updates, withdrawing its routes for prefixes: {}",
+                        account_id,
+                        expired.join(", ")
+                    );
+                    expired_prefixes.extend(expired);
+                }
+            }
+            expired_prefixes.sort();
+            expired_prefixes.dedup();
+            expired_prefixes
+        };
+
+        if expired_prefixes.is_empty() {
+            Ok(())
+        } else {
+            self.update_best_routes(Some(expired_prefixes)).await
         }
     }
 
@@ -320,14 +524,13 @@ where
             .filter(|route| {
                 let ilp_address = self.ilp_address.read();
                 let address_scheme = (*ilp_address).scheme();
-                if !route.prefix.starts_with(address_scheme) {
+                if !matches_prefix(&route.prefix, address_scheme) {
                     warn!("Got route for a different global prefix: {:?}", route);
                     false
-                } else if route.prefix.len() <= address_scheme.len() + 1 {
-                    // note the + 1 is due to address_scheme not including a trailing "."
+                } else if route.prefix.trim_end_matches('.') == address_scheme {
                     warn!("Got route broadcast for the global prefix: {:?}", route);
                     false
-                } else if route.prefix.starts_with(&ilp_address as &str) {
+                } else if matches_prefix(&route.prefix, &ilp_address) {
                     trace!("Ignoring route broadcast for a prefix that starts with our own address: {:?}", route);
                     false
                 } else if route.path.iter().any(|p| p == &ilp_address as &str) {
@@ -378,7 +581,24 @@ where
         );
 
         // Filter out routes that don't make sense or that we won't accept
-        let update = self.filter_routes(update);
+        let mut update = self.filter_routes(update);
+        update.new_routes.retain(|route| {
+            let accepted = request.from.accepts_route(&route.prefix);
+            if !accepted {
+                debug!(
+                    "Ignoring route broadcast rejected by the route policy of account {}: {:?}",
+                    request.from.id(),
+                    route
+                );
+            }
+            accepted
+        });
+        if !update.withdrawn_routes.is_empty() {
+            let username = request.from.username();
+            self.route_flap_listeners
+                .lock()
+                .retain(|listener| listener.unbounded_send(username.clone()).is_ok());
+        }
 
         // Ensure the mutex gets dropped before the async block
         let result = {
@@ -389,10 +609,12 @@ where
                     RoutingTable::new(update.routing_table_id),
                 );
             }
-            incoming_tables
+            let table = incoming_tables
                 .get_mut(&request.from.id())
-                .expect("Should have inserted a routing table for this account")
-                .handle_update_request(request.from.clone(), update)
+                .expect("Should have inserted a routing table for this account");
+            // The policy may have changed since the last update
+            table.set_max_routes(request.from.max_routes().map(|max| max as usize));
+            table.handle_update_request(request.from.clone(), update)
         };
 
         // Update the routing table we maintain for the account we got this from.
@@ -526,6 +748,7 @@ where
         let forwarding_table = self.forwarding_table.clone();
         let forwarding_table_updates = self.forwarding_table_updates.clone();
         let incoming_tables = self.incoming_tables.clone();
+        let unhealthy_peers = self.unhealthy_peers.clone();
         let ilp_address = self.ilp_address.read().clone();
         let mut store = self.store.clone();
 
@@ -537,6 +760,7 @@ where
             // Note we only use a read lock here and later get a write lock if we need to update the table
             let local_table = local_table.read();
             let incoming_tables = incoming_tables.read();
+            let unhealthy_peers = unhealthy_peers.read();
 
             // Either check the given prefixes or check all of our local and configured routes
             let prefixes_to_check: Box<dyn Iterator<Item = &str>> =
@@ -558,6 +782,7 @@ where
                     &local_routes,
                     &configured_routes,
                     &incoming_tables,
+                    &unhealthy_peers,
                     prefix,
                 ) {
                     if let Some((ref next_account, ref _route)) = local_table.get_route(prefix) {
@@ -589,8 +814,11 @@ where
                 let mut forwarding_table_updates = forwarding_table_updates.write();
 
                 let mut new_routes: Vec<Route> = Vec::with_capacity(better_routes.len());
+                // Routes withdrawn from the forwarding table only, because they are now
+                // advertised as part of an aggregate route
+                let mut withdrawn_advertisements: Vec<String> = Vec::new();
 
-                for (prefix, account, mut route) in better_routes {
+                for (prefix, account, route) in better_routes.iter() {
                     debug!(
                         "Setting new route for prefix: {} -> Account: {} (id: {})",
                         prefix,
@@ -598,53 +826,111 @@ where
                         account.id(),
                     );
                     local_table.set_route(prefix.to_string(), account.clone(), route.clone());
+                }
 
+                for prefix in withdrawn_routes.iter() {
+                    debug!("Removed route for prefix: {}", prefix);
+                    local_table.delete_route(prefix);
+                    forwarding_table.delete_route(prefix);
+                }
+
+                // Each aggregate prefix is only advertised by one of the accounts listing it,
+                // which may change along with the routes. So the routes under the accounts'
+                // aggregate prefixes are checked again, along with the new routes
+                let aggregate_routes = get_aggregate_routes(&local_table, &ilp_address);
+                let mut routes_to_advertise: Vec<(String, A, Route)> = better_routes
+                    .iter()
+                    .map(|(prefix, account, route)| {
+                        (prefix.to_string(), account.clone(), route.clone())
+                    })
+                    .collect();
+                for (prefix, (account, route)) in local_table.routes() {
+                    let is_new = better_routes
+                        .iter()
+                        .any(|(new, _, _)| *new == prefix.as_str());
+                    if !is_new && aggregate_prefix(account, prefix).is_some() {
+                        routes_to_advertise.push((prefix.clone(), account.clone(), route.clone()));
+                    }
+                }
+
+                // The forwarding table's routes which are not in the local table are the
+                // aggregate routes, which are withdrawn once they no longer cover any route.
+                // They are updated before the other routes, whose lookups would otherwise
+                // resolve to the aggregate route of the account which advertised it before
+                let stale_aggregates: Vec<String> = forwarding_table
+                    .routes()
+                    .map(|(prefix, _)| prefix)
+                    .filter(|prefix| {
+                        !local_table.has_route(prefix) && !aggregate_routes.contains_key(*prefix)
+                    })
+                    .cloned()
+                    .collect();
+                for prefix in stale_aggregates {
+                    debug!("Removed aggregate route for prefix: {}", prefix);
+                    forwarding_table.delete_route(&prefix);
+                    withdrawn_advertisements.push(prefix);
+                }
+                for (prefix, (account, route)) in aggregate_routes.iter() {
+                    let old_route = forwarding_table.get_route(prefix);
+                    if old_route.is_none() || old_route.unwrap().0.id() != account.id() {
+                        debug!(
+                            "Setting aggregate route for prefix: {} -> Account: {} (id: {})",
+                            prefix,
+                            account.username(),
+                            account.id(),
+                        );
+                        forwarding_table.set_route(prefix.clone(), account.clone(), route.clone());
+                        new_routes.push(route.clone());
+                    }
+                }
+
+                for (prefix, account, mut route) in routes_to_advertise {
                     // Update the forwarding table
 
                     // Don't advertise routes that don't start with the global prefix
                     // or that advertise the whole global prefix
                     let address_scheme = ilp_address.scheme();
-                    let correct_address_scheme =
-                        route.prefix.starts_with(address_scheme) && route.prefix != address_scheme;
+                    let correct_address_scheme = matches_prefix(&route.prefix, address_scheme)
+                        && route.prefix.trim_end_matches('.') != address_scheme;
                     // We do want to advertise our address
                     let is_our_address = route.prefix == &ilp_address as &str;
                     // Don't advertise local routes because advertising only our address
                     // will be enough to ensure the packet gets to us and we can route it
                     // to the correct account on our node
                     let is_local_route =
-                        route.prefix.starts_with(&ilp_address as &str) && route.path.is_empty();
+                        matches_prefix(&route.prefix, &ilp_address) && route.path.is_empty();
                     let not_local_route = is_our_address || !is_local_route;
                     // Don't include routes we're also withdrawing
-                    let not_withdrawn_route = !withdrawn_routes.contains(&prefix);
-
-                    if correct_address_scheme && not_local_route && not_withdrawn_route {
-                        let old_route = forwarding_table.get_route(prefix);
+                    let not_withdrawn_route = !withdrawn_routes.contains(&prefix.as_str());
+                    // The routes under an aggregate prefix are only advertised as part of the
+                    // route for that prefix, by the account which advertises it
+                    let aggregated = aggregate_prefix(&account, &prefix)
+                        .and_then(|aggregate| aggregate_routes.get(aggregate))
+                        .map_or(false, |(owner, _)| owner.id() == account.id());
+
+                    if aggregated {
+                        if forwarding_table.delete_route(&prefix) {
+                            withdrawn_advertisements.push(prefix);
+                        }
+                    } else if correct_address_scheme && not_local_route && not_withdrawn_route {
+                        let old_route = forwarding_table.get_route(&prefix);
                         if old_route.is_none() || old_route.unwrap().0.id() != account.id() {
                             route.path.insert(0, ilp_address.to_string());
                             // Each hop hashes the auth before forwarding
                             route.auth = hash(&route.auth);
-                            forwarding_table.set_route(
-                                prefix.to_string(),
-                                account.clone(),
-                                route.clone(),
-                            );
+                            forwarding_table.set_route(prefix, account.clone(), route.clone());
                             new_routes.push(route);
                         }
                     }
                 }
 
-                for prefix in withdrawn_routes.iter() {
-                    debug!("Removed route for prefix: {}", prefix);
-                    local_table.delete_route(prefix);
-                    forwarding_table.delete_route(prefix);
-                }
-
                 let epoch = forwarding_table.increment_epoch();
                 forwarding_table_updates.push((
                     new_routes,
                     withdrawn_routes
                         .into_iter()
                         .map(|s| s.to_string())
+                        .chain(withdrawn_advertisements)
                         .collect(),
                 ));
                 debug_assert_eq!(epoch as usize + 1, forwarding_table_updates.len());
@@ -822,6 +1108,8 @@ where
             .take(epochs_to_take)
         {
             for new_route in new {
+                // A later route for the same prefix replaces the earlier one
+                new_routes.retain(|route| route.prefix != new_route.prefix);
                 new_routes.push(new_route.clone());
                 // If the route was previously withdrawn, ignore that now since it was added back
                 if withdrawn_routes.contains(&new_route.prefix) {
@@ -855,7 +1143,7 @@ where
             new_routes,
             withdrawn_routes,
             speaker: self.ilp_address.read().clone(),
-            hold_down_time: DEFAULT_ROUTE_EXPIRY_TIME,
+            hold_down_time: self.hold_down_time.load(Ordering::Relaxed),
         }
     }
 
@@ -890,20 +1178,76 @@ where
     }
 }
 
+/// Returns the aggregate prefix of the account which covers the prefix, if any
+fn aggregate_prefix<'a, A: CcpRoutingAccount>(account: &'a A, prefix: &str) -> Option<&'a String> {
+    account
+        .aggregate_route_prefixes()
+        .iter()
+        .find(|aggregate| prefix != aggregate.as_str() && matches_prefix(prefix, aggregate))
+}
+
+/// Returns the routes to advertise for the aggregate prefixes of the accounts, which cover the
+/// routes in the local table learned from these accounts. The path of each aggregate route
+/// includes the hops of all the routes it covers, so the peers in any of them reject it.
+///
+/// If several accounts list the same aggregate prefix, it is advertised by the one with the
+/// lowest id and the routes of the others are advertised on their own
+fn get_aggregate_routes<A: CcpRoutingAccount>(
+    local_table: &RoutingTable<A>,
+    ilp_address: &Address,
+) -> HashMap<String, (A, Route)> {
+    let address_scheme = ilp_address.scheme();
+    let mut covered_routes: Vec<(&String, &String, &A, &Route)> = local_table
+        .routes()
+        .filter(|(prefix, _)| {
+            matches_prefix(prefix, address_scheme) && !matches_prefix(prefix, ilp_address)
+        })
+        .filter_map(|(prefix, (account, route))| {
+            aggregate_prefix(account, prefix).map(|aggregate| (aggregate, prefix, account, route))
+        })
+        .collect();
+    // Sorted so that the aggregate routes do not depend on the order of the routing table
+    covered_routes.sort_by(|a, b| (a.2.id(), a.1).cmp(&(b.2.id(), b.1)));
+
+    let mut aggregate_routes: HashMap<String, (A, Route)> = HashMap::new();
+    for (aggregate, _, account, route) in covered_routes {
+        let (aggregate_account, aggregate_route) = aggregate_routes
+            .entry(aggregate.clone())
+            .or_insert_with(|| {
+                (
+                    account.clone(),
+                    Route {
+                        prefix: aggregate.clone(),
+                        path: vec![ilp_address.to_string()],
+                        auth: [0; 32],
+                        props: Vec::new(),
+                    },
+                )
+            });
+        if aggregate_account.id() != account.id() {
+            continue;
+        }
+        for hop in route.path.iter() {
+            if !aggregate_route.path.contains(hop) {
+                aggregate_route.path.push(hop.clone());
+            }
+        }
+    }
+    aggregate_routes
+}
+
 fn get_best_route_for_prefix<A: CcpRoutingAccount>(
     local_routes: &HashMap<String, A>,
     configured_routes: &HashMap<String, A>,
     incoming_tables: &HashMap<Uuid, RoutingTable<A>>,
+    unhealthy_peers: &HashSet<Uuid>,
     prefix: &str,
 ) -> Option<(A, Route)> {
     // Check if we have a configured route for that specific prefix
     // or any shorter prefix ("example.a.b.c" will match "example.a.b" and "example.a")
-    // Note that this logic is duplicated from the Address type. We are not using
-    // Addresses here because the prefixes may not be valid ILP addresses ("example." is
-    // a valid prefix but not a valid address)
-    let segments: Vec<&str> = prefix.split(|c| c == '.').collect();
-    for i in 0..segments.len() {
-        let prefix = &segments[0..segments.len() - i].join(".");
+    // The prefixes may not be valid ILP addresses ("example." is a valid prefix but not a
+    // valid address) so this uses the free function rather than the Address method
+    for prefix in prefixes(prefix) {
         if let Some(account) = configured_routes.get(prefix) {
             return Some((
                 account.clone(),
@@ -930,8 +1274,9 @@ fn get_best_route_for_prefix<A: CcpRoutingAccount>(
     }
 
     let mut candidate_routes = incoming_tables
-        .values()
-        .filter_map(|incoming_table| incoming_table.get_route(prefix));
+        .iter()
+        .filter(|(account_id, _)| !unhealthy_peers.contains(account_id))
+        .filter_map(|(_, incoming_table)| incoming_table.get_route(prefix));
     if let Some((account, route)) = candidate_routes.next() {
         let (best_account, best_route) = candidate_routes.fold(
             (account, route),
@@ -1087,7 +1432,8 @@ mod ranking_routes {
 
     #[test]
     fn prioritizes_configured_routes() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, "example.a");
+        let best_route =
+            get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, &HashSet::new(), "example.a");
         assert_eq!(
             best_route.unwrap().0.id(),
             Uuid::from_slice(&[4; 16]).unwrap()
@@ -1096,8 +1442,13 @@ mod ranking_routes {
 
     #[test]
     fn prioritizes_shorter_configured_routes() {
-        let best_route =
-            get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, "example.a.sub-prefix");
+        let best_route = get_best_route_for_prefix(
+            &LOCAL,
+            &CONFIGURED,
+            &INCOMING,
+            &HashSet::new(),
+            "example.a.sub-prefix",
+        );
         assert_eq!(
             best_route.unwrap().0.id(),
             Uuid::from_slice(&[4; 16]).unwrap()
@@ -1106,7 +1457,8 @@ mod ranking_routes {
 
     #[test]
     fn prioritizes_local_routes_over_broadcasted_ones() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, "example.c");
+        let best_route =
+            get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, &HashSet::new(), "example.c");
         assert_eq!(
             best_route.unwrap().0.id(),
             Uuid::from_slice(&[3; 16]).unwrap()
@@ -1115,7 +1467,8 @@ mod ranking_routes {
 
     #[test]
     fn prioritizes_children_over_peers() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, "example.d");
+        let best_route =
+            get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, &HashSet::new(), "example.d");
         assert_eq!(
             best_route.unwrap().0.id(),
             Uuid::from_slice(&[6; 16]).unwrap()
@@ -1124,16 +1477,29 @@ mod ranking_routes {
 
     #[test]
     fn prioritizes_shorter_paths() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, "example.e");
+        let best_route =
+            get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, &HashSet::new(), "example.e");
         assert_eq!(
             best_route.unwrap().0.id(),
             Uuid::from_slice(&[7; 16]).unwrap()
         );
     }
 
+    #[test]
+    fn skips_routes_of_unhealthy_peers() {
+        let unhealthy = HashSet::from_iter(vec![Uuid::from_slice(&[7; 16]).unwrap()]);
+        let best_route =
+            get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, &unhealthy, "example.e");
+        assert_eq!(
+            best_route.unwrap().0.id(),
+            Uuid::from_slice(&[8; 16]).unwrap()
+        );
+    }
+
     #[test]
     fn returns_none_for_no_route() {
-        let best_route = get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, "example.z");
+        let best_route =
+            get_best_route_for_prefix(&LOCAL, &CONFIGURED, &INCOMING, &HashSet::new(), "example.z");
         assert!(best_route.is_none());
     }
 }
@@ -1467,6 +1833,27 @@ mod handle_route_update_request {
         );
     }
 
+    #[tokio::test]
+    async fn applies_the_route_policy_of_the_account() {
+        let mut service = test_service();
+        let mut account = ROUTING_ACCOUNT.clone();
+        account.route_policy.accept_prefixes = vec!["example.".to_string()];
+        account.route_policy.reject_prefixes = vec!["example.prefix2".to_string()];
+        let mut request = UPDATE_REQUEST_COMPLEX.clone();
+        request.to_epoch_index = 1;
+        request.from_epoch_index = 0;
+        service
+            .handle_request(IncomingRequest {
+                from: account,
+                prepare: request.to_prepare(),
+            })
+            .await
+            .unwrap();
+        let local_table = service.local_table.read();
+        assert!(local_table.has_route("example.prefix1"));
+        assert!(!local_table.has_route("example.prefix2"));
+    }
+
     #[tokio::test]
     async fn writes_local_routing_table_to_store() {
         let mut service = test_service();
@@ -1560,6 +1947,7 @@ mod handle_route_update_request {
             })
             .await
             .unwrap();
+        let mut route_flaps = service.route_flaps();
         service
             .handle_request(IncomingRequest {
                 from: ROUTING_ACCOUNT.clone(),
@@ -1589,6 +1977,103 @@ mod handle_route_update_request {
         assert!((*service.local_table.read())
             .get_route("example.prefix2")
             .is_none());
+        assert_eq!(
+            route_flaps.try_next().unwrap().as_ref(),
+            Some(ROUTING_ACCOUNT.username())
+        );
+    }
+
+    #[tokio::test]
+    async fn withdraws_expired_routes() {
+        let mut service = test_service();
+        let mut request = UPDATE_REQUEST_COMPLEX.clone();
+        request.to_epoch_index = 1;
+        request.from_epoch_index = 0;
+        service
+            .handle_request(IncomingRequest {
+                from: ROUTING_ACCOUNT.clone(),
+                prepare: request.to_prepare(),
+            })
+            .await
+            .unwrap();
+
+        service
+            .withdraw_expired_routes(Instant::now())
+            .await
+            .unwrap();
+        assert!(service.store.routes.lock().contains_key("example.prefix1"));
+
+        service
+            .withdraw_expired_routes(Instant::now() + Duration::from_secs(31))
+            .await
+            .unwrap();
+        assert!((*service.local_table.read())
+            .get_route("example.prefix1")
+            .is_none());
+        assert!(!service.store.routes.lock().contains_key("example.prefix1"));
+        assert!(!service.store.routes.lock().contains_key("example.prefix2"));
+        assert_eq!(
+            service.incoming_tables.read()[&ROUTING_ACCOUNT.id()].epoch(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn withdraws_routes_of_unhealthy_peers() {
+        let mut service = test_service();
+        let mut request = UPDATE_REQUEST_COMPLEX.clone();
+        request.to_epoch_index = 1;
+        request.from_epoch_index = 0;
+        service
+            .handle_request(IncomingRequest {
+                from: ROUTING_ACCOUNT.clone(),
+                prepare: request.to_prepare(),
+            })
+            .await
+            .unwrap();
+        assert!(service.store.routes.lock().contains_key("example.prefix1"));
+
+        service
+            .set_peer_healthy(ROUTING_ACCOUNT.id(), false)
+            .await
+            .unwrap();
+        assert!(!service.store.routes.lock().contains_key("example.prefix1"));
+        assert!(!service.store.routes.lock().contains_key("example.prefix2"));
+
+        // The routes it advertised are used again once it recovers
+        service
+            .set_peer_healthy(ROUTING_ACCOUNT.id(), true)
+            .await
+            .unwrap();
+        assert!(service.store.routes.lock().contains_key("example.prefix1"));
+        assert!(service.store.routes.lock().contains_key("example.prefix2"));
+    }
+
+    #[tokio::test]
+    async fn reports_expiries_of_learned_routes() {
+        let mut service = test_service();
+        let expiries = service.route_expiries();
+        assert!(expiries.get().is_empty());
+
+        let mut request = UPDATE_REQUEST_COMPLEX.clone();
+        request.to_epoch_index = 1;
+        request.from_epoch_index = 0;
+        service
+            .handle_request(IncomingRequest {
+                from: ROUTING_ACCOUNT.clone(),
+                prepare: request.to_prepare(),
+            })
+            .await
+            .unwrap();
+        let expires_at = expiries.get()["example.prefix1"];
+        assert!(expires_at > Instant::now());
+        assert!(expires_at <= Instant::now() + Duration::from_secs(30));
+
+        service
+            .withdraw_expired_routes(Instant::now() + Duration::from_secs(31))
+            .await
+            .unwrap();
+        assert!(expiries.get().is_empty());
     }
 
     #[tokio::test]
@@ -1682,6 +2167,23 @@ mod create_route_update {
         assert!(update.withdrawn_routes.is_empty());
     }
 
+    #[tokio::test]
+    async fn hold_down_time_follows_the_broadcast_interval() {
+        let service = test_service();
+        assert_eq!(service.create_route_update(0, 0).hold_down_time, 60000);
+
+        let (broadcast_interval, updates) = watch::channel(30000);
+        let service_clone = service.clone();
+        tokio::spawn(async move {
+            service_clone
+                .start_reloadable_broadcast_interval(updates)
+                .await
+        });
+        broadcast_interval.broadcast(45000).unwrap();
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(service.create_route_update(0, 0).hold_down_time, 90000);
+    }
+
     #[tokio::test]
     async fn includes_the_given_range_of_epochs() {
         let service = test_service();
@@ -1894,6 +2396,156 @@ mod send_route_updates {
         assert_eq!(update.withdrawn_routes[0], "example.remote");
     }
 
+    #[tokio::test]
+    async fn broadcasts_aggregate_routes() {
+        let id10 = Uuid::from_slice(&[10; 16]).unwrap();
+        let (service, outgoing_requests) = test_service_with_routes();
+        let mut peer = TestAccount::new(id10, "example.peer");
+        peer.route_policy.aggregate_prefixes = vec!["example.remote".to_string()];
+
+        // This is normally spawned as a task when the service is created
+        service.update_best_routes(None).await.unwrap();
+
+        let route = |prefix: &str| Route {
+            prefix: prefix.to_string(),
+            path: vec!["example.peer".to_string(), prefix.to_string()],
+            auth: [0; 32],
+            props: Vec::new(),
+        };
+        service
+            .handle_route_update_request(IncomingRequest {
+                from: peer.clone(),
+                prepare: RouteUpdateRequest {
+                    routing_table_id: [0; 16],
+                    current_epoch_index: 1,
+                    from_epoch_index: 0,
+                    to_epoch_index: 1,
+                    hold_down_time: 30000,
+                    speaker: Address::from_str("example.peer").unwrap(),
+                    new_routes: vec![route("example.remote.a"), route("example.remote.b")],
+                    withdrawn_routes: Vec::new(),
+                }
+                .to_prepare(),
+            })
+            .await
+            .unwrap();
+
+        service.send_route_updates().await.unwrap();
+        let update = RouteUpdateRequest::try_from(&outgoing_requests.lock()[0].prepare).unwrap();
+        let prefixes: Vec<&str> = update
+            .new_routes
+            .iter()
+            .map(|route| str::from_utf8(route.prefix.as_ref()).unwrap())
+            .collect();
+        assert!(prefixes.contains(&"example.remote"));
+        assert!(!prefixes.contains(&"example.remote.a"));
+        assert!(!prefixes.contains(&"example.remote.b"));
+        let aggregate = update
+            .new_routes
+            .iter()
+            .find(|route| route.prefix == "example.remote")
+            .unwrap();
+        assert_eq!(aggregate.path[..2], ["example.connector", "example.peer"]);
+        assert!(aggregate.path.contains(&"example.remote.a".to_string()));
+        assert!(aggregate.path.contains(&"example.remote.b".to_string()));
+        // The specific routes are still used to forward the packets
+        assert!(service.local_table.read().has_route("example.remote.a"));
+
+        service
+            .handle_route_update_request(IncomingRequest {
+                from: peer,
+                prepare: RouteUpdateRequest {
+                    routing_table_id: [0; 16],
+                    current_epoch_index: 2,
+                    from_epoch_index: 1,
+                    to_epoch_index: 2,
+                    hold_down_time: 30000,
+                    speaker: Address::from_str("example.peer").unwrap(),
+                    new_routes: Vec::new(),
+                    withdrawn_routes: vec![
+                        "example.remote.a".to_string(),
+                        "example.remote.b".to_string(),
+                    ],
+                }
+                .to_prepare(),
+            })
+            .await
+            .unwrap();
+        assert!(!service.forwarding_table.read().has_route("example.remote"));
+        let epoch = service.forwarding_table.read().epoch();
+        let update = service.create_route_update(epoch - 1, epoch);
+        assert!(update
+            .withdrawn_routes
+            .contains(&"example.remote".to_string()));
+    }
+
+    #[tokio::test]
+    async fn advertises_each_aggregate_prefix_for_one_account() {
+        let id10 = Uuid::from_slice(&[10; 16]).unwrap();
+        let id11 = Uuid::from_slice(&[11; 16]).unwrap();
+        let (service, outgoing_requests) = test_service_with_routes();
+        service.update_best_routes(None).await.unwrap();
+
+        let route = |speaker: &str, prefix: &str| Route {
+            prefix: prefix.to_string(),
+            path: vec![speaker.to_string(), prefix.to_string()],
+            auth: [0; 32],
+            props: Vec::new(),
+        };
+        // The routes are learned from the account with the highest id first
+        for (id, speaker, prefix) in [
+            (id11, "example.other-peer", "example.remote.b"),
+            (id10, "example.peer", "example.remote.a"),
+        ]
+        .iter()
+        {
+            let mut peer = TestAccount::new(*id, speaker);
+            peer.route_policy.aggregate_prefixes = vec!["example.remote".to_string()];
+            service
+                .handle_route_update_request(IncomingRequest {
+                    from: peer,
+                    prepare: RouteUpdateRequest {
+                        routing_table_id: [0; 16],
+                        current_epoch_index: 1,
+                        from_epoch_index: 0,
+                        to_epoch_index: 1,
+                        hold_down_time: 30000,
+                        speaker: Address::from_str(speaker).unwrap(),
+                        new_routes: vec![route(speaker, prefix)],
+                        withdrawn_routes: Vec::new(),
+                    }
+                    .to_prepare(),
+                })
+                .await
+                .unwrap();
+        }
+
+        service.send_route_updates().await.unwrap();
+        let update = RouteUpdateRequest::try_from(&outgoing_requests.lock()[0].prepare).unwrap();
+        let aggregate = update
+            .new_routes
+            .iter()
+            .find(|route| route.prefix == "example.remote")
+            .unwrap();
+        assert_eq!(aggregate.path[..2], ["example.connector", "example.peer"]);
+        assert!(!aggregate.path.contains(&"example.other-peer".to_string()));
+        let forwarding_table = service.forwarding_table.read();
+        assert_eq!(
+            forwarding_table.get_route("example.remote").unwrap().0.id(),
+            id10
+        );
+        assert!(!forwarding_table.has_route("example.remote.a"));
+        // The routes of the other account are advertised on their own
+        assert_eq!(
+            forwarding_table
+                .get_route("example.remote.b")
+                .unwrap()
+                .0
+                .id(),
+            id11
+        );
+    }
+
     #[tokio::test]
     async fn backs_off_sending_to_unavailable_child_accounts() {
         let id1 = Uuid::from_slice(&[1; 16]).unwrap();
@@ -1909,6 +2561,7 @@ mod send_route_updates {
                     id: id2,
                     ilp_address: Address::from_str("example.connector.other-local").unwrap(),
                     relation: RoutingRelation::Child,
+                    route_policy: TestRoutePolicy::default(),
                 },
             ),
         ]);
@@ -1997,6 +2650,7 @@ mod send_route_updates {
             id: id2,
             ilp_address: Address::from_str("example.connector.other-local").unwrap(),
             relation: RoutingRelation::Child,
+            route_policy: TestRoutePolicy::default(),
         };
         let local_routes = HashMap::from_iter(vec![
             (
@@ -18,16 +18,19 @@ pub static ROUTING_ACCOUNT: Lazy<TestAccount> = Lazy::new(|| TestAccount {
     id: Uuid::new_v4(),
     ilp_address: Address::from_str("example.peer").unwrap(),
     relation: RoutingRelation::Peer,
+    route_policy: TestRoutePolicy::default(),
 });
 pub static NON_ROUTING_ACCOUNT: Lazy<TestAccount> = Lazy::new(|| TestAccount {
     id: Uuid::new_v4(),
     ilp_address: Address::from_str("example.me.nonroutingaccount").unwrap(),
     relation: RoutingRelation::NonRoutingAccount,
+    route_policy: TestRoutePolicy::default(),
 });
 pub static CHILD_ACCOUNT: Lazy<TestAccount> = Lazy::new(|| TestAccount {
     id: Uuid::new_v4(),
     ilp_address: Address::from_str("example.me.child").unwrap(),
     relation: RoutingRelation::Child,
+    route_policy: TestRoutePolicy::default(),
 });
 pub static EXAMPLE_CONNECTOR: Lazy<Address> =
     Lazy::new(|| Address::from_str("example.connector").unwrap());
@@ -38,6 +41,15 @@ pub struct TestAccount {
     pub id: Uuid,
     pub ilp_address: Address,
     pub relation: RoutingRelation,
+    pub route_policy: TestRoutePolicy,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TestRoutePolicy {
+    pub accept_prefixes: Vec<String>,
+    pub reject_prefixes: Vec<String>,
+    pub max_routes: Option<u32>,
+    pub aggregate_prefixes: Vec<String>,
 }
 
 impl TestAccount {
@@ -46,6 +58,7 @@ impl TestAccount {
             id,
             ilp_address: Address::from_str(ilp_address).unwrap(),
             relation: RoutingRelation::Peer,
+            route_policy: TestRoutePolicy::default(),
         }
     }
 }
@@ -76,6 +89,22 @@ impl CcpRoutingAccount for TestAccount {
     fn routing_relation(&self) -> RoutingRelation {
         self.relation
     }
+
+    fn accept_route_prefixes(&self) -> &[String] {
+        &self.route_policy.accept_prefixes
+    }
+
+    fn reject_route_prefixes(&self) -> &[String] {
+        &self.route_policy.reject_prefixes
+    }
+
+    fn max_routes(&self) -> Option<u32> {
+        self.route_policy.max_routes
+    }
+
+    fn aggregate_route_prefixes(&self) -> &[String] {
+        &self.route_policy.aggregate_prefixes
+    }
 }
 
 #[derive(Clone)]
@@ -232,6 +261,7 @@ pub fn test_service_with_routes() -> (
                 id: Uuid::from_slice(&[3; 16]).unwrap(),
                 ilp_address: Address::from_str("example.connector.other-local").unwrap(),
                 relation: RoutingRelation::NonRoutingAccount,
+                route_policy: TestRoutePolicy::default(),
             },
         ),
     ]);
@@ -1,4 +1,4 @@
-use super::BtpStoreError;
+use super::{BtpStoreError, SubAccountStoreError};
 use crate::error::ApiError;
 use std::error::Error as StdError;
 use thiserror::Error;
@@ -26,6 +26,15 @@ impl From<AccountStoreError> for BtpStoreError {
     }
 }
 
+impl From<AccountStoreError> for SubAccountStoreError {
+    fn from(src: AccountStoreError) -> Self {
+        match src {
+            AccountStoreError::AccountNotFound(s) => SubAccountStoreError::AccountNotFound(s),
+            _ => SubAccountStoreError::Other(Box::new(src)),
+        }
+    }
+}
+
 impl From<AccountStoreError> for ApiError {
     fn from(src: AccountStoreError) -> Self {
         match src {
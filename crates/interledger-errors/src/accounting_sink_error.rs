@@ -0,0 +1,22 @@
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the AccountingSink
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum AccountingSinkError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for AccountingSinkError {
+    fn from(src: RedisError) -> AccountingSinkError {
+        AccountingSinkError::Other(Box::new(src))
+    }
+}
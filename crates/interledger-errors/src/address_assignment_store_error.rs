@@ -0,0 +1,34 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the AddressAssignmentStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum AddressAssignmentStoreError {
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+impl From<AddressAssignmentStoreError> for ApiError {
+    fn from(src: AddressAssignmentStoreError) -> Self {
+        ApiError::internal_server_error().detail(src.to_string())
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<AddressAssignmentStoreError> for warp::Rejection {
+    fn from(src: AddressAssignmentStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for AddressAssignmentStoreError {
+    fn from(src: RedisError) -> AddressAssignmentStoreError {
+        AddressAssignmentStoreError::Other(Box::new(src))
+    }
+}
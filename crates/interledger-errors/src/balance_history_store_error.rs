@@ -0,0 +1,34 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the BalanceHistoryStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum BalanceHistoryStoreError {
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+impl From<BalanceHistoryStoreError> for ApiError {
+    fn from(src: BalanceHistoryStoreError) -> Self {
+        ApiError::internal_server_error().detail(src.to_string())
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<BalanceHistoryStoreError> for warp::Rejection {
+    fn from(src: BalanceHistoryStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for BalanceHistoryStoreError {
+    fn from(src: RedisError) -> BalanceHistoryStoreError {
+        BalanceHistoryStoreError::Other(Box::new(src))
+    }
+}
@@ -0,0 +1,39 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the ContactStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum ContactStoreError {
+    #[error("contact `{0}` was not found")]
+    ContactNotFound(String),
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+impl From<ContactStoreError> for ApiError {
+    fn from(src: ContactStoreError) -> Self {
+        match src {
+            ContactStoreError::ContactNotFound(_) => ApiError::not_found().detail(src.to_string()),
+            _ => ApiError::internal_server_error().detail(src.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<ContactStoreError> for warp::Rejection {
+    fn from(src: ContactStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for ContactStoreError {
+    fn from(src: RedisError) -> ContactStoreError {
+        ContactStoreError::Other(Box::new(src))
+    }
+}
@@ -20,6 +20,10 @@ pub enum CreateAccountError {
     InvalidRoutingRelation(String),
     #[error("the provided value for parameter `{0}` was too large")]
     ParamTooLarge(String),
+    #[error("the provided spread must be at least 0 and below 1: {0}")]
+    InvalidSpread(f64),
+    #[error("translate_prefix_from and translate_prefix_to must be provided together")]
+    IncompletePrefixTranslation,
 }
 
 impl From<CreateAccountError> for ApiError {
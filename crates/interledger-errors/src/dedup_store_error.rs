@@ -0,0 +1,34 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the DedupStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum DedupStoreError {
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+impl From<DedupStoreError> for ApiError {
+    fn from(src: DedupStoreError) -> Self {
+        ApiError::internal_server_error().detail(src.to_string())
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<DedupStoreError> for warp::Rejection {
+    fn from(src: DedupStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for DedupStoreError {
+    fn from(src: RedisError) -> DedupStoreError {
+        DedupStoreError::Other(Box::new(src))
+    }
+}
@@ -0,0 +1,34 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the DustStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum DustStoreError {
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+impl From<DustStoreError> for ApiError {
+    fn from(src: DustStoreError) -> Self {
+        ApiError::internal_server_error().detail(src.to_string())
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<DustStoreError> for warp::Rejection {
+    fn from(src: DustStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for DustStoreError {
+    fn from(src: RedisError) -> DustStoreError {
+        DustStoreError::Other(Box::new(src))
+    }
+}
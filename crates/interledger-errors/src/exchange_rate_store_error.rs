@@ -10,11 +10,18 @@ pub enum ExchangeRateStoreError {
     Other(#[from] Box<dyn StdError + Send + 'static>),
     #[error("Pair {from}/{to} not found")]
     PairNotFound { from: String, to: String },
+    #[error("Invalid rate override: {0}")]
+    InvalidRateOverride(String),
 }
 
 impl From<ExchangeRateStoreError> for ApiError {
     fn from(src: ExchangeRateStoreError) -> Self {
-        ApiError::internal_server_error().detail(src.to_string())
+        match src {
+            ExchangeRateStoreError::InvalidRateOverride(_) => {
+                ApiError::bad_request().detail(src.to_string())
+            }
+            _ => ApiError::internal_server_error().detail(src.to_string()),
+        }
     }
 }
 
@@ -8,6 +8,9 @@ pub use account_store_error::AccountStoreError;
 mod address_store_error;
 pub use address_store_error::AddressStoreError;
 
+mod address_assignment_store_error;
+pub use address_assignment_store_error::AddressAssignmentStoreError;
+
 mod http_store_error;
 pub use http_store_error::HttpStoreError;
 
@@ -20,6 +23,39 @@ pub use ccprouting_store_error::CcpRoutingStoreError;
 mod balance_store_error;
 pub use balance_store_error::BalanceStoreError;
 
+mod usage_store_error;
+pub use usage_store_error::UsageStoreError;
+
+mod dedup_store_error;
+pub use dedup_store_error::DedupStoreError;
+
+mod dust_store_error;
+pub use dust_store_error::DustStoreError;
+
+mod balance_history_store_error;
+pub use balance_history_store_error::BalanceHistoryStoreError;
+
+mod sub_account_store_error;
+pub use sub_account_store_error::SubAccountStoreError;
+
+mod stream_receive_store_error;
+pub use stream_receive_store_error::StreamReceiveStoreError;
+
+mod stream_replay_store_error;
+pub use stream_replay_store_error::StreamReplayStoreError;
+
+mod contact_store_error;
+pub use contact_store_error::ContactStoreError;
+
+mod webhook_store_error;
+pub use webhook_store_error::WebhookStoreError;
+
+mod accounting_sink_error;
+pub use accounting_sink_error::AccountingSinkError;
+
+mod replication_store_error;
+pub use replication_store_error::ReplicationStoreError;
+
 mod node_store_error;
 pub use node_store_error::NodeStoreError;
 
@@ -29,5 +65,11 @@ pub use exchange_rate_store_error::ExchangeRateStoreError;
 mod settlement_errors;
 pub use settlement_errors::{IdempotentStoreError, LeftoversStoreError, SettlementStoreError};
 
+mod settlement_queue_store_error;
+pub use settlement_queue_store_error::SettlementQueueStoreError;
+
+mod settlement_history_store_error;
+pub use settlement_history_store_error::SettlementHistoryStoreError;
+
 mod create_account_error;
 pub use create_account_error::CreateAccountError;
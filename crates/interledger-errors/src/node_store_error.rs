@@ -15,6 +15,8 @@ pub enum NodeStoreError {
     AccountNotFound(String),
     #[error("account `{0}` already exists")]
     AccountExists(String),
+    #[error("account `{0}` was not inserted because another account failed to be")]
+    AccountNotInserted(String),
     #[error("not all of the given accounts exist")]
     MissingAccounts,
     #[error("invalid account: {0}")]
@@ -0,0 +1,34 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the ReplicaStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum ReplicationStoreError {
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+impl From<ReplicationStoreError> for ApiError {
+    fn from(src: ReplicationStoreError) -> Self {
+        ApiError::internal_server_error().detail(src.to_string())
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<ReplicationStoreError> for warp::Rejection {
+    fn from(src: ReplicationStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for ReplicationStoreError {
+    fn from(src: RedisError) -> ReplicationStoreError {
+        ReplicationStoreError::Other(Box::new(src))
+    }
+}
@@ -54,6 +54,8 @@ pub enum SettlementStoreError {
     BalanceUpdateFailure,
     #[error("could not refund settlement")]
     RefundFailure,
+    #[error("balance would overflow")]
+    BalanceOverflow,
 }
 
 impl From<SettlementStoreError> for ApiError {
@@ -0,0 +1,34 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the SettlementHistoryStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum SettlementHistoryStoreError {
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+impl From<SettlementHistoryStoreError> for ApiError {
+    fn from(src: SettlementHistoryStoreError) -> Self {
+        ApiError::internal_server_error().detail(src.to_string())
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<SettlementHistoryStoreError> for warp::Rejection {
+    fn from(src: SettlementHistoryStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for SettlementHistoryStoreError {
+    fn from(src: RedisError) -> SettlementHistoryStoreError {
+        SettlementHistoryStoreError::Other(Box::new(src))
+    }
+}
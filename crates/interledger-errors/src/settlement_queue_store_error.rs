@@ -0,0 +1,34 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the SettlementQueueStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum SettlementQueueStoreError {
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+impl From<SettlementQueueStoreError> for ApiError {
+    fn from(src: SettlementQueueStoreError) -> Self {
+        ApiError::internal_server_error().detail(src.to_string())
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<SettlementQueueStoreError> for warp::Rejection {
+    fn from(src: SettlementQueueStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for SettlementQueueStoreError {
+    fn from(src: RedisError) -> SettlementQueueStoreError {
+        SettlementQueueStoreError::Other(Box::new(src))
+    }
+}
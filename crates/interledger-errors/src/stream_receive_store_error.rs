@@ -0,0 +1,44 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the StreamReceiveStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum StreamReceiveStoreError {
+    #[error(
+        "amount {0} exceeds the largest amount which can be received ({})",
+        i64::MAX
+    )]
+    AmountTooLarge(u64),
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+impl From<StreamReceiveStoreError> for ApiError {
+    fn from(src: StreamReceiveStoreError) -> Self {
+        match src {
+            StreamReceiveStoreError::AmountTooLarge(_) => {
+                ApiError::bad_request().detail(src.to_string())
+            }
+            _ => ApiError::internal_server_error().detail(src.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<StreamReceiveStoreError> for warp::Rejection {
+    fn from(src: StreamReceiveStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for StreamReceiveStoreError {
+    fn from(src: RedisError) -> StreamReceiveStoreError {
+        StreamReceiveStoreError::Other(Box::new(src))
+    }
+}
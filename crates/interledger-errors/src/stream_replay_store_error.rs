@@ -0,0 +1,34 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the StreamReplayStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum StreamReplayStoreError {
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+impl From<StreamReplayStoreError> for ApiError {
+    fn from(src: StreamReplayStoreError) -> Self {
+        ApiError::internal_server_error().detail(src.to_string())
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<StreamReplayStoreError> for warp::Rejection {
+    fn from(src: StreamReplayStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for StreamReplayStoreError {
+    fn from(src: RedisError) -> StreamReplayStoreError {
+        StreamReplayStoreError::Other(Box::new(src))
+    }
+}
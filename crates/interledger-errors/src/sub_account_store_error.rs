@@ -0,0 +1,46 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the SubAccountStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum SubAccountStoreError {
+    #[error("account `{0}` was not found")]
+    AccountNotFound(String),
+    #[error("sub-account `{0}` does not have the same asset as the receiving account")]
+    AssetMismatch(String),
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+impl From<SubAccountStoreError> for ApiError {
+    fn from(src: SubAccountStoreError) -> Self {
+        match src {
+            SubAccountStoreError::AccountNotFound(_) => {
+                ApiError::account_not_found().detail(src.to_string())
+            }
+            SubAccountStoreError::AssetMismatch(_) => {
+                ApiError::bad_request().detail(src.to_string())
+            }
+            _ => ApiError::internal_server_error().detail(src.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<SubAccountStoreError> for warp::Rejection {
+    fn from(src: SubAccountStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for SubAccountStoreError {
+    fn from(src: RedisError) -> SubAccountStoreError {
+        SubAccountStoreError::Other(Box::new(src))
+    }
+}
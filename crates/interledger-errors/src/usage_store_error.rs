@@ -0,0 +1,34 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the UsageStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum UsageStoreError {
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+impl From<UsageStoreError> for ApiError {
+    fn from(src: UsageStoreError) -> Self {
+        ApiError::internal_server_error().detail(src.to_string())
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<UsageStoreError> for warp::Rejection {
+    fn from(src: UsageStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for UsageStoreError {
+    fn from(src: RedisError) -> UsageStoreError {
+        UsageStoreError::Other(Box::new(src))
+    }
+}
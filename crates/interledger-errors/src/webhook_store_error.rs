@@ -0,0 +1,39 @@
+use crate::error::ApiError;
+use std::error::Error as StdError;
+use thiserror::Error;
+
+/// Errors for the WebhookStore
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum WebhookStoreError {
+    #[error("account `{0}` has no webhook")]
+    WebhookNotFound(String),
+    #[error("{0}")]
+    Other(#[from] Box<dyn StdError + Send + 'static>),
+}
+
+impl From<WebhookStoreError> for ApiError {
+    fn from(src: WebhookStoreError) -> Self {
+        match src {
+            WebhookStoreError::WebhookNotFound(_) => ApiError::not_found().detail(src.to_string()),
+            _ => ApiError::internal_server_error().detail(src.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "warp_errors")]
+impl From<WebhookStoreError> for warp::Rejection {
+    fn from(src: WebhookStoreError) -> Self {
+        ApiError::from(src).into()
+    }
+}
+
+#[cfg(feature = "redis_errors")]
+use redis::RedisError;
+
+#[cfg(feature = "redis_errors")]
+impl From<RedisError> for WebhookStoreError {
+    fn from(src: RedisError) -> WebhookStoreError {
+        WebhookStoreError::Other(Box::new(src))
+    }
+}
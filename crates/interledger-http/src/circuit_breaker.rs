@@ -0,0 +1,157 @@
+//! Per-account circuit breakers, which stop the ILP over HTTP client from sending requests
+//! to peers whose endpoints keep failing, until they had some time to recover.
+
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// State of the circuit breaker of an account
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BreakerState {
+    /// Requests are sent, counting the consecutive failures
+    Closed { failures: u32 },
+    /// Requests are rejected without being sent until the given time
+    Open { until: Instant },
+    /// A single trial request was let through, whose outcome closes or reopens the
+    /// breaker. Another one is let through after the given time if it did not complete
+    HalfOpen { until: Instant },
+}
+
+/// The circuit breakers of all of the accounts the client sends requests to
+#[derive(Clone)]
+pub(crate) struct CircuitBreakers {
+    /// Number of consecutive failures after which the breaker of an account opens.
+    /// The breakers never open if this is `None`
+    threshold: Option<u32>,
+    /// Time during which an open breaker rejects the requests
+    reset_timeout: Duration,
+    states: Arc<Mutex<HashMap<Uuid, BreakerState>>>,
+}
+
+impl CircuitBreakers {
+    pub(crate) fn new(threshold: Option<u32>, reset_timeout: Duration) -> Self {
+        CircuitBreakers {
+            threshold,
+            reset_timeout,
+            states: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns true if a request may be sent to the account. Once an open breaker's timeout
+    /// has elapsed, a single trial request is let through (and another one if that request
+    /// did not complete within the timeout)
+    pub(crate) fn try_acquire(&self, account_id: Uuid) -> bool {
+        if self.threshold.is_none() {
+            return true;
+        }
+        let now = Instant::now();
+        let mut states = self.states.lock();
+        match states.get(&account_id).copied() {
+            None | Some(BreakerState::Closed { .. }) => true,
+            Some(BreakerState::Open { until }) | Some(BreakerState::HalfOpen { until })
+                if now < until =>
+            {
+                false
+            }
+            Some(_) => {
+                states.insert(
+                    account_id,
+                    BreakerState::HalfOpen {
+                        until: now + self.reset_timeout,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Closes the account's breaker
+    pub(crate) fn record_success(&self, account_id: Uuid) {
+        if self.threshold.is_some() {
+            self.states.lock().remove(&account_id);
+        }
+    }
+
+    /// Counts a failed request to the account, opening its breaker if the threshold is
+    /// reached or if the request was the trial of a half-open breaker
+    pub(crate) fn record_failure(&self, account_id: Uuid) {
+        let threshold = match self.threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let mut states = self.states.lock();
+        let state = states
+            .entry(account_id)
+            .or_insert(BreakerState::Closed { failures: 0 });
+        *state = match *state {
+            BreakerState::Closed { failures } if failures + 1 < threshold => BreakerState::Closed {
+                failures: failures + 1,
+            },
+            // Other requests sent before the breaker opened do not extend its timeout
+            BreakerState::Open { until } => BreakerState::Open { until },
+            _ => BreakerState::Open {
+                until: Instant::now() + self.reset_timeout,
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breakers = CircuitBreakers::new(Some(3), Duration::from_secs(60));
+        let id = Uuid::new_v4();
+        breakers.record_failure(id);
+        breakers.record_failure(id);
+        breakers.record_success(id);
+        breakers.record_failure(id);
+        breakers.record_failure(id);
+        assert!(breakers.try_acquire(id));
+        breakers.record_failure(id);
+        assert!(!breakers.try_acquire(id));
+        // other accounts are not affected
+        assert!(breakers.try_acquire(Uuid::new_v4()));
+    }
+
+    #[test]
+    fn lets_a_single_trial_through_once_the_timeout_elapsed() {
+        let breakers = CircuitBreakers::new(Some(1), Duration::from_millis(0));
+        let id = Uuid::new_v4();
+        breakers.record_failure(id);
+        assert!(breakers.try_acquire(id));
+        breakers.record_failure(id);
+        assert!(breakers.try_acquire(id));
+        breakers.record_success(id);
+        assert!(breakers.try_acquire(id));
+
+        let breakers = CircuitBreakers::new(Some(1), Duration::from_secs(60));
+        breakers.states.lock().insert(
+            id,
+            BreakerState::Open {
+                until: Instant::now(),
+            },
+        );
+        assert!(breakers.try_acquire(id));
+        // the trial request has not completed yet
+        assert!(!breakers.try_acquire(id));
+        breakers.record_failure(id);
+        assert!(!breakers.try_acquire(id));
+    }
+
+    #[test]
+    fn never_opens_without_threshold() {
+        let breakers = CircuitBreakers::new(None, Duration::from_secs(60));
+        let id = Uuid::new_v4();
+        for _ in 0..100 {
+            breakers.record_failure(id);
+        }
+        assert!(breakers.try_acquire(id));
+    }
+}
@@ -1,16 +1,179 @@
-use super::{HttpAccount, HttpStore};
+use super::{circuit_breaker::CircuitBreakers, HttpAccount, HttpStore};
 use async_trait::async_trait;
-use bytes::BytesMut;
-use futures::future::TryFutureExt;
-use interledger_packet::{Address, ErrorCode, Packet, RejectBuilder};
+use futures::future::{self, Either, TryFutureExt};
+use interledger_packet::{pool, Address, ErrorCode, Packet, Prepare, RejectBuilder};
 use interledger_service::*;
 use reqwest::{
     header::{HeaderMap, HeaderName, HeaderValue},
-    Client, ClientBuilder, Response as HttpResponse,
+    Client, ClientBuilder, RequestBuilder, Response as HttpResponse,
 };
 use secrecy::{ExposeSecret, SecretString};
-use std::{convert::TryFrom, marker::PhantomData, sync::Arc, time::Duration};
-use tracing::{error, trace};
+use serde::Deserialize;
+use std::{
+    collections::hash_map::DefaultHasher,
+    convert::TryFrom,
+    hash::{Hash, Hasher},
+    iter,
+    marker::PhantomData,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, error, trace};
+use url::Url;
+
+fn default_idle_timeout() -> u64 {
+    90_000
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+fn default_retry_backoff() -> u64 {
+    50
+}
+
+fn default_circuit_breaker_reset_timeout() -> u64 {
+    30_000
+}
+
+/// Connection settings of the HTTP client used to send ILP over HTTP requests.
+///
+/// Connections to each peer are kept open and reused across requests, which matters for
+/// connectors sending many small requests per second to the same peers.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct HttpClientConfig {
+    /// Maximum number of idle connections kept open to each peer. Unlimited by default
+    #[serde(default)]
+    pub max_idle_connections_per_peer: Option<usize>,
+    /// Time, in milliseconds, an idle connection is kept open before it is closed.
+    /// Defaults to 90000 (90 seconds)
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Whether to send requests over HTTP/2 without negotiating it first ("prior knowledge").
+    /// Only enable this if all of the peers' ILP over HTTP endpoints support HTTP/2
+    /// over cleartext or TLS connections
+    #[serde(default)]
+    pub http2_prior_knowledge: bool,
+    /// Interval, in milliseconds, of the TCP keep-alive probes sent on the connections to
+    /// peers, which keeps them from being dropped by NATs and firewalls. Disabled by default
+    #[serde(default)]
+    pub tcp_keepalive: Option<u64>,
+    /// Time, in milliseconds, after which a packet which is safe to deliver twice is also sent
+    /// to the peer's fallback URL if its ILP over HTTP URL has not responded yet. The first
+    /// response is used. Disabled by default
+    #[serde(default)]
+    pub hedge_delay: Option<u64>,
+    /// Maximum number of times a request which failed without any response from the peer
+    /// is retried, on the account's next alternate URL if it has any. Packets which are not
+    /// safe to deliver twice are only retried if the connection to the peer could not be
+    /// established. Defaults to 2
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Time, in milliseconds, before the first retry. It is doubled on each of the next ones
+    /// and requests are not retried past the expiry of their Prepare. Defaults to 50
+    #[serde(default = "default_retry_backoff")]
+    pub retry_backoff: u64,
+    /// Number of consecutive failed requests to an account after which its circuit breaker
+    /// opens, rejecting the packets to the account without sending them. Disabled by default
+    #[serde(default)]
+    pub circuit_breaker_threshold: Option<u32>,
+    /// Time, in milliseconds, after which an open circuit breaker lets a trial request
+    /// through. It closes again if that request succeeds. Defaults to 30000 (30 seconds)
+    #[serde(default = "default_circuit_breaker_reset_timeout")]
+    pub circuit_breaker_reset_timeout: u64,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        HttpClientConfig {
+            max_idle_connections_per_peer: None,
+            idle_timeout: default_idle_timeout(),
+            http2_prior_knowledge: false,
+            tcp_keepalive: None,
+            hedge_delay: None,
+            max_retries: default_max_retries(),
+            retry_backoff: default_retry_backoff(),
+            circuit_breaker_threshold: None,
+            circuit_breaker_reset_timeout: default_circuit_breaker_reset_timeout(),
+        }
+    }
+}
+
+/// Returns true if delivering the packet twice is harmless, so it may be sent to two of the
+/// peer's URLs at once or retried after any transport error. This is only the case of the
+/// requests without any amount addressed to the peer itself (such as ILDCP and CCP requests).
+/// Other packets without any amount, such as STREAM packets, may still carry data which must
+/// not be processed twice.
+fn is_idempotent(prepare: &Prepare) -> bool {
+    prepare.amount() == 0 && prepare.destination().scheme() == "peer"
+}
+
+/// The `Idempotency-Key` header sent along with the hedged and retried requests, so the
+/// peers which support it can tell the copies of the packet are the same. It is derived from
+/// the whole packet, since different packets may share the same condition (such as all the
+/// ILDCP requests)
+fn idempotency_key(prepare: &Prepare) -> String {
+    let mut hasher = DefaultHasher::new();
+    prepare.as_ref().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Returns true if the request which failed with the given error may be sent again, which is
+/// the case if the peer cannot have received the packet or if receiving it twice is harmless
+fn should_retry(err: &reqwest::Error, prepare: &Prepare) -> bool {
+    !err.is_builder() && (err.is_connect() || is_idempotent(prepare))
+}
+
+/// Sends the primary request and, if it has not succeeded after `delay`, the fallback request
+/// too. Returns the first response received, or the error of the request which failed last.
+async fn send_hedged(
+    primary: RequestBuilder,
+    fallback: RequestBuilder,
+    delay: Duration,
+) -> Result<HttpResponse, reqwest::Error> {
+    let primary = Box::pin(primary.send());
+    let fallback = Box::pin(async move {
+        tokio::time::delay_for(delay).await;
+        trace!("Hedging ILP over HTTP request to the fallback URL");
+        fallback.send().await
+    });
+    match future::select(primary, fallback).await {
+        Either::Left((Ok(response), _)) | Either::Right((Ok(response), _)) => Ok(response),
+        Either::Left((Err(err), fallback)) => {
+            debug!(
+                "Error sending HTTP request, waiting for the fallback URL: {:?}",
+                err
+            );
+            fallback.await
+        }
+        Either::Right((Err(err), primary)) => {
+            debug!("Error sending HTTP request to the fallback URL: {:?}", err);
+            primary.await
+        }
+    }
+}
+
+/// Builds the HTTP client used by the HttpClientService
+fn build_client(config: &HttpClientConfig) -> Client {
+    let mut headers = HeaderMap::with_capacity(2);
+    headers.insert(
+        HeaderName::from_static("content-type"),
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    let mut builder = ClientBuilder::new()
+        .default_headers(headers)
+        .timeout(Duration::from_secs(30))
+        .pool_idle_timeout(Duration::from_millis(config.idle_timeout))
+        .tcp_keepalive(config.tcp_keepalive.map(Duration::from_millis));
+    if let Some(max_idle) = config.max_idle_connections_per_peer {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if config.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    builder.build().unwrap()
+}
 
 /// The HttpClientService implements [OutgoingService](../../interledger_service/trait.OutgoingService)
 /// for sending ILP Prepare packets over to the HTTP URL associated with the provided account
@@ -21,6 +184,15 @@ pub struct HttpClientService<S, O, A> {
     /// An HTTP client configured with a 30 second timeout by default. It is used to send the
     /// ILP over HTTP messages to the peer
     client: Client,
+    /// Time after which the packets which are safe to deliver twice are also sent to the
+    /// peer's fallback URL, if any
+    hedge_delay: Option<Duration>,
+    /// Maximum number of retries of the requests which failed without any response
+    max_retries: u32,
+    /// Time before the first retry, doubled on each of the next ones
+    retry_backoff: Duration,
+    /// Circuit breakers of the accounts the requests are sent to
+    breakers: CircuitBreakers,
     /// The store used by the client to get the node's ILP Address,
     /// used to populate the `triggered_by` field in Reject packets
     store: Arc<S>,
@@ -38,24 +210,33 @@ where
 {
     /// Constructs the HttpClientService
     pub fn new(store: S, next: O) -> Self {
-        let mut headers = HeaderMap::with_capacity(2);
-        headers.insert(
-            HeaderName::from_static("content-type"),
-            HeaderValue::from_static("application/octet-stream"),
-        );
-        let client = ClientBuilder::new()
-            .default_headers(headers)
-            .timeout(Duration::from_secs(30))
-            .build()
-            .unwrap();
-
         HttpClientService {
-            client,
+            client: build_client(&HttpClientConfig::default()),
+            hedge_delay: None,
+            max_retries: default_max_retries(),
+            retry_backoff: Duration::from_millis(default_retry_backoff()),
+            breakers: CircuitBreakers::new(
+                None,
+                Duration::from_millis(default_circuit_breaker_reset_timeout()),
+            ),
             store: Arc::new(store),
             next,
             account_type: PhantomData,
         }
     }
+
+    /// Replaces the HTTP client with one using the given connection settings
+    pub fn with_config(mut self, config: &HttpClientConfig) -> Self {
+        self.client = build_client(config);
+        self.hedge_delay = config.hedge_delay.map(Duration::from_millis);
+        self.max_retries = config.max_retries;
+        self.retry_backoff = Duration::from_millis(config.retry_backoff);
+        self.breakers = CircuitBreakers::new(
+            config.circuit_breaker_threshold,
+            Duration::from_millis(config.circuit_breaker_reset_timeout),
+        );
+        self
+    }
 }
 
 #[async_trait]
@@ -68,46 +249,118 @@ where
     /// Send an OutgoingRequest to a peer that implements the ILP-Over-HTTP.
     async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
         let ilp_address = self.store.get_ilp_address();
-        let ilp_address_clone = ilp_address.clone();
-        let self_clone = self.clone();
         if let Some(url) = request.to.get_http_url() {
-            trace!(
-                "Sending outgoing ILP over HTTP packet to account: {} (URL: {})",
-                request.to.id(),
-                url.as_str()
-            );
+            let account_id = request.to.id();
+            if !self.breakers.try_acquire(account_id) {
+                debug!(
+                    "Circuit breaker of account {} is open, rejecting packet without sending it",
+                    account_id
+                );
+                return Err(RejectBuilder {
+                    code: ErrorCode::T01_PEER_UNREACHABLE,
+                    message: b"Peer's ILP over HTTP endpoint is failing",
+                    triggered_by: Some(&ilp_address),
+                    data: &[],
+                }
+                .build());
+            }
+
             let token = request
                 .to
                 .get_http_auth_token()
                 .unwrap_or_else(|| SecretString::new("".to_owned()));
             let header = format!("Bearer {}", token.expose_secret());
             let body = request.prepare.as_ref().to_owned();
-            let resp = self_clone
-                .client
-                .post(url.as_ref())
-                .header("authorization", &header)
-                .body(body)
-                .send()
-                .map_err(move |err| {
-                    error!("Error sending HTTP request: {:?}", err);
-                    let mut code = ErrorCode::T01_PEER_UNREACHABLE;
-                    if let Some(status) = err.status() {
-                        if status.is_client_error() {
-                            code = ErrorCode::F00_BAD_REQUEST
-                        }
-                    };
-
-                    let message = format!("Error sending ILP over HTTP request: {}", err);
-                    RejectBuilder {
-                        code,
-                        message: message.as_bytes(),
-                        triggered_by: Some(&ilp_address),
-                        data: &[],
+            let key = idempotency_key(&request.prepare);
+            let urls: Vec<&Url> = iter::once(url)
+                .chain(request.to.get_http_alternate_urls())
+                .collect();
+            let mut backoff = self.retry_backoff;
+            let mut attempt = 0;
+            let resp = loop {
+                let url = urls[attempt as usize % urls.len()];
+                trace!(
+                    "Sending outgoing ILP over HTTP packet to account: {} (URL: {})",
+                    account_id,
+                    url.as_str()
+                );
+                let primary = self
+                    .client
+                    .post(url.as_ref())
+                    .header("authorization", &header);
+                let fallback = match (self.hedge_delay, request.to.get_http_fallback_url()) {
+                    (Some(delay), Some(fallback_url))
+                        if attempt == 0 && is_idempotent(&request.prepare) =>
+                    {
+                        Some((delay, fallback_url))
+                    }
+                    _ => None,
+                };
+                let result = if let Some((delay, fallback_url)) = fallback {
+                    let fallback = self
+                        .client
+                        .post(fallback_url.as_ref())
+                        .header("authorization", &header)
+                        .header("idempotency-key", &key)
+                        .body(body.clone());
+                    let primary = primary.header("idempotency-key", &key).body(body.clone());
+                    send_hedged(primary, fallback, delay).await
+                } else if attempt > 0 {
+                    primary
+                        .header("idempotency-key", &key)
+                        .body(body.clone())
+                        .send()
+                        .await
+                } else {
+                    primary.body(body.clone()).send().await
+                };
+
+                match result {
+                    Err(err)
+                        if attempt < self.max_retries
+                            && should_retry(&err, &request.prepare)
+                            && SystemTime::now() + backoff < request.prepare.expires_at() =>
+                    {
+                        debug!(
+                            "Error sending HTTP request to {}, retrying in {:?}: {:?}",
+                            url.as_str(),
+                            backoff,
+                            err
+                        );
+                        tokio::time::delay_for(backoff).await;
+                        backoff *= 2;
+                        attempt += 1;
+                    }
+                    result => break result,
+                }
+            };
+
+            match resp {
+                Ok(ref resp) if !resp.status().is_server_error() => {
+                    self.breakers.record_success(account_id)
+                }
+                _ => self.breakers.record_failure(account_id),
+            }
+            let ilp_address_clone = ilp_address.clone();
+            let resp = resp.map_err(move |err| {
+                error!("Error sending HTTP request: {:?}", err);
+                let mut code = ErrorCode::T01_PEER_UNREACHABLE;
+                if let Some(status) = err.status() {
+                    if status.is_client_error() {
+                        code = ErrorCode::F00_BAD_REQUEST
                     }
-                    .build()
-                })
-                .await?;
-            parse_packet_from_response(resp, ilp_address_clone).await
+                };
+
+                let message = format!("Error sending ILP over HTTP request: {}", err);
+                RejectBuilder {
+                    code,
+                    message: message.as_bytes(),
+                    triggered_by: Some(&ilp_address_clone),
+                    data: &[],
+                }
+                .build()
+            })?;
+            parse_packet_from_response(resp, ilp_address).await
         } else {
             self.next.send_request(request).await
         }
@@ -158,7 +411,7 @@ async fn parse_packet_from_response(response: HttpResponse, ilp_address: Address
         })
         .await?;
 
-    let body = body.into_iter().collect::<BytesMut>();
+    let body = pool::copy_from_slice(&body);
     match Packet::try_from(body) {
         Ok(Packet::Fulfill(fulfill)) => Ok(fulfill),
         Ok(Packet::Reject(reject)) => Err(reject),
@@ -171,3 +424,164 @@ async fn parse_packet_from_response(response: HttpResponse, ilp_address: Address
         .build()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use warp::Filter;
+
+    #[test]
+    fn deserializes_config_with_defaults() {
+        let config: HttpClientConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config, HttpClientConfig::default());
+
+        let config: HttpClientConfig = serde_json::from_str(
+            r#"{"max_idle_connections_per_peer": 8, "idle_timeout": 5000, "http2_prior_knowledge": true, "tcp_keepalive": 30000, "hedge_delay": 200, "max_retries": 0, "retry_backoff": 10, "circuit_breaker_threshold": 5, "circuit_breaker_reset_timeout": 1000}"#,
+        )
+        .unwrap();
+        assert_eq!(config.max_idle_connections_per_peer, Some(8));
+        assert_eq!(config.idle_timeout, 5000);
+        assert!(config.http2_prior_knowledge);
+        assert_eq!(config.tcp_keepalive, Some(30000));
+        assert_eq!(config.hedge_delay, Some(200));
+        assert_eq!(config.max_retries, 0);
+        assert_eq!(config.retry_backoff, 10);
+        assert_eq!(config.circuit_breaker_threshold, Some(5));
+        assert_eq!(config.circuit_breaker_reset_timeout, 1000);
+    }
+
+    #[test]
+    fn only_hedges_peer_requests_without_amount() {
+        let prepare = |destination, amount, data: &[u8]| {
+            interledger_packet::PrepareBuilder {
+                destination: Address::from_str(destination).unwrap(),
+                amount,
+                expires_at: std::time::SystemTime::UNIX_EPOCH,
+                execution_condition: &[1; 32],
+                data,
+            }
+            .build()
+        };
+        assert!(is_idempotent(&prepare("peer.config", 0, &[])));
+        assert!(!is_idempotent(&prepare("peer.config", 1, &[])));
+        // STREAM packets may carry data even without any amount
+        assert!(!is_idempotent(&prepare("example.destination", 0, &[])));
+
+        let key = idempotency_key(&prepare("peer.config", 0, &[]));
+        assert_eq!(key.len(), 16);
+        assert_eq!(key, idempotency_key(&prepare("peer.config", 0, &[])));
+        // Packets with the same condition do not share the same key
+        assert_ne!(key, idempotency_key(&prepare("peer.config", 0, &[1])));
+        assert_ne!(key, idempotency_key(&prepare("peer.route.control", 0, &[])));
+    }
+
+    #[tokio::test]
+    async fn only_retries_packets_with_amount_if_they_were_not_sent() {
+        let prepare = |amount| {
+            interledger_packet::PrepareBuilder {
+                destination: Address::from_str("peer.config").unwrap(),
+                amount,
+                expires_at: std::time::SystemTime::now(),
+                execution_condition: &[1; 32],
+                data: &[],
+            }
+            .build()
+        };
+        // Nothing listens on port 1
+        let err = build_client(&HttpClientConfig::default())
+            .post("http://127.0.0.1:1/ilp")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(should_retry(&err, &prepare(0)));
+        assert!(should_retry(&err, &prepare(1)));
+
+        let (addr, server) = warp::serve(warp::post().and_then(|| async {
+            tokio::time::delay_for(Duration::from_secs(5)).await;
+            Ok::<_, warp::Rejection>("slow")
+        }))
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let err = Client::builder()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap()
+            .post(&format!("http://{}/ilp", addr))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(should_retry(&err, &prepare(0)));
+        assert!(!should_retry(&err, &prepare(1)));
+    }
+
+    #[tokio::test]
+    async fn hedged_request_uses_the_first_response() {
+        let (slow_addr, slow_server) = warp::serve(warp::post().and_then(|| async {
+            tokio::time::delay_for(Duration::from_secs(5)).await;
+            Ok::<_, warp::Rejection>("slow")
+        }))
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(slow_server);
+        let (fast_addr, fast_server) =
+            warp::serve(warp::post().map(|| "fast")).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(fast_server);
+
+        let client = build_client(&HttpClientConfig::default());
+        let response = send_hedged(
+            client.post(&format!("http://{}/ilp", slow_addr)),
+            client.post(&format!("http://{}/ilp", fast_addr)),
+            Duration::from_millis(50),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.text().await.unwrap(), "fast");
+
+        // the fallback is only sent after the delay
+        let response = send_hedged(
+            client.post(&format!("http://{}/ilp", fast_addr)),
+            client.post(&format!("http://{}/ilp", slow_addr)),
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.text().await.unwrap(), "fast");
+    }
+
+    #[tokio::test]
+    async fn hedged_request_falls_back_if_the_first_url_fails() {
+        let (addr, server) =
+            warp::serve(warp::post().map(|| "ok")).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let client = build_client(&HttpClientConfig::default());
+        // Nothing listens on port 1
+        let response = send_hedged(
+            client.post("http://127.0.0.1:1/ilp"),
+            client.post(&format!("http://{}/ilp", addr)),
+            Duration::from_millis(10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn sends_requests_over_http2_with_prior_knowledge() {
+        let (addr, server) =
+            warp::serve(warp::post().map(|| "ok")).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let url = format!("http://{}/ilp", addr);
+
+        let client = build_client(&HttpClientConfig {
+            http2_prior_knowledge: true,
+            ..Default::default()
+        });
+        let response = client.post(&url).body("").send().await.unwrap();
+        assert_eq!(response.version(), http::Version::HTTP_2);
+
+        let client = build_client(&HttpClientConfig::default());
+        let response = client.post(&url).body("").send().await.unwrap();
+        assert_eq!(response.version(), http::Version::HTTP_11);
+    }
+}
@@ -12,18 +12,33 @@ use serde::de::DeserializeOwned;
 use url::Url;
 use warp::{self, Filter, Rejection};
 
+/// Per-account circuit breakers of the ILP over HTTP client
+mod circuit_breaker;
 /// [ILP over HTTP](https://interledger.org/rfcs/0035-ilp-over-http/) Outgoing Service
 mod client;
 /// [ILP over HTTP](https://interledger.org/rfcs/0035-ilp-over-http/) API (implemented with [Warp](https://docs.rs/warp/0.2.0/warp/))
 mod server;
+/// ILP over WebSocket: the packets of ILP over HTTP peers sent over a single WebSocket connection
+mod websocket;
 
-pub use self::client::HttpClientService;
-pub use self::server::HttpServer;
+pub use self::client::{HttpClientConfig, HttpClientService};
+pub use self::server::{bind, bind_socket, serve, serve_listener, HttpServer, HttpServerConfig};
+pub use self::websocket::{WsClientService, WsConnections, WsServer};
 
 /// Extension trait for [Account](../interledger_service/trait.Account.html) with [ILP over HTTP](https://interledger.org/rfcs/0035-ilp-over-http/) related information
 pub trait HttpAccount: Account {
     /// Returns the HTTP URL corresponding to this account
     fn get_http_url(&self) -> Option<&Url>;
+    /// Returns a second HTTP URL of the peer, to which the requests which are safe to
+    /// deliver twice may be sent if the first URL is slow to respond
+    fn get_http_fallback_url(&self) -> Option<&Url> {
+        None
+    }
+    /// Returns the other HTTP URLs of the peer, to which the requests are retried in turn
+    /// if sending them to the first URL fails
+    fn get_http_alternate_urls(&self) -> &[Url] {
+        &[]
+    }
     /// Returns the HTTP token which is sent as an HTTP header on each ILP over HTTP request
     fn get_http_auth_token(&self) -> Option<SecretString>;
 }
@@ -1,13 +1,19 @@
 use super::HttpStore;
 use bytes::{Bytes, BytesMut};
+use futures::Future;
+use hyper::service::make_service_fn;
 use interledger_errors::ApiError;
-use interledger_packet::Prepare;
+use interledger_packet::{pool, Prepare};
 use interledger_service::Username;
 use interledger_service::{IncomingRequest, IncomingService};
 use secrecy::{ExposeSecret, SecretString};
-use std::convert::TryFrom;
-use std::net::SocketAddr;
-use tracing::error;
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::convert::{Infallible, TryFrom};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+use tracing::{debug, error};
 use warp::{Filter, Rejection};
 
 /// Max message size that is allowed to transfer from a request or a message.
@@ -16,6 +22,189 @@ pub const MAX_PACKET_SIZE: u64 = 40000;
 /// e.g. in `token = "Bearer: MyAuthToken"`, `MyAuthToken` can be taken via token[BEARER_TOKEN_START..]
 pub const BEARER_TOKEN_START: usize = 7;
 
+fn default_http2_keep_alive_timeout() -> u64 {
+    20_000
+}
+
+fn default_http1_keep_alive() -> bool {
+    true
+}
+
+/// Connection settings of the HTTP server which accepts ILP over HTTP requests.
+///
+/// The server accepts both HTTP/1.1 and HTTP/2 with prior knowledge on the same port, so
+/// peers sending many packets per second can multiplex their requests over a single
+/// connection instead of waiting for each response.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct HttpServerConfig {
+    /// Interval, in milliseconds, at which HTTP/2 pings are sent to keep the connections to
+    /// peers alive and detect the dead ones. No pings are sent by default
+    #[serde(default)]
+    pub http2_keep_alive_interval: Option<u64>,
+    /// Time, in milliseconds, to wait for the response to an HTTP/2 ping before closing the
+    /// connection. Defaults to 20000 (20 seconds)
+    #[serde(default = "default_http2_keep_alive_timeout")]
+    pub http2_keep_alive_timeout: u64,
+    /// Maximum number of concurrent requests each peer may send over a single
+    /// HTTP/2 connection. Unlimited by default
+    #[serde(default)]
+    pub http2_max_concurrent_streams: Option<u32>,
+    /// Whether HTTP/1.1 connections are kept open between requests. Defaults to true
+    #[serde(default = "default_http1_keep_alive")]
+    pub http1_keep_alive: bool,
+    /// Interval, in milliseconds, of the TCP keep-alive probes. Disabled by default
+    #[serde(default)]
+    pub tcp_keepalive: Option<u64>,
+    /// Whether the listener socket is bound with `SO_REUSEPORT` (on Unix), so a new process
+    /// can bind the same address while the old one is still draining its connections.
+    /// Disabled by default
+    #[serde(default)]
+    pub reuse_port: bool,
+    /// Time, in milliseconds, that a server asked to shut down keeps the connections which
+    /// were upgraded to WebSockets (such as BTP) open after it stopped accepting new
+    /// connections and answered the in-flight requests. Defaults to 0
+    #[serde(default)]
+    pub shutdown_grace_period: u64,
+}
+
+impl Default for HttpServerConfig {
+    fn default() -> Self {
+        HttpServerConfig {
+            http2_keep_alive_interval: None,
+            http2_keep_alive_timeout: default_http2_keep_alive_timeout(),
+            http2_max_concurrent_streams: None,
+            http1_keep_alive: default_http1_keep_alive(),
+            tcp_keepalive: None,
+            reuse_port: false,
+            shutdown_grace_period: 0,
+        }
+    }
+}
+
+/// The first file descriptor passed by a service manager using the systemd socket
+/// activation protocol
+#[cfg(unix)]
+const LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+/// Takes the listener socket passed to this process with the systemd socket activation
+/// protocol (`LISTEN_PID` and `LISTEN_FDS`), if any. The socket can only be taken once.
+#[cfg(unix)]
+fn take_activated_listener() -> Option<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != std::process::id() {
+        return None;
+    }
+    let fds: u32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    if fds == 0 {
+        return None;
+    }
+    // Safe because the service manager handed the descriptor over to this process and
+    // the variables were removed, so it is not wrapped twice
+    Some(unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+#[cfg(not(unix))]
+fn take_activated_listener() -> Option<TcpListener> {
+    None
+}
+
+/// Returns the listener socket of the HTTP server.
+///
+/// If the process was started with socket activation, the socket passed by the service
+/// manager is used instead of binding a new one to `addr`. Otherwise, the socket is
+/// bound with `SO_REUSEPORT` if enabled in the config, so a new version of the node can
+/// start accepting connections before the old one shuts down.
+pub fn bind(addr: SocketAddr, config: &HttpServerConfig) -> io::Result<TcpListener> {
+    let listener = match take_activated_listener() {
+        Some(listener) => {
+            debug!(
+                "Using the listener passed with socket activation: {:?}",
+                listener.local_addr()
+            );
+            listener
+        }
+        None => bind_socket(addr, config.reuse_port)?,
+    };
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Binds a new listener socket to `addr`, with `SO_REUSEPORT` (on Unix) if `reuse_port` is
+/// set. Unlike `bind`, this never takes the socket passed with socket activation, so it is
+/// meant for the listeners other than the HTTP server's (such as the settlement API's).
+pub fn bind_socket(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let domain = if addr.is_ipv4() {
+        Domain::ipv4()
+    } else {
+        Domain::ipv6()
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    // Same as the std and tokio listeners (on Windows, this would allow stealing the address)
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    if reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    let listener = socket.into_tcp_listener();
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Serves the filter on the given address with the given connection settings.
+///
+/// Unlike `warp::serve`, the filters do not have access to the remote address of the requests.
+pub async fn serve<F>(filter: F, addr: SocketAddr, config: &HttpServerConfig) -> io::Result<()>
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply,
+{
+    let listener = bind(addr, config)?;
+    serve_listener(filter, listener, config, futures::future::pending()).await
+}
+
+/// Serves the filter on the given listener with the given connection settings, until
+/// `shutdown` resolves.
+///
+/// Once `shutdown` resolves, the listener is closed and the connections are closed as soon
+/// as their in-flight requests were answered, so another process bound to the same address
+/// takes over without dropping any request. Connections upgraded to WebSockets are not
+/// tracked by the server and stay open until the process exits.
+pub async fn serve_listener<F, S>(
+    filter: F,
+    listener: TcpListener,
+    config: &HttpServerConfig,
+    shutdown: S,
+) -> io::Result<()>
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: warp::Reply,
+    S: Future<Output = ()>,
+{
+    let service = warp::service(filter);
+    let make_service = make_service_fn(move |_| {
+        let service = service.clone();
+        async move { Ok::<_, Infallible>(service) }
+    });
+    hyper::Server::from_tcp(listener)
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
+        .http1_keepalive(config.http1_keep_alive)
+        .http2_keep_alive_interval(config.http2_keep_alive_interval.map(Duration::from_millis))
+        .http2_keep_alive_timeout(Duration::from_millis(config.http2_keep_alive_timeout))
+        .http2_max_concurrent_streams(config.http2_max_concurrent_streams)
+        .tcp_keepalive(config.tcp_keepalive.map(Duration::from_millis))
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+}
+
 /// A warp filter that parses incoming ILP-Over-HTTP requests, validates the authorization,
 /// and passes the request to an IncomingService handler.
 #[derive(Clone)]
@@ -30,7 +219,7 @@ pub struct HttpServer<I, S> {
 /// Returns the account which matches the provided username/password combination
 /// from the store, or returns an error if the account was not found or if the
 /// credentials were incorrect
-async fn get_account<S>(
+pub(crate) async fn get_account<S>(
     store: S,
     path_username: &Username,
     password: &SecretString,
@@ -73,7 +262,7 @@ where
 {
     let account = get_account(store, &path_username, &password).await?;
 
-    let buffer = bytes::BytesMut::from(body.as_ref());
+    let buffer = pool::copy_from_slice(body.as_ref());
     if let Ok(prepare) = Prepare::try_from(buffer) {
         let result = incoming
             .handle_request(IncomingRequest {
@@ -142,6 +331,7 @@ mod tests {
     use crate::HttpAccount;
     use async_trait::async_trait;
     use bytes::BytesMut;
+    use futures::FutureExt;
     use http::Response;
     use interledger_errors::{default_rejection_handler, HttpStoreError};
     use interledger_packet::{Address, ErrorCode, PrepareBuilder, RejectBuilder};
@@ -190,6 +380,100 @@ mod tests {
             .await
     }
 
+    #[test]
+    fn deserializes_server_config_with_defaults() {
+        let config: HttpServerConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config, HttpServerConfig::default());
+
+        let config: HttpServerConfig = serde_json::from_str(
+            r#"{"http2_keep_alive_interval": 10000, "http2_max_concurrent_streams": 100, "http1_keep_alive": false}"#,
+        )
+        .unwrap();
+        assert_eq!(config.http2_keep_alive_interval, Some(10000));
+        assert_eq!(config.http2_keep_alive_timeout, 20000);
+        assert_eq!(config.http2_max_concurrent_streams, Some(100));
+        assert!(!config.http1_keep_alive);
+        assert!(!config.reuse_port);
+        assert_eq!(config.shutdown_grace_period, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn binds_the_same_address_twice_with_reuse_port() {
+        let config = HttpServerConfig {
+            reuse_port: true,
+            ..Default::default()
+        };
+        let first = bind("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind(addr, &config).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+
+        // without the option the address is taken
+        assert!(bind(addr, &HttpServerConfig::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn stops_accepting_connections_on_shutdown() {
+        let config = HttpServerConfig::default();
+        let listener = bind("127.0.0.1:0".parse().unwrap(), &config).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = futures::channel::oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            serve_listener(
+                warp::post().map(|| "ok").boxed(),
+                listener,
+                &config,
+                shutdown_rx.map(|_| ()),
+            )
+            .await
+        });
+        let url = format!("http://{}/ilp", addr);
+
+        let response = reqwest::Client::new().post(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(reqwest::Client::new().post(&url).send().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_http1_and_http2_with_prior_knowledge() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = HttpServerConfig {
+            http2_keep_alive_interval: Some(1000),
+            http2_max_concurrent_streams: Some(10),
+            ..Default::default()
+        };
+        tokio::spawn(async move {
+            serve(warp::post().map(|| "ok").boxed(), addr, &config)
+                .await
+                .unwrap()
+        });
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        let url = format!("http://{}/ilp", addr);
+
+        let client = reqwest::Client::builder()
+            .http2_prior_knowledge()
+            .build()
+            .unwrap();
+        let response = client.post(&url).body("").send().await.unwrap();
+        assert_eq!(response.version(), http::Version::HTTP_2);
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        let response = reqwest::Client::new()
+            .post(&url)
+            .body("")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.version(), http::Version::HTTP_11);
+    }
+
     #[tokio::test]
     async fn new_api_test() {
         let store = TestStore;
@@ -0,0 +1,740 @@
+use super::{server::get_account, HttpAccount, HttpStore};
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use futures::{
+    channel::{
+        mpsc::{unbounded, UnboundedSender},
+        oneshot,
+    },
+    lock::Mutex as AsyncMutex,
+    FutureExt, StreamExt,
+};
+use interledger_packet::{
+    oer::{BufOerExt, MutBufOerExt},
+    pool, Address, ErrorCode, Packet, Prepare, Reject, RejectBuilder,
+};
+use interledger_service::*;
+use parking_lot::Mutex;
+use secrecy::{ExposeSecret, SecretString};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+use tokio_tungstenite::{connect_async, tungstenite};
+use tracing::{debug, error, trace, warn};
+use url::Url;
+use uuid::Uuid;
+use warp::{
+    ws::{Message, WebSocket, Ws},
+    Filter, Rejection,
+};
+
+/// Max size of the messages accepted on an ILP over WebSocket connection
+const MAX_MESSAGE_SIZE: usize = 40000;
+
+/// Returns true if the packets to the account's ILP over HTTP URL should be sent over a
+/// WebSocket connection instead of one HTTP request each
+fn is_websocket_url(url: &Url) -> bool {
+    url.scheme() == "ws" || url.scheme() == "wss"
+}
+
+/// Each WebSocket message carries one packet, prefixed with the id of the request it belongs
+/// to and the length of the packet: `request_id (u32) | length (var uint) | ILP packet`.
+/// The response to a Prepare is sent back with the same request id.
+fn encode_frame(request_id: u32, packet: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + 9 + packet.len());
+    frame.put_u32(request_id);
+    frame.put_var_octet_string(packet);
+    frame
+}
+
+/// Returns the request id and the packet of the frame, or None if it is malformed
+fn decode_frame(mut frame: &[u8]) -> Option<(u32, &[u8])> {
+    let request_id = frame.read_u32().ok()?;
+    let packet = frame.read_var_octet_string().ok()?;
+    if frame.is_empty() {
+        Some((request_id, packet))
+    } else {
+        None
+    }
+}
+
+/// A warp filter which accepts [ILP over HTTP](https://interledger.org/rfcs/0035-ilp-over-http/)
+/// peers connecting over a WebSocket instead of sending one HTTP request per packet, and passes
+/// the Prepare packets they send to an IncomingService handler.
+///
+/// The peers authenticate with the same `Authorization: Bearer` token as ILP over HTTP requests.
+#[derive(Clone)]
+pub struct WsServer<I, S> {
+    /// The next [incoming service](../interledger_service/trait.IncomingService.html)
+    incoming: I,
+    /// A store which implements [`HttpStore`](trait.HttpStore.html)
+    store: S,
+    /// The connections the accounts opened, so that they can be closed with the other ones
+    connections: WsConnections,
+}
+
+impl<I, S> WsServer<I, S>
+where
+    I: IncomingService<S::Account> + Clone + Send + Sync + 'static,
+    S: HttpStore + Clone,
+    S::Account: Clone + Send + Sync + 'static,
+{
+    pub fn new(incoming: I, store: S) -> Self {
+        WsServer {
+            incoming,
+            store,
+            connections: WsConnections::default(),
+        }
+    }
+
+    /// Tracks the connections the accounts open in the given handle, such as the one of the
+    /// [`WsClientService`](./struct.WsClientService.html), so that
+    /// [`close_all`](./struct.WsConnections.html#method.close_all) also closes them
+    pub fn with_connections(mut self, connections: WsConnections) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Returns a Warp filter which accepts the WebSocket connections of the accounts.
+    /// The endpoint is /accounts/:username/ilp/ws.
+    pub fn as_filter(
+        &self,
+    ) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+        let store = self.store.clone();
+        let incoming = self.incoming.clone();
+        let connections = self.connections.clone();
+        let with_store = warp::any().map(move || store.clone());
+        let with_incoming = warp::any().map(move || (incoming.clone(), connections.clone()));
+        warp::path("accounts")
+            .and(warp::path::param::<Username>())
+            .and(warp::path("ilp"))
+            .and(warp::path("ws"))
+            .and(warp::path::end())
+            .and(warp::header::<SecretString>("authorization"))
+            .and(with_store)
+            .and_then(
+                |username: Username, password: SecretString, store: S| async move {
+                    get_account(store, &username, &password)
+                        .await
+                        .map_err(Rejection::from)
+                },
+            )
+            .and(warp::ws())
+            .and(with_incoming)
+            .map(
+                |account: S::Account, ws: Ws, (incoming, connections): (I, WsConnections)| {
+                    ws.max_message_size(MAX_MESSAGE_SIZE)
+                        .on_upgrade(move |socket| {
+                            serve_connection(socket, account, incoming, connections)
+                        })
+                },
+            )
+    }
+}
+
+/// Handles the Prepare packets sent over the connection concurrently and sends back their
+/// responses, until the peer closes it or it is closed with the other connections
+async fn serve_connection<I, A>(
+    socket: WebSocket,
+    account: A,
+    incoming: I,
+    connections: WsConnections,
+) where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Clone + Send + Sync + 'static,
+{
+    debug!(
+        "Account {} connected over ILP over WebSocket",
+        account.username()
+    );
+    let (write, mut read) = socket.split();
+    let (sender, receiver) = unbounded();
+    tokio::spawn(receiver.map(Ok).forward(write).map(|_| ()));
+    let session_id = connections.add_session(sender.clone());
+
+    while let Some(message) = read.next().await {
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                debug!("Error reading ILP over WebSocket message: {:?}", err);
+                break;
+            }
+        };
+        if message.is_close() {
+            break;
+        } else if !message.is_binary() {
+            continue;
+        }
+
+        let parsed = decode_frame(message.as_bytes()).and_then(|(request_id, packet)| {
+            Prepare::try_from(pool::copy_from_slice(packet))
+                .ok()
+                .map(|prepare| (request_id, prepare))
+        });
+        let (request_id, prepare) = match parsed {
+            Some(parsed) => parsed,
+            None => {
+                warn!(
+                    "Ignoring ILP over WebSocket message from account {} which does not contain a valid Prepare packet",
+                    account.username()
+                );
+                continue;
+            }
+        };
+
+        let mut incoming = incoming.clone();
+        let from = account.clone();
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            let response: BytesMut = match incoming
+                .handle_request(IncomingRequest { from, prepare })
+                .await
+            {
+                Ok(fulfill) => fulfill.into(),
+                Err(reject) => reject.into(),
+            };
+            // The peer may have closed the connection in the meantime
+            let _ = sender.unbounded_send(Message::binary(encode_frame(request_id, &response)));
+        });
+    }
+
+    connections.remove_session(session_id);
+    debug!(
+        "Account {} closed its ILP over WebSocket connection",
+        account.username()
+    );
+}
+
+/// Open WebSocket connection to a peer, along with the requests waiting for their responses
+#[derive(Clone)]
+struct WsConnection {
+    sender: UnboundedSender<tungstenite::Message>,
+    pending: Arc<Mutex<HashMap<u32, oneshot::Sender<Packet>>>>,
+    next_request_id: Arc<AtomicU32>,
+}
+
+impl WsConnection {
+    /// Connects to the URL, authenticating with the token, and starts handling the responses
+    async fn connect(url: &Url, token: Option<SecretString>) -> Result<Self, ()> {
+        let mut request = http::Request::builder().uri(url.as_str());
+        if let Some(token) = token {
+            request = request.header("authorization", format!("Bearer {}", token.expose_secret()));
+        }
+        let request = request.body(()).map_err(|err| {
+            error!("Invalid ILP over WebSocket request to {}: {:?}", url, err);
+        })?;
+        let (socket, _) = connect_async(request).await.map_err(|err| {
+            error!(
+                "Error connecting to ILP over WebSocket URL {}: {:?}",
+                url, err
+            );
+        })?;
+
+        let (write, mut read) = socket.split();
+        let (sender, receiver) = unbounded();
+        let connection = WsConnection {
+            sender,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            next_request_id: Arc::new(AtomicU32::new(0)),
+        };
+        tokio::spawn(receiver.map(Ok).forward(write).map(|_| ()));
+        tokio::spawn({
+            let connection = connection.clone();
+            let url = url.clone();
+            async move {
+                while let Some(Ok(message)) = read.next().await {
+                    let data = match message {
+                        tungstenite::Message::Binary(data) => data,
+                        tungstenite::Message::Close(_) => break,
+                        _ => continue,
+                    };
+                    let parsed = decode_frame(&data).and_then(|(request_id, packet)| {
+                        Packet::try_from(pool::copy_from_slice(packet))
+                            .ok()
+                            .map(|packet| (request_id, packet))
+                    });
+                    match parsed {
+                        Some((request_id, packet)) => {
+                            let waiting = connection.pending.lock().remove(&request_id);
+                            if let Some(waiting) = waiting {
+                                let _ = waiting.send(packet);
+                            }
+                        }
+                        None => warn!(
+                            "Ignoring ILP over WebSocket message from {} which does not contain a valid packet",
+                            url
+                        ),
+                    }
+                }
+                debug!("ILP over WebSocket connection to {} closed", url);
+                // Fails the requests still waiting for a response
+                connection.sender.close_channel();
+                connection.pending.lock().clear();
+            }
+        });
+        Ok(connection)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Closes the connection and fails the requests still waiting for a response
+    fn close(&self) {
+        self.sender.close_channel();
+        self.pending.lock().clear();
+    }
+
+    /// Sends the Prepare and returns the id of the request and the receiver of its response
+    fn send(&self, prepare: Prepare) -> Result<(u32, oneshot::Receiver<Packet>), ()> {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().insert(request_id, sender);
+        let message = tungstenite::Message::binary(encode_frame(request_id, prepare.as_ref()));
+        if self.sender.unbounded_send(message).is_err() {
+            self.pending.lock().remove(&request_id);
+            return Err(());
+        }
+        Ok((request_id, receiver))
+    }
+}
+
+/// The connection to an account, which is locked while connecting so that only one of the
+/// packets sent to the account at the same time opens it
+type ConnectionSlot = Arc<AsyncMutex<Option<WsConnection>>>;
+
+/// Handle to the ILP over WebSocket connections a [`WsClientService`](./struct.WsClientService.html)
+/// opened, by account id, and to the ones the accounts opened to a
+/// [`WsServer`](./struct.WsServer.html) sharing it
+#[derive(Clone, Default)]
+pub struct WsConnections {
+    slots: Arc<Mutex<HashMap<Uuid, ConnectionSlot>>>,
+    /// The senders of the responses of the connections the accounts opened, by session id
+    sessions: Arc<Mutex<HashMap<u64, UnboundedSender<Message>>>>,
+    next_session_id: Arc<AtomicU64>,
+}
+
+impl WsConnections {
+    /// Closes the connection to the account, if any, so that the next packet to it opens a new
+    /// one with the account's current URL and token. Called once the account is updated or deleted
+    pub fn close_connection(&self, account_id: &Uuid) {
+        let slot = self.slots.lock().remove(account_id);
+        if let Some(slot) = slot {
+            close_slot(*account_id, slot);
+        }
+    }
+
+    /// Closes all of the open connections, both the ones opened to the accounts and the ones
+    /// the accounts opened. Called once the node stopped handling packets
+    pub fn close_all(&self) {
+        debug!("Closing all ILP over WebSocket connections");
+        let slots: Vec<_> = self.slots.lock().drain().collect();
+        for (account_id, slot) in slots {
+            close_slot(account_id, slot);
+        }
+        // Sends a close frame once the responses queued before it were sent
+        for (_, sender) in self.sessions.lock().drain() {
+            sender.close_channel();
+        }
+    }
+
+    fn add_session(&self, sender: UnboundedSender<Message>) -> u64 {
+        let session_id = self.next_session_id.fetch_add(1, Ordering::Relaxed);
+        self.sessions.lock().insert(session_id, sender);
+        session_id
+    }
+
+    fn remove_session(&self, session_id: u64) {
+        self.sessions.lock().remove(&session_id);
+    }
+
+    fn slot(&self, account_id: Uuid) -> ConnectionSlot {
+        self.slots.lock().entry(account_id).or_default().clone()
+    }
+
+    /// Whether the connection to the account was closed since the slot was taken
+    fn is_evicted(&self, account_id: Uuid, slot: &ConnectionSlot) -> bool {
+        match self.slots.lock().get(&account_id) {
+            Some(current) => !Arc::ptr_eq(current, slot),
+            None => true,
+        }
+    }
+}
+
+/// Closes the connection of the slot, once the connection being opened, if any, is open
+fn close_slot(account_id: Uuid, slot: ConnectionSlot) {
+    tokio::spawn(async move {
+        if let Some(connection) = slot.lock().await.take() {
+            debug!(
+                "Closing the ILP over WebSocket connection to account {}",
+                account_id
+            );
+            connection.close();
+        }
+    });
+}
+
+/// The WsClientService implements [OutgoingService](../../interledger_service/trait.OutgoingService)
+/// for sending ILP Prepare packets over a WebSocket connection to the accounts whose
+/// ILP over HTTP URL is a `ws://` or `wss://` URL. The connection to each account is opened
+/// with its first packet and reused for the next ones.
+///
+/// Requests for the other accounts are forwarded to the next service.
+#[derive(Clone)]
+pub struct WsClientService<S, O, A> {
+    /// The open connections, by account id
+    connections: WsConnections,
+    /// The store used by the client to get the node's ILP Address,
+    /// used to populate the `triggered_by` field in Reject packets
+    store: Arc<S>,
+    /// The next outgoing service to which non ILP-over-WebSocket requests should
+    /// be forwarded to
+    next: O,
+    account_type: PhantomData<A>,
+}
+
+impl<S, O, A> WsClientService<S, O, A>
+where
+    S: AddressStore,
+    O: OutgoingService<A> + Clone,
+    A: HttpAccount,
+{
+    /// Constructs the WsClientService
+    pub fn new(store: S, next: O) -> Self {
+        WsClientService {
+            connections: WsConnections::default(),
+            store: Arc::new(store),
+            next,
+            account_type: PhantomData,
+        }
+    }
+
+    /// Returns a handle to the open connections, to close them once their accounts change
+    pub fn connections(&self) -> WsConnections {
+        self.connections.clone()
+    }
+
+    /// Returns the open connection to the account, connecting to it if there is none
+    async fn connection(&self, account: &A, url: &Url) -> Result<WsConnection, ()> {
+        let account_id = account.id();
+        let slot = self.connections.slot(account_id);
+        let mut current = slot.lock().await;
+        if let Some(connection) = current
+            .as_ref()
+            .filter(|connection| !connection.is_closed())
+        {
+            return Ok(connection.clone());
+        }
+        trace!(
+            "Connecting to account {} over ILP over WebSocket (URL: {})",
+            account_id,
+            url
+        );
+        let connection = WsConnection::connect(url, account.get_http_auth_token()).await?;
+        if self.connections.is_evicted(account_id, &slot) {
+            debug!(
+                "Account {} changed while connecting to it over ILP over WebSocket",
+                account_id
+            );
+            connection.close();
+            return Err(());
+        }
+        if let Some(replaced) = current.replace(connection.clone()) {
+            replaced.close();
+        }
+        Ok(connection)
+    }
+}
+
+fn reject(code: ErrorCode, message: &[u8], ilp_address: &Address) -> Reject {
+    RejectBuilder {
+        code,
+        message,
+        triggered_by: Some(ilp_address),
+        data: &[],
+    }
+    .build()
+}
+
+#[async_trait]
+impl<S, O, A> OutgoingService<A> for WsClientService<S, O, A>
+where
+    S: AddressStore + Send + Sync,
+    O: OutgoingService<A> + Clone + Sync + Send,
+    A: HttpAccount + Clone + Sync + Send,
+{
+    /// Send an OutgoingRequest to a peer over its ILP over WebSocket connection
+    async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
+        let url = match request.to.get_http_url() {
+            Some(url) if is_websocket_url(url) => url.clone(),
+            _ => return self.next.send_request(request).await,
+        };
+        let ilp_address = self.store.get_ilp_address();
+        let connection = self.connection(&request.to, &url).await.map_err(|_| {
+            reject(
+                ErrorCode::T01_PEER_UNREACHABLE,
+                b"Could not connect to the peer's ILP over WebSocket endpoint",
+                &ilp_address,
+            )
+        })?;
+
+        let expires_in = request
+            .prepare
+            .expires_at()
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        let (request_id, response) = connection.send(request.prepare).map_err(|_| {
+            reject(
+                ErrorCode::T01_PEER_UNREACHABLE,
+                b"ILP over WebSocket connection closed",
+                &ilp_address,
+            )
+        })?;
+
+        match tokio::time::timeout(expires_in, response).await {
+            Ok(Ok(Packet::Fulfill(fulfill))) => Ok(fulfill),
+            Ok(Ok(Packet::Reject(rejected))) => Err(rejected),
+            Ok(Ok(Packet::Prepare(_))) => Err(reject(
+                ErrorCode::T01_PEER_UNREACHABLE,
+                b"Peer responded with a Prepare packet",
+                &ilp_address,
+            )),
+            Ok(Err(_)) => Err(reject(
+                ErrorCode::T01_PEER_UNREACHABLE,
+                b"ILP over WebSocket connection closed",
+                &ilp_address,
+            )),
+            Err(_) => {
+                connection.pending.lock().remove(&request_id);
+                Err(reject(ErrorCode::R00_TRANSFER_TIMED_OUT, &[], &ilp_address))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_errors::HttpStoreError;
+    use interledger_packet::{FulfillBuilder, PrepareBuilder};
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    static USERNAME: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+    static ILP_ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("example.alice").unwrap());
+    const AUTH_PASSWORD: &str = "password";
+
+    #[derive(Debug, Clone)]
+    struct TestAccount;
+
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            Uuid::new_v4()
+        }
+
+        fn username(&self) -> &Username {
+            &USERNAME
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &ILP_ADDRESS
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+    }
+
+    impl HttpAccount for TestAccount {
+        fn get_http_url(&self) -> Option<&Url> {
+            None
+        }
+
+        fn get_http_auth_token(&self) -> Option<SecretString> {
+            None
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct TestStore;
+
+    #[async_trait]
+    impl HttpStore for TestStore {
+        type Account = TestAccount;
+
+        async fn get_account_from_http_auth(
+            &self,
+            username: &Username,
+            token: &str,
+        ) -> Result<Self::Account, HttpStoreError> {
+            if username == &*USERNAME && token == AUTH_PASSWORD {
+                Ok(TestAccount)
+            } else {
+                Err(HttpStoreError::Unauthorized(username.to_string()))
+            }
+        }
+    }
+
+    fn test_server() -> WsServer<impl IncomingService<TestAccount> + Clone, TestStore> {
+        let incoming = incoming_service_fn(|request| {
+            if request.prepare.amount() > 100 {
+                Err(reject(ErrorCode::F08_AMOUNT_TOO_LARGE, &[], &ILP_ADDRESS))
+            } else {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: b"fulfilled",
+                }
+                .build())
+            }
+        });
+        WsServer::new(incoming, TestStore)
+    }
+
+    fn prepare_frame(request_id: u32, amount: u64) -> Vec<u8> {
+        let prepare = PrepareBuilder {
+            amount,
+            destination: ILP_ADDRESS.clone(),
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+            execution_condition: &[0; 32],
+            data: &[],
+        }
+        .build();
+        encode_frame(request_id, prepare.as_ref())
+    }
+
+    #[test]
+    fn decodes_encoded_frames() {
+        let frame = encode_frame(7, b"packet");
+        assert_eq!(frame[..4], [0, 0, 0, 7]);
+        assert_eq!(decode_frame(&frame), Some((7, &b"packet"[..])));
+
+        // Truncated packet
+        assert_eq!(decode_frame(&frame[..frame.len() - 1]), None);
+        // Trailing bytes
+        let mut frame = frame;
+        frame.push(0);
+        assert_eq!(decode_frame(&frame), None);
+        // No request id
+        assert_eq!(decode_frame(&[0, 0]), None);
+    }
+
+    #[test]
+    fn only_sends_ws_urls_over_websockets() {
+        assert!(is_websocket_url(
+            &Url::parse("ws://localhost/ilp/ws").unwrap()
+        ));
+        assert!(is_websocket_url(
+            &Url::parse("wss://example.com/ilp/ws").unwrap()
+        ));
+        assert!(!is_websocket_url(
+            &Url::parse("https://example.com/ilp").unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    async fn closes_the_connections_of_changed_accounts() {
+        let connections = WsConnections::default();
+        let account_id = Uuid::new_v4();
+        let slot = connections.slot(account_id);
+        let (sender, _receiver) = unbounded();
+        let connection = WsConnection {
+            sender,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            next_request_id: Arc::new(AtomicU32::new(0)),
+        };
+        *slot.lock().await = Some(connection.clone());
+        assert!(Arc::ptr_eq(&connections.slot(account_id), &slot));
+        assert!(!connections.is_evicted(account_id, &slot));
+
+        connections.close_connection(&account_id);
+        assert!(connections.is_evicted(account_id, &slot));
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        assert!(connection.is_closed());
+        // The next packet opens a new connection
+        assert!(!Arc::ptr_eq(&connections.slot(account_id), &slot));
+    }
+
+    #[tokio::test]
+    async fn responds_to_prepares_with_their_request_id() {
+        let filter = test_server().as_filter();
+        let mut client = warp::test::ws()
+            .path("/accounts/alice/ilp/ws")
+            .header("authorization", format!("Bearer {}", AUTH_PASSWORD))
+            .handshake(filter)
+            .await
+            .unwrap();
+
+        client.send(Message::binary(prepare_frame(1, 10))).await;
+        client.send(Message::binary(prepare_frame(2, 1000))).await;
+
+        let mut responses = HashMap::new();
+        for _ in 0..2 {
+            let message = client.recv().await.unwrap();
+            let (request_id, packet) = decode_frame(message.as_bytes()).unwrap();
+            let packet = Packet::try_from(pool::copy_from_slice(packet)).unwrap();
+            responses.insert(request_id, packet);
+        }
+        match &responses[&1] {
+            Packet::Fulfill(fulfill) => assert_eq!(fulfill.data(), b"fulfilled"),
+            packet => panic!("Expected a Fulfill, got {:?}", packet),
+        }
+        match &responses[&2] {
+            Packet::Reject(reject) => assert_eq!(reject.code(), ErrorCode::F08_AMOUNT_TOO_LARGE),
+            packet => panic!("Expected a Reject, got {:?}", packet),
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_all_connections() {
+        let connections = WsConnections::default();
+        let (sender, _receiver) = unbounded();
+        let connection = WsConnection {
+            sender,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            next_request_id: Arc::new(AtomicU32::new(0)),
+        };
+        *connections.slot(Uuid::new_v4()).lock().await = Some(connection.clone());
+
+        let filter = test_server()
+            .with_connections(connections.clone())
+            .as_filter();
+        let mut client = warp::test::ws()
+            .path("/accounts/alice/ilp/ws")
+            .header("authorization", format!("Bearer {}", AUTH_PASSWORD))
+            .handshake(filter)
+            .await
+            .unwrap();
+        // Waits for the server to start serving the connection
+        client.send(Message::binary(prepare_frame(1, 10))).await;
+        client.recv().await.unwrap();
+
+        connections.close_all();
+        assert!(client.recv_closed().await.is_ok());
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        assert!(connection.is_closed());
+        assert!(connections.sessions.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_connections_with_invalid_tokens() {
+        let filter = test_server().as_filter();
+        let result = warp::test::ws()
+            .path("/accounts/alice/ilp/ws")
+            .header("authorization", "Bearer wrong")
+            .handshake(filter)
+            .await;
+        assert!(result.is_err());
+    }
+}
@@ -12,4 +12,4 @@ mod server;
 
 pub use client::get_ildcp_info;
 pub use packet::*;
-pub use server::IldcpService;
+pub use server::{AddressAssignmentStore, DynamicIldcpService, IldcpService};
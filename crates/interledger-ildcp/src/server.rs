@@ -1,10 +1,49 @@
 use super::packet::*;
 use super::Account;
 use async_trait::async_trait;
+use interledger_errors::AddressAssignmentStoreError;
 use interledger_packet::*;
 use interledger_service::*;
 use std::marker::PhantomData;
-use tracing::debug;
+use tracing::{debug, error};
+use uuid::Uuid;
+
+/// Store trait which persists the ILP addresses a parent node dynamically
+/// assigns to its child accounts
+#[async_trait]
+pub trait AddressAssignmentStore {
+    /// Loads the address assigned to the account, if one was assigned
+    async fn get_assigned_address(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Option<Address>, AddressAssignmentStoreError>;
+
+    /// Assigns an address of the form `<parent_address>.child<N>` to the account,
+    /// where `N` is chosen such that the address is not used by any other account,
+    /// and updates the account and its route to use it.
+    ///
+    /// If the account already has an assigned address, its suffix is kept
+    /// (it is only moved under `parent_address` if that changed) and MUST be returned
+    /// instead of assigning a new one, so that calling this is idempotent.
+    async fn assign_address(
+        &self,
+        account_id: Uuid,
+        parent_address: &Address,
+    ) -> Result<Address, AddressAssignmentStoreError>;
+}
+
+fn ildcp_response<A: Account>(account: &A, ilp_address: &Address) -> Fulfill {
+    let builder = IldcpResponseBuilder {
+        ilp_address,
+        asset_code: account.asset_code(),
+        asset_scale: account.asset_scale(),
+    };
+    debug!(
+        "Responding to query for ildcp info by account: {:?}",
+        ilp_address
+    );
+    Fulfill::from(builder.build())
+}
 
 /// A simple service that intercepts incoming ILDCP requests
 /// and responds using the information in the Account struct.
@@ -35,28 +74,94 @@ where
 {
     async fn handle_request(&mut self, request: IncomingRequest<A>) -> IlpResult {
         if is_ildcp_request(&request.prepare) {
-            let from = request.from.ilp_address();
-            let builder = IldcpResponseBuilder {
-                ilp_address: &from,
-                asset_code: request.from.asset_code(),
-                asset_scale: request.from.asset_scale(),
-            };
-            debug!("Responding to query for ildcp info by account: {:?}", from);
-            let response = builder.build();
-            Ok(Fulfill::from(response))
+            Ok(ildcp_response(&request.from, request.from.ilp_address()))
         } else {
             self.next.handle_request(request).await
         }
     }
 }
 
+/// An ILDCP service for parent nodes which, instead of responding with the address
+/// configured on the account, allocates an address such as `example.parent.child123`
+/// for each child account on its first ILDCP request and persists it in the store.
+///
+/// Accounts whose address is not under the node's own address (such as parents and peers)
+/// are answered with their configured address, like the [`IldcpService`](./struct.IldcpService.html) does.
+///
+/// Requires an `AddressAssignmentStore` and an `AddressStore`
+#[derive(Clone)]
+pub struct DynamicIldcpService<S, I, A> {
+    store: S,
+    next: I,
+    account_type: PhantomData<A>,
+}
+
+impl<S, I, A> DynamicIldcpService<S, I, A>
+where
+    S: AddressAssignmentStore + AddressStore,
+    I: IncomingService<A>,
+    A: Account,
+{
+    pub fn new(store: S, next: I) -> Self {
+        DynamicIldcpService {
+            store,
+            next,
+            account_type: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<S, I, A> IncomingService<A> for DynamicIldcpService<S, I, A>
+where
+    S: AddressAssignmentStore + AddressStore + Send + Sync + 'static,
+    I: IncomingService<A> + Send,
+    A: Account,
+{
+    async fn handle_request(&mut self, request: IncomingRequest<A>) -> IlpResult {
+        if !is_ildcp_request(&request.prepare) {
+            return self.next.handle_request(request).await;
+        }
+
+        let node_address = self.store.get_ilp_address();
+        let is_child = request
+            .from
+            .ilp_address()
+            .starts_with(&format!("{}.", node_address));
+        if !is_child {
+            return Ok(ildcp_response(&request.from, request.from.ilp_address()));
+        }
+
+        let account_id = request.from.id();
+        match self.store.assign_address(account_id, &node_address).await {
+            Ok(ilp_address) => Ok(ildcp_response(&request.from, &ilp_address)),
+            Err(err) => {
+                error!(
+                    "Error assigning an address to account {}: {}",
+                    account_id, err
+                );
+                Err(RejectBuilder {
+                    code: ErrorCode::T00_INTERNAL_ERROR,
+                    message: b"Error assigning ILP address",
+                    triggered_by: Some(&node_address),
+                    data: &[],
+                }
+                .build())
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::get_ildcp_info;
+    use interledger_errors::AddressStoreError;
     use once_cell::sync::Lazy;
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
     use std::str::FromStr;
-    use uuid::Uuid;
+    use std::sync::Arc;
 
     pub static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
     pub static EXAMPLE_ADDRESS: Lazy<Address> =
@@ -110,4 +215,145 @@ mod tests {
         assert_eq!(ildpc_info.asset_code(), b"XYZ");
         assert_eq!(ildpc_info.asset_scale(), 9);
     }
+
+    static NODE_ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("example.parent").unwrap());
+    static CHILD_ADDRESS: Lazy<Address> =
+        Lazy::new(|| Address::from_str("example.parent.alice").unwrap());
+    static CHILD_ID: Lazy<Uuid> = Lazy::new(Uuid::new_v4);
+
+    #[derive(Clone, Debug)]
+    struct TestChildAccount(Address);
+
+    impl Account for TestChildAccount {
+        fn id(&self) -> Uuid {
+            *CHILD_ID
+        }
+
+        fn username(&self) -> &Username {
+            &ALICE
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &self.0
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct TestStore {
+        assigned: Arc<Mutex<HashMap<Uuid, Address>>>,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl AddressAssignmentStore for TestStore {
+        async fn get_assigned_address(
+            &self,
+            account_id: Uuid,
+        ) -> Result<Option<Address>, AddressAssignmentStoreError> {
+            Ok(self.assigned.lock().get(&account_id).cloned())
+        }
+
+        async fn assign_address(
+            &self,
+            account_id: Uuid,
+            parent_address: &Address,
+        ) -> Result<Address, AddressAssignmentStoreError> {
+            if self.fail {
+                return Err(AddressAssignmentStoreError::Other(Box::new(
+                    AddressStoreError::Other(Box::new(std::fmt::Error)),
+                )));
+            }
+            let mut assigned = self.assigned.lock();
+            let next = assigned.len() + 1;
+            let address = assigned.entry(account_id).or_insert_with(|| {
+                parent_address
+                    .with_suffix(format!("child{}", next).as_bytes())
+                    .unwrap()
+            });
+            Ok(address.clone())
+        }
+    }
+
+    #[async_trait]
+    impl AddressStore for TestStore {
+        async fn set_ilp_address(&self, _ilp_address: Address) -> Result<(), AddressStoreError> {
+            unimplemented!()
+        }
+
+        async fn clear_ilp_address(&self) -> Result<(), AddressStoreError> {
+            unimplemented!()
+        }
+
+        fn get_ilp_address(&self) -> Address {
+            NODE_ADDRESS.clone()
+        }
+    }
+
+    fn no_other_handler() -> impl IncomingService<TestChildAccount> + Clone {
+        incoming_service_fn(|_| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: b"No other incoming handler!",
+                data: &[],
+                triggered_by: None,
+            }
+            .build())
+        })
+    }
+
+    #[tokio::test]
+    async fn assigns_address_to_child_once() {
+        let store = TestStore::default();
+        let mut service = DynamicIldcpService::new(store.clone(), no_other_handler());
+        let child = TestChildAccount(CHILD_ADDRESS.clone());
+
+        let info = get_ildcp_info(&mut service, child.clone()).await.unwrap();
+        assert_eq!(info.ilp_address().to_string(), "example.parent.child1");
+        assert_eq!(info.asset_code(), b"XYZ");
+        assert_eq!(info.asset_scale(), 9);
+
+        // The account now has the assigned address, which must be kept
+        let child = TestChildAccount(info.ilp_address());
+        let info = get_ildcp_info(&mut service, child).await.unwrap();
+        assert_eq!(info.ilp_address().to_string(), "example.parent.child1");
+        assert_eq!(
+            store.get_assigned_address(*CHILD_ID).await.unwrap(),
+            Some(info.ilp_address())
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_assign_address_outside_node_address() {
+        let store = TestStore::default();
+        let mut service = DynamicIldcpService::new(store.clone(), no_other_handler());
+        let peer = TestChildAccount(Address::from_str("example.peer").unwrap());
+
+        let info = get_ildcp_info(&mut service, peer).await.unwrap();
+        assert_eq!(info.ilp_address().to_string(), "example.peer");
+        assert!(store.assigned.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_if_assignment_fails() {
+        let store = TestStore {
+            fail: true,
+            ..Default::default()
+        };
+        let mut service = DynamicIldcpService::new(store, no_other_handler());
+        let prepare = IldcpRequest {}.to_prepare();
+        let from = TestChildAccount(CHILD_ADDRESS.clone());
+        let reject = service
+            .handle_request(IncomingRequest { from, prepare })
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T00_INTERNAL_ERROR);
+    }
 }
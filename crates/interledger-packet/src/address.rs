@@ -33,6 +33,67 @@ static ADDRESS_PATTERN: Lazy<regex::bytes::Regex> = Lazy::new(|| {
     .unwrap()
 });
 
+/// The allocation schemes ILP addresses start with.
+/// See [`IL-RFC 15: ILP Addresses](https://github.com/interledger/rfcs/blob/master/0015-ilp-addresses/0015-ilp-addresses.md#allocation-schemes)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AllocationScheme {
+    /// `g`: the global Interledger network
+    Global,
+    /// `private`: private networks
+    Private,
+    /// `example`: used in documentation and examples
+    Example,
+    /// `peer`: used between peers for protocols such as CCP and ILDCP
+    Peer,
+    /// `self`: used by a node for itself, e.g. to talk to its plugins
+    Loopback,
+    /// `test`, `test1`, `test2` and `test3`: the test networks
+    Test,
+    /// `local`: local networks
+    Local,
+}
+
+impl AllocationScheme {
+    /// Returns the allocation scheme of the prefix (or address), or `None` if it does not
+    /// start with a valid one
+    pub fn from_prefix(prefix: &str) -> Option<Self> {
+        match prefix.split('.').next()? {
+            "g" => Some(AllocationScheme::Global),
+            "private" => Some(AllocationScheme::Private),
+            "example" => Some(AllocationScheme::Example),
+            "peer" => Some(AllocationScheme::Peer),
+            "self" => Some(AllocationScheme::Loopback),
+            "test" | "test1" | "test2" | "test3" => Some(AllocationScheme::Test),
+            "local" => Some(AllocationScheme::Local),
+            _ => None,
+        }
+    }
+}
+
+/// Returns whether the address is the prefix or one of the addresses under it, comparing
+/// whole segments: `g.alice` matches `g.alice` and `g.alice.bob` but not `g.alicia`.
+/// Prefixes ending with a separator, like `g.alice.`, only match the addresses under them,
+/// and the empty prefix matches every address.
+///
+/// This also applies to prefixes which are not valid addresses themselves.
+pub fn matches_prefix(address: &str, prefix: &str) -> bool {
+    if prefix.is_empty() {
+        return true;
+    }
+    match address.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('.') || prefix.ends_with('.'),
+        None => false,
+    }
+}
+
+/// Returns the prefix followed by each of its shorter prefixes, down to its first segment,
+/// without allocating: `g.alice.bob`, `g.alice` and then `g`
+pub fn prefixes(prefix: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(prefix), |prefix| {
+        prefix.rfind('.').map(|index| &prefix[..index])
+    })
+}
+
 /// An ILP address backed by `Bytes`.
 #[derive(Clone, Eq, Hash, PartialEq)]
 pub struct Address(Bytes);
@@ -174,6 +235,38 @@ impl Address {
             .expect("Addresses must have a scheme as the first segment")
     }
 
+    /// Returns the allocation scheme of the address
+    pub fn allocation_scheme(&self) -> AllocationScheme {
+        // This should never panic because we validate the Address when it's created
+        AllocationScheme::from_prefix(self).expect("Addresses must start with a valid scheme")
+    }
+
+    /// Returns whether the address is under the prefix, i.e. it has all of the prefix's
+    /// segments followed by at least one more. See [`matches_prefix`](./fn.matches_prefix.html)
+    pub fn is_child_of(&self, prefix: &str) -> bool {
+        self.len() > prefix.trim_end_matches('.').len() && matches_prefix(self, prefix)
+    }
+
+    /// Returns the longest sequence of whole segments the address starts with that the other
+    /// address (or prefix) starts with too. It is empty if their schemes are different
+    pub fn longest_common_prefix(&self, other: &str) -> &str {
+        let mut common_len = 0;
+        let mut other_segments = other.split('.');
+        for segment in self.segments() {
+            if other_segments.next() != Some(segment) {
+                break;
+            }
+            // Each segment after the first one is preceded by a separator
+            common_len += if common_len == 0 { 0 } else { 1 } + segment.len();
+        }
+        &self[..common_len]
+    }
+
+    /// Returns the address followed by each of its shorter prefixes, down to its scheme
+    pub fn prefixes(&self) -> impl Iterator<Item = &str> {
+        prefixes(self)
+    }
+
     /// Suffixes the ILP Address with the provided suffix. Includes a '.' separator
     pub fn with_suffix(&self, suffix: &[u8]) -> Result<Address, AddressError> {
         let new_address_len = self.len() + 1 + suffix.len();
@@ -372,6 +465,78 @@ mod test_address {
         );
     }
 
+    #[test]
+    fn test_allocation_scheme() {
+        for (address, scheme) in &[
+            ("g.alice", AllocationScheme::Global),
+            ("private.alice", AllocationScheme::Private),
+            ("peer.config", AllocationScheme::Peer),
+            ("self.plugin", AllocationScheme::Loopback),
+            ("test2.alice", AllocationScheme::Test),
+        ] {
+            assert_eq!(
+                Address::from_str(address).unwrap().allocation_scheme(),
+                *scheme
+            );
+        }
+        assert_eq!(
+            AllocationScheme::from_prefix("g."),
+            Some(AllocationScheme::Global)
+        );
+        assert_eq!(
+            AllocationScheme::from_prefix("private"),
+            Some(AllocationScheme::Private)
+        );
+        assert_eq!(AllocationScheme::from_prefix("test4.alice"), None);
+        assert_eq!(AllocationScheme::from_prefix("global.alice"), None);
+        assert_eq!(AllocationScheme::from_prefix(""), None);
+    }
+
+    #[test]
+    fn test_matches_prefix() {
+        assert!(matches_prefix("g.alice", "g.alice"));
+        assert!(matches_prefix("g.alice.bob", "g.alice"));
+        assert!(matches_prefix("g.alice.bob", "g.alice."));
+        assert!(matches_prefix("g.alice", ""));
+        assert!(!matches_prefix("g.alicia", "g.alice"));
+        assert!(!matches_prefix("g.alice", "g.alice."));
+        assert!(!matches_prefix("g.alice", "g.alice.bob"));
+    }
+
+    #[test]
+    fn test_is_child_of() {
+        let address = Address::from_str("g.alice.bob").unwrap();
+        assert!(address.is_child_of("g"));
+        assert!(address.is_child_of("g.alice"));
+        assert!(address.is_child_of("g.alice."));
+        assert!(!address.is_child_of("g.alice.bob"));
+        assert!(!address.is_child_of("g.ali"));
+        assert!(!address.is_child_of("private"));
+    }
+
+    #[test]
+    fn test_longest_common_prefix() {
+        let address = Address::from_str("g.alice.bob.charlie").unwrap();
+        assert_eq!(
+            address.longest_common_prefix("g.alice.bob.dave"),
+            "g.alice.bob"
+        );
+        assert_eq!(address.longest_common_prefix("g.alice.bobby"), "g.alice");
+        assert_eq!(address.longest_common_prefix("g.alice."), "g.alice");
+        assert_eq!(
+            address.longest_common_prefix("g.alice.bob.charlie"),
+            "g.alice.bob.charlie"
+        );
+        assert_eq!(address.longest_common_prefix("private.alice"), "");
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let address = Address::from_str("g.alice.bob").unwrap();
+        assert!(address.prefixes().eq(vec!["g.alice.bob", "g.alice", "g"]));
+        assert!(prefixes("example.").eq(vec!["example.", "example"]));
+    }
+
     fn make_address(length: usize) -> Vec<u8> {
         let mut addr = b"test.".to_vec();
         addr.resize(length, b'_');
@@ -1,5 +1,7 @@
+use super::ErrorCodeError;
+use std::convert::TryFrom;
 use std::fmt;
-use std::str;
+use std::str::{self, FromStr};
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub struct ErrorCode([u8; 3]);
@@ -122,6 +124,38 @@ impl fmt::Display for ErrorCode {
     }
 }
 
+/// Parses the 3 character code, such as `F02`, which `Display` prints
+impl FromStr for ErrorCode {
+    type Err = ErrorCodeError;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        let bytes = <[u8; 3]>::try_from(src.as_bytes())
+            .map_err(|_| ErrorCodeError::InvalidLength(src.len()))?;
+        ErrorCode::new(bytes).ok_or(ErrorCodeError::NonAscii)
+    }
+}
+
+#[cfg(any(feature = "serde", test))]
+impl<'de> serde::Deserialize<'de> for ErrorCode {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let string = String::deserialize(deserializer)?;
+        ErrorCode::from_str(&string).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(any(feature = "serde", test))]
+impl serde::Serialize for ErrorCode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
 #[cfg(test)]
 mod test_error_code {
     use super::*;
@@ -142,6 +176,42 @@ mod test_error_code {
         );
     }
 
+    #[test]
+    fn parses_display_output() {
+        for code in &[
+            ErrorCode::F02_UNREACHABLE,
+            ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+            ErrorCode::R00_TRANSFER_TIMED_OUT,
+            ErrorCode::new(*b"???").unwrap(),
+        ] {
+            assert_eq!(ErrorCode::from_str(&code.to_string()), Ok(*code));
+        }
+        assert_eq!(
+            ErrorCode::from_str("F0"),
+            Err(ErrorCodeError::InvalidLength(2))
+        );
+        assert_eq!(
+            ErrorCode::from_str("F002"),
+            Err(ErrorCodeError::InvalidLength(4))
+        );
+        assert_eq!(ErrorCode::from_str("Fä"), Err(ErrorCodeError::NonAscii));
+    }
+
+    #[test]
+    fn serde() {
+        use serde_test::{assert_de_tokens, assert_de_tokens_error, assert_ser_tokens, Token};
+        assert_ser_tokens(&ErrorCode::F08_AMOUNT_TOO_LARGE, &[Token::Str("F08")]);
+        assert_de_tokens(
+            &ErrorCode::F08_AMOUNT_TOO_LARGE,
+            &[Token::BorrowedStr("F08")],
+        );
+        assert_de_tokens(&ErrorCode::F08_AMOUNT_TOO_LARGE, &[Token::String("F08")]);
+        assert_de_tokens_error::<ErrorCode>(
+            &[Token::BorrowedStr("F8")],
+            "Error code must be 3 characters long, found 2",
+        );
+    }
+
     #[test]
     fn rejects_non_ia5string() {
         use std::convert::TryInto;
@@ -31,6 +31,18 @@ pub enum PacketTypeError {
     Unexpected(u8, u8),
 }
 
+#[derive(PartialEq, Debug, thiserror::Error)]
+#[error("Unknown packet type name: {0}")]
+pub struct PacketTypeNameError(pub String);
+
+#[derive(PartialEq, Debug, thiserror::Error)]
+pub enum ErrorCodeError {
+    #[error("Error code must be 3 characters long, found {0}")]
+    InvalidLength(usize),
+    #[error("Error code must only contain ASCII characters")]
+    NonAscii,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum TrailingBytesError {
     #[error("Unexpected outer trailing bytes")]
@@ -53,6 +65,8 @@ pub enum OerError {
     VarUint(#[from] VarUintError),
     #[error("{0}")]
     VariableLengthTimestamp(#[from] VariableLengthTimestampError),
+    #[error("{0}")]
+    Timestamp(#[from] TimestampError),
 }
 
 #[derive(PartialEq, Debug, thiserror::Error)]
@@ -76,6 +90,8 @@ pub enum VarUintError {
     ZeroLength,
     #[error("var uint too large")]
     TooLarge,
+    #[error("var uint {0} exceeds the maximum of {1}")]
+    ExceedsMaximum(u64, u64),
 }
 
 #[derive(PartialEq, Debug, thiserror::Error)]
@@ -85,3 +101,14 @@ pub enum VariableLengthTimestampError {
     #[error("Input failed to parse as timestamp")]
     InvalidTimestamp,
 }
+
+#[derive(PartialEq, Debug, thiserror::Error)]
+pub enum TimestampError {
+    #[error("DateTime must be numeric")]
+    NonNumeric,
+    #[error("Input failed to parse as timestamp: {0}")]
+    InvalidTimestamp(chrono::ParseError),
+    #[cfg(feature = "roundtrip-only")]
+    #[cfg_attr(feature = "roundtrip-only", error("Timestamp not roundtrippable"))]
+    NonRoundtrippable,
+}
@@ -11,14 +11,19 @@ mod fixtures;
 pub mod hex;
 pub mod oer;
 mod packet;
+pub mod pool;
+mod reject_data;
 
-pub use self::address::{Address, AddressError};
+pub use self::address::{matches_prefix, prefixes, Address, AddressError, AllocationScheme};
 pub use self::error::{ErrorClass, ErrorCode};
-pub use self::errors::{OerError, PacketTypeError, ParseError, TrailingBytesError};
+pub use self::errors::{
+    ErrorCodeError, OerError, PacketTypeError, PacketTypeNameError, ParseError, TrailingBytesError,
+};
 
 pub use self::packet::MaxPacketAmountDetails;
 pub use self::packet::{Fulfill, Packet, PacketType, Prepare, Reject};
 pub use self::packet::{FulfillBuilder, PrepareBuilder, RejectBuilder};
+pub use self::reject_data::{RejectDataError, RejectDetail, RejectDetails};
 
 #[cfg(any(fuzzing, test))]
 pub fn lenient_packet_roundtrips(data: &[u8]) -> Result<(), ParseError> {
@@ -84,3 +89,63 @@ pub fn lenient_packet_roundtrips(data: &[u8]) -> Result<(), ParseError> {
 
     Ok(())
 }
+
+/// Reads a variable length integer and a variable length octet string from the start of
+/// `data`, checking that the lengths are predicted right and that the values are written
+/// back the same (byte for byte, with the `strict` feature)
+#[cfg(any(fuzzing, test))]
+pub fn fuzz_oer(data: &[u8]) {
+    use bytes::BytesMut;
+    use oer::{BufOerExt, MutBufOerExt};
+
+    let mut reader = data;
+    if let Ok(uint) = reader.read_var_uint() {
+        let mut written = BytesMut::new();
+        written.put_var_uint(uint);
+        assert_eq!(written.len(), 1 + oer::predict_var_uint_size(uint) as usize);
+        assert_eq!((&written[..]).read_var_uint(), Ok(uint));
+        #[cfg(feature = "strict")]
+        assert_eq!(
+            hex::HexString(&data[..data.len() - reader.len()]),
+            hex::HexString(&written[..])
+        );
+    }
+
+    let mut reader = data;
+    if let Ok(octets) = reader.read_var_octet_string() {
+        assert_eq!(data.peek_var_octet_string(), Ok(octets));
+        let mut written = BytesMut::new();
+        written.put_var_octet_string(octets);
+        assert_eq!(written.len(), oer::predict_var_octet_string(octets.len()));
+        assert_eq!((&written[..]).read_var_octet_string(), Ok(octets));
+        #[cfg(feature = "strict")]
+        assert_eq!(
+            hex::HexString(&data[..data.len() - reader.len()]),
+            hex::HexString(&written[..])
+        );
+    }
+}
+
+#[cfg(test)]
+mod fuzzing {
+    use super::fuzz_oer;
+
+    #[test]
+    fn oer_readers() {
+        let mut long_string = vec![0x82, 0x01, 0x00];
+        long_string.extend_from_slice(&[0xaa; 256]);
+        for data in &[
+            &[][..],
+            &[0x00],
+            &[0x01, 0x00],
+            &[0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            &[0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            &[0x81, 0x05],
+            &[0x88, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            &[0x80],
+            &long_string[..],
+        ] {
+            fuzz_oer(data);
+        }
+    }
+}
@@ -1,16 +1,24 @@
 #![forbid(unsafe_code)]
 
-use super::errors::{LengthPrefixError, OerError, VarUintError, VariableLengthTimestampError};
+use super::errors::{
+    LengthPrefixError, OerError, TimestampError, VarUintError, VariableLengthTimestampError,
+};
 use std::convert::TryFrom;
 use std::u64;
 
 use bytes::{Buf, BufMut, BytesMut};
-use chrono::{TimeZone, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 
 const HIGH_BIT: u8 = 0x80;
 const LOWER_SEVEN_BITS: u8 = 0x7f;
-// NOTE: this is stricly different than packet::INTERLEDGER_TIMESTAMP_FORMAT
+// NOTE: this is stricly different than INTERLEDGER_TIMESTAMP_FORMAT
 static VARIABLE_LENGTH_TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S%.3fZ";
+// NOTE: this is strictly different from the VARIABLE_LENGTH_TIMESTAMP_FORMAT which has a dot, and
+// is used for much more lenient timestamps with 0-3 fractions.
+static INTERLEDGER_TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S%3f";
+
+/// Length of the fixed length timestamps used in ILP Prepare packets on the wire.
+pub const INTERLEDGER_TIMESTAMP_LEN: usize = 17;
 
 /// Smallest allowed varlen octets container length, which is 1 byte for `0x00`.
 pub const EMPTY_VARLEN_OCTETS_LEN: usize = predict_var_octet_string(0);
@@ -67,6 +75,26 @@ pub trait BufOerExt<'a> {
     fn read_var_octet_string_length(&mut self) -> Result<usize, OerError>;
     fn read_var_uint(&mut self) -> Result<u64, OerError>;
 
+    /// Decodes a fixed-size octet string of `len` bytes.
+    fn read_octets(&mut self, len: usize) -> Result<&'a [u8], OerError>;
+    fn read_u8(&mut self) -> Result<u8, OerError>;
+    fn read_u16(&mut self) -> Result<u16, OerError>;
+    fn read_u32(&mut self) -> Result<u32, OerError>;
+    fn read_u64(&mut self) -> Result<u64, OerError>;
+
+    /// Decodes a variable-length unsigned integer, failing if it is larger than `max`.
+    fn read_var_uint_bounded(&mut self, max: u64) -> Result<u64, OerError>;
+
+    /// Decodes the number of elements of a SEQUENCE OF, failing if the rest of the buffer
+    /// cannot hold that many elements of at least `min_element_len` bytes each. This makes it
+    /// safe to preallocate the elements.
+    fn read_sequence_len(&mut self, min_element_len: usize) -> Result<usize, OerError>;
+
+    /// Decodes a fixed length timestamp as used in ILP Prepare packets according to [RFC-0027].
+    ///
+    /// [RFC-0027]: https://github.com/interledger/rfcs/blob/2dfdcf47ac52489a4ad473a5d869cd9f0217db67/0027-interledger-protocol-4/0027-interledger-protocol-4.md#ilp-prepare
+    fn read_interledger_timestamp(&mut self) -> Result<DateTime<Utc>, OerError>;
+
     /// Decodes a variable length timestamp according to [RFC-0030].
     ///
     /// [RFC-0030]: https://github.com/interledger/rfcs/blob/2473d2963a65e5534076c483f3c08a81b8e0cc88/0030-notes-on-oer-encoding/0030-notes-on-oer-encoding.md#variable-length-timestamps
@@ -175,6 +203,90 @@ impl<'a> BufOerExt<'a> for &'a [u8] {
         }
     }
 
+    #[inline]
+    fn read_octets(&mut self, len: usize) -> Result<&'a [u8], OerError> {
+        if self.len() < len {
+            Err(OerError::UnexpectedEof)
+        } else {
+            let to_return = &self[..len];
+            *self = &self[len..];
+            Ok(to_return)
+        }
+    }
+
+    #[inline]
+    fn read_u8(&mut self) -> Result<u8, OerError> {
+        Ok(self.read_octets(1)?[0])
+    }
+
+    #[inline]
+    fn read_u16(&mut self) -> Result<u16, OerError> {
+        Ok(self.read_octets(2)?.get_u16())
+    }
+
+    #[inline]
+    fn read_u32(&mut self) -> Result<u32, OerError> {
+        Ok(self.read_octets(4)?.get_u32())
+    }
+
+    #[inline]
+    fn read_u64(&mut self) -> Result<u64, OerError> {
+        Ok(self.read_octets(8)?.get_u64())
+    }
+
+    #[inline]
+    fn read_var_uint_bounded(&mut self, max: u64) -> Result<u64, OerError> {
+        let uint = self.read_var_uint()?;
+        if uint > max {
+            Err(VarUintError::ExceedsMaximum(uint, max).into())
+        } else {
+            Ok(uint)
+        }
+    }
+
+    #[inline]
+    fn read_sequence_len(&mut self, min_element_len: usize) -> Result<usize, OerError> {
+        let uint = self.read_var_uint()?;
+        let max = self.len() / min_element_len.max(1);
+        // the elements could not fit in the buffer so a longer one must be truncated
+        usize::try_from(uint)
+            .ok()
+            .filter(|len| *len <= max)
+            .ok_or(OerError::UnexpectedEof)
+    }
+
+    fn read_interledger_timestamp(&mut self) -> Result<DateTime<Utc>, OerError> {
+        let octets = self.read_octets(INTERLEDGER_TIMESTAMP_LEN)?;
+
+        if !octets.iter().all(|b| b.is_ascii_digit()) {
+            return Err(TimestampError::NonNumeric.into());
+        }
+
+        let s = std::str::from_utf8(octets)
+            .expect("octets are only ascii digits, utf8 conversion must succeed");
+        let ts = Utc
+            .datetime_from_str(s, INTERLEDGER_TIMESTAMP_FORMAT)
+            .map_err(TimestampError::InvalidTimestamp)?;
+
+        #[cfg(feature = "roundtrip-only")]
+        {
+            // chrono will leniently parse some timestamps into forms which don't roundtrip.
+            // this works around the class of fuzzer findings demonstrated by
+            // fuzzed_2_chrono_60s_rollover: a leap second only shows up once the timestamp has
+            // been converted to a SystemTime, so the check has to go through one.
+            if DateTime::<Utc>::from(std::time::SystemTime::from(ts))
+                .format(INTERLEDGER_TIMESTAMP_FORMAT)
+                .to_string()
+                .as_bytes()
+                != octets
+            {
+                return Err(TimestampError::NonRoundtrippable.into());
+            }
+        }
+
+        Ok(ts)
+    }
+
     fn read_variable_length_timestamp(&mut self) -> Result<VariableLengthTimestamp, OerError> {
         use once_cell::sync::OnceCell;
         use regex::bytes::Regex;
@@ -293,6 +405,20 @@ pub trait MutBufOerExt: BufMut + Sized {
         write!(self.writer(), "{}", vts)
             .expect("BufMut should expand and formatting should never fail");
     }
+
+    /// Encodes the given timestamp as a fixed length timestamp, see
+    /// [`BufOerExt::read_interledger_timestamp`].
+    fn put_interledger_timestamp(&mut self, timestamp: &DateTime<Utc>) {
+        use bytes::buf::BufMutExt;
+        use std::io::Write;
+
+        write!(
+            self.writer(),
+            "{}",
+            timestamp.format(INTERLEDGER_TIMESTAMP_FORMAT)
+        )
+        .expect("BufMut should expand and formatting should never fail");
+    }
 }
 
 impl<B: BufMut + Sized> MutBufOerExt for B {}
@@ -592,6 +718,68 @@ mod test_buf_oer_ext {
         }
     }
 
+    #[test]
+    fn test_read_fixed_size() {
+        let mut reader = &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09][..];
+        assert_eq!(reader.read_octets(2).unwrap(), &[0x01, 0x02]);
+        assert_eq!(reader.read_u16().unwrap(), 0x0304);
+        assert_eq!(reader.read_u32().unwrap(), 0x0506_0708);
+        assert_eq!(reader.read_u32().unwrap_err(), OerError::UnexpectedEof);
+        assert_eq!(reader.read_u8().unwrap(), 0x09);
+        assert_eq!(reader.read_u8().unwrap_err(), OerError::UnexpectedEof);
+        assert_eq!(reader.read_u64().unwrap_err(), OerError::UnexpectedEof);
+        assert_eq!(reader.read_octets(1).unwrap_err(), OerError::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_read_var_uint_bounded() {
+        assert_eq!((&[0x01, 0x09][..]).read_var_uint_bounded(9), Ok(9));
+        assert_eq!(
+            (&[0x02, 0x01, 0x00][..]).read_var_uint_bounded(255),
+            Err(OerError::VarUint(VarUintError::ExceedsMaximum(256, 255)))
+        );
+    }
+
+    #[test]
+    fn test_read_sequence_len() {
+        let mut reader = &[0x01, 0x02, 0xaa, 0xbb, 0xcc, 0xdd][..];
+        assert_eq!(reader.read_sequence_len(2), Ok(2));
+        assert_eq!(reader.len(), 4);
+
+        // an absurd number of elements is rejected before anything is allocated for them
+        let mut reader = &[0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00][..];
+        assert_eq!(reader.read_sequence_len(1), Err(OerError::UnexpectedEof));
+        let mut reader = &[0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00][..];
+        assert_eq!(reader.read_sequence_len(2), Err(OerError::UnexpectedEof));
+    }
+
+    #[test]
+    fn read_interledger_timestamp() {
+        let mut reader = &b"20171224161432279rest"[..];
+        let ts = reader.read_interledger_timestamp().unwrap();
+        assert_eq!(ts.to_string(), "2017-12-24 16:14:32.279 UTC");
+        assert_eq!(reader, b"rest");
+
+        let invalid: &[(&[u8], OerError)] = &[
+            (b"2017122416143227", OerError::UnexpectedEof),
+            (
+                b"2017122416143227Z",
+                OerError::Timestamp(TimestampError::NonNumeric),
+            ),
+        ];
+        for (buffer, oer_error) in invalid {
+            assert_eq!(
+                (&buffer[..]).read_interledger_timestamp().unwrap_err(),
+                *oer_error
+            );
+        }
+
+        assert!(matches!(
+            (&b"20171324161432279"[..]).read_interledger_timestamp(),
+            Err(OerError::Timestamp(TimestampError::InvalidTimestamp(_)))
+        ));
+    }
+
     #[test]
     fn peek_too_long_uint() {
         // in interledger-stream there is a use case to accept larger than u64::MAX for a varuint.
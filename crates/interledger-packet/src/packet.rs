@@ -1,26 +1,26 @@
 use std::fmt;
-use std::str;
+use std::mem;
+use std::str::{self, FromStr};
 use std::time::SystemTime;
 
 use bytes::{Buf, BufMut, BytesMut};
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{DateTime, Utc};
 
+use crate::errors::TimestampError;
 use crate::oer::{self, BufOerExt, MutBufOerExt};
+use crate::pool;
 use crate::{hex::HexString, OerError};
-use crate::{Address, ErrorCode, PacketTypeError, ParseError, TrailingBytesError};
+use crate::{
+    Address, ErrorCode, PacketTypeError, PacketTypeNameError, ParseError, TrailingBytesError,
+};
 use std::convert::TryFrom;
-use std::io::Write;
 
 const AMOUNT_LEN: usize = 8;
-const EXPIRY_LEN: usize = 17;
+const EXPIRY_LEN: usize = oer::INTERLEDGER_TIMESTAMP_LEN;
 const CONDITION_LEN: usize = 32;
 const FULFILLMENT_LEN: usize = 32;
 const ERROR_CODE_LEN: usize = 3;
 
-// NOTE: this is strictly different from the oer::GENERALIZED_TIMESTAMP_FORMAT which has a dot, and
-// is used for much more lenient timestamps with 0-3 fractions.
-static INTERLEDGER_TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S%3f";
-
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
 pub enum PacketType {
@@ -58,6 +58,50 @@ impl TryFrom<u8> for PacketType {
     }
 }
 
+impl fmt::Display for PacketType {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(match self {
+            PacketType::Prepare => "Prepare",
+            PacketType::Fulfill => "Fulfill",
+            PacketType::Reject => "Reject",
+        })
+    }
+}
+
+/// Parses the names `Display` prints, ignoring case
+impl FromStr for PacketType {
+    type Err = PacketTypeNameError;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        [PacketType::Prepare, PacketType::Fulfill, PacketType::Reject]
+            .iter()
+            .find(|packet_type| packet_type.to_string().eq_ignore_ascii_case(src))
+            .copied()
+            .ok_or_else(|| PacketTypeNameError(src.to_string()))
+    }
+}
+
+#[cfg(any(feature = "serde", test))]
+impl<'de> serde::Deserialize<'de> for PacketType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let string = String::deserialize(deserializer)?;
+        PacketType::from_str(&string).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(any(feature = "serde", test))]
+impl serde::Serialize for PacketType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Packet {
     Prepare(Prepare),
@@ -143,44 +187,18 @@ impl TryFrom<BytesMut> for Prepare {
             return Err(OerError::UnexpectedEof.into());
         }
 
-        let amount = content.get_u64();
-
+        let amount = content.read_u64()?;
         // Fixed Length DateTime format - RFC 0027
-        // https://github.com/interledger/rfcs/blob/2dfdcf47ac52489a4ad473a5d869cd9f0217db67/0027-interledger-protocol-4/0027-interledger-protocol-4.md#ilp-prepare
-        let mut read_expires_at = [0x00; 17];
-        content.copy_to_slice(&mut read_expires_at);
-
-        if !read_expires_at
-            .iter()
-            .map(|e| (b'0'..=b'9').contains(e))
-            .fold(true, |a, b| a & b)
-        {
-            return Err(ParseError::TimestampConversion);
-        }
-
-        let expires_at = str::from_utf8(&read_expires_at[..])
-            .expect("read_expires_at matches only ascii, utf8 conversion must succeed");
-        let expires_at: DateTime<Utc> =
-            Utc.datetime_from_str(&expires_at, INTERLEDGER_TIMESTAMP_FORMAT)?;
-        let expires_at = SystemTime::from(expires_at);
-
-        #[cfg(feature = "roundtrip-only")]
-        {
-            // chrono will leniently parse some timestamps into forms which don't roundtrip.
-            // this works around the class of fuzzer findings demonstrated by
-            // fuzzed_1_chrono_60s_rollover.
-            let mut roundtripped = [0u8; 17];
-            write!(
-                &mut roundtripped[..],
-                "{}",
-                DateTime::<Utc>::from(expires_at).format(INTERLEDGER_TIMESTAMP_FORMAT),
-            )
-            .unwrap();
-
-            if roundtripped != read_expires_at {
-                return Err(ParseError::NonRoundtrippableTimestamp);
+        let expires_at = content.read_interledger_timestamp().map_err(|e| match e {
+            OerError::Timestamp(TimestampError::NonNumeric) => ParseError::TimestampConversion,
+            OerError::Timestamp(TimestampError::InvalidTimestamp(e)) => ParseError::ChronoErr(e),
+            #[cfg(feature = "roundtrip-only")]
+            OerError::Timestamp(TimestampError::NonRoundtrippable) => {
+                ParseError::NonRoundtrippableTimestamp
             }
-        }
+            e => ParseError::Oer(e),
+        })?;
+        let expires_at = SystemTime::from(expires_at);
 
         // Skip execution condition.
         content.skip(CONDITION_LEN)?;
@@ -226,12 +244,8 @@ impl Prepare {
     pub fn set_expires_at(&mut self, expires_at: SystemTime) {
         self.expires_at = expires_at;
         let offset = self.content_offset + AMOUNT_LEN;
-        write!(
-            &mut self.buffer[offset..offset + EXPIRY_LEN],
-            "{}",
-            DateTime::<Utc>::from(expires_at).format(INTERLEDGER_TIMESTAMP_FORMAT),
-        )
-        .unwrap();
+        let mut expiry = &mut self.buffer[offset..offset + EXPIRY_LEN];
+        expiry.put_interledger_timestamp(&DateTime::<Utc>::from(expires_at));
     }
 
     /// The returned value always has a length of 32.
@@ -242,6 +256,12 @@ impl Prepare {
         &self.buffer[begin..end]
     }
 
+    #[inline]
+    pub fn set_execution_condition(&mut self, execution_condition: &[u8; 32]) {
+        let begin = self.content_offset + AMOUNT_LEN + EXPIRY_LEN;
+        self.buffer[begin..begin + CONDITION_LEN].copy_from_slice(execution_condition);
+    }
+
     #[inline]
     pub fn destination(&self) -> Address {
         self.destination.clone()
@@ -288,31 +308,34 @@ impl fmt::Debug for Prepare {
 
 impl<'a> PrepareBuilder<'a> {
     pub fn build(&self) -> Prepare {
-        use bytes::buf::BufMutExt;
+        self.build_with_data(self.data.len(), |data| data.copy_from_slice(self.data))
+    }
+
+    /// Builds the Prepare with `data_len` bytes of data which `write_data` writes directly
+    /// into the packet's buffer, to avoid serializing the data separately and copying it.
+    /// The builder's `data` is ignored.
+    pub fn build_with_data<F>(&self, data_len: usize, write_data: F) -> Prepare
+    where
+        F: FnOnce(&mut [u8]),
+    {
         const STATIC_LEN: usize = AMOUNT_LEN + EXPIRY_LEN + CONDITION_LEN;
         let destination_size = oer::predict_var_octet_string(self.destination.len());
-        let data_size = oer::predict_var_octet_string(self.data.len());
+        let data_size = oer::predict_var_octet_string(data_len);
         let content_len = STATIC_LEN + destination_size + data_size;
         let buf_size = 1 + oer::predict_var_octet_string(content_len);
-        let mut buffer = BytesMut::with_capacity(buf_size);
+        let mut buffer = pool::take(buf_size);
 
         buffer.put_u8(PacketType::Prepare as u8);
         buffer.put_var_octet_string_length(content_len);
         let content_offset = buffer.len();
         buffer.put_u64(self.amount);
-
-        let mut writer = buffer.writer();
-        write!(
-            writer,
-            "{}",
-            DateTime::<Utc>::from(self.expires_at).format(INTERLEDGER_TIMESTAMP_FORMAT),
-        )
-        .unwrap();
-        let mut buffer = writer.into_inner();
-
+        buffer.put_interledger_timestamp(&DateTime::<Utc>::from(self.expires_at));
         buffer.put_slice(&self.execution_condition[..]);
         buffer.put_var_octet_string::<&[u8]>(self.destination.as_ref());
-        buffer.put_var_octet_string(self.data);
+        buffer.put_var_octet_string_length(data_len);
+        let data_start = buffer.len();
+        buffer.resize(data_start + data_len, 0);
+        write_data(&mut buffer[data_start..]);
 
         Prepare {
             buffer,
@@ -388,8 +411,14 @@ impl AsRef<[u8]> for Fulfill {
 }
 
 impl From<Fulfill> for BytesMut {
-    fn from(fulfill: Fulfill) -> Self {
-        fulfill.buffer
+    fn from(mut fulfill: Fulfill) -> Self {
+        mem::take(&mut fulfill.buffer)
+    }
+}
+
+impl Drop for Fulfill {
+    fn drop(&mut self) {
+        pool::recycle(mem::take(&mut self.buffer));
     }
 }
 
@@ -408,7 +437,7 @@ impl<'a> FulfillBuilder<'a> {
         let data_size = oer::predict_var_octet_string(self.data.len());
         let content_len = FULFILLMENT_LEN + data_size;
         let buf_size = 1 + oer::predict_var_octet_string(content_len);
-        let mut buffer = BytesMut::with_capacity(buf_size);
+        let mut buffer = pool::take(buf_size);
 
         buffer.put_u8(PacketType::Fulfill as u8);
         buffer.put_var_octet_string_length(content_len);
@@ -452,9 +481,8 @@ impl TryFrom<BytesMut> for Reject {
             return Err(OerError::UnexpectedEof.into());
         }
 
-        let mut code = [0; 3];
-        content.copy_to_slice(&mut code);
-
+        let code = <[u8; ERROR_CODE_LEN]>::try_from(content.read_octets(ERROR_CODE_LEN)?)
+            .expect("read_octets returns exactly ERROR_CODE_LEN bytes");
         let code = ErrorCode::new(code).ok_or(ParseError::ErrorCodeConversion)?;
 
         let triggered_by_offset = content_offset + content_len - content.len();
@@ -519,8 +547,14 @@ impl AsRef<[u8]> for Reject {
 }
 
 impl From<Reject> for BytesMut {
-    fn from(reject: Reject) -> Self {
-        reject.buffer
+    fn from(mut reject: Reject) -> Self {
+        mem::take(&mut reject.buffer)
+    }
+}
+
+impl Drop for Reject {
+    fn drop(&mut self) {
+        pool::recycle(mem::take(&mut self.buffer));
     }
 }
 
@@ -553,7 +587,7 @@ impl<'a> RejectBuilder<'a> {
         let data_size = oer::predict_var_octet_string(self.data.len());
         let content_len = ERROR_CODE_LEN + triggered_by_size + message_size + data_size;
         let buf_size = 1 + oer::predict_var_octet_string(content_len);
-        let mut buffer = BytesMut::with_capacity(buf_size);
+        let mut buffer = pool::take(buf_size);
 
         buffer.put_u8(PacketType::Reject as u8);
         buffer.put_var_octet_string_length(content_len);
@@ -589,10 +623,7 @@ fn deserialize_envelope(
     packet_type: PacketType,
     mut reader: &[u8],
 ) -> Result<(usize, &[u8]), ParseError> {
-    if reader.remaining() < PacketType::LEN {
-        return Err(OerError::UnexpectedEof.into());
-    }
-    let got_type = reader.get_u8();
+    let got_type = reader.read_u8()?;
 
     if got_type != packet_type as u8 {
         return Err(PacketTypeError::Unexpected(got_type, packet_type as u8).into());
@@ -666,8 +697,14 @@ impl MaxPacketAmountDetails {
 }
 
 impl From<Prepare> for BytesMut {
-    fn from(prepare: Prepare) -> Self {
-        prepare.buffer
+    fn from(mut prepare: Prepare) -> Self {
+        mem::take(&mut prepare.buffer)
+    }
+}
+
+impl Drop for Prepare {
+    fn drop(&mut self) {
+        pool::recycle(mem::take(&mut self.buffer));
     }
 }
 
@@ -792,6 +829,30 @@ mod test_packet_type {
         assert!(PacketType::try_from(15).is_err());
     }
 
+    #[test]
+    fn parses_display_output() {
+        for packet_type in &[PacketType::Prepare, PacketType::Fulfill, PacketType::Reject] {
+            assert_eq!(
+                PacketType::from_str(&packet_type.to_string()).unwrap(),
+                *packet_type
+            );
+        }
+        assert_eq!(PacketType::from_str("reject").unwrap(), PacketType::Reject);
+        assert_eq!(
+            "Unknown packet type name: Response",
+            &PacketType::from_str("Response").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn serde() {
+        use serde_test::{assert_de_tokens, assert_ser_tokens, Token};
+        assert_ser_tokens(&PacketType::Fulfill, &[Token::Str("Fulfill")]);
+        assert_de_tokens(&PacketType::Fulfill, &[Token::BorrowedStr("fulfill")]);
+        // Strings which can't be borrowed from the input, such as escaped ones
+        assert_de_tokens(&PacketType::Fulfill, &[Token::String("Fulfill")]);
+    }
+
     #[test]
     fn try_from_empty() {
         assert_eq!(
@@ -963,6 +1024,34 @@ mod test_prepare {
         assert_eq!(PREPARE.execution_condition(), fixtures::EXECUTION_CONDITION,);
     }
 
+    #[test]
+    fn test_set_execution_condition() {
+        let destination = PREPARE_BUILDER.destination.clone();
+        let mut prepare = PrepareBuilder {
+            execution_condition: &[0; 32],
+            destination,
+            ..*PREPARE_BUILDER
+        }
+        .build();
+        prepare.set_execution_condition(PREPARE_BUILDER.execution_condition);
+        assert_eq!(BytesMut::from(prepare), PREPARE_BYTES);
+    }
+
+    #[test]
+    fn test_build_with_data() {
+        let destination = PREPARE_BUILDER.destination.clone();
+        let prepare = PrepareBuilder {
+            data: &[],
+            destination,
+            ..*PREPARE_BUILDER
+        }
+        .build_with_data(fixtures::DATA.len(), |data| {
+            data.copy_from_slice(fixtures::DATA)
+        });
+        assert_eq!(prepare, *PREPARE);
+        assert_eq!(BytesMut::from(prepare), PREPARE_BYTES);
+    }
+
     #[test]
     fn test_data() {
         assert_eq!(PREPARE.data(), fixtures::DATA);
@@ -0,0 +1,215 @@
+//! Size-classed pool of the buffers packets are serialized into.
+//!
+//! Forwarding a packet allocates a handful of `BytesMut` (the HTTP or BTP body, the
+//! Prepare, the response, STREAM data). With the `buffer-pool` feature enabled, those
+//! buffers are taken from and returned to a process-wide pool instead, which cuts
+//! allocator pressure at high packet rates. Without the feature the functions in this
+//! module simply allocate and drop, so callers don't need to be feature-gated.
+
+use bytes::BytesMut;
+
+/// Capacities of the pooled buffers. A request is served from the smallest class that fits,
+/// the largest class fits a Prepare carrying the maximum 32767 bytes of data.
+pub const SIZE_CLASSES: [usize; 4] = [512, 2048, 8192, 33_792];
+
+/// Maximum number of idle buffers kept per size class
+pub const MAX_BUFFERS_PER_CLASS: usize = 1024;
+
+/// Counters of the pool's activity since the process started
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PoolStats {
+    /// Buffers served from the pool
+    pub hits: u64,
+    /// Buffers that had to be allocated
+    pub misses: u64,
+    /// Buffers returned to the pool
+    pub recycled: u64,
+    /// Buffers dropped because they were too small or their class was full
+    pub discarded: u64,
+    /// Idle buffers currently held by the pool
+    pub pooled: u64,
+}
+
+impl PoolStats {
+    /// Share of the requested buffers that were served from the pool
+    pub fn reuse_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// Returns an empty buffer with at least the given capacity
+#[inline]
+pub fn take(capacity: usize) -> BytesMut {
+    imp::take(capacity)
+}
+
+/// Returns a buffer holding a copy of the given bytes
+#[inline]
+pub fn copy_from_slice(bytes: &[u8]) -> BytesMut {
+    let mut buffer = take(bytes.len());
+    buffer.extend_from_slice(bytes);
+    buffer
+}
+
+/// Hands a buffer which is no longer needed back to the pool
+#[inline]
+pub fn recycle(buffer: BytesMut) {
+    imp::recycle(buffer)
+}
+
+/// Returns the pool's counters. These are all zero if the `buffer-pool` feature is disabled.
+pub fn stats() -> PoolStats {
+    imp::stats()
+}
+
+#[cfg(feature = "buffer-pool")]
+mod imp {
+    use super::{PoolStats, MAX_BUFFERS_PER_CLASS, SIZE_CLASSES};
+    use bytes::BytesMut;
+    use once_cell::sync::Lazy;
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    };
+
+    static CLASSES: Lazy<Vec<Mutex<Vec<BytesMut>>>> = Lazy::new(|| {
+        SIZE_CLASSES
+            .iter()
+            .map(|_| Mutex::new(Vec::new()))
+            .collect()
+    });
+
+    static HITS: AtomicU64 = AtomicU64::new(0);
+    static MISSES: AtomicU64 = AtomicU64::new(0);
+    static RECYCLED: AtomicU64 = AtomicU64::new(0);
+    static DISCARDED: AtomicU64 = AtomicU64::new(0);
+    static POOLED: AtomicU64 = AtomicU64::new(0);
+
+    pub fn take(capacity: usize) -> BytesMut {
+        let class = match SIZE_CLASSES.iter().position(|size| *size >= capacity) {
+            Some(class) => class,
+            None => {
+                // Larger than any packet, not worth keeping around
+                MISSES.fetch_add(1, Ordering::Relaxed);
+                return BytesMut::with_capacity(capacity);
+            }
+        };
+        let pooled = CLASSES[class].lock().unwrap().pop();
+        match pooled {
+            Some(buffer) => {
+                HITS.fetch_add(1, Ordering::Relaxed);
+                POOLED.fetch_sub(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                MISSES.fetch_add(1, Ordering::Relaxed);
+                BytesMut::with_capacity(SIZE_CLASSES[class])
+            }
+        }
+    }
+
+    pub fn recycle(mut buffer: BytesMut) {
+        // A buffer goes into the largest class it can serve so that `take` never
+        // hands out less capacity than it was asked for
+        let class = match SIZE_CLASSES
+            .iter()
+            .rposition(|size| *size <= buffer.capacity())
+        {
+            Some(class) => class,
+            None => {
+                DISCARDED.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        buffer.clear();
+        let mut buffers = CLASSES[class].lock().unwrap();
+        if buffers.len() < MAX_BUFFERS_PER_CLASS {
+            buffers.push(buffer);
+            RECYCLED.fetch_add(1, Ordering::Relaxed);
+            POOLED.fetch_add(1, Ordering::Relaxed);
+        } else {
+            DISCARDED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats() -> PoolStats {
+        PoolStats {
+            hits: HITS.load(Ordering::Relaxed),
+            misses: MISSES.load(Ordering::Relaxed),
+            recycled: RECYCLED.load(Ordering::Relaxed),
+            discarded: DISCARDED.load(Ordering::Relaxed),
+            pooled: POOLED.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(not(feature = "buffer-pool"))]
+mod imp {
+    use super::PoolStats;
+    use bytes::BytesMut;
+
+    #[inline]
+    pub fn take(capacity: usize) -> BytesMut {
+        BytesMut::with_capacity(capacity)
+    }
+
+    #[inline]
+    pub fn recycle(_buffer: BytesMut) {}
+
+    pub fn stats() -> PoolStats {
+        PoolStats::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taken_buffers_have_the_requested_capacity() {
+        for capacity in &[0, 1, 512, 513, 33_792, 100_000] {
+            let buffer = take(*capacity);
+            assert!(buffer.capacity() >= *capacity);
+            assert!(buffer.is_empty());
+            recycle(buffer);
+        }
+    }
+
+    #[test]
+    fn copies_the_slice() {
+        let buffer = copy_from_slice(b"hello");
+        assert_eq!(&buffer[..], b"hello");
+        recycle(buffer);
+        // A recycled buffer comes back empty
+        assert!(take(5).is_empty());
+    }
+
+    #[test]
+    fn reuse_rate() {
+        assert_eq!(PoolStats::default().reuse_rate(), 0.0);
+        let stats = PoolStats {
+            hits: 3,
+            misses: 1,
+            ..Default::default()
+        };
+        assert_eq!(stats.reuse_rate(), 0.75);
+    }
+
+    #[cfg(feature = "buffer-pool")]
+    #[test]
+    fn reuses_recycled_buffers() {
+        // Other tests share the global pool, so only check that counters move
+        let before = stats();
+        recycle(BytesMut::with_capacity(8192));
+        let buffer = take(5000);
+        assert!(buffer.capacity() >= 5000);
+        let after = stats();
+        assert!(after.recycled > before.recycled);
+        assert!(after.hits > before.hits);
+    }
+}
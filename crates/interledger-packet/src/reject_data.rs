@@ -0,0 +1,284 @@
+//! Structured, machine-readable details carried in the data of Reject packets.
+//!
+//! The details are encoded as a versioned list of type-length-value entries:
+//!
+//! ```text
+//! "IRD" | version (u8) | (type (u8) | value (var octet string))*
+//! ```
+//!
+//! Entries of an unknown type are kept as they are, so that newer versions can add
+//! details without breaking older readers. Peers which put other, opaque bytes in the
+//! data of their Rejects are unaffected: their data simply does not parse as details.
+//!
+//! The `F08: Amount Too Large` Rejects start with the [RFC-0027] max packet amount
+//! details, which the other implementations expect, and may be followed by the
+//! structured details.
+//!
+//! [RFC-0027]: https://interledger.org/rfcs/0027-interledger-protocol-4/#f08-amount-too-large
+
+use crate::oer::{BufOerExt, MutBufOerExt};
+use crate::{ErrorCode, MaxPacketAmountDetails, OerError, Reject};
+use bytes::{Buf, BufMut, BytesMut};
+use std::time::Duration;
+
+/// Marks the start of the structured details
+const PREFIX: &[u8] = b"IRD";
+/// The version of the encoding written by this implementation
+const VERSION: u8 = 1;
+/// Length of the max packet amount details defined in RFC-0027
+const MAX_PACKET_AMOUNT_DETAILS_LEN: usize = 16;
+
+const TYPE_MAX_PACKET_AMOUNT: u8 = 1;
+const TYPE_RETRY_AFTER: u8 = 2;
+const TYPE_MINIMUM_AMOUNT: u8 = 3;
+
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum RejectDataError {
+    #[error("Reject data does not contain structured details")]
+    NotStructured,
+    #[error("Unsupported reject data version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("Invalid reject data: {0}")]
+    Oer(#[from] OerError),
+}
+
+/// A single machine-readable detail about why a packet was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum RejectDetail {
+    /// The amount the rejecting node received and the maximum it accepts in a single packet
+    MaxPacketAmount(MaxPacketAmountDetails),
+    /// How long the sender should wait before sending more packets
+    RetryAfter(Duration),
+    /// The smallest amount the rejecting node accepts in a single packet
+    MinimumAmount(u64),
+    /// A detail of a type this implementation does not know about
+    Unknown { r#type: u8, value: Vec<u8> },
+}
+
+impl RejectDetail {
+    fn r#type(&self) -> u8 {
+        match self {
+            RejectDetail::MaxPacketAmount(_) => TYPE_MAX_PACKET_AMOUNT,
+            RejectDetail::RetryAfter(_) => TYPE_RETRY_AFTER,
+            RejectDetail::MinimumAmount(_) => TYPE_MINIMUM_AMOUNT,
+            RejectDetail::Unknown { r#type, .. } => *r#type,
+        }
+    }
+
+    fn value(&self) -> Vec<u8> {
+        let mut value = BytesMut::new();
+        match self {
+            RejectDetail::MaxPacketAmount(details) => value.put_slice(&details.to_bytes()),
+            RejectDetail::RetryAfter(duration) => value.put_var_uint(duration.as_millis() as u64),
+            RejectDetail::MinimumAmount(amount) => value.put_var_uint(*amount),
+            RejectDetail::Unknown { value: bytes, .. } => value.put_slice(bytes),
+        }
+        value.to_vec()
+    }
+
+    fn parse(r#type: u8, mut value: &[u8]) -> Result<Self, OerError> {
+        Ok(match r#type {
+            TYPE_MAX_PACKET_AMOUNT => RejectDetail::MaxPacketAmount(
+                MaxPacketAmountDetails::from_bytes(value).map_err(|_| OerError::UnexpectedEof)?,
+            ),
+            TYPE_RETRY_AFTER => {
+                RejectDetail::RetryAfter(Duration::from_millis(value.read_var_uint()?))
+            }
+            TYPE_MINIMUM_AMOUNT => RejectDetail::MinimumAmount(value.read_var_uint()?),
+            _ => RejectDetail::Unknown {
+                r#type,
+                value: value.to_vec(),
+            },
+        })
+    }
+}
+
+/// The machine-readable details of a Reject packet.
+///
+/// ```
+/// # use interledger_packet::{ErrorCode, RejectDetails, RejectDetail};
+/// # use std::time::Duration;
+/// let details = RejectDetails::new().with(RejectDetail::RetryAfter(Duration::from_secs(5)));
+/// let data = details.to_bytes(ErrorCode::T05_RATE_LIMITED);
+/// let parsed = RejectDetails::from_bytes(ErrorCode::T05_RATE_LIMITED, &data).unwrap();
+/// assert_eq!(parsed.retry_after(), Some(Duration::from_secs(5)));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RejectDetails {
+    details: Vec<RejectDetail>,
+}
+
+impl RejectDetails {
+    pub fn new() -> Self {
+        RejectDetails::default()
+    }
+
+    pub fn with(mut self, detail: RejectDetail) -> Self {
+        self.details.push(detail);
+        self
+    }
+
+    /// Parses the details from the data of a Reject with the given code.
+    ///
+    /// Returns `RejectDataError::NotStructured` if the data is opaque, that is, if it
+    /// contains neither structured details nor, for `F08` Rejects, the max packet amount.
+    pub fn from_bytes(code: ErrorCode, mut data: &[u8]) -> Result<Self, RejectDataError> {
+        let mut details = RejectDetails::new();
+        if code == ErrorCode::F08_AMOUNT_TOO_LARGE && data.len() >= MAX_PACKET_AMOUNT_DETAILS_LEN {
+            let max_packet_amount =
+                MaxPacketAmountDetails::from_bytes(data).map_err(|_| OerError::UnexpectedEof)?;
+            details
+                .details
+                .push(RejectDetail::MaxPacketAmount(max_packet_amount));
+            data.advance(MAX_PACKET_AMOUNT_DETAILS_LEN);
+            // Whatever follows the RFC-0027 details may be opaque
+            if !data.starts_with(PREFIX) {
+                return Ok(details);
+            }
+        }
+
+        if !data.starts_with(PREFIX) {
+            return Err(RejectDataError::NotStructured);
+        }
+        data.advance(PREFIX.len());
+        if data.is_empty() {
+            return Err(OerError::UnexpectedEof.into());
+        }
+        let version = data.get_u8();
+        if version != VERSION {
+            return Err(RejectDataError::UnsupportedVersion(version));
+        }
+        while !data.is_empty() {
+            let r#type = data.get_u8();
+            let value = data.read_var_octet_string()?;
+            let detail = RejectDetail::parse(r#type, value)?;
+            // The max packet amount of an F08 was already read from the RFC-0027 details
+            if !(r#type == TYPE_MAX_PACKET_AMOUNT && details.max_packet_amount().is_some()) {
+                details.details.push(detail);
+            }
+        }
+        Ok(details)
+    }
+
+    /// Parses the details of the Reject, returning `None` if its data is opaque or invalid
+    pub fn from_reject(reject: &Reject) -> Option<Self> {
+        RejectDetails::from_bytes(reject.code(), reject.data()).ok()
+    }
+
+    /// Encodes the details as the data of a Reject with the given code
+    pub fn to_bytes(&self, code: ErrorCode) -> Vec<u8> {
+        let mut data = BytesMut::new();
+        let mut entries = self.details.iter().collect::<Vec<_>>();
+        if code == ErrorCode::F08_AMOUNT_TOO_LARGE {
+            if let Some(max_packet_amount) = self.max_packet_amount() {
+                data.put_slice(&max_packet_amount.to_bytes());
+                entries.retain(|detail| !matches!(detail, RejectDetail::MaxPacketAmount(_)));
+            }
+        }
+        if !entries.is_empty() {
+            data.put_slice(PREFIX);
+            data.put_u8(VERSION);
+            for detail in entries {
+                data.put_u8(detail.r#type());
+                data.put_var_octet_string(detail.value().as_slice());
+            }
+        }
+        data.to_vec()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RejectDetail> {
+        self.details.iter()
+    }
+
+    pub fn max_packet_amount(&self) -> Option<&MaxPacketAmountDetails> {
+        self.details.iter().find_map(|detail| match detail {
+            RejectDetail::MaxPacketAmount(details) => Some(details),
+            _ => None,
+        })
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.details.iter().find_map(|detail| match detail {
+            RejectDetail::RetryAfter(duration) => Some(*duration),
+            _ => None,
+        })
+    }
+
+    pub fn minimum_amount(&self) -> Option<u64> {
+        self.details.iter().find_map(|detail| match detail {
+            RejectDetail::MinimumAmount(amount) => Some(*amount),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrips_details() {
+        let details = RejectDetails::new()
+            .with(RejectDetail::RetryAfter(Duration::from_millis(1500)))
+            .with(RejectDetail::MinimumAmount(1000))
+            .with(RejectDetail::Unknown {
+                r#type: 200,
+                value: b"future detail".to_vec(),
+            });
+        let data = details.to_bytes(ErrorCode::T05_RATE_LIMITED);
+        assert!(data.starts_with(b"IRD\x01"));
+        let parsed = RejectDetails::from_bytes(ErrorCode::T05_RATE_LIMITED, &data).unwrap();
+        assert_eq!(parsed, details);
+        assert_eq!(parsed.retry_after(), Some(Duration::from_millis(1500)));
+        assert_eq!(parsed.minimum_amount(), Some(1000));
+        assert!(parsed.max_packet_amount().is_none());
+    }
+
+    #[test]
+    fn keeps_the_rfc_format_for_f08() {
+        let max_packet_amount = MaxPacketAmountDetails::new(100, 10);
+        let details =
+            RejectDetails::new().with(RejectDetail::MaxPacketAmount(max_packet_amount.clone()));
+        let data = details.to_bytes(ErrorCode::F08_AMOUNT_TOO_LARGE);
+        assert_eq!(data, max_packet_amount.to_bytes());
+
+        // Readers which only know about RFC-0027 ignore the details which follow
+        let details = details.with(RejectDetail::RetryAfter(Duration::from_secs(1)));
+        let data = details.to_bytes(ErrorCode::F08_AMOUNT_TOO_LARGE);
+        assert_eq!(
+            MaxPacketAmountDetails::from_bytes(&data[..]).unwrap(),
+            max_packet_amount
+        );
+        let parsed = RejectDetails::from_bytes(ErrorCode::F08_AMOUNT_TOO_LARGE, &data).unwrap();
+        assert_eq!(parsed, details);
+    }
+
+    #[test]
+    fn rejects_opaque_data() {
+        assert_eq!(
+            RejectDetails::from_bytes(ErrorCode::T00_INTERNAL_ERROR, b"some error"),
+            Err(RejectDataError::NotStructured)
+        );
+        assert_eq!(
+            RejectDetails::from_bytes(ErrorCode::F08_AMOUNT_TOO_LARGE, b"too short"),
+            Err(RejectDataError::NotStructured)
+        );
+        // The max packet amount is still read if opaque bytes follow it
+        let mut data = MaxPacketAmountDetails::new(100, 10).to_bytes().to_vec();
+        data.extend_from_slice(b"opaque");
+        assert_eq!(
+            RejectDetails::from_bytes(ErrorCode::F08_AMOUNT_TOO_LARGE, &data)
+                .unwrap()
+                .max_packet_amount(),
+            Some(&MaxPacketAmountDetails::new(100, 10))
+        );
+        assert_eq!(
+            RejectDetails::from_bytes(ErrorCode::T00_INTERNAL_ERROR, b"IRD\x02"),
+            Err(RejectDataError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            RejectDetails::from_bytes(ErrorCode::T00_INTERNAL_ERROR, b"IRD\x01\x02\x05"),
+            Err(RejectDataError::Oer(OerError::UnexpectedEof))
+        );
+    }
+}
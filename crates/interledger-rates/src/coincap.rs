@@ -1,3 +1,5 @@
+use super::RateProvider;
+use async_trait::async_trait;
 use futures::TryFutureExt;
 use once_cell::sync::Lazy;
 use reqwest::{Client, Url};
@@ -25,7 +27,26 @@ struct RateResponse {
     data: Vec<Rate>,
 }
 
-pub async fn query_coincap(client: &Client) -> Result<HashMap<String, f64>, ()> {
+/// Fetches the rates from the [CoinCap](https://coincap.io) API
+#[derive(Debug, Clone)]
+pub struct CoinCap {
+    client: Client,
+}
+
+impl CoinCap {
+    pub fn new(client: Client) -> Self {
+        CoinCap { client }
+    }
+}
+
+#[async_trait]
+impl RateProvider for CoinCap {
+    async fn fetch_rates(&self) -> Result<HashMap<String, f64>, ()> {
+        query_coincap(&self.client).await
+    }
+}
+
+async fn query_coincap(client: &Client) -> Result<HashMap<String, f64>, ()> {
     let (assets, rates) = futures::future::join(
         query_coincap_endpoint(client, COINCAP_ASSETS_URL.clone()),
         query_coincap_endpoint(client, COINCAP_RATES_URL.clone()),
@@ -35,7 +56,7 @@ pub async fn query_coincap(client: &Client) -> Result<HashMap<String, f64>, ()>
     let all_rates: HashMap<String, f64> = assets?
         .data
         .into_iter()
-        .chain(rates?.data.into_iter())
+        .chain(rates?.data)
         .filter_map(|record| match f64::from_str(record.rate_usd.as_str()) {
             Ok(rate) => Some((record.symbol.to_uppercase(), rate)),
             Err(err) => {
@@ -0,0 +1,123 @@
+use super::RateProvider;
+use async_trait::async_trait;
+use futures::TryFutureExt;
+use once_cell::sync::Lazy;
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::{error, warn};
+
+// This endpoint has the rates of the major fiat currencies and cryptocurrencies against BTC
+static COINGECKO_EXCHANGE_RATES_URL: Lazy<Url> =
+    Lazy::new(|| Url::parse("https://api.coingecko.com/api/v3/exchange_rates").unwrap());
+
+#[derive(Deserialize, Debug)]
+struct Rate {
+    /// How many units of the asset one BTC is worth
+    value: f64,
+}
+
+#[derive(Deserialize, Debug)]
+struct RateResponse {
+    rates: HashMap<String, Rate>,
+}
+
+/// Fetches the rates from the [CoinGecko](https://www.coingecko.com) API
+#[derive(Debug, Clone)]
+pub struct CoinGecko {
+    client: Client,
+}
+
+impl CoinGecko {
+    pub fn new(client: Client) -> Self {
+        CoinGecko { client }
+    }
+}
+
+#[async_trait]
+impl RateProvider for CoinGecko {
+    async fn fetch_rates(&self) -> Result<HashMap<String, f64>, ()> {
+        let res = self
+            .client
+            .get(COINGECKO_EXCHANGE_RATES_URL.clone())
+            .send()
+            .map_err(|err| {
+                error!("Error fetching exchange rates from CoinGecko: {:?}", err);
+            })
+            .await?;
+
+        let res = res.error_for_status().map_err(|err| {
+            error!(
+                "HTTP error getting exchange rates from CoinGecko: {:?}",
+                err
+            );
+        })?;
+
+        let res: RateResponse = res
+            .json()
+            .map_err(|err| {
+                error!(
+                    "Error getting exchange rate response body from CoinGecko, incorrect type: {:?}",
+                    err
+                );
+            })
+            .await?;
+        rates_in_usd(res)
+    }
+}
+
+/// Converts the rates against BTC into the USD value of each asset
+fn rates_in_usd(res: RateResponse) -> Result<HashMap<String, f64>, ()> {
+    let btc_in_usd = match res.rates.get("usd") {
+        Some(rate) if rate.value > 0.0 => rate.value,
+        _ => {
+            error!("CoinGecko did not return the USD rate");
+            return Err(());
+        }
+    };
+    Ok(res
+        .rates
+        .into_iter()
+        .filter_map(|(symbol, rate)| {
+            if rate.value > 0.0 {
+                Some((symbol.to_uppercase(), btc_in_usd / rate.value))
+            } else {
+                warn!("Ignoring invalid {} rate: {}", symbol, rate.value);
+                None
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn converts_rates_to_usd() {
+        let res: RateResponse = serde_json::from_value(json!({
+            "rates": {
+                "btc": { "name": "Bitcoin", "unit": "BTC", "value": 1.0, "type": "crypto" },
+                "eth": { "name": "Ether", "unit": "ETH", "value": 20.0, "type": "crypto" },
+                "usd": { "name": "US Dollar", "unit": "$", "value": 50000.0, "type": "fiat" },
+                "xyz": { "name": "Invalid", "unit": "XYZ", "value": 0.0, "type": "fiat" },
+            }
+        }))
+        .unwrap();
+        let rates = rates_in_usd(res).unwrap();
+        assert_eq!(rates.len(), 3);
+        assert_eq!(rates["BTC"], 50000.0);
+        assert_eq!(rates["ETH"], 2500.0);
+        assert_eq!(rates["USD"], 1.0);
+    }
+
+    #[test]
+    fn requires_the_usd_rate() {
+        let res: RateResponse = serde_json::from_value(json!({
+            "rates": { "btc": { "value": 1.0 } }
+        }))
+        .unwrap();
+        assert!(rates_in_usd(res).is_err());
+    }
+}
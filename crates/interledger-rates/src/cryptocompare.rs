@@ -1,3 +1,5 @@
+use super::RateProvider;
+use async_trait::async_trait;
 use futures::TryFutureExt;
 use once_cell::sync::Lazy;
 use reqwest::{Client, Url};
@@ -42,7 +44,27 @@ struct Response {
     data: Vec<Record>,
 }
 
-pub async fn query_cryptocompare(
+/// Fetches the rates from the [CryptoCompare](https://cryptocompare.com) API, which requires an API key
+#[derive(Debug, Clone)]
+pub struct CryptoCompare {
+    client: Client,
+    api_key: SecretString,
+}
+
+impl CryptoCompare {
+    pub fn new(client: Client, api_key: SecretString) -> Self {
+        CryptoCompare { client, api_key }
+    }
+}
+
+#[async_trait]
+impl RateProvider for CryptoCompare {
+    async fn fetch_rates(&self) -> Result<HashMap<String, f64>, ()> {
+        query_cryptocompare(&self.client, &self.api_key).await
+    }
+}
+
+async fn query_cryptocompare(
     client: &Client,
     api_key: &SecretString,
 ) -> Result<HashMap<String, f64>, ()> {
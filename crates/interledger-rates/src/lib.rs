@@ -1,3 +1,4 @@
+use async_trait::async_trait;
 use futures::TryFutureExt;
 use interledger_errors::ExchangeRateStoreError;
 use reqwest::Client;
@@ -6,13 +7,19 @@ use serde::Deserialize;
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tracing::{debug, error, trace, warn};
 
 mod cryptocompare;
 
 mod coincap;
 
+mod coingecko;
+
+pub use coincap::CoinCap;
+pub use coingecko::CoinGecko;
+pub use cryptocompare::CryptoCompare;
+
 pub trait ExchangeRateStore: Clone {
     // TODO we may want to make this async if/when we use pubsub to broadcast
     // rate changes to different instances of a horizontally-scalable node
@@ -28,6 +35,78 @@ pub trait ExchangeRateStore: Clone {
     // but in the normal case of getting the rate between two assets, we don't want to
     // copy all the rate data
     fn get_all_exchange_rates(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError>;
+
+    /// Returns when the rates were last set, so that stale rates are not used.
+    /// Stores which do not keep track of it return `None`.
+    fn get_exchange_rates_updated_at(&self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Replaces the fixed rates of asset pairs, which are used instead of the rates of
+    /// the provider. They are keyed by pair, as in `USDC/USD`, and expressed as the value
+    /// of one unit of the first asset in the second one
+    fn set_rate_overrides(
+        &self,
+        overrides: HashMap<String, f64>,
+    ) -> Result<(), ExchangeRateStoreError>;
+
+    fn get_rate_overrides(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError>;
+
+    /// Returns the fixed rate from the first asset to the second one, if one was set for
+    /// the pair in either direction
+    fn get_rate_override(&self, from: &str, to: &str) -> Option<f64> {
+        let overrides = self.get_rate_overrides().ok()?;
+        find_rate_override(&overrides, from, to)
+    }
+}
+
+/// Returns the fixed rate from the first asset to the second one among the overrides,
+/// which applies to the pair in both directions
+pub fn find_rate_override(overrides: &HashMap<String, f64>, from: &str, to: &str) -> Option<f64> {
+    if let Some(rate) = overrides.get(&format!("{}/{}", from, to)) {
+        return Some(*rate);
+    }
+    overrides
+        .get(&format!("{}/{}", to, from))
+        .map(|rate| 1.0 / rate)
+}
+
+/// Checks that the overrides are keyed by pairs of different assets and that their rates
+/// are positive, and returns them with the asset codes in uppercase like the accounts' ones
+pub fn validate_rate_overrides(
+    overrides: HashMap<String, f64>,
+) -> Result<HashMap<String, f64>, ExchangeRateStoreError> {
+    overrides
+        .into_iter()
+        .map(|(pair, rate)| {
+            let pair = pair.to_uppercase();
+            let valid_pair = match pair.split('/').collect::<Vec<_>>().as_slice() {
+                [from, to] => !from.is_empty() && !to.is_empty() && from != to,
+                _ => false,
+            };
+            if !valid_pair {
+                return Err(ExchangeRateStoreError::InvalidRateOverride(format!(
+                    "{} is not a pair of different assets such as USDC/USD",
+                    pair
+                )));
+            }
+            if !rate.is_finite() || rate <= 0.0 {
+                return Err(ExchangeRateStoreError::InvalidRateOverride(format!(
+                    "the rate of {} must be positive, got {}",
+                    pair, rate
+                )));
+            }
+            Ok((pair, rate))
+        })
+        .collect()
+}
+
+/// An external source of exchange rates, polled by the `ExchangeRateFetcher`
+#[async_trait]
+pub trait RateProvider: std::fmt::Debug {
+    /// Fetches the current rates, expressed as the value of one unit of each asset in USD
+    /// and keyed by the uppercase asset codes
+    async fn fetch_rates(&self) -> Result<HashMap<String, f64>, ()>;
 }
 
 /// This determines which external API service to poll for exchange rates.
@@ -51,6 +130,27 @@ pub enum ExchangeRateProvider {
     /// [CryptoCompare]: https://cryptocompare.com
     #[serde(alias = "cryptocompare")]
     CryptoCompare(SecretString),
+    /// Use the [CoinGecko] API.
+    ///
+    /// Note that when configured with YAML, this MUST be specified as
+    /// "CoinGecko", not "coingecko".
+    ///
+    /// [CoinGecko]: https://www.coingecko.com
+    #[serde(alias = "coingecko")]
+    CoinGecko,
+}
+
+impl ExchangeRateProvider {
+    /// Creates the provider which queries the configured API
+    pub fn into_rate_provider(self, client: Client) -> Arc<dyn RateProvider + Send + Sync> {
+        match self {
+            ExchangeRateProvider::CoinCap => Arc::new(CoinCap::new(client)),
+            ExchangeRateProvider::CryptoCompare(api_key) => {
+                Arc::new(CryptoCompare::new(client, api_key))
+            }
+            ExchangeRateProvider::CoinGecko => Arc::new(CoinGecko::new(client)),
+        }
+    }
 }
 
 impl PartialEq<ExchangeRateProvider> for ExchangeRateProvider {
@@ -60,6 +160,7 @@ impl PartialEq<ExchangeRateProvider> for ExchangeRateProvider {
         use secrecy::ExposeSecret;
         match (self, other) {
             (ExchangeRateProvider::CoinCap, ExchangeRateProvider::CoinCap) => true,
+            (ExchangeRateProvider::CoinGecko, ExchangeRateProvider::CoinGecko) => true,
             (ExchangeRateProvider::CryptoCompare(l), ExchangeRateProvider::CryptoCompare(r))
                 if l.expose_secret() == r.expose_secret() =>
             {
@@ -73,11 +174,10 @@ impl PartialEq<ExchangeRateProvider> for ExchangeRateProvider {
 /// Poll exchange rate providers for the current exchange rates
 #[derive(Clone)]
 pub struct ExchangeRateFetcher<S> {
-    provider: ExchangeRateProvider,
+    provider: Arc<dyn RateProvider + Send + Sync>,
     consecutive_failed_polls: Arc<AtomicU32>,
     failed_polls_before_invalidation: u32,
     store: S,
-    client: Client,
 }
 
 impl<S> ExchangeRateFetcher<S>
@@ -89,13 +189,25 @@ where
         provider: ExchangeRateProvider,
         failed_polls_before_invalidation: u32,
         store: S,
+    ) -> Self {
+        Self::with_provider(
+            provider.into_rate_provider(Client::new()),
+            failed_polls_before_invalidation,
+            store,
+        )
+    }
+
+    /// Creates a fetcher which polls the given provider, which may be any source of rates
+    pub fn with_provider(
+        provider: Arc<dyn RateProvider + Send + Sync>,
+        failed_polls_before_invalidation: u32,
+        store: S,
     ) -> Self {
         ExchangeRateFetcher {
             provider,
             consecutive_failed_polls: Arc::new(AtomicU32::new(0)),
             failed_polls_before_invalidation,
             store,
-            client: Client::new(),
         }
     }
 
@@ -116,16 +228,6 @@ where
         tokio::spawn(interval);
     }
 
-    /// Calls the proper exchange rate provider
-    async fn fetch_rates(&self) -> Result<HashMap<String, f64>, ()> {
-        match self.provider {
-            ExchangeRateProvider::CryptoCompare(ref api_key) => {
-                cryptocompare::query_cryptocompare(&self.client, api_key).await
-            }
-            ExchangeRateProvider::CoinCap => coincap::query_coincap(&self.client).await,
-        }
-    }
-
     /// Gets the exchange rates and proceeds to update the store with the newly polled values
     async fn update_rates(&self) -> Result<(), ()> {
         let consecutive_failed_polls = self.consecutive_failed_polls.clone();
@@ -135,7 +237,7 @@ where
         let store_clone = self.store.clone();
         let provider = self.provider.clone();
         #[allow(clippy::cognitive_complexity)]
-        let mut rates = self.fetch_rates()
+        let mut rates = self.provider.fetch_rates()
             .map_err(move |_| {
                 // Note that a race between the read on this line and the check on the line after
                 // is quite unlikely as long as the interval between polls is reasonable.
@@ -166,3 +268,36 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_overrides_in_both_directions() {
+        let mut overrides = HashMap::new();
+        overrides.insert("USDC/USD".to_string(), 1.0);
+        overrides.insert("XYZ/ABC".to_string(), 4.0);
+        assert_eq!(find_rate_override(&overrides, "USDC", "USD"), Some(1.0));
+        assert_eq!(find_rate_override(&overrides, "ABC", "XYZ"), Some(0.25));
+        assert_eq!(find_rate_override(&overrides, "USDC", "EUR"), None);
+    }
+
+    #[test]
+    fn validates_overrides() {
+        let overrides = |pair: &str, rate: f64| {
+            let mut overrides = HashMap::new();
+            overrides.insert(pair.to_string(), rate);
+            validate_rate_overrides(overrides)
+        };
+        assert_eq!(
+            overrides("usdc/usd", 1.0).unwrap().get("USDC/USD"),
+            Some(&1.0)
+        );
+        assert!(overrides("USD", 1.0).is_err());
+        assert!(overrides("USD/USD", 1.0).is_err());
+        assert!(overrides("USDC/USD/EUR", 1.0).is_err());
+        assert!(overrides("USDC/USD", 0.0).is_err());
+        assert!(overrides("USDC/USD", f64::NAN).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Benchmark a synthetic incoming -> router -> outgoing pipeline, in which the outgoing
+//! service fulfills every packet right away, so that only the work done by the node itself
+//! for each packet is measured.
+
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::executor::block_on;
+use interledger_errors::{AccountStoreError, AddressStoreError};
+use interledger_packet::{Address, FulfillBuilder, PrepareBuilder};
+use interledger_router::{Router, RouterStore};
+use interledger_service::{
+    outgoing_service_fn, Account, AccountStore, AddressStore, IlpResult, IncomingRequest,
+    IncomingService, Username,
+};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// Number of routes in the routing table, besides the one the packets take
+const ROUTES: usize = 1000;
+
+static USERNAME: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+static ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("example.alice").unwrap());
+static DESTINATION: Lazy<Address> =
+    Lazy::new(|| Address::from_str("example.bob.payment.a1b2c3").unwrap());
+
+#[derive(Debug, Clone)]
+struct BenchAccount(Uuid);
+
+impl Account for BenchAccount {
+    fn id(&self) -> Uuid {
+        self.0
+    }
+
+    fn username(&self) -> &Username {
+        &USERNAME
+    }
+
+    fn asset_scale(&self) -> u8 {
+        9
+    }
+
+    fn asset_code(&self) -> &str {
+        "XYZ"
+    }
+
+    fn ilp_address(&self) -> &Address {
+        &ADDRESS
+    }
+}
+
+#[derive(Clone)]
+struct BenchStore {
+    routes: Arc<HashMap<String, Uuid>>,
+}
+
+impl BenchStore {
+    fn new() -> Self {
+        let mut routes: HashMap<String, Uuid> = (0..ROUTES)
+            .map(|i| (format!("example.peer{}", i), Uuid::new_v4()))
+            .collect();
+        routes.insert("example.bob".to_string(), Uuid::new_v4());
+        BenchStore {
+            routes: Arc::new(routes),
+        }
+    }
+}
+
+#[async_trait]
+impl AccountStore for BenchStore {
+    type Account = BenchAccount;
+
+    async fn get_accounts(
+        &self,
+        account_ids: Vec<Uuid>,
+    ) -> Result<Vec<BenchAccount>, AccountStoreError> {
+        Ok(account_ids.into_iter().map(BenchAccount).collect())
+    }
+
+    async fn get_account_id_from_username(
+        &self,
+        _username: &Username,
+    ) -> Result<Uuid, AccountStoreError> {
+        Ok(Uuid::new_v4())
+    }
+}
+
+#[async_trait]
+impl AddressStore for BenchStore {
+    async fn set_ilp_address(&self, _ilp_address: Address) -> Result<(), AddressStoreError> {
+        Ok(())
+    }
+
+    async fn clear_ilp_address(&self) -> Result<(), AddressStoreError> {
+        Ok(())
+    }
+
+    fn get_ilp_address(&self) -> Address {
+        Address::from_str("example.connector").unwrap()
+    }
+}
+
+impl RouterStore for BenchStore {
+    fn routing_table(&self) -> Arc<HashMap<String, Uuid>> {
+        self.routes.clone()
+    }
+}
+
+/// Stands in for the incoming services packets go through before the router
+struct IncomingStage<I> {
+    next: I,
+}
+
+#[async_trait]
+impl<I> IncomingService<BenchAccount> for IncomingStage<I>
+where
+    I: IncomingService<BenchAccount> + Send,
+{
+    async fn handle_request(&mut self, request: IncomingRequest<BenchAccount>) -> IlpResult {
+        self.next.handle_request(request).await
+    }
+}
+
+fn request() -> IncomingRequest<BenchAccount> {
+    IncomingRequest {
+        from: BenchAccount(Uuid::new_v4()),
+        prepare: PrepareBuilder {
+            destination: DESTINATION.clone(),
+            amount: 100,
+            execution_condition: &[0; 32],
+            expires_at: UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+            data: &[0; 64],
+        }
+        .build(),
+    }
+}
+
+fn benchmark_pipeline(c: &mut Criterion) {
+    let fulfill = outgoing_service_fn(|_| {
+        Ok(FulfillBuilder {
+            fulfillment: &[0; 32],
+            data: &[0; 64],
+        }
+        .build())
+    });
+
+    let mut pipeline = IncomingStage {
+        next: Router::new(BenchStore::new(), fulfill.clone()),
+    };
+    c.bench_function("Pipeline (incoming -> router -> outgoing)", |b| {
+        b.iter(|| block_on(pipeline.handle_request(request())).unwrap());
+    });
+
+    let mut router = Router::new(BenchStore::new(), fulfill)
+        .with_account_cache(Duration::from_secs(60))
+        .with_loop_protection(true);
+    c.bench_function("Pipeline (account cache, loop protection)", |b| {
+        b.iter(|| block_on(router.handle_request(request())).unwrap());
+    });
+}
+
+criterion_group!(benches, benchmark_pipeline);
+criterion_main!(benches);
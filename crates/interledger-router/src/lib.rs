@@ -11,14 +11,20 @@
 //! only using the information provided by the store. The routing table in the
 //! store can either be configured or populated using the `CcpRouteManager`
 //! (see the `interledger-ccp` crate for more details).
+//!
+//! In addition to the routing table, the router can be configured with multiple
+//! weighted or prioritized next hops for a prefix, which are used for basic load
+//! balancing and failover (see [`NextHop`](./struct.NextHop.html)).
 
 use interledger_service::AccountStore;
 use std::{collections::HashMap, sync::Arc};
 use uuid::Uuid;
 
+mod prefix_index;
 mod router;
 
-pub use self::router::Router;
+pub use self::prefix_index::{PrefixIndex, RouteIndex, RouteIndexStats};
+pub use self::router::{NextHop, Router};
 
 /// A trait for Store implmentations that have ILP routing tables.
 pub trait RouterStore: AccountStore + Clone + Send + Sync + 'static {
@@ -28,4 +34,22 @@ pub trait RouterStore: AccountStore + Clone + Send + Sync + 'static {
     /// This ensures that individual packets can be routed without hitting the underlying store.
     /// An Arc is returned to avoid copying the underlying data while processing each packet.
     fn routing_table(&self) -> Arc<HashMap<String, Uuid>>;
+
+    /// **Synchronously** return the longest prefix of the destination in the routing table,
+    /// as its length and the account it routes to.
+    /// Stores keeping a [`RouteIndex`](./struct.RouteIndex.html) of their routing table should
+    /// use it, since this default scans the whole routing table.
+    fn route_for(&self, destination: &str) -> Option<(usize, Uuid)> {
+        self.routing_table()
+            .iter()
+            .filter(|(prefix, _)| destination.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(prefix, account_id)| (prefix.len(), *account_id))
+    }
+
+    /// Returns how often the routes were looked up in the store's
+    /// [`RouteIndex`](./struct.RouteIndex.html), if it keeps one
+    fn route_index_stats(&self) -> Option<RouteIndexStats> {
+        None
+    }
 }
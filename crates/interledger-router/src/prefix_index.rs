@@ -0,0 +1,292 @@
+use metrics::{recorder, Key};
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tracing::debug;
+use uuid::Uuid;
+
+/// Radix tree of the routing table's prefixes, which finds the longest prefix of a
+/// destination in O(destination length) instead of comparing it to every route.
+///
+/// The edges are labeled with the longest strings the prefixes have in common, so the tree
+/// only has a node for each prefix and for each point where prefixes diverge.
+#[derive(Debug, Clone, Default)]
+pub struct PrefixIndex {
+    root: Node,
+    len: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    account_id: Option<Uuid>,
+    /// The children by the first character of their label
+    children: HashMap<char, (String, Node)>,
+}
+
+impl PrefixIndex {
+    pub fn new() -> Self {
+        PrefixIndex::default()
+    }
+
+    /// Number of prefixes in the index
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Routes the addresses starting with the prefix to the account, replacing the
+    /// account it was routed to before
+    pub fn insert(&mut self, prefix: &str, account_id: Uuid) {
+        let mut node = &mut self.root;
+        let mut rest = prefix;
+        loop {
+            let first = match rest.chars().next() {
+                Some(first) => first,
+                None => {
+                    if node.account_id.replace(account_id).is_none() {
+                        self.len += 1;
+                    }
+                    return;
+                }
+            };
+            let (label, child) = match node.children.entry(first) {
+                Entry::Vacant(entry) => {
+                    let leaf = Node {
+                        account_id: Some(account_id),
+                        children: HashMap::new(),
+                    };
+                    entry.insert((rest.to_string(), leaf));
+                    self.len += 1;
+                    return;
+                }
+                Entry::Occupied(entry) => entry.into_mut(),
+            };
+            let common = common_prefix_len(label, rest);
+            if common < label.len() {
+                // Splits the edge where the prefix diverges from it
+                let suffix = label.split_off(common);
+                let old_child = std::mem::take(child);
+                child
+                    .children
+                    .insert(suffix.chars().next().unwrap(), (suffix, old_child));
+            }
+            node = child;
+            rest = &rest[common..];
+        }
+    }
+
+    /// Returns the longest prefix of the destination in the index, as its length
+    /// and the account it routes to
+    pub fn longest_match(&self, destination: &str) -> Option<(usize, Uuid)> {
+        let mut node = &self.root;
+        let mut matched = 0;
+        let mut best = node.account_id.map(|account_id| (0, account_id));
+        while let Some(first) = destination[matched..].chars().next() {
+            match node.children.get(&first) {
+                Some((label, child)) if destination[matched..].starts_with(label.as_str()) => {
+                    matched += label.len();
+                    node = child;
+                    if let Some(account_id) = node.account_id {
+                        best = Some((matched, account_id));
+                    }
+                }
+                _ => break,
+            }
+        }
+        best
+    }
+}
+
+/// Length in bytes of the longest common prefix of both strings
+fn common_prefix_len(a: &str, b: &str) -> usize {
+    a.char_indices()
+        .zip(b.chars())
+        .find(|((_, a), b)| a != b)
+        .map(|((index, _), _)| index)
+        .unwrap_or_else(|| a.len().min(b.len()))
+}
+
+impl<'a> std::iter::FromIterator<(&'a String, &'a Uuid)> for PrefixIndex {
+    fn from_iter<I: IntoIterator<Item = (&'a String, &'a Uuid)>>(routes: I) -> Self {
+        let mut index = PrefixIndex::new();
+        for (prefix, account_id) in routes {
+            index.insert(prefix, *account_id);
+        }
+        index
+    }
+}
+
+/// How often the routes were looked up in the in-memory index
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RouteIndexStats {
+    /// Number of lookups which found a route
+    pub hits: u64,
+    /// Number of lookups which did not find any route
+    pub misses: u64,
+    /// Number of times the index was rebuilt because the routing table changed
+    pub rebuilds: u64,
+    /// Number of prefixes in the index
+    pub prefixes: usize,
+}
+
+/// A routing table along with the prefix index built from it
+type IndexedRoutes = (Arc<HashMap<String, Uuid>>, Arc<PrefixIndex>);
+
+/// The routing table kept in memory by the stores, along with its prefix index.
+///
+/// The stores replace the routing table whenever they load it, and the index is only
+/// rebuilt if the routes changed. Each rebuild is counted in the `router.index.rebuilds`
+/// metric.
+pub struct RouteIndex {
+    routes: RwLock<IndexedRoutes>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    rebuilds: AtomicU64,
+}
+
+impl Default for RouteIndex {
+    fn default() -> Self {
+        RouteIndex {
+            routes: RwLock::new((Arc::new(HashMap::new()), Arc::new(PrefixIndex::new()))),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            rebuilds: AtomicU64::new(0),
+        }
+    }
+}
+
+impl RouteIndex {
+    pub fn new() -> Self {
+        RouteIndex::default()
+    }
+
+    /// Replaces the routing table, rebuilding the index if the routes changed.
+    /// Returns whether they did
+    pub fn update(&self, routes: HashMap<String, Uuid>) -> bool {
+        if *self.routes.read().0 == routes {
+            return false;
+        }
+        let index: PrefixIndex = routes.iter().collect();
+        debug!("Rebuilt the route index with {} prefixes", index.len());
+        *self.routes.write() = (Arc::new(routes), Arc::new(index));
+        self.rebuilds.fetch_add(1, Ordering::Relaxed);
+        recorder().increment_counter(Key::from_name("router.index.rebuilds"), 1);
+        true
+    }
+
+    /// The current routing table
+    pub fn routing_table(&self) -> Arc<HashMap<String, Uuid>> {
+        self.routes.read().0.clone()
+    }
+
+    /// Returns the longest prefix of the destination in the routing table, as its length
+    /// and the account it routes to
+    pub fn lookup(&self, destination: &str) -> Option<(usize, Uuid)> {
+        let index = self.routes.read().1.clone();
+        let route = index.longest_match(destination);
+        let counter = if route.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        route
+    }
+
+    pub fn stats(&self) -> RouteIndexStats {
+        RouteIndexStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            rebuilds: self.rebuilds.load(Ordering::Relaxed),
+            prefixes: self.routes.read().1.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(prefixes: &[&str]) -> (PrefixIndex, Vec<Uuid>) {
+        let ids: Vec<Uuid> = (0..prefixes.len())
+            .map(|i| Uuid::from_u128(i as u128))
+            .collect();
+        let mut index = PrefixIndex::new();
+        for (prefix, id) in prefixes.iter().zip(ids.iter()) {
+            index.insert(prefix, *id);
+        }
+        (index, ids)
+    }
+
+    #[test]
+    fn finds_the_longest_matching_prefix() {
+        let (index, ids) = index(&[
+            "",
+            "example.",
+            "example.alice",
+            "example.alicia",
+            "example.b",
+            "test.",
+        ]);
+        assert_eq!(index.len(), 6);
+        assert_eq!(index.longest_match("example.alice"), Some((13, ids[2])));
+        assert_eq!(
+            index.longest_match("example.alice.1234"),
+            Some((13, ids[2]))
+        );
+        assert_eq!(index.longest_match("example.alicia"), Some((14, ids[3])));
+        assert_eq!(index.longest_match("example.ali"), Some((8, ids[1])));
+        assert_eq!(index.longest_match("example.bob"), Some((9, ids[4])));
+        assert_eq!(index.longest_match("test.charlie"), Some((5, ids[5])));
+        assert_eq!(index.longest_match("private.dave"), Some((0, ids[0])));
+    }
+
+    #[test]
+    fn without_catch_all_route_unknown_destinations_do_not_match() {
+        let (index, ids) = index(&["example.alice", "example.al"]);
+        assert_eq!(index.longest_match("example.alice"), Some((13, ids[0])));
+        assert_eq!(index.longest_match("example.alfred"), Some((10, ids[1])));
+        assert_eq!(index.longest_match("example.a"), None);
+        assert_eq!(index.longest_match("test.alice"), None);
+    }
+
+    #[test]
+    fn inserting_a_prefix_twice_replaces_its_account() {
+        let (mut index, _) = index(&["example.alice"]);
+        let id = Uuid::from_u128(42);
+        index.insert("example.alice", id);
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.longest_match("example.alice"), Some((13, id)));
+    }
+
+    #[test]
+    fn only_rebuilds_when_the_routes_change() {
+        let routes: HashMap<String, Uuid> = vec![("example.alice".to_string(), Uuid::from_u128(1))]
+            .into_iter()
+            .collect();
+        let index = RouteIndex::new();
+        assert!(index.update(routes.clone()));
+        assert!(!index.update(routes));
+
+        assert!(index.lookup("example.alice.1234").is_some());
+        assert!(index.lookup("example.bob").is_none());
+        assert_eq!(
+            index.stats(),
+            RouteIndexStats {
+                hits: 1,
+                misses: 1,
+                rebuilds: 1,
+                prefixes: 1,
+            }
+        );
+    }
+}
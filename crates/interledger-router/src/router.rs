@@ -1,9 +1,81 @@
 use super::RouterStore;
 use async_trait::async_trait;
-use interledger_packet::{ErrorCode, RejectBuilder};
+use interledger_packet::{
+    matches_prefix, Address, AllocationScheme, ErrorCode, PrepareBuilder, RejectBuilder,
+};
 use interledger_service::*;
+use metrics::{recorder, Key};
+use parking_lot::Mutex;
+use rand::Rng;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::str;
-use tracing::{error, trace};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, trace};
+use uuid::Uuid;
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// One of several next hops configured for a prefix.
+///
+/// Next hops with the lowest `priority` are tried first. Among the next hops with
+/// the same priority, one is picked at random in proportion to its `weight` (next
+/// hops with a weight of 0 are only used if all others with their priority failed).
+/// If a next hop rejects the packet with a connection-level error (`T01: Peer Unreachable`
+/// or `T02: Peer Busy`), the packet is retried with the next candidate.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct NextHop {
+    /// The account to forward packets to
+    pub account_id: Uuid,
+    /// Relative share of the packets this next hop receives. Defaults to 1
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Next hops with a lower priority are only used if all those with a higher one failed.
+    /// 0 is the highest priority and the default
+    #[serde(default)]
+    pub priority: u32,
+}
+
+/// Returns whether a reject means the next hop could not be reached,
+/// so the packet may be retried with another one
+fn is_connection_error(code: ErrorCode) -> bool {
+    code == ErrorCode::T01_PEER_UNREACHABLE || code == ErrorCode::T02_PEER_BUSY
+}
+
+/// Orders the accounts to try, by priority and then randomly according to their weights
+fn order_next_hops(next_hops: &[NextHop]) -> Vec<Uuid> {
+    let mut remaining = next_hops.to_vec();
+    remaining.sort_by_key(|next_hop| next_hop.priority);
+
+    let mut rng = rand::thread_rng();
+    let mut ordered = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        let priority = remaining[0].priority;
+        let group_len = remaining
+            .iter()
+            .take_while(|next_hop| next_hop.priority == priority)
+            .count();
+        let total_weight: u64 = remaining[..group_len]
+            .iter()
+            .map(|next_hop| u64::from(next_hop.weight))
+            .sum();
+
+        let mut index = 0;
+        if total_weight > 0 {
+            let mut point = rng.gen_range(0, total_weight);
+            while point >= u64::from(remaining[index].weight) {
+                point -= u64::from(remaining[index].weight);
+                index += 1;
+            }
+        }
+        ordered.push(remaining.remove(index).account_id);
+    }
+    ordered
+}
 
 /// # Interledger Router
 ///
@@ -15,6 +87,16 @@ use tracing::{error, trace};
 /// The router implements the IncomingService trait and uses the routing table
 /// to determine the `to` (or "next hop") Account for the given request.
 ///
+/// Prefixes can also be routed to multiple next hops with `with_next_hops`, in which case
+/// the router balances packets between them and fails over to the other candidates if
+/// a next hop cannot be reached. These take precedence over routing table entries
+/// for the same prefix.
+///
+/// With `with_loop_protection`, the router also rejects packets which would loop: those
+/// addressed to the node's own address space that no local account matches (which would
+/// otherwise be forwarded to a parent that routes them straight back) and those which would
+/// be forwarded back to the account they arrived from.
+///
 /// Note that the router does **not**:
 ///   - apply exchange rates or fees to the Prepare packet
 ///   - adjust account balances
@@ -23,9 +105,39 @@ use tracing::{error, trace};
 /// That is done by OutgoingServices.
 
 #[derive(Clone)]
-pub struct Router<S, O> {
+pub struct Router<S: AccountStore, O> {
     store: S,
     next: O,
+    next_hops: Arc<HashMap<String, Vec<NextHop>>>,
+    loop_protection: bool,
+    account_cache: Option<AccountCache<S::Account>>,
+}
+
+/// The accounts recently loaded from the store, with the time they were loaded at
+#[derive(Clone)]
+struct AccountCache<A> {
+    ttl: Duration,
+    accounts: Arc<Mutex<HashMap<Uuid, (A, Instant)>>>,
+}
+
+impl<A: Account> AccountCache<A> {
+    fn get(&self, account_id: &Uuid) -> Option<A> {
+        let accounts = self.accounts.lock();
+        let (account, loaded_at) = accounts.get(account_id)?;
+        if loaded_at.elapsed() < self.ttl {
+            Some(account.clone())
+        } else {
+            None
+        }
+    }
+
+    fn insert(&self, account: A) {
+        let mut accounts = self.accounts.lock();
+        let ttl = self.ttl;
+        // Drop the expired accounts so that deleted accounts do not pile up
+        accounts.retain(|_, (_, loaded_at)| loaded_at.elapsed() < ttl);
+        accounts.insert(account.id(), (account, Instant::now()));
+    }
 }
 
 impl<S, O> Router<S, O>
@@ -34,10 +146,179 @@ where
     O: OutgoingService<S::Account>,
 {
     pub fn new(store: S, next: O) -> Self {
-        Router { store, next }
+        Router {
+            store,
+            next,
+            next_hops: Arc::new(HashMap::new()),
+            loop_protection: false,
+            account_cache: None,
+        }
+    }
+
+    /// Keeps the accounts the packets are forwarded to in memory for the given duration,
+    /// so that most packets can be forwarded without loading the account from the store.
+    /// Changes to the accounts take up to that long to be picked up.
+    ///
+    /// The cache hits and misses are counted in the `router.account_cache.hit` and
+    /// `router.account_cache.miss` metrics.
+    pub fn with_account_cache(mut self, ttl: Duration) -> Self {
+        self.account_cache = if ttl > Duration::from_secs(0) {
+            Some(AccountCache {
+                ttl,
+                accounts: Arc::new(Mutex::new(HashMap::new())),
+            })
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Routes each of the given prefixes to multiple next hops
+    pub fn with_next_hops(mut self, next_hops: HashMap<String, Vec<NextHop>>) -> Self {
+        self.next_hops = Arc::new(
+            next_hops
+                .into_iter()
+                .filter(|(_, next_hops)| !next_hops.is_empty())
+                .collect(),
+        );
+        self
+    }
+
+    /// Rejects packets for the node's own address space which have no local account
+    /// and packets which would be sent back to the account they came from
+    pub fn with_loop_protection(mut self, loop_protection: bool) -> Self {
+        self.loop_protection = loop_protection;
+        self
     }
 }
 
+impl<S, O> Router<S, O>
+where
+    S: AddressStore + RouterStore,
+    O: OutgoingService<S::Account> + Clone + Send + 'static,
+{
+    /// Rejects a packet which would loop, explaining why
+    fn reject_loop(&self, request: &IncomingRequest<S::Account>, message: &str) -> IlpResult {
+        debug!(
+            "Rejecting packet to {} from account {}: {}",
+            request.prepare.destination(),
+            request.from.id(),
+            message
+        );
+        Err(RejectBuilder {
+            code: ErrorCode::F02_UNREACHABLE,
+            message: message.as_bytes(),
+            triggered_by: Some(&self.store.get_ilp_address()),
+            data: &[],
+        }
+        .build())
+    }
+
+    /// Moves a packet addressed to one of the node's secondary addresses (assigned by its
+    /// other parents, when it is multihomed) to the same destination under its primary
+    /// address, so that it reaches the local accounts like the packets addressed to that one
+    fn to_primary_address_space(
+        &self,
+        mut request: IncomingRequest<S::Account>,
+        ilp_address: &Address,
+    ) -> IncomingRequest<S::Account> {
+        let secondary_addresses = self.store.get_secondary_ilp_addresses();
+        if secondary_addresses.is_empty() {
+            return request;
+        }
+        let destination = request.prepare.destination();
+        let secondary_address = secondary_addresses
+            .iter()
+            .find(|address| *address != ilp_address && matches_prefix(&destination, address));
+        if let Some(secondary_address) = secondary_address {
+            let suffix = &destination.as_bytes()[secondary_address.len()..];
+            let mut translated = Vec::with_capacity(ilp_address.len() + suffix.len());
+            translated.extend_from_slice(ilp_address.as_bytes());
+            translated.extend_from_slice(suffix);
+            if let Ok(translated) = Address::try_from(&translated[..]) {
+                trace!(
+                    "Packet to secondary address {} is routed as {}",
+                    destination,
+                    translated
+                );
+                let mut execution_condition = [0; 32];
+                execution_condition.copy_from_slice(request.prepare.execution_condition());
+                request.prepare = PrepareBuilder {
+                    destination: translated,
+                    amount: request.prepare.amount(),
+                    expires_at: request.prepare.expires_at(),
+                    execution_condition: &execution_condition,
+                    data: request.prepare.data(),
+                }
+                .build();
+            }
+        }
+        request
+    }
+
+    /// Sends the request to each candidate in turn, until one of them
+    /// returns something other than a connection-level error
+    async fn send_to_candidates(
+        &mut self,
+        request: IncomingRequest<S::Account>,
+        candidates: Vec<Uuid>,
+    ) -> IlpResult {
+        let ilp_address = self.store.get_ilp_address();
+        let mut result = Err(RejectBuilder {
+            code: ErrorCode::F02_UNREACHABLE,
+            message: &[],
+            triggered_by: Some(&ilp_address),
+            data: &[],
+        }
+        .build());
+
+        for account_id in candidates {
+            let account = match get_account(&self.store, &self.account_cache, account_id).await {
+                Ok(account) => account,
+                Err(_) => continue,
+            };
+
+            let mut next = self.next.clone();
+            result = next
+                .send_request(request.clone().into_outgoing(account))
+                .await;
+            match result {
+                Err(ref reject) if is_connection_error(reject.code()) => debug!(
+                    "Next hop {} could not be reached ({:?}), trying the next candidate",
+                    account_id,
+                    reject.code()
+                ),
+                _ => return result,
+            }
+        }
+        result
+    }
+}
+
+/// Loads the account from the cache, or from the store if it is not cached
+async fn get_account<S: RouterStore>(
+    store: &S,
+    account_cache: &Option<AccountCache<S::Account>>,
+    account_id: Uuid,
+) -> Result<S::Account, ()> {
+    if let Some(cache) = account_cache {
+        if let Some(account) = cache.get(&account_id) {
+            recorder().increment_counter(Key::from_name("router.account_cache.hit"), 1);
+            return Ok(account);
+        }
+        recorder().increment_counter(Key::from_name("router.account_cache.miss"), 1);
+    }
+    let account = store
+        .get_accounts(vec![account_id])
+        .await
+        .map_err(|_| error!("No record found for account: {}", account_id))?
+        .remove(0);
+    if let Some(cache) = account_cache {
+        cache.insert(account.clone());
+    }
+    Ok(account)
+}
+
 #[async_trait]
 impl<S, O> IncomingService<S::Account> for Router<S, O>
 where
@@ -46,66 +327,100 @@ where
 {
     /// Figures out the next node to pass the received Prepare packet to.
     ///
-    /// Firstly, it checks if there is a direct path for that account and uses that.
-    /// If not it scans through the routing table and checks if the route prefix matches
-    /// the prepare packet's destination or if it's a catch-all address (i.e. empty prefix)
+    /// It looks up the longest route prefix matching the prepare packet's destination, which
+    /// may be the destination itself or a catch-all address (i.e. empty prefix).
+    /// If one of the prefixes configured with multiple next hops matches at least as much of
+    /// the destination, the packet is sent to those next hops instead.
+    ///
+    /// Packets addressed to the node's secondary addresses are routed as if they were
+    /// addressed to its primary address.
     async fn handle_request(&mut self, request: IncomingRequest<S::Account>) -> IlpResult {
-        let destination = request.prepare.destination();
-        let mut next_hop = None;
-        let routing_table = self.store.routing_table();
         let ilp_address = self.store.get_ilp_address();
-
-        // Check if we have a direct path for that account or if we need to scan
-        // through the routing table
+        let request = self.to_primary_address_space(request, &ilp_address);
+        let destination = request.prepare.destination();
         let dest: &str = &destination;
-        if let Some(account_id) = routing_table.get(dest) {
-            trace!(
-                "Found direct route for address: \"{}\". Account: {}",
-                destination,
-                account_id
-            );
-            next_hop = Some(*account_id);
-        } else if !routing_table.is_empty() {
-            let mut matching_prefix = "";
-            let routing_table = self.store.routing_table();
-            for (ref prefix, account) in (*routing_table).iter() {
-                // Check if the route prefix matches or is empty (meaning it's a catch-all address)
-                if (prefix.is_empty() || dest.starts_with(prefix.as_str()))
-                    && prefix.len() >= matching_prefix.len()
-                {
-                    next_hop.replace(*account);
-                    matching_prefix = prefix.as_str();
-                }
-            }
-            if let Some(account_id) = next_hop {
+
+        // Look up the longest prefix of the destination in the routing table
+        let (next_hop, matching_len) = match self.store.route_for(dest) {
+            Some((matching_len, account_id)) => {
                 trace!(
                     "Found matching route for address: \"{}\". Prefix: \"{}\", account: {}",
                     destination,
-                    matching_prefix,
+                    &dest[..matching_len],
                     account_id,
                 );
+                (Some(account_id), matching_len)
+            }
+            None => {
+                if self.next_hops.is_empty() {
+                    error!("Unable to route request because no route matches the destination");
+                }
+                (None, 0)
+            }
+        };
+
+        let multipath = self
+            .next_hops
+            .iter()
+            .filter(|(prefix, _)| dest.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len());
+        if self.loop_protection && matches_prefix(dest, &ilp_address) {
+            let route_len = multipath
+                .map(|(prefix, _)| prefix.len())
+                .into_iter()
+                .chain(next_hop.map(|_| matching_len))
+                .max();
+            if route_len.unwrap_or(0) <= ilp_address.len() {
+                return self.reject_loop(
+                    &request,
+                    "Destination is in this node's address space but does not match any local account",
+                );
+            }
+        }
+
+        if let Some((prefix, next_hops)) = multipath {
+            if next_hop.is_none() || prefix.len() >= matching_len {
+                let mut candidates = order_next_hops(next_hops);
+                if self.loop_protection {
+                    let from = request.from.id();
+                    candidates.retain(|account_id| *account_id != from);
+                    if candidates.is_empty() {
+                        return self.reject_loop(
+                            &request,
+                            "Packet would be routed back to the account it came from",
+                        );
+                    }
+                }
+                trace!(
+                    "Found multiple next hops for address: \"{}\". Prefix: \"{}\", candidates: {:?}",
+                    destination,
+                    prefix,
+                    candidates,
+                );
+                return self.send_to_candidates(request, candidates).await;
             }
-        } else {
-            error!("Unable to route request because routing table is empty");
         }
 
         if let Some(account_id) = next_hop {
+            if self.loop_protection && account_id == request.from.id() {
+                return self.reject_loop(
+                    &request,
+                    "Packet would be routed back to the account it came from",
+                );
+            }
             let mut next = self.next.clone();
-            match self.store.get_accounts(vec![account_id]).await {
-                Ok(mut accounts) => {
-                    let request = request.into_outgoing(accounts.remove(0));
+            match get_account(&self.store, &self.account_cache, account_id).await {
+                Ok(account) => {
+                    let request = request.into_outgoing(account);
                     next.send_request(request).await
                 }
-                Err(_) => {
-                    error!("No record found for account: {}", account_id);
-                    Err(RejectBuilder {
-                        code: ErrorCode::F02_UNREACHABLE,
-                        message: &[],
-                        triggered_by: Some(&ilp_address),
-                        data: &[],
-                    }
-                    .build())
+                Err(_) => Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    triggered_by: Some(&ilp_address),
+                    data: &[],
                 }
+                .build()),
             }
         } else {
             error!(
@@ -114,7 +429,7 @@ where
                     // Log a warning if the global prefix does not match
                     let destination = request.prepare.destination();
                     if destination.scheme() != ilp_address.scheme()
-                        && destination.scheme() != "peer"
+                        && destination.allocation_scheme() != AllocationScheme::Peer
                     {
                         format!(
                         " (warning: address does not start with the right scheme prefix, expected: \"{}\")",
@@ -141,7 +456,7 @@ where
 mod tests {
     use super::*;
     use interledger_errors::*;
-    use interledger_packet::{Address, FulfillBuilder, PrepareBuilder};
+    use interledger_packet::{Address, FulfillBuilder, Prepare, PrepareBuilder};
     use interledger_service::outgoing_service_fn;
     use once_cell::sync::Lazy;
     use parking_lot::Mutex;
@@ -433,4 +748,497 @@ mod tests {
         assert!(result.is_ok());
         assert_eq!(to.lock().take().unwrap().0, id2);
     }
+
+    fn test_prepare() -> Prepare {
+        PrepareBuilder {
+            destination: Address::from_str("example.destination").unwrap(),
+            amount: 100,
+            execution_condition: &[1; 32],
+            expires_at: UNIX_EPOCH,
+            data: &[],
+        }
+        .build()
+    }
+
+    #[test]
+    fn orders_next_hops_by_priority_and_weight() {
+        let id0 = Uuid::from_slice(&[0; 16]).unwrap();
+        let id1 = Uuid::from_slice(&[1; 16]).unwrap();
+        let id2 = Uuid::from_slice(&[2; 16]).unwrap();
+        let next_hops = vec![
+            NextHop {
+                account_id: id2,
+                weight: 1,
+                priority: 1,
+            },
+            NextHop {
+                account_id: id1,
+                weight: 0,
+                priority: 0,
+            },
+            NextHop {
+                account_id: id0,
+                weight: 5,
+                priority: 0,
+            },
+        ];
+        for _ in 0..10 {
+            assert_eq!(order_next_hops(&next_hops), vec![id0, id1, id2]);
+        }
+    }
+
+    #[tokio::test]
+    async fn next_hops_take_precedence_over_routing_table() {
+        let id0 = Uuid::from_slice(&[0; 16]).unwrap();
+        let id1 = Uuid::from_slice(&[1; 16]).unwrap();
+        let to: Arc<Mutex<Option<TestAccount>>> = Arc::new(Mutex::new(None));
+        let to_clone = to.clone();
+        let mut router = Router::new(
+            TestStore {
+                routes: vec![("example.".to_string(), id1)].into_iter().collect(),
+            },
+            outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
+                *to_clone.lock() = Some(request.to);
+
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            }),
+        )
+        .with_next_hops(
+            vec![(
+                "example.".to_string(),
+                vec![NextHop {
+                    account_id: id0,
+                    weight: 1,
+                    priority: 0,
+                }],
+            )]
+            .into_iter()
+            .collect(),
+        );
+
+        let result = router
+            .handle_request(IncomingRequest {
+                from: TestAccount(id1),
+                prepare: test_prepare(),
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(to.lock().take().unwrap().0, id0);
+    }
+
+    #[tokio::test]
+    async fn fails_over_on_connection_errors_only() {
+        let id0 = Uuid::from_slice(&[0; 16]).unwrap();
+        let id1 = Uuid::from_slice(&[1; 16]).unwrap();
+        let id2 = Uuid::from_slice(&[2; 16]).unwrap();
+        let tried: Arc<Mutex<Vec<Uuid>>> = Arc::new(Mutex::new(Vec::new()));
+        let tried_clone = tried.clone();
+        let mut router = Router::new(
+            TestStore {
+                routes: HashMap::new(),
+            },
+            outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
+                tried_clone.lock().push(request.to.0);
+                let code = if request.to.0 == id0 {
+                    ErrorCode::T01_PEER_UNREACHABLE
+                } else {
+                    ErrorCode::F99_APPLICATION_ERROR
+                };
+                Err(RejectBuilder {
+                    code,
+                    message: &[],
+                    triggered_by: None,
+                    data: &[],
+                }
+                .build())
+            }),
+        )
+        .with_next_hops(
+            vec![(
+                String::new(),
+                (0..3)
+                    .map(|priority| NextHop {
+                        account_id: [id0, id1, id2][priority],
+                        weight: 1,
+                        priority: priority as u32,
+                    })
+                    .collect(),
+            )]
+            .into_iter()
+            .collect(),
+        );
+
+        let reject = router
+            .handle_request(IncomingRequest {
+                from: TestAccount(id2),
+                prepare: test_prepare(),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F99_APPLICATION_ERROR);
+        assert_eq!(*tried.lock(), vec![id0, id1]);
+    }
+
+    fn fulfilling_router(
+        routes: Vec<(&str, Uuid)>,
+    ) -> Router<TestStore, impl OutgoingService<TestAccount> + Clone> {
+        Router::new(
+            TestStore {
+                routes: routes
+                    .into_iter()
+                    .map(|(prefix, account_id)| (prefix.to_string(), account_id))
+                    .collect(),
+            },
+            outgoing_service_fn(|_| {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            }),
+        )
+    }
+
+    fn prepare_to(destination: &str) -> Prepare {
+        PrepareBuilder {
+            destination: Address::from_str(destination).unwrap(),
+            amount: 100,
+            execution_condition: &[1; 32],
+            expires_at: UNIX_EPOCH,
+            data: &[],
+        }
+        .build()
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_local_address_with_loop_protection() {
+        let parent = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        let routes = vec![("", parent), ("example.connector.bob", bob)];
+
+        // Without loop protection, the packet is sent to the parent
+        let mut router = fulfilling_router(routes.clone());
+        let result = router
+            .handle_request(IncomingRequest {
+                from: TestAccount(Uuid::new_v4()),
+                prepare: prepare_to("example.connector.carl"),
+            })
+            .await;
+        assert!(result.is_ok());
+
+        let mut router = fulfilling_router(routes).with_loop_protection(true);
+        let reject = router
+            .handle_request(IncomingRequest {
+                from: TestAccount(Uuid::new_v4()),
+                prepare: prepare_to("example.connector.carl"),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F02_UNREACHABLE);
+        assert!(str::from_utf8(reject.message())
+            .unwrap()
+            .contains("address space"));
+
+        // Local accounts and other nodes' addresses are still routed
+        for destination in &["example.connector.bob.123", "example.connectorx.bob"] {
+            let result = router
+                .handle_request(IncomingRequest {
+                    from: TestAccount(Uuid::new_v4()),
+                    prepare: prepare_to(destination),
+                })
+                .await;
+            assert!(result.is_ok(), "{}", destination);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_packets_routed_back_to_sender_with_loop_protection() {
+        let parent = Uuid::new_v4();
+        let mut router = fulfilling_router(vec![("", parent)]).with_loop_protection(true);
+
+        let reject = router
+            .handle_request(IncomingRequest {
+                from: TestAccount(parent),
+                prepare: prepare_to("example.destination"),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F02_UNREACHABLE);
+        assert!(str::from_utf8(reject.message())
+            .unwrap()
+            .contains("routed back"));
+
+        let result = router
+            .handle_request(IncomingRequest {
+                from: TestAccount(Uuid::new_v4()),
+                prepare: prepare_to("example.destination"),
+            })
+            .await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn skips_sender_among_next_hops_with_loop_protection() {
+        let id0 = Uuid::from_slice(&[0; 16]).unwrap();
+        let id1 = Uuid::from_slice(&[1; 16]).unwrap();
+        let to: Arc<Mutex<Option<Uuid>>> = Arc::new(Mutex::new(None));
+        let to_clone = to.clone();
+        let mut router = Router::new(
+            TestStore {
+                routes: HashMap::new(),
+            },
+            outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
+                *to_clone.lock() = Some(request.to.0);
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            }),
+        )
+        .with_next_hops(
+            vec![(
+                String::new(),
+                vec![
+                    NextHop {
+                        account_id: id0,
+                        weight: 1,
+                        priority: 0,
+                    },
+                    NextHop {
+                        account_id: id1,
+                        weight: 1,
+                        priority: 1,
+                    },
+                ],
+            )]
+            .into_iter()
+            .collect(),
+        )
+        .with_loop_protection(true);
+
+        let result = router
+            .handle_request(IncomingRequest {
+                from: TestAccount(id0),
+                prepare: prepare_to("example.destination"),
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(to.lock().take().unwrap(), id1);
+    }
+
+    /// Counts the accounts loaded from the TestStore
+    #[derive(Clone)]
+    struct CountingStore {
+        inner: TestStore,
+        lookups: Arc<Mutex<usize>>,
+    }
+
+    #[async_trait]
+    impl AccountStore for CountingStore {
+        type Account = TestAccount;
+
+        async fn get_accounts(
+            &self,
+            account_ids: Vec<Uuid>,
+        ) -> Result<Vec<TestAccount>, AccountStoreError> {
+            *self.lookups.lock() += 1;
+            self.inner.get_accounts(account_ids).await
+        }
+
+        async fn get_account_id_from_username(
+            &self,
+            username: &Username,
+        ) -> Result<Uuid, AccountStoreError> {
+            self.inner.get_account_id_from_username(username).await
+        }
+    }
+
+    #[async_trait]
+    impl AddressStore for CountingStore {
+        async fn set_ilp_address(&self, ilp_address: Address) -> Result<(), AddressStoreError> {
+            self.inner.set_ilp_address(ilp_address).await
+        }
+
+        async fn clear_ilp_address(&self) -> Result<(), AddressStoreError> {
+            self.inner.clear_ilp_address().await
+        }
+
+        fn get_ilp_address(&self) -> Address {
+            self.inner.get_ilp_address()
+        }
+    }
+
+    impl RouterStore for CountingStore {
+        fn routing_table(&self) -> Arc<HashMap<String, Uuid>> {
+            self.inner.routing_table()
+        }
+    }
+
+    #[tokio::test]
+    async fn caches_accounts() {
+        let bob = Uuid::new_v4();
+        let store = CountingStore {
+            inner: TestStore {
+                routes: vec![("example.bob".to_string(), bob)].into_iter().collect(),
+            },
+            lookups: Arc::new(Mutex::new(0)),
+        };
+        let router = Router::new(
+            store.clone(),
+            outgoing_service_fn(|_| {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let mut uncached = router.clone();
+        let mut cached = router.with_account_cache(Duration::from_millis(100));
+
+        for _ in 0..3 {
+            cached
+                .handle_request(IncomingRequest {
+                    from: TestAccount(Uuid::new_v4()),
+                    prepare: prepare_to("example.bob"),
+                })
+                .await
+                .unwrap();
+        }
+        assert_eq!(*store.lookups.lock(), 1);
+
+        // The account is loaded again once it expires
+        tokio::time::delay_for(Duration::from_millis(150)).await;
+        cached
+            .handle_request(IncomingRequest {
+                from: TestAccount(Uuid::new_v4()),
+                prepare: prepare_to("example.bob"),
+            })
+            .await
+            .unwrap();
+        assert_eq!(*store.lookups.lock(), 2);
+
+        uncached
+            .handle_request(IncomingRequest {
+                from: TestAccount(Uuid::new_v4()),
+                prepare: prepare_to("example.bob"),
+            })
+            .await
+            .unwrap();
+        assert_eq!(*store.lookups.lock(), 3);
+    }
+
+    /// A TestStore which was also assigned secondary addresses
+    #[derive(Clone)]
+    struct MultihomedStore {
+        inner: TestStore,
+        secondary_addresses: Vec<Address>,
+    }
+
+    #[async_trait]
+    impl AccountStore for MultihomedStore {
+        type Account = TestAccount;
+
+        async fn get_accounts(
+            &self,
+            account_ids: Vec<Uuid>,
+        ) -> Result<Vec<TestAccount>, AccountStoreError> {
+            self.inner.get_accounts(account_ids).await
+        }
+
+        async fn get_account_id_from_username(
+            &self,
+            username: &Username,
+        ) -> Result<Uuid, AccountStoreError> {
+            self.inner.get_account_id_from_username(username).await
+        }
+    }
+
+    #[async_trait]
+    impl AddressStore for MultihomedStore {
+        async fn set_ilp_address(&self, ilp_address: Address) -> Result<(), AddressStoreError> {
+            self.inner.set_ilp_address(ilp_address).await
+        }
+
+        async fn clear_ilp_address(&self) -> Result<(), AddressStoreError> {
+            self.inner.clear_ilp_address().await
+        }
+
+        fn get_ilp_address(&self) -> Address {
+            self.inner.get_ilp_address()
+        }
+
+        fn get_secondary_ilp_addresses(&self) -> Vec<Address> {
+            self.secondary_addresses.clone()
+        }
+    }
+
+    impl RouterStore for MultihomedStore {
+        fn routing_table(&self) -> Arc<HashMap<String, Uuid>> {
+            self.inner.routing_table()
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_packets_to_secondary_addresses_like_the_primary_one() {
+        let bob = Uuid::new_v4();
+        let destinations = Arc::new(Mutex::new(Vec::new()));
+        let destinations_clone = destinations.clone();
+        let mut router = Router::new(
+            MultihomedStore {
+                inner: TestStore {
+                    routes: vec![("example.connector.bob".to_string(), bob)]
+                        .into_iter()
+                        .collect(),
+                },
+                secondary_addresses: vec![Address::from_str("example.other-parent.node").unwrap()],
+            },
+            outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
+                destinations_clone
+                    .lock()
+                    .push((request.to.id(), request.prepare.destination()));
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            }),
+        )
+        .with_loop_protection(true);
+
+        for destination in &[
+            "example.connector.bob.abc",
+            "example.other-parent.node.bob.abc",
+        ] {
+            router
+                .handle_request(IncomingRequest {
+                    from: TestAccount(Uuid::new_v4()),
+                    prepare: prepare_to(destination),
+                })
+                .await
+                .unwrap();
+        }
+        let primary_destination = Address::from_str("example.connector.bob.abc").unwrap();
+        assert_eq!(
+            *destinations.lock(),
+            vec![
+                (bob, primary_destination.clone()),
+                (bob, primary_destination)
+            ]
+        );
+
+        // Like the primary address space, the secondary one only has the local accounts
+        let result = router
+            .handle_request(IncomingRequest {
+                from: TestAccount(Uuid::new_v4()),
+                prepare: prepare_to("example.other-parent.node.carl"),
+            })
+            .await;
+        assert_eq!(result.unwrap_err().code(), ErrorCode::F02_UNREACHABLE);
+    }
 }
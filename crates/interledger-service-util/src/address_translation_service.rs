@@ -0,0 +1,290 @@
+use async_trait::async_trait;
+use interledger_packet::{Address, ErrorCode, PrepareBuilder, RejectBuilder};
+use interledger_service::{Account, AddressStore, IlpResult, OutgoingRequest, OutgoingService};
+use std::convert::TryInto;
+use std::str::FromStr;
+use tracing::{debug, trace};
+
+/// An account whose peer expects the packets to be addressed under its own prefix,
+/// used by the [`AddressTranslationService`](./struct.AddressTranslationService.html)
+pub trait AddressTranslationAccount: Account {
+    /// The prefix of the destinations which are rewritten in the packets sent to the account,
+    /// and the peer's own prefix it is replaced with
+    fn address_translation(&self) -> Option<(&Address, &Address)> {
+        None
+    }
+}
+
+/// Replaces the `from` prefix of the address with `to`. Returns `None` if the address is not
+/// under the prefix, and an error if the translated address is not valid
+fn translate(address: &str, from: &Address, to: &Address) -> Option<Result<Address, ()>> {
+    let suffix = address.strip_prefix(&**from)?;
+    if !suffix.is_empty() && !suffix.starts_with('.') {
+        return None;
+    }
+    Some(Address::from_str(&format!("{}{}", to, suffix)).map_err(|_| ()))
+}
+
+/// # Address Translation Service
+///
+/// Outgoing Service which lets the node interoperate with legacy peers which expect the packets
+/// to be addressed under their own prefix rather than under the global one. The destinations
+/// under the account's translated prefix are rewritten to start with the peer's prefix, and the
+/// `triggered_by` addresses of the Rejects the peer responds with are translated back.
+///
+/// The packets to the accounts without any translation are forwarded untouched.
+///
+/// Requires an `AddressStore`, which is used to set the `triggered_by` field of the Rejects.
+#[derive(Clone)]
+pub struct AddressTranslationService<O, S> {
+    store: S,
+    next: O,
+}
+
+impl<O, S> AddressTranslationService<O, S> {
+    pub fn new(store: S, next: O) -> Self {
+        AddressTranslationService { store, next }
+    }
+}
+
+#[async_trait]
+impl<O, S, A> OutgoingService<A> for AddressTranslationService<O, S>
+where
+    O: OutgoingService<A> + Send + Sync + 'static,
+    S: AddressStore + Send + Sync + 'static,
+    A: AddressTranslationAccount + Send + Sync + 'static,
+{
+    /// On send request:
+    /// 1. If the account has no translation or the destination is not under its translated
+    ///    prefix, forward the request
+    /// 1. If the translated destination is not a valid address, reject it with `F02: Unreachable`
+    /// 1. Otherwise, forward the request with the translated destination and translate the
+    ///    `triggered_by` address of the Reject, if any, back to the global prefix
+    async fn send_request(&mut self, mut request: OutgoingRequest<A>) -> IlpResult {
+        let (from, to) = match request.to.address_translation() {
+            Some((from, to)) => (from.clone(), to.clone()),
+            None => return self.next.send_request(request).await,
+        };
+        let destination = request.prepare.destination();
+        let translated = match translate(&destination, &from, &to) {
+            Some(Ok(translated)) => translated,
+            Some(Err(())) => {
+                debug!(
+                    "Rejecting packet to account {}: the destination {} is too long once translated to the prefix {}",
+                    request.to.id(),
+                    destination,
+                    to
+                );
+                return Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"Destination cannot be translated to the peer's prefix",
+                    triggered_by: Some(&self.store.get_ilp_address()),
+                    data: &[],
+                }
+                .build());
+            }
+            None => return self.next.send_request(request).await,
+        };
+        trace!(
+            "Translating destination {} to {} for account {}",
+            destination,
+            translated,
+            request.to.id()
+        );
+        request.prepare = PrepareBuilder {
+            destination: translated,
+            amount: request.prepare.amount(),
+            expires_at: request.prepare.expires_at(),
+            // The condition is always 32 bytes long
+            execution_condition: request.prepare.execution_condition().try_into().unwrap(),
+            data: request.prepare.data(),
+        }
+        .build();
+
+        self.next.send_request(request).await.map_err(|reject| {
+            let triggered_by = reject
+                .triggered_by()
+                .and_then(|address| translate(&address, &to, &from))
+                .and_then(Result::ok);
+            match triggered_by {
+                Some(triggered_by) => RejectBuilder {
+                    code: reject.code(),
+                    message: reject.message(),
+                    triggered_by: Some(&triggered_by),
+                    data: reject.data(),
+                }
+                .build(),
+                None => reject,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_errors::AddressStoreError;
+    use interledger_packet::{FulfillBuilder, Prepare};
+    use interledger_service::{outgoing_service_fn, Username};
+    use once_cell::sync::Lazy;
+    use std::time::SystemTime;
+    use uuid::Uuid;
+
+    static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+    static NODE_ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("g.node").unwrap());
+    static GLOBAL_PREFIX: Lazy<Address> = Lazy::new(|| Address::from_str("g.legacy").unwrap());
+    static PEER_PREFIX: Lazy<Address> = Lazy::new(|| Address::from_str("private.legacy").unwrap());
+
+    #[derive(Clone, Debug)]
+    struct TestAccount(bool);
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            Uuid::new_v4()
+        }
+
+        fn username(&self) -> &Username {
+            &ALICE
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &NODE_ADDRESS
+        }
+    }
+
+    impl AddressTranslationAccount for TestAccount {
+        fn address_translation(&self) -> Option<(&Address, &Address)> {
+            if self.0 {
+                Some((&GLOBAL_PREFIX, &PEER_PREFIX))
+            } else {
+                None
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestStore;
+
+    #[async_trait]
+    impl AddressStore for TestStore {
+        async fn set_ilp_address(&self, _: Address) -> Result<(), AddressStoreError> {
+            Ok(())
+        }
+
+        async fn clear_ilp_address(&self) -> Result<(), AddressStoreError> {
+            Ok(())
+        }
+
+        fn get_ilp_address(&self) -> Address {
+            NODE_ADDRESS.clone()
+        }
+    }
+
+    fn request(to: TestAccount, destination: &str) -> OutgoingRequest<TestAccount> {
+        OutgoingRequest {
+            from: TestAccount(false),
+            to,
+            original_amount: 100,
+            prepare: PrepareBuilder {
+                destination: Address::from_str(destination).unwrap(),
+                amount: 100,
+                expires_at: SystemTime::now(),
+                execution_condition: &[1; 32],
+                data: b"data",
+            }
+            .build(),
+        }
+    }
+
+    fn service() -> AddressTranslationService<impl OutgoingService<TestAccount> + Clone, TestStore>
+    {
+        AddressTranslationService::new(
+            TestStore,
+            outgoing_service_fn(|request| {
+                let prepare: &Prepare = &request.prepare;
+                if prepare.destination().to_string().ends_with("reject") {
+                    Err(RejectBuilder {
+                        code: ErrorCode::F99_APPLICATION_ERROR,
+                        message: prepare.destination().as_ref(),
+                        triggered_by: Some(&prepare.destination()),
+                        data: prepare.data(),
+                    }
+                    .build())
+                } else {
+                    Ok(FulfillBuilder {
+                        fulfillment: &[0; 32],
+                        data: prepare.destination().as_ref(),
+                    }
+                    .build())
+                }
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn translates_destinations_under_the_prefix() {
+        let fulfill = service()
+            .send_request(request(TestAccount(true), "g.legacy.bob"))
+            .await
+            .unwrap();
+        assert_eq!(fulfill.data(), b"private.legacy.bob");
+
+        let fulfill = service()
+            .send_request(request(TestAccount(true), "g.legacyish.bob"))
+            .await
+            .unwrap();
+        assert_eq!(fulfill.data(), b"g.legacyish.bob");
+
+        let fulfill = service()
+            .send_request(request(TestAccount(false), "g.legacy.bob"))
+            .await
+            .unwrap();
+        assert_eq!(fulfill.data(), b"g.legacy.bob");
+    }
+
+    #[tokio::test]
+    async fn translates_rejects_back() {
+        let reject = service()
+            .send_request(request(TestAccount(true), "g.legacy.bob.reject"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            reject.triggered_by(),
+            Some(Address::from_str("g.legacy.bob.reject").unwrap())
+        );
+        // the rest of the Reject is left untouched
+        assert_eq!(reject.code(), ErrorCode::F99_APPLICATION_ERROR);
+        assert_eq!(reject.message(), b"private.legacy.bob.reject");
+        assert_eq!(reject.data(), b"data");
+    }
+
+    #[tokio::test]
+    async fn rejects_addresses_too_long_once_translated() {
+        let reject = service()
+            .send_request(request(
+                TestAccount(true),
+                &format!("g.legacy.{}", "b".repeat(1010)),
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F02_UNREACHABLE);
+        assert_eq!(reject.triggered_by(), Some(NODE_ADDRESS.clone()));
+
+        let long = Address::from_str(&format!("private.{}", "a".repeat(1010))).unwrap();
+        let destination = format!("g.legacy.{}", "b".repeat(1000));
+        assert!(translate(&destination, &GLOBAL_PREFIX, &long)
+            .unwrap()
+            .is_err());
+        assert_eq!(
+            translate("g.legacy", &GLOBAL_PREFIX, &PEER_PREFIX),
+            Some(Ok(PEER_PREFIX.clone()))
+        );
+    }
+}
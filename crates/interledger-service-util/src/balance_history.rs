@@ -0,0 +1,115 @@
+use async_trait::async_trait;
+use interledger_errors::BalanceHistoryStoreError;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+/// The balance of an account at a point in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalanceSample {
+    /// When the sample was taken, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// The balance from the account holder's perspective (see `BalanceStore::get_balance`)
+    pub balance: i64,
+}
+
+impl BalanceSample {
+    /// Length of the binary encoding of a sample
+    pub const ENCODED_LEN: usize = 16;
+
+    /// Encodes the sample as its big-endian timestamp followed by its big-endian balance,
+    /// which is how the stores keep them compact
+    pub fn to_bytes(&self) -> [u8; BalanceSample::ENCODED_LEN] {
+        let mut bytes = [0; BalanceSample::ENCODED_LEN];
+        bytes[..8].copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.balance.to_be_bytes());
+        bytes
+    }
+
+    /// Decodes a sample encoded with `to_bytes`, returning `None` if it has the wrong length
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != BalanceSample::ENCODED_LEN {
+            return None;
+        }
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&bytes[..8]);
+        let mut balance = [0; 8];
+        balance.copy_from_slice(&bytes[8..]);
+        Some(BalanceSample {
+            timestamp: u64::from_be_bytes(timestamp),
+            balance: i64::from_be_bytes(balance),
+        })
+    }
+}
+
+/// Store trait which keeps a bounded history of each account's balance, so that dashboards
+/// can chart how the balances evolve without polling the node at a high frequency.
+///
+/// The history of each account is a ring buffer: once it holds `capacity` samples, each new
+/// sample replaces the oldest one.
+#[async_trait]
+pub trait BalanceHistoryStore {
+    /// Appends a sample of the current balance of every account to its history, taken at
+    /// `timestamp` (in milliseconds since the Unix epoch), and drops the samples beyond the
+    /// `capacity` most recent ones
+    async fn record_balance_samples(
+        &self,
+        timestamp: u64,
+        capacity: usize,
+    ) -> Result<(), BalanceHistoryStoreError>;
+
+    /// Loads the samples of the given account taken between `from` and `to` (inclusive, in
+    /// milliseconds since the Unix epoch), oldest first
+    async fn get_balance_history(
+        &self,
+        account_id: Uuid,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<BalanceSample>, BalanceHistoryStoreError>;
+}
+
+/// Spawns a task which samples the balances of all accounts on the given interval,
+/// keeping the `capacity` most recent samples of each account.
+pub fn start_balance_history<S>(
+    store: S,
+    interval: Duration,
+    capacity: usize,
+) -> tokio::task::JoinHandle<()>
+where
+    S: BalanceHistoryStore + Send + Sync + 'static,
+{
+    debug!(
+        "Sampling the balances every {:?}, keeping {} samples per account",
+        interval, capacity
+    );
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            if let Err(err) = store.record_balance_samples(timestamp, capacity).await {
+                error!("Error sampling the balances: {}", err);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_samples_compactly() {
+        let sample = BalanceSample {
+            timestamp: 1_600_000_000_000,
+            balance: -1234,
+        };
+        let bytes = sample.to_bytes();
+        assert_eq!(BalanceSample::from_bytes(&bytes), Some(sample));
+        assert_eq!(BalanceSample::from_bytes(&bytes[1..]), None);
+    }
+}
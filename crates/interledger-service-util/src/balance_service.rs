@@ -4,13 +4,20 @@ use interledger_errors::BalanceStoreError;
 use interledger_packet::{ErrorCode, RejectBuilder};
 use interledger_service::*;
 use interledger_settlement::core::{
-    types::{SettlementAccount, SettlementStore},
-    SettlementClient,
+    types::{
+        SettlementAccount, SettlementDirection, SettlementHistoryStore, SettlementRecord,
+        SettlementStore,
+    },
+    SettlementClient, SettlementScheduler,
 };
 use std::marker::PhantomData;
-use std::sync::{Arc, Mutex};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{fmt, time::Duration, time::Instant};
-use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc::error::TrySendError, watch};
 use tracing::{debug, error, info, trace, warn};
 use uuid::Uuid;
 
@@ -55,6 +62,40 @@ pub trait BalanceStore {
     ) -> Result<(i64, u64), BalanceStoreError>;
 }
 
+/// Counts the settlement tasks spawned after the packets are fulfilled or their delayed
+/// settlements expire, which have not completed yet, so that the node can wait for them
+/// before exiting
+#[derive(Clone, Default, Debug)]
+pub struct SettlementTasks {
+    in_progress: Arc<AtomicUsize>,
+}
+
+impl SettlementTasks {
+    /// Number of settlement tasks which have not completed yet
+    pub fn in_progress(&self) -> usize {
+        self.in_progress.load(Ordering::SeqCst)
+    }
+
+    /// Counts a settlement task as in progress until the returned guard is dropped
+    pub fn track(&self) -> SettlementTaskGuard {
+        self.in_progress.fetch_add(1, Ordering::SeqCst);
+        SettlementTaskGuard {
+            in_progress: self.in_progress.clone(),
+        }
+    }
+}
+
+/// Counts a settlement task as in progress until it is dropped
+pub struct SettlementTaskGuard {
+    in_progress: Arc<AtomicUsize>,
+}
+
+impl Drop for SettlementTaskGuard {
+    fn drop(&mut self) {
+        self.in_progress.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 /// # Balance Service
 ///
 /// Responsible for managing the balances of the account and the interaction with the Settlement Engine
@@ -65,6 +106,9 @@ pub struct BalanceService<S, O, A> {
     store: S,
     next: O,
     settlement_client: SettlementClient,
+    settlement_scheduler: Option<SettlementScheduler>,
+    settlement_tasks: SettlementTasks,
+    events: Option<EventBus>,
     policy: Policy,
     account_type: PhantomData<A>,
     channel_last_fail: Arc<Mutex<Instant>>,
@@ -72,7 +116,7 @@ pub struct BalanceService<S, O, A> {
 
 impl<S, O, A> BalanceService<S, O, A>
 where
-    S: AddressStore + BalanceStore + SettlementStore<Account = A>,
+    S: AddressStore + BalanceStore + SettlementStore<Account = A> + SettlementHistoryStore,
     O: OutgoingService<A>,
     A: Account + SettlementAccount,
 {
@@ -85,6 +129,9 @@ where
             store,
             next,
             settlement_client: SettlementClient::default(),
+            settlement_scheduler: None,
+            settlement_tasks: SettlementTasks::default(),
+            events: None,
             policy: match sender {
                 Some(tx) => Policy::TimeBased(tx),
                 None => Policy::ThresholdOnly,
@@ -93,12 +140,46 @@ where
             channel_last_fail: Arc::new(Mutex::new(Instant::now())),
         }
     }
+
+    /// Hands the settlements over to the scheduler, which retries them if the settlement
+    /// engine is unavailable, instead of sending them from the packet's task
+    pub fn with_settlement_scheduler(mut self, scheduler: SettlementScheduler) -> Self {
+        self.settlement_scheduler = Some(scheduler);
+        self
+    }
+
+    /// Counts the tasks it spawns to apply the balance changes of the fulfilled packets and
+    /// send the settlements they trigger in `tasks`
+    pub fn with_settlement_tasks(mut self, tasks: SettlementTasks) -> Self {
+        self.settlement_tasks = tasks;
+        self
+    }
+
+    /// Publishes the accounts' balances after fulfilled packets and the settlements it sends
+    pub fn with_event_bus(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Never settles, for nodes which only clear: the balances are only bounded by the
+    /// accounts' `min_balance`, and any amount the store would have settled is refunded
+    pub fn with_clearing_only(mut self) -> Self {
+        self.policy = Policy::Never;
+        self
+    }
 }
 
 #[async_trait]
 impl<S, O, A> OutgoingService<A> for BalanceService<S, O, A>
 where
-    S: AddressStore + BalanceStore + SettlementStore<Account = A> + Clone + Send + Sync + 'static,
+    S: AddressStore
+        + BalanceStore
+        + SettlementStore<Account = A>
+        + SettlementHistoryStore
+        + Clone
+        + Send
+        + Sync
+        + 'static,
     O: OutgoingService<A> + Send + Clone + 'static,
     A: SettlementAccount + Send + Sync + 'static,
 {
@@ -131,6 +212,8 @@ where
         let outgoing_amount = request.prepare.amount();
         let ilp_address = self.store.get_ilp_address();
         let settlement_client = self.settlement_client.clone();
+        let settlement_scheduler = self.settlement_scheduler.clone();
+        let events = self.events.clone();
 
         // Update the balance _before_ sending the settlement so that we don't accidentally send
         // multiple settlements for the same balance. While there will be a small moment of time (the delta
@@ -174,9 +257,21 @@ where
                         from_id,
                         to,
                         settlement_client,
+                        settlement_scheduler,
+                        events,
                         self.policy.clone(),
                         self.channel_last_fail.clone(),
+                        self.settlement_tasks.track(),
                     );
+                } else if let Some(ref events) = events {
+                    // The incoming amount was already taken from the balance of the account
+                    // the packet came from, even though nothing is credited to the other one
+                    events.publish(AccountEvent::PacketFulfilled {
+                        from_account_id: from_id,
+                        to_account_id: to.id(),
+                        incoming_amount,
+                        outgoing_amount,
+                    });
                 }
 
                 Ok(fulfill)
@@ -216,22 +311,37 @@ fn settle_or_rollback_later<Acct, Store>(
     from_id: Uuid,
     to: Acct,
     settlement_client: SettlementClient,
+    settlement_scheduler: Option<SettlementScheduler>,
+    events: Option<EventBus>,
     policy: Policy,
     channel_last_fail: Arc<Mutex<Instant>>,
+    task: SettlementTaskGuard,
 ) where
     Acct: SettlementAccount + Send + Sync + 'static,
-    Store: BalanceStore + SettlementStore<Account = Acct> + Send + Sync + 'static,
+    Store: BalanceStore
+        + SettlementStore<Account = Acct>
+        + SettlementHistoryStore
+        + Send
+        + Sync
+        + 'static,
 {
-    tokio::spawn(settle_or_rollback_now(
-        incoming_amount,
-        outgoing_amount,
-        store,
-        from_id,
-        to,
-        settlement_client,
-        policy,
-        channel_last_fail,
-    ));
+    tokio::spawn(async move {
+        let result = settle_or_rollback_now(
+            incoming_amount,
+            outgoing_amount,
+            store,
+            from_id,
+            to,
+            settlement_client,
+            settlement_scheduler,
+            events,
+            policy,
+            channel_last_fail,
+        )
+        .await;
+        drop(task);
+        result
+    });
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -242,12 +352,19 @@ async fn settle_or_rollback_now<Acct, Store>(
     from_id: Uuid,
     to: Acct,
     settlement_client: SettlementClient,
+    settlement_scheduler: Option<SettlementScheduler>,
+    events: Option<EventBus>,
     mut policy: Policy,
     channel_last_fail: Arc<Mutex<Instant>>,
 ) -> Result<(), ()>
 where
     Acct: SettlementAccount + Send + Sync + 'static,
-    Store: BalanceStore + SettlementStore<Account = Acct> + Send + Sync + 'static,
+    Store: BalanceStore
+        + SettlementStore<Account = Acct>
+        + SettlementHistoryStore
+        + Send
+        + Sync
+        + 'static,
 {
     let (balance, amount_to_settle) = store
         .update_balances_for_fulfill(to.id(), outgoing_amount)
@@ -263,6 +380,45 @@ where
         amount_to_settle
     );
 
+    if let Some(ref events) = events {
+        events.publish(AccountEvent::PacketFulfilled {
+            from_account_id: from_id,
+            to_account_id: to.id(),
+            incoming_amount,
+            outgoing_amount,
+        });
+        if amount_to_settle > 0 {
+            events.publish(AccountEvent::SettlementReserved {
+                account_id: to.id(),
+                amount: amount_to_settle,
+            });
+        }
+        publish_balances(&store, events, from_id, to.id(), balance).await;
+    }
+
+    if let Policy::Never = policy {
+        if amount_to_settle > 0 {
+            warn!(
+                "Account {} has a settle threshold although the node only clears, keeping {} in its balance",
+                to.id(),
+                amount_to_settle
+            );
+            return store
+                .refund_settlement(to.id(), amount_to_settle)
+                .map_err(|e| {
+                    error!(
+                        "Refunding account {} with the amount it would have settled failed, amount: {}: {}",
+                        to.id(),
+                        amount_to_settle,
+                        e
+                    )
+                })
+                .await
+                .map(|_| publish_refund(&events, to.id(), amount_to_settle));
+        }
+        return Ok(());
+    }
+
     if amount_to_settle == 0 {
         // so we might have some balance, but it's not over the threshold
         // this might still end up scheduling a no-op as we should really be comparing to
@@ -284,7 +440,49 @@ where
     // cancel a pending settlement always before trying it
     policy.clear_later(to.id(), channel_last_fail);
 
-    settle_or_rollback(store, to, amount_to_settle, settlement_client).await
+    settle_or_rollback(
+        store,
+        to,
+        amount_to_settle,
+        settlement_client,
+        settlement_scheduler,
+        events,
+    )
+    .await
+}
+
+/// Publishes the balance of the account the packet was sent to, which the store returned,
+/// and the balance of the account it came from, if anyone is listening
+async fn publish_balances<Store>(
+    store: &Store,
+    events: &EventBus,
+    from_id: Uuid,
+    to_id: Uuid,
+    to_balance: i64,
+) where
+    Store: BalanceStore,
+{
+    if !events.has_subscribers() {
+        return;
+    }
+    events.publish(AccountEvent::BalanceChanged {
+        account_id: to_id,
+        balance: to_balance,
+    });
+    match store.get_balance(from_id).await {
+        Ok(balance) => events.publish(AccountEvent::BalanceChanged {
+            account_id: from_id,
+            balance,
+        }),
+        Err(err) => warn!("Error loading the balance of account {}: {}", from_id, err),
+    }
+}
+
+/// Publishes that the amount taken out of the account's balance to be settled was put back
+fn publish_refund(events: &Option<EventBus>, account_id: Uuid, amount: u64) {
+    if let Some(events) = events {
+        events.publish(AccountEvent::SettlementRefunded { account_id, amount });
+    }
 }
 
 async fn settle_or_rollback<Store, Acct>(
@@ -292,9 +490,11 @@ async fn settle_or_rollback<Store, Acct>(
     to: Acct,
     amount: u64,
     client: SettlementClient,
+    scheduler: Option<SettlementScheduler>,
+    events: Option<EventBus>,
 ) -> Result<(), ()>
 where
-    Store: SettlementStore<Account = Acct> + 'static,
+    Store: SettlementStore<Account = Acct> + SettlementHistoryStore + 'static,
     Acct: SettlementAccount + 'static,
 {
     if amount == 0 {
@@ -303,6 +503,32 @@ where
     }
 
     if let Some(engine_details) = to.settlement_engine_details() {
+        if let Some(scheduler) = scheduler {
+            // The scheduler persists the settlement, and retries or refunds it if it fails
+            if scheduler.schedule(to.id(), amount).is_ok() {
+                debug!(
+                    "Scheduled settlement for account {} for {}",
+                    to.id(),
+                    amount
+                );
+                return Ok(());
+            }
+            store
+                .refund_settlement(to.id(), amount)
+                .map_err(|e| {
+                    error!(
+                        "Refunding account {} after failing to schedule settlement failed, amount: {}: {}",
+                        to.id(),
+                        amount,
+                        e
+                    )
+                })
+                .await?;
+            record_settlement(&store, to.id(), amount, SettlementDirection::Refunded).await;
+            publish_refund(&events, to.id(), amount);
+            return Ok(());
+        }
+
         let engine_url = engine_details.url;
         // Note that if this program crashes after changing the balance (in the PROCESS_FULFILL
         // script) and the send_settlement fails but the program isn't alive to hear that, the
@@ -336,12 +562,21 @@ where
                     )
                 })
                 .await?;
+            record_settlement(&store, to.id(), amount, SettlementDirection::Refunded).await;
+            publish_refund(&events, to.id(), amount);
         } else {
             info!(
                 "Settlement for account {} for {} succeeded",
                 to.id(),
                 amount
             );
+            record_settlement(&store, to.id(), amount, SettlementDirection::Outgoing).await;
+            if let Some(events) = events {
+                events.publish(AccountEvent::SettlementSent {
+                    account_id: to.id(),
+                    amount,
+                });
+            }
         }
     } else {
         debug!("Settlement for account {} for {} failed as the account has no settlement engine details",
@@ -351,19 +586,47 @@ where
     Ok(())
 }
 
+/// Adds the settlement sent without the scheduler to the account's settlement history
+async fn record_settlement<Store>(
+    store: &Store,
+    account_id: Uuid,
+    amount: u64,
+    direction: SettlementDirection,
+) where
+    Store: SettlementHistoryStore,
+{
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default();
+    let record = SettlementRecord {
+        timestamp,
+        direction,
+        amount,
+        id: None,
+    };
+    if let Err(err) = store.record_settlement(account_id, record).await {
+        error!(
+            "Error recording settlement for account {}: {}",
+            account_id, err
+        );
+    }
+}
+
 /// Captures the behaviour of either operating in a delayed settlement or threshold-only
-/// environment.
+/// environment, or in a clearing-only one which never settles.
 #[derive(Debug, Clone)]
 enum Policy {
     ThresholdOnly,
     TimeBased(tokio::sync::mpsc::Sender<ManageTimeout>),
+    Never,
 }
 
 impl Policy {
     /// Called to clear a pending timeout, if there's any
     fn clear_later(&mut self, account_id: Uuid, channel_last_fail: Arc<Mutex<Instant>>) {
         match *self {
-            Policy::ThresholdOnly => (),
+            Policy::ThresholdOnly | Policy::Never => (),
             Policy::TimeBased(ref mut sender) => Policy::drop_error(
                 sender.try_send(ManageTimeout::Clear(account_id)),
                 channel_last_fail,
@@ -374,7 +637,7 @@ impl Policy {
     /// Called to signal this account id needs to be settled later
     fn settle_later(&mut self, account_id: Uuid, channel_last_fail: Arc<Mutex<Instant>>) {
         match *self {
-            Policy::ThresholdOnly => (),
+            Policy::ThresholdOnly | Policy::Never => (),
             Policy::TimeBased(ref mut sender) => Policy::drop_error(
                 sender.try_send(ManageTimeout::Set(account_id)),
                 channel_last_fail,
@@ -478,15 +741,29 @@ impl fmt::Display for ExitReason {
 /// Start a background task for time based settlement. If time based settlement is configured but
 /// this task is never started, the time-based settlement does not happen and a warning is logged
 /// every minute on eligble random peering account.
+///
+/// If a `scheduler` is given, the delayed settlements are handed over to it like the
+/// ones triggered by the settle threshold. Otherwise the settlements which go through
+/// are published on the `events` bus, if one is given.
+///
+/// While `enabled` is false, the expired timeouts are set again instead of settling, so that
+/// the accounts are settled once it is switched back on.
+///
+/// The timeouts set after a new `delay` is sent through its channel use the new delay.
 pub fn start_delayed_settlement<St, Store, Acct>(
-    delay: Duration,
+    delay: watch::Receiver<Duration>,
     cmds: St,
     store: Store,
+    scheduler: Option<SettlementScheduler>,
+    events: Option<EventBus>,
+    enabled: Arc<AtomicBool>,
+    tasks: SettlementTasks,
 ) -> tokio::task::JoinHandle<()>
 where
     St: futures::stream::FusedStream<Item = ManageTimeout> + Send + Sync + 'static + Unpin,
     Store: BalanceStore
         + SettlementStore<Account = Acct>
+        + SettlementHistoryStore
         + AccountStore<Account = Acct>
         + Clone
         + Send
@@ -498,10 +775,13 @@ where
     tokio::spawn(async move {
         info!(
             "Starting to run delayed settlements with a timeout of {:?}",
-            delay
+            *delay.borrow()
         );
 
-        let exit_reason = run_timeouts_and_settle_on_delay(delay, cmds, store, client).await;
+        let exit_reason = run_timeouts_and_settle_on_delay(
+            delay, cmds, store, client, scheduler, events, enabled, tasks,
+        )
+        .await;
 
         info!(
             "Stopped running timeouts and delayed settlements: {}",
@@ -510,16 +790,22 @@ where
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn run_timeouts_and_settle_on_delay<St, Store, Acct>(
-    delay: Duration,
+    delay: watch::Receiver<Duration>,
     mut cmds: St,
     store: Store,
     client: SettlementClient,
+    scheduler: Option<SettlementScheduler>,
+    events: Option<EventBus>,
+    enabled: Arc<AtomicBool>,
+    tasks: SettlementTasks,
 ) -> ExitReason
 where
     St: futures::stream::FusedStream<Item = ManageTimeout> + Send + Sync + 'static + Unpin,
     Store: BalanceStore
         + SettlementStore<Account = Acct>
+        + SettlementHistoryStore
         + AccountStore<Account = Acct>
         + Clone
         + Send
@@ -550,6 +836,7 @@ where
                     }
                     ManageTimeout::Set(id) => {
                         let timeouts = &mut timeouts;
+                        let delay = *delay.borrow();
                         in_queue.entry(id).or_insert_with(move || {
                             let key = timeouts.insert(id, delay);
 
@@ -566,12 +853,22 @@ where
                         let id = expired.into_inner();
                         in_queue.remove(&id); // unsure if this can ever be none
 
+                        if !enabled.load(Ordering::Relaxed) {
+                            trace!("Delayed settlements are stopped, setting the timeout for account {} again", id);
+                            in_queue.insert(id, timeouts.insert(id, *delay.borrow()));
+                            continue;
+                        }
+
                         trace!("Delayed settlement for account {} expired", id);
 
                         let client = client.clone();
+                        let scheduler = scheduler.clone();
+                        let events = events.clone();
                         let store = store.clone();
+                        let task = tasks.track();
 
                         tokio::spawn(async move {
+                            let _task = task;
                             // bailing out instead of not re-scheduling on failing to load the
                             // account: it is assumed that if this account is valid and should be
                             // settled there is near-continouos traffic which would trigger either
@@ -603,8 +900,16 @@ where
                                 "Account {} balance at time-based settlement: {}, amount that needs to be settled: {}",
                                 to.id(), balance, amount_to_settle
                             );
+                            if amount_to_settle > 0 {
+                                if let Some(ref events) = events {
+                                    events.publish(AccountEvent::SettlementReserved {
+                                        account_id: id,
+                                        amount: amount_to_settle,
+                                    });
+                                }
+                            }
 
-                            settle_or_rollback(store, to, amount_to_settle, client).await
+                            settle_or_rollback(store, to, amount_to_settle, client, scheduler, events).await
                         });
                     },
                     Some(Err(e)) if e.is_shutdown() => {
@@ -633,11 +938,21 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use interledger_errors::{AddressStoreError, SettlementStoreError};
+    use interledger_errors::{
+        AccountStoreError, AddressStoreError, SettlementHistoryStoreError,
+        SettlementQueueStoreError, SettlementStoreError,
+    };
     use interledger_packet::{Address, FulfillBuilder, PrepareBuilder, RejectBuilder};
-    use interledger_settlement::core::types::SettlementEngineDetails;
+    use interledger_settlement::core::{
+        types::{
+            PendingMessage, PendingSettlement, SettlementEngineDetails, SettlementHistoryStore,
+            SettlementQueueStore, SettlementRecord,
+        },
+        RetryPolicy,
+    };
     use once_cell::sync::Lazy;
     use parking_lot::RwLock;
+    use std::collections::HashMap;
     use std::str::FromStr;
     use std::sync::Arc;
     use std::time::Duration;
@@ -662,6 +977,10 @@ mod tests {
         mock.assert();
         assert!(!*store.refunded_settlement.read());
         assert!(!*store.rejected_message.read());
+        let history = store.settlement_history.read();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].direction, SettlementDirection::Outgoing);
+        assert_eq!(history[0].amount, 1);
     }
 
     #[tokio::test]
@@ -708,6 +1027,116 @@ mod tests {
         mock.assert();
         assert!(*store.refunded_settlement.read());
         assert!(!*store.rejected_message.read());
+        let history = store.settlement_history.read();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].direction, SettlementDirection::Refunded);
+    }
+
+    #[tokio::test]
+    async fn clearing_only_refunds_instead_of_settling() {
+        let mock = mockito::mock("POST", mockito::Matcher::Any)
+            .create()
+            .expect(0);
+        let next = outgoing_service_fn(move |_| {
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"test data",
+            }
+            .build())
+        });
+        let store = TestStore::new(1);
+        let mut service = BalanceService::new(store.clone(), None, next).with_clearing_only();
+        let fulfill = service.send_request(TEST_REQUEST.clone()).await.unwrap();
+        assert_eq!(fulfill.data(), b"test data");
+
+        tokio::time::delay_for(Duration::from_millis(100u64)).await;
+        mock.assert();
+        assert!(*store.refunded_settlement.read());
+    }
+
+    #[tokio::test]
+    async fn publishes_the_amounts_reserved_for_settlement_and_refunded() {
+        let next = outgoing_service_fn(move |_| {
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"test data",
+            }
+            .build())
+        });
+        let store = TestStore::new(1);
+        let events = EventBus::new();
+        let mut receiver = events.subscribe();
+        let mut service = BalanceService::new(store.clone(), None, next)
+            .with_clearing_only()
+            .with_event_bus(events);
+        service.send_request(TEST_REQUEST.clone()).await.unwrap();
+
+        tokio::time::delay_for(Duration::from_millis(100u64)).await;
+        let mut published = Vec::new();
+        while let Ok(event) = receiver.try_recv() {
+            if let AccountEvent::BalanceChanged { .. } = event {
+                continue;
+            }
+            published.push(event);
+        }
+        let id = TEST_REQUEST.to.id();
+        assert_eq!(
+            published,
+            vec![
+                AccountEvent::PacketFulfilled {
+                    from_account_id: id,
+                    to_account_id: id,
+                    incoming_amount: 100,
+                    outgoing_amount: 100,
+                },
+                AccountEvent::SettlementReserved {
+                    account_id: id,
+                    amount: 1,
+                },
+                AccountEvent::SettlementRefunded {
+                    account_id: id,
+                    amount: 1,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn hands_settlements_to_scheduler() {
+        let mock = mockito::mock("POST", mockito::Matcher::Any)
+            .with_status(500)
+            .create();
+        let next = outgoing_service_fn(move |_| {
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"test data",
+            }
+            .build())
+        });
+        let store = TestStore::new(1);
+        let scheduler = SettlementScheduler::start(
+            store.clone(),
+            SettlementClient::default(),
+            RetryPolicy {
+                initial_backoff: Duration::from_secs(60),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let mut service = BalanceService::new(store.clone(), None, next)
+            .with_settlement_scheduler(scheduler.clone());
+        let fulfill = service.send_request(TEST_REQUEST.clone()).await.unwrap();
+        assert_eq!(fulfill.data(), b"test data");
+
+        tokio::time::delay_for(Duration::from_millis(100u64)).await;
+        mock.assert();
+        // The failed settlement is waiting to be retried instead of being refunded
+        let pending = scheduler.pending_settlements();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].attempts, 1);
+        assert_eq!(store.pending_settlements.read().len(), 1);
+        assert!(!*store.refunded_settlement.read());
     }
 
     #[tokio::test]
@@ -748,7 +1177,7 @@ mod tests {
 
     impl Account for TestAccount {
         fn id(&self) -> Uuid {
-            Uuid::new_v4()
+            Uuid::from_slice(&[1; 16]).unwrap()
         }
 
         fn username(&self) -> &Username {
@@ -782,6 +1211,8 @@ mod tests {
         amount_to_settle: u64,
         rejected_message: Arc<RwLock<bool>>,
         refunded_settlement: Arc<RwLock<bool>>,
+        pending_settlements: Arc<RwLock<HashMap<Uuid, PendingSettlement>>>,
+        settlement_history: Arc<RwLock<Vec<SettlementRecord>>>,
     }
 
     impl TestStore {
@@ -790,6 +1221,8 @@ mod tests {
                 amount_to_settle,
                 rejected_message: Arc::new(RwLock::new(false)),
                 refunded_settlement: Arc::new(RwLock::new(false)),
+                pending_settlements: Arc::new(RwLock::new(HashMap::new())),
+                settlement_history: Arc::new(RwLock::new(Vec::new())),
             }
         }
     }
@@ -812,7 +1245,7 @@ mod tests {
     #[async_trait]
     impl BalanceStore for TestStore {
         async fn get_balance(&self, _: Uuid) -> Result<i64, BalanceStoreError> {
-            unimplemented!()
+            Ok(0)
         }
 
         async fn update_balances_for_prepare(
@@ -857,8 +1290,8 @@ mod tests {
             _: Uuid,
             _: u64,
             _: Option<String>,
-        ) -> Result<(), SettlementStoreError> {
-            Ok(())
+        ) -> Result<u64, SettlementStoreError> {
+            Ok(0)
         }
 
         async fn refund_settlement(&self, _: Uuid, _: u64) -> Result<(), SettlementStoreError> {
@@ -867,6 +1300,90 @@ mod tests {
         }
     }
 
+    #[async_trait]
+    impl AccountStore for TestStore {
+        type Account = TestAccount;
+
+        async fn get_accounts(
+            &self,
+            ids: Vec<Uuid>,
+        ) -> Result<Vec<TestAccount>, AccountStoreError> {
+            Ok(ids.iter().map(|_| TEST_REQUEST.to.clone()).collect())
+        }
+
+        async fn get_account_id_from_username(
+            &self,
+            _: &Username,
+        ) -> Result<Uuid, AccountStoreError> {
+            Err(AccountStoreError::AccountNotFound(String::new()))
+        }
+    }
+
+    #[async_trait]
+    impl SettlementQueueStore for TestStore {
+        async fn save_pending_settlement(
+            &self,
+            settlement: PendingSettlement,
+        ) -> Result<(), SettlementQueueStoreError> {
+            self.pending_settlements
+                .write()
+                .insert(settlement.id, settlement);
+            Ok(())
+        }
+
+        async fn remove_pending_settlement(
+            &self,
+            id: Uuid,
+        ) -> Result<(), SettlementQueueStoreError> {
+            self.pending_settlements.write().remove(&id);
+            Ok(())
+        }
+
+        async fn load_pending_settlements(
+            &self,
+        ) -> Result<Vec<PendingSettlement>, SettlementQueueStoreError> {
+            Ok(self.pending_settlements.read().values().cloned().collect())
+        }
+
+        async fn save_pending_message(
+            &self,
+            _message: PendingMessage,
+        ) -> Result<(), SettlementQueueStoreError> {
+            Ok(())
+        }
+
+        async fn remove_pending_message(&self, _id: Uuid) -> Result<(), SettlementQueueStoreError> {
+            Ok(())
+        }
+
+        async fn load_pending_messages(
+            &self,
+        ) -> Result<Vec<PendingMessage>, SettlementQueueStoreError> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[async_trait]
+    impl SettlementHistoryStore for TestStore {
+        async fn record_settlement(
+            &self,
+            _: Uuid,
+            record: SettlementRecord,
+        ) -> Result<(), SettlementHistoryStoreError> {
+            self.settlement_history.write().push(record);
+            Ok(())
+        }
+
+        async fn get_settlement_history(
+            &self,
+            _: Uuid,
+            _: u64,
+            _: u64,
+        ) -> Result<Vec<SettlementRecord>, SettlementHistoryStoreError> {
+            Ok(Vec::new())
+        }
+    }
+
     static TEST_REQUEST: Lazy<OutgoingRequest<TestAccount>> = Lazy::new(|| {
         let url = mockito::server_url();
         OutgoingRequest {
@@ -0,0 +1,518 @@
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use interledger_errors::DedupStoreError;
+use interledger_packet::{Address, ErrorCode, Packet, Prepare, Reject, RejectBuilder};
+use interledger_service::*;
+use ring::digest::{Context, SHA256};
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+/// How often the response of a packet which is still in flight is looked up
+/// when a duplicate of it is received
+const IN_FLIGHT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// What the store knows about a Prepare packet, identified by its [dedup key](./fn.dedup_key.html)
+#[derive(Debug, Clone, PartialEq)]
+pub enum PacketStatus {
+    /// The packet was not seen before, and is now recorded as in flight
+    New,
+    /// The same packet was received before and is still being forwarded
+    InFlight,
+    /// The same packet was received before and answered with this Fulfill or Reject
+    Answered(Bytes),
+}
+
+/// Store trait which remembers the Prepare packets being forwarded and their responses,
+/// for as long as the packets are valid
+#[async_trait]
+pub trait DedupStore {
+    /// Records the packet with the given key as in flight for `ttl`, unless it was already
+    /// recorded, in which case its current status is returned instead
+    async fn start_packet(
+        &self,
+        key: [u8; 32],
+        ttl: Duration,
+    ) -> Result<PacketStatus, DedupStoreError>;
+
+    /// Saves the Fulfill or Reject the packet with the given key was answered with, for `ttl`
+    async fn save_packet_response(
+        &self,
+        key: [u8; 32],
+        response: Bytes,
+        ttl: Duration,
+    ) -> Result<(), DedupStoreError>;
+}
+
+/// Returns the key identifying the Prepare packets sent by the account which are duplicates
+/// of each other: the hash of the account's id and of the packet's execution condition,
+/// destination, amount and expiry.
+///
+/// The account is part of the key so that the response to a packet is never handed out to
+/// another account than the one which sent it.
+pub fn dedup_key(account_id: Uuid, prepare: &Prepare) -> [u8; 32] {
+    let expires_at = prepare
+        .expires_at()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut context = Context::new(&SHA256);
+    context.update(account_id.as_bytes());
+    context.update(prepare.execution_condition());
+    context.update(prepare.destination().as_ref());
+    context.update(&prepare.amount().to_be_bytes());
+    context.update(&expires_at.to_be_bytes());
+    let mut key = [0; 32];
+    key.copy_from_slice(context.finish().as_ref());
+    key
+}
+
+/// # Dedup Service
+///
+/// Incoming Service which forwards each Prepare packet only once, even if the account sends
+/// it again (for example because its HTTP client retried the request). The duplicates are
+/// answered with the Fulfill or Reject of the original packet, so the packet is neither
+/// forwarded twice nor reflected in the balances twice. Duplicates received while the original
+/// packet is still in flight wait for its response.
+///
+/// The packets and their responses are kept in the store until the packets expire, so the
+/// duplicates are also detected if they are received by another node sharing the same store.
+/// If the store fails, the packets are forwarded without being deduplicated.
+///
+/// Requires a `DedupStore` and an `AddressStore`
+#[derive(Clone)]
+pub struct DedupService<S, I, A> {
+    store: S,
+    next: I,
+    account_type: PhantomData<A>,
+}
+
+impl<S, I, A> DedupService<S, I, A>
+where
+    S: DedupStore + AddressStore,
+    I: IncomingService<A>,
+    A: Account,
+{
+    pub fn new(store: S, next: I) -> Self {
+        DedupService {
+            store,
+            next,
+            account_type: PhantomData,
+        }
+    }
+}
+
+impl<S, I, A> DedupService<S, I, A>
+where
+    S: DedupStore + AddressStore + Clone + Send + Sync + 'static,
+    I: IncomingService<A> + Clone + Send + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    /// Forwards the packet and saves its response before returning it.
+    ///
+    /// The packet is forwarded in its own task, so that its response is saved even if the
+    /// request is dropped (for example because the sender hung up), rather than leaving the
+    /// packet in flight and its duplicates waiting until it expires.
+    async fn forward(&mut self, key: [u8; 32], request: IncomingRequest<A>) -> IlpResult {
+        let expires_at = request.prepare.expires_at();
+        let store = self.store.clone();
+        let mut next = self.next.clone();
+        let forwarded = tokio::spawn(async move {
+            let result = next.handle_request(request).await;
+            let response = match &result {
+                Ok(fulfill) => Bytes::copy_from_slice(fulfill.as_ref()),
+                Err(reject) => Bytes::copy_from_slice(reject.as_ref()),
+            };
+            // Keeps the response until the packet expires, the duplicates can't be valid after that
+            let ttl = expires_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+                .max(Duration::from_millis(1));
+            if let Err(err) = store.save_packet_response(key, response, ttl).await {
+                error!(
+                    "Error saving the response of a deduplicated packet: {}",
+                    err
+                );
+            }
+            result
+        });
+        match forwarded.await {
+            Ok(result) => result,
+            Err(err) => {
+                error!("Error forwarding a deduplicated packet: {}", err);
+                let ilp_address = self.store.get_ilp_address();
+                Err(reject(ErrorCode::T00_INTERNAL_ERROR, &[], &ilp_address))
+            }
+        }
+    }
+}
+
+fn reject(code: ErrorCode, message: &[u8], ilp_address: &Address) -> Reject {
+    RejectBuilder {
+        code,
+        message,
+        triggered_by: Some(ilp_address),
+        data: &[],
+    }
+    .build()
+}
+
+#[async_trait]
+impl<S, I, A> IncomingService<A> for DedupService<S, I, A>
+where
+    S: DedupStore + AddressStore + Clone + Send + Sync + 'static,
+    I: IncomingService<A> + Clone + Send + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    /// On receiving a request:
+    /// 1. Records the packet as in flight in the store
+    /// 1. If it was not seen before, forwards it and saves its response
+    /// 1. If it was already answered, returns the same response
+    /// 1. If it is still in flight, waits for its response until the packet expires
+    async fn handle_request(&mut self, request: IncomingRequest<A>) -> IlpResult {
+        let expires_at = request.prepare.expires_at();
+        // The expired packets (accepted thanks to the clock skew tolerance) can't be
+        // kept until they expire
+        let ttl = match expires_at.duration_since(SystemTime::now()) {
+            Ok(ttl) if ttl > Duration::from_millis(0) => ttl,
+            _ => return self.next.handle_request(request).await,
+        };
+        let key = dedup_key(request.from.id(), &request.prepare);
+        let mut status = match self.store.start_packet(key, ttl).await {
+            Ok(status) => status,
+            Err(err) => {
+                error!(
+                    "Error deduplicating packet from account {}, forwarding it anyway: {}",
+                    request.from.id(),
+                    err
+                );
+                return self.next.handle_request(request).await;
+            }
+        };
+
+        loop {
+            match status {
+                PacketStatus::New => return self.forward(key, request).await,
+                PacketStatus::Answered(response) => {
+                    debug!(
+                        "Answering duplicate packet from account {} with the original's response",
+                        request.from.id()
+                    );
+                    let ilp_address = self.store.get_ilp_address();
+                    return match Packet::try_from(BytesMut::from(response.as_ref())) {
+                        Ok(Packet::Fulfill(fulfill)) => Ok(fulfill),
+                        Ok(Packet::Reject(rejected)) => Err(rejected),
+                        _ => Err(reject(ErrorCode::T00_INTERNAL_ERROR, &[], &ilp_address)),
+                    };
+                }
+                PacketStatus::InFlight => {
+                    let remaining = expires_at
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default();
+                    if remaining <= IN_FLIGHT_POLL_INTERVAL {
+                        let ilp_address = self.store.get_ilp_address();
+                        return Err(reject(
+                            ErrorCode::R00_TRANSFER_TIMED_OUT,
+                            b"Duplicate packet expired before the original was answered",
+                            &ilp_address,
+                        ));
+                    }
+                    tokio::time::delay_for(IN_FLIGHT_POLL_INTERVAL).await;
+                    status = match self
+                        .store
+                        .start_packet(key, remaining - IN_FLIGHT_POLL_INTERVAL)
+                        .await
+                    {
+                        Ok(status) => status,
+                        Err(err) => {
+                            error!("Error loading the response of a duplicate packet: {}", err);
+                            let ilp_address = self.store.get_ilp_address();
+                            return Err(reject(ErrorCode::T00_INTERNAL_ERROR, &[], &ilp_address));
+                        }
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_errors::AddressStoreError;
+    use interledger_packet::{FulfillBuilder, PrepareBuilder};
+    use once_cell::sync::Lazy;
+    use parking_lot::Mutex;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    static ILP_ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("example.node").unwrap());
+    static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+
+    #[derive(Debug, Clone)]
+    struct TestAccount(Uuid);
+
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            self.0
+        }
+
+        fn username(&self) -> &Username {
+            &ALICE
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &ILP_ADDRESS
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct TestStore {
+        packets: Arc<Mutex<HashMap<[u8; 32], Option<Bytes>>>>,
+        started: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl DedupStore for TestStore {
+        async fn start_packet(
+            &self,
+            key: [u8; 32],
+            _ttl: Duration,
+        ) -> Result<PacketStatus, DedupStoreError> {
+            self.started.fetch_add(1, Ordering::SeqCst);
+            let mut packets = self.packets.lock();
+            Ok(match packets.get(&key) {
+                None => {
+                    packets.insert(key, None);
+                    PacketStatus::New
+                }
+                Some(None) => PacketStatus::InFlight,
+                Some(Some(response)) => PacketStatus::Answered(response.clone()),
+            })
+        }
+
+        async fn save_packet_response(
+            &self,
+            key: [u8; 32],
+            response: Bytes,
+            _ttl: Duration,
+        ) -> Result<(), DedupStoreError> {
+            self.packets.lock().insert(key, Some(response));
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl AddressStore for TestStore {
+        async fn set_ilp_address(&self, _ilp_address: Address) -> Result<(), AddressStoreError> {
+            unimplemented!()
+        }
+
+        async fn clear_ilp_address(&self) -> Result<(), AddressStoreError> {
+            unimplemented!()
+        }
+
+        fn get_ilp_address(&self) -> Address {
+            ILP_ADDRESS.clone()
+        }
+    }
+
+    fn test_request(
+        account_id: Uuid,
+        amount: u64,
+        expires_at: SystemTime,
+    ) -> IncomingRequest<TestAccount> {
+        IncomingRequest {
+            from: TestAccount(account_id),
+            prepare: PrepareBuilder {
+                destination: Address::from_str("example.destination").unwrap(),
+                amount,
+                expires_at,
+                execution_condition: &[1; 32],
+                data: b"test data",
+            }
+            .build(),
+        }
+    }
+
+    fn counting_service(
+        forwarded: Arc<AtomicUsize>,
+    ) -> impl IncomingService<TestAccount> + Clone + Send {
+        incoming_service_fn(move |_| {
+            forwarded.fetch_add(1, Ordering::SeqCst);
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"fulfilled",
+            }
+            .build())
+        })
+    }
+
+    #[derive(Clone)]
+    struct DelayedService(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl IncomingService<TestAccount> for DelayedService {
+        async fn handle_request(&mut self, _request: IncomingRequest<TestAccount>) -> IlpResult {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"delayed",
+            }
+            .build())
+        }
+    }
+
+    #[test]
+    fn keys_depend_on_the_account_and_the_packet() {
+        let expires_at = SystemTime::now();
+        let account_id = Uuid::from_u128(1);
+        let key = dedup_key(
+            account_id,
+            &test_request(account_id, 10, expires_at).prepare,
+        );
+        assert_eq!(
+            key,
+            dedup_key(
+                account_id,
+                &test_request(account_id, 10, expires_at).prepare
+            )
+        );
+        assert_ne!(
+            key,
+            dedup_key(
+                account_id,
+                &test_request(account_id, 11, expires_at).prepare
+            )
+        );
+        assert_ne!(
+            key,
+            dedup_key(
+                account_id,
+                &test_request(account_id, 10, expires_at + Duration::from_secs(1)).prepare
+            )
+        );
+        assert_ne!(
+            key,
+            dedup_key(
+                Uuid::from_u128(2),
+                &test_request(account_id, 10, expires_at).prepare
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_duplicates_with_the_original_response() {
+        let forwarded = Arc::new(AtomicUsize::new(0));
+        let mut service =
+            DedupService::new(TestStore::default(), counting_service(forwarded.clone()));
+        let account_id = Uuid::new_v4();
+        let expires_at = SystemTime::now() + Duration::from_secs(30);
+
+        let fulfill = service
+            .handle_request(test_request(account_id, 10, expires_at))
+            .await
+            .unwrap();
+        // The response was saved before it was returned
+        let duplicate = service
+            .handle_request(test_request(account_id, 10, expires_at))
+            .await
+            .unwrap();
+        assert_eq!(duplicate.data(), fulfill.data());
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+
+        // Another account's packet is not a duplicate
+        service
+            .handle_request(test_request(Uuid::new_v4(), 10, expires_at))
+            .await
+            .unwrap();
+        assert_eq!(forwarded.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn duplicates_in_flight_wait_for_the_response() {
+        let forwarded = Arc::new(AtomicUsize::new(0));
+        let store = TestStore::default();
+        let mut service = DedupService::new(store.clone(), counting_service(forwarded.clone()));
+        let account_id = Uuid::new_v4();
+        let expires_at = SystemTime::now() + Duration::from_secs(30);
+        let request = test_request(account_id, 10, expires_at);
+        let key = dedup_key(account_id, &request.prepare);
+        store
+            .start_packet(key, Duration::from_secs(30))
+            .await
+            .unwrap();
+
+        let duplicate = tokio::spawn({
+            let mut service = service.clone();
+            async move { service.handle_request(request).await }
+        });
+        // Waits until the duplicate found the original in flight
+        while store.started.load(Ordering::SeqCst) < 2 {
+            tokio::task::yield_now().await;
+        }
+        let original = FulfillBuilder {
+            fulfillment: &[0; 32],
+            data: b"original",
+        }
+        .build();
+        store
+            .save_packet_response(
+                key,
+                Bytes::copy_from_slice(original.as_ref()),
+                Duration::from_secs(30),
+            )
+            .await
+            .unwrap();
+
+        let duplicate = duplicate.await.unwrap().unwrap();
+        assert_eq!(duplicate.data(), b"original");
+        assert_eq!(forwarded.load(Ordering::SeqCst), 0);
+
+        // Other packets are still forwarded
+        service
+            .handle_request(test_request(account_id, 20, expires_at))
+            .await
+            .unwrap();
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn saves_the_response_of_dropped_requests() {
+        let forwarded = Arc::new(AtomicUsize::new(0));
+        let mut service =
+            DedupService::new(TestStore::default(), DelayedService(forwarded.clone()));
+        let account_id = Uuid::new_v4();
+        let expires_at = SystemTime::now() + Duration::from_secs(30);
+
+        // The sender hangs up before the packet is answered
+        let dropped = tokio::time::timeout(
+            Duration::from_millis(1),
+            service.handle_request(test_request(account_id, 10, expires_at)),
+        )
+        .await;
+        assert!(dropped.is_err());
+
+        let duplicate = service
+            .handle_request(test_request(account_id, 10, expires_at))
+            .await
+            .unwrap();
+        assert_eq!(duplicate.data(), b"delayed");
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+    }
+}
@@ -2,7 +2,8 @@ use async_trait::async_trait;
 use bytes::{Buf, BufMut, BytesMut};
 use core::borrow::Borrow;
 use interledger_packet::{
-    oer::BufOerExt, Address, ErrorCode, Prepare, PrepareBuilder, RejectBuilder,
+    oer::{self, BufOerExt, MutBufOerExt},
+    Address, ErrorCode, FulfillBuilder, Prepare, PrepareBuilder, RejectBuilder,
 };
 use interledger_service::*;
 use std::convert::TryFrom;
@@ -16,13 +17,30 @@ const ECHO_PREFIX: &str = "ECHOECHOECHOECHO";
 /// The length of the `ECHO_PREFIX`
 const ECHO_PREFIX_LEN: usize = 16;
 
+/// The fulfillment of unidirectional echo requests. It is publicly known, so that the
+/// node can fulfill the requests without sending anything back to the initiator.
+pub const ECHO_FULFILLMENT: [u8; 32] = [0; 32];
+/// The execution condition of unidirectional echo requests, the SHA-256 hash of `ECHO_FULFILLMENT`
+pub const ECHO_CONDITION: [u8; 32] = [
+    102, 104, 122, 173, 248, 98, 189, 119, 108, 143, 193, 139, 142, 159, 142, 32, 8, 151, 20, 133,
+    110, 226, 51, 179, 144, 42, 89, 29, 13, 95, 41, 37,
+];
+
 enum EchoPacketType {
     Request = 0,
     Response = 1,
 }
 
 /// A service that implements the Echo Protocol.
-/// Currently, this service only supports bidirectional mode (unidirectional mode is not supported yet).
+///
+/// Echo requests addressed to the node are handled in one of two modes, depending on their
+/// execution condition:
+/// - Unidirectional: requests with the `ECHO_CONDITION` are fulfilled by the node itself with
+///   the `ECHO_FULFILLMENT`, which measures the round trip from the initiator to the node.
+/// - Bidirectional: any other request is sent back to the source address it contains, as a
+///   Prepare with the same amount and condition, which the initiator then fulfills. This
+///   measures the round trip through the network in both directions.
+///
 /// The service doesn't shorten expiry as it expects the expiry to be shortened by another service
 /// like `ExpiryShortenerService`.
 #[derive(Clone)]
@@ -103,6 +121,15 @@ where
             .build());
         }
 
+        if request.prepare.execution_condition() == ECHO_CONDITION {
+            debug!("Fulfilling unidirectional echo request");
+            return Ok(FulfillBuilder {
+                fulfillment: &ECHO_FULFILLMENT,
+                data: &echo_response_data(),
+            }
+            .build());
+        }
+
         // check source address
         let source_address = match reader.read_var_octet_string() {
             Ok(value) => match Address::try_from(value) {
@@ -148,10 +175,8 @@ where
     }
 }
 
-#[cfg(test)]
-use interledger_packet::{oer, oer::MutBufOerExt};
-// This should be exported when we have a use for it outside of the tests
-#[cfg(test)]
+/// Builds the echo requests an initiator sends to ping a node. Set the `execution_condition`
+/// to `ECHO_CONDITION` for a unidirectional request.
 pub struct EchoRequestBuilder<'a> {
     pub amount: u64,
     pub expires_at: SystemTime,
@@ -162,7 +187,6 @@ pub struct EchoRequestBuilder<'a> {
     pub source_address: &'a Address,
 }
 
-#[cfg(test)]
 impl<'a> EchoRequestBuilder<'a> {
     pub fn build(&self) -> Prepare {
         let source_address_len = oer::predict_var_octet_string(self.source_address.len());
@@ -191,20 +215,24 @@ pub struct EchoResponseBuilder<'a> {
 
 impl<'a> EchoResponseBuilder<'a> {
     pub fn build(&self) -> Prepare {
-        let mut data_buffer = BytesMut::with_capacity(ECHO_PREFIX_LEN + 1);
-        data_buffer.put(ECHO_PREFIX.as_bytes());
-        data_buffer.put_u8(EchoPacketType::Response as u8);
         PrepareBuilder {
             amount: self.amount,
             expires_at: self.expires_at,
             execution_condition: self.execution_condition,
             destination: self.destination.clone(),
-            data: data_buffer.borrow(),
+            data: &echo_response_data(),
         }
         .build()
     }
 }
 
+fn echo_response_data() -> BytesMut {
+    let mut data_buffer = BytesMut::with_capacity(ECHO_PREFIX_LEN + 1);
+    data_buffer.put(ECHO_PREFIX.as_bytes());
+    data_buffer.put_u8(EchoPacketType::Response as u8);
+    data_buffer
+}
+
 #[cfg(test)]
 mod echo_tests {
     use super::*;
@@ -406,6 +434,39 @@ mod echo_tests {
         assert!(result.is_ok());
     }
 
+    /// Requests with the echo condition are fulfilled by the node instead of being sent back.
+    #[tokio::test]
+    async fn test_unidirectional_echo_packet() {
+        assert_eq!(get_hash_of(&ECHO_FULFILLMENT), ECHO_CONDITION);
+        let node_address = Address::from_str("example.recipient").unwrap();
+        let source_address = Address::from_str("example.initiator").unwrap();
+
+        // setup service
+        let handler = incoming_service_fn(|_| -> IlpResult {
+            panic!("Unidirectional echo requests should not be passed on")
+        });
+        let mut echo_service = EchoService::new(TestStore(node_address.clone()), handler);
+
+        // setup request
+        let prepare = EchoRequestBuilder {
+            amount: 1,
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+            execution_condition: &ECHO_CONDITION,
+            destination: &node_address,
+            source_address: &source_address,
+        }
+        .build();
+        let from = TestAccount(Uuid::new_v4());
+
+        // test
+        let fulfill = echo_service
+            .handle_request(IncomingRequest { from, prepare })
+            .await
+            .unwrap();
+        assert_eq!(fulfill.fulfillment(), &ECHO_FULFILLMENT);
+        assert_eq!(fulfill.data(), b"ECHOECHOECHOECHO\x01");
+    }
+
     /// If echo packet type is neither `1` nor `2`, the packet is considered to be malformed.
     #[tokio::test]
     async fn test_invalid_echo_packet_type() {
@@ -1,18 +1,146 @@
 use async_trait::async_trait;
+use interledger_errors::DustStoreError;
 use interledger_packet::{ErrorCode, RejectBuilder};
 use interledger_rates::ExchangeRateStore;
 use interledger_service::*;
-use interledger_settlement::core::types::{ConversionError, Convert, ConvertDetails};
+use interledger_settlement::core::{
+    amount::convert_scale,
+    types::{ConversionError, Convert, ConvertDetails, SettlementStore},
+};
 use std::marker::PhantomData;
-use tracing::{error, trace, warn};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tracing::{debug, error, trace, warn};
+use uuid::Uuid;
+
+/// Extension trait for [`Account`](../interledger_service/trait.Account.html) with the spread
+/// charged on the packets the account sends
+pub trait SpreadAccount: Account {
+    /// The spread, as a fraction, which overrides the service's spread for the packets
+    /// coming from this account
+    fn spread(&self) -> Option<f64> {
+        None
+    }
+}
+
+/// Returns true if the spread is a fraction the outgoing amounts can be reduced by, that
+/// is at least 0 and below 1
+pub fn is_valid_spread(spread: f64) -> bool {
+    (0.0..1.0).contains(&spread)
+}
+
+/// Store trait which accumulates the dust lost by converting the packets each account sent
+#[async_trait]
+pub trait DustStore {
+    /// Adds the dust to the account's accumulated dust, in the account's asset scale
+    async fn add_dust(&self, account_id: Uuid, dust: u64) -> Result<(), DustStoreError>;
+
+    /// Returns the dust accumulated for the account and resets it
+    async fn take_dust(&self, account_id: Uuid) -> Result<u64, DustStoreError>;
+}
+
+/// The dust lost when converting packets to accounts with a smaller asset scale, so that it
+/// can be settled later rather than being silently dropped.
+///
+/// The dust of the fulfilled packets is accumulated per incoming account, in the asset scale
+/// of that account, and persisted in a `DustStore`. Cloning the ledger is cheap and all clones
+/// share the same store.
+#[derive(Clone)]
+pub struct DustLedger {
+    store: Arc<dyn DustStore + Send + Sync>,
+}
+
+impl DustLedger {
+    pub fn new<S>(store: S) -> Self
+    where
+        S: DustStore + Send + Sync + 'static,
+    {
+        DustLedger {
+            store: Arc::new(store),
+        }
+    }
+
+    /// Returns the dust accumulated for the account and resets it, for example once it was settled
+    pub async fn take(&self, account_id: Uuid) -> Result<u64, DustStoreError> {
+        self.store.take_dust(account_id).await
+    }
+
+    /// Credits the dust accumulated for each of the accounts back to its balance, so that it is
+    /// settled with the account's peer along with the rest of its balance. The dust which could
+    /// not be credited is kept for the next time
+    pub async fn credit<S>(&self, store: &S, account_ids: &[Uuid])
+    where
+        S: SettlementStore,
+    {
+        for &account_id in account_ids {
+            let dust = match self.take(account_id).await {
+                Ok(dust) if dust > 0 => dust,
+                Ok(_) => continue,
+                Err(err) => {
+                    error!("Error loading the dust of account {}: {}", account_id, err);
+                    continue;
+                }
+            };
+            // The dust which could not be credited, e.g. because it would overflow
+            // the balance, is kept for the next time
+            let uncredited = match store
+                .update_balance_for_incoming_settlement(account_id, dust, None)
+                .await
+            {
+                Ok(uncredited) => {
+                    debug!(
+                        "Credited {} of conversion dust to account {}",
+                        dust - uncredited,
+                        account_id
+                    );
+                    uncredited
+                }
+                Err(err) => {
+                    error!(
+                        "Error crediting {} of conversion dust to account {}: {}",
+                        dust, account_id, err
+                    );
+                    dust
+                }
+            };
+            if uncredited > 0 {
+                if let Err(err) = self.store.add_dust(account_id, uncredited).await {
+                    error!(
+                        "Error keeping the dust of account {}, {} of dust was lost: {}",
+                        account_id, uncredited, err
+                    );
+                }
+            }
+        }
+    }
+
+    async fn add(&self, account_id: Uuid, dust: u64) {
+        if let Err(err) = self.store.add_dust(account_id, dust).await {
+            error!(
+                "Error saving {} of dust lost converting a packet from account {}: {}",
+                dust, account_id, err
+            );
+        }
+    }
+}
 
 /// # Exchange Rates Service
 ///
 /// Responsible for getting the exchange rates for the two assets in the outgoing request (`request.from.asset_code`, `request.to.asset_code`).
 /// Requires a `ExchangeRateStore`
+///
+/// The outgoing amount is reduced by the spread of the account the packet comes from, or by
+/// the service's spread if that account does not have one, and is always rounded down.
+/// Packets between accounts of the same asset are converted exactly when there is no spread,
+/// and the dust lost by scaling them down may be kept in a `DustLedger`.
 #[derive(Clone)]
 pub struct ExchangeRateService<S, O, A> {
-    spread: f64,
+    /// The latest spread, which may be changed while the service is running
+    spread: watch::Receiver<f64>,
+    /// Packets which need a currency conversion are rejected if the rates are older than this
+    max_rate_age: Option<Duration>,
+    dust_ledger: Option<DustLedger>,
     store: S,
     next: O,
     account_type: PhantomData<A>,
@@ -22,16 +150,53 @@ impl<S, O, A> ExchangeRateService<S, O, A>
 where
     S: AddressStore + ExchangeRateStore,
     O: OutgoingService<A>,
-    A: Account,
+    A: SpreadAccount,
 {
     pub fn new(spread: f64, store: S, next: O) -> Self {
         ExchangeRateService {
-            spread,
+            spread: watch::channel(spread).1,
+            max_rate_age: None,
+            dust_ledger: None,
             store,
             next,
             account_type: PhantomData,
         }
     }
+
+    /// Stops converting packets between assets if the rates were not updated
+    /// within the given duration, rather than using outdated rates
+    pub fn with_max_rate_age(mut self, max_rate_age: Option<Duration>) -> Self {
+        self.max_rate_age = max_rate_age;
+        self
+    }
+
+    /// Applies the spreads sent through the channel to the packets forwarded after they are
+    /// sent, instead of the spread the service was created with
+    pub fn with_spread_updates(mut self, spread: watch::Receiver<f64>) -> Self {
+        self.spread = spread;
+        self
+    }
+
+    /// Accumulates the dust lost by converting the fulfilled packets in the given ledger
+    pub fn with_dust_ledger(mut self, dust_ledger: DustLedger) -> Self {
+        self.dust_ledger = Some(dust_ledger);
+        self
+    }
+
+    /// Returns whether the rates in the store are older than the maximum age. The rates of
+    /// the stores which do not keep track of when they were set are never deemed stale
+    fn rates_are_stale(&self) -> bool {
+        match self.max_rate_age {
+            Some(max_rate_age) => match self.store.get_exchange_rates_updated_at() {
+                Some(updated_at) => SystemTime::now()
+                    .duration_since(updated_at)
+                    .map(|age| age > max_rate_age)
+                    .unwrap_or(false),
+                None => false,
+            },
+            None => false,
+        }
+    }
 }
 
 #[async_trait]
@@ -40,19 +205,40 @@ where
     // TODO can we make these non-'static?
     S: AddressStore + ExchangeRateStore + Clone + Send + Sync + 'static,
     O: OutgoingService<A> + Send + Sync + Clone + 'static,
-    A: Account + Send + Sync + 'static,
+    A: SpreadAccount + Send + Sync + 'static,
 {
     /// On send request:
     /// 1. If the prepare packet's amount is 0, it just forwards
-    /// 1. Retrieves the exchange rate from the store (the store independently is responsible for polling the rates)
-    ///     - return reject if the call to the store fails
+    /// 1. Uses the fixed rate of the pair if one was set, or else retrieves the exchange rate from the store (the store independently is responsible for polling the rates)
+    ///     - return reject if the call to the store fails or if the rates are older than the maximum age
     /// 1. Calculates the exchange rate AND scales it up/down depending on how many decimals each asset requires
     /// 1. Updates the amount in the prepare packet and forwards it
     async fn send_request(&mut self, mut request: OutgoingRequest<A>) -> IlpResult {
         let ilp_address = self.store.get_ilp_address();
+        let from_id = request.from.id();
+        let mut dust_lost = 0;
         if request.prepare.amount() > 0 {
             let rates: (f64, f64) = if request.from.asset_code() == request.to.asset_code() {
                 (1f64, 1f64)
+            } else if let Some(rate) = self
+                .store
+                .get_rate_override(request.from.asset_code(), request.to.asset_code())
+            {
+                // Fixed rates don't depend on the provider, so they are never stale
+                (rate, 1f64)
+            } else if self.rates_are_stale() {
+                error!(
+                    "Exchange rates are stale, not converting from {} to {}",
+                    request.from.asset_code(),
+                    request.to.asset_code()
+                );
+                return Err(RejectBuilder {
+                    code: ErrorCode::T00_INTERNAL_ERROR,
+                    message: b"Exchange rates are stale",
+                    triggered_by: Some(&ilp_address),
+                    data: &[],
+                }
+                .build());
             } else if let Ok(rates) = self
                 .store
                 .get_exchange_rates(&[&request.from.asset_code(), &request.to.asset_code()])
@@ -82,13 +268,24 @@ where
                 .build());
             };
 
+            let spread = request
+                .from
+                .spread()
+                .unwrap_or_else(|| *self.spread.borrow());
+            let scales = (request.from.asset_scale(), request.to.asset_scale());
+            let exact_conversion = if request.from.asset_code() == request.to.asset_code() {
+                convert_without_rate(request.prepare.amount(), spread, scales)
+            } else {
+                None
+            };
             // Can we overflow here?
-            let outgoing_amount = calculate_outgoing_amount(
-                request.prepare.amount(),
-                self.spread,
-                rates,
-                (request.from.asset_scale(), request.to.asset_scale()),
-            );
+            let outgoing_amount = match exact_conversion {
+                Some((outgoing_amount, dust)) => {
+                    dust_lost = dust;
+                    Ok(outgoing_amount)
+                }
+                None => calculate_outgoing_amount(request.prepare.amount(), spread, rates, scales),
+            };
 
             match outgoing_amount {
                 Ok(outgoing_amount) => {
@@ -140,7 +337,36 @@ where
             };
         }
 
-        self.next.send_request(request).await
+        let result = self.next.send_request(request).await;
+        if let (Ok(_), Some(dust_ledger)) = (&result, &self.dust_ledger) {
+            if dust_lost > 0 {
+                trace!(
+                    "Lost {} of dust converting packet from account {}",
+                    dust_lost,
+                    from_id
+                );
+                dust_ledger.add(from_id, dust_lost).await;
+            }
+        }
+        result
+    }
+}
+
+/// Converts the amount between the asset scales of two accounts of the same asset without
+/// going through floating point numbers, returning the outgoing amount and the dust lost.
+/// Returns `None` if there is a spread to apply or if the amount cannot be converted to a
+/// non-zero u64, in which case `calculate_outgoing_amount` must be used instead.
+fn convert_without_rate(
+    input: u64,
+    spread: f64,
+    (asset_scale_src, asset_scale_dest): (u8, u8),
+) -> Option<(u64, u64)> {
+    if spread != 0.0 {
+        return None;
+    }
+    match convert_scale(input, asset_scale_src, asset_scale_dest) {
+        Ok((outgoing_amount, dust)) if outgoing_amount > 0 => Some((outgoing_amount, dust)),
+        _ => None,
     }
 }
 
@@ -195,16 +421,13 @@ fn calculate_outgoing_amount(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use interledger_errors::{AddressStoreError, ExchangeRateStoreError};
+    use interledger_errors::{AddressStoreError, ExchangeRateStoreError, SettlementStoreError};
     use interledger_packet::{Address, Fulfill, FulfillBuilder, PrepareBuilder, Reject};
     use interledger_service::{outgoing_service_fn, Account};
     use once_cell::sync::Lazy;
     use std::collections::HashMap;
     use std::str::FromStr;
-    use std::{
-        sync::{Arc, Mutex},
-        time::SystemTime,
-    };
+    use std::sync::{Arc, Mutex};
     use uuid::Uuid;
 
     pub static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
@@ -268,6 +491,266 @@ mod tests {
         assert_eq!(ret.1[0].prepare.amount(), 0);
     }
 
+    #[tokio::test]
+    async fn applies_the_account_spread() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        let outgoing = outgoing_service_fn(move |request| {
+            requests_clone.lock().unwrap().push(request);
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"hello!",
+            }
+            .build())
+        });
+        let mut service = test_service(1.0, 2.0, 0.01, outgoing);
+        let mut from = TestAccount::new("ABC".to_owned(), 1);
+        from.spread = Some(0.1);
+        service
+            .send_request(OutgoingRequest {
+                from,
+                to: TestAccount::new("XYZ".to_owned(), 1),
+                original_amount: 100,
+                prepare: PrepareBuilder {
+                    destination: Address::from_str("example.destination").unwrap(),
+                    amount: 100,
+                    expires_at: SystemTime::now(),
+                    execution_condition: &[1; 32],
+                    data: b"hello",
+                }
+                .build(),
+            })
+            .await
+            .unwrap();
+        // The account's spread is used instead of the service's
+        assert_eq!(requests.lock().unwrap()[0].prepare.amount(), 45);
+    }
+
+    #[tokio::test]
+    async fn applies_spread_updates() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        let outgoing = outgoing_service_fn(move |request| {
+            requests_clone.lock().unwrap().push(request);
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"hello!",
+            }
+            .build())
+        });
+        let (spread, spread_updates) = watch::channel(0.01);
+        let mut service = test_service(1.0, 2.0, 0.5, outgoing).with_spread_updates(spread_updates);
+        let request = || OutgoingRequest {
+            from: TestAccount::new("ABC".to_owned(), 1),
+            to: TestAccount::new("XYZ".to_owned(), 1),
+            original_amount: 100,
+            prepare: PrepareBuilder {
+                destination: Address::from_str("example.destination").unwrap(),
+                amount: 100,
+                expires_at: SystemTime::now(),
+                execution_condition: &[1; 32],
+                data: b"hello",
+            }
+            .build(),
+        };
+        service.send_request(request()).await.unwrap();
+        spread.broadcast(0.1).unwrap();
+        service.send_request(request()).await.unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].prepare.amount(), 49);
+        assert_eq!(requests[1].prepare.amount(), 45);
+    }
+
+    #[tokio::test]
+    async fn rejects_when_rates_are_stale() {
+        let outgoing = outgoing_service_fn(move |_| {
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"hello!",
+            }
+            .build())
+        });
+        let request = || OutgoingRequest {
+            from: TestAccount::new("ABC".to_owned(), 1),
+            to: TestAccount::new("XYZ".to_owned(), 1),
+            original_amount: 100,
+            prepare: PrepareBuilder {
+                destination: Address::from_str("example.destination").unwrap(),
+                amount: 100,
+                expires_at: SystemTime::now(),
+                execution_condition: &[1; 32],
+                data: b"hello",
+            }
+            .build(),
+        };
+        let mut store = test_store(1.0, 1.0);
+        store.updated_at = Some(SystemTime::now() - Duration::from_secs(60));
+        let mut service = ExchangeRateService::new(0.0, store.clone(), outgoing.clone())
+            .with_max_rate_age(Some(Duration::from_secs(30)));
+        let reject = service.send_request(request()).await.unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T00_INTERNAL_ERROR);
+        assert_eq!(reject.message(), b"Exchange rates are stale");
+
+        // The age of rates whose update time the store does not know is not checked
+        store.updated_at = None;
+        let mut service = ExchangeRateService::new(0.0, store.clone(), outgoing.clone())
+            .with_max_rate_age(Some(Duration::from_secs(30)));
+        assert!(service.send_request(request()).await.is_ok());
+
+        store.updated_at = Some(SystemTime::now());
+        let mut service = ExchangeRateService::new(0.0, store, outgoing)
+            .with_max_rate_age(Some(Duration::from_secs(30)));
+        assert!(service.send_request(request()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn uses_rate_overrides_even_when_rates_are_stale() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        let outgoing = outgoing_service_fn(move |request| {
+            requests_clone.lock().unwrap().push(request);
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"hello!",
+            }
+            .build())
+        });
+        let mut store = test_store(1.0, 1.0);
+        store.overrides.insert("XYZ/ABC".to_owned(), 0.5);
+        let mut service = ExchangeRateService::new(0.0, store, outgoing)
+            .with_max_rate_age(Some(Duration::from_secs(30)));
+        service
+            .send_request(OutgoingRequest {
+                from: TestAccount::new("ABC".to_owned(), 1),
+                to: TestAccount::new("XYZ".to_owned(), 1),
+                original_amount: 100,
+                prepare: PrepareBuilder {
+                    destination: Address::from_str("example.destination").unwrap(),
+                    amount: 100,
+                    expires_at: SystemTime::now(),
+                    execution_condition: &[1; 32],
+                    data: b"hello",
+                }
+                .build(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(requests.lock().unwrap()[0].prepare.amount(), 200);
+    }
+
+    #[test]
+    fn converts_same_asset_exactly() {
+        assert_eq!(convert_without_rate(1999, 0.0, (9, 6)), Some((1, 999)));
+        assert_eq!(convert_without_rate(12, 0.0, (6, 9)), Some((12_000, 0)));
+        // Beyond the integer precision of f64
+        assert_eq!(
+            convert_without_rate(u64::MAX, 0.0, (0, 0)),
+            Some((u64::MAX, 0))
+        );
+        assert_eq!(convert_without_rate(1999, 0.01, (9, 6)), None);
+        assert_eq!(convert_without_rate(999, 0.0, (9, 6)), None);
+        assert_eq!(convert_without_rate(u64::MAX, 0.0, (0, 1)), None);
+    }
+
+    #[derive(Clone, Default)]
+    struct TestDustStore {
+        dust: Arc<Mutex<HashMap<Uuid, u64>>>,
+        credited: Arc<Mutex<Vec<(Uuid, u64)>>>,
+    }
+
+    #[async_trait]
+    impl DustStore for TestDustStore {
+        async fn add_dust(&self, account_id: Uuid, dust: u64) -> Result<(), DustStoreError> {
+            *self.dust.lock().unwrap().entry(account_id).or_insert(0) += dust;
+            Ok(())
+        }
+
+        async fn take_dust(&self, account_id: Uuid) -> Result<u64, DustStoreError> {
+            Ok(self.dust.lock().unwrap().remove(&account_id).unwrap_or(0))
+        }
+    }
+
+    #[async_trait]
+    impl SettlementStore for TestDustStore {
+        type Account = TestAccount;
+
+        async fn update_balance_for_incoming_settlement(
+            &self,
+            account_id: Uuid,
+            amount: u64,
+            _idempotency_key: Option<String>,
+        ) -> Result<u64, SettlementStoreError> {
+            self.credited.lock().unwrap().push((account_id, amount));
+            Ok(0)
+        }
+
+        async fn refund_settlement(
+            &self,
+            _account_id: Uuid,
+            _settle_amount: u64,
+        ) -> Result<(), SettlementStoreError> {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn accumulates_the_dust_of_fulfilled_packets() {
+        let dust_store = TestDustStore::default();
+        let dust_ledger = DustLedger::new(dust_store.clone());
+        let from = TestAccount::new("ABC".to_owned(), 9);
+        let to = TestAccount::new("ABC".to_owned(), 6);
+        let fulfill = Arc::new(Mutex::new(true));
+        let fulfill_clone = fulfill.clone();
+        let outgoing = outgoing_service_fn(move |_request| {
+            if *fulfill_clone.lock().unwrap() {
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build())
+            } else {
+                Err(RejectBuilder {
+                    code: ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+                    message: &[],
+                    triggered_by: None,
+                    data: &[],
+                }
+                .build())
+            }
+        });
+        let mut service =
+            test_service(1.0, 1.0, 0.0, outgoing).with_dust_ledger(dust_ledger.clone());
+        let request = |amount| OutgoingRequest {
+            from: from.clone(),
+            to: to.clone(),
+            original_amount: amount,
+            prepare: PrepareBuilder {
+                destination: Address::from_str("example.destination").unwrap(),
+                amount,
+                expires_at: SystemTime::now(),
+                execution_condition: &[1; 32],
+                data: &[],
+            }
+            .build(),
+        };
+
+        service.send_request(request(1999)).await.unwrap();
+        service.send_request(request(2500)).await.unwrap();
+        // Rejected packets did not move any money
+        *fulfill.lock().unwrap() = false;
+        service.send_request(request(1999)).await.unwrap_err();
+        assert_eq!(dust_store.dust.lock().unwrap()[&from.id()], 1499);
+
+        // The dust is credited back to the account it was taken from
+        dust_ledger.credit(&dust_store, &[from.id(), to.id()]).await;
+        assert_eq!(
+            *dust_store.credited.lock().unwrap(),
+            vec![(from.id(), 1499)]
+        );
+        assert_eq!(dust_ledger.take(from.id()).await.unwrap(), 0);
+    }
+
     // Errors most likely are caused by floating point errors
     #[test]
     fn calculates_with_small_input() {
@@ -337,6 +820,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn calculates_with_extreme_scales() {
+        // Rounds down in the node's favor even when scaling up by a lot
+        assert_eq!(
+            calculate_outgoing_amount(3, 0.5, (1.0, 1.0), (0, 1)),
+            Ok(15)
+        );
+        assert_eq!(
+            calculate_outgoing_amount(1, 0.01, (1.0, 1.0), (0, 18)),
+            Ok(990_000_000_000_000_000)
+        );
+        // Scaling down to less than one unit is rejected rather than rounded up
+        assert!(matches!(
+            calculate_outgoing_amount(u64::MAX, 0.01, (1.0, 1.0), (255, 0)),
+            Err(OutgoingAmountError::LessThanOne(_))
+        ));
+        assert!(matches!(
+            calculate_outgoing_amount(1000, 0.01, (1.0, 1.0), (3, 0)),
+            Err(OutgoingAmountError::LessThanOne(_))
+        ));
+        // Never goes below zero, even with a spread larger than 1
+        assert_eq!(
+            calculate_outgoing_amount(u64::MAX, 1.5, (1.0, 1.0), (0, 18)),
+            Ok(0)
+        );
+        assert!(matches!(
+            calculate_outgoing_amount(u64::MAX, 0.01, (1.0, 1.0), (0, 18)),
+            Err(OutgoingAmountError::ToU64ConvertOverflow(_))
+        ));
+    }
+
     #[test]
     fn calculates_with_high_asset_scale() {
         assert_eq!(
@@ -388,20 +902,30 @@ mod tests {
 
     #[derive(Debug, Clone)]
     struct TestAccount {
+        id: Uuid,
         ilp_address: Address,
         asset_code: String,
         asset_scale: u8,
+        spread: Option<f64>,
     }
     impl TestAccount {
         fn new(asset_code: String, asset_scale: u8) -> Self {
             TestAccount {
+                id: Uuid::new_v4(),
                 ilp_address: Address::from_str("example.alice").unwrap(),
                 asset_code,
                 asset_scale,
+                spread: None,
             }
         }
     }
 
+    impl SpreadAccount for TestAccount {
+        fn spread(&self) -> Option<f64> {
+            self.spread
+        }
+    }
+
     #[async_trait]
     impl AddressStore for TestStore {
         /// Saves the ILP Address in the store's memory and database
@@ -421,7 +945,7 @@ mod tests {
 
     impl Account for TestAccount {
         fn id(&self) -> Uuid {
-            Uuid::new_v4()
+            self.id
         }
 
         fn username(&self) -> &Username {
@@ -444,6 +968,8 @@ mod tests {
     #[derive(Debug, Clone)]
     struct TestStore {
         rates: HashMap<Vec<String>, (f64, f64)>,
+        updated_at: Option<SystemTime>,
+        overrides: HashMap<String, f64>,
     }
 
     impl ExchangeRateStore for TestStore {
@@ -476,12 +1002,31 @@ mod tests {
         fn get_all_exchange_rates(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError> {
             unimplemented!()
         }
+
+        fn get_exchange_rates_updated_at(&self) -> Option<SystemTime> {
+            self.updated_at
+        }
+
+        fn set_rate_overrides(
+            &self,
+            _overrides: HashMap<String, f64>,
+        ) -> Result<(), ExchangeRateStoreError> {
+            unimplemented!()
+        }
+
+        fn get_rate_overrides(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError> {
+            Ok(self.overrides.clone())
+        }
     }
 
     fn test_store(rate1: f64, rate2: f64) -> TestStore {
         let mut rates = HashMap::new();
         rates.insert(vec!["ABC".to_owned(), "XYZ".to_owned()], (rate1, rate2));
-        TestStore { rates }
+        TestStore {
+            rates,
+            updated_at: None,
+            overrides: HashMap::new(),
+        }
     }
 
     fn test_service(
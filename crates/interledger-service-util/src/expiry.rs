@@ -0,0 +1,113 @@
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+
+/// When a Prepare packet expires, by both clocks: the wall-clock expiry carried on the
+/// wire, and the monotonic instant it corresponds to, measured once when the packet is
+/// handled. Timeouts must be derived from the latter, so that they are neither cut short
+/// nor extended when the wall clock jumps (for example when NTP adjusts it) while the
+/// packet is in flight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketExpiry {
+    expires_at: SystemTime,
+    deadline: Instant,
+}
+
+impl PacketExpiry {
+    /// Measures the packet's expiry against the current readings of both clocks
+    pub fn new(expires_at: SystemTime) -> Self {
+        PacketExpiry::measured_at(expires_at, SystemTime::now(), Instant::now())
+    }
+
+    /// Measures the packet's expiry against the given readings of the wall clock
+    /// and the monotonic clock, which must have been taken at the same time
+    pub fn measured_at(expires_at: SystemTime, wall_now: SystemTime, now: Instant) -> Self {
+        let deadline = match expires_at.duration_since(wall_now) {
+            Ok(time_left) => now + time_left,
+            Err(expired_for) => now.checked_sub(expired_for.duration()).unwrap_or(now),
+        };
+        PacketExpiry {
+            expires_at,
+            deadline,
+        }
+    }
+
+    /// The wall-clock expiry, as carried on the wire
+    pub fn expires_at(&self) -> SystemTime {
+        self.expires_at
+    }
+
+    /// The monotonic instant the packet expires at
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Whether the packet has expired
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(Instant::now())
+    }
+
+    /// Whether the packet has expired as of the given instant
+    pub fn is_expired_at(&self, now: Instant) -> bool {
+        now >= self.deadline
+    }
+
+    /// How long is left until the packet expires (zero if it already has)
+    pub fn time_left(&self) -> Duration {
+        self.time_left_at(Instant::now())
+    }
+
+    /// How long is left until the packet expires as of the given instant
+    pub fn time_left_at(&self, now: Instant) -> Duration {
+        self.deadline.saturating_duration_since(now)
+    }
+
+    /// How long ago the packet expired (zero if it did not yet)
+    pub fn expired_for(&self) -> Duration {
+        Instant::now().saturating_duration_since(self.deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_the_wall_clock_expiry_to_a_deadline() {
+        let wall_now = SystemTime::now();
+        let now = Instant::now();
+
+        let expiry = PacketExpiry::measured_at(wall_now + Duration::from_secs(30), wall_now, now);
+        assert_eq!(expiry.deadline(), now + Duration::from_secs(30));
+        assert_eq!(expiry.time_left_at(now), Duration::from_secs(30));
+        assert!(!expiry.is_expired_at(now));
+
+        let expiry = PacketExpiry::measured_at(wall_now - Duration::from_secs(1), wall_now, now);
+        assert!(expiry.is_expired_at(now));
+        assert_eq!(expiry.time_left_at(now), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn is_not_affected_by_wall_clock_jumps() {
+        let wall_now = SystemTime::now();
+        let now = Instant::now();
+        let expires_at = wall_now + Duration::from_secs(30);
+        let expiry = PacketExpiry::measured_at(expires_at, wall_now, now);
+
+        // The wall clock jumps an hour ahead ten seconds later. Measuring the expiry against
+        // the wall clock again would consider the packet long expired
+        let later = now + Duration::from_secs(10);
+        let jumped_wall_now = wall_now + Duration::from_secs(3610);
+        assert!(PacketExpiry::measured_at(expires_at, jumped_wall_now, later).is_expired_at(later));
+        assert!(!expiry.is_expired_at(later));
+        assert_eq!(expiry.time_left_at(later), Duration::from_secs(20));
+
+        // The wall clock jumps an hour back instead: the packet still expires on time
+        let jumped_wall_now = wall_now - Duration::from_secs(3590);
+        let expiry_after_jump = PacketExpiry::measured_at(expires_at, jumped_wall_now, later);
+        assert_eq!(
+            expiry_after_jump.time_left_at(later),
+            Duration::from_secs(3620)
+        );
+        assert!(expiry.is_expired_at(now + Duration::from_secs(30)));
+    }
+}
@@ -2,31 +2,71 @@
 //!
 //! Miscellaneous, small Interledger Services.
 
+/// Service responsible for translating the destinations of the packets sent to legacy peers
+/// to their own prefix
+mod address_translation_service;
+/// Periodic sampling of the account balances into a bounded history
+mod balance_history;
 /// Balance tracking service
 mod balance_service;
+/// Service responsible for answering the duplicates of the packets being or already forwarded
+/// with the original's response
+mod dedup_service;
 /// Service which implements the echo protocol
 mod echo_service;
 /// Service responsible for setting and fetching dollar denominated exchange rates
 mod exchange_rates_service;
+/// Conversion of the wall-clock expiry of packets to monotonic deadlines
+mod expiry;
 /// Service responsible for shortening the expiry time of packets,
 /// to take into account for network latency
 mod expiry_shortener_service;
 /// Service responsible for capping the amount an account can send in a packet
 mod max_packet_amount_service;
+/// Service responsible for tracking the health of the peers packets are sent to
+mod peer_monitor_service;
+/// Service responsible for keeping score of the peers' behavior and throttling or suspending misbehaving ones
+mod peer_scoreboard_service;
 /// Service responsible for capping the amount of packets and amount in packets an account can send
 mod rate_limit_service;
+/// Service responsible for rejecting packets addressed to schemes the node is not allowed to route to
+mod scheme_policy_service;
+/// Service responsible for keeping the tenants of a multi-tenant node apart
+mod tenant_isolation_service;
+/// Service responsible for counting the packets and amounts sent and received by each account
+mod usage_service;
 /// Service responsible for checking that packets are not expired and that prepare packets' fulfillment conditions
 /// match the fulfillment inside the incoming fulfills
 mod validator_service;
 
-pub use self::balance_service::{start_delayed_settlement, BalanceService, BalanceStore};
-pub use self::echo_service::EchoService;
-pub use self::exchange_rates_service::ExchangeRateService;
+pub use self::address_translation_service::{AddressTranslationAccount, AddressTranslationService};
+pub use self::balance_history::{start_balance_history, BalanceHistoryStore, BalanceSample};
+pub use self::balance_service::{
+    start_delayed_settlement, BalanceService, BalanceStore, SettlementTaskGuard, SettlementTasks,
+};
+pub use self::dedup_service::{dedup_key, DedupService, DedupStore, PacketStatus};
+pub use self::echo_service::{
+    EchoRequestBuilder, EchoResponseBuilder, EchoService, ECHO_CONDITION, ECHO_FULFILLMENT,
+};
+pub use self::exchange_rates_service::{
+    is_valid_spread, DustLedger, DustStore, ExchangeRateService, SpreadAccount,
+};
+pub use self::expiry::PacketExpiry;
 pub use self::expiry_shortener_service::{
     ExpiryShortenerService, RoundTripTimeAccount, DEFAULT_ROUND_TRIP_TIME,
 };
 pub use self::max_packet_amount_service::{MaxPacketAmountAccount, MaxPacketAmountService};
+pub use self::peer_monitor_service::{
+    Connectivity, HealthPolicy, PeerHealth, PeerHealthChange, PeerMonitor, PeerMonitorService,
+};
+pub use self::peer_scoreboard_service::{
+    PeerCounts, PeerEvent, PeerScore, PeerScoreboard, PeerScoreboardService, PeerStatus,
+    PeerStatusChange, ScoreboardPolicy,
+};
 pub use self::rate_limit_service::{
     RateLimitAccount, RateLimitError, RateLimitService, RateLimitStore,
 };
+pub use self::scheme_policy_service::{SchemePolicy, SchemePolicyService};
+pub use self::tenant_isolation_service::{Tenant, TenantIsolationService, TenantPolicy};
+pub use self::usage_service::{AccountUsage, UsagePeriod, UsageService, UsageStore};
 pub use self::validator_service::ValidatorService;
@@ -1,5 +1,7 @@
 use async_trait::async_trait;
-use interledger_packet::{ErrorCode, MaxPacketAmountDetails, RejectBuilder};
+use interledger_packet::{
+    ErrorCode, MaxPacketAmountDetails, RejectBuilder, RejectDetail, RejectDetails,
+};
 use interledger_service::*;
 use tracing::debug;
 
@@ -50,8 +52,12 @@ where
                 request.prepare.amount(),
                 max_packet_amount
             );
-            let details =
-                MaxPacketAmountDetails::new(request.prepare.amount(), max_packet_amount).to_bytes();
+            let details = RejectDetails::new()
+                .with(RejectDetail::MaxPacketAmount(MaxPacketAmountDetails::new(
+                    request.prepare.amount(),
+                    max_packet_amount,
+                )))
+                .to_bytes(ErrorCode::F08_AMOUNT_TOO_LARGE);
             Err(RejectBuilder {
                 code: ErrorCode::F08_AMOUNT_TOO_LARGE,
                 message: &[],
@@ -135,6 +141,10 @@ mod tests {
         let mut service = MaxPacketAmountService::new(store.clone(), next);
         let reject = service.handle_request(request).await.unwrap_err();
         assert_eq!(reject.code(), ErrorCode::F08_AMOUNT_TOO_LARGE);
+        // Senders read the amount received and the maximum from the standard F08 data
+        let details = MaxPacketAmountDetails::from_bytes(reject.data()).unwrap();
+        assert_eq!(details.amount_received(), 100);
+        assert_eq!(details.max_amount(), 99);
     }
 
     #[derive(Clone)]
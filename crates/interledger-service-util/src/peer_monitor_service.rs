@@ -0,0 +1,548 @@
+use async_trait::async_trait;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use interledger_packet::ErrorClass;
+use interledger_service::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// When the `PeerMonitor` deems a peer unhealthy.
+///
+/// A peer is unhealthy while its link reports it disconnected, or once too many of the packets
+/// sent to it within a window failed. The errors are counted over fixed windows, so a peer which
+/// stays connected recovers at the end of the first window in which it did not fail too
+/// many packets.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct HealthPolicy {
+    /// Length of the windows over which the errors are counted, in milliseconds
+    #[serde(default = "default_window")]
+    pub window: u64,
+    /// Minimum number of packets the node must have sent to a peer within a window
+    /// before its error rate is applied
+    #[serde(default = "default_min_packets")]
+    pub min_packets: u64,
+    /// Share of the packets sent to a peer which may fail before it is deemed unhealthy
+    #[serde(default = "default_max_error_rate")]
+    pub max_error_rate: f64,
+}
+
+fn default_window() -> u64 {
+    60_000
+}
+
+fn default_min_packets() -> u64 {
+    20
+}
+
+fn default_max_error_rate() -> f64 {
+    0.5
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        HealthPolicy {
+            window: default_window(),
+            min_packets: default_min_packets(),
+            max_error_rate: default_max_error_rate(),
+        }
+    }
+}
+
+/// Whether the node can reach a peer over its BTP or HTTP link
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Connectivity {
+    /// Nothing was sent to or heard from the peer yet
+    Unknown,
+    Connected,
+    Disconnected,
+}
+
+/// The health of a peer, as returned by the API
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeerHealth {
+    pub healthy: bool,
+    pub connectivity: Connectivity,
+    /// When the peer last fulfilled a packet, in milliseconds since the Unix epoch
+    pub last_fulfill: Option<u64>,
+    /// Packets sent to the peer in the current window
+    pub packets: u64,
+    /// Packets sent to the peer in the current window which failed, because the peer
+    /// could not be reached, timed out or rejected them with a temporary error
+    pub errors: u64,
+    pub error_rate: f64,
+}
+
+/// The change of a peer's health, which is used as a hint to withdraw (or restore)
+/// the routes through the peer
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerHealthChange {
+    pub account_id: Uuid,
+    pub healthy: bool,
+    /// Why the health changed
+    pub reason: &'static str,
+}
+
+/// The outcome of a packet sent to a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Fulfill,
+    /// The peer rejected the packet with a final or relative error
+    Reject,
+    /// The peer rejected the packet with a temporary error
+    PeerError,
+    /// The packet could not be delivered to the peer or it did not respond in time
+    LinkError,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PeerState {
+    healthy: bool,
+    connectivity: Connectivity,
+    last_fulfill: Option<SystemTime>,
+    packets: u64,
+    errors: u64,
+    window_started_at: Instant,
+}
+
+impl PeerState {
+    fn new(now: Instant) -> Self {
+        PeerState {
+            healthy: true,
+            connectivity: Connectivity::Unknown,
+            last_fulfill: None,
+            packets: 0,
+            errors: 0,
+            window_started_at: now,
+        }
+    }
+
+    fn error_rate(&self) -> f64 {
+        if self.packets == 0 {
+            0.0
+        } else {
+            self.errors as f64 / self.packets as f64
+        }
+    }
+
+    fn to_health(self) -> PeerHealth {
+        PeerHealth {
+            healthy: self.healthy,
+            connectivity: self.connectivity,
+            last_fulfill: self.last_fulfill.map(|time| {
+                time.duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_millis() as u64
+            }),
+            packets: self.packets,
+            errors: self.errors,
+            error_rate: self.error_rate(),
+        }
+    }
+}
+
+/// Shared record of the health of the node's peers, across the links (BTP or HTTP) packets
+/// are sent over.
+///
+/// Packet outcomes are recorded by the `PeerMonitorService`. The connectivity of the
+/// peers whose links report it, such as BTP connections, is recorded with
+/// `record_connectivity`. Listeners of `health_changes` are notified whenever a peer
+/// becomes unhealthy or recovers.
+#[derive(Debug, Clone)]
+pub struct PeerMonitor {
+    policy: Arc<HealthPolicy>,
+    peers: Arc<Mutex<HashMap<Uuid, PeerState>>>,
+    listeners: Arc<Mutex<Vec<UnboundedSender<PeerHealthChange>>>>,
+}
+
+impl PeerMonitor {
+    pub fn new(policy: HealthPolicy) -> Self {
+        PeerMonitor {
+            policy: Arc::new(policy),
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            listeners: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Returns a stream of the changes of the peers' health
+    pub fn health_changes(&self) -> UnboundedReceiver<PeerHealthChange> {
+        let (sender, receiver) = unbounded();
+        self.listeners.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Returns the peer's health. The peers nothing was sent to or heard from yet are
+    /// healthy, with an unknown connectivity
+    pub fn health(&self, account_id: Uuid) -> PeerHealth {
+        let now = Instant::now();
+        if !self.peers.lock().unwrap().contains_key(&account_id) {
+            return PeerState::new(now).to_health();
+        }
+        self.update(account_id, now, |_| {}).to_health()
+    }
+
+    /// Records whether the peer's link is up
+    pub fn record_connectivity(&self, account_id: Uuid, connected: bool) {
+        self.update(account_id, Instant::now(), |state| {
+            state.connectivity = if connected {
+                Connectivity::Connected
+            } else {
+                Connectivity::Disconnected
+            };
+        });
+    }
+
+    fn record(&self, account_id: Uuid, outcome: Outcome) {
+        self.update(account_id, Instant::now(), |state| {
+            state.packets += 1;
+            match outcome {
+                Outcome::Fulfill => state.last_fulfill = Some(SystemTime::now()),
+                Outcome::Reject => {}
+                Outcome::PeerError | Outcome::LinkError => state.errors += 1,
+            }
+            // Anything the peer responded with shows it is reachable. Failed packets only
+            // count toward the error rate: a peer is only deemed disconnected by its link
+            if outcome != Outcome::LinkError {
+                state.connectivity = Connectivity::Connected;
+            }
+        });
+    }
+
+    /// Starts new windows for the peers whose window is over, so that the peers no packets
+    /// are sent to (for example, because their routes were withdrawn) recover too
+    pub fn refresh(&self) {
+        let account_ids: Vec<Uuid> = self.peers.lock().unwrap().keys().cloned().collect();
+        let now = Instant::now();
+        for account_id in account_ids {
+            self.update(account_id, now, |_| {});
+        }
+    }
+
+    /// Returns a future that refreshes the peers' health at the end of each window
+    pub async fn start_health_checks(&self) {
+        let mut interval = tokio::time::interval(Duration::from_millis(self.policy.window));
+        loop {
+            interval.tick().await;
+            self.refresh();
+        }
+    }
+
+    /// Applies the change to the peer's state, once its window is up to date, and
+    /// re-evaluates its health
+    fn update<F>(&self, account_id: Uuid, now: Instant, change: F) -> PeerState
+    where
+        F: FnOnce(&mut PeerState),
+    {
+        let (state, notification) = {
+            let mut peers = self.peers.lock().unwrap();
+            let state = peers
+                .entry(account_id)
+                .or_insert_with(|| PeerState::new(now));
+            if now.duration_since(state.window_started_at)
+                >= Duration::from_millis(self.policy.window)
+            {
+                state.packets = 0;
+                state.errors = 0;
+                state.window_started_at = now;
+            }
+            change(state);
+
+            let unhealthy_reason = self.policy.evaluate(state);
+            let notification = match unhealthy_reason {
+                Some(reason) if state.healthy => Some((false, reason)),
+                None if !state.healthy => Some((true, "it recovered")),
+                _ => None,
+            };
+            state.healthy = unhealthy_reason.is_none();
+            (*state, notification)
+        };
+        if let Some((healthy, reason)) = notification {
+            self.notify(account_id, healthy, reason);
+        }
+        state
+    }
+
+    fn notify(&self, account_id: Uuid, healthy: bool, reason: &'static str) {
+        if healthy {
+            info!("Peer {} is healthy again: {}", account_id, reason);
+        } else {
+            warn!("Peer {} is unhealthy: {}", account_id, reason);
+        }
+        let change = PeerHealthChange {
+            account_id,
+            healthy,
+            reason,
+        };
+        self.listeners
+            .lock()
+            .unwrap()
+            .retain(|listener| listener.unbounded_send(change.clone()).is_ok());
+    }
+}
+
+impl HealthPolicy {
+    /// Returns why the peer is unhealthy, if it is
+    fn evaluate(&self, state: &PeerState) -> Option<&'static str> {
+        if state.connectivity == Connectivity::Disconnected {
+            Some("it is disconnected")
+        } else if state.packets >= self.min_packets && state.error_rate() > self.max_error_rate {
+            Some("too many packets sent to it failed")
+        } else {
+            None
+        }
+    }
+}
+
+/// # Peer Monitor Service
+///
+/// Outgoing Service which records the outcome of each packet sent to a peer in a
+/// `PeerMonitor`. It should be placed right before the services which send the packets over
+/// BTP or HTTP, so that it sees the Rejects those create when the peer cannot be reached.
+///
+/// Requires an `AddressStore`, which is used to tell the Rejects triggered by the node itself
+/// from those of the peer.
+#[derive(Clone)]
+pub struct PeerMonitorService<O, S> {
+    monitor: PeerMonitor,
+    store: S,
+    next: O,
+}
+
+impl<O, S> PeerMonitorService<O, S> {
+    /// Simple constructor
+    pub fn new(monitor: PeerMonitor, store: S, next: O) -> Self {
+        PeerMonitorService {
+            monitor,
+            store,
+            next,
+        }
+    }
+}
+
+#[async_trait]
+impl<O, S, A> OutgoingService<A> for PeerMonitorService<O, S>
+where
+    O: OutgoingService<A> + Send + Sync,
+    S: AddressStore + Send + Sync,
+    A: Account + Send + Sync + 'static,
+{
+    async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
+        let account_id = request.to.id();
+        let result = self.next.send_request(request).await;
+        let outcome = match result {
+            Ok(_) => Some(Outcome::Fulfill),
+            Err(ref reject) => {
+                let triggered_by_node =
+                    reject.triggered_by().as_ref() == Some(&self.store.get_ilp_address());
+                match (triggered_by_node, reject.code()) {
+                    (true, code) if code.class() != ErrorClass::Final => Some(Outcome::LinkError),
+                    // The packet was not sent to the peer, for example because the account
+                    // has neither a BTP nor an HTTP link
+                    (true, _) => None,
+                    (false, code) if code.class() == ErrorClass::Temporary => {
+                        Some(Outcome::PeerError)
+                    }
+                    (false, _) => Some(Outcome::Reject),
+                }
+            }
+        };
+        if let Some(outcome) = outcome {
+            self.monitor.record(account_id, outcome);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_errors::AddressStoreError;
+    use interledger_packet::{Address, ErrorCode, FulfillBuilder, PrepareBuilder, RejectBuilder};
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+
+    static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+    static NODE_ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("example.node").unwrap());
+    static PEER_ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("example.peer").unwrap());
+
+    #[derive(Clone, Debug)]
+    struct TestAccount;
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            Uuid::nil()
+        }
+
+        fn username(&self) -> &Username {
+            &ALICE
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &PEER_ADDRESS
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestStore;
+
+    #[async_trait]
+    impl AddressStore for TestStore {
+        async fn set_ilp_address(&self, _: Address) -> Result<(), AddressStoreError> {
+            Ok(())
+        }
+
+        async fn clear_ilp_address(&self) -> Result<(), AddressStoreError> {
+            Ok(())
+        }
+
+        fn get_ilp_address(&self) -> Address {
+            NODE_ADDRESS.clone()
+        }
+    }
+
+    fn policy() -> HealthPolicy {
+        HealthPolicy {
+            window: 60_000,
+            min_packets: 4,
+            max_error_rate: 0.5,
+        }
+    }
+
+    async fn send(monitor: &PeerMonitor, code: Option<ErrorCode>, triggered_by: &Address) {
+        let triggered_by = triggered_by.clone();
+        let mut service = PeerMonitorService::new(
+            monitor.clone(),
+            TestStore,
+            outgoing_service_fn(move |_| match code {
+                None => Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: &[],
+                }
+                .build()),
+                Some(code) => Err(RejectBuilder {
+                    code,
+                    message: &[],
+                    triggered_by: Some(&triggered_by),
+                    data: &[],
+                }
+                .build()),
+            }),
+        );
+        let _ = service
+            .send_request(OutgoingRequest {
+                from: TestAccount,
+                to: TestAccount,
+                original_amount: 100,
+                prepare: PrepareBuilder {
+                    destination: PEER_ADDRESS.clone(),
+                    amount: 100,
+                    expires_at: SystemTime::now() + Duration::from_secs(30),
+                    execution_condition: &[0; 32],
+                    data: &[],
+                }
+                .build(),
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn tracks_fulfills_and_errors() {
+        let monitor = PeerMonitor::new(policy());
+        assert_eq!(
+            monitor.health(Uuid::nil()).connectivity,
+            Connectivity::Unknown
+        );
+
+        send(&monitor, None, &PEER_ADDRESS).await;
+        send(
+            &monitor,
+            Some(ErrorCode::F99_APPLICATION_ERROR),
+            &PEER_ADDRESS,
+        )
+        .await;
+        send(
+            &monitor,
+            Some(ErrorCode::T04_INSUFFICIENT_LIQUIDITY),
+            &PEER_ADDRESS,
+        )
+        .await;
+        // Rejects the node triggered without sending the packet are not counted
+        send(&monitor, Some(ErrorCode::F02_UNREACHABLE), &NODE_ADDRESS).await;
+
+        let health = monitor.health(Uuid::nil());
+        assert!(health.healthy);
+        assert_eq!(health.connectivity, Connectivity::Connected);
+        assert!(health.last_fulfill.is_some());
+        assert_eq!(health.packets, 3);
+        assert_eq!(health.errors, 1);
+    }
+
+    #[tokio::test]
+    async fn notifies_unhealthy_peers_and_their_recovery() {
+        let monitor = PeerMonitor::new(policy());
+        let mut changes = monitor.health_changes();
+
+        // Packets the peer could not be reached for only count toward the error rate
+        send(
+            &monitor,
+            Some(ErrorCode::T01_PEER_UNREACHABLE),
+            &NODE_ADDRESS,
+        )
+        .await;
+        let health = monitor.health(Uuid::nil());
+        assert!(health.healthy);
+        assert_eq!(health.connectivity, Connectivity::Unknown);
+        assert_eq!(health.errors, 1);
+        assert!(changes.try_next().is_err());
+
+        monitor.record_connectivity(Uuid::nil(), false);
+        let health = monitor.health(Uuid::nil());
+        assert!(!health.healthy);
+        assert_eq!(health.connectivity, Connectivity::Disconnected);
+        let change = changes.try_next().unwrap().unwrap();
+        assert_eq!(change.account_id, Uuid::nil());
+        assert!(!change.healthy);
+
+        monitor.record_connectivity(Uuid::nil(), true);
+        assert!(monitor.health(Uuid::nil()).healthy);
+        assert!(changes.try_next().unwrap().unwrap().healthy);
+        assert!(changes.try_next().is_err());
+    }
+
+    #[tokio::test]
+    async fn applies_the_error_rate() {
+        let monitor = PeerMonitor::new(policy());
+        for code in &[
+            ErrorCode::R00_TRANSFER_TIMED_OUT,
+            ErrorCode::T01_PEER_UNREACHABLE,
+            ErrorCode::T01_PEER_UNREACHABLE,
+        ] {
+            send(&monitor, Some(*code), &NODE_ADDRESS).await;
+        }
+        // Below the minimum number of packets
+        assert!(monitor.health(Uuid::nil()).healthy);
+
+        send(&monitor, None, &PEER_ADDRESS).await;
+        let health = monitor.health(Uuid::nil());
+        assert!(!health.healthy);
+        assert_eq!(health.error_rate, 0.75);
+
+        // The peer recovers once a new window starts
+        monitor.update(
+            Uuid::nil(),
+            Instant::now() + Duration::from_secs(61),
+            |_| {},
+        );
+        assert!(monitor.health(Uuid::nil()).healthy);
+    }
+}
@@ -0,0 +1,662 @@
+use async_trait::async_trait;
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use interledger_packet::{ErrorCode, RejectBuilder, RejectDetail, RejectDetails};
+use interledger_service::*;
+use metrics::{labels, recorder, Key};
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Maximum number of peers the scoreboard keeps track of, so that failed authentication
+/// attempts for made-up usernames cannot fill up the node's memory
+const MAX_TRACKED_PEERS: usize = 10_000;
+
+/// Something a peer did which the scoreboard keeps track of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The peer fulfilled a packet the node sent to it
+    Fulfill,
+    /// The peer rejected a packet the node sent to it
+    Reject,
+    /// The peer fulfilled a packet with a fulfillment which does not match its condition
+    InvalidFulfillment,
+    /// The peer sent a route update withdrawing routes it advertised
+    RouteFlap,
+    /// A connection for the peer's account failed to authenticate
+    AuthFailure,
+}
+
+/// What the node does with the packets of a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerStatus {
+    /// The peer's packets are handled as usual
+    Good,
+    /// The peer may only send `throttled_packets` packets per window
+    Throttled,
+    /// No packets are accepted from or sent to the peer until the given time
+    Suspended { until: Instant },
+}
+
+/// The change of a peer's status, which operators are notified of
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStatusChange {
+    pub username: Username,
+    pub status: PeerStatus,
+    /// Why the status changed
+    pub reason: String,
+}
+
+/// When the scoreboard automatically throttles or suspends a peer.
+///
+/// The events are counted over fixed windows. Throttled peers are reinstated at the end of
+/// the first window in which none of the throttling thresholds were exceeded, and suspended
+/// peers once their suspension is over. None of the thresholds is set by default, in which
+/// case the peers' behavior is only tracked.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ScoreboardPolicy {
+    /// Length of the windows over which the events are counted, in milliseconds
+    #[serde(default = "default_window")]
+    pub window: u64,
+    /// Minimum number of packets the node must have sent to a peer within a window
+    /// before the reject ratios are applied
+    #[serde(default = "default_min_packets")]
+    pub min_packets: u64,
+    /// Share of the packets sent to a peer which it may reject before it is throttled
+    #[serde(default)]
+    pub throttle_reject_ratio: Option<f64>,
+    /// Share of the packets sent to a peer which it may reject before it is suspended
+    #[serde(default)]
+    pub suspend_reject_ratio: Option<f64>,
+    /// Number of invalid fulfillments a peer may send within a window before it is suspended
+    #[serde(default)]
+    pub max_invalid_fulfillments: Option<u64>,
+    /// Number of route updates withdrawing routes a peer may send within a window before it is throttled
+    #[serde(default)]
+    pub max_route_flaps: Option<u64>,
+    /// Number of failed authentications a peer may have within a window before it is suspended
+    #[serde(default)]
+    pub max_auth_failures: Option<u64>,
+    /// Number of packets a throttled peer may send per window
+    #[serde(default = "default_throttled_packets")]
+    pub throttled_packets: u64,
+    /// How long peers are suspended for, in milliseconds
+    #[serde(default = "default_suspension")]
+    pub suspension: u64,
+}
+
+fn default_window() -> u64 {
+    60_000
+}
+
+fn default_min_packets() -> u64 {
+    100
+}
+
+fn default_throttled_packets() -> u64 {
+    100
+}
+
+fn default_suspension() -> u64 {
+    300_000
+}
+
+impl Default for ScoreboardPolicy {
+    fn default() -> Self {
+        ScoreboardPolicy {
+            window: default_window(),
+            min_packets: default_min_packets(),
+            throttle_reject_ratio: None,
+            suspend_reject_ratio: None,
+            max_invalid_fulfillments: None,
+            max_route_flaps: None,
+            max_auth_failures: None,
+            throttled_packets: default_throttled_packets(),
+            suspension: default_suspension(),
+        }
+    }
+}
+
+/// The events of a peer counted in the current window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerCounts {
+    pub fulfills: u64,
+    pub rejects: u64,
+    pub invalid_fulfillments: u64,
+    pub route_flaps: u64,
+    pub auth_failures: u64,
+    /// Packets the peer sent while it was throttled
+    pub throttled_packets: u64,
+}
+
+impl PeerCounts {
+    fn reject_ratio(&self) -> f64 {
+        let packets = self.fulfills + self.rejects + self.invalid_fulfillments;
+        if packets == 0 {
+            0.0
+        } else {
+            (self.rejects + self.invalid_fulfillments) as f64 / packets as f64
+        }
+    }
+}
+
+/// A peer's entry in the scoreboard
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerScore {
+    pub status: PeerStatus,
+    pub counts: PeerCounts,
+    window_started_at: Instant,
+}
+
+impl PeerScore {
+    fn new(now: Instant) -> Self {
+        PeerScore {
+            status: PeerStatus::Good,
+            counts: PeerCounts::default(),
+            window_started_at: now,
+        }
+    }
+}
+
+/// Shared scoreboard of the behavior of the node's peers, which throttles or suspends
+/// them according to its `ScoreboardPolicy`.
+///
+/// Packet outcomes are recorded by the `PeerScoreboardService`. Other events, such as
+/// route flaps and failed authentications, are recorded with `record`.
+#[derive(Debug, Clone)]
+pub struct PeerScoreboard {
+    policy: Arc<ScoreboardPolicy>,
+    /// Indexed by the lowercased usernames
+    scores: Arc<Mutex<HashMap<String, PeerScore>>>,
+    listeners: Arc<Mutex<Vec<UnboundedSender<PeerStatusChange>>>>,
+}
+
+impl PeerScoreboard {
+    pub fn new(policy: ScoreboardPolicy) -> Self {
+        PeerScoreboard {
+            policy: Arc::new(policy),
+            scores: Arc::new(Mutex::new(HashMap::new())),
+            listeners: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Returns a stream of the changes of the peers' statuses
+    pub fn status_changes(&self) -> UnboundedReceiver<PeerStatusChange> {
+        let (sender, receiver) = unbounded();
+        self.listeners.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Returns the peer's current status
+    pub fn status(&self, username: &Username) -> PeerStatus {
+        self.update(username, Instant::now(), |_, _| None)
+            .unwrap_or(PeerStatus::Good)
+    }
+
+    /// Returns the scores of all the peers which are being tracked
+    pub fn scores(&self) -> HashMap<String, PeerScore> {
+        self.scores.lock().unwrap().clone()
+    }
+
+    /// Counts the event against the peer, throttling or suspending it if the policy says so
+    pub fn record(&self, username: &Username, event: PeerEvent) {
+        let policy = self.policy.clone();
+        self.update(username, Instant::now(), move |score, now| {
+            let counts = &mut score.counts;
+            match event {
+                PeerEvent::Fulfill => counts.fulfills += 1,
+                PeerEvent::Reject => counts.rejects += 1,
+                PeerEvent::InvalidFulfillment => counts.invalid_fulfillments += 1,
+                PeerEvent::RouteFlap => counts.route_flaps += 1,
+                PeerEvent::AuthFailure => counts.auth_failures += 1,
+            }
+            if let PeerStatus::Suspended { .. } = score.status {
+                return None;
+            }
+            let (sanction, reason) = policy.evaluate(counts)?;
+            score.status = match sanction {
+                Sanction::Throttle if score.status == PeerStatus::Throttled => return None,
+                Sanction::Throttle => PeerStatus::Throttled,
+                Sanction::Suspend => PeerStatus::Suspended {
+                    until: now + Duration::from_millis(policy.suspension),
+                },
+            };
+            Some(reason)
+        });
+    }
+
+    /// Lifts the peer's throttling or suspension and resets its counts
+    pub fn reinstate(&self, username: &Username) {
+        let now = Instant::now();
+        let previous = self
+            .scores
+            .lock()
+            .unwrap()
+            .insert(username.to_lowercase(), PeerScore::new(now));
+        if previous.map_or(false, |score| score.status != PeerStatus::Good) {
+            self.notify(username, PeerStatus::Good, "reinstated by the operator");
+        }
+    }
+
+    /// Counts a packet sent by the peer towards its throttled rate. Returns how long the
+    /// peer must wait before it may send packets again if it may not send this one
+    fn admit(&self, username: &Username) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut retry_after = None;
+        let throttled_packets = self.policy.throttled_packets;
+        let window = Duration::from_millis(self.policy.window);
+        self.update(username, now, |score, now| {
+            match score.status {
+                PeerStatus::Good => {}
+                PeerStatus::Throttled => {
+                    if score.counts.throttled_packets >= throttled_packets {
+                        retry_after = Some(window - now.duration_since(score.window_started_at));
+                    } else {
+                        score.counts.throttled_packets += 1;
+                    }
+                }
+                PeerStatus::Suspended { until } => retry_after = Some(until - now),
+            }
+            None
+        });
+        retry_after.map_or(Ok(()), Err)
+    }
+
+    /// Applies the change to the peer's score, once its window and status are up to date.
+    /// The change returns the reason if it changed the peer's status
+    fn update<F>(&self, username: &Username, now: Instant, change: F) -> Option<PeerStatus>
+    where
+        F: FnOnce(&mut PeerScore, Instant) -> Option<&'static str>,
+    {
+        let mut notifications = Vec::new();
+        let status = {
+            let mut scores = self.scores.lock().unwrap();
+            if !scores.contains_key(&username.to_lowercase()) && scores.len() >= MAX_TRACKED_PEERS {
+                return None;
+            }
+            let score = scores
+                .entry(username.to_lowercase())
+                .or_insert_with(|| PeerScore::new(now));
+
+            if now.duration_since(score.window_started_at)
+                >= Duration::from_millis(self.policy.window)
+            {
+                if score.status == PeerStatus::Throttled
+                    && self.policy.evaluate(&score.counts).is_none()
+                {
+                    score.status = PeerStatus::Good;
+                    notifications.push((score.status, "its behavior improved"));
+                }
+                score.counts = PeerCounts::default();
+                score.window_started_at = now;
+            }
+            if let PeerStatus::Suspended { until } = score.status {
+                if now >= until {
+                    score.status = PeerStatus::Good;
+                    notifications.push((score.status, "its suspension is over"));
+                }
+            }
+
+            if let Some(reason) = change(score, now) {
+                notifications.push((score.status, reason));
+            }
+            score.status
+        };
+        for (status, reason) in notifications {
+            self.notify(username, status, reason);
+        }
+        Some(status)
+    }
+
+    fn notify(&self, username: &Username, status: PeerStatus, reason: &str) {
+        let name = match status {
+            PeerStatus::Good => {
+                info!("Peer {} was reinstated: {}", username, reason);
+                "peers.reinstated"
+            }
+            PeerStatus::Throttled => {
+                warn!("Throttling peer {}: {}", username, reason);
+                "peers.throttled"
+            }
+            PeerStatus::Suspended { .. } => {
+                warn!("Suspending peer {}: {}", username, reason);
+                "peers.suspended"
+            }
+        };
+        recorder().increment_counter(
+            Key::from_name_and_labels(name, labels!("username" => username.to_string())),
+            1,
+        );
+        let change = PeerStatusChange {
+            username: username.clone(),
+            status,
+            reason: reason.to_string(),
+        };
+        self.listeners
+            .lock()
+            .unwrap()
+            .retain(|listener| listener.unbounded_send(change.clone()).is_ok());
+    }
+}
+
+/// What the policy does with a peer which misbehaves
+enum Sanction {
+    Throttle,
+    Suspend,
+}
+
+impl ScoreboardPolicy {
+    /// Returns what should be done with the peer, and why, if the counts exceed the thresholds
+    fn evaluate(&self, counts: &PeerCounts) -> Option<(Sanction, &'static str)> {
+        let exceeds = |count: u64, max: Option<u64>| max.map_or(false, |max| count > max);
+        let applies_ratios =
+            counts.fulfills + counts.rejects + counts.invalid_fulfillments >= self.min_packets;
+        let exceeds_ratio = |ratio: Option<f64>| {
+            applies_ratios && ratio.map_or(false, |ratio| counts.reject_ratio() > ratio)
+        };
+        if exceeds(counts.invalid_fulfillments, self.max_invalid_fulfillments) {
+            Some((Sanction::Suspend, "too many invalid fulfillments"))
+        } else if exceeds(counts.auth_failures, self.max_auth_failures) {
+            Some((Sanction::Suspend, "too many failed authentications"))
+        } else if exceeds_ratio(self.suspend_reject_ratio) {
+            Some((Sanction::Suspend, "too many rejected packets"))
+        } else if exceeds_ratio(self.throttle_reject_ratio) {
+            Some((Sanction::Throttle, "too many rejected packets"))
+        } else if exceeds(counts.route_flaps, self.max_route_flaps) {
+            Some((Sanction::Throttle, "too many route flaps"))
+        } else {
+            None
+        }
+    }
+}
+
+/// # Peer Scoreboard Service
+///
+/// Incoming or Outgoing Service which keeps score of the peers' behavior in a `PeerScoreboard`
+/// and enforces the throttling and suspensions it decides on.
+///
+/// As an Outgoing Service, it records whether each packet was fulfilled (checking the
+/// fulfillment against the condition) or rejected by the peer, and rejects the packets to
+/// suspended peers with `T01: Peer Unreachable`. It should be placed before the outgoing
+/// `ValidatorService`, so that it sees the invalid fulfillments.
+///
+/// As an Incoming Service, it rejects the packets from suspended peers, and those of throttled
+/// peers which exceed their rate, with `T05: Rate Limited`.
+///
+/// Requires an `AddressStore`, which is used to tell the Rejects triggered by the node itself,
+/// which are not counted against the peer, from those of the peer.
+#[derive(Clone)]
+pub struct PeerScoreboardService<IO, S> {
+    scoreboard: PeerScoreboard,
+    store: S,
+    next: IO,
+}
+
+impl<IO, S> PeerScoreboardService<IO, S> {
+    /// Simple constructor
+    pub fn new(scoreboard: PeerScoreboard, store: S, next: IO) -> Self {
+        PeerScoreboardService {
+            scoreboard,
+            store,
+            next,
+        }
+    }
+}
+
+#[async_trait]
+impl<I, S, A> IncomingService<A> for PeerScoreboardService<I, S>
+where
+    I: IncomingService<A> + Send + Sync,
+    S: AddressStore + Send + Sync,
+    A: Account + Send + Sync + 'static,
+{
+    async fn handle_request(&mut self, request: IncomingRequest<A>) -> IlpResult {
+        if let Err(retry_after) = self.scoreboard.admit(request.from.username()) {
+            let details = RejectDetails::new().with(RejectDetail::RetryAfter(retry_after));
+            return Err(RejectBuilder {
+                code: ErrorCode::T05_RATE_LIMITED,
+                message: b"Account is throttled or suspended",
+                triggered_by: Some(&self.store.get_ilp_address()),
+                data: &details.to_bytes(ErrorCode::T05_RATE_LIMITED),
+            }
+            .build());
+        }
+        self.next.handle_request(request).await
+    }
+}
+
+#[async_trait]
+impl<O, S, A> OutgoingService<A> for PeerScoreboardService<O, S>
+where
+    O: OutgoingService<A> + Send + Sync,
+    S: AddressStore + Send + Sync,
+    A: Account + Send + Sync + 'static,
+{
+    async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
+        let username = request.to.username().clone();
+        let ilp_address = self.store.get_ilp_address();
+        if let PeerStatus::Suspended { .. } = self.scoreboard.status(&username) {
+            return Err(RejectBuilder {
+                code: ErrorCode::T01_PEER_UNREACHABLE,
+                message: b"Peer is suspended",
+                triggered_by: Some(&ilp_address),
+                data: &[],
+            }
+            .build());
+        }
+
+        let condition = request.prepare.execution_condition().to_vec();
+        let result = self.next.send_request(request).await;
+        match result {
+            Ok(ref fulfill) => {
+                if digest(&SHA256, fulfill.fulfillment()).as_ref() == &condition[..] {
+                    self.scoreboard.record(&username, PeerEvent::Fulfill);
+                } else {
+                    self.scoreboard
+                        .record(&username, PeerEvent::InvalidFulfillment);
+                }
+            }
+            Err(ref reject) => {
+                if reject.triggered_by().as_ref() != Some(&ilp_address) {
+                    self.scoreboard.record(&username, PeerEvent::Reject);
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_errors::AddressStoreError;
+    use interledger_packet::{Address, FulfillBuilder, PrepareBuilder};
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+    use std::time::SystemTime;
+    use uuid::Uuid;
+
+    #[test]
+    fn suspends_peers_for_invalid_fulfillments() {
+        let scoreboard = PeerScoreboard::new(ScoreboardPolicy {
+            max_invalid_fulfillments: Some(1),
+            ..Default::default()
+        });
+        let mut changes = scoreboard.status_changes();
+        scoreboard.record(&ALICE, PeerEvent::InvalidFulfillment);
+        assert_eq!(scoreboard.status(&ALICE), PeerStatus::Good);
+        scoreboard.record(&ALICE, PeerEvent::InvalidFulfillment);
+        assert!(matches!(
+            scoreboard.status(&ALICE),
+            PeerStatus::Suspended { .. }
+        ));
+        assert!(scoreboard.admit(&ALICE).is_err());
+
+        let change = changes.try_next().unwrap().unwrap();
+        assert_eq!(change.username, *ALICE);
+        assert_eq!(change.reason, "too many invalid fulfillments");
+
+        scoreboard.reinstate(&ALICE);
+        assert_eq!(scoreboard.status(&ALICE), PeerStatus::Good);
+        assert_eq!(
+            changes.try_next().unwrap().unwrap().status,
+            PeerStatus::Good
+        );
+    }
+
+    #[test]
+    fn throttles_peers_which_reject_too_many_packets() {
+        let scoreboard = PeerScoreboard::new(ScoreboardPolicy {
+            min_packets: 10,
+            throttle_reject_ratio: Some(0.5),
+            throttled_packets: 2,
+            ..Default::default()
+        });
+        // The ratio is only applied once enough packets were sent
+        for _ in 0..9 {
+            scoreboard.record(&ALICE, PeerEvent::Reject);
+        }
+        assert_eq!(scoreboard.status(&ALICE), PeerStatus::Good);
+        scoreboard.record(&ALICE, PeerEvent::Fulfill);
+        assert_eq!(scoreboard.status(&ALICE), PeerStatus::Throttled);
+
+        assert!(scoreboard.admit(&ALICE).is_ok());
+        assert!(scoreboard.admit(&ALICE).is_ok());
+        let retry_after = scoreboard.admit(&ALICE).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(60));
+        // Other peers are unaffected
+        assert!(scoreboard.admit(&BOB).is_ok());
+    }
+
+    #[test]
+    fn reinstates_peers_after_their_suspension() {
+        let scoreboard = PeerScoreboard::new(ScoreboardPolicy {
+            max_auth_failures: Some(0),
+            suspension: 0,
+            ..Default::default()
+        });
+        let mut changes = scoreboard.status_changes();
+        scoreboard.record(&ALICE, PeerEvent::AuthFailure);
+        assert_eq!(scoreboard.status(&ALICE), PeerStatus::Good);
+        let statuses: Vec<_> = std::iter::from_fn(|| changes.try_next().ok().flatten())
+            .map(|change| change.reason)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec!["too many failed authentications", "its suspension is over"]
+        );
+    }
+
+    #[tokio::test]
+    async fn records_the_packets_sent_to_peers() {
+        let scoreboard = PeerScoreboard::new(ScoreboardPolicy {
+            max_invalid_fulfillments: Some(0),
+            ..Default::default()
+        });
+        let mut service = PeerScoreboardService::new(
+            scoreboard.clone(),
+            TestStore,
+            outgoing_service_fn(|request: OutgoingRequest<TestAccount>| {
+                match request.prepare.amount() {
+                    0 => Err(RejectBuilder {
+                        code: ErrorCode::F99_APPLICATION_ERROR,
+                        message: &[],
+                        triggered_by: Some(&Address::from_str("test.peer").unwrap()),
+                        data: &[],
+                    }
+                    .build()),
+                    1 => Err(RejectBuilder {
+                        code: ErrorCode::T01_PEER_UNREACHABLE,
+                        message: &[],
+                        triggered_by: Some(&Address::from_str("test.node").unwrap()),
+                        data: &[],
+                    }
+                    .build()),
+                    // The fulfillment does not match the condition
+                    _ => Ok(FulfillBuilder {
+                        fulfillment: &[1; 32],
+                        data: &[],
+                    }
+                    .build()),
+                }
+            }),
+        );
+
+        service.send_request(test_request(0)).await.unwrap_err();
+        service.send_request(test_request(1)).await.unwrap_err();
+        let counts = scoreboard.scores()["alice"].counts;
+        // The node's own Rejects are not counted against the peer
+        assert_eq!(counts.rejects, 1);
+
+        service.send_request(test_request(2)).await.unwrap();
+        let reject = service.send_request(test_request(2)).await.unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T01_PEER_UNREACHABLE);
+        assert_eq!(reject.message(), b"Peer is suspended");
+    }
+
+    fn test_request(amount: u64) -> OutgoingRequest<TestAccount> {
+        OutgoingRequest {
+            from: TestAccount(BOB.clone()),
+            to: TestAccount(ALICE.clone()),
+            original_amount: amount,
+            prepare: PrepareBuilder {
+                destination: Address::from_str("test.peer.alice").unwrap(),
+                amount,
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                execution_condition: &[0; 32],
+                data: &[],
+            }
+            .build(),
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestStore;
+
+    #[async_trait]
+    impl AddressStore for TestStore {
+        async fn set_ilp_address(&self, _: Address) -> Result<(), AddressStoreError> {
+            Ok(())
+        }
+
+        async fn clear_ilp_address(&self) -> Result<(), AddressStoreError> {
+            Ok(())
+        }
+
+        fn get_ilp_address(&self) -> Address {
+            Address::from_str("test.node").unwrap()
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct TestAccount(Username);
+
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            Uuid::new_v4()
+        }
+
+        fn username(&self) -> &Username {
+            &self.0
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &EXAMPLE_ADDRESS
+        }
+    }
+
+    static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+    static BOB: Lazy<Username> = Lazy::new(|| Username::from_str("bob").unwrap());
+    static EXAMPLE_ADDRESS: Lazy<Address> =
+        Lazy::new(|| Address::from_str("test.node.alice").unwrap());
+}
@@ -16,6 +16,18 @@ pub trait RateLimitAccount: Account {
     fn amount_per_minute_limit(&self) -> Option<u64> {
         None
     }
+
+    /// The number of packets this account may send at once before being limited to its
+    /// packets per minute. Defaults to the packets per minute limit
+    fn packets_burst_limit(&self) -> Option<u32> {
+        None
+    }
+
+    /// The units this account may send at once before being limited to its amount per
+    /// minute. Defaults to the amount per minute limit
+    fn amount_burst_limit(&self) -> Option<u64> {
+        None
+    }
 }
 
 /// Rate limiting related errors
@@ -36,7 +48,11 @@ pub trait RateLimitStore {
     type Account: RateLimitAccount;
 
     /// Apply rate limits based on the packets per minute and amount of per minute
-    /// limits set on the provided account
+    /// limits set on the provided account.
+    ///
+    /// The limits are token buckets, which hold up to the account's burst limits and are
+    /// refilled continuously at its per minute limits. A packet is only charged to the
+    /// buckets if it is within both limits
     async fn apply_rate_limits(
         &self,
         account: Self::Account,
@@ -0,0 +1,233 @@
+use async_trait::async_trait;
+use interledger_packet::{ErrorCode, RejectBuilder};
+use interledger_service::*;
+use serde::Deserialize;
+use tracing::debug;
+
+/// Lists of the address schemes (the first segment of an ILP address, such as `g` or `test`)
+/// which packets may be sent to.
+///
+/// A scheme is allowed if it is not in `deny` and either `allow` is empty or contains it.
+/// The default policy allows all schemes.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct SchemePolicy {
+    /// Schemes packets may be sent to. If empty, all schemes not in `deny` are allowed.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Schemes packets may never be sent to
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl SchemePolicy {
+    /// Returns whether packets may be sent to addresses in the given scheme
+    pub fn is_allowed(&self, scheme: &str) -> bool {
+        let is_listed = |list: &[String]| list.iter().any(|s| s == scheme);
+        !is_listed(&self.deny) && (self.allow.is_empty() || is_listed(&self.allow))
+    }
+}
+
+/// # Scheme Policy Service
+///
+/// Incoming Service which rejects packets whose destination is in an address scheme the
+/// node is not configured to route to, for example to make sure a staging node only
+/// forwards packets within `test`.
+///
+/// Packets sent to `peer.` addresses (such as ILDCP, CCP and settlement messages) are
+/// always forwarded, since they never leave the link between two nodes.
+///
+/// Requires an `AddressStore`, which is used to set the `triggered_by` field of the Rejects.
+#[derive(Clone)]
+pub struct SchemePolicyService<I, S> {
+    policy: SchemePolicy,
+    store: S,
+    next: I,
+}
+
+impl<I, S> SchemePolicyService<I, S> {
+    /// Simple constructor
+    pub fn new(policy: SchemePolicy, store: S, next: I) -> Self {
+        SchemePolicyService {
+            policy,
+            store,
+            next,
+        }
+    }
+}
+
+#[async_trait]
+impl<I, S, A> IncomingService<A> for SchemePolicyService<I, S>
+where
+    I: IncomingService<A> + Send + Sync + 'static,
+    S: AddressStore + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    /// On receive request:
+    /// 1. If the destination's scheme is `peer` or allowed by the policy, forward the request
+    /// 1. Otherwise, reject it with `F02: Unreachable`
+    async fn handle_request(&mut self, request: IncomingRequest<A>) -> IlpResult {
+        let destination = request.prepare.destination();
+        let scheme = destination.scheme();
+        if scheme == "peer" || self.policy.is_allowed(scheme) {
+            return self.next.handle_request(request).await;
+        }
+
+        debug!(
+            "Rejecting packet from account {} to {}: the `{}` address scheme is not allowed",
+            request.from.id(),
+            destination,
+            scheme
+        );
+        let ilp_address = self.store.get_ilp_address();
+        Err(RejectBuilder {
+            code: ErrorCode::F02_UNREACHABLE,
+            message: format!(
+                "Address scheme `{}` is not allowed by this node's policy",
+                scheme
+            )
+            .as_bytes(),
+            triggered_by: Some(&ilp_address),
+            data: &[],
+        }
+        .build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_errors::AddressStoreError;
+    use interledger_packet::{Address, FulfillBuilder, PrepareBuilder};
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+    use std::time::{Duration, SystemTime};
+    use uuid::Uuid;
+
+    #[test]
+    fn applies_allow_and_deny_lists() {
+        let policy = SchemePolicy::default();
+        assert!(policy.is_allowed("g"));
+        assert!(policy.is_allowed("test"));
+
+        let policy = SchemePolicy {
+            allow: vec!["test".to_string(), "example".to_string()],
+            deny: vec!["example".to_string()],
+        };
+        assert!(policy.is_allowed("test"));
+        assert!(!policy.is_allowed("example"));
+        assert!(!policy.is_allowed("g"));
+
+        let policy = SchemePolicy {
+            allow: vec![],
+            deny: vec!["g".to_string()],
+        };
+        assert!(policy.is_allowed("test"));
+        assert!(!policy.is_allowed("g"));
+    }
+
+    #[tokio::test]
+    async fn rejects_packets_to_other_schemes() {
+        let mut service = test_service();
+        let reject = service
+            .handle_request(test_request("g.alice"))
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F02_UNREACHABLE);
+        assert_eq!(
+            reject.message(),
+            &b"Address scheme `g` is not allowed by this node's policy"[..]
+        );
+        assert_eq!(
+            reject.triggered_by(),
+            Some(Address::from_str("test.connector").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_allowed_and_peer_packets() {
+        let mut service = test_service();
+        service
+            .handle_request(test_request("test.alice"))
+            .await
+            .unwrap();
+        service
+            .handle_request(test_request("peer.config"))
+            .await
+            .unwrap();
+    }
+
+    fn test_service() -> SchemePolicyService<impl IncomingService<TestAccount> + Clone, TestStore> {
+        let next = incoming_service_fn(|_| {
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: &[],
+            }
+            .build())
+        });
+        let policy = SchemePolicy {
+            allow: vec!["test".to_string()],
+            deny: vec![],
+        };
+        SchemePolicyService::new(policy, TestStore, next)
+    }
+
+    fn test_request(destination: &str) -> IncomingRequest<TestAccount> {
+        IncomingRequest {
+            from: TestAccount,
+            prepare: PrepareBuilder {
+                destination: Address::from_str(destination).unwrap(),
+                amount: 100,
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                execution_condition: &[0; 32],
+                data: &[],
+            }
+            .build(),
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestStore;
+
+    #[async_trait]
+    impl AddressStore for TestStore {
+        async fn set_ilp_address(&self, _: Address) -> Result<(), AddressStoreError> {
+            unimplemented!()
+        }
+
+        async fn clear_ilp_address(&self) -> Result<(), AddressStoreError> {
+            unimplemented!()
+        }
+
+        fn get_ilp_address(&self) -> Address {
+            Address::from_str("test.connector").unwrap()
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct TestAccount;
+
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            Uuid::new_v4()
+        }
+
+        fn username(&self) -> &Username {
+            &ALICE
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &EXAMPLE_ADDRESS
+        }
+    }
+
+    static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+    static EXAMPLE_ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("test.alice").unwrap());
+}
@@ -0,0 +1,382 @@
+use async_trait::async_trait;
+use interledger_packet::{ErrorCode, RejectBuilder, RejectDetail, RejectDetails};
+use interledger_service::*;
+use metrics::{labels, recorder, Key};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// The window over which the tenants' per-minute rate limits are applied
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// One of the tenants hosted by a multi-tenant node
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Tenant {
+    /// Usernames of the accounts which belong to the tenant
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    /// Address prefixes the tenant's accounts may send packets to
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    /// Maximum number of packets all of the tenant's accounts together may send per minute
+    #[serde(default)]
+    pub packets_per_minute: Option<u32>,
+}
+
+impl Tenant {
+    fn is_allowed(&self, destination: &str, global_prefixes: &[String]) -> bool {
+        self.prefixes
+            .iter()
+            .chain(global_prefixes)
+            .any(|prefix| destination.starts_with(prefix.as_str()))
+    }
+}
+
+/// Which destinations the accounts of each tenant may send packets to.
+///
+/// The accounts which do not belong to any tenant are not restricted.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TenantPolicy {
+    /// The tenants, by name
+    #[serde(default)]
+    pub tenants: HashMap<String, Tenant>,
+    /// Address prefixes the accounts of every tenant may send packets to
+    #[serde(default)]
+    pub global_prefixes: Vec<String>,
+}
+
+/// A fixed window counter used to apply a tenant's per-minute rate limit
+#[derive(Debug, Clone, Copy)]
+struct RateLimitWindow {
+    started_at: Instant,
+    packets: u32,
+}
+
+/// # Tenant Isolation Service
+///
+/// Incoming Service which keeps the tenants of a node hosting several of them apart:
+/// packets from a tenant's accounts are rejected unless their destination starts with
+/// one of the tenant's prefixes or one of the global prefixes, and each tenant's accounts
+/// share a per-minute packet limit.
+///
+/// The packets of each tenant are counted in the `requests.incoming.tenant.*` metrics,
+/// labeled with the tenant's name.
+///
+/// Packets sent to `peer.` addresses (such as ILDCP, CCP and settlement messages) are
+/// always forwarded, since they never leave the link between two nodes.
+///
+/// Requires an `AddressStore`, which is used to set the `triggered_by` field of the Rejects.
+#[derive(Clone)]
+pub struct TenantIsolationService<I, S> {
+    policy: Arc<TenantPolicy>,
+    /// Maps the usernames of the tenants' accounts to the tenants' names
+    tenant_names: Arc<HashMap<String, String>>,
+    rate_limits: Arc<Mutex<HashMap<String, RateLimitWindow>>>,
+    store: S,
+    next: I,
+}
+
+impl<I, S> TenantIsolationService<I, S> {
+    /// Simple constructor
+    pub fn new(policy: TenantPolicy, store: S, next: I) -> Self {
+        let tenant_names = policy
+            .tenants
+            .iter()
+            .flat_map(|(name, tenant)| {
+                tenant
+                    .accounts
+                    .iter()
+                    .map(move |username| (username.clone(), name.clone()))
+            })
+            .collect();
+        TenantIsolationService {
+            policy: Arc::new(policy),
+            tenant_names: Arc::new(tenant_names),
+            rate_limits: Arc::new(Mutex::new(HashMap::new())),
+            store,
+            next,
+        }
+    }
+
+    /// Counts the packet towards the tenant's limit. If the limit was reached, returns
+    /// how long it takes until the tenant may send packets again
+    fn apply_rate_limit(&self, tenant_name: &str, limit: u32) -> Result<(), Duration> {
+        let mut rate_limits = self.rate_limits.lock().unwrap();
+        let window = rate_limits
+            .entry(tenant_name.to_string())
+            .or_insert_with(|| RateLimitWindow {
+                started_at: Instant::now(),
+                packets: 0,
+            });
+        if window.started_at.elapsed() >= RATE_LIMIT_WINDOW {
+            *window = RateLimitWindow {
+                started_at: Instant::now(),
+                packets: 0,
+            };
+        }
+        if window.packets >= limit {
+            // Time passed since the window was checked above, so it may have just ended
+            Err(RATE_LIMIT_WINDOW
+                .checked_sub(window.started_at.elapsed())
+                .unwrap_or_default())
+        } else {
+            window.packets += 1;
+            Ok(())
+        }
+    }
+}
+
+fn increment_counter(name: &'static str, tenant_name: &str) {
+    recorder().increment_counter(
+        Key::from_name_and_labels(name, labels!("tenant" => tenant_name.to_string())),
+        1,
+    );
+}
+
+#[async_trait]
+impl<I, S, A> IncomingService<A> for TenantIsolationService<I, S>
+where
+    I: IncomingService<A> + Send + Sync + 'static,
+    S: AddressStore + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    /// On receive request:
+    /// 1. If the account does not belong to a tenant or the destination's scheme is `peer`,
+    ///    forward the request
+    /// 1. If the destination is not in the tenant's or the global prefixes, reject it with
+    ///    `F02: Unreachable`
+    /// 1. If the tenant exceeded its packet limit, reject it with `T05: Rate Limited`,
+    ///    telling the sender when the limit resets
+    /// 1. Otherwise, forward the request
+    async fn handle_request(&mut self, request: IncomingRequest<A>) -> IlpResult {
+        let tenant_name = match self.tenant_names.get(&request.from.username().to_string()) {
+            Some(tenant_name) => tenant_name.clone(),
+            None => return self.next.handle_request(request).await,
+        };
+        let destination = request.prepare.destination();
+        if destination.scheme() == "peer" {
+            return self.next.handle_request(request).await;
+        }
+        let tenant = &self.policy.tenants[&tenant_name];
+
+        let reject = if !tenant.is_allowed(&destination, &self.policy.global_prefixes) {
+            debug!(
+                "Rejecting packet from account {} of tenant {} to {}: the destination is outside of the tenant's prefixes",
+                request.from.id(),
+                tenant_name,
+                destination
+            );
+            increment_counter("requests.incoming.tenant.isolated", &tenant_name);
+            Some((
+                ErrorCode::F02_UNREACHABLE,
+                "Destination is not reachable by this account's tenant",
+                RejectDetails::new(),
+            ))
+        } else if let Some(Err(retry_after)) = tenant
+            .packets_per_minute
+            .map(|limit| self.apply_rate_limit(&tenant_name, limit))
+        {
+            warn!(
+                "Tenant {} was rate limited for sending too many packets. Limit is: {} per minute",
+                tenant_name,
+                tenant.packets_per_minute.unwrap_or_default()
+            );
+            increment_counter("requests.incoming.tenant.rate_limited", &tenant_name);
+            Some((
+                ErrorCode::T05_RATE_LIMITED,
+                "",
+                RejectDetails::new().with(RejectDetail::RetryAfter(retry_after)),
+            ))
+        } else {
+            None
+        };
+        if let Some((code, message, details)) = reject {
+            let ilp_address = self.store.get_ilp_address();
+            return Err(RejectBuilder {
+                code,
+                message: message.as_bytes(),
+                triggered_by: Some(&ilp_address),
+                data: &details.to_bytes(code),
+            }
+            .build());
+        }
+
+        increment_counter("requests.incoming.tenant.prepare", &tenant_name);
+        let result = self.next.handle_request(request).await;
+        if result.is_ok() {
+            increment_counter("requests.incoming.tenant.fulfill", &tenant_name);
+        } else {
+            increment_counter("requests.incoming.tenant.reject", &tenant_name);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use interledger_errors::AddressStoreError;
+    use interledger_packet::{Address, FulfillBuilder, PrepareBuilder};
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+    use std::time::SystemTime;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn restricts_tenants_to_their_prefixes() {
+        let mut service = test_service(None);
+        service
+            .handle_request(test_request(&ALICE, "test.node.tenant-a.bob"))
+            .await
+            .unwrap();
+        service
+            .handle_request(test_request(&ALICE, "test.exchange.usd"))
+            .await
+            .unwrap();
+        service
+            .handle_request(test_request(&ALICE, "peer.config"))
+            .await
+            .unwrap();
+
+        let reject = service
+            .handle_request(test_request(&ALICE, "test.node.tenant-b.carl"))
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F02_UNREACHABLE);
+        assert_eq!(
+            reject.message(),
+            &b"Destination is not reachable by this account's tenant"[..]
+        );
+        assert_eq!(
+            reject.triggered_by(),
+            Some(Address::from_str("test.node").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_restrict_other_accounts() {
+        let mut service = test_service(Some(0));
+        service
+            .handle_request(test_request(&DAVE, "test.node.tenant-b.carl"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn applies_the_tenants_rate_limit() {
+        let mut service = test_service(Some(2));
+        for _ in 0..2 {
+            service
+                .handle_request(test_request(&ALICE, "test.node.tenant-a.bob"))
+                .await
+                .unwrap();
+        }
+        let reject = service
+            .handle_request(test_request(&ALICE, "test.node.tenant-a.bob"))
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T05_RATE_LIMITED);
+        let retry_after = RejectDetails::from_reject(&reject)
+            .unwrap()
+            .retry_after()
+            .unwrap();
+        assert!(retry_after > Duration::from_secs(55) && retry_after <= RATE_LIMIT_WINDOW);
+        // The limit is shared with the tenant's other accounts
+        let reject = service
+            .handle_request(test_request(&BOB, "test.exchange.usd"))
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T05_RATE_LIMITED);
+    }
+
+    fn test_service(
+        packets_per_minute: Option<u32>,
+    ) -> TenantIsolationService<impl IncomingService<TestAccount> + Clone, TestStore> {
+        let next = incoming_service_fn(|_| {
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: &[],
+            }
+            .build())
+        });
+        let policy = TenantPolicy {
+            tenants: vec![(
+                "tenant-a".to_string(),
+                Tenant {
+                    accounts: vec!["alice".to_string(), "bob".to_string()],
+                    prefixes: vec!["test.node.tenant-a.".to_string()],
+                    packets_per_minute,
+                },
+            )]
+            .into_iter()
+            .collect(),
+            global_prefixes: vec!["test.exchange.".to_string()],
+        };
+        TenantIsolationService::new(policy, TestStore, next)
+    }
+
+    fn test_request(username: &Username, destination: &str) -> IncomingRequest<TestAccount> {
+        IncomingRequest {
+            from: TestAccount(username.clone()),
+            prepare: PrepareBuilder {
+                destination: Address::from_str(destination).unwrap(),
+                amount: 100,
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                execution_condition: &[0; 32],
+                data: &[],
+            }
+            .build(),
+        }
+    }
+
+    #[derive(Clone)]
+    struct TestStore;
+
+    #[async_trait]
+    impl AddressStore for TestStore {
+        async fn set_ilp_address(&self, _: Address) -> Result<(), AddressStoreError> {
+            Ok(())
+        }
+
+        async fn clear_ilp_address(&self) -> Result<(), AddressStoreError> {
+            Ok(())
+        }
+
+        fn get_ilp_address(&self) -> Address {
+            Address::from_str("test.node").unwrap()
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct TestAccount(Username);
+
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            Uuid::new_v4()
+        }
+
+        fn username(&self) -> &Username {
+            &self.0
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &EXAMPLE_ADDRESS
+        }
+    }
+
+    static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+    static BOB: Lazy<Username> = Lazy::new(|| Username::from_str("bob").unwrap());
+    static DAVE: Lazy<Username> = Lazy::new(|| Username::from_str("dave").unwrap());
+    static EXAMPLE_ADDRESS: Lazy<Address> =
+        Lazy::new(|| Address::from_str("test.node.alice").unwrap());
+}
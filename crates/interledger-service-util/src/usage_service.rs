@@ -0,0 +1,325 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::TryFutureExt;
+use interledger_errors::UsageStoreError;
+use interledger_service::*;
+use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+use tracing::error;
+use uuid::Uuid;
+
+/// The periods for which usage counters are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsagePeriod {
+    Daily,
+    Monthly,
+}
+
+impl UsagePeriod {
+    /// All of the periods usage is tracked for
+    pub const ALL: [UsagePeriod; 2] = [UsagePeriod::Daily, UsagePeriod::Monthly];
+
+    /// Returns the identifier of the period containing the given time,
+    /// for example `2020-01-31` for a daily period or `2020-01` for a monthly one.
+    pub fn period_id(self, time: DateTime<Utc>) -> String {
+        match self {
+            UsagePeriod::Daily => time.format("%Y-%m-%d").to_string(),
+            UsagePeriod::Monthly => time.format("%Y-%m").to_string(),
+        }
+    }
+
+    /// Returns the identifier of the current period
+    pub fn current_period_id(self) -> String {
+        self.period_id(Utc::now())
+    }
+}
+
+/// Usage counters of an account over a single period
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountUsage {
+    /// The identifier of the period these counters cover (see [`UsagePeriod::period_id`](./enum.UsagePeriod.html#method.period_id))
+    pub period: String,
+    /// Number of fulfilled packets the account sent through the node
+    pub packets_sent: u64,
+    /// Number of fulfilled packets the account received from the node
+    pub packets_received: u64,
+    /// Total amount sent by the account, in its own asset and scale
+    pub amount_sent: u64,
+    /// Total amount received by the account, in its own asset and scale
+    pub amount_received: u64,
+}
+
+/// Store trait which maintains the per-account usage counters
+/// used for metering and billing
+#[async_trait]
+pub trait UsageStore {
+    /// Records a fulfilled packet sent by `from_account_id` and received by `to_account_id`,
+    /// incrementing the counters of the current daily and monthly periods of both accounts
+    async fn record_usage(
+        &self,
+        from_account_id: Uuid,
+        incoming_amount: u64,
+        to_account_id: Uuid,
+        outgoing_amount: u64,
+    ) -> Result<(), UsageStoreError>;
+
+    /// Loads the counters of the given account for the current period
+    async fn get_usage(
+        &self,
+        account_id: Uuid,
+        period: UsagePeriod,
+    ) -> Result<AccountUsage, UsageStoreError>;
+
+    /// Resets the counters of the given account for the current period
+    async fn reset_usage(
+        &self,
+        account_id: Uuid,
+        period: UsagePeriod,
+    ) -> Result<(), UsageStoreError>;
+}
+
+/// # Usage Service
+///
+/// Outgoing Service which counts the fulfilled packets and the amounts sent and
+/// received by each account, so that node operators can meter and bill their users.
+///
+/// The counters are updated after the Fulfill is relayed back, so that the store is never
+/// on the critical path of the packet. Packets sent to `peer.` addresses (such as ILDCP, CCP
+/// and settlement messages) are not counted.
+///
+/// Requires an `Account` and a `UsageStore`
+#[derive(Clone)]
+pub struct UsageService<S, O, A> {
+    store: S,
+    next: O,
+    account_type: PhantomData<A>,
+}
+
+impl<S, O, A> UsageService<S, O, A>
+where
+    S: UsageStore,
+    O: OutgoingService<A>,
+    A: Account,
+{
+    pub fn new(store: S, next: O) -> Self {
+        UsageService {
+            store,
+            next,
+            account_type: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<S, O, A> OutgoingService<A> for UsageService<S, O, A>
+where
+    S: UsageStore + Clone + Send + Sync + 'static,
+    O: OutgoingService<A> + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    /// On send request:
+    /// 1. Forwards the request
+    /// 1. If it returns a Fulfill, spawns a task calling `store.record_usage` and
+    ///    returns the Fulfill INDEPENDENTLY of whether recording succeeds
+    async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
+        if request.prepare.destination().scheme() == "peer" {
+            return self.next.send_request(request).await;
+        }
+
+        let from_id = request.from.id();
+        let to_id = request.to.id();
+        let incoming_amount = request.original_amount;
+        let outgoing_amount = request.prepare.amount();
+
+        let fulfill = self.next.send_request(request).await?;
+
+        tokio::spawn({
+            let store = self.store.clone();
+            async move {
+                store
+                    .record_usage(from_id, incoming_amount, to_id, outgoing_amount)
+                    .map_err(move |err| {
+                        error!(
+                            "Error recording usage for packet from account: {} to account: {}: {}",
+                            from_id, to_id, err
+                        )
+                    })
+                    .await
+            }
+        });
+
+        Ok(fulfill)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use interledger_packet::{Address, ErrorCode, FulfillBuilder, PrepareBuilder, RejectBuilder};
+    use once_cell::sync::Lazy;
+    use parking_lot::Mutex;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn period_ids() {
+        let time = Utc.ymd(2020, 1, 31).and_hms(23, 59, 59);
+        assert_eq!(UsagePeriod::Daily.period_id(time), "2020-01-31");
+        assert_eq!(UsagePeriod::Monthly.period_id(time), "2020-01");
+    }
+
+    #[tokio::test]
+    async fn records_usage_for_fulfill() {
+        let store = TestStore::default();
+        let next = outgoing_service_fn(|_| {
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: b"test data",
+            }
+            .build())
+        });
+        let mut service = UsageService::new(store.clone(), next);
+        service
+            .send_request(test_request("example.destination"))
+            .await
+            .unwrap();
+
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        assert_eq!(*store.recorded.lock(), vec![(*FROM_ID, 100, *TO_ID, 200)]);
+    }
+
+    #[tokio::test]
+    async fn does_not_record_usage_for_reject() {
+        let store = TestStore::default();
+        let next = outgoing_service_fn(|_| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: &[],
+                triggered_by: None,
+                data: &[],
+            }
+            .build())
+        });
+        let mut service = UsageService::new(store.clone(), next);
+        service
+            .send_request(test_request("example.destination"))
+            .await
+            .unwrap_err();
+
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        assert!(store.recorded.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn does_not_record_usage_for_peer_protocols() {
+        let store = TestStore::default();
+        let next = outgoing_service_fn(|_| {
+            Ok(FulfillBuilder {
+                fulfillment: &[0; 32],
+                data: &[],
+            }
+            .build())
+        });
+        let mut service = UsageService::new(store.clone(), next);
+        service
+            .send_request(test_request("peer.route.update"))
+            .await
+            .unwrap();
+
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+        assert!(store.recorded.lock().is_empty());
+    }
+
+    fn test_request(destination: &str) -> OutgoingRequest<TestAccount> {
+        OutgoingRequest {
+            from: TestAccount(*FROM_ID),
+            to: TestAccount(*TO_ID),
+            original_amount: 100,
+            prepare: PrepareBuilder {
+                destination: Address::from_str(destination).unwrap(),
+                amount: 200,
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                execution_condition: &[0; 32],
+                data: &[],
+            }
+            .build(),
+        }
+    }
+
+    static FROM_ID: Lazy<Uuid> = Lazy::new(Uuid::new_v4);
+    static TO_ID: Lazy<Uuid> = Lazy::new(Uuid::new_v4);
+    static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+    static EXAMPLE_ADDRESS: Lazy<Address> =
+        Lazy::new(|| Address::from_str("example.alice").unwrap());
+
+    #[derive(Debug, Clone)]
+    struct TestAccount(Uuid);
+
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            self.0
+        }
+
+        fn username(&self) -> &Username {
+            &ALICE
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &EXAMPLE_ADDRESS
+        }
+    }
+
+    /// (from account, incoming amount, to account, outgoing amount)
+    type RecordedUsage = (Uuid, u64, Uuid, u64);
+
+    #[derive(Clone, Default)]
+    struct TestStore {
+        recorded: Arc<Mutex<Vec<RecordedUsage>>>,
+    }
+
+    #[async_trait]
+    impl UsageStore for TestStore {
+        async fn record_usage(
+            &self,
+            from_account_id: Uuid,
+            incoming_amount: u64,
+            to_account_id: Uuid,
+            outgoing_amount: u64,
+        ) -> Result<(), UsageStoreError> {
+            self.recorded.lock().push((
+                from_account_id,
+                incoming_amount,
+                to_account_id,
+                outgoing_amount,
+            ));
+            Ok(())
+        }
+
+        async fn get_usage(
+            &self,
+            _account_id: Uuid,
+            _period: UsagePeriod,
+        ) -> Result<AccountUsage, UsageStoreError> {
+            unimplemented!()
+        }
+
+        async fn reset_usage(
+            &self,
+            _account_id: Uuid,
+            _period: UsagePeriod,
+        ) -> Result<(), UsageStoreError> {
+            unimplemented!()
+        }
+    }
+}
@@ -1,11 +1,13 @@
+use super::expiry::PacketExpiry;
 use async_trait::async_trait;
 use chrono::{DateTime, Duration, Utc};
 use interledger_packet::{hex::HexString, ErrorCode, RejectBuilder};
 use interledger_service::*;
+use metrics::{labels, recorder, Key};
 use ring::digest::{digest, SHA256};
 use std::marker::PhantomData;
-use tokio::time::timeout;
-use tracing::error;
+use tokio::time::timeout_at;
+use tracing::{error, warn};
 
 /// # Validator Service
 ///
@@ -16,9 +18,25 @@ use tracing::error;
 pub struct ValidatorService<IO, S, A> {
     store: S,
     next: IO,
+    /// How long after their expiry incoming packets are still accepted
+    clock_skew_tolerance: Duration,
     account_type: PhantomData<A>,
 }
 
+impl<IO, S, A> ValidatorService<IO, S, A> {
+    /// Accepts incoming packets up to `tolerance` after their expiry, so that peers whose
+    /// clocks are behind the node's do not get their packets rejected with `R00: Transfer Timed Out`.
+    /// Each packet accepted thanks to the tolerance is counted in the `requests.incoming.skew_adjusted`
+    /// metric, labeled with the username of the account it came from.
+    ///
+    /// This has no effect on outgoing validators.
+    pub fn with_clock_skew_tolerance(mut self, tolerance: std::time::Duration) -> Self {
+        self.clock_skew_tolerance =
+            Duration::from_std(tolerance).unwrap_or_else(|_| Duration::max_value());
+        self
+    }
+}
+
 impl<I, S, A> ValidatorService<I, S, A>
 where
     I: IncomingService<A>,
@@ -31,6 +49,7 @@ where
         ValidatorService {
             store,
             next,
+            clock_skew_tolerance: Duration::zero(),
             account_type: PhantomData,
         }
     }
@@ -49,6 +68,7 @@ where
         ValidatorService {
             store,
             next,
+            clock_skew_tolerance: Duration::zero(),
             account_type: PhantomData,
         }
     }
@@ -62,12 +82,31 @@ where
     A: Account + Send + Sync,
 {
     /// On receiving a request:
-    /// 1. If the prepare packet in the request is not expired, forward it, otherwise return a reject
+    /// 1. If the prepare packet in the request is not expired (allowing for the clock skew tolerance),
+    ///    forward it, otherwise return a reject
     async fn handle_request(&mut self, request: IncomingRequest<A>) -> IlpResult {
         let expires_at = DateTime::<Utc>::from(request.prepare.expires_at());
         let now = Utc::now();
         if expires_at >= now {
             self.next.handle_request(request).await
+        } else if expires_at
+            .checked_add_signed(self.clock_skew_tolerance)
+            // A tolerance too large to be added to the expiry covers any packet
+            .map_or(true, |deadline| deadline >= now)
+        {
+            warn!(
+                "Accepting incoming packet from account {} which expired {}ms ago, within the clock skew tolerance",
+                request.from.username(),
+                now.signed_duration_since(expires_at).num_milliseconds(),
+            );
+            recorder().increment_counter(
+                Key::from_name_and_labels(
+                    "requests.incoming.skew_adjusted",
+                    labels!("from_username" => request.from.username().to_string()),
+                ),
+                1,
+            );
+            self.next.handle_request(request).await
         } else {
             error!(
                 "Incoming packet expired {}ms ago at {:?} (time now: {:?})",
@@ -105,17 +144,12 @@ where
         let mut condition: [u8; 32] = [0; 32];
         condition[..].copy_from_slice(request.prepare.execution_condition()); // why?
 
-        let expires_at = DateTime::<Utc>::from(request.prepare.expires_at());
-        let now = Utc::now();
-        let time_left = expires_at - now;
+        // Measure the expiry once, so that the timeout is not affected by wall clock jumps
+        let expiry = PacketExpiry::new(request.prepare.expires_at());
         let ilp_address = self.store.get_ilp_address();
-        if time_left > Duration::zero() {
+        if !expiry.is_expired() {
             // Result of the future
-            let result = timeout(
-                time_left.to_std().expect("Time left must be positive"),
-                self.next.send_request(request),
-            )
-            .await;
+            let result = timeout_at(expiry.deadline(), self.next.send_request(request)).await;
 
             let fulfill = match result {
                 // If the future completed in time, it returns an IlpResult,
@@ -124,9 +158,8 @@ where
                 // If the future timed out, then it results in an error
                 Err(_) => {
                     error!(
-                        "Outgoing request timed out after {}ms (expiry was: {})",
-                        time_left.num_milliseconds(),
-                        expires_at,
+                        "Outgoing request timed out (expiry was: {})",
+                        DateTime::<Utc>::from(expiry.expires_at()),
                     );
                     return Err(RejectBuilder {
                         code: ErrorCode::R00_TRANSFER_TIMED_OUT,
@@ -154,7 +187,7 @@ where
         } else {
             error!(
                 "Outgoing packet expired {}ms ago",
-                (Duration::zero() - time_left).num_milliseconds(),
+                expiry.expired_for().as_millis(),
             );
             // Already expired
             Err(RejectBuilder {
@@ -318,6 +351,58 @@ mod incoming {
             ErrorCode::R00_TRANSFER_TIMED_OUT
         );
     }
+
+    #[tokio::test]
+    async fn accepts_expired_incoming_packet_within_clock_skew_tolerance() {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        let mut validator = ValidatorService::incoming(
+            TestStore,
+            incoming_service_fn(move |request| {
+                requests_clone.lock().unwrap().push(request);
+                Ok(FulfillBuilder {
+                    fulfillment: &[0; 32],
+                    data: b"test data",
+                }
+                .build())
+            }),
+        )
+        .with_clock_skew_tolerance(Duration::from_secs(5));
+        let prepare = |expired_for: Duration| IncomingRequest {
+            from: TestAccount(Uuid::new_v4()),
+            prepare: PrepareBuilder {
+                destination: Address::from_str("example.destination").unwrap(),
+                amount: 100,
+                expires_at: SystemTime::now() - expired_for,
+                execution_condition: &[0; 32],
+                data: b"test data",
+            }
+            .build(),
+        };
+
+        let result = validator
+            .handle_request(prepare(Duration::from_secs(2)))
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        let result = validator
+            .handle_request(prepare(Duration::from_secs(30)))
+            .await;
+        assert_eq!(
+            result.unwrap_err().code(),
+            ErrorCode::R00_TRANSFER_TIMED_OUT
+        );
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        // Tolerances which overflow the expiry accept every packet
+        let mut validator = validator.with_clock_skew_tolerance(Duration::from_secs(u64::MAX));
+        let result = validator
+            .handle_request(prepare(Duration::from_secs(30)))
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,169 @@
+/// Store-agnostic identifier of an account
+#[cfg(feature = "redis_account_id")]
+use redis::{ErrorKind, FromRedisValue, RedisError, RedisWrite, ToRedisArgs, Value};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display},
+    str::FromStr,
+};
+use uuid::Uuid;
+
+/// The identifier of an account, shared by the stores and the APIs.
+///
+/// Account ids are UUIDs. Earlier deployments identified accounts with
+/// sequential numbers, so parsing also accepts a plain decimal number, which is
+/// mapped to the UUID with the same 128-bit value (see [`AccountId::from_legacy`]).
+/// This lets stores and APIs keep resolving the ids handed out before the
+/// switch to UUIDs.
+///
+/// The [`Account`](./trait.Account.html) trait still returns the underlying `Uuid`,
+/// which converts to and from this type with `From`.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct AccountId(Uuid);
+
+impl AccountId {
+    /// Returns the account id which a legacy numeric account id maps to.
+    ///
+    /// Account `1` becomes `00000000-0000-0000-0000-000000000001`.
+    pub fn from_legacy(id: u64) -> Self {
+        AccountId(Uuid::from_u128(u128::from(id)))
+    }
+
+    /// Returns the legacy numeric id, if this id was derived from one
+    pub fn legacy_id(&self) -> Option<u64> {
+        u64::try_from(self.0.as_u128()).ok()
+    }
+
+    /// Returns the underlying UUID
+    pub fn as_uuid(&self) -> &Uuid {
+        &self.0
+    }
+}
+
+impl From<Uuid> for AccountId {
+    fn from(id: Uuid) -> Self {
+        AccountId(id)
+    }
+}
+
+impl From<AccountId> for Uuid {
+    fn from(id: AccountId) -> Self {
+        id.0
+    }
+}
+
+impl From<AccountId> for String {
+    fn from(id: AccountId) -> Self {
+        id.to_string()
+    }
+}
+
+impl Display for AccountId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0.to_hyphenated().to_string())
+    }
+}
+
+impl TryFrom<String> for AccountId {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<AccountId, String> {
+        AccountId::from_str(&value)
+    }
+}
+
+impl FromStr for AccountId {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        if !src.is_empty() && src.bytes().all(|b| b.is_ascii_digit()) {
+            return u64::from_str(src)
+                .map(AccountId::from_legacy)
+                .map_err(|_| "legacy account id out of range".to_owned());
+        }
+        Uuid::from_str(src)
+            .map(AccountId)
+            .map_err(|_| "invalid account id format".to_owned())
+    }
+}
+
+#[cfg(feature = "redis_account_id")]
+impl ToRedisArgs for AccountId {
+    fn write_redis_args<W: RedisWrite + ?Sized>(&self, out: &mut W) {
+        out.write_arg(self.to_string().as_bytes());
+    }
+}
+
+#[cfg(feature = "redis_account_id")]
+impl FromRedisValue for AccountId {
+    fn from_redis_value(v: &Value) -> Result<Self, RedisError> {
+        let account_id = String::from_redis_value(v)?;
+        // Also accepts the numeric ids of accounts which were not migrated yet
+        AccountId::from_str(&account_id)
+            .map_err(|_| RedisError::from((ErrorKind::TypeError, "Invalid account id string")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_uuids() {
+        let uuid = Uuid::from_str("7b6b6a2c-5e0d-4bdc-9f6c-1e3c2f2e8a11").unwrap();
+        let id = AccountId::from_str(&uuid.to_string()).unwrap();
+        assert_eq!(Uuid::from(id), uuid);
+        assert_eq!(id.legacy_id(), None);
+    }
+
+    #[test]
+    fn parses_legacy_numeric_ids() {
+        let id = AccountId::from_str("42").unwrap();
+        assert_eq!(id, AccountId::from_legacy(42));
+        assert_eq!(id.legacy_id(), Some(42));
+        // the migrated form parses back to the same id
+        assert_eq!(AccountId::from_str(&id.to_string()).unwrap(), id);
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        assert!(AccountId::from_str("").is_err());
+        assert!(AccountId::from_str("alice").is_err());
+        assert!(AccountId::from_str("-1").is_err());
+        assert!(AccountId::from_str("99999999999999999999999").is_err());
+    }
+
+    #[test]
+    fn parses_display_output() {
+        for id in &[
+            AccountId::default(),
+            AccountId::from_legacy(1),
+            AccountId::from_str("7b6b6a2c-5e0d-4bdc-9f6c-1e3c2f2e8a11").unwrap(),
+        ] {
+            assert_eq!(AccountId::from_str(&id.to_string()), Ok(*id));
+        }
+        assert_eq!(
+            AccountId::from_legacy(1).to_string(),
+            "00000000-0000-0000-0000-000000000001"
+        );
+    }
+
+    #[test]
+    fn serde() {
+        use serde_test::{assert_de_tokens, assert_de_tokens_error, assert_tokens, Token};
+        let id = AccountId::from_legacy(1);
+        assert_tokens(
+            &id,
+            &[Token::String("00000000-0000-0000-0000-000000000001")],
+        );
+        assert_de_tokens(&id, &[Token::BorrowedStr("1")]);
+        assert_de_tokens_error::<AccountId>(
+            &[Token::BorrowedStr("alice")],
+            "invalid account id format",
+        );
+    }
+}
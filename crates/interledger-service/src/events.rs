@@ -0,0 +1,184 @@
+use metrics::{recorder, Key};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{
+    broadcast,
+    mpsc::{self, error::TrySendError},
+};
+use tracing::error;
+use uuid::Uuid;
+
+/// Number of events a subscriber may fall behind by before it misses some
+const EVENT_BUS_CAPACITY: usize = 1024;
+/// Number of events a lossless subscriber may fall behind by before it misses some
+const LOSSLESS_EVENT_BUS_CAPACITY: usize = 65_536;
+
+/// Something which happened to an account, which parts of the node other than the one
+/// it happened in may want to react to (for example to notify the account's owner)
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountEvent {
+    /// The account's balance changed after a packet was fulfilled
+    BalanceChanged { account_id: Uuid, balance: i64 },
+    /// A packet from one account to another was fulfilled, which made the incoming amount
+    /// final for the first one and credited the outgoing amount to the second one
+    PacketFulfilled {
+        from_account_id: Uuid,
+        to_account_id: Uuid,
+        incoming_amount: u64,
+        outgoing_amount: u64,
+    },
+    /// `amount` was taken out of the account's balance to be settled with the peer
+    SettlementReserved { account_id: Uuid, amount: u64 },
+    /// `amount` was put back into the account's balance because its settlement failed
+    SettlementRefunded { account_id: Uuid, amount: u64 },
+    /// The account's settlement engine accepted a settlement of `amount` to the peer
+    SettlementSent { account_id: Uuid, amount: u64 },
+    /// A settlement of `amount` from the peer was credited to the account
+    SettlementReceived { account_id: Uuid, amount: u64 },
+    /// The node stopped handling the account's packets for `duration_ms` milliseconds
+    AccountSuspended {
+        account_id: Uuid,
+        reason: String,
+        duration_ms: u64,
+    },
+}
+
+/// Fans the events of all the accounts out to any number of subscribers.
+///
+/// Publishing is cheap and never blocks, so services may publish from their packet
+/// handling paths. Events published while there are no subscribers are dropped.
+///
+/// The subscribers which fall behind by more than the bus' capacity miss events. The
+/// lossless ones have a much larger buffer, and the events they miss once it is full are
+/// logged and counted in the `event_bus.lossless.dropped` metric.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<AccountEvent>,
+    lossless_senders: Arc<Mutex<Vec<mpsc::Sender<AccountEvent>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus {
+            sender: broadcast::channel(EVENT_BUS_CAPACITY).0,
+            lossless_senders: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Sends the event to the current subscribers
+    pub fn publish(&self, event: AccountEvent) {
+        {
+            let mut lossless_senders = self.lossless_senders.lock().unwrap();
+            lossless_senders.retain_mut(|sender| match sender.try_send(event.clone()) {
+                Ok(()) => true,
+                // Publishing must not block, so the subscribers which are this far behind
+                // miss the event instead of slowing the packets down
+                Err(TrySendError::Full(event)) => {
+                    error!(
+                        "Lossless event subscriber is {} events behind, dropping {:?}",
+                        LOSSLESS_EVENT_BUS_CAPACITY, event
+                    );
+                    recorder().increment_counter(Key::from_name("event_bus.lossless.dropped"), 1);
+                    true
+                }
+                Err(TrySendError::Closed(_)) => false,
+            });
+        }
+        // Fails only if there are no subscribers
+        let _ = self.sender.send(event);
+    }
+
+    /// Whether anyone is listening, so that publishers can skip work to build events
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0 || !self.lossless_senders.lock().unwrap().is_empty()
+    }
+
+    /// Returns a receiver for all of the events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<AccountEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Returns a receiver for all of the events published from now on, which buffers up to
+    /// 65536 events it has not received yet. Meant for the subscribers which must see every
+    /// event (such as the accounting exporter). Once the buffer is full, the events it misses
+    /// are counted in the `event_bus.lossless.dropped` metric
+    pub fn subscribe_lossless(&self) -> mpsc::Receiver<AccountEvent> {
+        let (sender, receiver) = mpsc::channel(LOSSLESS_EVENT_BUS_CAPACITY);
+        self.lossless_senders.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lossless_subscribers_receive_every_event() {
+        let events = EventBus::new();
+        let mut receiver = events.subscribe();
+        let mut lossless_receiver = events.subscribe_lossless();
+        let account_id = Uuid::new_v4();
+        for balance in 0..(2 * EVENT_BUS_CAPACITY as i64) {
+            events.publish(AccountEvent::BalanceChanged {
+                account_id,
+                balance,
+            });
+        }
+
+        assert!(receiver.try_recv().is_err());
+        for balance in 0..(2 * EVENT_BUS_CAPACITY as i64) {
+            assert_eq!(
+                lossless_receiver.try_recv().unwrap(),
+                AccountEvent::BalanceChanged {
+                    account_id,
+                    balance
+                }
+            );
+        }
+
+        // The dropped lossless subscribers are forgotten on the next publish
+        drop(receiver);
+        drop(lossless_receiver);
+        events.publish(AccountEvent::BalanceChanged {
+            account_id,
+            balance: 0,
+        });
+        assert!(!events.has_subscribers());
+    }
+
+    #[test]
+    fn lossless_subscribers_drop_events_once_full() {
+        let events = EventBus::new();
+        let mut lossless_receiver = events.subscribe_lossless();
+        let account_id = Uuid::new_v4();
+        for balance in 0..(LOSSLESS_EVENT_BUS_CAPACITY as i64 + 1) {
+            events.publish(AccountEvent::BalanceChanged {
+                account_id,
+                balance,
+            });
+        }
+
+        for balance in 0..(LOSSLESS_EVENT_BUS_CAPACITY as i64) {
+            assert_eq!(
+                lossless_receiver.try_recv().unwrap(),
+                AccountEvent::BalanceChanged {
+                    account_id,
+                    balance
+                }
+            );
+        }
+        // The last event did not fit, but the subscriber still receives the next ones
+        assert!(lossless_receiver.try_recv().is_err());
+        events.publish(AccountEvent::BalanceChanged {
+            account_id,
+            balance: 0,
+        });
+        assert!(lossless_receiver.try_recv().is_ok());
+    }
+}
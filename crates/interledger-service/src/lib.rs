@@ -37,6 +37,10 @@ use std::{
 };
 use uuid::Uuid;
 
+mod account_id;
+pub use account_id::AccountId;
+mod events;
+pub use events::{AccountEvent, EventBus};
 mod username;
 pub use username::Username;
 #[cfg(feature = "trace")]
@@ -345,6 +349,21 @@ pub trait AddressStore {
     /// Gets the node's ILP Address *synchronously*
     /// (the value is stored in memory because it is read often by all services)
     fn get_ilp_address(&self) -> Address;
+
+    /// Saves the addresses assigned to the node by its other parents, when it is multihomed.
+    /// Packets addressed to them are handled like the ones addressed to the primary address.
+    /// Stores which do not support multihoming ignore them
+    async fn set_secondary_ilp_addresses(
+        &self,
+        _ilp_addresses: Vec<Address>,
+    ) -> Result<(), AddressStoreError> {
+        Ok(())
+    }
+
+    /// Gets the addresses assigned to the node by its other parents *synchronously*
+    fn get_secondary_ilp_addresses(&self) -> Vec<Address> {
+        Vec::new()
+    }
 }
 
 // Even though we wrap the types _a lot_ of times in multiple configurations
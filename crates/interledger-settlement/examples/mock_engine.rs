@@ -0,0 +1,526 @@
+//! # Mock Settlement Engine
+//!
+//! An in-memory settlement engine which settles instantly, for exercising the full
+//! settlement flow of a node locally without any ledger infrastructure. It exposes the
+//! API described in the [RFC](https://interledger.org/rfcs/0038-settlement-engines/)
+//! with the same scaffolding as the real engines.
+//!
+//! Outgoing settlements are "transferred" by sending a message to the peer's engine
+//! through the connector, and the peer's engine immediately reports them to its own
+//! connector as incoming settlements. Run one engine next to each of two peered nodes:
+//!
+//! ```bash
+//! cargo run -p interledger-settlement --example mock_engine -- \
+//!     --port 3000 --connector-url http://127.0.0.1:7771
+//! ```
+//!
+//! and point the accounts' `settlement_engine_url` at it. Failures can be injected with
+//! `--send-failure-rate`, `--receive-failure-rate` and `--notify-failure-rate`, and
+//! `GET /accounts/:id` shows how much the mock ledger has sent and received.
+use async_trait::async_trait;
+use bytes::Bytes;
+use clap::{value_t, App, Arg};
+use http::StatusCode;
+use interledger_errors::{ApiError, IdempotentStoreError};
+use interledger_settlement::core::{
+    amount::{convert_scale, scale_amount},
+    engines_api::create_settlement_engine_filter,
+    idempotency::{IdempotentData, IdempotentStore},
+    types::{ApiResponse, ApiResult, Quantity, SettlementEngine},
+};
+use parking_lot::RwLock;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tracing::{debug, error, info, warn};
+use url::Url;
+use uuid::Uuid;
+use warp::{Filter, Reply};
+
+/// The message exchanged by the engines to transfer an outgoing settlement
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SettlementMessage {
+    /// Used as the idempotency key of the incoming settlement reported to the connector
+    id: Uuid,
+    #[serde(flatten)]
+    quantity: Quantity,
+}
+
+/// The settlements of an account on the mock ledger, in the engine's asset scale
+#[derive(Debug, Clone, Default, Serialize)]
+struct MockAccount {
+    #[serde(serialize_with = "as_string")]
+    sent: u128,
+    #[serde(serialize_with = "as_string")]
+    received: u128,
+    /// Dust lost converting outgoing settlements to the engine's scale, expressed in
+    /// the connector's scale. It is added to the next outgoing settlement
+    #[serde(skip)]
+    leftovers: Option<(u128, u8)>,
+}
+
+fn as_string<S: serde::Serializer>(amount: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&amount.to_string())
+}
+
+/// Probabilities (between 0 and 1) with which the engine fails on purpose
+#[derive(Debug, Clone, Copy, Default)]
+struct FailureInjection {
+    /// Rejects outgoing settlements requested by the connector
+    send: f64,
+    /// Rejects the settlement messages of the peer's engine, which fails its settlement
+    receive: f64,
+    /// Accepts incoming settlements without reporting them to the connector, as if
+    /// the notification had been lost
+    notify: f64,
+}
+
+impl FailureInjection {
+    fn should_fail(rate: f64) -> bool {
+        rate > 0.0 && rand::thread_rng().gen_bool(rate.min(1.0))
+    }
+}
+
+#[derive(Clone)]
+struct MockEngine {
+    accounts: Arc<RwLock<HashMap<String, MockAccount>>>,
+    connector_url: Url,
+    asset_scale: u8,
+    failures: FailureInjection,
+    client: reqwest::Client,
+}
+
+impl MockEngine {
+    fn new(connector_url: Url, asset_scale: u8, failures: FailureInjection) -> Self {
+        MockEngine {
+            accounts: Arc::new(RwLock::new(HashMap::new())),
+            connector_url,
+            asset_scale,
+            failures,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    fn connector_endpoint(&self, account_id: &str, path: &str) -> Url {
+        let mut url = self.connector_url.clone();
+        url.path_segments_mut()
+            .expect("connector URL cannot be a base")
+            .pop_if_empty()
+            .extend(&["accounts", account_id, path]);
+        url
+    }
+
+    fn check_account(&self, account_id: &str) -> Result<(), ApiError> {
+        if self.accounts.read().contains_key(account_id) {
+            Ok(())
+        } else {
+            Err(ApiError::account_not_found().detail(format!(
+                "Account {} was not created in the engine",
+                account_id
+            )))
+        }
+    }
+
+    /// Converts the amount to the engine's scale, carrying over the dust left by the
+    /// account's previous settlement. Returns the converted amount and the new dust
+    fn to_engine_scale(
+        &self,
+        account_id: &str,
+        quantity: &Quantity,
+    ) -> Result<(u128, u128), ApiError> {
+        let mut amount: u128 = quantity.amount.parse().map_err(|_| {
+            ApiError::bad_request().detail(format!("Invalid amount: {}", quantity.amount))
+        })?;
+        let leftovers = self
+            .accounts
+            .read()
+            .get(account_id)
+            .and_then(|account| account.leftovers);
+        if let Some((leftovers, scale)) = leftovers {
+            amount = scale_amount(leftovers, scale, quantity.scale)
+                .ok()
+                .and_then(|leftovers| amount.checked_add(leftovers))
+                .unwrap_or(amount);
+        }
+        convert_scale(amount, quantity.scale, self.asset_scale)
+            .map_err(|err| ApiError::bad_request().detail(err.to_string()))
+    }
+
+    fn record<F: FnOnce(&mut MockAccount)>(&self, account_id: &str, update: F) {
+        if let Some(account) = self.accounts.write().get_mut(account_id) {
+            update(account);
+        }
+    }
+
+    async fn notify_connector(&self, account_id: &str, message: &SettlementMessage) {
+        if FailureInjection::should_fail(self.failures.notify) {
+            warn!(
+                "Injected failure: not notifying the connector of incoming settlement {} for account {}",
+                message.id, account_id
+            );
+            return;
+        }
+        let result = self
+            .client
+            .post(self.connector_endpoint(account_id, "settlements"))
+            .header("Idempotency-Key", message.id.to_string())
+            .json(&message.quantity)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = result {
+            error!(
+                "Error notifying the connector of incoming settlement {} for account {}: {}",
+                message.id, account_id, err
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl SettlementEngine for MockEngine {
+    async fn create_account(&self, account_id: String) -> ApiResult {
+        debug!("Creating account {}", account_id);
+        self.accounts.write().entry(account_id).or_default();
+        Ok(ApiResponse::Default)
+    }
+
+    async fn delete_account(&self, account_id: String) -> ApiResult {
+        debug!("Deleting account {}", account_id);
+        self.accounts.write().remove(&account_id);
+        Ok(ApiResponse::Default)
+    }
+
+    async fn send_money(&self, account_id: String, money: Quantity) -> ApiResult {
+        self.check_account(&account_id)?;
+        if FailureInjection::should_fail(self.failures.send) {
+            warn!(
+                "Injected failure: rejecting settlement to account {}",
+                account_id
+            );
+            return Err(ApiError::internal_server_error()
+                .detail("Injected failure: outgoing settlement rejected"));
+        }
+
+        let (amount, leftovers) = self.to_engine_scale(&account_id, &money)?;
+        let message = SettlementMessage {
+            id: Uuid::new_v4(),
+            quantity: Quantity::new(amount, self.asset_scale),
+        };
+        if amount > 0 {
+            let body = serde_json::to_vec(&message).expect("messages are serializable");
+            self.client
+                .post(self.connector_endpoint(&account_id, "messages"))
+                .body(body)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|err| {
+                    let err = ApiError::internal_server_error().detail(format!(
+                        "Error sending settlement to the peer's engine: {}",
+                        err
+                    ));
+                    error!("{}", err);
+                    err
+                })?;
+        }
+
+        info!(
+            "Settled {} (scale {}) with account {}",
+            amount, self.asset_scale, account_id
+        );
+        let scale = money.scale;
+        self.record(&account_id, move |account| {
+            account.sent += amount;
+            account.leftovers = Some((leftovers, scale));
+        });
+        Ok(ApiResponse::Default)
+    }
+
+    async fn receive_message(&self, account_id: String, message: Vec<u8>) -> ApiResult {
+        self.check_account(&account_id)?;
+        let message: SettlementMessage = serde_json::from_slice(&message).map_err(|err| {
+            ApiError::bad_request().detail(format!("Invalid settlement message: {}", err))
+        })?;
+        if FailureInjection::should_fail(self.failures.receive) {
+            warn!(
+                "Injected failure: rejecting settlement {} from account {}",
+                message.id, account_id
+            );
+            return Err(ApiError::internal_server_error()
+                .detail("Injected failure: incoming settlement rejected"));
+        }
+
+        let (amount, _) = self.to_engine_scale(&account_id, &message.quantity)?;
+        info!(
+            "Received settlement {} of {} (scale {}) from account {}",
+            message.id, amount, self.asset_scale, account_id
+        );
+        self.record(&account_id, move |account| account.received += amount);
+        self.notify_connector(&account_id, &message).await;
+        Ok(ApiResponse::Data(Bytes::from("SETTLED")))
+    }
+}
+
+/// In-memory store for the idempotent responses, which live as long as the engine
+#[derive(Clone, Default)]
+struct InMemoryIdempotentStore {
+    cache: Arc<RwLock<HashMap<String, IdempotentData>>>,
+}
+
+#[async_trait]
+impl IdempotentStore for InMemoryIdempotentStore {
+    async fn load_idempotent_data(
+        &self,
+        idempotency_key: String,
+    ) -> Result<Option<IdempotentData>, IdempotentStoreError> {
+        Ok(self.cache.read().get(&idempotency_key).cloned())
+    }
+
+    async fn save_idempotent_data(
+        &self,
+        idempotency_key: String,
+        input_hash: [u8; 32],
+        status_code: StatusCode,
+        data: Bytes,
+    ) -> Result<(), IdempotentStoreError> {
+        self.cache.write().insert(
+            idempotency_key,
+            IdempotentData::new(status_code, data, input_hash),
+        );
+        Ok(())
+    }
+
+    async fn get_idempotency_keys(&self) -> Result<Vec<String>, IdempotentStoreError> {
+        Ok(self.cache.read().keys().cloned().collect())
+    }
+
+    async fn delete_idempotent_data(
+        &self,
+        idempotency_keys: Vec<String>,
+    ) -> Result<(), IdempotentStoreError> {
+        let mut cache = self.cache.write();
+        for idempotency_key in idempotency_keys {
+            cache.remove(&idempotency_key);
+        }
+        Ok(())
+    }
+
+    async fn remove_expired_idempotent_data(&self) -> Result<usize, IdempotentStoreError> {
+        Ok(0)
+    }
+}
+
+fn mock_engine_api(
+    engine: MockEngine,
+) -> impl warp::Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let accounts = engine.accounts.clone();
+    // GET /accounts/:id
+    let get_account = warp::get()
+        .and(warp::path("accounts"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then(move |account_id: String| {
+            let account = accounts.read().get(&account_id).cloned();
+            // Not rejecting, since the engine's filter would recover from the rejection
+            async move {
+                Ok::<_, warp::Rejection>(match account {
+                    Some(account) => warp::reply::json(&account).into_response(),
+                    None => ApiError::account_not_found().into_response(),
+                })
+            }
+        });
+
+    get_account.or(create_settlement_engine_filter(
+        engine,
+        InMemoryIdempotentStore::default(),
+    ))
+}
+
+fn rate_arg(name: &'static str, help: &'static str) -> Arg<'static, 'static> {
+    Arg::with_name(name)
+        .long(name)
+        .takes_value(true)
+        .default_value("0")
+        .help(help)
+}
+
+#[tokio::main(basic_scheduler)]
+async fn main() {
+    tracing_subscriber::fmt::init();
+
+    let matches = App::new("mock_engine")
+        .about("In-memory settlement engine which settles instantly")
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .takes_value(true)
+                .default_value("3000")
+                .help("Port to listen on for the connector's requests"),
+        )
+        .arg(
+            Arg::with_name("connector-url")
+                .long("connector-url")
+                .takes_value(true)
+                .default_value("http://127.0.0.1:7771")
+                .help("URL of the connector's settlement API"),
+        )
+        .arg(
+            Arg::with_name("asset-scale")
+                .long("asset-scale")
+                .takes_value(true)
+                .default_value("9")
+                .help("Asset scale of the amounts on the mock ledger"),
+        )
+        .arg(rate_arg(
+            "send-failure-rate",
+            "Probability of rejecting an outgoing settlement",
+        ))
+        .arg(rate_arg(
+            "receive-failure-rate",
+            "Probability of rejecting an incoming settlement",
+        ))
+        .arg(rate_arg(
+            "notify-failure-rate",
+            "Probability of not reporting an incoming settlement to the connector",
+        ))
+        .get_matches();
+
+    let port = value_t!(matches, "port", u16).unwrap_or_else(|err| err.exit());
+    let connector_url = value_t!(matches, "connector-url", Url).unwrap_or_else(|err| err.exit());
+    let asset_scale = value_t!(matches, "asset-scale", u8).unwrap_or_else(|err| err.exit());
+    let failures = FailureInjection {
+        send: value_t!(matches, "send-failure-rate", f64).unwrap_or_else(|err| err.exit()),
+        receive: value_t!(matches, "receive-failure-rate", f64).unwrap_or_else(|err| err.exit()),
+        notify: value_t!(matches, "notify-failure-rate", f64).unwrap_or_else(|err| err.exit()),
+    };
+
+    let engine = MockEngine::new(connector_url, asset_scale, failures);
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    info!(
+        "Mock settlement engine listening on {} with failure injection {:?}",
+        addr, failures
+    );
+    warp::serve(mock_engine_api(engine)).run(addr).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+    use serde_json::json;
+
+    fn engine(failures: FailureInjection) -> MockEngine {
+        MockEngine::new(Url::parse(&mockito::server_url()).unwrap(), 9, failures)
+    }
+
+    #[tokio::test]
+    async fn settles_through_the_peer_engine() {
+        let sender = engine(FailureInjection::default());
+        let receiver = engine(FailureInjection::default());
+        sender.create_account("alice".to_owned()).await.unwrap();
+        receiver.create_account("bob".to_owned()).await.unwrap();
+
+        // scale 6 is converted to the engine's scale 9
+        let message = mockito::mock("POST", "/accounts/alice/messages")
+            .match_body(Matcher::PartialJson(
+                json!({ "amount": "100000", "scale": 9 }),
+            ))
+            .with_status(201)
+            .create();
+        sender
+            .send_money("alice".to_owned(), Quantity::new(100, 6))
+            .await
+            .unwrap();
+        message.assert();
+
+        let notification = mockito::mock("POST", "/accounts/bob/settlements")
+            .match_body(Matcher::Json(json!({ "amount": "100000", "scale": 9 })))
+            .match_header("idempotency-key", Matcher::Any)
+            .with_status(201)
+            .create();
+        let settlement = SettlementMessage {
+            id: Uuid::new_v4(),
+            quantity: Quantity::new(100_000, 9),
+        };
+        let response = receiver
+            .receive_message("bob".to_owned(), serde_json::to_vec(&settlement).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response, ApiResponse::Data(Bytes::from("SETTLED")));
+        notification.assert();
+
+        assert_eq!(sender.accounts.read()["alice"].sent, 100_000);
+        assert_eq!(receiver.accounts.read()["bob"].received, 100_000);
+    }
+
+    #[tokio::test]
+    async fn carries_over_dust() {
+        let engine = MockEngine::new(
+            Url::parse(&mockito::server_url()).unwrap(),
+            2,
+            FailureInjection::default(),
+        );
+        engine.create_account("carol".to_owned()).await.unwrap();
+        let _message = mockito::mock("POST", "/accounts/carol/messages")
+            .with_status(201)
+            .create();
+
+        // 0.015 is settled as 0.01, then 0.015 + 0.005 as 0.02
+        engine
+            .send_money("carol".to_owned(), Quantity::new(15, 3))
+            .await
+            .unwrap();
+        engine
+            .send_money("carol".to_owned(), Quantity::new(15, 3))
+            .await
+            .unwrap();
+        assert_eq!(engine.accounts.read()["carol"].sent, 3);
+    }
+
+    #[tokio::test]
+    async fn injects_failures() {
+        let engine = engine(FailureInjection {
+            send: 1.0,
+            receive: 1.0,
+            notify: 0.0,
+        });
+        engine.create_account("dave".to_owned()).await.unwrap();
+
+        let err = engine
+            .send_money("dave".to_owned(), Quantity::new(100, 6))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        let settlement = SettlementMessage {
+            id: Uuid::new_v4(),
+            quantity: Quantity::new(100, 9),
+        };
+        let err = engine
+            .receive_message("dave".to_owned(), serde_json::to_vec(&settlement).unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(engine.accounts.read()["dave"].sent, 0);
+        assert_eq!(engine.accounts.read()["dave"].received, 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_accounts() {
+        let engine = engine(FailureInjection::default());
+        let err = engine
+            .send_money("eve".to_owned(), Quantity::new(100, 6))
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::NOT_FOUND);
+
+        let api = mock_engine_api(engine);
+        let response = warp::test::request()
+            .method("GET")
+            .path("/accounts/eve")
+            .reply(&api)
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -1,13 +1,14 @@
 use crate::core::{
     types::{SettlementAccount, SE_ILP_ADDRESS},
-    SettlementClient,
+    SettlementClient, SettlementScheduler,
 };
 use async_trait::async_trait;
 use futures::TryFutureExt;
 use interledger_packet::{ErrorCode, FulfillBuilder, RejectBuilder};
 use interledger_service::{Account, IlpResult, IncomingRequest, IncomingService};
 use std::marker::PhantomData;
-use tracing::error;
+use tracing::{debug, error};
+use uuid::Uuid;
 
 const PEER_FULFILLMENT: [u8; 32] = [0; 32];
 
@@ -21,6 +22,8 @@ pub struct SettlementMessageService<I, A> {
     /// HTTP client used to notify the engine corresponding to the account about
     /// an incoming message from a peer's engine
     client: SettlementClient,
+    /// Outbox retrying the messages which could not be delivered to the engine
+    outbox: Option<SettlementScheduler>,
     account_type: PhantomData<A>,
 }
 
@@ -33,9 +36,33 @@ where
         SettlementMessageService {
             next,
             client: SettlementClient::default(),
+            outbox: None,
             account_type: PhantomData,
         }
     }
+
+    /// Queues the messages which could not be delivered to the engine in the scheduler,
+    /// which persists them and retries them in the background until the engine accepts them
+    pub fn with_outbox(mut self, scheduler: SettlementScheduler) -> Self {
+        self.outbox = Some(scheduler);
+        self
+    }
+}
+
+/// Hands the message to the outbox, if there is one. The peer's engine is still
+/// rejected, since it expects the engine's response to its message
+fn queue_for_redelivery(outbox: Option<&SettlementScheduler>, account_id: Uuid, message: &[u8]) {
+    if let Some(outbox) = outbox {
+        if outbox
+            .schedule_message(account_id, message.to_vec())
+            .is_ok()
+        {
+            debug!(
+                "Queued message for the settlement engine of account {} for redelivery",
+                account_id
+            );
+        }
+    }
 }
 
 #[async_trait]
@@ -49,6 +76,7 @@ where
         // of the settlement engine being used for this account
         if let Some(settlement_engine_details) = request.from.settlement_engine_details() {
             if request.prepare.destination() == SE_ILP_ADDRESS.clone() {
+                let outbox = self.outbox.as_ref();
                 // Send a messsage to the engine (with retries)
                 let response = self
                     .client
@@ -57,8 +85,9 @@ where
                         settlement_engine_details.url,
                         request.prepare.data().to_vec(),
                     )
-                    .map_err(move |error| {
+                    .map_err(|error| {
                         error!("Error sending message to settlement engine: {:?}", error);
+                        queue_for_redelivery(outbox, request.from.id(), request.prepare.data());
                         RejectBuilder {
                             code: ErrorCode::T00_INTERNAL_ERROR,
                             message: b"Error sending message to settlement engine",
@@ -101,6 +130,7 @@ where
                     let code = if status.is_client_error() {
                         ErrorCode::F00_BAD_REQUEST
                     } else {
+                        queue_for_redelivery(outbox, request.from.id(), request.prepare.data());
                         ErrorCode::T00_INTERNAL_ERROR
                     };
 
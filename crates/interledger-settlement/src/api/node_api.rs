@@ -1,26 +1,29 @@
 use crate::core::{
+    amount::split_credit,
     get_hash_of,
     idempotency::*,
     scale_with_precision_loss,
     types::{
-        ApiResponse, ApiResult, LeftoversStore, Quantity, SettlementAccount, SettlementStore,
-        CONVERSION_ERROR_TYPE, SE_ILP_ADDRESS,
+        ApiResponse, ApiResult, LeftoversStore, Quantity, SettlementAccount, SettlementDirection,
+        SettlementHistoryStore, SettlementRecord, SettlementStore, CONVERSION_ERROR_TYPE,
+        SE_ILP_ADDRESS,
     },
 };
 use bytes::Bytes;
-use futures::future::Either;
 use futures::TryFutureExt;
 use hyper::{Response, StatusCode};
 use interledger_errors::*;
 use interledger_packet::PrepareBuilder;
-use interledger_service::{Account, AccountStore, OutgoingRequest, OutgoingService};
+use interledger_service::{
+    Account, AccountEvent, AccountId, AccountStore, EventBus, OutgoingRequest, OutgoingService,
+};
 use num_bigint::BigUint;
-use num_traits::cast::ToPrimitive;
+use num_traits::Zero;
 use std::{
     str::{self, FromStr},
-    time::{Duration, SystemTime},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tracing::error;
+use tracing::{error, warn};
 use uuid::Uuid;
 use warp::{self, reject::Rejection, Filter};
 
@@ -37,10 +40,12 @@ async fn receive_settlement<S, A>(
     idempotency_key: Option<String>,
     quantity: Quantity,
     store: S,
+    events: Option<EventBus>,
 ) -> Result<impl warp::Reply, Rejection>
 where
     S: LeftoversStore<AccountId = Uuid, AssetType = BigUint>
         + SettlementStore<Account = A>
+        + SettlementHistoryStore
         + IdempotentStore
         + AccountStore<Account = A>
         + Clone
@@ -56,7 +61,13 @@ where
     let store_clone = store.clone();
     let (status_code, message) = make_idempotent_call(
         store,
-        do_receive_settlement(store_clone, account_id, quantity, idempotency_key_clone),
+        do_receive_settlement(
+            store_clone,
+            account_id,
+            quantity,
+            idempotency_key_clone,
+            events,
+        ),
         input_hash,
         idempotency_key,
         StatusCode::CREATED,
@@ -81,6 +92,7 @@ async fn send_message<S, A, O>(
 where
     S: LeftoversStore<AccountId = Uuid, AssetType = BigUint>
         + SettlementStore<Account = A>
+        + SettlementHistoryStore
         + IdempotentStore
         + AccountStore<Account = A>
         + Clone
@@ -114,13 +126,17 @@ where
 /// 1. receives messages about incoming settlements from the engine
 /// 1. sends messages from the connector's engine to the peer's
 ///    message service which are sent to the peer's engine
+///
+/// The credited incoming settlements are published on the `events` bus, if one is given.
 pub fn create_settlements_filter<S, O, A>(
     store: S,
     outgoing_handler: O,
+    events: Option<EventBus>,
 ) -> warp::filters::BoxedFilter<(impl warp::Reply,)>
 where
     S: LeftoversStore<AccountId = Uuid, AssetType = BigUint>
         + SettlementStore<Account = A>
+        + SettlementHistoryStore
         + IdempotentStore
         + AccountStore<Account = A>
         + Clone
@@ -143,6 +159,7 @@ where
         .and(idempotency)
         .and(warp::body::json())
         .and(with_store.clone())
+        .and(warp::any().map(move || events.clone()))
         .and_then(receive_settlement);
 
     // POST /accounts/:account_id/messages (optional idempotency-key header)
@@ -173,10 +190,12 @@ async fn do_receive_settlement<S, A>(
     account_id: String,
     body: Quantity,
     idempotency_key: Option<String>,
+    events: Option<EventBus>,
 ) -> ApiResult
 where
     S: LeftoversStore<AccountId = Uuid, AssetType = BigUint>
         + SettlementStore<Account = A>
+        + SettlementHistoryStore
         + IdempotentStore
         + AccountStore<Account = A>
         + Clone
@@ -190,11 +209,14 @@ where
     let engine_scale = body.scale;
 
     // Convert to the desired data types
-    let account_id = Uuid::from_str(&account_id).map_err(move |_| {
-        let err = ApiError::invalid_account_id(Some(&account_id));
-        error!("{}", err);
-        err
-    })?;
+    // Engines configured before the switch to UUIDs may still use numeric ids
+    let account_id: Uuid = AccountId::from_str(&account_id)
+        .map_err(move |_| {
+            let err = ApiError::invalid_account_id(Some(&account_id));
+            error!("{}", err);
+            err
+        })?
+        .into();
 
     let engine_amount = BigUint::from_str(&engine_amount).map_err(|_| {
         let error_msg = format!("Could not convert amount: {:?}", engine_amount);
@@ -251,28 +273,48 @@ where
         .await?;
 
     // add the leftovers to the scaled engine amount
-    let total_amount = scaled_engine_amount.clone() + scaled_leftover_amount;
-    let engine_amount_u64 = total_amount.to_u64().unwrap_or(std::u64::MAX);
-
-    let ret = futures::future::join_all(vec![
-        // update the account's balance in the store
-        Either::Left(store.update_balance_for_incoming_settlement(
+    let total_amount = scaled_engine_amount + scaled_leftover_amount;
+    // The balances are i64, so high-scale amounts may be too large to be credited at once.
+    // Whatever does not fit is kept as leftovers and credited with the next settlements.
+    let (credited_amount, mut uncredited_amount) = split_credit(&total_amount);
+
+    // update the account's balance in the store, which credits only as much as
+    // the balance can hold
+    let balance_update = store
+        .update_balance_for_incoming_settlement(
             account_id,
-            engine_amount_u64,
-            idempotency_key,
-        )),
+            credited_amount,
+            idempotency_key.clone(),
+        )
+        .await;
+    let credited_amount = match &balance_update {
+        Ok(overflow) => {
+            uncredited_amount += BigUint::from(*overflow);
+            credited_amount - overflow
+        }
+        Err(_) => credited_amount,
+    };
+    if !uncredited_amount.is_zero() {
+        warn!(
+            "Settlement of {} for account {} is too large to be credited at once, crediting {} and keeping the rest as leftovers",
+            total_amount, account_id, credited_amount
+        );
+    }
+
+    let mut futures = vec![
         // save any precision loss that occurred during the
         // scaling of the engine's amount to the account's scale
-        Either::Right(
-            store
-                .save_uncredited_settlement_amount(account_id, (precision_loss, engine_scale))
-                .map_err(SettlementStoreError::from),
-        ),
-    ])
-    .await;
+        store.save_uncredited_settlement_amount(account_id, (precision_loss, engine_scale)),
+    ];
+    if balance_update.is_ok() && !uncredited_amount.is_zero() {
+        futures.push(
+            store.save_uncredited_settlement_amount(account_id, (uncredited_amount, asset_scale)),
+        );
+    }
+    let ret = futures::future::join_all(futures).await;
 
     // if any of the futures errored, then we should propagate that
-    if ret.iter().any(|r| r.is_err()) {
+    if balance_update.is_err() || ret.iter().any(|r| r.is_err()) {
         let error_msg = format!(
             "Error updating the balance and leftovers of account: {}",
             account_id
@@ -286,6 +328,29 @@ where
         return Err(ApiError::from_api_error_type(&error_type).detail(error_msg));
     }
 
+    // Failing to log the settlement must not fail it, since the balance was already credited
+    let record = SettlementRecord {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64,
+        direction: SettlementDirection::Incoming,
+        amount: credited_amount,
+        id: idempotency_key,
+    };
+    if let Err(err) = store.record_settlement(account_id, record).await {
+        error!(
+            "Error recording incoming settlement for account {}: {}",
+            account_id, err
+        );
+    }
+    if let Some(events) = events {
+        events.publish(AccountEvent::SettlementReceived {
+            account_id,
+            amount: credited_amount,
+        });
+    }
+
     Ok(ApiResponse::Default)
 }
 
@@ -310,11 +375,14 @@ where
     O: OutgoingService<A> + Clone + Send + Sync,
     A: SettlementAccount + Account + Send + Sync,
 {
-    let account_id = Uuid::from_str(&account_id).map_err(move |_| {
-        let err = ApiError::invalid_account_id(Some(&account_id));
-        error!("{}", err);
-        err
-    })?;
+    // Engines configured before the switch to UUIDs may still use numeric ids
+    let account_id: Uuid = AccountId::from_str(&account_id)
+        .map_err(move |_| {
+            let err = ApiError::invalid_account_id(Some(&account_id));
+            error!("{}", err);
+            err
+        })?
+        .into();
     let accounts = store
         .get_accounts(vec![account_id])
         .map_err(move |_| {
@@ -570,6 +638,25 @@ mod tests {
             );
         }
 
+        #[tokio::test]
+        // Amounts which do not fit in the i64 balances are credited in parts
+        async fn settlement_larger_than_the_balance() {
+            let id = TEST_ACCOUNT_0.clone().id.to_string();
+            let store = test_store(false, true);
+            let api = test_api(store.clone(), false);
+
+            let response = settlement_call(&api, &id, u64::MAX, 9, None).await;
+            assert_eq!(response.body(), &Bytes::from("RECEIVED"));
+            assert_eq!(store.get_balance(TEST_ACCOUNT_0.id), i64::MAX);
+            assert_eq!(
+                store
+                    .get_uncredited_settlement_amount(TEST_ACCOUNT_0.id)
+                    .await
+                    .unwrap(),
+                (BigUint::from(u64::MAX - i64::MAX as u64), 9)
+            );
+        }
+
         #[tokio::test]
         async fn account_has_no_engine_configured() {
             let id = TEST_ACCOUNT_0.clone().id.to_string();
@@ -632,6 +719,36 @@ mod tests {
             );
         }
 
+        #[tokio::test]
+        async fn legacy_numeric_account_id() {
+            // the test account's id is the nil UUID, which legacy id 0 maps to
+            let store = test_store(false, true);
+            let api = test_api(store.clone(), false);
+
+            let response = settlement_call(&api, "0", 200, OUR_SCALE, None).await;
+            assert_eq!(response.body(), &Bytes::from("RECEIVED"));
+            assert_eq!(response.status(), StatusCode::CREATED);
+            assert_eq!(store.get_balance(TEST_ACCOUNT_0.id), 2);
+        }
+
+        #[tokio::test]
+        async fn records_incoming_settlements() {
+            let id = TEST_ACCOUNT_0.clone().id.to_string();
+            let store = test_store(false, true);
+            let api = test_api(store.clone(), false);
+
+            let response = settlement_call(&api, &id, 200, OUR_SCALE, Some(IDEMPOTENCY)).await;
+            assert_eq!(response.status(), StatusCode::CREATED);
+            // the idempotent retry is not recorded twice
+            settlement_call(&api, &id, 200, OUR_SCALE, Some(IDEMPOTENCY)).await;
+
+            let history = store.settlement_history.read();
+            assert_eq!(history.len(), 1);
+            assert_eq!(history[0].direction, SettlementDirection::Incoming);
+            assert_eq!(history[0].amount, 2);
+            assert_eq!(history[0].id.as_deref(), Some(IDEMPOTENCY));
+        }
+
         #[tokio::test]
         async fn account_not_in_store() {
             let id = TEST_ACCOUNT_0.clone().id.to_string();
@@ -4,7 +4,7 @@ use crate::core::{
     scale_with_precision_loss,
     types::{
         Convert, ConvertDetails, LeftoversStore, SettlementAccount, SettlementEngineDetails,
-        SettlementStore,
+        SettlementHistoryStore, SettlementRecord, SettlementStore,
     },
 };
 use bytes::Bytes;
@@ -80,6 +80,7 @@ pub struct TestStore {
     pub cache: Arc<RwLock<HashMap<String, IdempotentData>>>,
     pub cache_hits: Arc<RwLock<u64>>,
     pub uncredited_settlement_amount: Arc<RwLock<HashMap<Uuid, (BigUint, u8)>>>,
+    pub settlement_history: Arc<RwLock<Vec<SettlementRecord>>>,
 }
 
 #[async_trait]
@@ -91,7 +92,7 @@ impl SettlementStore for TestStore {
         account_id: Uuid,
         amount: u64,
         _idempotency_key: Option<String>,
-    ) -> Result<(), SettlementStoreError> {
+    ) -> Result<u64, SettlementStoreError> {
         let mut accounts = self.accounts.write();
         for mut a in &mut *accounts {
             if a.id() == account_id {
@@ -101,7 +102,7 @@ impl SettlementStore for TestStore {
         if self.should_fail {
             Err(SettlementStoreError::BalanceUpdateFailure)
         } else {
-            Ok(())
+            Ok(0)
         }
     }
 
@@ -148,6 +149,25 @@ impl IdempotentStore for TestStore {
         );
         Ok(())
     }
+
+    async fn get_idempotency_keys(&self) -> Result<Vec<String>, IdempotentStoreError> {
+        Ok(self.cache.read().keys().cloned().collect())
+    }
+
+    async fn delete_idempotent_data(
+        &self,
+        idempotency_keys: Vec<String>,
+    ) -> Result<(), IdempotentStoreError> {
+        let mut cache = self.cache.write();
+        for idempotency_key in idempotency_keys {
+            cache.remove(&idempotency_key);
+        }
+        Ok(())
+    }
+
+    async fn remove_expired_idempotent_data(&self) -> Result<usize, IdempotentStoreError> {
+        Ok(0)
+    }
 }
 
 #[async_trait]
@@ -189,6 +209,27 @@ impl AccountStore for TestStore {
     }
 }
 
+#[async_trait]
+impl SettlementHistoryStore for TestStore {
+    async fn record_settlement(
+        &self,
+        _account_id: Uuid,
+        record: SettlementRecord,
+    ) -> Result<(), SettlementHistoryStoreError> {
+        self.settlement_history.write().push(record);
+        Ok(())
+    }
+
+    async fn get_settlement_history(
+        &self,
+        _account_id: Uuid,
+        _from: u64,
+        _to: u64,
+    ) -> Result<Vec<SettlementRecord>, SettlementHistoryStoreError> {
+        Ok(self.settlement_history.read().clone())
+    }
+}
+
 #[async_trait]
 impl LeftoversStore for TestStore {
     type AccountId = Uuid;
@@ -289,6 +330,7 @@ impl TestStore {
             cache: Arc::new(RwLock::new(HashMap::new())),
             cache_hits: Arc::new(RwLock::new(0)),
             uncredited_settlement_amount: Arc::new(RwLock::new(HashMap::new())),
+            settlement_history: Arc::new(RwLock::new(Vec::new())),
         }
     }
 
@@ -366,5 +408,5 @@ pub fn test_api(
             .build())
         }
     });
-    create_settlements_filter(test_store, outgoing)
+    create_settlements_filter(test_store, outgoing, None)
 }
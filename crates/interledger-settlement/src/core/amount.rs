@@ -0,0 +1,187 @@
+//! Amounts are u64 in ILP packets, but the settlements of high-scale assets (e.g. tokens
+//! with 18 decimals) may add up to more than that. These helpers keep such amounts in 128
+//! bits (or as a `BigUint`) while they are aggregated and scaled, and only narrow them
+//! once they are applied to a balance, failing explicitly instead of wrapping or saturating.
+//!
+//! Converting an amount to a smaller asset scale loses its least significant digits, so
+//! `convert_scale` returns them alongside the converted amount for the callers to keep
+//! track of, rather than silently dropping them.
+
+use super::types::{ConversionError, Convert, ConvertDetails};
+use num_bigint::BigUint;
+use num_traits::{ToPrimitive, Zero};
+use std::convert::TryFrom;
+use std::ops::Sub;
+
+/// Largest amount which may be credited to a balance at once, since the stores keep
+/// the balances as i64
+pub const MAX_CREDIT: u64 = i64::MAX as u64;
+
+/// Converts the amount from one asset scale to another, returning the converted amount along
+/// with the remainder (or "dust") which was lost by scaling down, expressed in `from_scale`.
+/// The remainder is always zero when scaling up, which fails instead if the amount overflows.
+pub fn convert_scale<T>(amount: T, from_scale: u8, to_scale: u8) -> Result<(T, T), ConversionError>
+where
+    T: Convert<Item = T> + Clone + Sub<Output = T> + Zero,
+{
+    let converted = amount.normalize_scale(ConvertDetails {
+        from: from_scale,
+        to: to_scale,
+    })?;
+    if to_scale >= from_scale {
+        return Ok((converted, T::zero()));
+    }
+    // Scaling the truncated amount back up cannot overflow since it is at most the amount
+    let truncated = converted.normalize_scale(ConvertDetails {
+        from: to_scale,
+        to: from_scale,
+    })?;
+    Ok((converted, amount - truncated))
+}
+
+/// Converts the amount from one asset scale to another in 128 bits, returning an error
+/// if it does not fit (see `Convert`)
+pub fn scale_amount(amount: u128, from: u8, to: u8) -> Result<u128, ConversionError> {
+    amount.normalize_scale(ConvertDetails { from, to })
+}
+
+/// Narrows the amount to the u64 used on the wire, returning an error if it does not fit
+pub fn to_wire_amount(amount: u128) -> Result<u64, ConversionError> {
+    u64::try_from(amount).map_err(|_| ConversionError)
+}
+
+/// Splits the amount into the part which can be credited to a balance at once
+/// (at most `MAX_CREDIT`) and the remainder, which should be credited later
+pub fn split_credit(amount: &BigUint) -> (u64, BigUint) {
+    match amount.to_u64() {
+        Some(amount) if amount <= MAX_CREDIT => (amount, Zero::zero()),
+        _ => (MAX_CREDIT, amount - BigUint::from(MAX_CREDIT)),
+    }
+}
+
+/// Adds the amount to the balance, returning an error if the result does not fit in an i64
+pub fn checked_credit(balance: i64, amount: u64) -> Result<i64, ConversionError> {
+    narrow_balance(i128::from(balance) + i128::from(amount))
+}
+
+/// Subtracts the amount from the balance, returning an error if the result does not fit in an i64
+pub fn checked_debit(balance: i64, amount: u64) -> Result<i64, ConversionError> {
+    narrow_balance(i128::from(balance) - i128::from(amount))
+}
+
+/// Credits an incoming settlement to the balance and/or prepaid amount, depending on
+/// whether the account currently owes money or not.
+///
+/// Only as much of the amount is credited as keeps the sum of the balance and the
+/// prepaid amount within an i64. Returns the new balance and prepaid amount, along
+/// with the part of the amount which was not credited.
+pub fn credit_settlement(balance: i64, prepaid_amount: i64, amount: u64) -> (i64, i64, u64) {
+    let (balance, prepaid_amount) = (i128::from(balance), i128::from(prepaid_amount));
+    let room = (i128::from(i64::MAX) - (balance + prepaid_amount)).max(0);
+    let credit = i128::from(amount).min(room);
+    let (new_balance, new_prepaid_amount) = if balance >= 0 {
+        (balance, prepaid_amount + credit)
+    } else if -balance >= credit {
+        (balance + credit, prepaid_amount)
+    } else {
+        (0, prepaid_amount + credit + balance)
+    };
+    // Both fit, since their sum is at most i64::MAX and neither of them grew negative
+    (
+        new_balance as i64,
+        new_prepaid_amount as i64,
+        amount - credit as u64,
+    )
+}
+
+/// Narrows a balance computed in 128 bits to the i64 the stores keep
+pub fn narrow_balance(balance: i128) -> Result<i64, ConversionError> {
+    i64::try_from(balance).map_err(|_| ConversionError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn converts_scales_with_the_remainder() {
+        assert_eq!(convert_scale(1999u64, 9, 6), Ok((1, 999)));
+        assert_eq!(convert_scale(1000u64, 9, 6), Ok((1, 0)));
+        assert_eq!(convert_scale(999u64, 9, 6), Ok((0, 999)));
+        assert_eq!(convert_scale(12u64, 6, 9), Ok((12_000, 0)));
+        assert_eq!(convert_scale(u64::MAX, 0, 255), Err(ConversionError));
+        assert_eq!(convert_scale(u64::MAX, 255, 0), Ok((0, u64::MAX)));
+        assert_eq!(
+            convert_scale(1_000_000_000_000_000_000_123u128, 18, 9),
+            Ok((1_000_000_000_000, 123))
+        );
+        assert_eq!(
+            convert_scale(BigUint::from(8053u32), 12, 9),
+            Ok((BigUint::from(8u32), BigUint::from(53u32)))
+        );
+    }
+
+    #[test]
+    fn scales_high_scale_amounts() {
+        // 1000 units of an asset with 18 decimals do not fit in a u64
+        let amount = scale_amount(1000, 0, 18).unwrap();
+        assert_eq!(amount, 1_000_000_000_000_000_000_000);
+        assert!(to_wire_amount(amount).is_err());
+        assert_eq!(
+            to_wire_amount(scale_amount(amount, 18, 9).unwrap()),
+            Ok(1_000_000_000_000)
+        );
+
+        assert!(scale_amount(u128::MAX, 0, 1).is_err());
+        assert!(scale_amount(1, 0, 39).is_err());
+        assert_eq!(scale_amount(0, 0, 255), Ok(0));
+        assert_eq!(scale_amount(u128::MAX, 255, 0), Ok(0));
+    }
+
+    #[test]
+    fn splits_credits_which_do_not_fit_in_a_balance() {
+        assert_eq!(
+            split_credit(&BigUint::from(1000u32)),
+            (1000, BigUint::zero())
+        );
+        assert_eq!(
+            split_credit(&BigUint::from(MAX_CREDIT)),
+            (MAX_CREDIT, BigUint::zero())
+        );
+        let amount = BigUint::from_str("1000000000000000000000").unwrap();
+        let (credit, remainder) = split_credit(&amount);
+        assert_eq!(credit, MAX_CREDIT);
+        assert_eq!(BigUint::from(credit) + remainder, amount);
+    }
+
+    #[test]
+    fn checks_balance_overflows() {
+        assert_eq!(checked_credit(-10, 20), Ok(10));
+        assert_eq!(checked_credit(i64::MIN, u64::MAX), Ok(i64::MAX));
+        assert!(checked_credit(1, MAX_CREDIT).is_err());
+        assert_eq!(checked_debit(10, 20), Ok(-10));
+        assert!(checked_debit(-1, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn credits_settlements_up_to_the_largest_balance() {
+        // the prepaid amount is credited if the account owes nothing
+        assert_eq!(credit_settlement(10, 5, 100), (10, 105, 0));
+        // the debt is paid off first
+        assert_eq!(credit_settlement(-100, 5, 60), (-40, 5, 0));
+        assert_eq!(credit_settlement(-100, 5, 160), (0, 65, 0));
+
+        let max = i64::MAX as u64;
+        assert_eq!(
+            credit_settlement(100, 0, u64::MAX),
+            (100, i64::MAX - 100, u64::MAX - (max - 100))
+        );
+        assert_eq!(
+            credit_settlement(-100, 0, u64::MAX),
+            (0, i64::MAX, u64::MAX - max - 100)
+        );
+        assert_eq!(credit_settlement(i64::MAX, 0, 1), (i64::MAX, 0, 1));
+        assert_eq!(credit_settlement(i64::MIN, 0, u64::MAX), (0, i64::MAX, 0));
+    }
+}
@@ -1,5 +1,5 @@
 use crate::core::{
-    idempotency::{IdempotentData, IdempotentStore},
+    idempotency::{IdempotentData, IdempotentStore, DEFAULT_IDEMPOTENCY_KEY_TTL},
     scale_with_precision_loss,
     types::{Convert, ConvertDetails, LeftoversStore},
 };
@@ -14,6 +14,7 @@ use redis_crate::{
 };
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use tracing::{debug, error, trace};
 
@@ -25,20 +26,42 @@ mod test_helpers;
 /// Domain separator for leftover amounts
 static UNCREDITED_AMOUNT_KEY: &str = "uncredited_engine_settlement_amount";
 
+/// Sorted set of the saved idempotency keys, scored by the time they expire at.
+/// The responses themselves expire natively, but the set has to be cleaned up
+/// by `remove_expired_idempotent_data`
+static IDEMPOTENCY_KEYS_KEY: &str = "idempotency_keys";
+
 /// Helper function to get a redis key
 fn uncredited_amount_key(account_id: &str) -> String {
     format!("{}:{}", UNCREDITED_AMOUNT_KEY, account_id)
 }
 
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 /// Builder object to create a Redis connection for the engine
 pub struct EngineRedisStoreBuilder {
     redis_url: ConnectionInfo,
+    idempotency_key_ttl: Duration,
 }
 
 impl EngineRedisStoreBuilder {
     /// Simple constructor
     pub fn new(redis_url: ConnectionInfo) -> Self {
-        EngineRedisStoreBuilder { redis_url }
+        EngineRedisStoreBuilder {
+            redis_url,
+            idempotency_key_ttl: DEFAULT_IDEMPOTENCY_KEY_TTL,
+        }
+    }
+
+    /// Sets how long the responses to idempotent requests are kept (defaults to 24 hours)
+    pub fn idempotency_key_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.idempotency_key_ttl = ttl;
+        self
     }
 
     /// Connects to the provided redis_url and returns a Redis connection for the Settlement Engine
@@ -57,7 +80,10 @@ impl EngineRedisStoreBuilder {
             .await?;
         debug!("Connected to redis: {:?}", client);
 
-        Ok(EngineRedisStore { connection })
+        Ok(EngineRedisStore {
+            connection,
+            idempotency_key_ttl: self.idempotency_key_ttl,
+        })
     }
 }
 
@@ -68,6 +94,7 @@ impl EngineRedisStoreBuilder {
 #[derive(Clone)]
 pub struct EngineRedisStore {
     pub connection: MultiplexedConnection,
+    idempotency_key_ttl: Duration,
 }
 
 #[async_trait]
@@ -116,7 +143,16 @@ impl IdempotentStore for EngineRedisStore {
             .arg("input_hash")
             .arg(&input_hash)
             .ignore()
-            .expire(&idempotency_key, 86400)
+            .pexpire(
+                &idempotency_key,
+                self.idempotency_key_ttl.as_millis() as usize,
+            )
+            .ignore()
+            .zadd(
+                IDEMPOTENCY_KEYS_KEY,
+                &idempotency_key,
+                now_millis() + self.idempotency_key_ttl.as_millis() as u64,
+            )
             .ignore();
         pipe.query_async(&mut connection).await?;
         trace!(
@@ -127,6 +163,41 @@ impl IdempotentStore for EngineRedisStore {
         );
         Ok(())
     }
+
+    async fn get_idempotency_keys(&self) -> Result<Vec<String>, IdempotentStoreError> {
+        let mut connection = self.connection.clone();
+        let keys: Vec<String> = connection
+            .zrangebyscore(IDEMPOTENCY_KEYS_KEY, format!("({}", now_millis()), "+inf")
+            .await?;
+        Ok(keys)
+    }
+
+    async fn delete_idempotent_data(
+        &self,
+        idempotency_keys: Vec<String>,
+    ) -> Result<(), IdempotentStoreError> {
+        if idempotency_keys.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis_crate::pipe();
+        let mut connection = self.connection.clone();
+        pipe.atomic()
+            .del(idempotency_keys.as_slice())
+            .ignore()
+            .zrem(IDEMPOTENCY_KEYS_KEY, idempotency_keys.as_slice())
+            .ignore();
+        pipe.query_async(&mut connection).await?;
+        debug!("Deleted idempotency keys: {:?}", idempotency_keys);
+        Ok(())
+    }
+
+    async fn remove_expired_idempotent_data(&self) -> Result<usize, IdempotentStoreError> {
+        let mut connection = self.connection.clone();
+        let removed: usize = connection
+            .zrembyscore(IDEMPOTENCY_KEYS_KEY, "-inf", now_millis())
+            .await?;
+        Ok(removed)
+    }
 }
 
 /// Helper datatype for storing and loading quantities of a number with different scales
@@ -307,7 +378,7 @@ impl LeftoversStore for EngineRedisStore {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use test_helpers::{test_store, IDEMPOTENCY_KEY};
+    use test_helpers::{test_store, TestContext, IDEMPOTENCY_KEY};
 
     mod idempotency {
         use super::*;
@@ -342,5 +413,50 @@ mod tests {
                 .unwrap();
             assert!(data2.is_none());
         }
+
+        #[tokio::test]
+        async fn lists_deletes_and_expires_idempotency_keys() {
+            let context = TestContext::new();
+            let store = EngineRedisStoreBuilder::new(context.get_client_connection_info())
+                .idempotency_key_ttl(Duration::from_millis(500))
+                .connect()
+                .await
+                .unwrap();
+            for key in &["a", "b", "c"] {
+                store
+                    .save_idempotent_data(
+                        key.to_string(),
+                        Default::default(),
+                        StatusCode::OK,
+                        Bytes::from("TEST"),
+                    )
+                    .await
+                    .unwrap();
+            }
+            let mut keys = store.get_idempotency_keys().await.unwrap();
+            keys.sort();
+            assert_eq!(keys, vec!["a", "b", "c"]);
+
+            store
+                .delete_idempotent_data(vec!["b".to_string()])
+                .await
+                .unwrap();
+            assert!(store
+                .load_idempotent_data("b".to_string())
+                .await
+                .unwrap()
+                .is_none());
+            assert_eq!(store.get_idempotency_keys().await.unwrap().len(), 2);
+            assert_eq!(store.remove_expired_idempotent_data().await.unwrap(), 0);
+
+            tokio::time::delay_for(Duration::from_millis(600)).await;
+            assert!(store.get_idempotency_keys().await.unwrap().is_empty());
+            assert!(store
+                .load_idempotent_data("a".to_string())
+                .await
+                .unwrap()
+                .is_none());
+            assert_eq!(store.remove_expired_idempotent_data().await.unwrap(), 2);
+        }
     }
 }
@@ -3,4 +3,6 @@ mod redis_helpers;
 #[cfg(test)]
 mod store_helpers;
 #[cfg(test)]
+pub use redis_helpers::TestContext;
+#[cfg(test)]
 pub use store_helpers::{test_store, IDEMPOTENCY_KEY};
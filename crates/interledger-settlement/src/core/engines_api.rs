@@ -281,6 +281,25 @@ mod tests {
             );
             Ok(())
         }
+
+        async fn get_idempotency_keys(&self) -> Result<Vec<String>, IdempotentStoreError> {
+            Ok(self.cache.read().keys().cloned().collect())
+        }
+
+        async fn delete_idempotent_data(
+            &self,
+            idempotency_keys: Vec<String>,
+        ) -> Result<(), IdempotentStoreError> {
+            let mut cache = self.cache.write();
+            for idempotency_key in idempotency_keys {
+                cache.remove(&idempotency_key);
+            }
+            Ok(())
+        }
+
+        async fn remove_expired_idempotent_data(&self) -> Result<usize, IdempotentStoreError> {
+            Ok(0)
+        }
     }
 
     pub static IDEMPOTENCY: &str = "abcd01234";
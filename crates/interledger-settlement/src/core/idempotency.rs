@@ -6,7 +6,11 @@ use futures::TryFutureExt;
 use http::StatusCode;
 use interledger_errors::IdempotentStoreError;
 use interledger_errors::*;
-use tracing::error;
+use std::time::Duration;
+use tracing::{debug, error};
+
+/// How long the idempotent responses are kept by default (24 hours)
+pub const DEFAULT_IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(86400);
 
 /// Data stored for the idempotency features
 #[derive(Debug, Clone, PartialEq)]
@@ -50,6 +54,40 @@ pub trait IdempotentStore {
         status_code: StatusCode,
         data: Bytes,
     ) -> Result<(), IdempotentStoreError>;
+
+    /// Returns the idempotency keys whose responses are saved and not yet expired
+    async fn get_idempotency_keys(&self) -> Result<Vec<String>, IdempotentStoreError>;
+
+    /// Removes the saved responses of the given idempotency keys, so that
+    /// the requests using them are executed again
+    async fn delete_idempotent_data(
+        &self,
+        idempotency_keys: Vec<String>,
+    ) -> Result<(), IdempotentStoreError>;
+
+    /// Removes whatever the store keeps for the responses which outlived their TTL
+    /// and returns how many were removed. Stores which cannot expire the responses
+    /// natively rely on this being called periodically (see `spawn_idempotent_data_expiry`)
+    async fn remove_expired_idempotent_data(&self) -> Result<usize, IdempotentStoreError>;
+}
+
+/// Spawns a task which removes the expired idempotent responses from the store on the
+/// given interval, so that the store does not grow unboundedly
+pub fn spawn_idempotent_data_expiry<S>(store: S, interval: Duration) -> tokio::task::JoinHandle<()>
+where
+    S: IdempotentStore + Send + Sync + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match store.remove_expired_idempotent_data().await {
+                Ok(0) => {}
+                Ok(removed) => debug!("Removed {} expired idempotent responses", removed),
+                Err(err) => error!("Error removing the expired idempotent responses: {}", err),
+            }
+        }
+    })
 }
 
 /// Helper function that returns any idempotent data that corresponds to a
@@ -10,16 +10,23 @@ pub mod engines_api;
 mod settlement_client;
 pub use settlement_client::SettlementClient;
 
+mod scheduler;
+pub use scheduler::{RetryPolicy, SettlementScheduler};
+
+mod reconciliation;
+pub use reconciliation::{Discrepancy, Reconciler, ReconciliationPolicy};
+
 /// Expose useful utilities for implementing idempotent functionalities
 pub mod idempotency;
 
 /// Expose useful traits
 pub mod types;
 
+/// Helpers for converting amounts between asset scales and for amounts which do not fit in a u64
+pub mod amount;
+
 use num_bigint::BigUint;
-use num_traits::Zero;
 use ring::digest::{digest, SHA256};
-use types::{Convert, ConvertDetails};
 
 /// Converts a number from a precision to another while taking precision loss into account
 ///
@@ -47,34 +54,9 @@ pub fn scale_with_precision_loss(
     local_scale: u8,
     remote_scale: u8,
 ) -> (BigUint, BigUint) {
-    // It's safe to unwrap here since BigUint's normalize_scale cannot fail.
-    let scaled = amount
-        .normalize_scale(ConvertDetails {
-            from: remote_scale,
-            to: local_scale,
-        })
-        .unwrap();
-
-    if local_scale < remote_scale {
-        // If we ended up downscaling, scale the value back up back,
-        // and return any precision loss
-        // note that `from` and `to` are reversed compared to the previous call
-        let upscaled = scaled
-            .normalize_scale(ConvertDetails {
-                from: local_scale,
-                to: remote_scale,
-            })
-            .unwrap();
-        let precision_loss = if upscaled < amount {
-            amount - upscaled
-        } else {
-            Zero::zero()
-        };
-        (scaled, precision_loss)
-    } else {
-        // there is no need to do anything further if we upscaled
-        (scaled, Zero::zero())
-    }
+    // BigUint's conversions cannot overflow
+    amount::convert_scale(amount, remote_scale, local_scale)
+        .expect("BigUint scale conversions cannot fail")
 }
 
 /// Returns the 32-bytes SHA256 hash of the provided preimage
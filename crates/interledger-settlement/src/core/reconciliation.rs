@@ -0,0 +1,505 @@
+use super::amount::scale_amount;
+use super::settlement_client::SettlementClient;
+use super::types::{
+    Quantity, SettlementAccount, SettlementDirection, SettlementHistoryStore, SettlementRecord,
+    SettlementStore, SETTLEMENT_HISTORY_CAPACITY,
+};
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+use url::Url;
+use uuid::Uuid;
+
+/// How the [`Reconciler`](./struct.Reconciler.html) handles the discrepancies it finds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReconciliationPolicy {
+    /// Largest discrepancy which may be corrected automatically, in the account's asset scale
+    pub tolerance: u64,
+    /// Whether the discrepancies within the tolerance are corrected, rather than only reported.
+    /// Only the incoming settlements which the store missed can be corrected, by crediting
+    /// the account, and only once the same discrepancy was found twice in a row so that the
+    /// settlements being processed while reconciling are not credited twice
+    pub auto_correct: bool,
+}
+
+/// A difference between the settlements recorded in the store for an account and the
+/// amount its settlement engine reports to have settled
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Discrepancy {
+    /// The account whose settlements differ
+    pub account_id: Uuid,
+    /// Whether the settlements were sent to or received from the peer
+    pub direction: SettlementDirection,
+    /// Total of the settlements recorded in the store, in the account's asset scale
+    pub recorded: u128,
+    /// Total reported by the settlement engine, in the account's asset scale
+    pub settled: u128,
+    /// When the discrepancy was first found, in milliseconds since the Unix epoch
+    pub detected_at: u64,
+}
+
+type Discrepancies = HashMap<(Uuid, SettlementDirection), Discrepancy>;
+
+/// # Settlement Reconciler
+///
+/// Compares the settlements recorded in the store's settlement history with the totals
+/// reported by the accounts' settlement engines, to detect the balances which drifted
+/// because a settlement was lost on either side. The discrepancies found by the last run
+/// are kept so that they can be exposed to the operators.
+///
+/// Since the history only keeps the most recent settlements, the engine is asked for the
+/// totals since the oldest recorded one once the history of an account is full. The accounts
+/// whose engines do not report their totals are skipped.
+#[derive(Clone)]
+pub struct Reconciler {
+    client: SettlementClient,
+    policy: ReconciliationPolicy,
+    discrepancies: Arc<RwLock<Discrepancies>>,
+}
+
+impl Reconciler {
+    /// Simple constructor
+    pub fn new(client: SettlementClient, policy: ReconciliationPolicy) -> Self {
+        Reconciler {
+            client,
+            policy,
+            discrepancies: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Returns the discrepancies found by the last run, oldest first
+    pub fn discrepancies(&self) -> Vec<Discrepancy> {
+        let mut discrepancies: Vec<Discrepancy> =
+            self.discrepancies.read().values().cloned().collect();
+        discrepancies.sort_by_key(|discrepancy| (discrepancy.detected_at, discrepancy.account_id));
+        discrepancies
+    }
+
+    /// Reconciles the settlements of the accounts which have a settlement engine and
+    /// returns the discrepancies found. The previous discrepancies of the accounts which
+    /// could not be reconciled are kept, since they may still be there
+    pub async fn reconcile<S, A>(&self, store: &S, accounts: &[A]) -> Vec<Discrepancy>
+    where
+        S: SettlementStore<Account = A> + SettlementHistoryStore,
+        A: SettlementAccount,
+    {
+        let previous = self.discrepancies.read().clone();
+        let mut found: Discrepancies = HashMap::new();
+        for account in accounts {
+            let engine_url = match account.settlement_engine_details() {
+                Some(details) => details.url,
+                None => continue,
+            };
+            match self
+                .reconcile_account(store, account, engine_url, &previous)
+                .await
+            {
+                Ok(discrepancies) => found.extend(discrepancies.into_iter().map(|discrepancy| {
+                    ((discrepancy.account_id, discrepancy.direction), discrepancy)
+                })),
+                Err(err) => {
+                    warn!(
+                        "Cannot reconcile the settlements of account {}: {}",
+                        account.id(),
+                        err
+                    );
+                    found.extend(
+                        previous
+                            .iter()
+                            .filter(|((account_id, _), _)| *account_id == account.id())
+                            .map(|(key, discrepancy)| (*key, discrepancy.clone())),
+                    );
+                }
+            }
+        }
+
+        debug!(
+            "Reconciled the settlements of {} accounts, found {} discrepancies",
+            accounts.len(),
+            found.len()
+        );
+        *self.discrepancies.write() = found;
+        self.discrepancies()
+    }
+
+    async fn reconcile_account<S, A>(
+        &self,
+        store: &S,
+        account: &A,
+        engine_url: Url,
+        previous: &Discrepancies,
+    ) -> Result<Vec<Discrepancy>, String>
+    where
+        S: SettlementStore<Account = A> + SettlementHistoryStore,
+        A: SettlementAccount,
+    {
+        let account_id = account.id();
+        let history = store
+            .get_settlement_history(account_id, 0, u64::MAX)
+            .await
+            .map_err(|err| err.to_string())?;
+        let from = if history.len() >= SETTLEMENT_HISTORY_CAPACITY {
+            history[0].timestamp
+        } else {
+            0
+        };
+        let totals = match self
+            .client
+            .get_settled_totals(account_id, engine_url, from)
+            .await
+            .map_err(|err| err.to_string())?
+        {
+            Some(totals) => totals,
+            None => {
+                debug!(
+                    "Not reconciling the settlements of account {}, its settlement engine does not report its totals",
+                    account_id
+                );
+                return Ok(Vec::new());
+            }
+        };
+
+        let (mut sent, mut received) = (0u128, 0u128);
+        for record in history.iter() {
+            match record.direction {
+                SettlementDirection::Outgoing => sent += u128::from(record.amount),
+                SettlementDirection::Incoming => received += u128::from(record.amount),
+                SettlementDirection::Refunded => {}
+            }
+        }
+        let asset_scale = account.asset_scale();
+        let totals = [
+            (
+                SettlementDirection::Outgoing,
+                sent,
+                to_asset_scale(&totals.sent, asset_scale)?,
+            ),
+            (
+                SettlementDirection::Incoming,
+                received,
+                to_asset_scale(&totals.received, asset_scale)?,
+            ),
+        ];
+
+        let mut discrepancies = Vec::new();
+        for &(direction, recorded, settled) in totals.iter() {
+            if recorded == settled {
+                continue;
+            }
+            let seen_before = previous
+                .get(&(account_id, direction))
+                .filter(|discrepancy| {
+                    discrepancy.recorded == recorded && discrepancy.settled == settled
+                });
+            let discrepancy = Discrepancy {
+                account_id,
+                direction,
+                recorded,
+                settled,
+                detected_at: seen_before.map_or_else(now_millis, |seen| seen.detected_at),
+            };
+            if seen_before.is_some() && self.can_correct(&discrepancy) {
+                match correct(store, &discrepancy).await {
+                    Ok(()) => continue,
+                    Err(err) => warn!(
+                        "Error correcting the incoming settlements of account {}: {}",
+                        account_id, err
+                    ),
+                }
+            }
+            warn!(
+                "Settlements of account {} differ from its settlement engine's: {:?} recorded: {}, settled: {}",
+                account_id, direction, recorded, settled
+            );
+            discrepancies.push(discrepancy);
+        }
+        Ok(discrepancies)
+    }
+
+    fn can_correct(&self, discrepancy: &Discrepancy) -> bool {
+        self.policy.auto_correct
+            && discrepancy.direction == SettlementDirection::Incoming
+            && discrepancy.settled > discrepancy.recorded
+            && discrepancy.settled - discrepancy.recorded <= u128::from(self.policy.tolerance)
+    }
+}
+
+/// Credits the account with the incoming settlements the store missed and records them
+async fn correct<S, A>(store: &S, discrepancy: &Discrepancy) -> Result<(), String>
+where
+    S: SettlementStore<Account = A> + SettlementHistoryStore,
+{
+    // Bounded by the tolerance, which is a u64
+    let amount = (discrepancy.settled - discrepancy.recorded) as u64;
+    // Keyed by the settled total, so that the same correction is never applied twice
+    let idempotency_key = format!(
+        "reconciliation:{}:{}",
+        discrepancy.account_id, discrepancy.settled
+    );
+    let uncredited_amount = store
+        .update_balance_for_incoming_settlement(
+            discrepancy.account_id,
+            amount,
+            Some(idempotency_key.clone()),
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+    let record = SettlementRecord {
+        timestamp: now_millis(),
+        direction: SettlementDirection::Incoming,
+        amount: amount - uncredited_amount,
+        id: Some(idempotency_key),
+    };
+    store
+        .record_settlement(discrepancy.account_id, record)
+        .await
+        .map_err(|err| err.to_string())?;
+    if uncredited_amount > 0 {
+        return Err(format!(
+            "{} of the missed settlements would overflow the balance and was not credited",
+            uncredited_amount
+        ));
+    }
+    info!(
+        "Credited account {} with {} of incoming settlements which were not recorded",
+        discrepancy.account_id, amount
+    );
+    Ok(())
+}
+
+fn to_asset_scale(quantity: &Quantity, asset_scale: u8) -> Result<u128, String> {
+    let amount = quantity
+        .amount
+        .parse::<u128>()
+        .map_err(|_| format!("invalid amount settled: {}", quantity.amount))?;
+    scale_amount(amount, quantity.scale, asset_scale)
+        .map_err(|_| format!("settled amount too large: {}", quantity.amount))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::SettlementEngineDetails;
+    use async_trait::async_trait;
+    use interledger_errors::{SettlementHistoryStoreError, SettlementStoreError};
+    use interledger_packet::Address;
+    use interledger_service::{Account, Username};
+    use mockito::{mock, Matcher};
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+
+    static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+    static ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("example.alice").unwrap());
+
+    #[derive(Debug, Clone)]
+    struct TestAccount(Uuid);
+
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            self.0
+        }
+
+        fn username(&self) -> &Username {
+            &ALICE
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn asset_scale(&self) -> u8 {
+            6
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &ADDRESS
+        }
+    }
+
+    impl SettlementAccount for TestAccount {
+        fn settlement_engine_details(&self) -> Option<SettlementEngineDetails> {
+            Some(SettlementEngineDetails {
+                url: Url::parse(&mockito::server_url()).unwrap(),
+            })
+        }
+    }
+
+    /// The amounts credited to the account, with their idempotency keys
+    type Credits = Vec<(u64, Option<String>)>;
+
+    #[derive(Clone, Default)]
+    struct TestStore {
+        credited: Arc<RwLock<Credits>>,
+        history: Arc<RwLock<Vec<SettlementRecord>>>,
+    }
+
+    #[async_trait]
+    impl SettlementStore for TestStore {
+        type Account = TestAccount;
+
+        async fn update_balance_for_incoming_settlement(
+            &self,
+            _account_id: Uuid,
+            amount: u64,
+            idempotency_key: Option<String>,
+        ) -> Result<u64, SettlementStoreError> {
+            self.credited.write().push((amount, idempotency_key));
+            Ok(0)
+        }
+
+        async fn refund_settlement(
+            &self,
+            _account_id: Uuid,
+            _settle_amount: u64,
+        ) -> Result<(), SettlementStoreError> {
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl SettlementHistoryStore for TestStore {
+        async fn record_settlement(
+            &self,
+            _account_id: Uuid,
+            record: SettlementRecord,
+        ) -> Result<(), SettlementHistoryStoreError> {
+            self.history.write().push(record);
+            Ok(())
+        }
+
+        async fn get_settlement_history(
+            &self,
+            _account_id: Uuid,
+            _from: u64,
+            _to: u64,
+        ) -> Result<Vec<SettlementRecord>, SettlementHistoryStoreError> {
+            Ok(self.history.read().clone())
+        }
+    }
+
+    fn record(direction: SettlementDirection, amount: u64) -> SettlementRecord {
+        SettlementRecord {
+            timestamp: 1000,
+            direction,
+            amount,
+            id: None,
+        }
+    }
+
+    // Each test reconciles its own account, since the mock server is shared between them.
+    // The engine reports amounts with a scale of 9, 3 more than the accounts'
+    fn mock_totals(account_id: Uuid, sent: u64, received: u64) -> mockito::Mock {
+        mock(
+            "GET",
+            format!("/accounts/{}/settlements/totals", account_id).as_str(),
+        )
+        .match_query(Matcher::UrlEncoded("from".to_string(), "0".to_string()))
+        .with_body(
+            serde_json::json!({
+                "sent": {"amount": (sent * 1000).to_string(), "scale": 9},
+                "received": {"amount": (received * 1000).to_string(), "scale": 9},
+            })
+            .to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn reports_discrepancies() {
+        let account_id = Uuid::new_v4();
+        let m = mock_totals(account_id, 100, 70).expect(2).create();
+        let store = TestStore::default();
+        store.history.write().extend(vec![
+            record(SettlementDirection::Outgoing, 100),
+            record(SettlementDirection::Refunded, 30),
+            record(SettlementDirection::Incoming, 50),
+        ]);
+        let reconciler = Reconciler::new(SettlementClient::default(), Default::default());
+
+        let discrepancies = reconciler
+            .reconcile(&store, &[TestAccount(account_id)])
+            .await;
+        assert_eq!(discrepancies.len(), 1);
+        assert_eq!(discrepancies[0].direction, SettlementDirection::Incoming);
+        assert_eq!(discrepancies[0].recorded, 50);
+        assert_eq!(discrepancies[0].settled, 70);
+        assert_eq!(reconciler.discrepancies(), discrepancies);
+
+        // Discrepancies are only reported unless auto-correction is enabled
+        let again = reconciler
+            .reconcile(&store, &[TestAccount(account_id)])
+            .await;
+        m.assert();
+        assert_eq!(again, discrepancies);
+        assert!(store.credited.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn skips_engines_which_do_not_report_totals() {
+        let account_id = Uuid::new_v4();
+        let m = mock(
+            "GET",
+            format!("/accounts/{}/settlements/totals", account_id).as_str(),
+        )
+        .match_query(Matcher::Any)
+        .with_status(404)
+        .create();
+        let store = TestStore::default();
+        store
+            .history
+            .write()
+            .push(record(SettlementDirection::Incoming, 50));
+        let reconciler = Reconciler::new(SettlementClient::default(), Default::default());
+
+        let discrepancies = reconciler
+            .reconcile(&store, &[TestAccount(account_id)])
+            .await;
+        m.assert();
+        assert!(discrepancies.is_empty());
+    }
+
+    #[tokio::test]
+    async fn corrects_persistent_discrepancies_within_tolerance() {
+        let account_id = Uuid::new_v4();
+        let m = mock_totals(account_id, 0, 70).expect(2).create();
+        let store = TestStore::default();
+        store
+            .history
+            .write()
+            .push(record(SettlementDirection::Incoming, 50));
+        let reconciler = Reconciler::new(
+            SettlementClient::default(),
+            ReconciliationPolicy {
+                tolerance: 20,
+                auto_correct: true,
+            },
+        );
+
+        // The discrepancy is only corrected once it was found twice
+        let discrepancies = reconciler
+            .reconcile(&store, &[TestAccount(account_id)])
+            .await;
+        assert_eq!(discrepancies.len(), 1);
+        assert!(store.credited.read().is_empty());
+
+        let discrepancies = reconciler
+            .reconcile(&store, &[TestAccount(account_id)])
+            .await;
+        m.assert();
+        assert!(discrepancies.is_empty());
+        assert_eq!(
+            *store.credited.read(),
+            vec![(20, Some(format!("reconciliation:{}:70", account_id)))]
+        );
+        assert_eq!(store.history.read().len(), 2);
+    }
+}
@@ -0,0 +1,956 @@
+use super::get_hash_of;
+use super::settlement_client::SettlementClient;
+use super::types::{
+    PendingMessage, PendingSettlement, SettlementAccount, SettlementDirection,
+    SettlementHistoryStore, SettlementQueueStore, SettlementRecord, SettlementStore,
+};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use interledger_errors::{AccountStoreError, SettlementQueueStoreError};
+use interledger_service::{AccountEvent, AccountStore, EventBus};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
+use url::Url;
+use uuid::Uuid;
+
+/// How the scheduler retries the settlement requests which failed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Delay before the first retry. It doubles after every further failed request
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between two requests
+    pub max_backoff: Duration,
+    /// Number of failed requests after which the settlement is given up on
+    /// and its amount is refunded to the account's balance
+    pub max_attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+            max_attempts: 10,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before the next request, after `attempts` requests failed
+    fn backoff(&self, attempts: u32) -> Duration {
+        2u32.checked_pow(attempts.saturating_sub(1))
+            .and_then(|factor| self.initial_backoff.checked_mul(factor))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/// Why a settlement request failed
+enum SettlementFailure {
+    /// The request may succeed if it is retried later
+    Temporary(String),
+    /// Retrying the request is pointless, e.g. because the engine rejected it
+    Permanent(String),
+}
+
+type Queue = Arc<RwLock<HashMap<Uuid, PendingSettlement>>>;
+type MessageQueue = Arc<RwLock<HashMap<Uuid, PendingMessage>>>;
+/// Set once the scheduler is given an event bus, which may be after settlements were resumed
+type Events = Arc<RwLock<Option<EventBus>>>;
+
+/// # Settlement Scheduler
+///
+/// Queues outgoing settlements and sends them to the accounts' settlement engines in the
+/// background. Failed requests are retried with exponential backoff, using the same
+/// idempotency key every time, and the settlement is refunded once the engine rejects
+/// it or the retries are exhausted.
+///
+/// It also serves as the outbox of the messages from the peers' settlement engines which
+/// could not be delivered to the accounts' settlement engines, which are retried the same way.
+///
+/// The queued settlements and messages are persisted in the store so that they are resumed
+/// when the node restarts, which together with the idempotency keys guarantees that they are
+/// delivered at least once.
+#[derive(Clone)]
+pub struct SettlementScheduler {
+    sender: mpsc::UnboundedSender<PendingSettlement>,
+    queue: Queue,
+    message_sender: mpsc::UnboundedSender<PendingMessage>,
+    message_queue: MessageQueue,
+    events: Events,
+}
+
+impl SettlementScheduler {
+    /// Resumes the settlements which were pending when the node stopped
+    /// and starts processing the ones scheduled from now on
+    pub async fn start<S, A>(
+        store: S,
+        client: SettlementClient,
+        policy: RetryPolicy,
+    ) -> Result<Self, SettlementQueueStoreError>
+    where
+        S: SettlementStore<Account = A>
+            + SettlementQueueStore
+            + SettlementHistoryStore
+            + AccountStore<Account = A>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        A: SettlementAccount + Send + Sync + 'static,
+    {
+        let pending = store.load_pending_settlements().await?;
+        let queue: Queue = Arc::new(RwLock::new(HashMap::new()));
+        let events: Events = Arc::new(RwLock::new(None));
+        if !pending.is_empty() {
+            info!("Resuming {} pending settlements", pending.len());
+        }
+        for settlement in pending {
+            queue.write().insert(settlement.id, settlement.clone());
+            tokio::spawn(run_settlement(
+                store.clone(),
+                client.clone(),
+                policy,
+                queue.clone(),
+                events.clone(),
+                settlement,
+            ));
+        }
+
+        let pending_messages = store.load_pending_messages().await?;
+        let message_queue: MessageQueue = Arc::new(RwLock::new(HashMap::new()));
+        if !pending_messages.is_empty() {
+            info!(
+                "Resuming delivery of {} settlement engine messages",
+                pending_messages.len()
+            );
+        }
+        for message in pending_messages {
+            message_queue.write().insert(message.id, message.clone());
+            tokio::spawn(run_message(
+                store.clone(),
+                client.clone(),
+                policy,
+                message_queue.clone(),
+                message,
+            ));
+        }
+
+        let (message_sender, mut message_receiver) = mpsc::unbounded::<PendingMessage>();
+        let message_queue_clone = message_queue.clone();
+        let store_clone = store.clone();
+        let client_clone = client.clone();
+        tokio::spawn(async move {
+            while let Some(message) = message_receiver.next().await {
+                let store = store_clone.clone();
+                let client = client_clone.clone();
+                let queue = message_queue_clone.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = store.save_pending_message(message.clone()).await {
+                        error!(
+                            "Error saving pending settlement engine message {} for account {}: {}",
+                            message.id, message.account_id, err
+                        );
+                    }
+                    run_message(store, client, policy, queue, message).await
+                });
+            }
+            debug!("Stopped scheduling messages because the scheduler was dropped");
+        });
+
+        let (sender, mut receiver) = mpsc::unbounded::<PendingSettlement>();
+        let queue_clone = queue.clone();
+        let events_clone = events.clone();
+        tokio::spawn(async move {
+            while let Some(settlement) = receiver.next().await {
+                let store = store.clone();
+                let client = client.clone();
+                let queue = queue_clone.clone();
+                let events = events_clone.clone();
+                tokio::spawn(async move {
+                    // Save the settlement before sending it, so that it is not lost if
+                    // the node stops before the engine accepted it
+                    if let Err(err) = store.save_pending_settlement(settlement.clone()).await {
+                        error!(
+                            "Error saving pending settlement {} for account {}: {}",
+                            settlement.id, settlement.account_id, err
+                        );
+                    }
+                    run_settlement(store, client, policy, queue, events, settlement).await
+                });
+            }
+            debug!("Stopped scheduling settlements because the scheduler was dropped");
+        });
+
+        Ok(SettlementScheduler {
+            sender,
+            queue,
+            message_sender,
+            message_queue,
+            events,
+        })
+    }
+
+    /// Publishes the settlements which the engines accepted on the bus
+    pub fn with_event_bus(self, events: EventBus) -> Self {
+        *self.events.write() = Some(events);
+        self
+    }
+
+    /// Queues a settlement of `amount` with the account. The amount must already have been
+    /// deducted from the account's balance: it is refunded if the settlement fails.
+    ///
+    /// Returns the settlement back if the scheduler stopped, in which case the caller should
+    /// refund its amount.
+    pub fn schedule(&self, account_id: Uuid, amount: u64) -> Result<(), PendingSettlement> {
+        let settlement = PendingSettlement {
+            id: Uuid::new_v4(),
+            account_id,
+            amount,
+            attempts: 0,
+            next_attempt_at: now_millis(),
+            last_error: None,
+        };
+        let id = settlement.id;
+        self.queue.write().insert(id, settlement.clone());
+        self.sender.unbounded_send(settlement).map_err(|err| {
+            self.queue.write().remove(&id);
+            error!(
+                "Cannot schedule settlement for account {} of {} because the scheduler stopped",
+                account_id, amount
+            );
+            err.into_inner()
+        })
+    }
+
+    /// Returns the settlements which were not yet accepted by the settlement engines,
+    /// ordered by the time of their next attempt
+    pub fn pending_settlements(&self) -> Vec<PendingSettlement> {
+        let mut pending: Vec<PendingSettlement> = self.queue.read().values().cloned().collect();
+        pending.sort_by_key(|settlement| settlement.next_attempt_at);
+        pending
+    }
+
+    /// Queues the delivery of a message from the peer's settlement engine to the account's
+    /// settlement engine, after it failed to be delivered directly.
+    ///
+    /// The message is identified by its contents, so that it is queued only once if the
+    /// peer's engine also retries it. Its id is used as the idempotency key of the requests.
+    pub fn schedule_message(
+        &self,
+        account_id: Uuid,
+        message: Vec<u8>,
+    ) -> Result<(), PendingMessage> {
+        let id = message_id(account_id, &message);
+        let pending = PendingMessage {
+            id,
+            account_id,
+            message,
+            attempts: 0,
+            next_attempt_at: now_millis(),
+            last_error: None,
+        };
+        {
+            let mut queue = self.message_queue.write();
+            if queue.contains_key(&id) {
+                debug!(
+                    "Message {} for the settlement engine of account {} is already queued",
+                    id, account_id
+                );
+                return Ok(());
+            }
+            queue.insert(id, pending.clone());
+        }
+        self.message_sender.unbounded_send(pending).map_err(|err| {
+            self.message_queue.write().remove(&id);
+            error!(
+                "Cannot queue message for the settlement engine of account {} because the scheduler stopped",
+                account_id
+            );
+            err.into_inner()
+        })
+    }
+
+    /// Returns the messages which were not yet delivered to the settlement engines,
+    /// ordered by the time of their next attempt
+    pub fn pending_messages(&self) -> Vec<PendingMessage> {
+        let mut pending: Vec<PendingMessage> =
+            self.message_queue.read().values().cloned().collect();
+        pending.sort_by_key(|message| message.next_attempt_at);
+        pending
+    }
+}
+
+/// Derives the id of a message from the account and the message's contents
+fn message_id(account_id: Uuid, message: &[u8]) -> Uuid {
+    let hash = get_hash_of(&[account_id.as_bytes(), message].concat());
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&hash[..16]);
+    Uuid::from_bytes(bytes)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Sends the settlement until it either succeeds or is given up on, and then removes it
+async fn run_settlement<S, A>(
+    store: S,
+    client: SettlementClient,
+    policy: RetryPolicy,
+    queue: Queue,
+    events: Events,
+    mut settlement: PendingSettlement,
+) where
+    S: SettlementStore<Account = A>
+        + SettlementQueueStore
+        + SettlementHistoryStore
+        + AccountStore<Account = A>,
+    A: SettlementAccount,
+{
+    // Resumed settlements wait for the attempt which was scheduled before the restart
+    let delay = settlement.next_attempt_at.saturating_sub(now_millis());
+    if delay > 0 {
+        tokio::time::delay_for(Duration::from_millis(delay)).await;
+    }
+
+    loop {
+        let failure = match send_settlement(&store, &client, &settlement).await {
+            Ok(()) => {
+                info!(
+                    "Settlement for account {} for {} succeeded",
+                    settlement.account_id, settlement.amount
+                );
+                record_settlement(&store, &settlement, SettlementDirection::Outgoing).await;
+                if let Some(ref bus) = *events.read() {
+                    bus.publish(AccountEvent::SettlementSent {
+                        account_id: settlement.account_id,
+                        amount: settlement.amount,
+                    });
+                }
+                break;
+            }
+            Err(SettlementFailure::Temporary(err))
+                if settlement.attempts + 1 < policy.max_attempts =>
+            {
+                err
+            }
+            Err(SettlementFailure::Temporary(err)) | Err(SettlementFailure::Permanent(err)) => {
+                warn!(
+                    "Settlement for account {} for {} failed after {} attempts: {}",
+                    settlement.account_id,
+                    settlement.amount,
+                    settlement.attempts + 1,
+                    err
+                );
+                if let Err(err) = store
+                    .refund_settlement(settlement.account_id, settlement.amount)
+                    .await
+                {
+                    error!(
+                        "Refunding account {} after failed settlement failed, amount: {}: {}",
+                        settlement.account_id, settlement.amount, err
+                    );
+                } else {
+                    record_settlement(&store, &settlement, SettlementDirection::Refunded).await;
+                    if let Some(ref bus) = *events.read() {
+                        bus.publish(AccountEvent::SettlementRefunded {
+                            account_id: settlement.account_id,
+                            amount: settlement.amount,
+                        });
+                    }
+                }
+                break;
+            }
+        };
+
+        settlement.attempts += 1;
+        let backoff = policy.backoff(settlement.attempts);
+        settlement.next_attempt_at = now_millis() + backoff.as_millis() as u64;
+        debug!(
+            "Settlement for account {} for {} failed, retrying in {:?}: {}",
+            settlement.account_id, settlement.amount, backoff, failure
+        );
+        settlement.last_error = Some(failure);
+        queue.write().insert(settlement.id, settlement.clone());
+        if let Err(err) = store.save_pending_settlement(settlement.clone()).await {
+            error!(
+                "Error saving pending settlement {} for account {}: {}",
+                settlement.id, settlement.account_id, err
+            );
+        }
+        tokio::time::delay_for(backoff).await;
+    }
+
+    queue.write().remove(&settlement.id);
+    if let Err(err) = store.remove_pending_settlement(settlement.id).await {
+        error!(
+            "Error removing pending settlement {} for account {}: {}",
+            settlement.id, settlement.account_id, err
+        );
+    }
+}
+
+/// Delivers the message until it either succeeds or is given up on, and then removes it
+async fn run_message<S, A>(
+    store: S,
+    client: SettlementClient,
+    policy: RetryPolicy,
+    queue: MessageQueue,
+    mut message: PendingMessage,
+) where
+    S: SettlementQueueStore + AccountStore<Account = A>,
+    A: SettlementAccount,
+{
+    let delay = message.next_attempt_at.saturating_sub(now_millis());
+    if delay > 0 {
+        tokio::time::delay_for(Duration::from_millis(delay)).await;
+    }
+
+    loop {
+        let failure = match send_message(&store, &client, &message).await {
+            Ok(()) => {
+                info!(
+                    "Delivered message {} to the settlement engine of account {}",
+                    message.id, message.account_id
+                );
+                break;
+            }
+            Err(SettlementFailure::Temporary(err))
+                if message.attempts + 1 < policy.max_attempts =>
+            {
+                err
+            }
+            Err(SettlementFailure::Temporary(err)) | Err(SettlementFailure::Permanent(err)) => {
+                error!(
+                    "Giving up on delivering message {} to the settlement engine of account {} after {} attempts: {}",
+                    message.id,
+                    message.account_id,
+                    message.attempts + 1,
+                    err
+                );
+                break;
+            }
+        };
+
+        message.attempts += 1;
+        let backoff = policy.backoff(message.attempts);
+        message.next_attempt_at = now_millis() + backoff.as_millis() as u64;
+        debug!(
+            "Delivering message {} to the settlement engine of account {} failed, retrying in {:?}: {}",
+            message.id, message.account_id, backoff, failure
+        );
+        message.last_error = Some(failure);
+        queue.write().insert(message.id, message.clone());
+        if let Err(err) = store.save_pending_message(message.clone()).await {
+            error!(
+                "Error saving pending settlement engine message {} for account {}: {}",
+                message.id, message.account_id, err
+            );
+        }
+        tokio::time::delay_for(backoff).await;
+    }
+
+    queue.write().remove(&message.id);
+    if let Err(err) = store.remove_pending_message(message.id).await {
+        error!(
+            "Error removing pending settlement engine message {} for account {}: {}",
+            message.id, message.account_id, err
+        );
+    }
+}
+
+/// Adds the settlement to the account's settlement history
+async fn record_settlement<S>(
+    store: &S,
+    settlement: &PendingSettlement,
+    direction: SettlementDirection,
+) where
+    S: SettlementHistoryStore,
+{
+    let record = SettlementRecord {
+        timestamp: now_millis(),
+        direction,
+        amount: settlement.amount,
+        id: Some(settlement.id.to_hyphenated().to_string()),
+    };
+    if let Err(err) = store.record_settlement(settlement.account_id, record).await {
+        error!(
+            "Error recording settlement {} for account {}: {}",
+            settlement.id, settlement.account_id, err
+        );
+    }
+}
+
+/// Loads the account and the URL of its settlement engine
+async fn load_engine<S, A>(store: &S, account_id: Uuid) -> Result<(A, Url), SettlementFailure>
+where
+    S: AccountStore<Account = A>,
+    A: SettlementAccount,
+{
+    let account = match store.get_accounts(vec![account_id]).await {
+        Ok(mut accounts) if accounts.len() == 1 => accounts.remove(0),
+        Ok(_) | Err(AccountStoreError::AccountNotFound(_)) => {
+            return Err(SettlementFailure::Permanent(
+                "account does not exist".to_string(),
+            ))
+        }
+        Err(err) => return Err(SettlementFailure::Temporary(err.to_string())),
+    };
+    let engine_url = account
+        .settlement_engine_details()
+        .ok_or_else(|| {
+            SettlementFailure::Permanent("account has no settlement engine".to_string())
+        })?
+        .url;
+    Ok((account, engine_url))
+}
+
+async fn send_settlement<S, A>(
+    store: &S,
+    client: &SettlementClient,
+    settlement: &PendingSettlement,
+) -> Result<(), SettlementFailure>
+where
+    S: AccountStore<Account = A>,
+    A: SettlementAccount,
+{
+    let (account, engine_url) = load_engine(store, settlement.account_id).await?;
+    client
+        .send_settlement_with_idempotency_key(
+            account.id(),
+            engine_url,
+            settlement.amount,
+            account.asset_scale(),
+            settlement.id,
+        )
+        .await
+        .map(|_| ())
+        .map_err(|err| match err.status() {
+            // The engine will not accept the same request later
+            Some(status) if status.is_client_error() => {
+                SettlementFailure::Permanent(err.to_string())
+            }
+            _ => SettlementFailure::Temporary(err.to_string()),
+        })
+}
+
+async fn send_message<S, A>(
+    store: &S,
+    client: &SettlementClient,
+    message: &PendingMessage,
+) -> Result<(), SettlementFailure>
+where
+    S: AccountStore<Account = A>,
+    A: SettlementAccount,
+{
+    let (account, engine_url) = load_engine(store, message.account_id).await?;
+    let response = client
+        .send_message_with_idempotency_key(
+            account.id(),
+            engine_url,
+            message.message.clone(),
+            message.id,
+        )
+        .await
+        .map_err(|err| SettlementFailure::Temporary(err.to_string()))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else if status.is_client_error() {
+        Err(SettlementFailure::Permanent(format!(
+            "engine rejected the message with status {}",
+            status
+        )))
+    } else {
+        Err(SettlementFailure::Temporary(format!(
+            "engine responded with status {}",
+            status
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::SettlementEngineDetails;
+    use async_trait::async_trait;
+    use interledger_errors::{SettlementHistoryStoreError, SettlementStoreError};
+    use interledger_packet::Address;
+    use interledger_service::{Account, Username};
+    use mockito::{mock, Matcher};
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+    use url::Url;
+
+    static ALICE: Lazy<Username> = Lazy::new(|| Username::from_str("alice").unwrap());
+    static ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("example.alice").unwrap());
+
+    #[derive(Debug, Clone)]
+    struct TestAccount(Uuid);
+
+    impl Account for TestAccount {
+        fn id(&self) -> Uuid {
+            self.0
+        }
+
+        fn username(&self) -> &Username {
+            &ALICE
+        }
+
+        fn asset_code(&self) -> &str {
+            "XYZ"
+        }
+
+        fn asset_scale(&self) -> u8 {
+            9
+        }
+
+        fn ilp_address(&self) -> &Address {
+            &ADDRESS
+        }
+    }
+
+    impl SettlementAccount for TestAccount {
+        fn settlement_engine_details(&self) -> Option<SettlementEngineDetails> {
+            Some(SettlementEngineDetails {
+                url: Url::parse(&mockito::server_url()).unwrap(),
+            })
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct TestStore {
+        pending: Arc<RwLock<HashMap<Uuid, PendingSettlement>>>,
+        messages: Arc<RwLock<HashMap<Uuid, PendingMessage>>>,
+        refunded: Arc<RwLock<u64>>,
+        history: Arc<RwLock<Vec<SettlementRecord>>>,
+    }
+
+    #[async_trait]
+    impl AccountStore for TestStore {
+        type Account = TestAccount;
+
+        async fn get_accounts(
+            &self,
+            account_ids: Vec<Uuid>,
+        ) -> Result<Vec<TestAccount>, AccountStoreError> {
+            Ok(account_ids.into_iter().map(TestAccount).collect())
+        }
+
+        async fn get_account_id_from_username(
+            &self,
+            username: &Username,
+        ) -> Result<Uuid, AccountStoreError> {
+            Err(AccountStoreError::AccountNotFound(username.to_string()))
+        }
+    }
+
+    #[async_trait]
+    impl SettlementStore for TestStore {
+        type Account = TestAccount;
+
+        async fn update_balance_for_incoming_settlement(
+            &self,
+            _account_id: Uuid,
+            _amount: u64,
+            _idempotency_key: Option<String>,
+        ) -> Result<u64, SettlementStoreError> {
+            Ok(0)
+        }
+
+        async fn refund_settlement(
+            &self,
+            _account_id: Uuid,
+            settle_amount: u64,
+        ) -> Result<(), SettlementStoreError> {
+            *self.refunded.write() += settle_amount;
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl SettlementQueueStore for TestStore {
+        async fn save_pending_settlement(
+            &self,
+            settlement: PendingSettlement,
+        ) -> Result<(), SettlementQueueStoreError> {
+            self.pending.write().insert(settlement.id, settlement);
+            Ok(())
+        }
+
+        async fn remove_pending_settlement(
+            &self,
+            id: Uuid,
+        ) -> Result<(), SettlementQueueStoreError> {
+            self.pending.write().remove(&id);
+            Ok(())
+        }
+
+        async fn load_pending_settlements(
+            &self,
+        ) -> Result<Vec<PendingSettlement>, SettlementQueueStoreError> {
+            Ok(self.pending.read().values().cloned().collect())
+        }
+
+        async fn save_pending_message(
+            &self,
+            message: PendingMessage,
+        ) -> Result<(), SettlementQueueStoreError> {
+            self.messages.write().insert(message.id, message);
+            Ok(())
+        }
+
+        async fn remove_pending_message(&self, id: Uuid) -> Result<(), SettlementQueueStoreError> {
+            self.messages.write().remove(&id);
+            Ok(())
+        }
+
+        async fn load_pending_messages(
+            &self,
+        ) -> Result<Vec<PendingMessage>, SettlementQueueStoreError> {
+            Ok(self.messages.read().values().cloned().collect())
+        }
+    }
+
+    #[async_trait]
+    impl SettlementHistoryStore for TestStore {
+        async fn record_settlement(
+            &self,
+            _account_id: Uuid,
+            record: SettlementRecord,
+        ) -> Result<(), SettlementHistoryStoreError> {
+            self.history.write().push(record);
+            Ok(())
+        }
+
+        async fn get_settlement_history(
+            &self,
+            _account_id: Uuid,
+            _from: u64,
+            _to: u64,
+        ) -> Result<Vec<SettlementRecord>, SettlementHistoryStoreError> {
+            Ok(self.history.read().clone())
+        }
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+            max_attempts,
+        }
+    }
+
+    // Each test settles with its own account, since the mock server is shared between them
+    fn mock_settlement(account_id: Uuid, status_code: usize) -> mockito::Mock {
+        mock(
+            "POST",
+            format!("/accounts/{}/settlements", account_id).as_str(),
+        )
+        .match_body(Matcher::Json(
+            serde_json::json!({"amount": "100", "scale": 9}),
+        ))
+        .with_status(status_code)
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            max_attempts: 100,
+        };
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+        assert_eq!(policy.backoff(4), Duration::from_secs(8));
+        assert_eq!(policy.backoff(5), Duration::from_secs(10));
+        assert_eq!(policy.backoff(64), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn sends_scheduled_settlements() {
+        let account_id = Uuid::new_v4();
+        let m = mock_settlement(account_id, 200).create();
+        let store = TestStore::default();
+        let scheduler =
+            SettlementScheduler::start(store.clone(), SettlementClient::default(), policy(3))
+                .await
+                .unwrap();
+
+        scheduler.schedule(account_id, 100).unwrap();
+        assert_eq!(scheduler.pending_settlements()[0].amount, 100);
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+
+        m.assert();
+        assert!(scheduler.pending_settlements().is_empty());
+        assert!(store.pending.read().is_empty());
+        assert_eq!(*store.refunded.read(), 0);
+        let history = store.history.read();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].direction, SettlementDirection::Outgoing);
+        assert_eq!(history[0].amount, 100);
+    }
+
+    #[tokio::test]
+    async fn retries_and_refunds_failed_settlements() {
+        let account_id = Uuid::new_v4();
+        let m = mock_settlement(account_id, 500).expect(3).create();
+        let store = TestStore::default();
+        let scheduler =
+            SettlementScheduler::start(store.clone(), SettlementClient::default(), policy(3))
+                .await
+                .unwrap();
+
+        scheduler.schedule(account_id, 100).unwrap();
+        tokio::time::delay_for(Duration::from_millis(25)).await;
+        // The failed attempt is recorded until the settlement is given up on
+        let pending = store.pending.read().values().cloned().collect::<Vec<_>>();
+        assert_eq!(pending.len(), 1);
+        assert!(pending[0].attempts > 0);
+        assert!(pending[0].last_error.is_some());
+
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+        m.assert();
+        assert!(scheduler.pending_settlements().is_empty());
+        assert!(store.pending.read().is_empty());
+        assert_eq!(*store.refunded.read(), 100);
+        assert_eq!(
+            store.history.read()[0].direction,
+            SettlementDirection::Refunded
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_rejected_settlements() {
+        let account_id = Uuid::new_v4();
+        let m = mock_settlement(account_id, 400).expect(1).create();
+        let store = TestStore::default();
+        let scheduler =
+            SettlementScheduler::start(store.clone(), SettlementClient::default(), policy(3))
+                .await
+                .unwrap();
+
+        scheduler.schedule(account_id, 100).unwrap();
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+
+        m.assert();
+        assert!(store.pending.read().is_empty());
+        assert_eq!(*store.refunded.read(), 100);
+    }
+
+    #[tokio::test]
+    async fn resumes_persisted_settlements() {
+        let account_id = Uuid::new_v4();
+        let settlement = PendingSettlement {
+            id: Uuid::new_v4(),
+            account_id,
+            amount: 100,
+            attempts: 2,
+            next_attempt_at: now_millis(),
+            last_error: Some("engine unavailable".to_string()),
+        };
+        // The settlement is resent with the idempotency key it was first sent with
+        let m = mock_settlement(account_id, 200)
+            .match_header(
+                "Idempotency-Key",
+                settlement.id.to_hyphenated().to_string().as_str(),
+            )
+            .create();
+        let store = TestStore::default();
+        store
+            .pending
+            .write()
+            .insert(settlement.id, settlement.clone());
+
+        let scheduler =
+            SettlementScheduler::start(store.clone(), SettlementClient::default(), policy(3))
+                .await
+                .unwrap();
+        assert_eq!(scheduler.pending_settlements(), vec![settlement]);
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+
+        m.assert();
+        assert!(scheduler.pending_settlements().is_empty());
+        assert!(store.pending.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn delivers_queued_messages_once() {
+        let account_id = Uuid::new_v4();
+        let m = mock(
+            "POST",
+            format!("/accounts/{}/messages", account_id).as_str(),
+        )
+        .match_body("hello")
+        .match_header(
+            "Idempotency-Key",
+            message_id(account_id, b"hello")
+                .to_hyphenated()
+                .to_string()
+                .as_str(),
+        )
+        .with_status(200)
+        .expect(1)
+        .create();
+        let store = TestStore::default();
+        let scheduler =
+            SettlementScheduler::start(store.clone(), SettlementClient::default(), policy(3))
+                .await
+                .unwrap();
+
+        // The peer's engine retrying the message does not queue it twice
+        scheduler
+            .schedule_message(account_id, b"hello".to_vec())
+            .unwrap();
+        scheduler
+            .schedule_message(account_id, b"hello".to_vec())
+            .unwrap();
+        assert_eq!(scheduler.pending_messages().len(), 1);
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+
+        m.assert();
+        assert!(scheduler.pending_messages().is_empty());
+        assert!(store.messages.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resumes_persisted_messages() {
+        let account_id = Uuid::new_v4();
+        let message = PendingMessage {
+            id: Uuid::new_v4(),
+            account_id,
+            message: b"hello".to_vec(),
+            attempts: 1,
+            next_attempt_at: now_millis(),
+            last_error: Some("engine unavailable".to_string()),
+        };
+        let m = mock(
+            "POST",
+            format!("/accounts/{}/messages", account_id).as_str(),
+        )
+        .match_header(
+            "Idempotency-Key",
+            message.id.to_hyphenated().to_string().as_str(),
+        )
+        .with_status(200)
+        .create();
+        let store = TestStore::default();
+        store.messages.write().insert(message.id, message.clone());
+
+        let scheduler =
+            SettlementScheduler::start(store.clone(), SettlementClient::default(), policy(3))
+                .await
+                .unwrap();
+        assert_eq!(scheduler.pending_messages(), vec![message]);
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+
+        m.assert();
+        assert!(store.messages.read().is_empty());
+    }
+}
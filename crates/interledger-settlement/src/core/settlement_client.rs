@@ -1,6 +1,6 @@
-use crate::core::types::Quantity;
+use crate::core::types::{Quantity, SettledTotals};
 use futures_retry::{ErrorHandler, FutureRetry, RetryPolicy};
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use serde_json::json;
 use std::time::Duration;
 use tracing::{debug, trace};
@@ -45,14 +45,32 @@ impl SettlementClient {
     /// This is done by sending a POST to /accounts/:id/messages with the provided `message`
     /// as the request's body
     pub async fn send_message(&self, id: Uuid, engine_url: Url, message: Vec<u8>) -> Response {
+        // Every retry uses the same key, so that the engine handles the message only once
+        let idempotency_key = Uuid::new_v4();
         FutureRetry::new(
-            move || self.send_message_once(id, engine_url.clone(), message.clone()),
+            move || {
+                self.send_message_with_idempotency_key(
+                    id,
+                    engine_url.clone(),
+                    message.clone(),
+                    idempotency_key,
+                )
+            },
             RequestErrorHandler::new(self.max_retries),
         )
         .await
     }
 
-    async fn send_message_once(&self, id: Uuid, engine_url: Url, message: Vec<u8>) -> Response {
+    /// Sends a single message request to the engine with the given idempotency key.
+    /// Unlike settlement requests, responses with an error status are not turned into errors,
+    /// since the engine's response is relayed to the peer's engine
+    pub async fn send_message_with_idempotency_key(
+        &self,
+        id: Uuid,
+        engine_url: Url,
+        message: Vec<u8>,
+        idempotency_key: Uuid,
+    ) -> Response {
         // The `Prepare` packet's data was sent by the peer's settlement
         // engine so we assume it is in a format that our settlement engine
         // will understand
@@ -64,12 +82,14 @@ impl SettlementClient {
             .push("accounts")
             .push(&id.to_string())
             .push("messages");
-        let idempotency_uuid = uuid::Uuid::new_v4().to_hyphenated().to_string();
         self.client
             .post(settlement_engine_url.as_ref())
             .header("Content-Type", "application/octet-stream")
-            .header("Idempotency-Key", idempotency_uuid.clone())
-            .body(message.clone())
+            .header(
+                "Idempotency-Key",
+                idempotency_key.to_hyphenated().to_string(),
+            )
+            .body(message)
             .send()
             .await
     }
@@ -118,6 +138,28 @@ impl SettlementClient {
         engine_url: Url,
         amount: u64,
         asset_scale: u8,
+    ) -> Response {
+        // Mark the request as idempotent
+        let idempotency_uuid = Uuid::new_v4();
+        self.send_settlement_with_idempotency_key(
+            id,
+            engine_url,
+            amount,
+            asset_scale,
+            idempotency_uuid,
+        )
+        .await
+    }
+
+    /// Sends a single settlement request to the engine with the given idempotency key.
+    /// Retrying it with the same key does not make the engine settle more than once
+    pub async fn send_settlement_with_idempotency_key(
+        &self,
+        id: Uuid,
+        engine_url: Url,
+        amount: u64,
+        asset_scale: u8,
+        idempotency_key: Uuid,
     ) -> Response {
         let mut settlement_engine_url = engine_url;
 
@@ -133,20 +175,59 @@ impl SettlementClient {
             amount, settlement_engine_url
         );
 
-        // Mark the request as idempotent
-        let idempotency_uuid = Uuid::new_v4().to_hyphenated().to_string();
-
         // Make the POST request future
         let response = self
             .client
             .post(settlement_engine_url.as_ref())
-            .header("Idempotency-Key", idempotency_uuid)
+            .header(
+                "Idempotency-Key",
+                idempotency_key.to_hyphenated().to_string(),
+            )
             .json(&json!(Quantity::new(amount, asset_scale)))
             .send()
             .await?;
 
         Ok(response.error_for_status()?)
     }
+
+    /// Fetches the amounts the engine settled with the peer of the account since `from`
+    /// (in milliseconds since the Unix epoch).
+    /// This is done by sending a GET to /accounts/:id/settlements/totals?from=:from, which is
+    /// not part of the settlement engine RFC: `None` is returned if the engine does not support it
+    pub async fn get_settled_totals(
+        &self,
+        id: Uuid,
+        engine_url: Url,
+        from: u64,
+    ) -> Result<Option<SettledTotals>, reqwest::Error> {
+        let mut settlement_engine_url = engine_url;
+        settlement_engine_url
+            .path_segments_mut()
+            .expect("Invalid settlement engine URL")
+            .push(ACCOUNTS_ENDPOINT)
+            .push(&id.to_string())
+            .push("settlements")
+            .push("totals");
+        settlement_engine_url
+            .query_pairs_mut()
+            .append_pair("from", &from.to_string());
+        trace!(
+            "Fetching settled totals from settlement engine: {}",
+            settlement_engine_url
+        );
+
+        let response = self
+            .client
+            .get(settlement_engine_url.as_ref())
+            .send()
+            .await?;
+        match response.status() {
+            StatusCode::NOT_FOUND
+            | StatusCode::METHOD_NOT_ALLOWED
+            | StatusCode::NOT_IMPLEMENTED => Ok(None),
+            _ => response.error_for_status()?.json().await.map(Some),
+        }
+    }
 }
 
 struct RequestErrorHandler {
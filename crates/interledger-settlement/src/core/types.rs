@@ -2,7 +2,10 @@ use async_trait::async_trait;
 use bytes::Bytes;
 use http::StatusCode;
 use interledger_errors::{ApiError, ApiErrorType, ProblemType};
-use interledger_errors::{LeftoversStoreError, SettlementStoreError};
+use interledger_errors::{
+    LeftoversStoreError, SettlementHistoryStoreError, SettlementQueueStoreError,
+    SettlementStoreError,
+};
 use interledger_packet::Address;
 use interledger_service::Account;
 use num_bigint::BigUint;
@@ -121,12 +124,16 @@ pub trait SettlementStore {
     /// then no database operation must happen. If there is an idempotency
     /// conflict (same idempotency key, different inputs to function) then
     /// it should return an error
+    ///
+    /// Only as much is credited as keeps the account's balance within an i64
+    /// (see [`credit_settlement`](../amount/fn.credit_settlement.html)). Returns the
+    /// part of the amount which was not credited, for the caller to keep
     async fn update_balance_for_incoming_settlement(
         &self,
         account_id: Uuid,
         amount: u64,
         idempotency_key: Option<String>,
-    ) -> Result<(), SettlementStoreError>;
+    ) -> Result<u64, SettlementStoreError>;
 
     /// Increases the account's balance by the provided amount.
     /// Only call this if a settlement request has failed
@@ -137,6 +144,138 @@ pub trait SettlementStore {
     ) -> Result<(), SettlementStoreError>;
 }
 
+/// An outgoing settlement which was queued by the
+/// [`SettlementScheduler`](../struct.SettlementScheduler.html)
+/// but was not yet accepted by the account's settlement engine
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct PendingSettlement {
+    /// Sent as the Idempotency-Key of every request for this settlement, so that
+    /// the engine does not settle twice if a retried request had already gone through
+    pub id: Uuid,
+    /// The account being settled with
+    pub account_id: Uuid,
+    /// Amount to settle, in the account's asset scale
+    pub amount: u64,
+    /// Number of requests to the settlement engine which failed so far
+    pub attempts: u32,
+    /// When the next request will be sent, in milliseconds since the Unix epoch
+    pub next_attempt_at: u64,
+    /// The error of the last failed request
+    pub last_error: Option<String>,
+}
+
+/// A message from a peer's settlement engine which could not be delivered to the
+/// account's settlement engine and is being retried by the
+/// [`SettlementScheduler`](../struct.SettlementScheduler.html)
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct PendingMessage {
+    /// Sent as the Idempotency-Key of every request for this message
+    pub id: Uuid,
+    /// The account whose settlement engine the message is for
+    pub account_id: Uuid,
+    /// The message, as sent by the peer's settlement engine
+    pub message: Vec<u8>,
+    /// Number of requests to the settlement engine which failed so far
+    pub attempts: u32,
+    /// When the next request will be sent, in milliseconds since the Unix epoch
+    pub next_attempt_at: u64,
+    /// The error of the last failed request
+    pub last_error: Option<String>,
+}
+
+/// Trait used by the settlement scheduler to persist the settlements and messages which
+/// were not yet accepted by the settlement engines (its outbox), so that they are resumed
+/// after a restart
+#[async_trait]
+pub trait SettlementQueueStore {
+    /// Saves the settlement, replacing any previously saved state with the same id
+    async fn save_pending_settlement(
+        &self,
+        settlement: PendingSettlement,
+    ) -> Result<(), SettlementQueueStoreError>;
+
+    /// Removes the settlement once it was either sent or given up on
+    async fn remove_pending_settlement(&self, id: Uuid) -> Result<(), SettlementQueueStoreError>;
+
+    /// Loads all of the saved settlements
+    async fn load_pending_settlements(
+        &self,
+    ) -> Result<Vec<PendingSettlement>, SettlementQueueStoreError>;
+
+    /// Saves the message, replacing any previously saved state with the same id
+    async fn save_pending_message(
+        &self,
+        message: PendingMessage,
+    ) -> Result<(), SettlementQueueStoreError>;
+
+    /// Removes the message once it was either delivered or given up on
+    async fn remove_pending_message(&self, id: Uuid) -> Result<(), SettlementQueueStoreError>;
+
+    /// Loads all of the saved messages
+    async fn load_pending_messages(&self)
+        -> Result<Vec<PendingMessage>, SettlementQueueStoreError>;
+}
+
+/// Number of settlements kept in the history of each account, after which the oldest ones are dropped
+pub const SETTLEMENT_HISTORY_CAPACITY: usize = 1000;
+
+/// Whether a settlement was received from or sent to the peer
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SettlementDirection {
+    /// The peer's settlement engine notified us of a settlement received from the peer
+    Incoming,
+    /// Our settlement engine accepted a settlement to the peer
+    Outgoing,
+    /// An outgoing settlement was given up on and its amount was credited back to the account
+    Refunded,
+}
+
+/// A settlement in the history of an account
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct SettlementRecord {
+    /// When the settlement was recorded, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Whether the settlement was received, sent or refunded
+    pub direction: SettlementDirection,
+    /// Amount of the settlement, in the account's asset scale
+    pub amount: u64,
+    /// The idempotency key of the settlement, if it had one
+    pub id: Option<String>,
+}
+
+/// The amounts a settlement engine settled with the peer of an account, as returned
+/// by `GET /accounts/:id/settlements/totals`
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct SettledTotals {
+    /// Total of the settlements sent to the peer
+    pub sent: Quantity,
+    /// Total of the settlements received from the peer
+    pub received: Quantity,
+}
+
+/// Trait used to keep a bounded log of the settlements of each account, so that
+/// operators can audit how the balances with their peers evolved
+#[async_trait]
+pub trait SettlementHistoryStore {
+    /// Appends the settlement to the account's history, dropping the records beyond the
+    /// [`SETTLEMENT_HISTORY_CAPACITY`](./constant.SETTLEMENT_HISTORY_CAPACITY.html) most recent ones
+    async fn record_settlement(
+        &self,
+        account_id: Uuid,
+        record: SettlementRecord,
+    ) -> Result<(), SettlementHistoryStoreError>;
+
+    /// Loads the settlements of the account recorded between `from` and `to` (inclusive,
+    /// in milliseconds since the Unix epoch), oldest first
+    async fn get_settlement_history(
+        &self,
+        account_id: Uuid,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<SettlementRecord>, SettlementHistoryStoreError>;
+}
+
 /// Trait used by the connector and engine to track amounts which should have been
 /// settled but were not due to precision loss
 #[async_trait]
@@ -193,7 +332,7 @@ pub struct ConvertDetails {
     pub to: u8,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ConversionError;
 
 impl fmt::Display for ConversionError {
@@ -216,17 +355,33 @@ impl Convert for u64 {
     type Item = u64;
 
     fn normalize_scale(&self, details: ConvertDetails) -> Result<Self::Item, ConversionError> {
-        let scale_diff = (details.from as i8 - details.to as i8).abs() as u8;
-        let scale = 10u64.pow(scale_diff.into());
-        let (res, overflow) = if details.to >= details.from {
-            self.overflowing_mul(scale)
-        } else {
-            self.overflowing_div(scale)
-        };
-        if overflow {
-            Err(ConversionError)
-        } else {
-            Ok(res)
+        let scale_diff = (details.from as i16 - details.to as i16).unsigned_abs() as u32;
+        match 10u64.checked_pow(scale_diff) {
+            Some(scale) if details.to >= details.from => {
+                self.checked_mul(scale).ok_or(ConversionError)
+            }
+            Some(scale) => Ok(self / scale),
+            // Any non-zero amount overflows when upscaled by more than 10^19
+            // and is truncated to zero when downscaled by more than that
+            None if details.to >= details.from && *self != 0 => Err(ConversionError),
+            None => Ok(0),
+        }
+    }
+}
+
+impl Convert for u128 {
+    type Item = u128;
+
+    fn normalize_scale(&self, details: ConvertDetails) -> Result<Self::Item, ConversionError> {
+        let scale_diff = (details.from as i16 - details.to as i16).unsigned_abs() as u32;
+        match 10u128.checked_pow(scale_diff) {
+            Some(scale) if details.to >= details.from => {
+                self.checked_mul(scale).ok_or(ConversionError)
+            }
+            Some(scale) => Ok(self / scale),
+            // Same as for u64, with 10^38 being the largest power of 10 which fits
+            None if details.to >= details.from && *self != 0 => Err(ConversionError),
+            None => Ok(0),
         }
     }
 }
@@ -250,12 +405,12 @@ impl Convert for BigUint {
     type Item = BigUint;
 
     fn normalize_scale(&self, details: ConvertDetails) -> Result<Self::Item, ConversionError> {
-        let scale_diff = (details.from as i8 - details.to as i8).abs() as u8;
-        let scale = 10u64.pow(scale_diff.into());
+        let scale_diff = (details.from as i16 - details.to as i16).unsigned_abs() as u32;
+        let scale = num_traits::pow(BigUint::from(10u32), scale_diff as usize);
         if details.to >= details.from {
-            Ok(self.mul(scale))
+            Ok(self.mul(&scale))
         } else {
-            Ok(self.div(scale))
+            Ok(self.div(&scale))
         }
     }
 }
@@ -277,6 +432,20 @@ mod tests {
                 .to_string(),
             BigUint::from_u64(100u64).unwrap().to_string(),
         );
+        // Scale differences larger than the powers of 10 which fit in a u64
+        assert_eq!(
+            BigUint::from(1u32)
+                .normalize_scale(ConvertDetails { from: 0, to: 24 })
+                .unwrap()
+                .to_string(),
+            "1000000000000000000000000",
+        );
+        assert_eq!(
+            BigUint::from(1u32)
+                .normalize_scale(ConvertDetails { from: 24, to: 0 })
+                .unwrap(),
+            BigUint::from(0u32),
+        );
     }
 
     #[test]
@@ -286,6 +455,21 @@ mod tests {
         assert!(huge_number
             .normalize_scale(ConvertDetails { from: 1, to: 18 })
             .is_err(),);
+        assert!(1u64
+            .normalize_scale(ConvertDetails { from: 0, to: 20 })
+            .is_err(),);
+        // scale differences which overflow the power of 10 do not panic
+        assert_eq!(
+            0u64.normalize_scale(ConvertDetails { from: 0, to: 20 })
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            u64::MAX
+                .normalize_scale(ConvertDetails { from: 20, to: 0 })
+                .unwrap(),
+            0
+        );
         // 1 unit with scale 1, is 1 unit with scale 1
         assert_eq!(
             1u64.normalize_scale(ConvertDetails { from: 1, to: 1 })
@@ -1,19 +1,22 @@
+use super::payment_pointer::receiver_to_url;
 use super::{Error, SpspResponse};
 use futures::TryFutureExt;
 use interledger_rates::ExchangeRateStore;
 use interledger_service::{Account, IncomingService};
-use interledger_stream::{send_money, StreamDelivery};
+use interledger_stream::{send_money, send_money_with_options, SendMoneyOptions, StreamDelivery};
 use reqwest::Client;
 use tracing::{debug, error, trace};
 
 /// Get an ILP Address and shared secret by the receiver of this payment for this connection
-pub async fn query(server: &str) -> Result<SpspResponse, Error> {
-    let server = payment_pointer_to_url(server);
+///
+/// The receiver may be given either as a payment pointer (`$example.com/bob`) or as the URL of the SPSP server.
+pub async fn query(receiver: &str) -> Result<SpspResponse, Error> {
+    let server = receiver_to_url(receiver)?;
     trace!("Querying receiver: {}", server);
 
     let client = Client::new();
     let res = client
-        .get(&server)
+        .get(server)
         .header("Accept", "application/spsp4+json")
         .send()
         .map_err(|err| Error::HttpError(format!("Error querying SPSP receiver: {:?}", err)))
@@ -68,29 +71,54 @@ where
     Ok(receipt)
 }
 
-fn payment_pointer_to_url(payment_pointer: &str) -> String {
-    let mut url: String = if let Some(suffix) = payment_pointer.strip_prefix("$") {
-        let prefix = "https://";
-        let mut url = String::with_capacity(prefix.len() + suffix.len());
-        url.push_str(prefix);
-        url.push_str(suffix);
-        url
-    } else {
-        payment_pointer.to_string()
-    };
-
-    let num_slashes = url.matches('/').count();
-    if num_slashes == 2 {
-        url.push_str("/.well-known/pay");
-    } else if num_slashes == 1 && url.ends_with('/') {
-        url.push_str(".well-known/pay");
-    }
-    trace!(
-        "Converted payment pointer: {} to URL: {}",
-        payment_pointer,
-        url
+/// Query the details of the given Payment Pointer and deliver it the given amount, in the
+/// receiver's units, using the STREAM protocol (e.g. to pay an invoice).
+///
+/// At most `max_source_amount` is sent. This returns an error if it is not enough to deliver
+/// the amount.
+pub async fn pay_to_deliver<I, A, S>(
+    service: I,
+    from_account: A,
+    store: S,
+    receiver: &str,
+    destination_amount: u64,
+    max_source_amount: u64,
+    slippage: f64,
+) -> Result<StreamDelivery, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
+    let spsp = query(receiver).await?;
+    debug!(
+        "Delivering {} with SPSP to address: {}",
+        destination_amount, spsp.destination_account
     );
-    url
+
+    let receipt = send_money_with_options(
+        service,
+        &from_account,
+        store,
+        spsp.destination_account,
+        spsp.shared_secret,
+        max_source_amount,
+        SendMoneyOptions {
+            slippage,
+            destination_amount: Some(destination_amount),
+            ..Default::default()
+        },
+    )
+    // The rate probe and delivery failures are kept apart, unlike in `pay`, so that callers
+    // can tell an unreachable receiver from an insufficient `max_source_amount`
+    .map_err(|err| {
+        error!("Error delivering payment: {:?}", err);
+        Error::StreamError(err)
+    })
+    .await?;
+
+    debug!("Delivered SPSP payment. StreamDelivery: {:?}", receipt);
+    Ok(receipt)
 }
 
 #[cfg(test)]
@@ -101,7 +129,7 @@ mod payment_pointer {
     fn converts_pointer() {
         let pointer = "$subdomain.domain.example";
         assert_eq!(
-            payment_pointer_to_url(pointer),
+            receiver_to_url(pointer).unwrap().as_str(),
             "https://subdomain.domain.example/.well-known/pay"
         );
     }
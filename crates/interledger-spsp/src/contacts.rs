@@ -0,0 +1,128 @@
+use super::client::pay;
+use super::payment_pointer::receiver_to_url;
+use super::Error;
+use async_trait::async_trait;
+use interledger_errors::ContactStoreError;
+use interledger_rates::ExchangeRateStore;
+use interledger_service::{Account, IncomingService};
+use interledger_stream::StreamDelivery;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use uuid::Uuid;
+
+/// Maximum length of a contact's name
+const MAX_CONTACT_NAME_LENGTH: usize = 64;
+
+/// A receiver an account saved under a human-friendly name, so that it can pay it by name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    /// Name of the contact, unique among the contacts of the account. Made of ASCII
+    /// letters, digits, `_`, `-` and `.`, so that it can be used in URL paths as-is
+    pub name: String,
+    /// Payment pointer (or SPSP server URL) of the contact
+    pub payment_pointer: String,
+}
+
+impl Contact {
+    /// Creates a contact, checking that the name is valid and that the payment pointer
+    /// resolves to an SPSP server URL
+    pub fn new(name: String, payment_pointer: String) -> Result<Self, Error> {
+        let is_valid_name = !name.is_empty()
+            && name.len() <= MAX_CONTACT_NAME_LENGTH
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || b == b'.');
+        if !is_valid_name {
+            return Err(Error::InvalidContactNameError(name));
+        }
+        receiver_to_url(&payment_pointer)?;
+        Ok(Contact {
+            name,
+            payment_pointer,
+        })
+    }
+}
+
+/// Store trait which persists the contacts of each account
+#[async_trait]
+pub trait ContactStore {
+    /// Saves the contact for the given account, replacing any contact with the same name
+    async fn save_contact(
+        &self,
+        account_id: Uuid,
+        contact: Contact,
+    ) -> Result<(), ContactStoreError>;
+
+    /// Loads all of the contacts of the given account, sorted by name
+    async fn get_contacts(&self, account_id: Uuid) -> Result<Vec<Contact>, ContactStoreError>;
+
+    /// Loads the contact of the given account with the given name
+    async fn get_contact(&self, account_id: Uuid, name: &str)
+        -> Result<Contact, ContactStoreError>;
+
+    /// Deletes the contact of the given account with the given name
+    async fn delete_contact(&self, account_id: Uuid, name: &str) -> Result<(), ContactStoreError>;
+}
+
+/// Look up the contact of the sending account with the given name, then query its payment
+/// pointer and send it a payment, like [`pay`](./fn.pay.html)
+pub async fn pay_contact<I, A, S>(
+    service: I,
+    from_account: A,
+    store: S,
+    name: &str,
+    source_amount: u64,
+    slippage: f64,
+) -> Result<StreamDelivery, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ContactStore + ExchangeRateStore + Send + Sync + 'static,
+{
+    let contact = store.get_contact(from_account.id(), name).await?;
+    debug!(
+        "Paying contact {} at {}",
+        contact.name, contact.payment_pointer
+    );
+    pay(
+        service,
+        from_account,
+        store,
+        &contact.payment_pointer,
+        source_amount,
+        slippage,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_contacts() {
+        assert!(Contact::new("alice".to_string(), "$example.com/alice".to_string()).is_ok());
+        assert!(Contact::new(
+            "bob.smith-2".to_string(),
+            "https://example.com/spsp/bob".to_string()
+        )
+        .is_ok());
+
+        assert!(matches!(
+            Contact::new("".to_string(), "$example.com/alice".to_string()),
+            Err(Error::InvalidContactNameError(_))
+        ));
+        assert!(matches!(
+            Contact::new("alice/bob".to_string(), "$example.com/alice".to_string()),
+            Err(Error::InvalidContactNameError(_))
+        ));
+        assert!(matches!(
+            Contact::new("a".repeat(65), "$example.com/alice".to_string()),
+            Err(Error::InvalidContactNameError(_))
+        ));
+        assert!(matches!(
+            Contact::new("alice".to_string(), "example.com/alice".to_string()),
+            Err(Error::InvalidPaymentPointerError(_))
+        ));
+    }
+}
@@ -5,17 +5,26 @@
 //! This uses a simple HTTPS request to establish a shared key between the sender and receiver that is used to
 //! authenticate ILP packets sent between them. SPSP uses the STREAM transport protocol for sending money and data over ILP.
 
+use interledger_errors::ContactStoreError;
 use interledger_packet::Address;
-use interledger_stream::Error as StreamError;
+use interledger_stream::{
+    Error as StreamError, ReceiptDetails, RECEIPT_NONCE_LEN, RECEIPT_SECRET_LEN,
+};
 use serde::{Deserialize, Serialize};
 
 /// An SPSP client which can query an SPSP Server's payment pointer and initiate a STREAM payment
 mod client;
+/// Contacts mapping human-friendly names to payment pointers, which can be paid by name
+mod contacts;
+/// Payment pointer parsing and resolution to SPSP server URLs
+mod payment_pointer;
 /// An SPSP Server implementing an HTTP Service which generates ILP Addresses and Shared Secrets
 mod server;
 
-pub use client::{pay, query};
-pub use server::SpspResponder;
+pub use client::{pay, pay_to_deliver, query};
+pub use contacts::{pay_contact, Contact, ContactStore};
+pub use payment_pointer::PaymentPointer;
+pub use server::{InvoiceLookup, SpspInvoice, SpspResponder};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -31,6 +40,60 @@ pub enum Error {
     ListenError(String),
     #[error("Invalid Payment Pointer: {0}")]
     InvalidPaymentPointerError(String),
+    #[error("Invalid contact name: {0}")]
+    InvalidContactNameError(String),
+    #[error("Contact store error: {0}")]
+    ContactStoreError(#[from] ContactStoreError),
+    #[error("Invalid receipt headers: {0}")]
+    InvalidReceiptHeaders(&'static str),
+}
+
+/// Header carrying the base-64 encoded nonce of the STREAM receipts, set by the
+/// [receipt verifier](https://interledger.org/rfcs/0039-stream-receipts/) proxying the query
+pub const RECEIPT_NONCE_HEADER: &str = "Receipt-Nonce";
+/// Header carrying the base-64 encoded secret the STREAM receipts are signed with
+pub const RECEIPT_SECRET_HEADER: &str = "Receipt-Secret";
+
+/// Parses the receipt details a verifier adds to the SPSP queries it proxies. Returns
+/// `None` if the query has neither of the receipt headers
+pub fn receipt_details(headers: &hyper::HeaderMap) -> Result<Option<ReceiptDetails>, Error> {
+    let header = |name: &str, len: usize| -> Result<Option<Vec<u8>>, Error> {
+        match headers.get(name) {
+            Some(value) => {
+                let value = value
+                    .to_str()
+                    .map_err(|_| Error::InvalidReceiptHeaders("headers must be base-64"))?;
+                let bytes = base64::decode(value.trim())
+                    .map_err(|_| Error::InvalidReceiptHeaders("headers must be base-64"))?;
+                if bytes.len() == len {
+                    Ok(Some(bytes))
+                } else {
+                    Err(Error::InvalidReceiptHeaders(
+                        "nonce must be 16 bytes and secret 32 bytes",
+                    ))
+                }
+            }
+            None => Ok(None),
+        }
+    };
+    match (
+        header(RECEIPT_NONCE_HEADER, RECEIPT_NONCE_LEN)?,
+        header(RECEIPT_SECRET_HEADER, RECEIPT_SECRET_LEN)?,
+    ) {
+        (Some(nonce_bytes), Some(secret_bytes)) => {
+            let mut details = ReceiptDetails {
+                nonce: [0; RECEIPT_NONCE_LEN],
+                secret: [0; RECEIPT_SECRET_LEN],
+            };
+            details.nonce.copy_from_slice(&nonce_bytes);
+            details.secret.copy_from_slice(&secret_bytes);
+            Ok(Some(details))
+        }
+        (None, None) => Ok(None),
+        _ => Err(Error::InvalidReceiptHeaders(
+            "nonce and secret must be provided together",
+        )),
+    }
 }
 
 /// An SPSP Response returned by the SPSP server
@@ -42,6 +105,42 @@ pub struct SpspResponse {
     /// to be consumed for the STREAM connection
     #[serde(with = "serde_base64")]
     shared_secret: Vec<u8>,
+    /// The amounts of the invoice the connection pays, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    balance: Option<SpspBalance>,
+}
+
+/// The amounts of an invoice, in the receiver's units
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct SpspBalance {
+    /// The amount the invoice expects
+    #[serde(with = "serde_string")]
+    pub maximum: u64,
+    /// The amount already paid
+    #[serde(with = "serde_string")]
+    pub current: u64,
+}
+
+/// Amounts are sent as strings, since they may not fit in a JavaScript number
+#[doc(hidden)]
+mod serde_string {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(amount: &u64, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(amount)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<u64, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        <&str>::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
 }
 
 // From https://github.com/serde-rs/json/issues/360#issuecomment-330095360
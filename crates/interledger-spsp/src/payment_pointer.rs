@@ -0,0 +1,182 @@
+//! Payment pointer types.
+//!
+//! Reference: [Payment Pointers](https://paymentpointers.org/syntax-resolution/).
+
+use super::Error;
+use std::fmt;
+use std::str::FromStr;
+use url::{Host, Url};
+
+const WELL_KNOWN_PATH: &str = "/.well-known/pay";
+
+/// A payment pointer such as `$example.com/bob`, which resolves to the
+/// HTTPS URL of an SPSP server.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PaymentPointer {
+    host: String,
+    path: String,
+}
+
+impl PaymentPointer {
+    /// Returns the host part of the payment pointer.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Returns the path of the payment pointer, or `None` if it has none
+    /// and therefore resolves to the well-known path.
+    pub fn path(&self) -> Option<&str> {
+        if self.path.is_empty() {
+            None
+        } else {
+            Some(&self.path)
+        }
+    }
+
+    /// Converts the payment pointer into the HTTPS URL which should be queried
+    /// for the SPSP details.
+    pub fn to_url(&self) -> Url {
+        let path = self.path().unwrap_or(WELL_KNOWN_PATH);
+        // The host and path were validated when the pointer was parsed
+        Url::parse(&format!("https://{}{}", self.host, path)).unwrap()
+    }
+}
+
+impl FromStr for PaymentPointer {
+    type Err = Error;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            |reason: &str| Error::InvalidPaymentPointerError(format!("{} ({})", reason, src));
+
+        let rest = src
+            .strip_prefix('$')
+            .ok_or_else(|| invalid("must start with '$'"))?;
+        let (host, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        if host.contains(&['@', ':'][..]) {
+            return Err(invalid("host must not contain userinfo or a port"));
+        }
+        if path.contains(&['?', '#'][..]) {
+            return Err(invalid("must not contain a query or fragment"));
+        }
+        // A trailing slash alone also resolves to the well-known path
+        let path = if path == "/" { "" } else { path };
+
+        let url = Url::parse(&format!("https://{}{}", host, path))
+            .map_err(|err| invalid(&err.to_string()))?;
+        match url.host() {
+            Some(Host::Domain(_)) => {}
+            _ => return Err(invalid("host must be a domain name")),
+        }
+
+        Ok(PaymentPointer {
+            host: url.host_str().unwrap_or_default().to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for PaymentPointer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${}{}", self.host, self.path)
+    }
+}
+
+/// Resolves the receiver given to the SPSP client, which may be either a payment
+/// pointer or the URL of the SPSP server, to the URL to query.
+pub(crate) fn receiver_to_url(receiver: &str) -> Result<Url, Error> {
+    if receiver.starts_with('$') {
+        return Ok(receiver.parse::<PaymentPointer>()?.to_url());
+    }
+
+    let mut url = Url::parse(receiver).map_err(|err| {
+        Error::InvalidPaymentPointerError(format!("{} is not a valid URL: {}", receiver, err))
+    })?;
+    if url.path() == "/" {
+        url.set_path(WELL_KNOWN_PATH);
+    }
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pointer_without_path() {
+        let pointer: PaymentPointer = "$example.com".parse().unwrap();
+        assert_eq!(pointer.host(), "example.com");
+        assert_eq!(pointer.path(), None);
+        assert_eq!(pointer.to_string(), "$example.com");
+        assert_eq!(
+            pointer.to_url().as_str(),
+            "https://example.com/.well-known/pay"
+        );
+    }
+
+    #[test]
+    fn parses_pointer_with_trailing_slash() {
+        let pointer: PaymentPointer = "$example.com/".parse().unwrap();
+        assert_eq!(pointer.path(), None);
+        assert_eq!(
+            pointer.to_url().as_str(),
+            "https://example.com/.well-known/pay"
+        );
+    }
+
+    #[test]
+    fn parses_pointer_with_path() {
+        let pointer: PaymentPointer = "$example.com/bob".parse().unwrap();
+        assert_eq!(pointer.host(), "example.com");
+        assert_eq!(pointer.path(), Some("/bob"));
+        assert_eq!(pointer.to_url().as_str(), "https://example.com/bob");
+    }
+
+    #[test]
+    fn rejects_invalid_pointers() {
+        for pointer in &[
+            "example.com",
+            "$",
+            "$/bob",
+            "$example.com:8080/bob",
+            "$alice@example.com",
+            "$example.com/bob?x=y",
+            "$example.com/bob#x",
+            "$127.0.0.1/bob",
+        ] {
+            assert!(
+                pointer.parse::<PaymentPointer>().is_err(),
+                "{} should be invalid",
+                pointer
+            );
+        }
+    }
+
+    #[test]
+    fn resolves_urls_and_pointers() {
+        assert_eq!(
+            receiver_to_url("$subdomain.domain.example")
+                .unwrap()
+                .as_str(),
+            "https://subdomain.domain.example/.well-known/pay"
+        );
+        assert_eq!(
+            receiver_to_url("http://localhost:7770").unwrap().as_str(),
+            "http://localhost:7770/.well-known/pay"
+        );
+        assert_eq!(
+            receiver_to_url("http://localhost:7770/accounts/bob/spsp")
+                .unwrap()
+                .as_str(),
+            "http://localhost:7770/accounts/bob/spsp"
+        );
+        assert!(receiver_to_url("not a url").is_err());
+    }
+}
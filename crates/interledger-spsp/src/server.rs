@@ -1,14 +1,54 @@
-use super::SpspResponse;
+use super::{receipt_details, SpspBalance, SpspResponse};
+use async_trait::async_trait;
 use bytes::Bytes;
+use futures::future::{BoxFuture, FutureExt};
 use hyper::{service::Service as HttpService, Body, Error, Request, Response};
 use interledger_packet::Address;
-use interledger_stream::ConnectionGenerator;
+use interledger_stream::{ConnectionGenerator, ReceiptDetails};
 use std::error::Error as StdError;
 use std::{
-    fmt, str,
+    fmt,
+    future::Future,
+    str,
+    sync::Arc,
     task::{Context, Poll},
 };
-use tracing::debug;
+use tracing::{debug, error};
+
+/// The details of an invoice of an external system, which the SPSP responder embeds in the
+/// responses to the queries for the invoice's path
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpspInvoice {
+    /// Tags the connections generated for the invoice, so that the payments received over
+    /// them can be attributed to it. Replaces the responder's connection tag
+    pub connection_tag: Option<String>,
+    /// The amount the invoice expects, and the amount already paid
+    pub balance: Option<SpspBalance>,
+}
+
+/// Looks up the invoices the SPSP queries are for
+#[async_trait]
+pub trait InvoiceLookup: Send + Sync + 'static {
+    /// Returns the invoice of the request path, or `None` if there is no such invoice
+    async fn lookup_invoice(
+        &self,
+        path: &str,
+    ) -> Result<Option<SpspInvoice>, Box<dyn StdError + Send + Sync>>;
+}
+
+#[async_trait]
+impl<F, Fut> InvoiceLookup for F
+where
+    F: Fn(String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Option<SpspInvoice>, Box<dyn StdError + Send + Sync>>> + Send,
+{
+    async fn lookup_invoice(
+        &self,
+        path: &str,
+    ) -> Result<Option<SpspInvoice>, Box<dyn StdError + Send + Sync>> {
+        self(path.to_string()).await
+    }
+}
 
 /// A Hyper::Service that responds to incoming SPSP Query requests with newly generated
 /// details for a STREAM connection.
@@ -16,6 +56,8 @@ use tracing::debug;
 pub struct SpspResponder {
     ilp_address: Address,
     connection_generator: ConnectionGenerator,
+    connection_tag: Option<String>,
+    invoice_lookup: Option<Arc<dyn InvoiceLookup>>,
 }
 
 impl SpspResponder {
@@ -25,16 +67,111 @@ impl SpspResponder {
         SpspResponder {
             ilp_address,
             connection_generator,
+            connection_tag: None,
+            invoice_lookup: None,
         }
     }
 
+    /// Tags the generated connections, so that the payments received over them can be
+    /// told apart (see [Stream's `connection_tag`](../interledger_stream/fn.connection_tag.html))
+    pub fn with_connection_tag(mut self, connection_tag: String) -> Self {
+        self.connection_tag = Some(connection_tag);
+        self
+    }
+
+    /// Looks up the invoice of each query's path before responding to it. The queries for
+    /// the paths without any invoice are refused with a 404 response
+    pub fn with_invoice_lookup<L: InvoiceLookup>(mut self, invoice_lookup: L) -> Self {
+        self.invoice_lookup = Some(Arc::new(invoice_lookup));
+        self
+    }
+
     /// Returns an HTTP Response containing the destination account
     /// and shared secret for this connection
     /// These fields are generated via [Stream's `ConnectionGenerator`](../interledger_stream/struct.ConnectionGenerator.html#method.generate_address_and_secret)
     pub fn generate_http_response(&self) -> Response<Body> {
-        let (destination_account, shared_secret) = self
-            .connection_generator
-            .generate_address_and_secret(&self.ilp_address);
+        self.generate_response(self.connection_tag.as_deref(), None, None)
+    }
+
+    /// Returns an HTTP Response like [`generate_http_response`](#method.generate_http_response),
+    /// for a query proxied by a receipt verifier. The receipts of the amounts received over
+    /// the connection are signed with the verifier's details, which are sealed into the
+    /// generated address rather than returned to the sender
+    pub fn generate_http_response_with_receipts(
+        &self,
+        receipt_details: &ReceiptDetails,
+    ) -> Response<Body> {
+        self.generate_response(self.connection_tag.as_deref(), None, Some(receipt_details))
+    }
+
+    /// Returns the HTTP Response to a query for the given path. If the responder has an
+    /// invoice lookup, the response embeds the details of the path's invoice
+    pub async fn generate_http_response_for_path(
+        &self,
+        path: &str,
+        receipt_details: Option<&ReceiptDetails>,
+    ) -> Response<Body> {
+        let invoice_lookup = match self.invoice_lookup {
+            Some(ref invoice_lookup) => invoice_lookup,
+            None => {
+                return self.generate_response(
+                    self.connection_tag.as_deref(),
+                    None,
+                    receipt_details,
+                )
+            }
+        };
+        match invoice_lookup.lookup_invoice(path).await {
+            Ok(Some(invoice)) => {
+                let tag = invoice
+                    .connection_tag
+                    .as_deref()
+                    .or_else(|| self.connection_tag.as_deref());
+                self.generate_response(tag, Some(&invoice), receipt_details)
+            }
+            Ok(None) => {
+                debug!("No invoice found for SPSP query to path: {}", path);
+                Response::builder()
+                    .status(404)
+                    .body(Body::from("Unknown invoice"))
+                    .unwrap()
+            }
+            Err(err) => {
+                error!("Error looking up invoice of path {}: {}", path, err);
+                Response::builder()
+                    .status(502)
+                    .body(Body::from("Unable to look up invoice"))
+                    .unwrap()
+            }
+        }
+    }
+
+    fn generate_response(
+        &self,
+        tag: Option<&str>,
+        invoice: Option<&SpspInvoice>,
+        receipt_details: Option<&ReceiptDetails>,
+    ) -> Response<Body> {
+        let generated = match (tag, receipt_details) {
+            (tag, Some(receipt_details)) => self
+                .connection_generator
+                .generate_address_and_secret_with_receipts(&self.ilp_address, tag, receipt_details),
+            (Some(tag), None) => self
+                .connection_generator
+                .generate_address_and_secret_with_tag(&self.ilp_address, tag),
+            (None, None) => Ok(self
+                .connection_generator
+                .generate_address_and_secret(&self.ilp_address)),
+        };
+        let (destination_account, shared_secret) = match generated {
+            Ok(generated) => generated,
+            Err(_) => {
+                return Response::builder()
+                    .status(400)
+                    .body(Body::from("Invalid connection tag"))
+                    .unwrap()
+            }
+        };
         debug!(
             "Generated address and secret for: {:?}",
             destination_account
@@ -42,6 +179,7 @@ impl SpspResponder {
         let response = SpspResponse {
             destination_account,
             shared_secret: shared_secret.to_vec(),
+            balance: invoice.and_then(|invoice| invoice.balance.clone()),
         };
 
         Response::builder()
@@ -56,14 +194,30 @@ impl SpspResponder {
 impl HttpService<Request<Body>> for SpspResponder {
     type Response = Response<Body>;
     type Error = Error;
-    type Future = futures::future::Ready<Result<Self::Response, Self::Error>>;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
 
     fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Ok(()).into()
     }
 
-    fn call(&mut self, _request: Request<Body>) -> Self::Future {
-        futures::future::ok(self.generate_http_response())
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let responder = self.clone();
+        async move {
+            let receipt_details = match receipt_details(request.headers()) {
+                Ok(receipt_details) => receipt_details,
+                Err(err) => {
+                    debug!("Refusing SPSP query: {}", err);
+                    return Ok(Response::builder()
+                        .status(400)
+                        .body(Body::from(err.to_string()))
+                        .unwrap());
+                }
+            };
+            Ok(responder
+                .generate_http_response_for_path(request.uri().path(), receipt_details.as_ref())
+                .await)
+        }
+        .boxed()
     }
 }
 
@@ -112,4 +266,140 @@ mod spsp_server_test {
             "max-age=60"
         );
     }
+
+    #[tokio::test]
+    async fn generates_tagged_addresses() {
+        let addr = Address::from_str("example.receiver").unwrap();
+        let responder = SpspResponder::new(addr.clone(), Bytes::from(&[0; 32][..]))
+            .with_connection_tag("order-123".to_string());
+        let response = responder.generate_http_response();
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let spsp: SpspResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            interledger_stream::connection_tag(&spsp.destination_account, &addr),
+            Some("order-123")
+        );
+
+        let responder = SpspResponder::new(addr, Bytes::from(&[0; 32][..]))
+            .with_connection_tag("not.valid".to_string());
+        assert_eq!(responder.generate_http_response().status(), 400);
+    }
+
+    async fn lookup_invoice(
+        path: String,
+    ) -> Result<Option<SpspInvoice>, Box<dyn StdError + Send + Sync>> {
+        match path.as_str() {
+            "/invoices/123" => Ok(Some(SpspInvoice {
+                connection_tag: Some("invoice-123".to_string()),
+                balance: Some(SpspBalance {
+                    maximum: 1000,
+                    current: 250,
+                }),
+            })),
+            "/invoices/broken" => Err("invoice system unavailable".into()),
+            _ => Ok(None),
+        }
+    }
+
+    fn invoice_responder() -> SpspResponder {
+        SpspResponder::new(
+            Address::from_str("example.receiver").unwrap(),
+            Bytes::from(&[0; 32][..]),
+        )
+        .with_invoice_lookup(lookup_invoice)
+    }
+
+    #[tokio::test]
+    async fn embeds_invoice_details() {
+        let response = invoice_responder()
+            .generate_http_response_for_path("/invoices/123", None)
+            .await;
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let spsp: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(spsp["balance"]["maximum"], "1000");
+        assert_eq!(spsp["balance"]["current"], "250");
+        let spsp: SpspResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            interledger_stream::connection_tag(
+                &spsp.destination_account,
+                &Address::from_str("example.receiver").unwrap()
+            ),
+            Some("invoice-123")
+        );
+    }
+
+    #[tokio::test]
+    async fn seals_the_verifiers_receipt_details() {
+        let addr = Address::from_str("example.receiver").unwrap();
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let mut responder = SpspResponder::new(addr, server_secret.clone());
+        let response = responder
+            .call(
+                Request::builder()
+                    .uri("http://example.com")
+                    .header("Receipt-Nonce", base64::encode(&[1; 16]))
+                    .header("Receipt-Secret", base64::encode(&[2; 32]))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(!str::from_utf8(&body)
+            .unwrap()
+            .contains(&base64::encode(&[2; 32])));
+        let spsp: SpspResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            ConnectionGenerator::new(server_secret).receipt_details(&spsp.destination_account),
+            Some(ReceiptDetails {
+                nonce: [1; 16],
+                secret: [2; 32],
+            })
+        );
+
+        // The nonce and secret must be provided together
+        let response = responder
+            .call(
+                Request::builder()
+                    .uri("http://example.com")
+                    .header("Receipt-Nonce", base64::encode(&[1; 16]))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn refuses_unknown_invoices() {
+        let mut responder = invoice_responder();
+        let response = responder
+            .call(
+                Request::builder()
+                    .uri("http://example.com/invoices/456")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+
+        let response = responder
+            .generate_http_response_for_path("/invoices/broken", None)
+            .await;
+        assert_eq!(response.status(), 502);
+
+        // Without an invoice lookup, every path is answered
+        let response = SpspResponder::new(
+            Address::from_str("example.receiver").unwrap(),
+            Bytes::from(&[0; 32][..]),
+        )
+        .generate_http_response_for_path("/invoices/456", None)
+        .await;
+        assert_eq!(response.status(), 200);
+    }
 }
@@ -7,7 +7,8 @@ use interledger_http::HttpAccount;
 use interledger_packet::Address;
 use interledger_service::{Account as AccountTrait, Username};
 use interledger_service_util::{
-    MaxPacketAmountAccount, RateLimitAccount, RoundTripTimeAccount, DEFAULT_ROUND_TRIP_TIME,
+    is_valid_spread, AddressTranslationAccount, MaxPacketAmountAccount, RateLimitAccount,
+    RoundTripTimeAccount, SpreadAccount, DEFAULT_ROUND_TRIP_TIME,
 };
 use interledger_settlement::core::types::{SettlementAccount, SettlementEngineDetails};
 use ring::aead;
@@ -83,6 +84,29 @@ pub struct Account {
     /// for the account's asset code,  that will be used instead (even if the account is
     /// configured with a specific one)
     pub(crate) settlement_engine_url: Option<Url>,
+    /// The spread charged on the packets the account sends, instead of the node's spread
+    pub(crate) spread: Option<f64>,
+    /// A second ILP over HTTP URL of the peer, used to hedge the packets which are safe to
+    /// deliver twice
+    pub(crate) ilp_over_http_fallback_url: Option<Url>,
+    /// Other ILP over HTTP URLs of the peer, to which the requests are retried in turn
+    pub(crate) ilp_over_http_alternate_urls: Vec<Url>,
+    /// Prefix of the destinations translated to the peer's prefix in the packets sent to it
+    pub(crate) translate_prefix_from: Option<Address>,
+    /// The peer's own prefix
+    pub(crate) translate_prefix_to: Option<Address>,
+    /// The number of packets the account can send at once
+    pub(crate) packets_burst_limit: Option<u32>,
+    /// The amount the account can send at once
+    pub(crate) amount_burst_limit: Option<u64>,
+    /// Prefixes of the routes accepted from the account's broadcasts
+    pub(crate) accept_route_prefixes: Vec<String>,
+    /// Prefixes of the routes rejected from the account's broadcasts
+    pub(crate) reject_route_prefixes: Vec<String>,
+    /// The maximum number of routes accepted from the account's broadcasts
+    pub(crate) max_routes: Option<u32>,
+    /// Prefixes under which the routes learned from the account are aggregated
+    pub(crate) aggregate_route_prefixes: Vec<String>,
 }
 
 fn address_to_string<S>(address: &Address, serializer: S) -> Result<S::Ok, S::Error>
@@ -125,6 +149,18 @@ impl Account {
             None
         };
 
+        let ilp_over_http_fallback_url = if let Some(ref url) = details.ilp_over_http_fallback_url {
+            Some(Url::parse(url).map_err(CreateAccountError::InvalidHttpUrl)?)
+        } else {
+            None
+        };
+
+        let ilp_over_http_alternate_urls = details
+            .ilp_over_http_alternate_urls
+            .iter()
+            .map(|url| Url::parse(url).map_err(CreateAccountError::InvalidHttpUrl))
+            .collect::<Result<Vec<_>, _>>()?;
+
         let ilp_over_btp_url = if let Some(ref url) = details.ilp_over_btp_url {
             Some(Url::parse(url).map_err(CreateAccountError::InvalidBtpUrl)?)
         } else {
@@ -137,6 +173,14 @@ impl Account {
         } else {
             RoutingRelation::NonRoutingAccount
         };
+        if let Some(spread) = details.spread {
+            if !is_valid_spread(spread) {
+                return Err(CreateAccountError::InvalidSpread(spread));
+            }
+        }
+        if details.translate_prefix_from.is_some() != details.translate_prefix_to.is_some() {
+            return Err(CreateAccountError::IncompletePrefixTranslation);
+        }
         let settlement_engine_url =
             if let Some(settlement_engine_url) = details.settlement_engine_url {
                 Url::parse(&settlement_engine_url).ok()
@@ -173,9 +217,68 @@ impl Account {
             packets_per_minute_limit: details.packets_per_minute_limit,
             amount_per_minute_limit: details.amount_per_minute_limit,
             settlement_engine_url,
+            spread: details.spread,
+            ilp_over_http_fallback_url,
+            ilp_over_http_alternate_urls,
+            translate_prefix_from: details.translate_prefix_from,
+            translate_prefix_to: details.translate_prefix_to,
+            packets_burst_limit: details.packets_burst_limit,
+            amount_burst_limit: details.amount_burst_limit,
+            accept_route_prefixes: details.accept_route_prefixes,
+            reject_route_prefixes: details.reject_route_prefixes,
+            max_routes: details.max_routes,
+            aggregate_route_prefixes: details.aggregate_route_prefixes,
         })
     }
 
+    /// Returns the details the account was created from, including its tokens
+    pub(crate) fn to_details(&self) -> AccountDetails {
+        let token = |token: &Option<SecretBytesMut>| {
+            token.as_ref().map(|token| {
+                SecretString::new(String::from_utf8_lossy(token.expose_secret()).into_owned())
+            })
+        };
+        AccountDetails {
+            ilp_address: Some(self.ilp_address.clone()),
+            username: self.username.clone(),
+            asset_code: self.asset_code.clone(),
+            asset_scale: self.asset_scale,
+            max_packet_amount: self.max_packet_amount,
+            min_balance: self.min_balance,
+            ilp_over_http_url: self.ilp_over_http_url.as_ref().map(Url::to_string),
+            ilp_over_http_incoming_token: token(&self.ilp_over_http_incoming_token),
+            ilp_over_http_outgoing_token: token(&self.ilp_over_http_outgoing_token),
+            ilp_over_btp_url: self.ilp_over_btp_url.as_ref().map(Url::to_string),
+            ilp_over_btp_outgoing_token: token(&self.ilp_over_btp_outgoing_token),
+            ilp_over_btp_incoming_token: token(&self.ilp_over_btp_incoming_token),
+            settle_threshold: self.settle_threshold,
+            settle_to: self.settle_to,
+            routing_relation: Some(self.routing_relation.to_string()),
+            round_trip_time: Some(self.round_trip_time),
+            amount_per_minute_limit: self.amount_per_minute_limit,
+            packets_per_minute_limit: self.packets_per_minute_limit,
+            settlement_engine_url: self.settlement_engine_url.as_ref().map(Url::to_string),
+            spread: self.spread,
+            ilp_over_http_fallback_url: self
+                .ilp_over_http_fallback_url
+                .as_ref()
+                .map(Url::to_string),
+            ilp_over_http_alternate_urls: self
+                .ilp_over_http_alternate_urls
+                .iter()
+                .map(Url::to_string)
+                .collect(),
+            translate_prefix_from: self.translate_prefix_from.clone(),
+            translate_prefix_to: self.translate_prefix_to.clone(),
+            packets_burst_limit: self.packets_burst_limit,
+            amount_burst_limit: self.amount_burst_limit,
+            accept_route_prefixes: self.accept_route_prefixes.clone(),
+            reject_route_prefixes: self.reject_route_prefixes.clone(),
+            max_routes: self.max_routes,
+            aggregate_route_prefixes: self.aggregate_route_prefixes.clone(),
+        }
+    }
+
     /// Encrypts the account's incoming/outgoing BTP and HTTP keys with the provided encryption key
     pub fn encrypt_tokens(
         mut self,
@@ -296,6 +399,14 @@ impl HttpAccount for Account {
         self.ilp_over_http_url.as_ref()
     }
 
+    fn get_http_fallback_url(&self) -> Option<&Url> {
+        self.ilp_over_http_fallback_url.as_ref()
+    }
+
+    fn get_http_alternate_urls(&self) -> &[Url] {
+        &self.ilp_over_http_alternate_urls
+    }
+
     fn get_http_auth_token(&self) -> Option<SecretString> {
         self.ilp_over_http_outgoing_token.as_ref().map(|s| {
             SecretString::new(
@@ -329,6 +440,22 @@ impl CcpRoutingAccount for Account {
     fn routing_relation(&self) -> RoutingRelation {
         self.routing_relation
     }
+
+    fn accept_route_prefixes(&self) -> &[String] {
+        &self.accept_route_prefixes
+    }
+
+    fn reject_route_prefixes(&self) -> &[String] {
+        &self.reject_route_prefixes
+    }
+
+    fn max_routes(&self) -> Option<u32> {
+        self.max_routes
+    }
+
+    fn aggregate_route_prefixes(&self) -> &[String] {
+        &self.aggregate_route_prefixes
+    }
 }
 
 impl RoundTripTimeAccount for Account {
@@ -345,6 +472,29 @@ impl RateLimitAccount for Account {
     fn packets_per_minute_limit(&self) -> Option<u32> {
         self.packets_per_minute_limit
     }
+
+    fn packets_burst_limit(&self) -> Option<u32> {
+        self.packets_burst_limit
+    }
+
+    fn amount_burst_limit(&self) -> Option<u64> {
+        self.amount_burst_limit
+    }
+}
+
+impl AddressTranslationAccount for Account {
+    fn address_translation(&self) -> Option<(&Address, &Address)> {
+        match (&self.translate_prefix_from, &self.translate_prefix_to) {
+            (Some(from), Some(to)) => Some((from, to)),
+            _ => None,
+        }
+    }
+}
+
+impl SpreadAccount for Account {
+    fn spread(&self) -> Option<f64> {
+        self.spread
+    }
 }
 
 impl SettlementAccount for Account {
@@ -382,6 +532,17 @@ mod test {
         amount_per_minute_limit: None,
         packets_per_minute_limit: None,
         settlement_engine_url: None,
+        spread: None,
+        ilp_over_http_fallback_url: None,
+        ilp_over_http_alternate_urls: Vec::new(),
+        translate_prefix_from: None,
+        translate_prefix_to: None,
+        packets_burst_limit: None,
+        amount_burst_limit: None,
+        accept_route_prefixes: Vec::new(),
+        reject_route_prefixes: Vec::new(),
+        max_routes: None,
+        aggregate_route_prefixes: Vec::new(),
     });
 
     #[test]
@@ -412,5 +573,60 @@ mod test {
             "http://example.com/accounts/bob/ilp",
         );
         assert_eq!(account.routing_relation(), RoutingRelation::Peer);
+        assert_eq!(account.spread(), None);
+    }
+
+    #[test]
+    fn validates_the_spread() {
+        let mut details = ACCOUNT_DETAILS.clone();
+        details.spread = Some(0.02);
+        let account = Account::try_from(
+            Uuid::new_v4(),
+            details.clone(),
+            Address::from_str("example.account").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(account.spread(), Some(0.02));
+
+        for spread in &[f64::NAN, -0.01, 1.0, 2.0] {
+            details.spread = Some(*spread);
+            assert!(matches!(
+                Account::try_from(
+                    Uuid::new_v4(),
+                    details.clone(),
+                    Address::from_str("example.account").unwrap(),
+                ),
+                Err(CreateAccountError::InvalidSpread(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn requires_both_translated_prefixes() {
+        let mut details = ACCOUNT_DETAILS.clone();
+        details.translate_prefix_from = Some(Address::from_str("g.alice").unwrap());
+        assert!(matches!(
+            Account::try_from(
+                Uuid::new_v4(),
+                details.clone(),
+                Address::from_str("example.account").unwrap(),
+            ),
+            Err(CreateAccountError::IncompletePrefixTranslation)
+        ));
+
+        details.translate_prefix_to = Some(Address::from_str("private.alice").unwrap());
+        let account = Account::try_from(
+            Uuid::new_v4(),
+            details,
+            Address::from_str("example.account").unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            account.address_translation(),
+            Some((
+                &Address::from_str("g.alice").unwrap(),
+                &Address::from_str("private.alice").unwrap()
+            ))
+        );
     }
 }
@@ -6,6 +6,11 @@
 pub mod account;
 /// Cryptographic utilities for encrypting/decrypting data as well as clearing data from memory
 pub mod crypto;
+/// An in-memory backend, useful for tests and for running a node without a database
+#[cfg(feature = "memory")]
+pub mod memory;
 /// A redis backend using [redis-rs](https://github.com/mitsuhiko/redis-rs/)
 #[cfg(feature = "redis")]
 pub mod redis;
+/// Hooks for replicating the changes made to a store onto a standby node
+pub mod replication;
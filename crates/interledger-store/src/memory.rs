@@ -0,0 +1,2061 @@
+//! An in-memory store which keeps all of its data in the process' memory.
+//!
+//! It implements the same traits as the Redis store, so the whole node can be run
+//! without an external database, for example in tests, examples or embedded setups.
+//! All data is lost when the store is dropped.
+
+use super::account::Account;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::channel::mpsc::UnboundedSender;
+use http::StatusCode;
+use interledger_api::{
+    AccountDetails, AccountSettings, AccountingSink, LedgerEntry, NodeStore, Webhook, WebhookStore,
+};
+use interledger_btp::BtpStore;
+use interledger_ccp::{CcpRoutingAccount, CcpRoutingStore, RoutingRelation};
+use interledger_errors::*;
+use interledger_http::HttpStore;
+use interledger_ildcp::AddressAssignmentStore;
+use interledger_packet::Address;
+use interledger_rates::{find_rate_override, validate_rate_overrides, ExchangeRateStore};
+use interledger_router::{RouteIndex, RouteIndexStats, RouterStore};
+use interledger_service::{Account as AccountTrait, AccountStore, AddressStore, Username};
+use interledger_service_util::{
+    AccountUsage, BalanceHistoryStore, BalanceSample, BalanceStore, DedupStore, DustStore,
+    PacketStatus, RateLimitError, RateLimitStore, UsagePeriod, UsageStore,
+};
+use interledger_settlement::core::{
+    amount::{checked_credit, checked_debit, credit_settlement, narrow_balance},
+    idempotency::{IdempotentData, IdempotentStore, DEFAULT_IDEMPOTENCY_KEY_TTL},
+    scale_with_precision_loss,
+    types::{
+        Convert, ConvertDetails, LeftoversStore, PendingMessage, PendingSettlement,
+        SettlementHistoryStore, SettlementQueueStore, SettlementRecord, SettlementStore,
+        SETTLEMENT_HISTORY_CAPACITY,
+    },
+};
+use interledger_spsp::{Contact, ContactStore};
+use interledger_stream::{
+    ConnectionReceipts, PaymentNotification, StreamNotificationsStore, StreamReceiveStore,
+    StreamReplayStore, SubAccountStore,
+};
+use num_bigint::BigUint;
+use once_cell::sync::Lazy;
+use parking_lot::{Mutex, RwLock};
+use secrecy::{ExposeSecret, SecretBytesMut, SecretString};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::broadcast;
+use tracing::{debug, trace, warn};
+use url::Url;
+use uuid::Uuid;
+
+/// The node's default ILP Address
+static DEFAULT_ILP_ADDRESS: Lazy<Address> = Lazy::new(|| Address::from_str("local.host").unwrap());
+
+/// How long the idempotency keys of incoming settlements are remembered (same as in the Redis store)
+const IDEMPOTENCY_KEY_EXPIRY: Duration = Duration::from_secs(86400);
+
+/// How many of the most recent ledger entries are kept
+const LEDGER_CAPACITY: usize = 10_000;
+
+/// Builder for the in-memory store
+pub struct MemoryStoreBuilder {
+    /// Connector's ILP Address. Used to insert `Child` accounts as
+    node_ilp_address: Address,
+    idempotency_key_ttl: Duration,
+}
+
+impl Default for MemoryStoreBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStoreBuilder {
+    /// Simple Constructor
+    pub fn new() -> Self {
+        MemoryStoreBuilder {
+            node_ilp_address: DEFAULT_ILP_ADDRESS.clone(),
+            idempotency_key_ttl: DEFAULT_IDEMPOTENCY_KEY_TTL,
+        }
+    }
+
+    /// Sets the ILP Address corresponding to the node
+    pub fn node_ilp_address(&mut self, node_ilp_address: Address) -> &mut Self {
+        self.node_ilp_address = node_ilp_address;
+        self
+    }
+
+    /// Sets how long the responses to the idempotent settlement API requests are kept
+    /// (defaults to 24 hours). The expired responses are only forgotten about when
+    /// `remove_expired_idempotent_data` is called
+    pub fn idempotency_key_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.idempotency_key_ttl = ttl;
+        self
+    }
+
+    /// Creates the (empty) store
+    pub fn build(&self) -> MemoryStore {
+        let (payment_publisher, _) = broadcast::channel::<PaymentNotification>(256);
+        MemoryStore {
+            ilp_address: Arc::new(RwLock::new(self.node_ilp_address.clone())),
+            secondary_ilp_addresses: Arc::new(RwLock::new(Vec::new())),
+            state: Arc::new(RwLock::new(State::default())),
+            routes: Arc::new(RouteIndex::new()),
+            exchange_rates: Arc::new(RwLock::new(HashMap::new())),
+            exchange_rates_updated_at: Arc::new(RwLock::new(None)),
+            rate_overrides: Arc::new(RwLock::new(HashMap::new())),
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+            payment_publisher,
+            idempotency_key_ttl: self.idempotency_key_ttl,
+        }
+    }
+}
+
+/// The balance of an account, split the same way as in the Redis store
+#[derive(Debug, Default, Clone, Copy)]
+struct Balance {
+    balance: i64,
+    prepaid_amount: i64,
+}
+
+/// A token bucket used to apply one of the rate limits. It holds up to the burst limit
+/// and is refilled continuously at the per-minute limit
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    /// Returns the tokens in the bucket at the given time. A bucket which was never
+    /// charged is full
+    fn available(bucket: Option<Self>, now: Instant, per_minute: u64, burst: u64) -> f64 {
+        bucket.map_or(burst as f64, |bucket| {
+            let refilled =
+                now.duration_since(bucket.updated_at).as_secs_f64() * per_minute as f64 / 60.0;
+            (bucket.tokens + refilled).min(burst as f64)
+        })
+    }
+}
+
+/// The token buckets of an account's packets and throughput limits
+#[derive(Debug, Clone, Copy, Default)]
+struct RateLimitBuckets {
+    packets: Option<TokenBucket>,
+    amount: Option<TokenBucket>,
+}
+
+/// Everything the store keeps, behind a single lock so that
+/// each operation is atomic like the Redis transactions and scripts
+#[derive(Default)]
+struct State {
+    accounts: HashMap<Uuid, Account>,
+    balances: HashMap<Uuid, Balance>,
+    usernames: HashMap<String, Uuid>,
+    /// Routes to local accounts and routes learned via CCP
+    local_routes: HashMap<String, Uuid>,
+    static_routes: HashMap<String, Uuid>,
+    default_route: Option<Uuid>,
+    settlement_engines: HashMap<String, Url>,
+    rate_limits: HashMap<Uuid, RateLimitBuckets>,
+    /// The current period identifier and counters of each account and period
+    usage: HashMap<(Uuid, UsagePeriod), AccountUsage>,
+    /// The response (if any yet) and expiry of the packets being or already forwarded
+    dedup_packets: HashMap<[u8; 32], (Option<Bytes>, Instant)>,
+    /// The keys of the deduplicated packets, in the order they were first received
+    dedup_keys: VecDeque<(Instant, [u8; 32])>,
+    /// The most recent balance samples of each account, oldest first
+    balance_history: HashMap<Uuid, VecDeque<BalanceSample>>,
+    /// The expiry of the STREAM packets fulfilled by the node
+    fulfilled_stream_packets: HashMap<[u8; 32], Instant>,
+    /// The keys of the fulfilled STREAM packets, in the order they were fulfilled
+    fulfilled_stream_packet_keys: VecDeque<(Instant, [u8; 32])>,
+    /// The receipts of each account's tagged STREAM connections, by connection tag
+    connection_receipts: HashMap<Uuid, HashMap<String, ConnectionReceipts>>,
+    /// The payment pointers of each account's contacts, by name
+    contacts: HashMap<Uuid, BTreeMap<String, String>>,
+    webhooks: HashMap<Uuid, Webhook>,
+    /// The dust lost converting each account's packets, in the account's asset scale
+    conversion_dust: HashMap<Uuid, u64>,
+    assigned_addresses: HashMap<Uuid, String>,
+    next_assigned_address: u64,
+    /// The idempotent responses and when they were saved, by the clock tests can pause
+    idempotent_data: HashMap<String, (IdempotentData, tokio::time::Instant)>,
+    settlement_idempotency_keys: HashMap<String, Instant>,
+    uncredited_amounts: HashMap<Uuid, Vec<(BigUint, u8)>>,
+    pending_settlements: HashMap<Uuid, PendingSettlement>,
+    pending_messages: HashMap<Uuid, PendingMessage>,
+    /// The most recent settlements of each account, oldest first
+    settlement_history: HashMap<Uuid, VecDeque<SettlementRecord>>,
+    /// The most recent ledger entries, oldest first
+    ledger_entries: VecDeque<LedgerEntry>,
+}
+
+impl State {
+    /// Loads an account, using the settlement engine configured for its
+    /// asset code if the account does not specify one
+    fn load_account(&self, id: &Uuid) -> Option<Account> {
+        self.accounts.get(id).map(|account| {
+            let mut account = account.clone();
+            if account.settlement_engine_url.is_none() {
+                account.settlement_engine_url =
+                    self.settlement_engines.get(&account.asset_code).cloned();
+            }
+            account
+        })
+    }
+
+    fn add_account(&mut self, account: Account) {
+        self.usernames
+            .insert(account.username.to_string(), account.id);
+        self.balances.insert(account.id, Balance::default());
+        self.local_routes
+            .insert(account.ilp_address.to_string(), account.id);
+        self.accounts.insert(account.id, account);
+    }
+
+    fn balance_mut(&mut self, id: Uuid) -> Result<&mut Balance, AccountStoreError> {
+        self.balances
+            .get_mut(&id)
+            .ok_or_else(|| AccountStoreError::AccountNotFound(id.to_string()))
+    }
+
+    /// Builds the routing table from the local, default and static routes.
+    /// Static routes take precedence over the others.
+    fn routing_table(&self) -> HashMap<String, Uuid> {
+        self.local_routes
+            .iter()
+            .map(|(prefix, id)| (prefix.clone(), *id))
+            .chain(self.default_route.map(|id| (String::new(), id)))
+            .chain(
+                self.static_routes
+                    .iter()
+                    .map(|(prefix, id)| (prefix.clone(), *id)),
+            )
+            .collect()
+    }
+}
+
+/// A Store which keeps all of its data in memory.
+///
+/// Cloning the store is cheap and all clones share the same data.
+#[derive(Clone)]
+pub struct MemoryStore {
+    /// The Store's ILP Address
+    ilp_address: Arc<RwLock<Address>>,
+    /// The addresses assigned by the node's other parents, when it is multihomed
+    secondary_ilp_addresses: Arc<RwLock<Vec<Address>>>,
+    state: Arc<RwLock<State>>,
+    /// The routing table is kept separately so that it can be returned
+    /// synchronously without cloning it for every packet, along with its prefix index
+    routes: Arc<RouteIndex>,
+    exchange_rates: Arc<RwLock<HashMap<String, f64>>>,
+    /// When the exchange rates were last set
+    exchange_rates_updated_at: Arc<RwLock<Option<SystemTime>>>,
+    /// The fixed rates of asset pairs, which are used instead of the exchange rates
+    rate_overrides: Arc<RwLock<HashMap<String, f64>>>,
+    /// WebSocket senders which publish incoming payment updates
+    subscriptions: Arc<Mutex<HashMap<Uuid, Vec<UnboundedSender<PaymentNotification>>>>>,
+    /// A subscriber to all payment notifications, exposed via a WebSocket
+    payment_publisher: broadcast::Sender<PaymentNotification>,
+    /// How long the idempotent responses are kept
+    idempotency_key_ttl: Duration,
+}
+
+impl MemoryStore {
+    /// Returns the most recent ledger entries recorded in the store, oldest first
+    pub fn get_ledger_entries(&self) -> Vec<LedgerEntry> {
+        self.state.read().ledger_entries.iter().cloned().collect()
+    }
+
+    /// Must be called with the state's write lock held after any route changes
+    fn update_routes(&self, state: &State) {
+        let routes = state.routing_table();
+        trace!("Routing table is: {:?}", routes);
+        self.routes.update(routes);
+    }
+
+    fn get_account_by_username(&self, username: &Username) -> Option<Account> {
+        let state = self.state.read();
+        state
+            .usernames
+            .get(username.as_ref())
+            .and_then(|id| state.load_account(id))
+    }
+}
+
+/// Converts a token from the API into the format stored on the account
+fn token_bytes(token: SecretString) -> SecretBytesMut {
+    SecretBytesMut::new(token.expose_secret().as_str())
+}
+
+/// The error returned when updating the account's balance would overflow it
+fn balance_overflow(account_id: Uuid) -> BalanceStoreError {
+    BalanceStoreError::Other(Box::<dyn std::error::Error + Send + Sync>::from(format!(
+        "Balance of account {} would overflow",
+        account_id
+    )))
+}
+
+/// The amount to settle to bring the balance down to `settle_to`, or `None` if it overflows
+fn settle_amount(balance: i64, settle_to: i64) -> Option<u64> {
+    u64::try_from(i128::from(balance) - i128::from(settle_to)).ok()
+}
+
+#[async_trait]
+impl AccountStore for MemoryStore {
+    type Account = Account;
+
+    async fn get_accounts(
+        &self,
+        account_ids: Vec<Uuid>,
+    ) -> Result<Vec<Account>, AccountStoreError> {
+        let state = self.state.read();
+        let accounts: Vec<Account> = account_ids
+            .iter()
+            .filter_map(|id| state.load_account(id))
+            .collect();
+        if accounts.len() == account_ids.len() {
+            Ok(accounts)
+        } else {
+            Err(AccountStoreError::WrongLength {
+                expected: account_ids.len(),
+                actual: accounts.len(),
+            })
+        }
+    }
+
+    async fn get_account_id_from_username(
+        &self,
+        username: &Username,
+    ) -> Result<Uuid, AccountStoreError> {
+        match self.state.read().usernames.get(username.as_ref()) {
+            Some(id) => Ok(*id),
+            None => {
+                debug!("Username not found: {}", username);
+                Err(AccountStoreError::AccountNotFound(username.to_string()))
+            }
+        }
+    }
+}
+
+impl StreamNotificationsStore for MemoryStore {
+    type Account = Account;
+
+    fn add_payment_notification_subscription(
+        &self,
+        id: Uuid,
+        sender: UnboundedSender<PaymentNotification>,
+    ) {
+        trace!("Added payment notification listener for {}", id);
+        self.subscriptions
+            .lock()
+            .entry(id)
+            .or_default()
+            .push(sender);
+    }
+
+    fn publish_payment_notification(&self, payment: PaymentNotification) {
+        let account_id = match self
+            .state
+            .read()
+            .usernames
+            .get(payment.to_username.as_ref())
+        {
+            Some(id) => *id,
+            None => {
+                warn!(
+                    "Failed to find account ID corresponding to username: {}",
+                    payment.to_username
+                );
+                return;
+            }
+        };
+
+        debug!(
+            "Publishing payment notification {:?} for account {}",
+            payment, account_id
+        );
+        if self.payment_publisher.receiver_count() > 0 {
+            if let Err(err) = self.payment_publisher.send(payment.clone()) {
+                warn!("Failed to send a node-wide payment notification: {:?}", err);
+            }
+        }
+        if let Some(senders) = self.subscriptions.lock().get_mut(&account_id) {
+            senders.retain(|sender| {
+                if let Err(err) = sender.unbounded_send(payment.clone()) {
+                    debug!("Failed to send message: {}", err);
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+    }
+
+    fn all_payment_subscription(&self) -> broadcast::Receiver<PaymentNotification> {
+        self.payment_publisher.subscribe()
+    }
+}
+
+#[async_trait]
+impl BalanceStore for MemoryStore {
+    /// Returns the balance **from the account holder's perspective**, meaning the sum of
+    /// the Payable Balance and Pending Outgoing minus the Receivable Balance and the Pending Incoming.
+    async fn get_balance(&self, account_id: Uuid) -> Result<i64, BalanceStoreError> {
+        let state = self.state.read();
+        let balance = state
+            .balances
+            .get(&account_id)
+            .ok_or_else(|| AccountStoreError::AccountNotFound(account_id.to_string()))
+            .map_err(|err| BalanceStoreError::Other(Box::new(err)))?;
+        narrow_balance(i128::from(balance.balance) + i128::from(balance.prepaid_amount))
+            .map_err(|err| BalanceStoreError::Other(Box::new(err)))
+    }
+
+    async fn update_balances_for_prepare(
+        &self,
+        from_account_id: Uuid,
+        incoming_amount: u64,
+    ) -> Result<(), BalanceStoreError> {
+        // Don't do anything if the amount was 0
+        if incoming_amount == 0 {
+            return Ok(());
+        }
+
+        let mut state = self.state.write();
+        let min_balance = state
+            .accounts
+            .get(&from_account_id)
+            .and_then(|account| account.min_balance);
+        let balance = state
+            .balance_mut(from_account_id)
+            .map_err(|err| BalanceStoreError::Other(Box::new(err)))?;
+        let total = i128::from(balance.balance) + i128::from(balance.prepaid_amount);
+
+        // Check that the prepare wouldn't go under the account's minimum balance
+        if let Some(min_balance) = min_balance {
+            if total - i128::from(incoming_amount) < i128::from(min_balance) {
+                return Err(BalanceStoreError::Other(
+                    Box::<dyn std::error::Error + Send + Sync>::from(format!(
+                        "Incoming prepare of {} would bring account {} under its minimum balance. Current balance: {}, min balance: {}",
+                        incoming_amount, from_account_id, balance.balance, min_balance
+                    )),
+                ));
+            }
+        }
+
+        // Deduct the amount from the prepaid_amount and/or the balance
+        if i128::from(balance.prepaid_amount) >= i128::from(incoming_amount) {
+            balance.prepaid_amount = checked_debit(balance.prepaid_amount, incoming_amount)
+                .map_err(|_| balance_overflow(from_account_id))?;
+        } else {
+            balance.balance = narrow_balance(total - i128::from(incoming_amount))
+                .map_err(|_| balance_overflow(from_account_id))?;
+            balance.prepaid_amount = 0;
+        }
+
+        trace!(
+            "Processed prepare with incoming amount: {}. Account {} has balance (including prepaid amount): {} ",
+            incoming_amount, from_account_id, total - i128::from(incoming_amount)
+        );
+        Ok(())
+    }
+
+    async fn update_balances_for_fulfill(
+        &self,
+        to_account_id: Uuid,
+        outgoing_amount: u64,
+    ) -> Result<(i64, u64), BalanceStoreError> {
+        let mut state = self.state.write();
+        let (settle_threshold, settle_to) = state
+            .accounts
+            .get(&to_account_id)
+            .map(|account| (account.settle_threshold, account.settle_to))
+            .unwrap_or_default();
+        let balance = state
+            .balance_mut(to_account_id)
+            .map_err(|err| BalanceStoreError::Other(Box::new(err)))?;
+        let new_balance = checked_credit(balance.balance, outgoing_amount)
+            .map_err(|_| balance_overflow(to_account_id))?;
+
+        // Settle if the balance reached the settle threshold
+        // and the threshold is above the amount to settle down to
+        let mut amount_to_settle = 0;
+        let mut settled_balance = new_balance;
+        if let (Some(settle_threshold), Some(settle_to)) = (settle_threshold, settle_to) {
+            if new_balance >= settle_threshold && settle_threshold > settle_to {
+                amount_to_settle = settle_amount(new_balance, settle_to)
+                    .ok_or_else(|| balance_overflow(to_account_id))?;
+                settled_balance = settle_to;
+            }
+        }
+        let total =
+            narrow_balance(i128::from(settled_balance) + i128::from(balance.prepaid_amount))
+                .map_err(|_| balance_overflow(to_account_id))?;
+        balance.balance = settled_balance;
+        trace!(
+            "Processed fulfill for account {} for outgoing amount {}. Fulfill call result: {} {}",
+            to_account_id,
+            outgoing_amount,
+            total,
+            amount_to_settle,
+        );
+        Ok((total, amount_to_settle))
+    }
+
+    async fn update_balances_for_reject(
+        &self,
+        from_account_id: Uuid,
+        incoming_amount: u64,
+    ) -> Result<(), BalanceStoreError> {
+        if incoming_amount == 0 {
+            return Ok(());
+        }
+
+        let mut state = self.state.write();
+        let balance = state
+            .balance_mut(from_account_id)
+            .map_err(|err| BalanceStoreError::Other(Box::new(err)))?;
+        balance.balance = checked_credit(balance.balance, incoming_amount)
+            .map_err(|_| balance_overflow(from_account_id))?;
+
+        trace!(
+            "Processed reject for incoming amount: {}. Account {} has balance (including prepaid amount): {}",
+            incoming_amount, from_account_id, i128::from(balance.balance) + i128::from(balance.prepaid_amount)
+        );
+        Ok(())
+    }
+
+    async fn update_balances_for_delayed_settlement(
+        &self,
+        to_account_id: Uuid,
+    ) -> Result<(i64, u64), BalanceStoreError> {
+        let mut state = self.state.write();
+        let (settle_threshold, settle_to) = state
+            .accounts
+            .get(&to_account_id)
+            .map(|account| (account.settle_threshold, account.settle_to))
+            .unwrap_or_default();
+        let balance = state
+            .balance_mut(to_account_id)
+            .map_err(|err| BalanceStoreError::Other(Box::new(err)))?;
+
+        // Unlike for fulfills, settle down to settle_to even if the threshold was not reached
+        let mut amount_to_settle = 0;
+        let mut settled_balance = balance.balance;
+        if let (Some(settle_threshold), Some(settle_to)) = (settle_threshold, settle_to) {
+            if settle_threshold > settle_to && balance.balance >= settle_to {
+                amount_to_settle = settle_amount(balance.balance, settle_to)
+                    .ok_or_else(|| balance_overflow(to_account_id))?;
+                settled_balance = settle_to;
+            }
+        }
+        let total =
+            narrow_balance(i128::from(settled_balance) + i128::from(balance.prepaid_amount))
+                .map_err(|_| balance_overflow(to_account_id))?;
+        balance.balance = settled_balance;
+        trace!(
+            "Processed account {} for delayed settlement, balance: {}, to_settle: {}",
+            to_account_id,
+            total,
+            amount_to_settle
+        );
+        Ok((total, amount_to_settle))
+    }
+}
+
+impl ExchangeRateStore for MemoryStore {
+    fn get_exchange_rates(&self, asset_codes: &[&str]) -> Result<Vec<f64>, ExchangeRateStoreError> {
+        let rates: Vec<f64> = asset_codes
+            .iter()
+            .filter_map(|code| (*self.exchange_rates.read()).get(*code).cloned())
+            .collect();
+        if rates.len() == asset_codes.len() {
+            Ok(rates)
+        } else {
+            Err(ExchangeRateStoreError::PairNotFound {
+                from: asset_codes[0].to_string(),
+                to: asset_codes[1].to_string(),
+            })
+        }
+    }
+
+    fn get_all_exchange_rates(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError> {
+        Ok((*self.exchange_rates.read()).clone())
+    }
+
+    fn set_exchange_rates(
+        &self,
+        rates: HashMap<String, f64>,
+    ) -> Result<(), ExchangeRateStoreError> {
+        (*self.exchange_rates.write()) = rates;
+        (*self.exchange_rates_updated_at.write()) = Some(SystemTime::now());
+        Ok(())
+    }
+
+    fn get_exchange_rates_updated_at(&self) -> Option<SystemTime> {
+        *self.exchange_rates_updated_at.read()
+    }
+
+    fn set_rate_overrides(
+        &self,
+        overrides: HashMap<String, f64>,
+    ) -> Result<(), ExchangeRateStoreError> {
+        *self.rate_overrides.write() = validate_rate_overrides(overrides)?;
+        Ok(())
+    }
+
+    fn get_rate_overrides(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError> {
+        Ok(self.rate_overrides.read().clone())
+    }
+
+    fn get_rate_override(&self, from: &str, to: &str) -> Option<f64> {
+        find_rate_override(&self.rate_overrides.read(), from, to)
+    }
+}
+
+#[async_trait]
+impl BtpStore for MemoryStore {
+    type Account = Account;
+
+    async fn get_account_from_btp_auth(
+        &self,
+        username: &Username,
+        token: &str,
+    ) -> Result<Self::Account, BtpStoreError> {
+        let account = self
+            .get_account_by_username(username)
+            .ok_or_else(|| BtpStoreError::AccountNotFound(username.to_string()))?;
+        match account.ilp_over_btp_incoming_token {
+            Some(ref t) if t.expose_secret().as_ref() == token.as_bytes() => Ok(account),
+            _ => {
+                debug!("BTP auth failed for account {}", account.username);
+                Err(BtpStoreError::Unauthorized(username.to_string()))
+            }
+        }
+    }
+
+    async fn get_btp_outgoing_accounts(&self) -> Result<Vec<Self::Account>, BtpStoreError> {
+        let state = self.state.read();
+        Ok(state
+            .accounts
+            .iter()
+            .filter(|(_, account)| account.ilp_over_btp_url.is_some())
+            .filter_map(|(id, _)| state.load_account(id))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl HttpStore for MemoryStore {
+    type Account = Account;
+
+    /// Checks if the stored token for the provided account id matches the
+    /// provided token, and if so, returns the account associated with that token
+    async fn get_account_from_http_auth(
+        &self,
+        username: &Username,
+        token: &str,
+    ) -> Result<Self::Account, HttpStoreError> {
+        let account = self
+            .get_account_by_username(username)
+            .ok_or_else(|| HttpStoreError::AccountNotFound(username.to_string()))?;
+        match account.ilp_over_http_incoming_token {
+            Some(ref t) if t.expose_secret().as_ref() == token.as_bytes() => Ok(account),
+            _ => Err(HttpStoreError::Unauthorized(username.to_string())),
+        }
+    }
+}
+
+impl RouterStore for MemoryStore {
+    fn routing_table(&self) -> Arc<HashMap<String, Uuid>> {
+        self.routes.routing_table()
+    }
+
+    fn route_for(&self, destination: &str) -> Option<(usize, Uuid)> {
+        self.routes.lookup(destination)
+    }
+
+    fn route_index_stats(&self) -> Option<RouteIndexStats> {
+        Some(self.routes.stats())
+    }
+}
+
+#[async_trait]
+impl NodeStore for MemoryStore {
+    type Account = Account;
+
+    async fn insert_account(
+        &self,
+        account: AccountDetails,
+    ) -> Result<Self::Account, NodeStoreError> {
+        let id = Uuid::new_v4();
+        let account = Account::try_from(id, account, self.get_ilp_address())
+            .map_err(NodeStoreError::InvalidAccount)?;
+
+        let mut state = self.state.write();
+        // Check that there isn't already an account with values that MUST be unique
+        if state.usernames.contains_key(account.username.as_ref()) {
+            warn!(
+                "An account already exists with the same {}. Cannot insert account: {:?}",
+                account.id, account
+            );
+            return Err(NodeStoreError::AccountExists(account.username.to_string()));
+        }
+
+        state.add_account(account.clone());
+        self.update_routes(&state);
+
+        debug!(
+            "Inserted account {} (ILP address: {})",
+            account.id, account.ilp_address
+        );
+        Ok(state.load_account(&id).unwrap_or(account))
+    }
+
+    async fn insert_accounts(
+        &self,
+        accounts: Vec<AccountDetails>,
+        atomic: bool,
+    ) -> Vec<Result<Account, NodeStoreError>> {
+        let ilp_address = self.get_ilp_address();
+        let mut state = self.state.write();
+        // The accounts are all validated before any of them is inserted, under the same lock
+        let mut usernames = HashSet::new();
+        let results: Vec<Result<Account, NodeStoreError>> = accounts
+            .into_iter()
+            .map(|details| {
+                let account = Account::try_from(Uuid::new_v4(), details, ilp_address.clone())
+                    .map_err(NodeStoreError::InvalidAccount)?;
+                if state.usernames.contains_key(account.username.as_ref())
+                    || !usernames.insert(account.username.to_string())
+                {
+                    return Err(NodeStoreError::AccountExists(account.username.to_string()));
+                }
+                Ok(account)
+            })
+            .collect();
+
+        if atomic && results.iter().any(Result::is_err) {
+            return results
+                .into_iter()
+                .map(|result| {
+                    result.and_then(|account| {
+                        Err(NodeStoreError::AccountNotInserted(
+                            account.username.to_string(),
+                        ))
+                    })
+                })
+                .collect();
+        }
+
+        for account in results.iter().flatten() {
+            state.add_account(account.clone());
+        }
+        self.update_routes(&state);
+        debug!(
+            "Inserted {} accounts",
+            results.iter().filter(|result| result.is_ok()).count()
+        );
+        results
+            .into_iter()
+            .map(|result| result.map(|account| state.load_account(&account.id).unwrap_or(account)))
+            .collect()
+    }
+
+    async fn export_accounts(&self) -> Result<Vec<AccountDetails>, NodeStoreError> {
+        // The accounts' own settlement engines are exported, not the per-asset ones
+        Ok(self
+            .state
+            .read()
+            .accounts
+            .values()
+            .map(Account::to_details)
+            .collect())
+    }
+
+    async fn delete_account(&self, id: Uuid) -> Result<Account, NodeStoreError> {
+        let mut state = self.state.write();
+        let account = state
+            .load_account(&id)
+            .ok_or_else(|| NodeStoreError::AccountNotFound(id.to_string()))?;
+
+        state.accounts.remove(&id);
+        state.balances.remove(&id);
+        state.usernames.remove(account.username.as_ref());
+        state.local_routes.remove(&account.ilp_address.to_string());
+        state.assigned_addresses.remove(&id);
+        state.uncredited_amounts.remove(&id);
+        state.rate_limits.remove(&id);
+        state.usage.retain(|(account_id, _), _| *account_id != id);
+        state.balance_history.remove(&id);
+        state.connection_receipts.remove(&id);
+        state.contacts.remove(&id);
+        state.webhooks.remove(&id);
+        state.conversion_dust.remove(&id);
+        state.settlement_history.remove(&id);
+        self.update_routes(&state);
+
+        debug!("Deleted account {}", account.id);
+        Ok(account)
+    }
+
+    async fn update_account(
+        &self,
+        id: Uuid,
+        account: AccountDetails,
+    ) -> Result<Self::Account, NodeStoreError> {
+        let account = Account::try_from(id, account, self.get_ilp_address())
+            .map_err(NodeStoreError::InvalidAccount)?;
+
+        let mut state = self.state.write();
+        let old = match state.accounts.get(&id) {
+            Some(old) => old.clone(),
+            None => {
+                warn!(
+                    "No account exists with ID {}, cannot update account {:?}",
+                    account.id, account
+                );
+                return Err(NodeStoreError::AccountNotFound(account.id.to_string()));
+            }
+        };
+        if let Some(other_id) = state.usernames.get(account.username.as_ref()) {
+            if *other_id != id {
+                return Err(NodeStoreError::AccountExists(account.username.to_string()));
+            }
+        }
+
+        state.usernames.remove(old.username.as_ref());
+        state
+            .usernames
+            .insert(account.username.to_string(), account.id);
+        state.local_routes.remove(&old.ilp_address.to_string());
+        state
+            .local_routes
+            .insert(account.ilp_address.to_string(), account.id);
+        state.accounts.insert(account.id, account.clone());
+        self.update_routes(&state);
+
+        debug!(
+            "Updated account {} (id: {}, ILP address: {})",
+            account.username, account.id, account.ilp_address
+        );
+        Ok(state.load_account(&id).unwrap_or(account))
+    }
+
+    async fn modify_account_settings(
+        &self,
+        id: Uuid,
+        settings: AccountSettings,
+    ) -> Result<Self::Account, NodeStoreError> {
+        // Validate all of the settings before modifying the account
+        if let Some(settle_to) = settings.settle_to {
+            if settle_to > i64::MAX as u64 {
+                // Keep the same limits as the Redis store
+                return Err(NodeStoreError::InvalidAccount(
+                    CreateAccountError::ParamTooLarge("settle_to".to_owned()),
+                ));
+            }
+        }
+        let ilp_over_btp_url = settings
+            .ilp_over_btp_url
+            .map(|url| Url::parse(&url))
+            .transpose()
+            .map_err(|err| {
+                NodeStoreError::InvalidAccount(CreateAccountError::InvalidBtpUrl(err))
+            })?;
+        let ilp_over_http_url = settings
+            .ilp_over_http_url
+            .map(|url| Url::parse(&url))
+            .transpose()
+            .map_err(|err| {
+                NodeStoreError::InvalidAccount(CreateAccountError::InvalidHttpUrl(err))
+            })?;
+
+        let mut state = self.state.write();
+        let account = state
+            .accounts
+            .get_mut(&id)
+            .ok_or_else(|| NodeStoreError::AccountNotFound(id.to_string()))?;
+
+        if let Some(settle_to) = settings.settle_to {
+            account.settle_to = Some(settle_to as i64);
+        }
+        if let Some(settle_threshold) = settings.settle_threshold {
+            account.settle_threshold = Some(settle_threshold);
+        }
+        if let Some(url) = ilp_over_btp_url {
+            account.ilp_over_btp_url = Some(url);
+        }
+        if let Some(url) = ilp_over_http_url {
+            account.ilp_over_http_url = Some(url);
+        }
+        if let Some(token) = settings.ilp_over_btp_outgoing_token {
+            account.ilp_over_btp_outgoing_token = Some(token_bytes(token));
+        }
+        if let Some(token) = settings.ilp_over_http_outgoing_token {
+            account.ilp_over_http_outgoing_token = Some(token_bytes(token));
+        }
+        if let Some(token) = settings.ilp_over_btp_incoming_token {
+            account.ilp_over_btp_incoming_token = Some(token_bytes(token));
+        }
+        if let Some(token) = settings.ilp_over_http_incoming_token {
+            account.ilp_over_http_incoming_token = Some(token_bytes(token));
+        }
+
+        state
+            .load_account(&id)
+            .ok_or_else(|| NodeStoreError::AccountNotFound(id.to_string()))
+    }
+
+    async fn get_all_accounts(&self) -> Result<Vec<Self::Account>, NodeStoreError> {
+        let state = self.state.read();
+        Ok(state
+            .accounts
+            .keys()
+            .filter_map(|id| state.load_account(id))
+            .collect())
+    }
+
+    async fn set_static_routes<R>(&self, routes: R) -> Result<(), NodeStoreError>
+    where
+        R: IntoIterator<Item = (String, Uuid)> + Send + 'async_trait,
+    {
+        let routes: HashMap<String, Uuid> = routes.into_iter().collect();
+        let mut state = self.state.write();
+        if !routes.values().all(|id| state.accounts.contains_key(id)) {
+            warn!("Error setting static routes because not all of the given accounts exist");
+            return Err(NodeStoreError::MissingAccounts);
+        }
+
+        state.static_routes = routes;
+        self.update_routes(&state);
+        Ok(())
+    }
+
+    async fn set_static_route(
+        &self,
+        prefix: String,
+        account_id: Uuid,
+    ) -> Result<(), NodeStoreError> {
+        let mut state = self.state.write();
+        if !state.accounts.contains_key(&account_id) {
+            warn!(
+                "Cannot set static route for prefix: {} because account {} does not exist",
+                prefix, account_id
+            );
+            return Err(NodeStoreError::AccountNotFound(account_id.to_string()));
+        }
+
+        state.static_routes.insert(prefix, account_id);
+        self.update_routes(&state);
+        Ok(())
+    }
+
+    async fn get_static_routes(&self) -> Result<HashMap<String, Uuid>, NodeStoreError> {
+        Ok(self.state.read().static_routes.clone())
+    }
+
+    async fn delete_static_route(&self, prefix: &str) -> Result<(), NodeStoreError> {
+        let mut state = self.state.write();
+        // Deleting a route twice, such as when the deletion is replicated, changes nothing
+        if state.static_routes.remove(prefix).is_none() {
+            debug!("No static route to delete for prefix: {}", prefix);
+            return Ok(());
+        }
+        self.update_routes(&state);
+        debug!("Deleted static route for prefix: {}", prefix);
+        Ok(())
+    }
+
+    async fn set_default_route(&self, account_id: Uuid) -> Result<(), NodeStoreError> {
+        let mut state = self.state.write();
+        if !state.accounts.contains_key(&account_id) {
+            warn!(
+                "Cannot set default route because account {} does not exist",
+                account_id
+            );
+            return Err(NodeStoreError::AccountNotFound(account_id.to_string()));
+        }
+
+        state.default_route = Some(account_id);
+        self.update_routes(&state);
+        debug!("Set default route to account id: {}", account_id);
+        Ok(())
+    }
+
+    async fn get_default_route(&self) -> Result<Option<Uuid>, NodeStoreError> {
+        Ok(self.state.read().default_route)
+    }
+
+    async fn delete_default_route(&self) -> Result<(), NodeStoreError> {
+        let mut state = self.state.write();
+        if state.default_route.take().is_none() {
+            debug!("No default route to delete");
+            return Ok(());
+        }
+        self.update_routes(&state);
+        debug!("Deleted default route");
+        Ok(())
+    }
+
+    async fn set_settlement_engines(
+        &self,
+        asset_to_url_map: impl IntoIterator<Item = (String, Url)> + Send + 'async_trait,
+    ) -> Result<(), NodeStoreError> {
+        let mut state = self.state.write();
+        for (asset_code, url) in asset_to_url_map {
+            debug!("Setting settlement engine for {} to {}", asset_code, url);
+            state.settlement_engines.insert(asset_code, url);
+        }
+        Ok(())
+    }
+
+    async fn get_asset_settlement_engine(
+        &self,
+        asset_code: &str,
+    ) -> Result<Option<Url>, NodeStoreError> {
+        Ok(self
+            .state
+            .read()
+            .settlement_engines
+            .get(asset_code)
+            .cloned())
+    }
+}
+
+#[async_trait]
+impl AddressStore for MemoryStore {
+    // Updates the ILP address of the store & iterates over all children and
+    // updates their ILP Address to match the new address.
+    async fn set_ilp_address(&self, ilp_address: Address) -> Result<(), AddressStoreError> {
+        debug!("Setting ILP address to: {}", ilp_address);
+        let mut state = self.state.write();
+        *self.ilp_address.write() = ilp_address.clone();
+
+        let first_segment = ilp_address
+            .segments()
+            .next_back()
+            .expect("address did not have a first segment, this should be impossible");
+        let State {
+            ref mut accounts,
+            ref mut local_routes,
+            ref assigned_addresses,
+            ..
+        } = *state;
+        for account in accounts.values_mut() {
+            // Update the address and routes of all children and non-routing accounts.
+            if account.routing_relation() != RoutingRelation::Parent
+                && account.routing_relation() != RoutingRelation::Peer
+            {
+                local_routes.remove(&account.ilp_address.to_string());
+                // Keep the suffix of addresses assigned via ILDCP. Otherwise,
+                // if the username of the account ends with the node's address,
+                // we're already configured so no need to append anything.
+                account.ilp_address = if let Some(suffix) = assigned_addresses.get(&account.id) {
+                    ilp_address.with_suffix(suffix.as_bytes()).unwrap()
+                } else if first_segment == account.username().to_string() {
+                    ilp_address.clone()
+                } else {
+                    ilp_address
+                        .with_suffix(account.username().as_bytes())
+                        .unwrap()
+                };
+                local_routes.insert(account.ilp_address.to_string(), account.id);
+            }
+        }
+
+        self.update_routes(&state);
+        Ok(())
+    }
+
+    async fn clear_ilp_address(&self) -> Result<(), AddressStoreError> {
+        // overwrite the ilp address with the default value
+        *self.ilp_address.write() = DEFAULT_ILP_ADDRESS.clone();
+        Ok(())
+    }
+
+    fn get_ilp_address(&self) -> Address {
+        self.ilp_address.read().clone()
+    }
+
+    async fn set_secondary_ilp_addresses(
+        &self,
+        ilp_addresses: Vec<Address>,
+    ) -> Result<(), AddressStoreError> {
+        debug!("Setting secondary ILP addresses to: {:?}", ilp_addresses);
+        *self.secondary_ilp_addresses.write() = ilp_addresses;
+        Ok(())
+    }
+
+    fn get_secondary_ilp_addresses(&self) -> Vec<Address> {
+        self.secondary_ilp_addresses.read().clone()
+    }
+}
+
+#[async_trait]
+impl AddressAssignmentStore for MemoryStore {
+    async fn get_assigned_address(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Option<Address>, AddressAssignmentStoreError> {
+        let suffix = self
+            .state
+            .read()
+            .assigned_addresses
+            .get(&account_id)
+            .cloned();
+        Ok(suffix.map(|suffix| {
+            self.get_ilp_address()
+                .with_suffix(suffix.as_bytes())
+                .expect("assigned address suffixes are valid address segments")
+        }))
+    }
+
+    async fn assign_address(
+        &self,
+        account_id: Uuid,
+        parent_address: &Address,
+    ) -> Result<Address, AddressAssignmentStoreError> {
+        let mut state = self.state.write();
+        if !state.accounts.contains_key(&account_id) {
+            return Err(AddressAssignmentStoreError::Other(Box::new(
+                AccountStoreError::AccountNotFound(account_id.to_string()),
+            )));
+        }
+
+        // Allocate a new suffix if the account does not have one yet, skipping
+        // any address which is already routed to another account
+        let suffix = match state.assigned_addresses.get(&account_id) {
+            Some(suffix) => suffix.clone(),
+            None => {
+                let suffix = loop {
+                    state.next_assigned_address += 1;
+                    let suffix = format!("child{}", state.next_assigned_address);
+                    let address = format!("{}.{}", parent_address, suffix);
+                    if !state.local_routes.contains_key(&address) {
+                        break suffix;
+                    }
+                };
+                state.assigned_addresses.insert(account_id, suffix.clone());
+                suffix
+            }
+        };
+
+        let address = parent_address
+            .with_suffix(suffix.as_bytes())
+            .map_err(|err| AddressAssignmentStoreError::Other(Box::new(err)))?;
+        let old_address = state.accounts[&account_id].ilp_address.clone();
+        if old_address != address {
+            state.local_routes.remove(&old_address.to_string());
+            state.local_routes.insert(address.to_string(), account_id);
+            if let Some(account) = state.accounts.get_mut(&account_id) {
+                account.ilp_address = address.clone();
+            }
+            self.update_routes(&state);
+        }
+
+        debug!("Assigned address {} to account {}", address, account_id);
+        Ok(address)
+    }
+}
+
+#[async_trait]
+impl CcpRoutingStore for MemoryStore {
+    type Account = Account;
+
+    async fn get_accounts_to_send_routes_to(
+        &self,
+        ignore_accounts: Vec<Uuid>,
+    ) -> Result<Vec<Account>, CcpRoutingStoreError> {
+        let state = self.state.read();
+        Ok(state
+            .accounts
+            .iter()
+            .filter(|(id, account)| account.should_send_routes() && !ignore_accounts.contains(id))
+            .filter_map(|(id, _)| state.load_account(id))
+            .collect())
+    }
+
+    async fn get_accounts_to_receive_routes_from(
+        &self,
+    ) -> Result<Vec<Account>, CcpRoutingStoreError> {
+        let state = self.state.read();
+        Ok(state
+            .accounts
+            .iter()
+            .filter(|(_, account)| account.should_receive_routes())
+            .filter_map(|(id, _)| state.load_account(id))
+            .collect())
+    }
+
+    async fn get_local_and_configured_routes(
+        &self,
+    ) -> Result<(HashMap<String, Account>, HashMap<String, Account>), CcpRoutingStoreError> {
+        let state = self.state.read();
+        let local_table = state
+            .accounts
+            .keys()
+            .filter_map(|id| state.load_account(id))
+            .map(|account| (account.ilp_address.to_string(), account))
+            .collect();
+        let configured_table = state
+            .static_routes
+            .iter()
+            .filter_map(|(prefix, id)| {
+                state
+                    .load_account(id)
+                    .map(|account| (prefix.clone(), account))
+            })
+            .collect();
+        Ok((local_table, configured_table))
+    }
+
+    async fn set_routes(
+        &mut self,
+        routes: impl IntoIterator<Item = (String, Account)> + Send + 'async_trait,
+    ) -> Result<(), CcpRoutingStoreError> {
+        let routes: HashMap<String, Uuid> = routes
+            .into_iter()
+            .map(|(prefix, account)| (prefix, account.id))
+            .collect();
+        let mut state = self.state.write();
+        trace!("Saving {} routes", routes.len());
+        state.local_routes = routes;
+        self.update_routes(&state);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    type Account = Account;
+
+    /// Apply rate limits for number of packets per minute and amount of money per minute
+    ///
+    /// Like in the Redis store, the limits are token buckets which hold up to the account's
+    /// burst limits and are refilled continuously at its per minute limits.
+    async fn apply_rate_limits(
+        &self,
+        account: Account,
+        prepare_amount: u64,
+    ) -> Result<(), RateLimitError> {
+        if account.amount_per_minute_limit.is_none() && account.packets_per_minute_limit.is_none() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut state = self.state.write();
+        let buckets = state.rate_limits.entry(account.id).or_default();
+        let packets = account.packets_per_minute_limit.map(|limit| {
+            let burst = account.packets_burst_limit.unwrap_or(limit);
+            TokenBucket::available(buckets.packets, now, limit.into(), burst.into())
+        });
+        if packets.map_or(false, |packets| packets < 1.0) {
+            return Err(RateLimitError::PacketLimitExceeded);
+        }
+        let amount = account.amount_per_minute_limit.map(|limit| {
+            let burst = account.amount_burst_limit.unwrap_or(limit);
+            TokenBucket::available(buckets.amount, now, limit, burst)
+        });
+        if amount.map_or(false, |amount| amount < prepare_amount as f64) {
+            return Err(RateLimitError::ThroughputLimitExceeded);
+        }
+
+        if let Some(packets) = packets {
+            buckets.packets = Some(TokenBucket {
+                tokens: packets - 1.0,
+                updated_at: now,
+            });
+        }
+        if let Some(amount) = amount {
+            buckets.amount = Some(TokenBucket {
+                tokens: amount - prepare_amount as f64,
+                updated_at: now,
+            });
+        }
+        Ok(())
+    }
+
+    async fn refund_throughput_limit(
+        &self,
+        account: Account,
+        prepare_amount: u64,
+    ) -> Result<(), RateLimitError> {
+        if let Some(limit) = account.amount_per_minute_limit {
+            let burst = account.amount_burst_limit.unwrap_or(limit);
+            let now = Instant::now();
+            if let Some(buckets) = self.state.write().rate_limits.get_mut(&account.id) {
+                let amount = TokenBucket::available(buckets.amount, now, limit, burst);
+                buckets.amount = Some(TokenBucket {
+                    tokens: (amount + prepare_amount as f64).min(burst as f64),
+                    updated_at: now,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the counters of the account for the current period,
+/// starting from zero when a new period begins
+fn current_usage<'a>(
+    usage: &'a mut HashMap<(Uuid, UsagePeriod), AccountUsage>,
+    account_id: Uuid,
+    period: UsagePeriod,
+    period_id: &str,
+) -> &'a mut AccountUsage {
+    let usage = usage.entry((account_id, period)).or_default();
+    if usage.period != period_id {
+        *usage = AccountUsage {
+            period: period_id.to_string(),
+            ..Default::default()
+        };
+    }
+    usage
+}
+
+#[async_trait]
+impl SubAccountStore for MemoryStore {
+    async fn credit_sub_account(
+        &self,
+        account_id: Uuid,
+        sub_account: &Username,
+        amount: u64,
+    ) -> Result<(), SubAccountStoreError> {
+        let mut state = self.state.write();
+        let sub_account_id = *state
+            .usernames
+            .get(sub_account.as_ref())
+            .ok_or_else(|| SubAccountStoreError::AccountNotFound(sub_account.to_string()))?;
+        match (
+            state.accounts.get(&account_id),
+            state.accounts.get(&sub_account_id),
+        ) {
+            (Some(account), Some(sub)) => {
+                if account.asset_code != sub.asset_code || account.asset_scale != sub.asset_scale {
+                    return Err(SubAccountStoreError::AssetMismatch(sub_account.to_string()));
+                }
+            }
+            _ => {
+                return Err(SubAccountStoreError::AccountNotFound(
+                    account_id.to_string(),
+                ))
+            }
+        }
+
+        state.balance_mut(account_id)?.balance -= amount as i64;
+        state.balance_mut(sub_account_id)?.balance += amount as i64;
+        trace!(
+            "Moved {} from account {} to sub-account {}",
+            amount,
+            account_id,
+            sub_account_id
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StreamReceiveStore for MemoryStore {
+    async fn set_connection_receive_max(
+        &self,
+        account_id: Uuid,
+        connection_tag: &str,
+        receive_max: Option<u64>,
+    ) -> Result<(), StreamReceiveStoreError> {
+        let mut state = self.state.write();
+        state
+            .connection_receipts
+            .entry(account_id)
+            .or_default()
+            .entry(connection_tag.to_string())
+            .or_default()
+            .receive_max = receive_max;
+        Ok(())
+    }
+
+    async fn get_connection_receipts(
+        &self,
+        account_id: Uuid,
+        connection_tag: &str,
+    ) -> Result<ConnectionReceipts, StreamReceiveStoreError> {
+        let state = self.state.read();
+        Ok(state
+            .connection_receipts
+            .get(&account_id)
+            .and_then(|receipts| receipts.get(connection_tag))
+            .copied()
+            .unwrap_or_default())
+    }
+
+    async fn add_connection_receipt(
+        &self,
+        account_id: Uuid,
+        connection_tag: &str,
+        amount: u64,
+    ) -> Result<(ConnectionReceipts, bool), StreamReceiveStoreError> {
+        let mut state = self.state.write();
+        let receipts = state
+            .connection_receipts
+            .entry(account_id)
+            .or_default()
+            .entry(connection_tag.to_string())
+            .or_default();
+        if receipts.exceeded_by(amount) {
+            return Ok((*receipts, false));
+        }
+        receipts.total_received = receipts.total_received.saturating_add(amount);
+        Ok((*receipts, true))
+    }
+
+    async fn remove_connection_receipt(
+        &self,
+        account_id: Uuid,
+        connection_tag: &str,
+        amount: u64,
+    ) -> Result<(), StreamReceiveStoreError> {
+        let mut state = self.state.write();
+        if let Some(receipts) = state
+            .connection_receipts
+            .get_mut(&account_id)
+            .and_then(|receipts| receipts.get_mut(connection_tag))
+        {
+            receipts.total_received = receipts.total_received.saturating_sub(amount);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StreamReplayStore for MemoryStore {
+    async fn record_fulfilled_packet(
+        &self,
+        key: [u8; 32],
+        ttl: Duration,
+    ) -> Result<bool, StreamReplayStoreError> {
+        let now = Instant::now();
+        let mut state = self.state.write();
+        let state = &mut *state;
+        // Forgets the expired packets which were fulfilled first. The others are
+        // forgotten once they reach the front
+        while let Some((expires_at, old_key)) = state.fulfilled_stream_packet_keys.front().cloned()
+        {
+            if expires_at > now {
+                break;
+            }
+            state.fulfilled_stream_packet_keys.pop_front();
+            if let Some(expires_at) = state.fulfilled_stream_packets.get(&old_key) {
+                if *expires_at <= now {
+                    state.fulfilled_stream_packets.remove(&old_key);
+                }
+            }
+        }
+
+        match state.fulfilled_stream_packets.get(&key) {
+            Some(expires_at) if *expires_at > now => Ok(false),
+            _ => {
+                state.fulfilled_stream_packets.insert(key, now + ttl);
+                state
+                    .fulfilled_stream_packet_keys
+                    .push_back((now + ttl, key));
+                Ok(true)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl UsageStore for MemoryStore {
+    async fn record_usage(
+        &self,
+        from_account_id: Uuid,
+        incoming_amount: u64,
+        to_account_id: Uuid,
+        outgoing_amount: u64,
+    ) -> Result<(), UsageStoreError> {
+        let mut state = self.state.write();
+        for period in UsagePeriod::ALL.iter() {
+            let period_id = period.current_period_id();
+
+            let from = current_usage(&mut state.usage, from_account_id, *period, &period_id);
+            from.packets_sent += 1;
+            from.amount_sent = from.amount_sent.saturating_add(incoming_amount);
+
+            let to = current_usage(&mut state.usage, to_account_id, *period, &period_id);
+            to.packets_received += 1;
+            to.amount_received = to.amount_received.saturating_add(outgoing_amount);
+        }
+        Ok(())
+    }
+
+    async fn get_usage(
+        &self,
+        account_id: Uuid,
+        period: UsagePeriod,
+    ) -> Result<AccountUsage, UsageStoreError> {
+        let period_id = period.current_period_id();
+        match self.state.read().usage.get(&(account_id, period)) {
+            Some(usage) if usage.period == period_id => Ok(usage.clone()),
+            _ => Ok(AccountUsage {
+                period: period_id,
+                ..Default::default()
+            }),
+        }
+    }
+
+    async fn reset_usage(
+        &self,
+        account_id: Uuid,
+        period: UsagePeriod,
+    ) -> Result<(), UsageStoreError> {
+        self.state.write().usage.remove(&(account_id, period));
+        debug!("Reset {:?} usage of account: {}", period, account_id);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DedupStore for MemoryStore {
+    async fn start_packet(
+        &self,
+        key: [u8; 32],
+        ttl: Duration,
+    ) -> Result<PacketStatus, DedupStoreError> {
+        let now = Instant::now();
+        let mut state = self.state.write();
+        let state = &mut *state;
+        // Forgets the expired packets which were received first. The others are
+        // forgotten once they reach the front
+        while let Some((expires_at, old_key)) = state.dedup_keys.front().cloned() {
+            if expires_at > now {
+                break;
+            }
+            state.dedup_keys.pop_front();
+            if let Some((_, expires_at)) = state.dedup_packets.get(&old_key) {
+                if *expires_at <= now {
+                    state.dedup_packets.remove(&old_key);
+                }
+            }
+        }
+
+        match state.dedup_packets.get(&key) {
+            Some((response, expires_at)) if *expires_at > now => Ok(match response {
+                Some(response) => PacketStatus::Answered(response.clone()),
+                None => PacketStatus::InFlight,
+            }),
+            _ => {
+                state.dedup_packets.insert(key, (None, now + ttl));
+                state.dedup_keys.push_back((now + ttl, key));
+                Ok(PacketStatus::New)
+            }
+        }
+    }
+
+    async fn save_packet_response(
+        &self,
+        key: [u8; 32],
+        response: Bytes,
+        ttl: Duration,
+    ) -> Result<(), DedupStoreError> {
+        let expires_at = Instant::now() + ttl;
+        let mut state = self.state.write();
+        state
+            .dedup_packets
+            .insert(key, (Some(response), expires_at));
+        state.dedup_keys.push_back((expires_at, key));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DustStore for MemoryStore {
+    async fn add_dust(&self, account_id: Uuid, dust: u64) -> Result<(), DustStoreError> {
+        let mut state = self.state.write();
+        let total = state.conversion_dust.entry(account_id).or_insert(0);
+        *total = total.saturating_add(dust);
+        Ok(())
+    }
+
+    async fn take_dust(&self, account_id: Uuid) -> Result<u64, DustStoreError> {
+        Ok(self
+            .state
+            .write()
+            .conversion_dust
+            .remove(&account_id)
+            .unwrap_or(0))
+    }
+}
+
+#[async_trait]
+impl BalanceHistoryStore for MemoryStore {
+    async fn record_balance_samples(
+        &self,
+        timestamp: u64,
+        capacity: usize,
+    ) -> Result<(), BalanceHistoryStoreError> {
+        let mut state = self.state.write();
+        let state = &mut *state;
+        for (account_id, balance) in state.balances.iter() {
+            let samples = state.balance_history.entry(*account_id).or_default();
+            samples.push_back(BalanceSample {
+                timestamp,
+                balance: balance.balance + balance.prepaid_amount,
+            });
+            while samples.len() > capacity {
+                samples.pop_front();
+            }
+        }
+        trace!("Sampled the balances of {} accounts", state.balances.len());
+        Ok(())
+    }
+
+    async fn get_balance_history(
+        &self,
+        account_id: Uuid,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<BalanceSample>, BalanceHistoryStoreError> {
+        Ok(self
+            .state
+            .read()
+            .balance_history
+            .get(&account_id)
+            .map(|samples| {
+                samples
+                    .iter()
+                    .filter(|sample| sample.timestamp >= from && sample.timestamp <= to)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl ContactStore for MemoryStore {
+    async fn save_contact(
+        &self,
+        account_id: Uuid,
+        contact: Contact,
+    ) -> Result<(), ContactStoreError> {
+        self.state
+            .write()
+            .contacts
+            .entry(account_id)
+            .or_default()
+            .insert(contact.name, contact.payment_pointer);
+        Ok(())
+    }
+
+    async fn get_contacts(&self, account_id: Uuid) -> Result<Vec<Contact>, ContactStoreError> {
+        Ok(self
+            .state
+            .read()
+            .contacts
+            .get(&account_id)
+            .map(|contacts| {
+                contacts
+                    .iter()
+                    .map(|(name, payment_pointer)| Contact {
+                        name: name.clone(),
+                        payment_pointer: payment_pointer.clone(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn get_contact(
+        &self,
+        account_id: Uuid,
+        name: &str,
+    ) -> Result<Contact, ContactStoreError> {
+        self.state
+            .read()
+            .contacts
+            .get(&account_id)
+            .and_then(|contacts| contacts.get(name))
+            .map(|payment_pointer| Contact {
+                name: name.to_string(),
+                payment_pointer: payment_pointer.clone(),
+            })
+            .ok_or_else(|| ContactStoreError::ContactNotFound(name.to_string()))
+    }
+
+    async fn delete_contact(&self, account_id: Uuid, name: &str) -> Result<(), ContactStoreError> {
+        self.state
+            .write()
+            .contacts
+            .get_mut(&account_id)
+            .and_then(|contacts| contacts.remove(name))
+            .map(|_| ())
+            .ok_or_else(|| ContactStoreError::ContactNotFound(name.to_string()))
+    }
+}
+
+#[async_trait]
+impl WebhookStore for MemoryStore {
+    async fn set_webhook(
+        &self,
+        account_id: Uuid,
+        webhook: Webhook,
+    ) -> Result<(), WebhookStoreError> {
+        self.state.write().webhooks.insert(account_id, webhook);
+        Ok(())
+    }
+
+    async fn get_webhook(&self, account_id: Uuid) -> Result<Webhook, WebhookStoreError> {
+        self.state
+            .read()
+            .webhooks
+            .get(&account_id)
+            .cloned()
+            .ok_or_else(|| WebhookStoreError::WebhookNotFound(account_id.to_string()))
+    }
+
+    async fn delete_webhook(&self, account_id: Uuid) -> Result<(), WebhookStoreError> {
+        self.state
+            .write()
+            .webhooks
+            .remove(&account_id)
+            .map(|_| ())
+            .ok_or_else(|| WebhookStoreError::WebhookNotFound(account_id.to_string()))
+    }
+}
+
+#[async_trait]
+impl AccountingSink for MemoryStore {
+    async fn record_ledger_entry(&self, entry: &LedgerEntry) -> Result<(), AccountingSinkError> {
+        let mut state = self.state.write();
+        if state.ledger_entries.len() == LEDGER_CAPACITY {
+            state.ledger_entries.pop_front();
+        }
+        state.ledger_entries.push_back(entry.clone());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl IdempotentStore for MemoryStore {
+    async fn load_idempotent_data(
+        &self,
+        idempotency_key: String,
+    ) -> Result<Option<IdempotentData>, IdempotentStoreError> {
+        let state = self.state.read();
+        match state.idempotent_data.get(&idempotency_key) {
+            Some((data, saved_at)) if saved_at.elapsed() < self.idempotency_key_ttl => {
+                trace!("Loaded idempotency key {:?} - {:?}", idempotency_key, data);
+                Ok(Some(data.clone()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn save_idempotent_data(
+        &self,
+        idempotency_key: String,
+        input_hash: [u8; 32],
+        status_code: StatusCode,
+        data: Bytes,
+    ) -> Result<(), IdempotentStoreError> {
+        trace!(
+            "Cached {:?}: {:?}, {:?}",
+            idempotency_key,
+            status_code,
+            data,
+        );
+        self.state.write().idempotent_data.insert(
+            idempotency_key,
+            (
+                IdempotentData::new(status_code, data, input_hash),
+                tokio::time::Instant::now(),
+            ),
+        );
+        Ok(())
+    }
+
+    async fn get_idempotency_keys(&self) -> Result<Vec<String>, IdempotentStoreError> {
+        Ok(self
+            .state
+            .read()
+            .idempotent_data
+            .iter()
+            .filter(|(_, (_, saved_at))| saved_at.elapsed() < self.idempotency_key_ttl)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    async fn delete_idempotent_data(
+        &self,
+        idempotency_keys: Vec<String>,
+    ) -> Result<(), IdempotentStoreError> {
+        let mut state = self.state.write();
+        for idempotency_key in &idempotency_keys {
+            state.idempotent_data.remove(idempotency_key);
+        }
+        debug!("Deleted idempotency keys: {:?}", idempotency_keys);
+        Ok(())
+    }
+
+    async fn remove_expired_idempotent_data(&self) -> Result<usize, IdempotentStoreError> {
+        let mut state = self.state.write();
+        let before = state.idempotent_data.len();
+        let ttl = self.idempotency_key_ttl;
+        state
+            .idempotent_data
+            .retain(|_, (_, saved_at)| saved_at.elapsed() < ttl);
+        Ok(before - state.idempotent_data.len())
+    }
+}
+
+#[async_trait]
+impl SettlementStore for MemoryStore {
+    type Account = Account;
+
+    async fn update_balance_for_incoming_settlement(
+        &self,
+        account_id: Uuid,
+        amount: u64,
+        idempotency_key: Option<String>,
+    ) -> Result<u64, SettlementStoreError> {
+        let mut state = self.state.write();
+        if let Some(idempotency_key) = idempotency_key {
+            state
+                .settlement_idempotency_keys
+                .retain(|_, used_at| used_at.elapsed() < IDEMPOTENCY_KEY_EXPIRY);
+            // If idempotency key has been used, then do not perform any operations
+            if state
+                .settlement_idempotency_keys
+                .insert(idempotency_key, Instant::now())
+                .is_some()
+            {
+                return Ok(0);
+            }
+        }
+
+        let balance = state
+            .balance_mut(account_id)
+            .map_err(|err| SettlementStoreError::Other(Box::new(err)))?;
+        let (new_balance, new_prepaid_amount, uncredited_amount) =
+            credit_settlement(balance.balance, balance.prepaid_amount, amount);
+        balance.balance = new_balance;
+        balance.prepaid_amount = new_prepaid_amount;
+
+        trace!(
+            "Processed incoming settlement from account: {} for amount: {}. Balance is now: {}",
+            account_id,
+            amount - uncredited_amount,
+            new_balance + new_prepaid_amount
+        );
+        Ok(uncredited_amount)
+    }
+
+    async fn refund_settlement(
+        &self,
+        account_id: Uuid,
+        settle_amount: u64,
+    ) -> Result<(), SettlementStoreError> {
+        let mut state = self.state.write();
+        let balance = state
+            .balance_mut(account_id)
+            .map_err(|err| SettlementStoreError::Other(Box::new(err)))?;
+        balance.balance = checked_credit(balance.balance, settle_amount)
+            .map_err(|_| SettlementStoreError::BalanceOverflow)?;
+
+        trace!(
+            "Refunded settlement for account: {} of amount: {}. Balance is now: {}",
+            account_id,
+            settle_amount,
+            balance.balance
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SettlementQueueStore for MemoryStore {
+    async fn save_pending_settlement(
+        &self,
+        settlement: PendingSettlement,
+    ) -> Result<(), SettlementQueueStoreError> {
+        self.state
+            .write()
+            .pending_settlements
+            .insert(settlement.id, settlement);
+        Ok(())
+    }
+
+    async fn remove_pending_settlement(&self, id: Uuid) -> Result<(), SettlementQueueStoreError> {
+        self.state.write().pending_settlements.remove(&id);
+        Ok(())
+    }
+
+    async fn load_pending_settlements(
+        &self,
+    ) -> Result<Vec<PendingSettlement>, SettlementQueueStoreError> {
+        Ok(self
+            .state
+            .read()
+            .pending_settlements
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    async fn save_pending_message(
+        &self,
+        message: PendingMessage,
+    ) -> Result<(), SettlementQueueStoreError> {
+        self.state
+            .write()
+            .pending_messages
+            .insert(message.id, message);
+        Ok(())
+    }
+
+    async fn remove_pending_message(&self, id: Uuid) -> Result<(), SettlementQueueStoreError> {
+        self.state.write().pending_messages.remove(&id);
+        Ok(())
+    }
+
+    async fn load_pending_messages(
+        &self,
+    ) -> Result<Vec<PendingMessage>, SettlementQueueStoreError> {
+        Ok(self
+            .state
+            .read()
+            .pending_messages
+            .values()
+            .cloned()
+            .collect())
+    }
+}
+
+#[async_trait]
+impl SettlementHistoryStore for MemoryStore {
+    async fn record_settlement(
+        &self,
+        account_id: Uuid,
+        record: SettlementRecord,
+    ) -> Result<(), SettlementHistoryStoreError> {
+        let mut state = self.state.write();
+        let records = state.settlement_history.entry(account_id).or_default();
+        records.push_back(record);
+        while records.len() > SETTLEMENT_HISTORY_CAPACITY {
+            records.pop_front();
+        }
+        Ok(())
+    }
+
+    async fn get_settlement_history(
+        &self,
+        account_id: Uuid,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<SettlementRecord>, SettlementHistoryStoreError> {
+        Ok(self
+            .state
+            .read()
+            .settlement_history
+            .get(&account_id)
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|record| record.timestamp >= from && record.timestamp <= to)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl LeftoversStore for MemoryStore {
+    type AccountId = Uuid;
+    type AssetType = BigUint;
+
+    async fn get_uncredited_settlement_amount(
+        &self,
+        account_id: Uuid,
+    ) -> Result<(Self::AssetType, u8), LeftoversStoreError> {
+        // get the amounts and instantly delete them
+        let amounts = self
+            .state
+            .write()
+            .uncredited_amounts
+            .remove(&account_id)
+            .unwrap_or_default();
+
+        // We must scale them to the largest scale, and then add them together
+        let max_scale = amounts.iter().map(|(_, scale)| *scale).max().unwrap_or(0);
+        let mut sum = BigUint::from(0u32);
+        for (amount, scale) in amounts {
+            sum += amount
+                .normalize_scale(ConvertDetails {
+                    from: scale,
+                    to: max_scale,
+                })
+                .unwrap();
+        }
+        Ok((sum, max_scale))
+    }
+
+    async fn save_uncredited_settlement_amount(
+        &self,
+        account_id: Uuid,
+        uncredited_settlement_amount: (Self::AssetType, u8),
+    ) -> Result<(), LeftoversStoreError> {
+        trace!(
+            "Saving uncredited_settlement_amount {:?} {:?}",
+            account_id,
+            uncredited_settlement_amount
+        );
+        self.state
+            .write()
+            .uncredited_amounts
+            .entry(account_id)
+            .or_default()
+            .push(uncredited_settlement_amount);
+        Ok(())
+    }
+
+    async fn load_uncredited_settlement_amount(
+        &self,
+        account_id: Uuid,
+        local_scale: u8,
+    ) -> Result<Self::AssetType, LeftoversStoreError> {
+        trace!("Loading uncredited_settlement_amount {:?}", account_id);
+        let amount = self.get_uncredited_settlement_amount(account_id).await?;
+        // scale the amount from the max scale to the local scale, and then
+        // save any potential leftovers to the store
+        let (scaled_amount, precision_loss) =
+            scale_with_precision_loss(amount.0, local_scale, amount.1);
+
+        if precision_loss > BigUint::from(0u32) {
+            self.save_uncredited_settlement_amount(
+                account_id,
+                (precision_loss, std::cmp::max(local_scale, amount.1)),
+            )
+            .await?;
+        }
+
+        Ok(scaled_amount)
+    }
+
+    async fn clear_uncredited_settlement_amount(
+        &self,
+        account_id: Uuid,
+    ) -> Result<(), LeftoversStoreError> {
+        trace!("Clearing uncredited_settlement_amount {:?}", account_id);
+        self.state.write().uncredited_amounts.remove(&account_id);
+        Ok(())
+    }
+}
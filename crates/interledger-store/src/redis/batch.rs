@@ -0,0 +1,177 @@
+use super::reconnect::RedisReconnect;
+use futures::channel::{mpsc, oneshot};
+use futures::StreamExt;
+use interledger_service::AccountId;
+use metrics::{recorder, Key};
+use once_cell::sync::Lazy;
+use redis_crate::{ErrorKind, RedisError, Script};
+use std::time::Duration;
+use tokio::time::{timeout_at, Instant};
+use tracing::{error, trace};
+use uuid::Uuid;
+
+/// This lua script applies a batch of fulfill and reject balance updates
+/// and returns the resulting balance and amount to settle of each of them
+static PROCESS_BALANCE_BATCH: Lazy<Script> =
+    Lazy::new(|| Script::new(include_str!("lua/process_balance_batch.lua")));
+
+/// The balance updates which can be batched.
+///
+/// Prepares are not batched because they must be checked against the account's
+/// minimum balance before the packet is forwarded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum BalanceUpdateKind {
+    Fulfill,
+    Reject,
+}
+
+impl BalanceUpdateKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            BalanceUpdateKind::Fulfill => "fulfill",
+            BalanceUpdateKind::Reject => "reject",
+        }
+    }
+}
+
+struct PendingUpdate {
+    kind: BalanceUpdateKind,
+    account_id: Uuid,
+    amount: u64,
+    respond: oneshot::Sender<Result<(i64, u64), RedisError>>,
+}
+
+/// Coalesces the balance updates made within a short window into a single
+/// script invocation, so that they only take one round trip to Redis
+#[derive(Clone)]
+pub(crate) struct BalanceBatcher {
+    sender: mpsc::UnboundedSender<PendingUpdate>,
+}
+
+impl BalanceBatcher {
+    /// Spawns the task applying the batches. It stops once every clone of the batcher is dropped
+    pub fn spawn(
+        connection: RedisReconnect,
+        accounts_key: String,
+        window: Duration,
+        max_batch_size: usize,
+    ) -> Self {
+        let (sender, receiver) = mpsc::unbounded();
+        tokio::spawn(process_batches(
+            connection,
+            accounts_key,
+            window,
+            max_batch_size.max(1),
+            receiver,
+        ));
+        BalanceBatcher { sender }
+    }
+
+    /// Queues a balance update and returns the account's balance (including the prepaid
+    /// amount) and the amount to settle once the batch it is part of was applied
+    pub async fn update(
+        &self,
+        kind: BalanceUpdateKind,
+        account_id: Uuid,
+        amount: u64,
+    ) -> Result<(i64, u64), RedisError> {
+        let (respond, response) = oneshot::channel();
+        self.sender
+            .unbounded_send(PendingUpdate {
+                kind,
+                account_id,
+                amount,
+                respond,
+            })
+            .map_err(|_| batcher_stopped())?;
+        response.await.map_err(|_| batcher_stopped())?
+    }
+}
+
+fn batcher_stopped() -> RedisError {
+    RedisError::from((ErrorKind::IoError, "Balance update batching stopped"))
+}
+
+async fn process_batches(
+    connection: RedisReconnect,
+    accounts_key: String,
+    window: Duration,
+    max_batch_size: usize,
+    mut receiver: mpsc::UnboundedReceiver<PendingUpdate>,
+) {
+    while let Some(first) = receiver.next().await {
+        // The window starts with the first update so that a lone update is not delayed
+        // by more than the window, and the batch is applied as soon as it is full
+        let deadline = Instant::now() + window;
+        let mut batch = vec![first];
+        while batch.len() < max_batch_size {
+            match timeout_at(deadline, receiver.next()).await {
+                Ok(Some(update)) => batch.push(update),
+                // The window elapsed or the store was dropped
+                _ => break,
+            }
+        }
+        // Start collecting the next batch while this one is being applied
+        tokio::spawn(apply_batch(connection.clone(), accounts_key.clone(), batch));
+    }
+    trace!("Stopped batching balance updates because the store was dropped");
+}
+
+async fn apply_batch(
+    mut connection: RedisReconnect,
+    accounts_key: String,
+    batch: Vec<PendingUpdate>,
+) {
+    recorder().record_histogram(
+        Key::from_name("store.redis.balance_batch.size"),
+        batch.len() as u64,
+    );
+
+    let mut invocation = PROCESS_BALANCE_BATCH.prepare_invoke();
+    invocation.arg(&accounts_key);
+    for update in batch.iter() {
+        invocation
+            .arg(update.kind.as_str())
+            .arg(AccountId::from(update.account_id))
+            .arg(update.amount);
+    }
+    let result: Result<Vec<(i64, u64)>, RedisError> =
+        invocation.invoke_async(&mut connection).await;
+
+    match result {
+        Ok(results) if results.len() == batch.len() => {
+            trace!("Applied batch of {} balance updates", batch.len());
+            for (update, result) in batch.into_iter().zip(results) {
+                // The caller may have stopped waiting for the result
+                let _ = update.respond.send(Ok(result));
+            }
+        }
+        Ok(results) => {
+            error!(
+                "Applying batch of {} balance updates returned {} results",
+                batch.len(),
+                results.len()
+            );
+            for update in batch {
+                let _ = update.respond.send(Err(RedisError::from((
+                    ErrorKind::ResponseError,
+                    "Unexpected number of balance update results",
+                ))));
+            }
+        }
+        Err(err) => {
+            error!(
+                "Error applying batch of {} balance updates: {}",
+                batch.len(),
+                err
+            );
+            for update in batch {
+                let _ = update.respond.send(Err(RedisError::from((
+                    err.kind(),
+                    "Error applying batch of balance updates",
+                    err.to_string(),
+                ))));
+            }
+        }
+    }
+}
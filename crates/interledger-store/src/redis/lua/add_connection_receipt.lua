@@ -0,0 +1,40 @@
+-- Adds an amount to the total received over the tagged connections of an account,
+-- unless it would exceed their receive max
+local receipts_key = KEYS[1]
+local received_key = KEYS[2]
+local connection_tag = ARGV[1]
+local amount = ARGV[2]
+local expiry = ARGV[3]
+
+-- Lua numbers are doubles, so the amounts are compared as the decimal strings Redis
+-- stores them as instead
+local function exceeds(value, max)
+    if string.len(value) ~= string.len(max) then
+        return string.len(value) > string.len(max)
+    end
+    return value > max
+end
+
+-- false rather than nil, which would cut the returned tables short
+local receive_max = redis.call('HGET', receipts_key, 'receive_max:' .. connection_tag) or false
+
+-- INCRBY fails rather than overflowing, in which case the amount is not added either
+local added = redis.pcall('INCRBY', received_key, amount)
+if type(added) == 'table' and added.err then
+    return {redis.call('GET', received_key) or '0', receive_max, 0}
+end
+local received = redis.call('GET', received_key)
+
+if receive_max and exceeds(received, receive_max) then
+    redis.call('DECRBY', received_key, amount)
+    return {redis.call('GET', received_key), receive_max, 0}
+end
+
+-- The totals are only kept while the connections receive money, unless they have a
+-- receive max, which would otherwise be reset
+if receive_max then
+    redis.call('PERSIST', received_key)
+else
+    redis.call('EXPIRE', received_key, expiry)
+end
+return {received, receive_max, 1}
@@ -0,0 +1,58 @@
+-- Token buckets of an account's packets and throughput limits. Each bucket holds up to its
+-- burst size and is refilled continuously at its rate per minute. The rate of a bucket is
+-- empty if the account has no such limit.
+local packets_key = ARGV[1]
+local packets_rate = tonumber(ARGV[2])
+local packets_burst = tonumber(ARGV[3])
+local amount_key = ARGV[4]
+local amount_rate = tonumber(ARGV[5])
+local amount_burst = tonumber(ARGV[6])
+-- Negative to refund an amount charged earlier
+local amount = tonumber(ARGV[7])
+
+-- The buckets are refilled according to the clock of the Redis server, so that all of
+-- the nodes sharing the database apply the same limits
+redis.replicate_commands()
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local function available(key, rate, burst)
+    local tokens, updated_at = unpack(redis.call('HMGET', key, 'tokens', 'updated_at'))
+    if not tokens then
+        return burst
+    end
+    local elapsed = math.max(0, now - tonumber(updated_at))
+    return math.min(burst, tonumber(tokens) + elapsed * rate / 60000)
+end
+
+local function save(key, tokens, rate, burst)
+    tokens = math.min(burst, tokens)
+    redis.call('HMSET', key, 'tokens', tokens, 'updated_at', now)
+    -- Once the bucket would be full again, it can be dropped
+    if rate > 0 then
+        redis.call('PEXPIRE', key, math.ceil((burst - tokens) * 60000 / rate) + 1000)
+    end
+end
+
+local packets, amounts
+if packets_rate then
+    packets = available(packets_key, packets_rate, packets_burst)
+    if packets < 1 then
+        return 1
+    end
+end
+if amount_rate then
+    amounts = available(amount_key, amount_rate, amount_burst)
+    if amounts < amount then
+        return 2
+    end
+end
+
+-- Only charge the buckets once the packet is known to be within both limits
+if packets_rate then
+    save(packets_key, packets - 1, packets_rate, packets_burst)
+end
+if amount_rate then
+    save(amount_key, amounts - amount, amount_rate, amount_burst)
+end
+return 0
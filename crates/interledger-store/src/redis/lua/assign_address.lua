@@ -0,0 +1,35 @@
+local assigned_addresses_key = ARGV[1]
+local counter_key = ARGV[2]
+local routes_key = ARGV[3]
+local accounts_key = ARGV[4]
+local account_id = ARGV[5]
+local parent_address = ARGV[6]
+local account = accounts_key .. ':' .. account_id
+
+if redis.call('EXISTS', account) == 0 then
+    error('Account ' .. account_id .. ' does not exist')
+end
+
+-- Allocate a new suffix if the account does not have one yet, skipping
+-- any address which is already routed to another account
+local suffix = redis.call('HGET', assigned_addresses_key, account_id)
+if not suffix then
+    repeat
+        suffix = 'child' .. redis.call('INCR', counter_key)
+    until redis.call('HEXISTS', routes_key, parent_address .. '.' .. suffix) == 0
+    redis.call('HSET', assigned_addresses_key, account_id, suffix)
+end
+
+-- Point the account and its route to the assigned address
+local address = parent_address .. '.' .. suffix
+local old_address = redis.call('HGET', account, 'ilp_address')
+if old_address ~= address then
+    -- Only remove the old route if it still points to this account
+    if old_address and redis.call('HGET', routes_key, old_address) == account_id then
+        redis.call('HDEL', routes_key, old_address)
+    end
+    redis.call('HSET', account, 'ilp_address', address)
+    redis.call('HSET', routes_key, address, account_id)
+end
+
+return address
@@ -0,0 +1,68 @@
+local accounts_set_key = ARGV[1]
+local usernames_key = ARGV[2]
+local send_routes_key = ARGV[3]
+local receive_routes_key = ARGV[4]
+local btp_outgoing_key = ARGV[5]
+local routes_key = ARGV[6]
+local static_routes_key = ARGV[7]
+local default_route_key = ARGV[8]
+local assigned_addresses_key = ARGV[9]
+local accounts_key = ARGV[10]
+local balance_history_key = ARGV[11]
+local uncredited_amount_key = ARGV[12]
+local usage_key = ARGV[13]
+local old_id = ARGV[14]
+local new_id = ARGV[15]
+-- The rest are the periods of the account's usage counters
+
+-- Nothing to do if another node already migrated this account
+if redis.call('SISMEMBER', accounts_set_key, old_id) == 0 then
+    return 0
+end
+
+-- Move the per-account keys
+local function rename(prefix)
+    local old_key = prefix .. ':' .. old_id
+    if redis.call('EXISTS', old_key) == 1 then
+        redis.call('RENAME', old_key, prefix .. ':' .. new_id)
+    end
+end
+rename(accounts_key)
+rename(balance_history_key)
+rename(uncredited_amount_key)
+for i = 16, #ARGV do
+    local old_key = usage_key .. ':' .. old_id .. ':' .. ARGV[i]
+    if redis.call('EXISTS', old_key) == 1 then
+        redis.call('RENAME', old_key, usage_key .. ':' .. new_id .. ':' .. ARGV[i])
+    end
+end
+redis.call('HSET', accounts_key .. ':' .. new_id, 'id', new_id)
+
+-- Replace the id in the sets of account ids
+for _, set_key in ipairs({accounts_set_key, send_routes_key, receive_routes_key, btp_outgoing_key}) do
+    if redis.call('SREM', set_key, old_id) == 1 then
+        redis.call('SADD', set_key, new_id)
+    end
+end
+
+-- Replace the id in the hashes pointing to accounts
+for _, hash_key in ipairs({usernames_key, routes_key, static_routes_key}) do
+    local entries = redis.call('HGETALL', hash_key)
+    for i = 1, #entries, 2 do
+        if entries[i + 1] == old_id then
+            redis.call('HSET', hash_key, entries[i], new_id)
+        end
+    end
+end
+
+local suffix = redis.call('HGET', assigned_addresses_key, old_id)
+if suffix then
+    redis.call('HDEL', assigned_addresses_key, old_id)
+    redis.call('HSET', assigned_addresses_key, new_id, suffix)
+end
+
+if redis.call('GET', default_route_key) == old_id then
+    redis.call('SET', default_route_key, new_id)
+end
+
+return 1
@@ -0,0 +1,24 @@
+local accounts_key = ARGV[1]
+local results = {}
+
+-- The rest of the arguments are (type, account id, amount) triples, where
+-- the type is either 'fulfill' or 'reject'. Both increase the account's balance,
+-- but only fulfills may trigger a settlement (see process_fulfill.lua)
+for i = 2, #ARGV, 3 do
+    local account = accounts_key .. ':' .. ARGV[i + 1]
+    local amount = tonumber(ARGV[i + 2])
+
+    local balance = redis.call('HINCRBY', account, 'balance', amount)
+    local prepaid_amount, settle_threshold, settle_to = unpack(redis.call('HMGET', account, 'prepaid_amount', 'settle_threshold', 'settle_to'))
+
+    local settle_amount = 0
+    if ARGV[i] == 'fulfill' and (settle_threshold and settle_to) and (balance >= tonumber(settle_threshold)) and (tonumber(settle_threshold) > tonumber(settle_to)) then
+        settle_amount = balance - tonumber(settle_to)
+        balance = settle_to
+        redis.call('HSET', account, 'balance', balance)
+    end
+
+    table.insert(results, {balance + prepaid_amount, settle_amount})
+end
+
+return results
@@ -0,0 +1,55 @@
+-- Increments the usage counters of a packet's sender and receiver for each period.
+-- KEYS are the sender's and the receiver's counters of each period, in pairs, and
+-- ARGV the amounts sent and received followed by the expiry of each period.
+-- HINCRBY only counts up to the largest i64, so the amounts are added as the decimal
+-- strings Redis stores them as and saturate at the largest u64 instead
+local MAX_AMOUNT = '18446744073709551615'
+local incoming_amount = ARGV[1]
+local outgoing_amount = ARGV[2]
+
+local function add(a, b)
+    local digits = {}
+    local i, j, carry = string.len(a), string.len(b), 0
+    while i > 0 or j > 0 or carry > 0 do
+        local sum = carry
+        if i > 0 then
+            sum = sum + tonumber(string.sub(a, i, i))
+            i = i - 1
+        end
+        if j > 0 then
+            sum = sum + tonumber(string.sub(b, j, j))
+            j = j - 1
+        end
+        table.insert(digits, 1, sum % 10)
+        carry = math.floor(sum / 10)
+    end
+    return table.concat(digits)
+end
+
+local function exceeds(value, max)
+    if string.len(value) ~= string.len(max) then
+        return string.len(value) > string.len(max)
+    end
+    return value > max
+end
+
+local function add_amount(key, field, amount)
+    local total = add(redis.call('HGET', key, field) or '0', amount)
+    if exceeds(total, MAX_AMOUNT) then
+        total = MAX_AMOUNT
+    end
+    redis.call('HSET', key, field, total)
+end
+
+for period = 1, #KEYS / 2 do
+    local from_key, to_key = KEYS[2 * period - 1], KEYS[2 * period]
+    local expiry = ARGV[2 + period]
+
+    redis.call('HINCRBY', from_key, 'packets_sent', 1)
+    add_amount(from_key, 'amount_sent', incoming_amount)
+    redis.call('EXPIRE', from_key, expiry)
+
+    redis.call('HINCRBY', to_key, 'packets_received', 1)
+    add_amount(to_key, 'amount_received', outgoing_amount)
+    redis.call('EXPIRE', to_key, expiry)
+end
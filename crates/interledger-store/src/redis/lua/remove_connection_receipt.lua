@@ -0,0 +1,8 @@
+-- Subtracts an amount from the total received over the tagged connections of an account,
+-- unless the total already expired
+local received_key = KEYS[1]
+local amount = ARGV[1]
+
+if redis.call('EXISTS', received_key) == 1 then
+    redis.call('DECRBY', received_key, amount)
+end
@@ -1,14 +1,31 @@
 // The informal schema of our data in redis:
 //   send_routes_to         set         used for CCP routing
 //   receive_routes_from    set         used for CCP routing
-//   next_account_id        string      unique ID for each new account
-//   rates:current          hash        exchange rates
+//   rates:current          hash        exchange rates shared by all nodes using the db
+//   rates:updated_at       string      when the rates were last set (ms since the Unix epoch)
+//   rates:overrides        hash        fixed rates of asset pairs, keyed by "FROM/TO"
+//   rates:overrides_updated_at string  when the rate overrides were last set
 //   routes:current         hash        dynamic routing table
 //   routes:static          hash        static routing table
-//   accounts:<id>          hash        information for each account
+//   accounts:<id>          hash        information for each account, keyed by its UUID
+//                                      (numeric ids of earlier versions are migrated on connect)
 //   accounts               set
 //   usernames              hash
 //   btp_outgoing
+//   assigned_addresses     hash        address suffixes assigned to child accounts via ILDCP
+//   secondary_ilp_addresses list       addresses assigned by the node's other parents (multihoming)
+//   next_assigned_address  string      counter used to allocate address suffixes
+//   usage:<id>:<period>    hash        packets and amounts sent/received per day or month
+//   balance_history:<id>   list        most recent balance samples (16 bytes each), oldest first
+//   pending_settlements    hash        outgoing settlements not yet accepted by the engines
+//   pending_messages       hash        peer engines' messages not yet delivered to the engines
+//   webhooks               hash        each account's webhook (JSON, without its secret), keyed by account id
+//   webhook_secrets        hash        each account's encrypted webhook secret, keyed by account id
+//   conversion_dust        hash        dust lost converting each account's packets, keyed by account id
+//   settlement_history:<id> list       most recent settlements (JSON), oldest first
+//   stream_fulfilled:<key> string      marks a STREAM packet fulfilled by the node, until it expires
+//   stream_receipts:<id>   hash        amounts received over (and receive max of) each connection tag
+//   accounting:ledger      stream      ledger entries of the balance changes, for accounting systems
 // For interactive exploration of the store,
 // use the redis-cli tool included with your redis install.
 // Within redis-cli:
@@ -16,45 +33,72 @@
 //    smembers <key>        list the members of a set
 //    get <key>             get the value of a key
 //    hgetall <key>         the flattened list of every key/value entry within a hash
+/// Batching of the balance updates made once packets are fulfilled or rejected
+mod batch;
 mod reconnect;
+/// Shipping of store changes to standby nodes over Redis streams
+pub mod replication;
+use batch::{BalanceBatcher, BalanceUpdateKind};
 use reconnect::RedisReconnect;
 
 use super::account::{Account, AccountWithEncryptedTokens};
-use super::crypto::{encrypt_token, generate_keys, DecryptionKey, EncryptionKey};
+use super::crypto::{decrypt_token, encrypt_token, generate_keys, DecryptionKey, EncryptionKey};
+use super::replication::{ReplicaStore, ReplicationEvent, ReplicationSource};
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
 use futures::channel::mpsc::UnboundedSender;
 use http::StatusCode;
-use interledger_api::{AccountDetails, AccountSettings, EncryptedAccountSettings, NodeStore};
+use interledger_api::{
+    accounting::LEDGER_ENTRY_FIELDS, AccountDetails, AccountSettings, AccountingSink,
+    EncryptedAccountSettings, LedgerEntry, NodeStore, Webhook, WebhookStore,
+};
 use interledger_btp::BtpStore;
 use interledger_ccp::{CcpRoutingAccount, CcpRoutingStore, RoutingRelation};
 use interledger_errors::*;
 use interledger_http::HttpStore;
+use interledger_ildcp::AddressAssignmentStore;
 use interledger_packet::Address;
-use interledger_rates::ExchangeRateStore;
-use interledger_router::RouterStore;
-use interledger_service::{Account as AccountTrait, AccountStore, AddressStore, Username};
+use interledger_rates::{find_rate_override, validate_rate_overrides, ExchangeRateStore};
+use interledger_router::{RouteIndex, RouteIndexStats, RouterStore};
+use interledger_service::{
+    Account as AccountTrait, AccountId, AccountStore, AddressStore, Username,
+};
 use interledger_service_util::{
-    BalanceStore, RateLimitError, RateLimitStore, DEFAULT_ROUND_TRIP_TIME,
+    AccountUsage, BalanceHistoryStore, BalanceSample, BalanceStore, DedupStore, DustStore,
+    PacketStatus, RateLimitError, RateLimitStore, UsagePeriod, UsageStore, DEFAULT_ROUND_TRIP_TIME,
 };
 use interledger_settlement::core::{
-    idempotency::{IdempotentData, IdempotentStore},
+    amount::credit_settlement,
+    idempotency::{IdempotentData, IdempotentStore, DEFAULT_IDEMPOTENCY_KEY_TTL},
     scale_with_precision_loss,
-    types::{Convert, ConvertDetails, LeftoversStore, SettlementStore},
+    types::{
+        Convert, ConvertDetails, LeftoversStore, PendingMessage, PendingSettlement,
+        SettlementHistoryStore, SettlementQueueStore, SettlementRecord, SettlementStore,
+        SETTLEMENT_HISTORY_CAPACITY,
+    },
+};
+use interledger_spsp::{Contact, ContactStore};
+use interledger_stream::{
+    ConnectionReceipts, PaymentNotification, StreamNotificationsStore, StreamReceiveStore,
+    StreamReplayStore, SubAccountStore,
 };
-use interledger_stream::{PaymentNotification, StreamNotificationsStore};
 use num_bigint::BigUint;
 use once_cell::sync::Lazy;
 use parking_lot::{Mutex, RwLock};
 use redis_crate::AsyncCommands;
 use redis_crate::{
     self, cmd, from_redis_value, Client, ConnectionInfo, ControlFlow, ErrorKind, FromRedisValue,
-    PubSubCommands, RedisError, RedisWrite, Script, ToRedisArgs, Value,
+    Pipeline, PubSubCommands, RedisError, RedisWrite, Script, ToRedisArgs, Value,
 };
 use secrecy::{ExposeSecret, Secret, SecretBytesMut};
-use serde::{Deserialize, Serialize};
-use std::{borrow::Cow, str, str::FromStr, sync::Arc, time::Duration};
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    str,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::sync::broadcast;
 use tracing::{debug, error, trace, warn};
 use url::Url;
@@ -62,10 +106,25 @@ use uuid::Uuid;
 use zeroize::Zeroize;
 
 const DEFAULT_POLL_INTERVAL: u64 = 30000; // 30 seconds
-const ACCOUNT_DETAILS_FIELDS: usize = 21;
+const ACCOUNT_DETAILS_FIELDS: usize = 31;
 const DEFAULT_DB_PREFIX: &str = "";
+/// How many changes may be buffered for a standby node before it lags behind
+const REPLICATION_CHANNEL_CAPACITY: usize = 4096;
+/// How long usage counters are kept after their last update
+const DAILY_USAGE_EXPIRY: usize = 90 * 24 * 60 * 60; // 90 days
+const MONTHLY_USAGE_EXPIRY: usize = 400 * 24 * 60 * 60; // 400 days
+/// How long the totals received over tagged STREAM connections without a receive max are
+/// kept after their last packet
+const CONNECTION_RECEIPTS_EXPIRY: usize = 24 * 60 * 60; // 1 day
+/// Approximate number of entries the ledger stream is trimmed to
+const ACCOUNTING_LEDGER_MAX_LENGTH: usize = 1_000_000;
 
 static PARENT_ILP_KEY: &str = "parent_node_account_address";
+static SECONDARY_ILP_ADDRESSES_KEY: &str = "secondary_ilp_addresses";
+static RATES_KEY: &str = "rates:current";
+static RATES_UPDATED_AT_KEY: &str = "rates:updated_at";
+static RATE_OVERRIDES_KEY: &str = "rates:overrides";
+static RATE_OVERRIDES_UPDATED_AT_KEY: &str = "rates:overrides_updated_at";
 static ROUTES_KEY: &str = "routes:current";
 static STATIC_ROUTES_KEY: &str = "routes:static";
 static DEFAULT_ROUTE_KEY: &str = "routes:default";
@@ -76,6 +135,17 @@ static ACCOUNTS_KEY: &str = "accounts";
 static SEND_ROUTES_KEY: &str = "send_routes_to";
 static RECEIVE_ROUTES_FROM_KEY: &str = "receive_routes_from";
 static BPT_OUTGOING: &str = "btp_outgoing";
+static ASSIGNED_ADDRESSES_KEY: &str = "assigned_addresses";
+static NEXT_ASSIGNED_ADDRESS_KEY: &str = "next_assigned_address";
+static PENDING_SETTLEMENTS_KEY: &str = "pending_settlements";
+static PENDING_MESSAGES_KEY: &str = "pending_messages";
+static WEBHOOKS_KEY: &str = "webhooks";
+static WEBHOOK_SECRETS_KEY: &str = "webhook_secrets";
+static CONVERSION_DUST_KEY: &str = "conversion_dust";
+static ACCOUNTING_LEDGER_KEY: &str = "accounting:ledger";
+/// Sorted set of the saved idempotency keys, scored by the time (in milliseconds
+/// since the Unix epoch) their responses expire at
+static IDEMPOTENCY_KEYS_KEY: &str = "idempotency_keys";
 
 /// Domain separator for leftover amounts
 fn uncredited_amount_key(prefix: &str, account_id: impl ToString) -> String {
@@ -103,6 +173,65 @@ fn prefixed_key<'a>(prefix: &str, key: &'a str) -> Cow<'a, str> {
     }
 }
 
+/// Domain separator for usage counters
+fn usage_key(prefix: &str, account_id: Uuid, period: UsagePeriod) -> String {
+    prefixed_key(
+        prefix,
+        &format!("usage:{}:{}", account_id, period.current_period_id()),
+    )
+    .into_owned()
+}
+
+/// Domain separator for the packets being or already forwarded, by dedup key
+fn dedup_key(prefix: &str, key: &[u8; 32]) -> String {
+    let hex: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+    prefixed_key(prefix, &format!("dedup:{}", hex)).into_owned()
+}
+
+/// Domain separator for the STREAM packets fulfilled by the node
+fn stream_fulfilled_key(prefix: &str, key: &[u8; 32]) -> String {
+    let hex: String = key.iter().map(|byte| format!("{:02x}", byte)).collect();
+    prefixed_key(prefix, &format!("stream_fulfilled:{}", hex)).into_owned()
+}
+
+/// Domain separator for balance histories
+fn balance_history_key(prefix: &str, account_id: Uuid) -> String {
+    prefixed_key(prefix, &format!("balance_history:{}", account_id)).into_owned()
+}
+
+/// Domain separator for the contacts of an account
+fn contacts_key(prefix: &str, account_id: Uuid) -> String {
+    prefixed_key(prefix, &format!("contacts:{}", account_id)).into_owned()
+}
+
+/// Domain separator for the receive max of the tagged STREAM connections of an account
+fn stream_receipts_key(prefix: &str, account_id: Uuid) -> String {
+    prefixed_key(prefix, &format!("stream_receipts:{}", account_id)).into_owned()
+}
+
+/// Domain separator for the total received over the STREAM connections of an account with
+/// the given tag, which is kept apart so that it can expire
+fn stream_received_key(prefix: &str, account_id: Uuid, connection_tag: &str) -> String {
+    prefixed_key(
+        prefix,
+        &format!("stream_received:{}:{}", account_id, connection_tag),
+    )
+    .into_owned()
+}
+
+/// Rejects the amounts which do not fit in the signed integers Redis counts with
+fn check_receipt_amount(amount: u64) -> Result<(), StreamReceiveStoreError> {
+    if amount > i64::MAX as u64 {
+        return Err(StreamReceiveStoreError::AmountTooLarge(amount));
+    }
+    Ok(())
+}
+
+/// Domain separator for settlement histories
+fn settlement_history_key(prefix: &str, account_id: Uuid) -> String {
+    prefixed_key(prefix, &format!("settlement_history:{}", account_id)).into_owned()
+}
+
 /// Domain separator for accounts
 fn accounts_key(prefix: &str, account_id: Uuid) -> String {
     prefixed_key(prefix, &format!("accounts:{}", account_id)).into_owned()
@@ -143,6 +272,11 @@ static PROCESS_FULFILL: Lazy<Script> =
 static PROCESS_REJECT: Lazy<Script> =
     Lazy::new(|| Script::new(include_str!("lua/process_reject.lua")));
 
+/// Lua script which allocates an address for a child account (unless it already has one)
+/// and updates the account and its route to use it
+static ASSIGN_ADDRESS: Lazy<Script> =
+    Lazy::new(|| Script::new(include_str!("lua/assign_address.lua")));
+
 static PROCESS_DELAYED_SETTLEMENT: Lazy<Script> =
     Lazy::new(|| Script::new(include_str!("lua/process_settle.lua")));
 
@@ -154,6 +288,28 @@ static REFUND_SETTLEMENT: Lazy<Script> =
 static PROCESS_INCOMING_SETTLEMENT: Lazy<Script> =
     Lazy::new(|| Script::new(include_str!("lua/process_incoming_settlement.lua")));
 
+/// Lua script which replaces a legacy numeric account id with its UUID everywhere it is referenced
+static MIGRATE_ACCOUNT_ID: Lazy<Script> =
+    Lazy::new(|| Script::new(include_str!("lua/migrate_account_id.lua")));
+
+/// Lua script which charges a packet to the token buckets of an account's rate limits,
+/// or refunds its amount
+static APPLY_RATE_LIMITS: Lazy<Script> =
+    Lazy::new(|| Script::new(include_str!("lua/apply_rate_limits.lua")));
+
+/// Lua script which increments the usage counters of a packet's sender and receiver
+static RECORD_USAGE: Lazy<Script> = Lazy::new(|| Script::new(include_str!("lua/record_usage.lua")));
+
+/// Lua script which adds an amount received over a tagged STREAM connection to its total,
+/// unless it would exceed the connection's receive max
+static ADD_CONNECTION_RECEIPT: Lazy<Script> =
+    Lazy::new(|| Script::new(include_str!("lua/add_connection_receipt.lua")));
+
+/// Lua script which subtracts an amount which was not received over a tagged STREAM
+/// connection from its total
+static REMOVE_CONNECTION_RECEIPT: Lazy<Script> =
+    Lazy::new(|| Script::new(include_str!("lua/remove_connection_receipt.lua")));
+
 /// Builder for the Redis Store
 pub struct RedisStoreBuilder {
     redis_url: ConnectionInfo,
@@ -162,6 +318,11 @@ pub struct RedisStoreBuilder {
     /// Connector's ILP Address. Used to insert `Child` accounts as
     node_ilp_address: Address,
     db_prefix: String,
+    /// Prefix the keys of the node are moved from when connecting, if any
+    migrate_from_db_prefix: Option<String>,
+    /// Window and maximum size of the balance update batches, if they are batched
+    balance_batching: Option<(Duration, usize)>,
+    idempotency_key_ttl: Duration,
 }
 
 impl RedisStoreBuilder {
@@ -173,6 +334,9 @@ impl RedisStoreBuilder {
             poll_interval: DEFAULT_POLL_INTERVAL,
             node_ilp_address: DEFAULT_ILP_ADDRESS.clone(),
             db_prefix: DEFAULT_DB_PREFIX.to_string(),
+            migrate_from_db_prefix: None,
+            balance_batching: None,
+            idempotency_key_ttl: DEFAULT_IDEMPOTENCY_KEY_TTL,
         }
     }
 
@@ -182,7 +346,7 @@ impl RedisStoreBuilder {
         self
     }
 
-    /// Sets the poll interval at which the store will update its routes
+    /// Sets the poll interval at which the store will update its routes and exchange rates
     pub fn poll_interval(&mut self, poll_interval: u64) -> &mut Self {
         self.poll_interval = poll_interval;
         self
@@ -195,6 +359,34 @@ impl RedisStoreBuilder {
         self
     }
 
+    /// Moves the keys of a node which used the given db prefix (which may be empty) under
+    /// the prefix set with [`with_db_prefix`](#method.with_db_prefix) when connecting.
+    /// This way an existing deployment can start sharing its redis db with other nodes.
+    /// Keys which already exist under the new prefix are left untouched.
+    pub fn migrate_from_db_prefix(&mut self, old_prefix: &str) -> &mut Self {
+        self.migrate_from_db_prefix = Some(old_prefix.to_string());
+        self
+    }
+
+    /// Batches the balance updates made once packets are fulfilled or rejected, so that
+    /// those made within `window` are applied to Redis in a single round trip. A batch is
+    /// applied early if it reaches `max_batch_size` updates.
+    ///
+    /// This reduces the load on Redis at the cost of delaying the fulfills and rejects by
+    /// up to `window`. The sizes of the batches are recorded in the
+    /// `store.redis.balance_batch.size` histogram.
+    pub fn balance_batching(&mut self, window: Duration, max_batch_size: usize) -> &mut Self {
+        self.balance_batching = Some((window, max_batch_size));
+        self
+    }
+
+    /// Sets how long the responses to the idempotent settlement API requests
+    /// are kept (defaults to 24 hours)
+    pub fn idempotency_key_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.idempotency_key_ttl = ttl;
+        self
+    }
+
     /// Connects to the Redis Store
     ///
     /// Specifically
@@ -219,6 +411,14 @@ impl RedisStoreBuilder {
         let mut sub_connection = client
             .get_connection()
             .map_err(|err| error!("Error connecting subscription client to Redis: {:?}", err))?;
+        if let Some(old_prefix) = &self.migrate_from_db_prefix {
+            migrate_db_prefix(connection.clone(), old_prefix, &self.db_prefix)
+                .map_err(|err| error!("Error migrating keys to the new db prefix: {:?}", err))
+                .await?;
+        }
+        migrate_legacy_account_ids(connection.clone(), &self.db_prefix)
+            .map_err(|err| error!("Error migrating legacy account ids: {:?}", err))
+            .await?;
         // Before initializing the store, check if we have an address
         // that was configured due to adding a parent. If no parent was
         // found, use the builder's provided address (local.host) or the
@@ -237,19 +437,46 @@ impl RedisStoreBuilder {
         } else {
             ilp_address
         };
+        let secondary_ilp_addresses: Vec<String> = connection
+            .lrange(
+                &*prefixed_key(&self.db_prefix, SECONDARY_ILP_ADDRESSES_KEY),
+                0,
+                -1,
+            )
+            .map_err(|err| error!("Error loading the secondary ILP addresses: {:?}", err))
+            .await?;
+        let secondary_ilp_addresses = secondary_ilp_addresses
+            .iter()
+            .filter_map(|address| Address::from_str(address).ok())
+            .collect();
 
         let (all_payment_publisher, _) = broadcast::channel::<PaymentNotification>(256);
+        let balance_batcher = self.balance_batching.map(|(window, max_batch_size)| {
+            BalanceBatcher::spawn(
+                connection.clone(),
+                prefixed_key(&self.db_prefix, ACCOUNTS_KEY).into_owned(),
+                window,
+                max_batch_size,
+            )
+        });
 
         let store = RedisStore {
             ilp_address: Arc::new(RwLock::new(node_ilp_address)),
+            secondary_ilp_addresses: Arc::new(RwLock::new(secondary_ilp_addresses)),
             connection,
             subscriptions: Arc::new(Mutex::new(HashMap::new())),
             payment_publisher: all_payment_publisher,
             exchange_rates: Arc::new(RwLock::new(HashMap::new())),
-            routes: Arc::new(RwLock::new(Arc::new(HashMap::new()))),
+            exchange_rates_updated_at: Arc::new(RwLock::new(None)),
+            rate_overrides: Arc::new(RwLock::new(HashMap::new())),
+            rate_overrides_updated_at: Arc::new(RwLock::new(None)),
+            routes: Arc::new(RouteIndex::new()),
             encryption_key: Arc::new(encryption_key),
             decryption_key: Arc::new(decryption_key),
             db_prefix: self.db_prefix.clone(),
+            replication_publisher: broadcast::channel(REPLICATION_CHANNEL_CAPACITY).0,
+            balance_batcher,
+            idempotency_key_ttl: self.idempotency_key_ttl,
         };
 
         // Poll for routing table updates
@@ -257,6 +484,10 @@ impl RedisStoreBuilder {
         let connection_clone = Arc::downgrade(&store.connection.conn);
         let redis_info = store.connection.redis_info.clone();
         let routing_table = store.routes.clone();
+        let exchange_rates = store.exchange_rates.clone();
+        let exchange_rates_updated_at = store.exchange_rates_updated_at.clone();
+        let rate_overrides = store.rate_overrides.clone();
+        let rate_overrides_updated_at = store.rate_overrides_updated_at.clone();
 
         let db_prefix = self.db_prefix.clone();
         let poll_routes = async move {
@@ -265,15 +496,30 @@ impl RedisStoreBuilder {
             loop {
                 interval.tick().await;
                 if let Some(conn) = connection_clone.upgrade() {
-                    let _ = update_routes(
-                        RedisReconnect {
-                            conn,
-                            redis_info: redis_info.clone(),
-                        },
-                        routing_table.clone(),
-                        &db_prefix,
+                    let connection = RedisReconnect {
+                        conn,
+                        redis_info: redis_info.clone(),
+                    };
+                    let _ = update_routes(connection.clone(), routing_table.clone(), &db_prefix)
+                        .map_err(|err| error!("{}", err))
+                        .await;
+                    let _ = update_rates(
+                        connection.clone(),
+                        exchange_rates.clone(),
+                        exchange_rates_updated_at.clone(),
+                        &prefixed_key(&db_prefix, RATES_KEY),
+                        &prefixed_key(&db_prefix, RATES_UPDATED_AT_KEY),
+                    )
+                    .map_err(|err| error!("Error loading the exchange rates: {}", err))
+                    .await;
+                    let _ = update_rates(
+                        connection,
+                        rate_overrides.clone(),
+                        rate_overrides_updated_at.clone(),
+                        &prefixed_key(&db_prefix, RATE_OVERRIDES_KEY),
+                        &prefixed_key(&db_prefix, RATE_OVERRIDES_UPDATED_AT_KEY),
                     )
-                    .map_err(|err| error!("{}", err))
+                    .map_err(|err| error!("Error loading the rate overrides: {}", err))
                     .await;
                 } else {
                     debug!("Not polling routes anymore because connection was closed");
@@ -362,36 +608,123 @@ impl RedisStoreBuilder {
 pub struct RedisStore {
     /// The Store's ILP Address
     ilp_address: Arc<RwLock<Address>>,
+    /// The addresses assigned by the node's other parents, when it is multihomed
+    secondary_ilp_addresses: Arc<RwLock<Vec<Address>>>,
     /// A connection which reconnects if dropped by accident
     connection: RedisReconnect,
     /// WebSocket senders which publish incoming payment updates
     subscriptions: Arc<Mutex<HashMap<Uuid, Vec<UnboundedSender<PaymentNotification>>>>>,
     /// A subscriber to all payment notifications, exposed via a WebSocket
     payment_publisher: broadcast::Sender<PaymentNotification>,
+    /// The rates are kept in memory for the same reason as the routing table. They are
+    /// also saved in the db, so that all the nodes sharing it use the same rates.
     exchange_rates: Arc<RwLock<HashMap<String, f64>>>,
+    /// When the exchange rates were last set, by this node or another one
+    exchange_rates_updated_at: Arc<RwLock<Option<SystemTime>>>,
+    /// Fixed rates of asset pairs, which take precedence over the exchange rates
+    rate_overrides: Arc<RwLock<HashMap<String, f64>>>,
+    /// When the rate overrides were last set, by this node or another one
+    rate_overrides_updated_at: Arc<RwLock<Option<SystemTime>>>,
     /// The store keeps the routing table in memory so that it can be returned
     /// synchronously while the Router is processing packets.
-    /// The outer `Arc<RwLock>` is used so that we can update the stored routing
-    /// table after polling the store for updates.
-    /// The inner `Arc<HashMap>` is used so that the `routing_table` method can
-    /// return a reference to the routing table without cloning the underlying data.
-    routes: Arc<RwLock<Arc<HashMap<String, Uuid>>>>,
+    /// It is replaced after polling the store for updates, and indexed by prefix so
+    /// that the packets are routed without scanning the whole table.
+    routes: Arc<RouteIndex>,
     /// Encryption Key so that the no cleartext data are stored
     encryption_key: Arc<Secret<EncryptionKey>>,
     /// Decryption Key to provide cleartext data to users
     decryption_key: Arc<Secret<DecryptionKey>>,
     /// Prefix for all top level keys. This enables multiple nodes to use the same db instance.
     db_prefix: String,
+    /// Publishes the changes made to the store to standby nodes
+    replication_publisher: broadcast::Sender<ReplicationEvent>,
+    /// Applies the fulfill and reject balance updates in batches, if enabled
+    balance_batcher: Option<BalanceBatcher>,
+    /// How long the idempotent responses are kept
+    idempotency_key_ttl: Duration,
 }
 
 impl RedisStore {
+    /// Moves `amount` from the balance of an account to one of its sub-accounts
+    /// and publishes the change
+    async fn redis_credit_sub_account(
+        &self,
+        account_id: Uuid,
+        sub_account_id: Uuid,
+        amount: u64,
+    ) -> Result<(), RedisError> {
+        let mut pipe = redis_crate::pipe();
+        pipe.atomic()
+            .hincr(
+                accounts_key(&self.db_prefix, account_id),
+                "balance",
+                -(amount as i64),
+            )
+            .ignore()
+            .hincr(
+                accounts_key(&self.db_prefix, sub_account_id),
+                "balance",
+                amount,
+            )
+            .ignore();
+        let _: () = pipe.query_async(&mut self.connection.clone()).await?;
+
+        trace!(
+            "Moved {} from account {} to sub-account {}",
+            amount,
+            account_id,
+            sub_account_id
+        );
+        self.publish_change(ReplicationEvent::SubAccountCredited {
+            account_id,
+            sub_account_id,
+            amount,
+        });
+        Ok(())
+    }
+
+    /// Replaces the routes learned via CCP and publishes the change
+    async fn redis_set_routes(&self, routes: Vec<(String, AccountId)>) -> Result<(), RedisError> {
+        let num_routes = routes.len();
+        let mut connection = self.connection.clone();
+
+        // Save routes to Redis
+        let mut pipe = redis_crate::pipe();
+        pipe.atomic()
+            .del(&*prefixed_key(&self.db_prefix, ROUTES_KEY))
+            .ignore()
+            .hset_multiple(&*prefixed_key(&self.db_prefix, ROUTES_KEY), &routes)
+            .ignore();
+
+        pipe.query_async(&mut connection).await?;
+        trace!("Saved {} routes to Redis", num_routes);
+
+        update_routes(connection, self.routes.clone(), &self.db_prefix).await?;
+        self.publish_change(ReplicationEvent::RoutesSet {
+            routes: routes
+                .into_iter()
+                .map(|(prefix, account_id)| (prefix, account_id.into()))
+                .collect(),
+        });
+        Ok(())
+    }
+
+    /// Publishes a change to the standby nodes following this store, if there are any
+    fn publish_change(&self, event: ReplicationEvent) {
+        if self.replication_publisher.receiver_count() > 0 {
+            if let Err(err) = self.replication_publisher.send(event) {
+                warn!("Failed to publish a change for replication: {:?}", err);
+            }
+        }
+    }
+
     /// Gets all the account ids from Redis
     async fn get_all_accounts_ids(&self) -> Result<Vec<Uuid>, NodeStoreError> {
         let mut connection = self.connection.clone();
-        let account_ids: Vec<RedisAccountId> = connection
+        let account_ids: Vec<AccountId> = connection
             .smembers(&*prefixed_key(&self.db_prefix, ACCOUNTS_KEY))
             .await?;
-        Ok(account_ids.iter().map(|rid| rid.0).collect())
+        Ok(account_ids.into_iter().map(Uuid::from).collect())
     }
 
     /// Inserts the account corresponding to the provided `AccountWithEncryptedtokens`
@@ -411,9 +744,6 @@ impl RedisStore {
             &*prefixed_key(&self.db_prefix, USERNAMES_KEY),
             account.username().as_ref(),
         );
-        if account.routing_relation == RoutingRelation::Parent {
-            pipe.exists(&*prefixed_key(&self.db_prefix, PARENT_ILP_KEY));
-        }
 
         let results: Vec<bool> = pipe.query_async(&mut connection).await?;
         if results.iter().any(|val| *val) {
@@ -426,11 +756,29 @@ impl RedisStore {
 
         let mut pipe = redis_crate::pipe();
         pipe.atomic();
+        self.add_account_commands(&mut pipe, encrypted);
+
+        // The parent account settings are done via the API. We just
+        // had to check for the existence of a parent
+        pipe.query_async(&mut connection).await?;
+
+        update_routes(connection, routing_table, &self.db_prefix).await?;
+        debug!(
+            "Inserted account {} (ILP address: {})",
+            account.id, account.ilp_address
+        );
+        Ok(())
+    }
+
+    /// Adds the commands writing the new account to the pipeline
+    fn add_account_commands(&self, pipe: &mut Pipeline, encrypted: &AccountWithEncryptedTokens) {
+        let account = &encrypted.account;
+        let id = accounts_key(&self.db_prefix, account.id);
 
         // Add the account key to the list of accounts
         pipe.sadd(
             &*prefixed_key(&self.db_prefix, ACCOUNTS_KEY),
-            RedisAccountId(account.id),
+            AccountId::from(account.id),
         )
         .ignore();
 
@@ -438,7 +786,7 @@ impl RedisStore {
         pipe.hset(
             &*prefixed_key(&self.db_prefix, USERNAMES_KEY),
             account.username().as_ref(),
-            RedisAccountId(account.id),
+            AccountId::from(account.id),
         )
         .ignore();
 
@@ -449,7 +797,7 @@ impl RedisStore {
         if account.should_send_routes() {
             pipe.sadd(
                 &*prefixed_key(&self.db_prefix, SEND_ROUTES_KEY),
-                RedisAccountId(account.id),
+                AccountId::from(account.id),
             )
             .ignore();
         }
@@ -457,7 +805,7 @@ impl RedisStore {
         if account.should_receive_routes() {
             pipe.sadd(
                 &*prefixed_key(&self.db_prefix, RECEIVE_ROUTES_FROM_KEY),
-                RedisAccountId(account.id),
+                AccountId::from(account.id),
             )
             .ignore();
         }
@@ -465,7 +813,7 @@ impl RedisStore {
         if account.ilp_over_btp_url.is_some() {
             pipe.sadd(
                 &*prefixed_key(&self.db_prefix, BPT_OUTGOING),
-                RedisAccountId(account.id),
+                AccountId::from(account.id),
             )
             .ignore();
         }
@@ -474,23 +822,12 @@ impl RedisStore {
         pipe.hset(
             &*prefixed_key(&self.db_prefix, ROUTES_KEY),
             account.ilp_address.as_bytes(),
-            RedisAccountId(account.id),
+            AccountId::from(account.id),
         )
         .ignore();
 
         // Set account details
         pipe.cmd("HMSET").arg(&id).arg(encrypted).ignore();
-
-        // The parent account settings are done via the API. We just
-        // had to check for the existence of a parent
-        pipe.query_async(&mut connection).await?;
-
-        update_routes(connection, routing_table, &self.db_prefix).await?;
-        debug!(
-            "Inserted account {} (ILP address: {})",
-            account.id, account.ilp_address
-        );
-        Ok(())
     }
 
     /// Overwrites the account corresponding to the provided `AccountWithEncryptedtokens`
@@ -526,7 +863,7 @@ impl RedisStore {
         // Add the account key to the list of accounts
         pipe.sadd(
             &*prefixed_key(&self.db_prefix, ACCOUNTS_KEY),
-            RedisAccountId(account.id),
+            AccountId::from(account.id),
         )
         .ignore();
 
@@ -539,7 +876,7 @@ impl RedisStore {
         if account.should_send_routes() {
             pipe.sadd(
                 &*prefixed_key(&self.db_prefix, SEND_ROUTES_KEY),
-                RedisAccountId(account.id),
+                AccountId::from(account.id),
             )
             .ignore();
         }
@@ -547,7 +884,7 @@ impl RedisStore {
         if account.should_receive_routes() {
             pipe.sadd(
                 &*prefixed_key(&self.db_prefix, RECEIVE_ROUTES_FROM_KEY),
-                RedisAccountId(account.id),
+                AccountId::from(account.id),
             )
             .ignore();
         }
@@ -555,7 +892,7 @@ impl RedisStore {
         if account.ilp_over_btp_url.is_some() {
             pipe.sadd(
                 &*prefixed_key(&self.db_prefix, BPT_OUTGOING),
-                RedisAccountId(account.id),
+                AccountId::from(account.id),
             )
             .ignore();
         }
@@ -564,7 +901,7 @@ impl RedisStore {
         pipe.hset(
             &*prefixed_key(&self.db_prefix, ROUTES_KEY),
             account.ilp_address.to_bytes().to_vec(),
-            RedisAccountId(account.id),
+            AccountId::from(account.id),
         )
         .ignore();
 
@@ -634,7 +971,7 @@ impl RedisStore {
             pipe.hset(&accounts_key, "settle_to", settle_to);
         }
 
-        pipe.query_async(&mut self.connection.clone()).await?;
+        let _: () = pipe.query_async(&mut self.connection.clone()).await?;
 
         // return the updated account
         self.redis_get_account(id).await
@@ -648,7 +985,7 @@ impl RedisStore {
         let mut accounts: Vec<AccountWithEncryptedTokens> = LOAD_ACCOUNTS
             .arg(&*prefixed_key(&self.db_prefix, ACCOUNTS_KEY))
             .arg(&*prefixed_key(&self.db_prefix, SETTLEMENT_ENGINES_KEY))
-            .arg(RedisAccountId(id))
+            .arg(AccountId::from(id))
             .invoke_async(&mut self.connection.clone())
             .await?;
         accounts
@@ -664,12 +1001,18 @@ impl RedisStore {
     ) -> Result<AccountWithEncryptedTokens, NodeStoreError> {
         let encrypted = self.redis_get_account(id).await?;
         let account = &encrypted.account;
+        // The totals of the connections with a receive max do not expire on their own
+        let receipt_fields: Vec<String> = self
+            .connection
+            .clone()
+            .hkeys(stream_receipts_key(&self.db_prefix, id))
+            .await?;
         let mut pipe = redis_crate::pipe();
         pipe.atomic();
 
         pipe.srem(
             &*prefixed_key(&self.db_prefix, ACCOUNTS_KEY),
-            RedisAccountId(account.id),
+            AccountId::from(account.id),
         )
         .ignore();
         pipe.del(&*accounts_key(&self.db_prefix, account.id))
@@ -683,7 +1026,7 @@ impl RedisStore {
         if account.should_send_routes() {
             pipe.srem(
                 &*prefixed_key(&self.db_prefix, SEND_ROUTES_KEY),
-                RedisAccountId(account.id),
+                AccountId::from(account.id),
             )
             .ignore();
         }
@@ -691,7 +1034,7 @@ impl RedisStore {
         if account.should_receive_routes() {
             pipe.srem(
                 &*prefixed_key(&self.db_prefix, RECEIVE_ROUTES_FROM_KEY),
-                RedisAccountId(account.id),
+                AccountId::from(account.id),
             )
             .ignore();
         }
@@ -699,7 +1042,7 @@ impl RedisStore {
         if account.ilp_over_btp_url.is_some() {
             pipe.srem(
                 &*prefixed_key(&self.db_prefix, BPT_OUTGOING),
-                RedisAccountId(account.id),
+                AccountId::from(account.id),
             )
             .ignore();
         }
@@ -710,7 +1053,40 @@ impl RedisStore {
         )
         .ignore();
 
+        pipe.hdel(
+            &*prefixed_key(&self.db_prefix, ASSIGNED_ADDRESSES_KEY),
+            AccountId::from(account.id),
+        )
+        .ignore();
+
         pipe.del(uncredited_amount_key(&self.db_prefix, id));
+        pipe.del(balance_history_key(&self.db_prefix, id)).ignore();
+        pipe.del(contacts_key(&self.db_prefix, id)).ignore();
+        pipe.del(stream_receipts_key(&self.db_prefix, id)).ignore();
+        for connection_tag in receipt_fields
+            .iter()
+            .filter_map(|field| field.strip_prefix("receive_max:"))
+        {
+            pipe.del(stream_received_key(&self.db_prefix, id, connection_tag))
+                .ignore();
+        }
+        pipe.hdel(
+            &*prefixed_key(&self.db_prefix, WEBHOOKS_KEY),
+            id.to_string(),
+        )
+        .ignore();
+        pipe.hdel(
+            &*prefixed_key(&self.db_prefix, WEBHOOK_SECRETS_KEY),
+            id.to_string(),
+        )
+        .ignore();
+        pipe.hdel(
+            &*prefixed_key(&self.db_prefix, CONVERSION_DUST_KEY),
+            id.to_string(),
+        )
+        .ignore();
+        pipe.del(settlement_history_key(&self.db_prefix, id))
+            .ignore();
 
         let mut connection = self.connection.clone();
         pipe.query_async(&mut connection).await?;
@@ -763,7 +1139,7 @@ impl AccountStore for RedisStore {
         username: &Username,
     ) -> Result<Uuid, AccountStoreError> {
         let username = username.clone();
-        let id: Option<RedisAccountId> = self
+        let id: Option<AccountId> = self
             .connection
             .clone()
             .hget(
@@ -772,7 +1148,7 @@ impl AccountStore for RedisStore {
             )
             .await?;
         match id {
-            Some(rid) => Ok(rid.0),
+            Some(rid) => Ok(rid.into()),
             None => {
                 debug!("Username not found: {}", username);
                 Err(AccountStoreError::AccountNotFound(username.to_string()))
@@ -870,7 +1246,7 @@ impl BalanceStore for RedisStore {
 
         let balance: i64 = PROCESS_PREPARE
             .arg(&*prefixed_key(&self.db_prefix, ACCOUNTS_KEY))
-            .arg(RedisAccountId(from_account_id))
+            .arg(AccountId::from(from_account_id))
             .arg(incoming_amount)
             .invoke_async(&mut self.connection.clone())
             .await?;
@@ -879,6 +1255,10 @@ impl BalanceStore for RedisStore {
             "Processed prepare with incoming amount: {}. Account {} has balance (including prepaid amount): {} ",
             incoming_amount, from_account_id, balance
         );
+        self.publish_change(ReplicationEvent::Prepare {
+            account_id: from_account_id,
+            amount: incoming_amount,
+        });
         Ok(())
     }
 
@@ -887,12 +1267,21 @@ impl BalanceStore for RedisStore {
         to_account_id: Uuid,
         outgoing_amount: u64,
     ) -> Result<(i64, u64), BalanceStoreError> {
-        let (balance, amount_to_settle): (i64, u64) = PROCESS_FULFILL
-            .arg(&*prefixed_key(&self.db_prefix, ACCOUNTS_KEY))
-            .arg(RedisAccountId(to_account_id))
-            .arg(outgoing_amount)
-            .invoke_async(&mut self.connection.clone())
-            .await?;
+        let (balance, amount_to_settle): (i64, u64) = match self.balance_batcher {
+            Some(ref batcher) => {
+                batcher
+                    .update(BalanceUpdateKind::Fulfill, to_account_id, outgoing_amount)
+                    .await?
+            }
+            None => {
+                PROCESS_FULFILL
+                    .arg(&*prefixed_key(&self.db_prefix, ACCOUNTS_KEY))
+                    .arg(AccountId::from(to_account_id))
+                    .arg(outgoing_amount)
+                    .invoke_async(&mut self.connection.clone())
+                    .await?
+            }
+        };
 
         trace!(
             "Processed fulfill for account {} for outgoing amount {}. Fulfill call result: {} {}",
@@ -901,6 +1290,10 @@ impl BalanceStore for RedisStore {
             balance,
             amount_to_settle,
         );
+        self.publish_change(ReplicationEvent::Fulfill {
+            account_id: to_account_id,
+            amount: outgoing_amount,
+        });
         Ok((balance, amount_to_settle))
     }
 
@@ -913,17 +1306,31 @@ impl BalanceStore for RedisStore {
             return Ok(());
         }
 
-        let balance: i64 = PROCESS_REJECT
-            .arg(&*prefixed_key(&self.db_prefix, ACCOUNTS_KEY))
-            .arg(RedisAccountId(from_account_id))
-            .arg(incoming_amount)
-            .invoke_async(&mut self.connection.clone())
-            .await?;
+        let balance: i64 = match self.balance_batcher {
+            Some(ref batcher) => {
+                batcher
+                    .update(BalanceUpdateKind::Reject, from_account_id, incoming_amount)
+                    .await?
+                    .0
+            }
+            None => {
+                PROCESS_REJECT
+                    .arg(&*prefixed_key(&self.db_prefix, ACCOUNTS_KEY))
+                    .arg(AccountId::from(from_account_id))
+                    .arg(incoming_amount)
+                    .invoke_async(&mut self.connection.clone())
+                    .await?
+            }
+        };
 
         trace!(
             "Processed reject for incoming amount: {}. Account {} has balance (including prepaid amount): {}",
             incoming_amount, from_account_id, balance
         );
+        self.publish_change(ReplicationEvent::Reject {
+            account_id: from_account_id,
+            amount: incoming_amount,
+        });
 
         Ok(())
     }
@@ -934,7 +1341,7 @@ impl BalanceStore for RedisStore {
     ) -> Result<(i64, u64), BalanceStoreError> {
         let (balance, amount_to_settle): (i64, u64) = PROCESS_DELAYED_SETTLEMENT
             .arg(&*prefixed_key(&self.db_prefix, ACCOUNTS_KEY))
-            .arg(RedisAccountId(to_account_id))
+            .arg(AccountId::from(to_account_id))
             .invoke_async(&mut self.connection.clone())
             .await?;
 
@@ -944,6 +1351,9 @@ impl BalanceStore for RedisStore {
             balance,
             amount_to_settle
         );
+        self.publish_change(ReplicationEvent::DelayedSettlement {
+            account_id: to_account_id,
+        });
 
         Ok((balance, amount_to_settle))
     }
@@ -974,10 +1384,79 @@ impl ExchangeRateStore for RedisStore {
         &self,
         rates: HashMap<String, f64>,
     ) -> Result<(), ExchangeRateStoreError> {
-        // TODO publish rate updates through a pubsub mechanism to support horizontally scaling nodes
-        (*self.exchange_rates.write()) = rates;
+        let updated_at = SystemTime::now();
+        (*self.exchange_rates.write()) = rates.clone();
+        (*self.exchange_rates_updated_at.write()) = Some(updated_at);
+
+        // Save the rates so that the other nodes using the db pick them up when they poll it
+        let mut pipe = redis_crate::pipe();
+        let rates_key = prefixed_key(&self.db_prefix, RATES_KEY);
+        pipe.atomic().del(&*rates_key).ignore();
+        if !rates.is_empty() {
+            let rates: Vec<(String, f64)> = rates.into_iter().collect();
+            pipe.hset_multiple(&*rates_key, &rates).ignore();
+        }
+        pipe.set(
+            &*prefixed_key(&self.db_prefix, RATES_UPDATED_AT_KEY),
+            updated_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        )
+        .ignore();
+        let mut connection = self.connection.clone();
+        tokio::spawn(async move {
+            if let Err(err) = pipe.query_async::<_, ()>(&mut connection).await {
+                error!("Error saving the exchange rates: {}", err);
+            }
+        });
+        Ok(())
+    }
+
+    fn get_exchange_rates_updated_at(&self) -> Option<SystemTime> {
+        *self.exchange_rates_updated_at.read()
+    }
+
+    fn set_rate_overrides(
+        &self,
+        overrides: HashMap<String, f64>,
+    ) -> Result<(), ExchangeRateStoreError> {
+        let overrides = validate_rate_overrides(overrides)?;
+        let updated_at = SystemTime::now();
+        (*self.rate_overrides.write()) = overrides.clone();
+        (*self.rate_overrides_updated_at.write()) = Some(updated_at);
+
+        let mut pipe = redis_crate::pipe();
+        let overrides_key = prefixed_key(&self.db_prefix, RATE_OVERRIDES_KEY);
+        pipe.atomic().del(&*overrides_key).ignore();
+        if !overrides.is_empty() {
+            let overrides: Vec<(String, f64)> = overrides.into_iter().collect();
+            pipe.hset_multiple(&*overrides_key, &overrides).ignore();
+        }
+        pipe.set(
+            &*prefixed_key(&self.db_prefix, RATE_OVERRIDES_UPDATED_AT_KEY),
+            updated_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+        )
+        .ignore();
+        let mut connection = self.connection.clone();
+        tokio::spawn(async move {
+            if let Err(err) = pipe.query_async::<_, ()>(&mut connection).await {
+                error!("Error saving the rate overrides: {}", err);
+            }
+        });
         Ok(())
     }
+
+    fn get_rate_overrides(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError> {
+        Ok((*self.rate_overrides.read()).clone())
+    }
+
+    fn get_rate_override(&self, from: &str, to: &str) -> Option<f64> {
+        find_rate_override(&self.rate_overrides.read(), from, to)
+    }
 }
 
 #[async_trait]
@@ -1026,12 +1505,12 @@ impl BtpStore for RedisStore {
     }
 
     async fn get_btp_outgoing_accounts(&self) -> Result<Vec<Self::Account>, BtpStoreError> {
-        let account_ids: Vec<RedisAccountId> = self
+        let account_ids: Vec<AccountId> = self
             .connection
             .clone()
             .smembers(&*prefixed_key(&self.db_prefix, BPT_OUTGOING))
             .await?;
-        let account_ids: Vec<Uuid> = account_ids.into_iter().map(|id| id.0).collect();
+        let account_ids: Vec<Uuid> = account_ids.into_iter().map(Uuid::from).collect();
 
         if account_ids.is_empty() {
             return Ok(Vec::new());
@@ -1082,7 +1561,15 @@ impl HttpStore for RedisStore {
 
 impl RouterStore for RedisStore {
     fn routing_table(&self) -> Arc<HashMap<String, Uuid>> {
-        self.routes.read().clone()
+        self.routes.routing_table()
+    }
+
+    fn route_for(&self, destination: &str) -> Option<(usize, Uuid)> {
+        self.routes.lookup(destination)
+    }
+
+    fn route_index_stats(&self) -> Option<RouteIndexStats> {
+        Some(self.routes.stats())
     }
 }
 
@@ -1095,6 +1582,7 @@ impl NodeStore for RedisStore {
         account: AccountDetails,
     ) -> Result<Self::Account, NodeStoreError> {
         let id = Uuid::new_v4();
+        let details = account.clone();
         let account = Account::try_from(id, account, self.get_ilp_address())
             .map_err(NodeStoreError::InvalidAccount)?;
         debug!(
@@ -1106,11 +1594,154 @@ impl NodeStore for RedisStore {
             .encrypt_tokens(&self.encryption_key.expose_secret().0);
 
         self.redis_insert_account(&encrypted).await?;
+        self.publish_change(ReplicationEvent::AccountInserted { id, details });
         Ok(account)
     }
 
+    async fn insert_accounts(
+        &self,
+        accounts: Vec<AccountDetails>,
+        atomic: bool,
+    ) -> Vec<Result<Account, NodeStoreError>> {
+        let ilp_address = self.get_ilp_address();
+        let mut usernames = HashSet::new();
+        let mut results: Vec<Result<(Account, AccountDetails), NodeStoreError>> = accounts
+            .into_iter()
+            .map(|details| {
+                let account =
+                    Account::try_from(Uuid::new_v4(), details.clone(), ilp_address.clone())
+                        .map_err(NodeStoreError::InvalidAccount)?;
+                if !usernames.insert(account.username.to_string()) {
+                    return Err(NodeStoreError::AccountExists(account.username.to_string()));
+                }
+                Ok((account, details))
+            })
+            .collect();
+
+        // The accounts are all validated before any of them is written
+        let mut connection = self.connection.clone();
+        let mut pipe = redis_crate::pipe();
+        for (account, _) in results.iter().flatten() {
+            pipe.hexists(
+                &*prefixed_key(&self.db_prefix, USERNAMES_KEY),
+                account.username().as_ref(),
+            );
+        }
+        let exists: Vec<bool> = if usernames.is_empty() {
+            Vec::new()
+        } else {
+            match pipe.query_async(&mut connection).await {
+                Ok(exists) => exists,
+                Err(err) => {
+                    error!(
+                        "Error checking the usernames of the accounts to insert: {}",
+                        err
+                    );
+                    return results
+                        .into_iter()
+                        .map(|result| {
+                            result.and_then(|(account, _)| {
+                                Err(NodeStoreError::AccountNotInserted(
+                                    account.username.to_string(),
+                                ))
+                            })
+                        })
+                        .collect();
+                }
+            }
+        };
+        let mut exists = exists.into_iter();
+        for result in results.iter_mut() {
+            let username = match result {
+                Ok((ref account, _)) => account.username.to_string(),
+                Err(_) => continue,
+            };
+            if exists.next().unwrap_or(false) {
+                warn!(
+                    "An account already exists with the username {}. Cannot insert it",
+                    username
+                );
+                *result = Err(NodeStoreError::AccountExists(username));
+            }
+        }
+
+        // The valid accounts are then written in a single transaction, unless any of them
+        // is invalid and the insertion is atomic
+        let mut written = !(atomic && results.iter().any(Result::is_err));
+        let mut pipe = redis_crate::pipe();
+        pipe.atomic();
+        let mut inserted = 0;
+        if written {
+            for (account, _) in results.iter().flatten() {
+                let encrypted = account
+                    .clone()
+                    .encrypt_tokens(&self.encryption_key.expose_secret().0);
+                self.add_account_commands(&mut pipe, &encrypted);
+                inserted += 1;
+            }
+        }
+        if inserted > 0 {
+            match pipe.query_async(&mut connection).await {
+                Ok(()) => {
+                    if let Err(err) =
+                        update_routes(connection, self.routes.clone(), &self.db_prefix).await
+                    {
+                        error!(
+                            "Error updating the routes of the inserted accounts: {}",
+                            err
+                        );
+                    }
+                    debug!("Inserted {} accounts", inserted);
+                }
+                Err(err) => {
+                    error!("Error inserting the accounts: {}", err);
+                    written = false;
+                }
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|result| {
+                let (account, details) = result?;
+                if !written {
+                    return Err(NodeStoreError::AccountNotInserted(
+                        account.username.to_string(),
+                    ));
+                }
+                self.publish_change(ReplicationEvent::AccountInserted {
+                    id: account.id,
+                    details,
+                });
+                Ok(account)
+            })
+            .collect()
+    }
+
+    async fn export_accounts(&self) -> Result<Vec<AccountDetails>, NodeStoreError> {
+        let accounts = self.get_all_accounts().await?;
+        let engines: HashMap<String, String> = self
+            .connection
+            .clone()
+            .hgetall(&*prefixed_key(&self.db_prefix, SETTLEMENT_ENGINES_KEY))
+            .await?;
+        // The accounts are loaded with the per-asset settlement engines filled in,
+        // which are not part of the accounts' own details
+        Ok(accounts
+            .iter()
+            .map(|account| {
+                let mut details = account.to_details();
+                if details.settlement_engine_url.as_ref() == engines.get(&account.asset_code) {
+                    details.settlement_engine_url = None;
+                }
+                details
+            })
+            .collect())
+    }
+
     async fn delete_account(&self, id: Uuid) -> Result<Account, NodeStoreError> {
         let account = self.redis_delete_account(id).await?;
+        self.publish_change(ReplicationEvent::AccountDeleted { id });
         Ok(account.decrypt_tokens(&self.decryption_key.expose_secret().0))
     }
 
@@ -1119,6 +1750,7 @@ impl NodeStore for RedisStore {
         id: Uuid,
         account: AccountDetails,
     ) -> Result<Self::Account, NodeStoreError> {
+        let details = account.clone();
         let account = Account::try_from(id, account, self.get_ilp_address())
             .map_err(NodeStoreError::InvalidAccount)?;
 
@@ -1131,6 +1763,7 @@ impl NodeStore for RedisStore {
             .encrypt_tokens(&self.encryption_key.expose_secret().0);
 
         self.redis_update_account(&encrypted).await?;
+        self.publish_change(ReplicationEvent::AccountUpdated { id, details });
         Ok(account)
     }
 
@@ -1139,6 +1772,10 @@ impl NodeStore for RedisStore {
         id: Uuid,
         settings: AccountSettings,
     ) -> Result<Self::Account, NodeStoreError> {
+        let event = ReplicationEvent::AccountSettingsModified {
+            id,
+            settings: settings.clone(),
+        };
         let settings = EncryptedAccountSettings {
             settle_to: settings.settle_to,
             settle_threshold: settings.settle_threshold,
@@ -1175,6 +1812,7 @@ impl NodeStore for RedisStore {
         };
 
         let account = self.redis_modify_account(id, settings).await?;
+        self.publish_change(event);
         Ok(account.decrypt_tokens(&self.decryption_key.expose_secret().0))
     }
 
@@ -1208,14 +1846,14 @@ impl NodeStore for RedisStore {
         R: IntoIterator<Item = (String, Uuid)> + Send + 'async_trait,
     {
         let mut connection = self.connection.clone();
-        let routes: Vec<(String, RedisAccountId)> = routes
+        let routes: Vec<(String, AccountId)> = routes
             .into_iter()
-            .map(|(s, id)| (s, RedisAccountId(id)))
+            .map(|(s, id)| (s, AccountId::from(id)))
             .collect();
         let accounts = routes.iter().map(|(_prefix, account_id)| account_id);
         let mut pipe = redis_crate::pipe();
         for account_id in accounts {
-            pipe.exists(accounts_key(&self.db_prefix, (*account_id).0));
+            pipe.exists(accounts_key(&self.db_prefix, (*account_id).into()));
         }
 
         let routing_table = self.routes.clone();
@@ -1238,6 +1876,12 @@ impl NodeStore for RedisStore {
         pipe.query_async(&mut connection).await?;
 
         update_routes(connection, routing_table, &self.db_prefix).await?;
+        self.publish_change(ReplicationEvent::StaticRoutesSet {
+            routes: routes
+                .into_iter()
+                .map(|(prefix, account_id)| (prefix, account_id.into()))
+                .collect(),
+        });
         Ok(())
     }
 
@@ -1263,16 +1907,48 @@ impl NodeStore for RedisStore {
         connection
             .hset(
                 &*prefixed_key(&self.db_prefix, STATIC_ROUTES_KEY),
-                prefix,
-                RedisAccountId(account_id),
+                &prefix,
+                AccountId::from(account_id),
             )
             .await?;
 
         update_routes(connection, routing_table, &self.db_prefix).await?;
+        self.publish_change(ReplicationEvent::StaticRouteSet { prefix, account_id });
 
         Ok(())
     }
 
+    async fn get_static_routes(&self) -> Result<HashMap<String, Uuid>, NodeStoreError> {
+        let routes: Vec<(String, AccountId)> = self
+            .connection
+            .clone()
+            .hgetall(&*prefixed_key(&self.db_prefix, STATIC_ROUTES_KEY))
+            .await?;
+        Ok(routes
+            .into_iter()
+            .map(|(prefix, account_id)| (prefix, account_id.into()))
+            .collect())
+    }
+
+    async fn delete_static_route(&self, prefix: &str) -> Result<(), NodeStoreError> {
+        let routing_table = self.routes.clone();
+        let mut connection = self.connection.clone();
+        let deleted: u32 = connection
+            .hdel(&*prefixed_key(&self.db_prefix, STATIC_ROUTES_KEY), prefix)
+            .await?;
+        // Deleting a route twice, such as when the deletion is replicated, changes nothing
+        if deleted == 0 {
+            debug!("No static route to delete for prefix: {}", prefix);
+            return Ok(());
+        }
+        debug!("Deleted static route for prefix: {}", prefix);
+        update_routes(connection, routing_table, &self.db_prefix).await?;
+        self.publish_change(ReplicationEvent::StaticRouteDeleted {
+            prefix: prefix.to_string(),
+        });
+        Ok(())
+    }
+
     async fn set_default_route(&self, account_id: Uuid) -> Result<(), NodeStoreError> {
         let routing_table = self.routes.clone();
         // TODO replace this with a lua script to do both calls at once
@@ -1291,11 +1967,37 @@ impl NodeStore for RedisStore {
         connection
             .set(
                 &*prefixed_key(&self.db_prefix, DEFAULT_ROUTE_KEY),
-                RedisAccountId(account_id),
+                AccountId::from(account_id),
             )
             .await?;
         debug!("Set default route to account id: {}", account_id);
         update_routes(connection, routing_table, &self.db_prefix).await?;
+        self.publish_change(ReplicationEvent::DefaultRouteSet { account_id });
+        Ok(())
+    }
+
+    async fn get_default_route(&self) -> Result<Option<Uuid>, NodeStoreError> {
+        let account_id: Option<AccountId> = self
+            .connection
+            .clone()
+            .get(&*prefixed_key(&self.db_prefix, DEFAULT_ROUTE_KEY))
+            .await?;
+        Ok(account_id.map(Uuid::from))
+    }
+
+    async fn delete_default_route(&self) -> Result<(), NodeStoreError> {
+        let routing_table = self.routes.clone();
+        let mut connection = self.connection.clone();
+        let deleted: u32 = connection
+            .del(&*prefixed_key(&self.db_prefix, DEFAULT_ROUTE_KEY))
+            .await?;
+        if deleted == 0 {
+            debug!("No default route to delete");
+            return Ok(());
+        }
+        debug!("Deleted default route");
+        update_routes(connection, routing_table, &self.db_prefix).await?;
+        self.publish_change(ReplicationEvent::DefaultRouteDeleted);
         Ok(())
     }
 
@@ -1304,9 +2006,10 @@ impl NodeStore for RedisStore {
         asset_to_url_map: impl IntoIterator<Item = (String, Url)> + Send + 'async_trait,
     ) -> Result<(), NodeStoreError> {
         let mut connection = self.connection.clone();
-        let asset_to_url_map: Vec<(String, String)> = asset_to_url_map
-            .into_iter()
-            .map(|(asset_code, url)| (asset_code, url.to_string()))
+        let engines: HashMap<String, Url> = asset_to_url_map.into_iter().collect();
+        let asset_to_url_map: Vec<(String, String)> = engines
+            .iter()
+            .map(|(asset_code, url)| (asset_code.clone(), url.to_string()))
             .collect();
         debug!("Setting settlement engines to {:?}", asset_to_url_map);
         connection
@@ -1315,6 +2018,7 @@ impl NodeStore for RedisStore {
                 &asset_to_url_map,
             )
             .await?;
+        self.publish_change(ReplicationEvent::SettlementEnginesSet { engines });
         Ok(())
     }
 
@@ -1368,6 +2072,9 @@ impl AddressStore for RedisStore {
             .await?;
 
         let accounts = self.get_all_accounts().await?;
+        let assigned_suffixes: HashMap<AccountId, String> = connection
+            .hgetall(&*prefixed_key(&self.db_prefix, ASSIGNED_ADDRESSES_KEY))
+            .await?;
         // TODO: This can be an expensive operation if this function
         // gets called often. This currently only gets called when
         // inserting a new parent account in the API. It'd be nice
@@ -1394,10 +2101,14 @@ impl AddressStore for RedisStore {
                 )
                 .ignore();
 
-                // if the username of the account ends with the
+                // if the account was assigned an address via ILDCP, keep its suffix.
+                // Otherwise, if the username of the account ends with the
                 // node's address, we're already configured so no
                 // need to append anything.
-                let new_ilp_address = if first_segment == account.username().to_string() {
+                let assigned_suffix = assigned_suffixes.get(&AccountId::from(account.id()));
+                let new_ilp_address = if let Some(suffix) = assigned_suffix {
+                    ilp_address.with_suffix(suffix.as_bytes()).unwrap()
+                } else if first_segment == account.username().to_string() {
                     ilp_address.clone()
                 } else {
                     ilp_address
@@ -1414,7 +2125,7 @@ impl AddressStore for RedisStore {
                 pipe.hset(
                     &*prefixed_key(&self.db_prefix, ROUTES_KEY),
                     new_ilp_address.as_bytes(),
-                    RedisAccountId(account.id()),
+                    AccountId::from(account.id()),
                 )
                 .ignore();
             }
@@ -1422,6 +2133,9 @@ impl AddressStore for RedisStore {
 
         pipe.query_async(&mut connection.clone()).await?;
         update_routes(connection, routing_table, &self.db_prefix).await?;
+        self.publish_change(ReplicationEvent::IlpAddressSet {
+            address: ilp_address,
+        });
         Ok(())
     }
 
@@ -1434,6 +2148,7 @@ impl AddressStore for RedisStore {
 
         // overwrite the ilp address with the default value
         *(self.ilp_address.write()) = DEFAULT_ILP_ADDRESS.clone();
+        self.publish_change(ReplicationEvent::IlpAddressCleared);
         Ok(())
     }
 
@@ -1441,10 +2156,90 @@ impl AddressStore for RedisStore {
         // read consumes the Arc<RwLock<T>> so we cannot return a reference
         self.ilp_address.read().clone()
     }
+
+    async fn set_secondary_ilp_addresses(
+        &self,
+        ilp_addresses: Vec<Address>,
+    ) -> Result<(), AddressStoreError> {
+        debug!("Setting secondary ILP addresses to: {:?}", ilp_addresses);
+        let key = prefixed_key(&self.db_prefix, SECONDARY_ILP_ADDRESSES_KEY);
+        let mut pipe = redis_crate::pipe();
+        pipe.atomic();
+        pipe.del(&*key).ignore();
+        if !ilp_addresses.is_empty() {
+            let addresses: Vec<&[u8]> = ilp_addresses
+                .iter()
+                .map(|address| address.as_bytes())
+                .collect();
+            pipe.rpush(&*key, addresses).ignore();
+        }
+        pipe.query_async(&mut self.connection.clone())
+            .map_err(|err| AddressStoreError::Other(Box::new(err)))
+            .await?;
+
+        *self.secondary_ilp_addresses.write() = ilp_addresses.clone();
+        self.publish_change(ReplicationEvent::SecondaryIlpAddressesSet {
+            addresses: ilp_addresses,
+        });
+        Ok(())
+    }
+
+    fn get_secondary_ilp_addresses(&self) -> Vec<Address> {
+        self.secondary_ilp_addresses.read().clone()
+    }
 }
 
 type RoutingTable<A> = HashMap<String, A>;
 
+#[async_trait]
+impl AddressAssignmentStore for RedisStore {
+    async fn get_assigned_address(
+        &self,
+        account_id: Uuid,
+    ) -> Result<Option<Address>, AddressAssignmentStoreError> {
+        let suffix: Option<String> = self
+            .connection
+            .clone()
+            .hget(
+                &*prefixed_key(&self.db_prefix, ASSIGNED_ADDRESSES_KEY),
+                AccountId::from(account_id),
+            )
+            .await?;
+        Ok(suffix.map(|suffix| {
+            self.get_ilp_address()
+                .with_suffix(suffix.as_bytes())
+                .expect("assigned address suffixes are valid address segments")
+        }))
+    }
+
+    async fn assign_address(
+        &self,
+        account_id: Uuid,
+        parent_address: &Address,
+    ) -> Result<Address, AddressAssignmentStoreError> {
+        let mut connection = self.connection.clone();
+        let address: String = ASSIGN_ADDRESS
+            .arg(&*prefixed_key(&self.db_prefix, ASSIGNED_ADDRESSES_KEY))
+            .arg(&*prefixed_key(&self.db_prefix, NEXT_ASSIGNED_ADDRESS_KEY))
+            .arg(&*prefixed_key(&self.db_prefix, ROUTES_KEY))
+            .arg(&*prefixed_key(&self.db_prefix, ACCOUNTS_KEY))
+            .arg(AccountId::from(account_id))
+            .arg(parent_address.as_bytes())
+            .invoke_async(&mut connection)
+            .await?;
+        let address = Address::from_str(&address)
+            .map_err(|err| AddressAssignmentStoreError::Other(Box::new(err)))?;
+        debug!("Assigned address {} to account {}", address, account_id);
+
+        update_routes(connection, self.routes.clone(), &self.db_prefix).await?;
+        self.publish_change(ReplicationEvent::AddressAssigned {
+            account_id,
+            parent_address: parent_address.clone(),
+        });
+        Ok(address)
+    }
+}
+
 #[async_trait]
 impl CcpRoutingStore for RedisStore {
     type Account = Account;
@@ -1453,14 +2248,14 @@ impl CcpRoutingStore for RedisStore {
         &self,
         ignore_accounts: Vec<Uuid>,
     ) -> Result<Vec<Account>, CcpRoutingStoreError> {
-        let account_ids: Vec<RedisAccountId> = self
+        let account_ids: Vec<AccountId> = self
             .connection
             .clone()
             .smembers(&*prefixed_key(&self.db_prefix, SEND_ROUTES_KEY))
             .await?;
         let account_ids: Vec<Uuid> = account_ids
             .into_iter()
-            .map(|id| id.0)
+            .map(Uuid::from)
             .filter(|id| !ignore_accounts.contains(&id))
             .collect();
         if account_ids.is_empty() {
@@ -1474,12 +2269,12 @@ impl CcpRoutingStore for RedisStore {
     async fn get_accounts_to_receive_routes_from(
         &self,
     ) -> Result<Vec<Account>, CcpRoutingStoreError> {
-        let account_ids: Vec<RedisAccountId> = self
+        let account_ids: Vec<AccountId> = self
             .connection
             .clone()
             .smembers(&*prefixed_key(&self.db_prefix, RECEIVE_ROUTES_FROM_KEY))
             .await?;
-        let account_ids: Vec<Uuid> = account_ids.into_iter().map(|id| id.0).collect();
+        let account_ids: Vec<Uuid> = account_ids.into_iter().map(Uuid::from).collect();
 
         if account_ids.is_empty() {
             return Ok(Vec::new());
@@ -1492,7 +2287,7 @@ impl CcpRoutingStore for RedisStore {
     async fn get_local_and_configured_routes(
         &self,
     ) -> Result<(RoutingTable<Account>, RoutingTable<Account>), CcpRoutingStoreError> {
-        let static_routes: Vec<(String, RedisAccountId)> = self
+        let static_routes: Vec<(String, AccountId)> = self
             .connection
             .clone()
             .hgetall(&*prefixed_key(&self.db_prefix, STATIC_ROUTES_KEY))
@@ -1512,7 +2307,7 @@ impl CcpRoutingStore for RedisStore {
         let configured_table: HashMap<String, Account> = static_routes
             .into_iter()
             .filter_map(|(prefix, account_id)| {
-                if let Some(account) = account_map.get(&account_id.0) {
+                if let Some(account) = account_map.get(account_id.as_uuid()) {
                     Some((prefix, (*account).clone()))
                 } else {
                     warn!(
@@ -1531,123 +2326,732 @@ impl CcpRoutingStore for RedisStore {
         &mut self,
         routes: impl IntoIterator<Item = (String, Account)> + Send + 'async_trait,
     ) -> Result<(), CcpRoutingStoreError> {
-        let routes: Vec<(String, RedisAccountId)> = routes
+        let routes: Vec<(String, AccountId)> = routes
             .into_iter()
-            .map(|(prefix, account)| (prefix, RedisAccountId(account.id)))
+            .map(|(prefix, account)| (prefix, AccountId::from(account.id)))
             .collect();
-        let num_routes = routes.len();
-        let mut connection = self.connection.clone();
+        self.redis_set_routes(routes).await?;
+        Ok(())
+    }
+}
 
-        // Save routes to Redis
+impl ReplicationSource for RedisStore {
+    fn subscribe_to_changes(&self) -> broadcast::Receiver<ReplicationEvent> {
+        self.replication_publisher.subscribe()
+    }
+}
+
+#[async_trait]
+impl ReplicaStore for RedisStore {
+    async fn apply_replication_event(
+        &self,
+        event: ReplicationEvent,
+    ) -> Result<(), ReplicationStoreError> {
+        fn other<E: std::error::Error + Send + 'static>(err: E) -> ReplicationStoreError {
+            ReplicationStoreError::Other(Box::new(err))
+        }
+
+        trace!("Applying replicated change: {:?}", event);
+        match event {
+            ReplicationEvent::AccountInserted { id, details } => {
+                // Unlike `insert_account`, keep the id the account has on the primary
+                let account = Account::try_from(id, details.clone(), self.get_ilp_address())
+                    .map_err(|err| other(NodeStoreError::InvalidAccount(err)))?;
+                let encrypted = account.encrypt_tokens(&self.encryption_key.expose_secret().0);
+                self.redis_insert_account(&encrypted).await.map_err(other)?;
+                self.publish_change(ReplicationEvent::AccountInserted { id, details });
+            }
+            ReplicationEvent::AccountUpdated { id, details } => {
+                self.update_account(id, details).await.map_err(other)?;
+            }
+            ReplicationEvent::AccountSettingsModified { id, settings } => {
+                self.modify_account_settings(id, settings)
+                    .await
+                    .map_err(other)?;
+            }
+            ReplicationEvent::AccountDeleted { id } => {
+                self.delete_account(id).await.map_err(other)?;
+            }
+            ReplicationEvent::IlpAddressSet { address } => {
+                self.set_ilp_address(address).await.map_err(other)?;
+            }
+            ReplicationEvent::IlpAddressCleared => {
+                self.clear_ilp_address().await.map_err(other)?;
+            }
+            ReplicationEvent::SecondaryIlpAddressesSet { addresses } => {
+                self.set_secondary_ilp_addresses(addresses)
+                    .await
+                    .map_err(other)?;
+            }
+            ReplicationEvent::AddressAssigned {
+                account_id,
+                parent_address,
+            } => {
+                self.assign_address(account_id, &parent_address)
+                    .await
+                    .map_err(other)?;
+            }
+            ReplicationEvent::StaticRoutesSet { routes } => {
+                self.set_static_routes(routes).await.map_err(other)?;
+            }
+            ReplicationEvent::StaticRouteSet { prefix, account_id } => {
+                self.set_static_route(prefix, account_id)
+                    .await
+                    .map_err(other)?;
+            }
+            ReplicationEvent::StaticRouteDeleted { prefix } => {
+                self.delete_static_route(&prefix).await.map_err(other)?;
+            }
+            ReplicationEvent::DefaultRouteSet { account_id } => {
+                self.set_default_route(account_id).await.map_err(other)?;
+            }
+            ReplicationEvent::DefaultRouteDeleted => {
+                self.delete_default_route().await.map_err(other)?;
+            }
+            ReplicationEvent::RoutesSet { routes } => {
+                let routes = routes
+                    .into_iter()
+                    .map(|(prefix, account_id)| (prefix, AccountId::from(account_id)))
+                    .collect();
+                self.redis_set_routes(routes).await.map_err(other)?;
+            }
+            ReplicationEvent::SettlementEnginesSet { engines } => {
+                self.set_settlement_engines(engines).await.map_err(other)?;
+            }
+            ReplicationEvent::Prepare { account_id, amount } => {
+                self.update_balances_for_prepare(account_id, amount)
+                    .await
+                    .map_err(other)?;
+            }
+            ReplicationEvent::Fulfill { account_id, amount } => {
+                self.update_balances_for_fulfill(account_id, amount)
+                    .await
+                    .map_err(other)?;
+            }
+            ReplicationEvent::Reject { account_id, amount } => {
+                self.update_balances_for_reject(account_id, amount)
+                    .await
+                    .map_err(other)?;
+            }
+            ReplicationEvent::DelayedSettlement { account_id } => {
+                self.update_balances_for_delayed_settlement(account_id)
+                    .await
+                    .map_err(other)?;
+            }
+            ReplicationEvent::IncomingSettlement {
+                account_id,
+                amount,
+                idempotency_key,
+            } => {
+                self.update_balance_for_incoming_settlement(account_id, amount, idempotency_key)
+                    .await
+                    .map_err(other)?;
+            }
+            ReplicationEvent::SettlementRefund { account_id, amount } => {
+                self.refund_settlement(account_id, amount)
+                    .await
+                    .map_err(other)?;
+            }
+            ReplicationEvent::SubAccountCredited {
+                account_id,
+                sub_account_id,
+                amount,
+            } => {
+                self.redis_credit_sub_account(account_id, sub_account_id, amount)
+                    .await
+                    .map_err(other)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RedisStore {
+    /// Charges the packet and `amount` to the account's token buckets (without the packets
+    /// bucket if `packets` is false). Returns 0 if the buckets had enough tokens left, 1 if
+    /// the packets bucket did not and 2 if the throughput bucket did not
+    async fn charge_rate_limits(
+        &self,
+        account: &Account,
+        packets: bool,
+        amount: String,
+    ) -> Result<u8, RateLimitError> {
+        let packets_limit = account.packets_per_minute_limit.filter(|_| packets);
+        let packets_burst = account.packets_burst_limit.or(packets_limit);
+        let amount_burst = account
+            .amount_burst_limit
+            .or(account.amount_per_minute_limit);
+        let to_arg = |value: Option<u64>| value.map(|value| value.to_string()).unwrap_or_default();
+
+        APPLY_RATE_LIMITS
+            .arg(&*prefixed_key(
+                &self.db_prefix,
+                &format!("limit:packets_bucket:{}", account.id),
+            ))
+            .arg(to_arg(packets_limit.map(u64::from)))
+            .arg(to_arg(packets_burst.map(u64::from)))
+            .arg(&*prefixed_key(
+                &self.db_prefix,
+                &format!("limit:throughput_bucket:{}", account.id),
+            ))
+            .arg(to_arg(account.amount_per_minute_limit))
+            .arg(to_arg(amount_burst))
+            .arg(amount)
+            .invoke_async(&mut self.connection.clone())
+            .map_err(|err| {
+                error!("Error applying rate limits: {:?}", err);
+                RateLimitError::StoreError
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for RedisStore {
+    type Account = Account;
+
+    /// Apply rate limits for number of packets per minute and amount of money per minute
+    ///
+    /// The limits are token buckets kept in Redis and charged atomically by a Lua script, so
+    /// all of the nodes sharing the database share the same limits. The buckets hold up to
+    /// the account's burst limits (or its per minute limits, if none are set) and are refilled
+    /// continuously at its per minute limits.
+    async fn apply_rate_limits(
+        &self,
+        account: Account,
+        prepare_amount: u64,
+    ) -> Result<(), RateLimitError> {
+        if account.amount_per_minute_limit.is_none() && account.packets_per_minute_limit.is_none() {
+            return Ok(());
+        }
+        match self
+            .charge_rate_limits(&account, true, prepare_amount.to_string())
+            .await?
+        {
+            1 => Err(RateLimitError::PacketLimitExceeded),
+            2 => Err(RateLimitError::ThroughputLimitExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    async fn refund_throughput_limit(
+        &self,
+        account: Account,
+        prepare_amount: u64,
+    ) -> Result<(), RateLimitError> {
+        if account.amount_per_minute_limit.is_some() {
+            self.charge_rate_limits(&account, false, format!("-{}", prepare_amount))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SubAccountStore for RedisStore {
+    async fn credit_sub_account(
+        &self,
+        account_id: Uuid,
+        sub_account: &Username,
+        amount: u64,
+    ) -> Result<(), SubAccountStoreError> {
+        let sub_account_id = self.get_account_id_from_username(sub_account).await?;
+        let accounts = self.get_accounts(vec![account_id, sub_account_id]).await?;
+        if accounts[0].asset_code() != accounts[1].asset_code()
+            || accounts[0].asset_scale() != accounts[1].asset_scale()
+        {
+            return Err(SubAccountStoreError::AssetMismatch(sub_account.to_string()));
+        }
+
+        self.redis_credit_sub_account(account_id, sub_account_id, amount)
+            .await
+            .map_err(|err| SubAccountStoreError::Other(Box::new(err)))
+    }
+}
+
+#[async_trait]
+impl StreamReceiveStore for RedisStore {
+    async fn set_connection_receive_max(
+        &self,
+        account_id: Uuid,
+        connection_tag: &str,
+        receive_max: Option<u64>,
+    ) -> Result<(), StreamReceiveStoreError> {
+        let key = stream_receipts_key(&self.db_prefix, account_id);
+        let field = format!("receive_max:{}", connection_tag);
+        let received_key = stream_received_key(&self.db_prefix, account_id, connection_tag);
         let mut pipe = redis_crate::pipe();
-        pipe.atomic()
-            .del(&*prefixed_key(&self.db_prefix, ROUTES_KEY))
+        pipe.atomic();
+        match receive_max {
+            Some(receive_max) => {
+                check_receipt_amount(receive_max)?;
+                pipe.hset(key, field, receive_max).ignore();
+                // The total must be kept as long as the receive max applies to it
+                pipe.persist(received_key).ignore();
+            }
+            None => {
+                pipe.hdel(key, field).ignore();
+                pipe.expire(received_key, CONNECTION_RECEIPTS_EXPIRY)
+                    .ignore();
+            }
+        }
+        let _: () = pipe.query_async(&mut self.connection.clone()).await?;
+        Ok(())
+    }
+
+    async fn get_connection_receipts(
+        &self,
+        account_id: Uuid,
+        connection_tag: &str,
+    ) -> Result<ConnectionReceipts, StreamReceiveStoreError> {
+        let (receive_max, total_received): (Option<u64>, Option<u64>) = redis_crate::pipe()
+            .hget(
+                stream_receipts_key(&self.db_prefix, account_id),
+                format!("receive_max:{}", connection_tag),
+            )
+            .get(stream_received_key(
+                &self.db_prefix,
+                account_id,
+                connection_tag,
+            ))
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(ConnectionReceipts {
+            total_received: total_received.unwrap_or(0),
+            receive_max,
+        })
+    }
+
+    async fn add_connection_receipt(
+        &self,
+        account_id: Uuid,
+        connection_tag: &str,
+        amount: u64,
+    ) -> Result<(ConnectionReceipts, bool), StreamReceiveStoreError> {
+        check_receipt_amount(amount)?;
+        let (total_received, receive_max, added): (u64, Option<u64>, bool) = ADD_CONNECTION_RECEIPT
+            .key(stream_receipts_key(&self.db_prefix, account_id))
+            .key(stream_received_key(
+                &self.db_prefix,
+                account_id,
+                connection_tag,
+            ))
+            .arg(connection_tag)
+            .arg(amount)
+            .arg(CONNECTION_RECEIPTS_EXPIRY)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        Ok((
+            ConnectionReceipts {
+                total_received,
+                receive_max,
+            },
+            added,
+        ))
+    }
+
+    async fn remove_connection_receipt(
+        &self,
+        account_id: Uuid,
+        connection_tag: &str,
+        amount: u64,
+    ) -> Result<(), StreamReceiveStoreError> {
+        check_receipt_amount(amount)?;
+        let _: () = REMOVE_CONNECTION_RECEIPT
+            .key(stream_received_key(
+                &self.db_prefix,
+                account_id,
+                connection_tag,
+            ))
+            .arg(amount)
+            .invoke_async(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl StreamReplayStore for RedisStore {
+    async fn record_fulfilled_packet(
+        &self,
+        key: [u8; 32],
+        ttl: Duration,
+    ) -> Result<bool, StreamReplayStoreError> {
+        let recorded: Option<String> = cmd("SET")
+            .arg(stream_fulfilled_key(&self.db_prefix, &key))
+            .arg("")
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis().max(1) as u64)
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(recorded.is_some())
+    }
+}
+
+#[async_trait]
+impl DedupStore for RedisStore {
+    async fn start_packet(
+        &self,
+        key: [u8; 32],
+        ttl: Duration,
+    ) -> Result<PacketStatus, DedupStoreError> {
+        // The packets in flight are recorded with an empty response, which
+        // Fulfill and Reject packets never are
+        let key = dedup_key(&self.db_prefix, &key);
+        let (started, response): (Option<String>, Vec<u8>) = redis_crate::pipe()
+            .atomic()
+            .cmd("SET")
+            .arg(&key)
+            .arg("")
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis().max(1) as u64)
+            .get(&key)
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(if started.is_some() {
+            PacketStatus::New
+        } else if response.is_empty() {
+            PacketStatus::InFlight
+        } else {
+            PacketStatus::Answered(Bytes::from(response))
+        })
+    }
+
+    async fn save_packet_response(
+        &self,
+        key: [u8; 32],
+        response: Bytes,
+        ttl: Duration,
+    ) -> Result<(), DedupStoreError> {
+        let key = dedup_key(&self.db_prefix, &key);
+        cmd("SET")
+            .arg(&key)
+            .arg(response.as_ref())
+            .arg("PX")
+            .arg(ttl.as_millis().max(1) as u64)
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DustStore for RedisStore {
+    async fn add_dust(&self, account_id: Uuid, dust: u64) -> Result<(), DustStoreError> {
+        self.connection
+            .clone()
+            .hincr(
+                &*prefixed_key(&self.db_prefix, CONVERSION_DUST_KEY),
+                account_id.to_string(),
+                dust,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn take_dust(&self, account_id: Uuid) -> Result<u64, DustStoreError> {
+        let (dust,): (Option<u64>,) = redis_crate::pipe()
+            .atomic()
+            .hget(
+                &*prefixed_key(&self.db_prefix, CONVERSION_DUST_KEY),
+                account_id.to_string(),
+            )
+            .hdel(
+                &*prefixed_key(&self.db_prefix, CONVERSION_DUST_KEY),
+                account_id.to_string(),
+            )
             .ignore()
-            .hset_multiple(&*prefixed_key(&self.db_prefix, ROUTES_KEY), &routes)
-            .ignore();
+            .query_async(&mut self.connection.clone())
+            .await?;
+        Ok(dust.unwrap_or(0))
+    }
+}
 
+#[async_trait]
+impl UsageStore for RedisStore {
+    async fn record_usage(
+        &self,
+        from_account_id: Uuid,
+        incoming_amount: u64,
+        to_account_id: Uuid,
+        outgoing_amount: u64,
+    ) -> Result<(), UsageStoreError> {
+        // All of the counters are incremented atomically in a single round trip
+        let mut script = RECORD_USAGE.prepare_invoke();
+        for period in UsagePeriod::ALL.iter() {
+            script
+                .key(usage_key(&self.db_prefix, from_account_id, *period))
+                .key(usage_key(&self.db_prefix, to_account_id, *period));
+        }
+        script.arg(incoming_amount).arg(outgoing_amount);
+        for period in UsagePeriod::ALL.iter() {
+            script.arg(match period {
+                UsagePeriod::Daily => DAILY_USAGE_EXPIRY,
+                UsagePeriod::Monthly => MONTHLY_USAGE_EXPIRY,
+            });
+        }
+
+        let _: () = script.invoke_async(&mut self.connection.clone()).await?;
+        trace!(
+            "Recorded usage for packet from account: {} to account: {}",
+            from_account_id,
+            to_account_id
+        );
+        Ok(())
+    }
+
+    async fn get_usage(
+        &self,
+        account_id: Uuid,
+        period: UsagePeriod,
+    ) -> Result<AccountUsage, UsageStoreError> {
+        let key = usage_key(&self.db_prefix, account_id, period);
+        let counters: HashMap<String, u64> = self.connection.clone().hgetall(&key).await?;
+        let counter = |name: &str| counters.get(name).cloned().unwrap_or_default();
+        Ok(AccountUsage {
+            period: period.current_period_id(),
+            packets_sent: counter("packets_sent"),
+            packets_received: counter("packets_received"),
+            amount_sent: counter("amount_sent"),
+            amount_received: counter("amount_received"),
+        })
+    }
+
+    async fn reset_usage(
+        &self,
+        account_id: Uuid,
+        period: UsagePeriod,
+    ) -> Result<(), UsageStoreError> {
+        let key = usage_key(&self.db_prefix, account_id, period);
+        self.connection.clone().del(&key).await?;
+        debug!("Reset {:?} usage of account: {}", period, account_id);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BalanceHistoryStore for RedisStore {
+    async fn record_balance_samples(
+        &self,
+        timestamp: u64,
+        capacity: usize,
+    ) -> Result<(), BalanceHistoryStoreError> {
+        let account_ids = self
+            .get_all_accounts_ids()
+            .await
+            .map_err(|err| BalanceHistoryStoreError::Other(Box::new(err)))?;
+        if account_ids.is_empty() {
+            return Ok(());
+        }
+        let mut connection = self.connection.clone();
+
+        let mut pipe = redis_crate::pipe();
+        for account_id in account_ids.iter() {
+            pipe.hget(
+                accounts_key(&self.db_prefix, *account_id),
+                &["balance", "prepaid_amount"],
+            );
+        }
+        let balances: Vec<(Option<i64>, Option<i64>)> = pipe.query_async(&mut connection).await?;
+
+        // Each list is trimmed right after the push so it never holds more than `capacity` samples
+        let mut pipe = redis_crate::pipe();
+        for (account_id, (balance, prepaid_amount)) in account_ids.iter().zip(balances) {
+            let sample = BalanceSample {
+                timestamp,
+                balance: balance.unwrap_or_default() + prepaid_amount.unwrap_or_default(),
+            };
+            let key = balance_history_key(&self.db_prefix, *account_id);
+            pipe.rpush(&key, &sample.to_bytes()[..])
+                .ignore()
+                .ltrim(&key, -(capacity as isize), -1)
+                .ignore();
+        }
         pipe.query_async(&mut connection).await?;
-        trace!("Saved {} routes to Redis", num_routes);
+        trace!("Sampled the balances of {} accounts", account_ids.len());
+        Ok(())
+    }
+
+    async fn get_balance_history(
+        &self,
+        account_id: Uuid,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<BalanceSample>, BalanceHistoryStoreError> {
+        let samples: Vec<Vec<u8>> = self
+            .connection
+            .clone()
+            .lrange(balance_history_key(&self.db_prefix, account_id), 0, -1)
+            .await?;
+        Ok(samples
+            .iter()
+            .filter_map(|bytes| BalanceSample::from_bytes(bytes))
+            .filter(|sample| sample.timestamp >= from && sample.timestamp <= to)
+            .collect())
+    }
+}
+
+#[async_trait]
+impl ContactStore for RedisStore {
+    async fn save_contact(
+        &self,
+        account_id: Uuid,
+        contact: Contact,
+    ) -> Result<(), ContactStoreError> {
+        self.connection
+            .clone()
+            .hset(
+                contacts_key(&self.db_prefix, account_id),
+                contact.name,
+                contact.payment_pointer,
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn get_contacts(&self, account_id: Uuid) -> Result<Vec<Contact>, ContactStoreError> {
+        let contacts: BTreeMap<String, String> = self
+            .connection
+            .clone()
+            .hgetall(contacts_key(&self.db_prefix, account_id))
+            .await?;
+        Ok(contacts
+            .into_iter()
+            .map(|(name, payment_pointer)| Contact {
+                name,
+                payment_pointer,
+            })
+            .collect())
+    }
+
+    async fn get_contact(
+        &self,
+        account_id: Uuid,
+        name: &str,
+    ) -> Result<Contact, ContactStoreError> {
+        let payment_pointer: Option<String> = self
+            .connection
+            .clone()
+            .hget(contacts_key(&self.db_prefix, account_id), name)
+            .await?;
+        payment_pointer
+            .map(|payment_pointer| Contact {
+                name: name.to_string(),
+                payment_pointer,
+            })
+            .ok_or_else(|| ContactStoreError::ContactNotFound(name.to_string()))
+    }
 
-        update_routes(connection, self.routes.clone(), &self.db_prefix).await?;
+    async fn delete_contact(&self, account_id: Uuid, name: &str) -> Result<(), ContactStoreError> {
+        let deleted: u32 = self
+            .connection
+            .clone()
+            .hdel(contacts_key(&self.db_prefix, account_id), name)
+            .await?;
+        if deleted == 0 {
+            return Err(ContactStoreError::ContactNotFound(name.to_string()));
+        }
         Ok(())
     }
 }
 
 #[async_trait]
-impl RateLimitStore for RedisStore {
-    type Account = Account;
+impl AccountingSink for RedisStore {
+    async fn record_ledger_entry(&self, entry: &LedgerEntry) -> Result<(), AccountingSinkError> {
+        let mut xadd = cmd("XADD");
+        xadd.arg(&*prefixed_key(&self.db_prefix, ACCOUNTING_LEDGER_KEY))
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(ACCOUNTING_LEDGER_MAX_LENGTH)
+            .arg("*");
+        for (field, value) in LEDGER_ENTRY_FIELDS.iter().zip(entry.fields().iter()) {
+            xadd.arg(*field).arg(value);
+        }
+        let id: String = xadd.query_async(&mut self.connection.clone()).await?;
+        trace!("Recorded ledger entry {} as stream entry {}", entry.id, id);
+        Ok(())
+    }
+}
 
-    /// Apply rate limits for number of packets per minute and amount of money per minute
-    ///
-    /// This uses https://github.com/brandur/redis-cell so the redis-cell module MUST be loaded into redis before this is run
-    async fn apply_rate_limits(
+#[async_trait]
+impl WebhookStore for RedisStore {
+    async fn set_webhook(
         &self,
-        account: Account,
-        prepare_amount: u64,
-    ) -> Result<(), RateLimitError> {
-        if account.amount_per_minute_limit.is_some() || account.packets_per_minute_limit.is_some() {
-            let mut pipe = redis_crate::pipe();
-            let packet_limit = account.packets_per_minute_limit.is_some();
-            let amount_limit = account.amount_per_minute_limit.is_some();
-
-            if let Some(limit) = account.packets_per_minute_limit {
-                let limit = limit - 1;
-                let packets_limit =
-                    prefixed_key(&self.db_prefix, &format!("limit:packets:{}", account.id))
-                        .into_owned();
-                pipe.cmd("CL.THROTTLE")
-                    .arg(&packets_limit)
-                    .arg(limit)
-                    .arg(limit)
-                    .arg(60)
-                    .arg(1);
-            }
-
-            if let Some(limit) = account.amount_per_minute_limit {
-                let limit = limit - 1;
-                let throughput_limit =
-                    prefixed_key(&self.db_prefix, &format!("limit:throughput:{}", account.id))
-                        .into_owned();
-                pipe.cmd("CL.THROTTLE")
-                    .arg(&throughput_limit)
-                    // TODO allow separate configuration for burst limit
-                    .arg(limit)
-                    .arg(limit)
-                    .arg(60)
-                    .arg(prepare_amount);
-            }
-
-            let results: Vec<Vec<i64>> = pipe
-                .query_async(&mut self.connection.clone())
-                .map_err(|err| {
-                    error!("Error applying rate limits: {:?}", err);
-                    RateLimitError::StoreError
-                })
-                .await?;
+        account_id: Uuid,
+        webhook: Webhook,
+    ) -> Result<(), WebhookStoreError> {
+        // The secret is encrypted like the accounts' tokens and kept apart from the webhook
+        let encrypted_secret = encrypt_token(
+            &self.encryption_key.expose_secret().0,
+            webhook.secret.as_bytes(),
+        );
+        let data = serde_json::to_string(&Webhook {
+            secret: String::new(),
+            ..webhook.clone()
+        })
+        .map_err(|err| WebhookStoreError::Other(Box::new(err)))?;
+        let mut pipe = redis_crate::pipe();
+        pipe.atomic()
+            .hset(
+                &*prefixed_key(&self.db_prefix, WEBHOOKS_KEY),
+                account_id.to_string(),
+                data,
+            )
+            .ignore()
+            .hset(
+                &*prefixed_key(&self.db_prefix, WEBHOOK_SECRETS_KEY),
+                account_id.to_string(),
+                encrypted_secret.as_ref(),
+            )
+            .ignore();
+        let _: () = pipe.query_async(&mut self.connection.clone()).await?;
+        trace!("Set webhook of account {} to {}", account_id, webhook.url);
+        Ok(())
+    }
 
-            if packet_limit && amount_limit {
-                if results[0][0] == 1 {
-                    Err(RateLimitError::PacketLimitExceeded)
-                } else if results[1][0] == 1 {
-                    Err(RateLimitError::ThroughputLimitExceeded)
-                } else {
-                    Ok(())
-                }
-            } else if packet_limit && results[0][0] == 1 {
-                Err(RateLimitError::PacketLimitExceeded)
-            } else if amount_limit && results[0][0] == 1 {
-                Err(RateLimitError::ThroughputLimitExceeded)
-            } else {
-                Ok(())
-            }
-        } else {
-            Ok(())
-        }
+    async fn get_webhook(&self, account_id: Uuid) -> Result<Webhook, WebhookStoreError> {
+        let mut pipe = redis_crate::pipe();
+        pipe.hget(
+            &*prefixed_key(&self.db_prefix, WEBHOOKS_KEY),
+            account_id.to_string(),
+        )
+        .hget(
+            &*prefixed_key(&self.db_prefix, WEBHOOK_SECRETS_KEY),
+            account_id.to_string(),
+        );
+        let (data, encrypted_secret): (Option<String>, Option<Vec<u8>>) =
+            pipe.query_async(&mut self.connection.clone()).await?;
+        let (data, encrypted_secret) = match (data, encrypted_secret) {
+            (Some(data), Some(encrypted_secret)) => (data, encrypted_secret),
+            _ => return Err(WebhookStoreError::WebhookNotFound(account_id.to_string())),
+        };
+        let mut webhook: Webhook =
+            serde_json::from_str(&data).map_err(|err| WebhookStoreError::Other(Box::new(err)))?;
+        let secret = decrypt_token(&self.decryption_key.expose_secret().0, &encrypted_secret)
+            .map_err(|err| WebhookStoreError::Other(Box::new(err)))?;
+        webhook.secret = String::from_utf8(secret.expose_secret().to_vec())
+            .map_err(|err| WebhookStoreError::Other(Box::new(err)))?;
+        Ok(webhook)
     }
 
-    async fn refund_throughput_limit(
-        &self,
-        account: Account,
-        prepare_amount: u64,
-    ) -> Result<(), RateLimitError> {
-        if let Some(limit) = account.amount_per_minute_limit {
-            let limit = limit - 1;
-            let throughput_limit =
-                prefixed_key(&self.db_prefix, &format!("limit:throughput:{}", account.id))
-                    .into_owned();
-            cmd("CL.THROTTLE")
-                .arg(&throughput_limit)
-                .arg(limit)
-                .arg(limit)
-                .arg(60)
-                // TODO make sure this doesn't overflow
-                .arg(0i64 - (prepare_amount as i64))
-                .query_async(&mut self.connection.clone())
-                .map_err(|_| RateLimitError::StoreError)
-                .await?;
+    async fn delete_webhook(&self, account_id: Uuid) -> Result<(), WebhookStoreError> {
+        let mut pipe = redis_crate::pipe();
+        pipe.atomic()
+            .hdel(
+                &*prefixed_key(&self.db_prefix, WEBHOOKS_KEY),
+                account_id.to_string(),
+            )
+            .hdel(
+                &*prefixed_key(&self.db_prefix, WEBHOOK_SECRETS_KEY),
+                account_id.to_string(),
+            )
+            .ignore();
+        let (deleted,): (u32,) = pipe.query_async(&mut self.connection.clone()).await?;
+        if deleted == 0 {
+            return Err(WebhookStoreError::WebhookNotFound(account_id.to_string()));
         }
-
         Ok(())
     }
 }
@@ -1700,9 +3104,15 @@ impl IdempotentStore for RedisStore {
             .arg("input_hash")
             .arg(&input_hash)
             .ignore()
-            .expire(
+            .pexpire(
                 &prefixed_idempotency_key(&self.db_prefix, &idempotency_key),
-                86400,
+                self.idempotency_key_ttl.as_millis() as usize,
+            )
+            .ignore()
+            .zadd(
+                &*prefixed_key(&self.db_prefix, IDEMPOTENCY_KEYS_KEY),
+                &idempotency_key,
+                now_millis() + self.idempotency_key_ttl.as_millis() as u64,
             )
             .ignore();
         pipe.query_async(&mut connection).await?;
@@ -1715,6 +3125,64 @@ impl IdempotentStore for RedisStore {
         );
         Ok(())
     }
+
+    async fn get_idempotency_keys(&self) -> Result<Vec<String>, IdempotentStoreError> {
+        let mut connection = self.connection.clone();
+        let keys: Vec<String> = connection
+            .zrangebyscore(
+                &*prefixed_key(&self.db_prefix, IDEMPOTENCY_KEYS_KEY),
+                format!("({}", now_millis()),
+                "+inf",
+            )
+            .await?;
+        Ok(keys)
+    }
+
+    async fn delete_idempotent_data(
+        &self,
+        idempotency_keys: Vec<String>,
+    ) -> Result<(), IdempotentStoreError> {
+        if idempotency_keys.is_empty() {
+            return Ok(());
+        }
+        let prefixed_keys: Vec<String> = idempotency_keys
+            .iter()
+            .map(|key| prefixed_idempotency_key(&self.db_prefix, key))
+            .collect();
+        let mut pipe = redis_crate::pipe();
+        let mut connection = self.connection.clone();
+        pipe.atomic()
+            .del(prefixed_keys)
+            .ignore()
+            .zrem(
+                &*prefixed_key(&self.db_prefix, IDEMPOTENCY_KEYS_KEY),
+                idempotency_keys.as_slice(),
+            )
+            .ignore();
+        pipe.query_async(&mut connection).await?;
+        debug!("Deleted idempotency keys: {:?}", idempotency_keys);
+        Ok(())
+    }
+
+    async fn remove_expired_idempotent_data(&self) -> Result<usize, IdempotentStoreError> {
+        // The responses expire natively, only the index of the keys has to be cleaned up
+        let mut connection = self.connection.clone();
+        let removed: usize = connection
+            .zrembyscore(
+                &*prefixed_key(&self.db_prefix, IDEMPOTENCY_KEYS_KEY),
+                "-inf",
+                now_millis(),
+            )
+            .await?;
+        Ok(removed)
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or_default()
 }
 
 #[async_trait]
@@ -1726,22 +3194,55 @@ impl SettlementStore for RedisStore {
         account_id: Uuid,
         amount: u64,
         idempotency_key: Option<String>,
-    ) -> Result<(), SettlementStoreError> {
+    ) -> Result<u64, SettlementStoreError> {
         let idempotency_key = idempotency_key.unwrap();
-        let balance: i64 = PROCESS_INCOMING_SETTLEMENT
-            .arg(&*prefixed_key(&self.db_prefix, ACCOUNTS_KEY))
-            .arg(RedisAccountId(account_id))
-            .arg(amount)
-            .arg(&*prefixed_key(&self.db_prefix, idempotency_key.as_str()))
-            .invoke_async(&mut self.connection.clone())
-            .await?;
+        let mut connection = self.connection.clone();
+        let account_key = accounts_key(&self.db_prefix, account_id);
+        // The balance is credited in Rust, where it cannot overflow, and the script
+        // only applies the result if the account was not updated in the meantime
+        let (balance, prepaid_amount, uncredited_amount) = loop {
+            let (balance, prepaid_amount): (Option<i64>, Option<i64>) = connection
+                .hget(&account_key, &["balance", "prepaid_amount"])
+                .await?;
+            let (balance, prepaid_amount) = (
+                balance.unwrap_or_default(),
+                prepaid_amount.unwrap_or_default(),
+            );
+            let (new_balance, new_prepaid_amount, uncredited_amount) =
+                credit_settlement(balance, prepaid_amount, amount);
+            let status: i64 = PROCESS_INCOMING_SETTLEMENT
+                .arg(&*prefixed_key(&self.db_prefix, ACCOUNTS_KEY))
+                .arg(AccountId::from(account_id))
+                .arg(&*prefixed_key(&self.db_prefix, idempotency_key.as_str()))
+                .arg(balance)
+                .arg(prepaid_amount)
+                .arg(new_balance)
+                .arg(new_prepaid_amount)
+                .invoke_async(&mut connection)
+                .await?;
+            match status {
+                // The idempotency key was already used
+                0 => return Ok(0),
+                1 => break (new_balance, new_prepaid_amount, uncredited_amount),
+                _ => trace!(
+                    "Balance of account {} changed while crediting a settlement, retrying",
+                    account_id
+                ),
+            }
+        };
+        let credited_amount = amount - uncredited_amount;
         trace!(
             "Processed incoming settlement from account: {} for amount: {}. Balance is now: {}",
             account_id,
-            amount,
-            balance
+            credited_amount,
+            balance + prepaid_amount
         );
-        Ok(())
+        self.publish_change(ReplicationEvent::IncomingSettlement {
+            account_id,
+            amount: credited_amount,
+            idempotency_key: Some(idempotency_key),
+        });
+        Ok(uncredited_amount)
     }
 
     async fn refund_settlement(
@@ -1756,7 +3257,7 @@ impl SettlementStore for RedisStore {
         );
         let balance: i64 = REFUND_SETTLEMENT
             .arg(&*prefixed_key(&self.db_prefix, ACCOUNTS_KEY))
-            .arg(RedisAccountId(account_id))
+            .arg(AccountId::from(account_id))
             .arg(settle_amount)
             .invoke_async(&mut self.connection.clone())
             .await?;
@@ -1767,8 +3268,176 @@ impl SettlementStore for RedisStore {
             settle_amount,
             balance
         );
+        self.publish_change(ReplicationEvent::SettlementRefund {
+            account_id,
+            amount: settle_amount,
+        });
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SettlementQueueStore for RedisStore {
+    async fn save_pending_settlement(
+        &self,
+        settlement: PendingSettlement,
+    ) -> Result<(), SettlementQueueStoreError> {
+        let data = serde_json::to_string(&settlement)
+            .map_err(|err| SettlementQueueStoreError::Other(Box::new(err)))?;
+        self.connection
+            .clone()
+            .hset(
+                &*prefixed_key(&self.db_prefix, PENDING_SETTLEMENTS_KEY),
+                settlement.id.to_string(),
+                data,
+            )
+            .await?;
+        trace!(
+            "Saved pending settlement {} for account: {}",
+            settlement.id,
+            settlement.account_id
+        );
+        Ok(())
+    }
+
+    async fn remove_pending_settlement(&self, id: Uuid) -> Result<(), SettlementQueueStoreError> {
+        self.connection
+            .clone()
+            .hdel(
+                &*prefixed_key(&self.db_prefix, PENDING_SETTLEMENTS_KEY),
+                id.to_string(),
+            )
+            .await?;
+        trace!("Removed pending settlement {}", id);
+        Ok(())
+    }
+
+    async fn load_pending_settlements(
+        &self,
+    ) -> Result<Vec<PendingSettlement>, SettlementQueueStoreError> {
+        let settlements: HashMap<String, String> = self
+            .connection
+            .clone()
+            .hgetall(&*prefixed_key(&self.db_prefix, PENDING_SETTLEMENTS_KEY))
+            .await?;
+        let mut pending = Vec::with_capacity(settlements.len());
+        for (id, data) in settlements {
+            match serde_json::from_str(&data) {
+                Ok(settlement) => pending.push(settlement),
+                Err(err) => warn!("Ignoring invalid pending settlement {}: {}", id, err),
+            }
+        }
+        Ok(pending)
+    }
+
+    async fn save_pending_message(
+        &self,
+        message: PendingMessage,
+    ) -> Result<(), SettlementQueueStoreError> {
+        let data = serde_json::to_string(&message)
+            .map_err(|err| SettlementQueueStoreError::Other(Box::new(err)))?;
+        self.connection
+            .clone()
+            .hset(
+                &*prefixed_key(&self.db_prefix, PENDING_MESSAGES_KEY),
+                message.id.to_string(),
+                data,
+            )
+            .await?;
+        trace!(
+            "Saved pending settlement engine message {} for account: {}",
+            message.id,
+            message.account_id
+        );
+        Ok(())
+    }
+
+    async fn remove_pending_message(&self, id: Uuid) -> Result<(), SettlementQueueStoreError> {
+        self.connection
+            .clone()
+            .hdel(
+                &*prefixed_key(&self.db_prefix, PENDING_MESSAGES_KEY),
+                id.to_string(),
+            )
+            .await?;
+        trace!("Removed pending settlement engine message {}", id);
+        Ok(())
+    }
+
+    async fn load_pending_messages(
+        &self,
+    ) -> Result<Vec<PendingMessage>, SettlementQueueStoreError> {
+        let messages: HashMap<String, String> = self
+            .connection
+            .clone()
+            .hgetall(&*prefixed_key(&self.db_prefix, PENDING_MESSAGES_KEY))
+            .await?;
+        let mut pending = Vec::with_capacity(messages.len());
+        for (id, data) in messages {
+            match serde_json::from_str(&data) {
+                Ok(message) => pending.push(message),
+                Err(err) => warn!(
+                    "Ignoring invalid pending settlement engine message {}: {}",
+                    id, err
+                ),
+            }
+        }
+        Ok(pending)
+    }
+}
+
+#[async_trait]
+impl SettlementHistoryStore for RedisStore {
+    async fn record_settlement(
+        &self,
+        account_id: Uuid,
+        record: SettlementRecord,
+    ) -> Result<(), SettlementHistoryStoreError> {
+        let data = serde_json::to_string(&record)
+            .map_err(|err| SettlementHistoryStoreError::Other(Box::new(err)))?;
+        let key = settlement_history_key(&self.db_prefix, account_id);
+        redis_crate::pipe()
+            .atomic()
+            .rpush(&key, data)
+            .ignore()
+            .ltrim(&key, -(SETTLEMENT_HISTORY_CAPACITY as isize), -1)
+            .ignore()
+            .query_async(&mut self.connection.clone())
+            .await?;
+        trace!(
+            "Recorded {:?} settlement of {} for account: {}",
+            record.direction,
+            record.amount,
+            account_id
+        );
         Ok(())
     }
+
+    async fn get_settlement_history(
+        &self,
+        account_id: Uuid,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<SettlementRecord>, SettlementHistoryStoreError> {
+        let records: Vec<String> = self
+            .connection
+            .clone()
+            .lrange(settlement_history_key(&self.db_prefix, account_id), 0, -1)
+            .await?;
+        Ok(records
+            .iter()
+            .filter_map(
+                |data| match serde_json::from_str::<SettlementRecord>(data) {
+                    Ok(record) => Some(record),
+                    Err(err) => {
+                        warn!("Ignoring invalid settlement record: {}", err);
+                        None
+                    }
+                },
+            )
+            .filter(|record| record.timestamp >= from && record.timestamp <= to)
+            .collect())
+    }
 }
 
 // TODO: AmountWithScale is re-implemented on Interledger-Settlement. It'd be nice
@@ -1959,21 +3628,21 @@ impl LeftoversStore for RedisStore {
     }
 }
 
-type RouteVec = Vec<(String, RedisAccountId)>;
+type RouteVec = Vec<(String, AccountId)>;
 
 use futures::future::TryFutureExt;
 
 // TODO replace this with pubsub when async pubsub is added upstream: https://github.com/mitsuhiko/redis-rs/issues/183
 async fn update_routes(
     mut connection: RedisReconnect,
-    routing_table: Arc<RwLock<Arc<HashMap<String, Uuid>>>>,
+    routing_table: Arc<RouteIndex>,
     db_prefix: &str,
 ) -> Result<(), RedisError> {
     let mut pipe = redis_crate::pipe();
     pipe.hgetall(&*prefixed_key(db_prefix, ROUTES_KEY))
         .hgetall(&*prefixed_key(db_prefix, STATIC_ROUTES_KEY))
         .get(&*prefixed_key(db_prefix, DEFAULT_ROUTE_KEY));
-    let (routes, static_routes, default_route): (RouteVec, RouteVec, Option<RedisAccountId>) =
+    let (routes, static_routes, default_route): (RouteVec, RouteVec, Option<AccountId>) =
         pipe.query_async(&mut connection).await?;
     trace!(
         "Loaded routes from redis. Static routes: {:?}, default route: {:?}, other routes: {:?}",
@@ -1985,55 +3654,249 @@ async fn update_routes(
     // set the entry for "" in the routing table to route to that account
     let routes = routes
         .into_iter()
-        .map(|(s, rid)| (s, rid.0))
+        .map(|(s, rid)| (s, rid.into()))
         // Include the default route if there is one
-        .chain(default_route.map(|rid| (String::new(), rid.0)))
+        .chain(default_route.map(|rid| (String::new(), rid.into())))
         // Having the static_routes inserted after ensures that they will overwrite
         // any routes with the same prefix from the first set
-        .chain(static_routes.into_iter().map(|(s, rid)| (s, rid.0)))
+        .chain(static_routes.into_iter().map(|(s, rid)| (s, rid.into())))
         .collect();
     // TODO we may not want to print this because the routing table will be very big
     // if the node has a lot of local accounts
     trace!("Routing table is: {:?}", routes);
-    *routing_table.write() = Arc::new(routes);
+    if routing_table.update(routes) {
+        debug!("Routing table changed, rebuilt its prefix index");
+    }
     Ok(())
 }
 
-// Uuid does not implement ToRedisArgs and FromRedisValue.
-// Rust does not allow implementing foreign traits on foreign data types.
-// As a result, we wrap Uuid in a local data type, and implement the necessary
-// traits for that.
-#[derive(Eq, PartialEq, Hash, Debug, Default, Serialize, Deserialize, Copy, Clone)]
-struct RedisAccountId(Uuid);
-
-impl FromStr for RedisAccountId {
-    type Err = uuid::Error;
+/// Loads the exchange rates (or rate overrides) from the db if they were set (by any node)
+/// after the ones kept in memory
+async fn update_rates(
+    mut connection: RedisReconnect,
+    exchange_rates: Arc<RwLock<HashMap<String, f64>>>,
+    exchange_rates_updated_at: Arc<RwLock<Option<SystemTime>>>,
+    rates_key: &str,
+    updated_at_key: &str,
+) -> Result<(), RedisError> {
+    let mut pipe = redis_crate::pipe();
+    pipe.hgetall(rates_key).get(updated_at_key);
+    let (rates, updated_at): (HashMap<String, f64>, Option<u64>) =
+        pipe.query_async(&mut connection).await?;
+    let updated_at = match updated_at {
+        Some(updated_at) => UNIX_EPOCH + Duration::from_millis(updated_at),
+        None => return Ok(()),
+    };
+    if exchange_rates_updated_at
+        .read()
+        .map(|current| current >= updated_at)
+        .unwrap_or(false)
+    {
+        return Ok(());
+    }
+    trace!("Loaded {} from redis: {:?}", rates_key, rates);
+    *exchange_rates.write() = rates;
+    *exchange_rates_updated_at.write() = Some(updated_at);
+    Ok(())
+}
 
-    fn from_str(src: &str) -> Result<Self, Self::Err> {
-        let id = Uuid::from_str(&src)?;
-        Ok(RedisAccountId(id))
+/// Patterns matching every key of a node, relative to its db prefix
+static NODE_KEY_PATTERNS: &[&str] = &[
+    PARENT_ILP_KEY,
+    SECONDARY_ILP_ADDRESSES_KEY,
+    "rates:*",
+    "routes:*",
+    SETTLEMENT_ENGINES_KEY,
+    USERNAMES_KEY,
+    ACCOUNTS_KEY,
+    "accounts:*",
+    SEND_ROUTES_KEY,
+    RECEIVE_ROUTES_FROM_KEY,
+    BPT_OUTGOING,
+    ASSIGNED_ADDRESSES_KEY,
+    NEXT_ASSIGNED_ADDRESS_KEY,
+    PENDING_SETTLEMENTS_KEY,
+    PENDING_MESSAGES_KEY,
+    WEBHOOKS_KEY,
+    WEBHOOK_SECRETS_KEY,
+    CONVERSION_DUST_KEY,
+    IDEMPOTENCY_KEYS_KEY,
+    "idempotency-key:*",
+    "uncredited-amount:*",
+    "usage:*",
+    "dedup:*",
+    "limit:*",
+    "balance_history:*",
+    "settlement_history:*",
+    "contacts:*",
+    "stream_receipts:*",
+    "stream_fulfilled:*",
+    "accounting:*",
+];
+
+/// Escapes the characters of a db prefix which have a special meaning in SCAN patterns
+fn escape_pattern(prefix: &str) -> String {
+    let mut escaped = String::with_capacity(prefix.len());
+    for c in prefix.chars() {
+        if let '*' | '?' | '[' | ']' | '\\' = c {
+            escaped.push('\\');
+        }
+        escaped.push(c);
     }
+    escaped
 }
 
-impl Display for RedisAccountId {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
-        f.write_str(&self.0.to_hyphenated().to_string())
+/// Renames the keys of the node using `old_prefix` so they use `new_prefix` instead.
+/// Keys which already exist under the new prefix are skipped with a warning.
+/// Returns the number of moved keys.
+async fn migrate_db_prefix(
+    mut connection: RedisReconnect,
+    old_prefix: &str,
+    new_prefix: &str,
+) -> Result<usize, RedisError> {
+    if old_prefix == new_prefix {
+        return Ok(0);
+    }
+    let escaped_prefix = escape_pattern(old_prefix);
+    let mut keys = Vec::new();
+    for pattern in NODE_KEY_PATTERNS {
+        let pattern = prefixed_key(&escaped_prefix, pattern).into_owned();
+        let mut cursor = 0u64;
+        loop {
+            let (next_cursor, mut matched): (u64, Vec<String>) = cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern)
+                .arg("COUNT")
+                .arg(1000)
+                .query_async(&mut connection)
+                .await?;
+            keys.append(&mut matched);
+            if next_cursor == 0 {
+                break;
+            }
+            cursor = next_cursor;
+        }
+    }
+    // SCAN may return a key more than once
+    keys.sort_unstable();
+    keys.dedup();
+
+    let mut migrated = 0;
+    for old_key in keys {
+        let key = if old_prefix.is_empty() {
+            old_key.as_str()
+        } else {
+            &old_key[old_prefix.len() + 1..]
+        };
+        let new_key = prefixed_key(new_prefix, key);
+        let renamed: bool = connection.rename_nx(old_key.as_str(), &*new_key).await?;
+        if renamed {
+            migrated += 1;
+        } else {
+            warn!(
+                "Not moving key {} to {} because the latter already exists",
+                old_key, new_key
+            );
+        }
     }
+    if migrated > 0 {
+        warn!(
+            "Moved {} keys from db prefix \"{}\" to \"{}\"",
+            migrated, old_prefix, new_prefix
+        );
+    }
+    Ok(migrated)
 }
 
-impl ToRedisArgs for RedisAccountId {
-    fn write_redis_args<W: RedisWrite + ?Sized>(&self, out: &mut W) {
-        out.write_arg(self.0.to_hyphenated().to_string().as_bytes().as_ref());
+/// Rewrites the accounts saved with the numeric ids used by earlier versions
+/// to use the UUIDs those ids map to (see `AccountId::from_legacy`).
+/// Returns the number of migrated accounts.
+async fn migrate_legacy_account_ids(
+    mut connection: RedisReconnect,
+    db_prefix: &str,
+) -> Result<usize, RedisError> {
+    let account_ids: Vec<String> = connection
+        .smembers(&*prefixed_key(db_prefix, ACCOUNTS_KEY))
+        .await?;
+    let mut migrated = 0;
+    for old_id in account_ids {
+        let legacy_id = match u64::from_str(&old_id) {
+            Ok(legacy_id) => legacy_id,
+            Err(_) => continue,
+        };
+        let new_id = AccountId::from_legacy(legacy_id).to_string();
+        let usage_periods = scan_usage_periods(&mut connection, db_prefix, &old_id).await?;
+        let mut script = MIGRATE_ACCOUNT_ID.prepare_invoke();
+        for key in &[
+            ACCOUNTS_KEY,
+            USERNAMES_KEY,
+            SEND_ROUTES_KEY,
+            RECEIVE_ROUTES_FROM_KEY,
+            BPT_OUTGOING,
+            ROUTES_KEY,
+            STATIC_ROUTES_KEY,
+            DEFAULT_ROUTE_KEY,
+            ASSIGNED_ADDRESSES_KEY,
+            ACCOUNTS_KEY,
+            "balance_history",
+            "uncredited-amount",
+            "usage",
+        ] {
+            script.arg(&*prefixed_key(db_prefix, key));
+        }
+        let changed: bool = script
+            .arg(&old_id)
+            .arg(&new_id)
+            .arg(usage_periods)
+            .invoke_async(&mut connection)
+            .await?;
+        if changed {
+            debug!("Migrated legacy account id {} to {}", old_id, new_id);
+            migrated += 1;
+        }
+    }
+    if migrated > 0 {
+        warn!(
+            "Migrated {} accounts with legacy numeric ids to UUIDs",
+            migrated
+        );
     }
+    Ok(migrated)
 }
 
-impl FromRedisValue for RedisAccountId {
-    fn from_redis_value(v: &Value) -> Result<Self, RedisError> {
-        let account_id = String::from_redis_value(v)?;
-        let id = Uuid::from_str(&account_id)
-            .map_err(|_| RedisError::from((ErrorKind::TypeError, "Invalid account id string")))?;
-        Ok(RedisAccountId(id))
+/// Returns the periods of the account's usage counters, whose keys end with the period
+async fn scan_usage_periods(
+    connection: &mut RedisReconnect,
+    db_prefix: &str,
+    account_id: &str,
+) -> Result<Vec<String>, RedisError> {
+    let key_prefix = prefixed_key(db_prefix, &format!("usage:{}:", account_id)).into_owned();
+    let pattern = format!("{}*", escape_pattern(&key_prefix));
+    let mut periods = Vec::new();
+    let mut cursor = 0u64;
+    loop {
+        let (next_cursor, keys): (u64, Vec<String>) = cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(&pattern)
+            .arg("COUNT")
+            .arg(1000)
+            .query_async(connection)
+            .await?;
+        periods.extend(
+            keys.into_iter()
+                .map(|key| key[key_prefix.len()..].to_owned()),
+        );
+        if next_cursor == 0 {
+            break;
+        }
+        cursor = next_cursor;
     }
+    // SCAN may return a key more than once
+    periods.sort_unstable();
+    periods.dedup();
+    Ok(periods)
 }
 
 impl ToRedisArgs for &AccountWithEncryptedTokens {
@@ -2042,7 +3905,7 @@ impl ToRedisArgs for &AccountWithEncryptedTokens {
         let account = &self.account;
 
         "id".write_redis_args(&mut rv);
-        RedisAccountId(account.id).write_redis_args(&mut rv);
+        AccountId::from(account.id).write_redis_args(&mut rv);
         "username".write_redis_args(&mut rv);
         account
             .username
@@ -2130,6 +3993,67 @@ impl ToRedisArgs for &AccountWithEncryptedTokens {
             "settlement_engine_url".write_redis_args(&mut rv);
             settlement_engine_url.as_str().write_redis_args(&mut rv);
         }
+        if let Some(spread) = account.spread {
+            "spread".write_redis_args(&mut rv);
+            spread.write_redis_args(&mut rv);
+        }
+        if let Some(ilp_over_http_fallback_url) = account.ilp_over_http_fallback_url.as_ref() {
+            "ilp_over_http_fallback_url".write_redis_args(&mut rv);
+            ilp_over_http_fallback_url
+                .as_str()
+                .write_redis_args(&mut rv);
+        }
+        if !account.ilp_over_http_alternate_urls.is_empty() {
+            "ilp_over_http_alternate_urls".write_redis_args(&mut rv);
+            account
+                .ilp_over_http_alternate_urls
+                .iter()
+                .map(Url::as_str)
+                .collect::<Vec<_>>()
+                .join(",")
+                .write_redis_args(&mut rv);
+        }
+        if let Some(prefix) = account.translate_prefix_from.as_ref() {
+            "translate_prefix_from".write_redis_args(&mut rv);
+            rv.push(prefix.to_bytes().to_vec());
+        }
+        if let Some(prefix) = account.translate_prefix_to.as_ref() {
+            "translate_prefix_to".write_redis_args(&mut rv);
+            rv.push(prefix.to_bytes().to_vec());
+        }
+        if let Some(limit) = account.packets_burst_limit {
+            "packets_burst_limit".write_redis_args(&mut rv);
+            limit.write_redis_args(&mut rv);
+        }
+        if let Some(limit) = account.amount_burst_limit {
+            "amount_burst_limit".write_redis_args(&mut rv);
+            limit.write_redis_args(&mut rv);
+        }
+        if !account.accept_route_prefixes.is_empty() {
+            "accept_route_prefixes".write_redis_args(&mut rv);
+            account
+                .accept_route_prefixes
+                .join(",")
+                .write_redis_args(&mut rv);
+        }
+        if !account.reject_route_prefixes.is_empty() {
+            "reject_route_prefixes".write_redis_args(&mut rv);
+            account
+                .reject_route_prefixes
+                .join(",")
+                .write_redis_args(&mut rv);
+        }
+        if let Some(max_routes) = account.max_routes {
+            "max_routes".write_redis_args(&mut rv);
+            max_routes.write_redis_args(&mut rv);
+        }
+        if !account.aggregate_route_prefixes.is_empty() {
+            "aggregate_route_prefixes".write_redis_args(&mut rv);
+            account
+                .aggregate_route_prefixes
+                .join(",")
+                .write_redis_args(&mut rv);
+        }
 
         debug_assert!(rv.len() <= ACCOUNT_DETAILS_FIELDS * 2);
         debug_assert!((rv.len() % 2) == 0);
@@ -2157,11 +4081,11 @@ impl FromRedisValue for AccountWithEncryptedTokens {
         let round_trip_time: Option<u32> = get_value_option("round_trip_time", &hash)?;
         let round_trip_time: u32 = round_trip_time.unwrap_or(DEFAULT_ROUND_TRIP_TIME);
 
-        let rid: RedisAccountId = get_value("id", &hash)?;
+        let rid: AccountId = get_value("id", &hash)?;
 
         Ok(AccountWithEncryptedTokens {
             account: Account {
-                id: rid.0,
+                id: rid.into(),
                 username,
                 ilp_address,
                 asset_code: get_value("asset_code", &hash)?,
@@ -2197,6 +4121,17 @@ impl FromRedisValue for AccountWithEncryptedTokens {
                 packets_per_minute_limit: get_value_option("packets_per_minute_limit", &hash)?,
                 amount_per_minute_limit: get_value_option("amount_per_minute_limit", &hash)?,
                 settlement_engine_url: get_url_option("settlement_engine_url", &hash)?,
+                spread: get_value_option("spread", &hash)?,
+                ilp_over_http_fallback_url: get_url_option("ilp_over_http_fallback_url", &hash)?,
+                ilp_over_http_alternate_urls: get_url_list("ilp_over_http_alternate_urls", &hash)?,
+                translate_prefix_from: get_address_option("translate_prefix_from", &hash)?,
+                translate_prefix_to: get_address_option("translate_prefix_to", &hash)?,
+                packets_burst_limit: get_value_option("packets_burst_limit", &hash)?,
+                amount_burst_limit: get_value_option("amount_burst_limit", &hash)?,
+                accept_route_prefixes: get_string_list("accept_route_prefixes", &hash)?,
+                reject_route_prefixes: get_string_list("reject_route_prefixes", &hash)?,
+                max_routes: get_value_option("max_routes", &hash)?,
+                aggregate_route_prefixes: get_string_list("aggregate_route_prefixes", &hash)?,
             },
         })
     }
@@ -2253,6 +4188,41 @@ fn get_url_option(key: &str, map: &HashMap<String, Value>) -> Result<Option<Url>
     }
 }
 
+fn get_address_option(
+    key: &str,
+    map: &HashMap<String, Value>,
+) -> Result<Option<Address>, RedisError> {
+    let value: Option<String> = get_value_option(key, map)?;
+    value
+        .map(|value| {
+            Address::from_str(&value)
+                .map_err(|_| RedisError::from((ErrorKind::TypeError, "Invalid ILP address")))
+        })
+        .transpose()
+}
+
+/// Reads a comma-separated list of URLs, which is empty if the field is not set
+fn get_url_list(key: &str, map: &HashMap<String, Value>) -> Result<Vec<Url>, RedisError> {
+    let value: Option<String> = get_value_option(key, map)?;
+    value
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(|url| {
+            Url::parse(url).map_err(|_| RedisError::from((ErrorKind::TypeError, "Invalid URL")))
+        })
+        .collect()
+}
+
+/// Reads a comma-separated list, which is empty if the field is not set
+fn get_string_list(key: &str, map: &HashMap<String, Value>) -> Result<Vec<String>, RedisError> {
+    let value: Option<String> = get_value_option(key, map)?;
+    Ok(value
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(String::from)
+        .collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
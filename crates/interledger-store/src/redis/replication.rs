@@ -0,0 +1,205 @@
+//! Ships the changes made to a primary node's store to standby nodes using a
+//! [Redis stream](https://redis.io/topics/streams-intro).
+//!
+//! The primary appends each [`ReplicationEvent`](../../replication/enum.ReplicationEvent.html)
+//! to the stream as JSON, under the `event` field. Standby nodes read the stream in order and
+//! apply the changes to their own store, keeping track of the id of the last entry they applied.
+//! The stream may live on the primary's Redis instance or on a separate one.
+
+use super::reconnect::RedisReconnect;
+use crate::replication::{ReplicaStore, ReplicationEvent, ReplicationSource};
+use futures::TryFutureExt;
+use redis_crate::{cmd, from_redis_value, ConnectionInfo, RedisError, Value};
+use tokio::sync::broadcast::RecvError;
+use tracing::{debug, error, trace, warn};
+
+/// The stream is trimmed to approximately this many entries
+const STREAM_MAX_LENGTH: usize = 100_000;
+/// How long a standby waits for new entries before reading again, in milliseconds
+const READ_BLOCK_TIMEOUT: usize = 5000;
+/// The maximum number of entries a standby reads at once
+const READ_COUNT: usize = 100;
+/// The stream entry field which holds the serialized event
+const EVENT_FIELD: &str = "event";
+
+/// Spawns a task which appends every change published by `source` to the Redis stream `stream_key`.
+///
+/// Only the changes made after this is called are shipped, so standby nodes must be started
+/// from a copy of the primary's data taken after this point (or from an empty store, if
+/// shipping starts before any data is written).
+pub async fn ship_replication_events<S>(
+    source: &S,
+    redis_url: ConnectionInfo,
+    stream_key: String,
+) -> Result<(), ()>
+where
+    S: ReplicationSource,
+{
+    let mut connection = RedisReconnect::connect(redis_url).map_err(|_| ()).await?;
+    let mut changes = source.subscribe_to_changes();
+    debug!("Shipping store changes to Redis stream: {}", stream_key);
+
+    tokio::spawn(async move {
+        loop {
+            let event = match changes.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    error!(
+                        "Replication fell behind and {} changes were not shipped. Standby nodes must be resynchronized",
+                        skipped
+                    );
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let payload = match serde_json::to_string(&event) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    error!("Error serializing change {:?}: {}", event, err);
+                    continue;
+                }
+            };
+
+            let result: Result<String, RedisError> = cmd("XADD")
+                .arg(&stream_key)
+                .arg("MAXLEN")
+                .arg("~")
+                .arg(STREAM_MAX_LENGTH)
+                .arg("*")
+                .arg(EVENT_FIELD)
+                .arg(payload)
+                .query_async(&mut connection)
+                .await;
+            match result {
+                Ok(id) => trace!("Shipped change {:?} as stream entry {}", event, id),
+                Err(err) => error!(
+                    "Error shipping change to Redis stream {}: {}. Standby nodes must be resynchronized",
+                    stream_key, err
+                ),
+            }
+        }
+        debug!("Store was dropped, stopped shipping changes");
+    });
+    Ok(())
+}
+
+/// Spawns a task which reads the changes from the Redis stream `stream_key` and applies
+/// them to `replica`, starting after the entry with the id `last_id` or, if it is `None`,
+/// from the oldest entry still in the stream.
+pub async fn follow_replication_events<R>(
+    replica: R,
+    redis_url: ConnectionInfo,
+    stream_key: String,
+    last_id: Option<String>,
+) -> Result<(), ()>
+where
+    R: ReplicaStore + Send + Sync + 'static,
+{
+    let mut connection = RedisReconnect::connect(redis_url).map_err(|_| ()).await?;
+    let mut last_id = last_id.unwrap_or_else(|| "0".to_string());
+    debug!(
+        "Following store changes from Redis stream: {} (after entry {})",
+        stream_key, last_id
+    );
+
+    tokio::spawn(async move {
+        loop {
+            let reply: Result<Value, RedisError> = cmd("XREAD")
+                .arg("COUNT")
+                .arg(READ_COUNT)
+                .arg("BLOCK")
+                .arg(READ_BLOCK_TIMEOUT)
+                .arg("STREAMS")
+                .arg(&stream_key)
+                .arg(&last_id)
+                .query_async(&mut connection)
+                .await;
+            let entries = match reply.and_then(|reply| parse_stream_entries(&reply)) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    error!("Error reading from Redis stream {}: {}", stream_key, err);
+                    tokio::time::delay_for(std::time::Duration::from_millis(
+                        READ_BLOCK_TIMEOUT as u64,
+                    ))
+                    .await;
+                    continue;
+                }
+            };
+
+            for (id, payload) in entries {
+                match serde_json::from_str::<ReplicationEvent>(&payload) {
+                    Ok(event) => {
+                        if let Err(err) = replica.apply_replication_event(event).await {
+                            warn!("Error applying replicated change {}: {}", id, err);
+                        }
+                    }
+                    Err(err) => warn!("Ignoring invalid replicated change {}: {}", id, err),
+                }
+                last_id = id;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Parses the reply to `XREAD` for a single stream into the ids and events of its entries.
+///
+/// The reply is nil if there were no new entries, or looks like
+/// `[[stream_key, [[id, [field, value, ...]], ...]]]`
+fn parse_stream_entries(reply: &Value) -> Result<Vec<(String, String)>, RedisError> {
+    let streams = match reply {
+        Value::Nil => return Ok(Vec::new()),
+        Value::Bulk(streams) => streams,
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut entries = Vec::new();
+    for stream in streams {
+        let (_key, stream_entries): (String, Vec<Value>) = from_redis_value(stream)?;
+        for entry in stream_entries {
+            let (id, fields): (String, Vec<String>) = from_redis_value(&entry)?;
+            let event = fields
+                .chunks(2)
+                .find(|field| field[0] == EVENT_FIELD && field.len() == 2)
+                .map(|field| field[1].clone())
+                .unwrap_or_default();
+            entries.push((id, event));
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(value: &str) -> Value {
+        Value::Data(value.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn parses_xread_reply() {
+        assert!(parse_stream_entries(&Value::Nil).unwrap().is_empty());
+
+        let reply = Value::Bulk(vec![Value::Bulk(vec![
+            data("replication"),
+            Value::Bulk(vec![
+                Value::Bulk(vec![
+                    data("1-0"),
+                    Value::Bulk(vec![data("event"), data("{}")]),
+                ]),
+                Value::Bulk(vec![
+                    data("2-0"),
+                    Value::Bulk(vec![data("other"), data("x")]),
+                ]),
+            ]),
+        ])]);
+        assert_eq!(
+            parse_stream_entries(&reply).unwrap(),
+            vec![
+                ("1-0".to_string(), "{}".to_string()),
+                ("2-0".to_string(), String::new())
+            ]
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! Hooks which let a warm standby node follow the changes made to the store of a primary node,
+//! so that it can take over with minimal state loss.
+//!
+//! The primary's store publishes a [`ReplicationEvent`](./enum.ReplicationEvent.html) for every
+//! change to its accounts, routes and balances. A transport (such as the Redis streams in
+//! [`redis::replication`](../redis/replication/index.html)) ships these events to the standby,
+//! which applies them to its own store in the same order.
+//!
+//! Balance changes are replicated as the operations which caused them (rather than as absolute
+//! values), so applying them to a store which was in the same state yields the same balances.
+//! Rate limits, usage counters, idempotency keys and uncredited settlement amounts are not replicated.
+
+use async_trait::async_trait;
+use interledger_api::{AccountDetails, AccountSettings};
+use interledger_errors::ReplicationStoreError;
+use interledger_packet::Address;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::sync::broadcast;
+use url::Url;
+use uuid::Uuid;
+
+/// A change made to the primary's store.
+///
+/// Events are serialized as JSON objects tagged with their `type`, for example
+/// `{"type":"fulfill","account_id":"...","amount":100}`.
+///
+/// Note that account events contain the accounts' tokens in cleartext, so the
+/// transport must be as trusted as the store itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReplicationEvent {
+    /// An account was inserted with the given id
+    AccountInserted { id: Uuid, details: AccountDetails },
+    /// An account's details were replaced
+    AccountUpdated { id: Uuid, details: AccountDetails },
+    /// Some of an account's settings were modified
+    AccountSettingsModified { id: Uuid, settings: AccountSettings },
+    /// An account was deleted
+    AccountDeleted { id: Uuid },
+    /// The node's address was set by its parent
+    IlpAddressSet { address: Address },
+    /// The node's address was reset to the default one
+    IlpAddressCleared,
+    /// The addresses assigned by the node's other parents were set
+    SecondaryIlpAddressesSet { addresses: Vec<Address> },
+    /// An address was assigned to a child account via ILDCP
+    AddressAssigned {
+        account_id: Uuid,
+        parent_address: Address,
+    },
+    /// All of the static routes were replaced
+    StaticRoutesSet { routes: HashMap<String, Uuid> },
+    /// A single static route was set
+    StaticRouteSet { prefix: String, account_id: Uuid },
+    /// A single static route was deleted
+    StaticRouteDeleted { prefix: String },
+    /// The default route was set
+    DefaultRouteSet { account_id: Uuid },
+    /// The default route was deleted
+    DefaultRouteDeleted,
+    /// The routes learned via CCP were replaced
+    RoutesSet { routes: HashMap<String, Uuid> },
+    /// The settlement engines used for the given asset codes were set
+    SettlementEnginesSet { engines: HashMap<String, Url> },
+    /// A Prepare of `amount` from the account was processed
+    Prepare { account_id: Uuid, amount: u64 },
+    /// A Fulfill of `amount` to the account was processed (this may have triggered a settlement)
+    Fulfill { account_id: Uuid, amount: u64 },
+    /// A Reject of a Prepare of `amount` from the account was processed
+    Reject { account_id: Uuid, amount: u64 },
+    /// The account was processed for a delayed settlement
+    DelayedSettlement { account_id: Uuid },
+    /// An incoming settlement of `amount` was credited to the account
+    IncomingSettlement {
+        account_id: Uuid,
+        amount: u64,
+        idempotency_key: Option<String>,
+    },
+    /// A failed outgoing settlement of `amount` was refunded to the account
+    SettlementRefund { account_id: Uuid, amount: u64 },
+    /// `amount` received by the account was moved to one of its sub-accounts
+    SubAccountCredited {
+        account_id: Uuid,
+        sub_account_id: Uuid,
+        amount: u64,
+    },
+}
+
+/// Store which publishes the changes made to it so that they can be shipped to a standby node
+pub trait ReplicationSource {
+    /// Returns a receiver for all of the changes made to the store from now on.
+    ///
+    /// Receivers which fall too far behind will lag and miss events, in which case
+    /// the standby node must be resynchronized from a copy of the primary's data.
+    fn subscribe_to_changes(&self) -> broadcast::Receiver<ReplicationEvent>;
+}
+
+/// Store which can apply the changes published by a primary node's store
+#[async_trait]
+pub trait ReplicaStore {
+    /// Applies a single change. Changes must be applied in the order they were published.
+    async fn apply_replication_event(
+        &self,
+        event: ReplicationEvent,
+    ) -> Result<(), ReplicationStoreError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_events_with_type_tag() {
+        let account_id = Uuid::nil();
+        let event = ReplicationEvent::Fulfill {
+            account_id,
+            amount: 100,
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"fulfill","account_id":"00000000-0000-0000-0000-000000000000","amount":100}"#
+        );
+
+        let event: ReplicationEvent =
+            serde_json::from_str(r#"{"type":"ilp_address_cleared"}"#).unwrap();
+        assert!(matches!(event, ReplicationEvent::IlpAddressCleared));
+    }
+}
@@ -0,0 +1,52 @@
+use super::store_helpers::*;
+use interledger_api::{spawn_accounting_exporter, LedgerEntryKind};
+use interledger_service::{Account as AccountTrait, AccountEvent, EventBus};
+use std::time::Duration;
+
+#[tokio::test]
+async fn exports_balance_changes_as_ledger_entries() {
+    let (store, accs) = test_store().await;
+    let events = EventBus::new();
+    spawn_accounting_exporter(store.clone(), store.clone(), &events);
+
+    events.publish(AccountEvent::PacketFulfilled {
+        from_account_id: accs[0].id(),
+        to_account_id: accs[1].id(),
+        incoming_amount: 100,
+        outgoing_amount: 90,
+    });
+    events.publish(AccountEvent::BalanceChanged {
+        account_id: accs[1].id(),
+        balance: 90,
+    });
+    events.publish(AccountEvent::SettlementReserved {
+        account_id: accs[1].id(),
+        amount: 90,
+    });
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+
+    let entries = store.get_ledger_entries();
+    // The packet converts between assets, so it goes through their trading accounts
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].kind, LedgerEntryKind::PacketFulfilled);
+    assert_eq!(
+        entries[0].debit.ledger_account,
+        format!("account:{}", accs[0].id())
+    );
+    assert_eq!(entries[0].debit.asset_code, accs[0].asset_code());
+    assert_eq!(entries[0].credit.ledger_account, "trading:XYZ:6");
+    assert_eq!(entries[0].credit.amount, 100);
+    assert_eq!(entries[1].kind, LedgerEntryKind::PacketFulfilled);
+    assert_eq!(entries[1].debit.ledger_account, "trading:ABC:9");
+    assert_eq!(
+        entries[1].credit.ledger_account,
+        format!("account:{}", accs[1].id())
+    );
+    assert_eq!(entries[1].credit.amount, 90);
+    assert_eq!(entries[1].credit.asset_code, accs[1].asset_code());
+    assert_eq!(entries[2].kind, LedgerEntryKind::Adjustment);
+    assert_eq!(
+        entries[2].credit.ledger_account,
+        format!("pending_settlements:{}", accs[1].id())
+    );
+}
@@ -0,0 +1,284 @@
+use super::{fixtures::*, store_helpers::*};
+use interledger_api::{AccountSettings, NodeStore, Webhook, WebhookEventType, WebhookStore};
+use interledger_btp::{BtpAccount, BtpStore};
+use interledger_ccp::{CcpRoutingAccount, RoutingRelation};
+use interledger_http::{HttpAccount, HttpStore};
+use interledger_packet::Address;
+use interledger_service::Account as AccountTrait;
+use interledger_service::{AccountStore, AddressStore, Username};
+use interledger_service_util::BalanceStore;
+use interledger_spsp::{Contact, ContactStore};
+use secrecy::{ExposeSecret, SecretString};
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn insert_accounts() {
+    let (store, _) = test_store().await;
+    let account = store
+        .insert_account(ACCOUNT_DETAILS_2.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        *account.ilp_address(),
+        Address::from_str("example.alice.user1.charlie").unwrap()
+    );
+    assert_eq!(store.get_balance(account.id()).await.unwrap(), 0);
+
+    // cannot insert duplicate accounts
+    let err = store
+        .insert_account(ACCOUNT_DETAILS_2.clone())
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "account `charlie` already exists");
+}
+
+#[tokio::test]
+async fn update_ilp_and_children_addresses() {
+    let (store, accs) = test_store().await;
+    let ilp_address = Address::from_str("test.parent.our_address").unwrap();
+    store.set_ilp_address(ilp_address.clone()).await.unwrap();
+    assert_eq!(store.get_ilp_address(), ilp_address);
+
+    let accounts = store
+        .get_accounts(vec![accs[0].id(), accs[1].id()])
+        .await
+        .unwrap();
+    // the parent keeps its address while the child gets a new one
+    assert_eq!(accounts[0].routing_relation(), RoutingRelation::Parent);
+    assert_eq!(accounts[0].ilp_address(), accs[0].ilp_address());
+    assert_eq!(
+        *accounts[1].ilp_address(),
+        ilp_address.with_suffix(b"bob").unwrap()
+    );
+}
+
+#[tokio::test]
+async fn multiple_parents_allowed() {
+    let mut acc = ACCOUNT_DETAILS_2.clone();
+    acc.routing_relation = Some("Parent".to_owned());
+    acc.username = Username::from_str("another_name").unwrap();
+    acc.ilp_address = Some(Address::from_str("example.another_name").unwrap());
+    let (store, accs) = test_store().await;
+    let parent = store.insert_account(acc).await.unwrap();
+    assert_eq!(parent.routing_relation(), RoutingRelation::Parent);
+    // The first parent keeps assigning the node's address
+    assert_eq!(
+        store.get_ilp_address(),
+        accs[0].ilp_address().with_suffix(b"user1").unwrap()
+    );
+}
+
+#[tokio::test]
+async fn delete_and_update_accounts() {
+    let (store, accs) = test_store().await;
+    let mut new = ACCOUNT_DETAILS_0.clone();
+    new.asset_code = String::from("TUV");
+    let account = store.update_account(accs[0].id(), new).await.unwrap();
+    assert_eq!(account.asset_code(), "TUV");
+
+    let id = accs[0].id();
+    store.delete_account(id).await.unwrap();
+    let accounts = store.get_all_accounts().await.unwrap();
+    assert_eq!(accounts.len(), 1);
+    assert_ne!(accounts[0].id(), id);
+
+    let err = store.delete_account(id).await.unwrap_err();
+    assert_eq!(err.to_string(), format!("account `{}` was not found", id));
+    let err = store
+        .get_account_id_from_username(&Username::from_str("alice").unwrap())
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "account `alice` was not found");
+}
+
+#[tokio::test]
+async fn modify_account_settings() {
+    let (store, accs) = test_store().await;
+    let id = accs[0].id();
+    let settings = AccountSettings {
+        ilp_over_http_outgoing_token: Some(SecretString::new("test_token".to_owned())),
+        ilp_over_btp_url: Some("btp+ws://example.com/btp".to_owned()),
+        settle_threshold: Some(100),
+        ..Default::default()
+    };
+    let account = store.modify_account_settings(id, settings).await.unwrap();
+    assert_eq!(
+        account.get_http_auth_token().unwrap().expose_secret(),
+        "test_token",
+    );
+    assert_eq!(
+        account.get_ilp_over_btp_url().unwrap().as_str(),
+        "btp+ws://example.com/btp"
+    );
+
+    // invalid settings do not modify the account
+    let settings = AccountSettings {
+        settle_to: Some(u64::MAX),
+        settle_threshold: Some(200),
+        ..Default::default()
+    };
+    let err = store
+        .modify_account_settings(id, settings)
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "invalid account: the provided value for parameter `settle_to` was too large"
+    );
+    let (balance, amount_to_settle) = store.update_balances_for_fulfill(id, 150).await.unwrap();
+    assert_eq!(balance, -1000);
+    assert_eq!(amount_to_settle, 1150);
+}
+
+#[tokio::test]
+async fn authenticates_http_and_btp() {
+    let (store, _) = test_store().await;
+    let alice = Username::from_str("alice").unwrap();
+    let account = store
+        .get_account_from_http_auth(&alice, "incoming_auth_token")
+        .await
+        .unwrap();
+    assert_eq!(account.username(), &alice);
+    let err = store
+        .get_account_from_http_auth(&alice, "wrong_token")
+        .await
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "account `alice` is not authorized for this action"
+    );
+
+    let account = store
+        .get_account_from_btp_auth(&alice, "btp_token")
+        .await
+        .unwrap();
+    assert_eq!(account.username(), &alice);
+    assert!(store
+        .get_account_from_btp_auth(&Username::from_str("bob").unwrap(), "btp_token")
+        .await
+        .is_err());
+
+    let outgoing = store.get_btp_outgoing_accounts().await.unwrap();
+    assert_eq!(outgoing.len(), 1);
+    assert_eq!(outgoing[0].username(), &alice);
+}
+
+#[tokio::test]
+async fn errors_for_unknown_accounts() {
+    let (store, _) = test_store().await;
+    let err = store
+        .get_accounts(vec![Uuid::new_v4(), Uuid::new_v4()])
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "wrong account length (expected 2, got 0)");
+}
+
+#[tokio::test]
+async fn saves_and_deletes_contacts() {
+    let (store, accs) = test_store().await;
+    let id = accs[0].id();
+    let bob = Contact::new("bob".to_string(), "$example.com/bob".to_string()).unwrap();
+    let alice = Contact::new("alice".to_string(), "$example.com/alice".to_string()).unwrap();
+    store.save_contact(id, bob.clone()).await.unwrap();
+    store.save_contact(id, alice.clone()).await.unwrap();
+    assert_eq!(
+        store.get_contacts(id).await.unwrap(),
+        vec![alice, bob.clone()]
+    );
+    assert!(store.get_contacts(accs[1].id()).await.unwrap().is_empty());
+    assert_eq!(store.get_contact(id, "bob").await.unwrap(), bob);
+
+    store.delete_contact(id, "alice").await.unwrap();
+    let err = store.get_contact(id, "alice").await.unwrap_err();
+    assert_eq!(err.to_string(), "contact `alice` was not found");
+    assert!(store.delete_contact(id, "alice").await.is_err());
+
+    // The contacts are deleted along with the account
+    store.delete_account(id).await.unwrap();
+    assert!(store.get_contacts(id).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn inserts_and_exports_accounts_in_bulk() {
+    let (store, _) = test_store().await;
+    let mut dave = ACCOUNT_DETAILS_2.clone();
+    dave.username = Username::from_str("dave").unwrap();
+
+    // charlie is in the batch twice, so none of the accounts are inserted
+    let results = store
+        .insert_accounts(
+            vec![
+                ACCOUNT_DETAILS_2.clone(),
+                dave.clone(),
+                ACCOUNT_DETAILS_2.clone(),
+            ],
+            true,
+        )
+        .await;
+    assert_eq!(
+        results[1].as_ref().unwrap_err().to_string(),
+        "account `dave` was not inserted because another account failed to be"
+    );
+    assert_eq!(
+        results[2].as_ref().unwrap_err().to_string(),
+        "account `charlie` already exists"
+    );
+    assert!(store
+        .get_account_id_from_username(&ACCOUNT_DETAILS_2.username)
+        .await
+        .is_err());
+
+    let results = store
+        .insert_accounts(vec![dave, ACCOUNT_DETAILS_1.clone()], false)
+        .await;
+    assert_eq!(results[0].as_ref().unwrap().username().to_string(), "dave");
+    assert_eq!(
+        results[1].as_ref().unwrap_err().to_string(),
+        "account `bob` already exists"
+    );
+
+    let exported = store.export_accounts().await.unwrap();
+    assert_eq!(exported.len(), 3);
+    let alice = exported
+        .iter()
+        .find(|details| details.username.to_string() == "alice")
+        .unwrap();
+    assert_eq!(
+        alice
+            .ilp_over_http_incoming_token
+            .as_ref()
+            .unwrap()
+            .expose_secret(),
+        "incoming_auth_token"
+    );
+    assert_eq!(
+        alice.settlement_engine_url.as_deref(),
+        Some("http://settlement.example/")
+    );
+}
+
+#[tokio::test]
+async fn sets_and_deletes_webhooks() {
+    let (store, accs) = test_store().await;
+    let id = accs[0].id();
+    let webhook = Webhook {
+        url: "https://example.com/hook".parse().unwrap(),
+        secret: "secret".to_string(),
+        events: vec![WebhookEventType::SettlementCompleted],
+        balance_thresholds: vec![-100, 100],
+    };
+    assert!(store.get_webhook(id).await.is_err());
+    store.set_webhook(id, webhook.clone()).await.unwrap();
+    assert_eq!(store.get_webhook(id).await.unwrap(), webhook);
+
+    store.delete_webhook(id).await.unwrap();
+    let err = store.get_webhook(id).await.unwrap_err();
+    assert_eq!(err.to_string(), format!("account `{}` has no webhook", id));
+    assert!(store.delete_webhook(id).await.is_err());
+
+    // The webhook is deleted along with the account
+    store.set_webhook(id, webhook).await.unwrap();
+    store.delete_account(id).await.unwrap();
+    assert!(store.get_webhook(id).await.is_err());
+}
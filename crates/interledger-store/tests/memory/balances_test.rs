@@ -0,0 +1,230 @@
+use super::{fixtures::*, store_helpers::*};
+use interledger_api::NodeStore;
+use interledger_errors::SubAccountStoreError;
+use interledger_service::{Account as AccountTrait, Username};
+use interledger_service_util::{
+    BalanceHistoryStore, BalanceSample, BalanceStore, DustStore, RateLimitError, RateLimitStore,
+};
+use interledger_stream::SubAccountStore;
+use std::str::FromStr;
+
+#[tokio::test]
+async fn prepare_then_fulfill_with_settlement() {
+    let (store, accs) = test_store().await;
+    let account0_id = accs[0].id();
+    let account1_id = accs[1].id();
+    store
+        .update_balances_for_prepare(account0_id, 100)
+        .await
+        .unwrap();
+    assert_eq!(store.get_balance(account0_id).await.unwrap(), -100);
+    assert_eq!(store.get_balance(account1_id).await.unwrap(), 0);
+
+    let (balance, amount_to_settle) = store
+        .update_balances_for_fulfill(account1_id, 100)
+        .await
+        .unwrap();
+    assert_eq!(balance, -1000);
+    assert_eq!(amount_to_settle, 1100);
+    assert_eq!(store.get_balance(account0_id).await.unwrap(), -100);
+}
+
+#[tokio::test]
+async fn prepare_then_reject() {
+    let (store, accs) = test_store().await;
+    let id = accs[0].id();
+    store.update_balances_for_prepare(id, 100).await.unwrap();
+    assert_eq!(store.get_balance(id).await.unwrap(), -100);
+    store.update_balances_for_reject(id, 100).await.unwrap();
+    assert_eq!(store.get_balance(id).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn rejects_balance_overflows() {
+    let (store, accs) = test_store().await;
+    let id = accs[0].id();
+    store.update_balances_for_reject(id, 100).await.unwrap();
+    assert!(store
+        .update_balances_for_fulfill(id, u64::max_value())
+        .await
+        .is_err());
+    assert!(store
+        .update_balances_for_reject(id, u64::max_value())
+        .await
+        .is_err());
+    assert_eq!(store.get_balance(id).await.unwrap(), 100);
+}
+
+#[tokio::test]
+async fn enforces_minimum_balance() {
+    let (store, accs) = test_store().await;
+    let id = accs[0].id();
+    let err = store
+        .update_balances_for_prepare(id, 10000)
+        .await
+        .unwrap_err();
+    let expected = format!("Incoming prepare of 10000 would bring account {} under its minimum balance. Current balance: 0, min balance: -1000", id);
+    assert!(err.to_string().contains(&expected));
+}
+
+#[tokio::test]
+async fn netting_fulfilled_balances() {
+    let (store, accs) = test_store().await;
+    let acc = store
+        .insert_account(ACCOUNT_DETAILS_2.clone())
+        .await
+        .unwrap();
+    let account0 = accs[0].id();
+    let account1 = acc.id();
+
+    store
+        .update_balances_for_prepare(account0, 100)
+        .await
+        .unwrap();
+    store
+        .update_balances_for_fulfill(account1, 100)
+        .await
+        .unwrap();
+    store
+        .update_balances_for_prepare(account1, 80)
+        .await
+        .unwrap();
+    store
+        .update_balances_for_fulfill(account0, 80)
+        .await
+        .unwrap();
+
+    assert_eq!(store.get_balance(account0).await.unwrap(), -20);
+    assert_eq!(store.get_balance(account1).await.unwrap(), 20);
+}
+
+#[tokio::test]
+async fn rate_limits_packets_and_throughput() {
+    let (store, accs) = test_store().await;
+    // alice may send 2 packets and 1000 units per minute
+    let account = accs[0].clone();
+    store.apply_rate_limits(account.clone(), 600).await.unwrap();
+    let err = store
+        .apply_rate_limits(account.clone(), 600)
+        .await
+        .unwrap_err();
+    assert_eq!(err, RateLimitError::ThroughputLimitExceeded);
+
+    store
+        .refund_throughput_limit(account.clone(), 600)
+        .await
+        .unwrap();
+    store.apply_rate_limits(account.clone(), 600).await.unwrap();
+    let err = store.apply_rate_limits(account, 1).await.unwrap_err();
+    assert_eq!(err, RateLimitError::PacketLimitExceeded);
+}
+
+#[tokio::test]
+async fn rate_limits_allow_bursts() {
+    let (store, _) = test_store().await;
+    let mut details = ACCOUNT_DETAILS_2.clone();
+    details.packets_per_minute_limit = Some(1);
+    details.packets_burst_limit = Some(3);
+    details.amount_per_minute_limit = Some(1);
+    details.amount_burst_limit = Some(1000);
+    let account = store.insert_account(details).await.unwrap();
+
+    for _ in 0..2 {
+        store.apply_rate_limits(account.clone(), 400).await.unwrap();
+    }
+    // the packet is not charged to the packets bucket since it exceeds the throughput one
+    let err = store
+        .apply_rate_limits(account.clone(), 400)
+        .await
+        .unwrap_err();
+    assert_eq!(err, RateLimitError::ThroughputLimitExceeded);
+    store.apply_rate_limits(account.clone(), 200).await.unwrap();
+    let err = store.apply_rate_limits(account, 0).await.unwrap_err();
+    assert_eq!(err, RateLimitError::PacketLimitExceeded);
+}
+
+#[tokio::test]
+async fn credits_sub_accounts() {
+    let (store, accs) = test_store().await;
+    let mut details = ACCOUNT_DETAILS_2.clone();
+    details.asset_code = accs[1].asset_code().to_string();
+    details.asset_scale = accs[1].asset_scale();
+    let sub_account = store.insert_account(details).await.unwrap();
+
+    store
+        .credit_sub_account(accs[1].id(), sub_account.username(), 30)
+        .await
+        .unwrap();
+    assert_eq!(store.get_balance(accs[1].id()).await.unwrap(), -30);
+    assert_eq!(store.get_balance(sub_account.id()).await.unwrap(), 30);
+
+    // The sub-account must have the same asset as the receiving account
+    let err = store
+        .credit_sub_account(accs[0].id(), sub_account.username(), 30)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, SubAccountStoreError::AssetMismatch(_)));
+    let err = store
+        .credit_sub_account(accs[1].id(), &Username::from_str("nobody").unwrap(), 30)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, SubAccountStoreError::AccountNotFound(_)));
+    assert_eq!(store.get_balance(accs[1].id()).await.unwrap(), -30);
+}
+
+#[tokio::test]
+async fn keeps_the_most_recent_balance_samples() {
+    let (store, accs) = test_store().await;
+    let (alice, bob) = (accs[0].id(), accs[1].id());
+    store.record_balance_samples(1000, 2).await.unwrap();
+    store.update_balances_for_prepare(alice, 100).await.unwrap();
+    store.record_balance_samples(2000, 2).await.unwrap();
+    store.update_balances_for_reject(alice, 100).await.unwrap();
+    store.record_balance_samples(3000, 2).await.unwrap();
+
+    // The first sample was dropped to keep the history within its capacity
+    let samples = store.get_balance_history(alice, 0, u64::MAX).await.unwrap();
+    assert_eq!(
+        samples,
+        vec![
+            BalanceSample {
+                timestamp: 2000,
+                balance: -100
+            },
+            BalanceSample {
+                timestamp: 3000,
+                balance: 0
+            },
+        ]
+    );
+    let samples = store.get_balance_history(bob, 2500, 3000).await.unwrap();
+    assert_eq!(
+        samples,
+        vec![BalanceSample {
+            timestamp: 3000,
+            balance: 0
+        }]
+    );
+
+    store.delete_account(alice).await.unwrap();
+    assert!(store
+        .get_balance_history(alice, 0, u64::MAX)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn accumulates_and_takes_conversion_dust() {
+    let (store, accs) = test_store().await;
+    let id = accs[0].id();
+    store.add_dust(id, 999).await.unwrap();
+    store.add_dust(id, 500).await.unwrap();
+    assert_eq!(store.take_dust(id).await.unwrap(), 1499);
+    assert_eq!(store.take_dust(id).await.unwrap(), 0);
+
+    // The dust is deleted along with the account
+    store.add_dust(id, 1).await.unwrap();
+    store.delete_account(id).await.unwrap();
+    assert_eq!(store.take_dust(id).await.unwrap(), 0);
+}
@@ -0,0 +1,49 @@
+use super::store_helpers::*;
+
+use bytes::Bytes;
+use interledger_service_util::{DedupStore, PacketStatus};
+use std::time::Duration;
+
+#[tokio::test]
+async fn returns_the_status_of_packets_seen_before() {
+    let (store, _accs) = test_store().await;
+    let ttl = Duration::from_secs(30);
+    assert_eq!(
+        store.start_packet([1; 32], ttl).await.unwrap(),
+        PacketStatus::New
+    );
+    assert_eq!(
+        store.start_packet([1; 32], ttl).await.unwrap(),
+        PacketStatus::InFlight
+    );
+    assert_eq!(
+        store.start_packet([2; 32], ttl).await.unwrap(),
+        PacketStatus::New
+    );
+
+    store
+        .save_packet_response([1; 32], Bytes::from_static(b"response"), ttl)
+        .await
+        .unwrap();
+    assert_eq!(
+        store.start_packet([1; 32], ttl).await.unwrap(),
+        PacketStatus::Answered(Bytes::from_static(b"response"))
+    );
+}
+
+#[tokio::test]
+async fn forgets_packets_once_they_expire() {
+    let (store, _accs) = test_store().await;
+    store
+        .start_packet([1; 32], Duration::from_millis(10))
+        .await
+        .unwrap();
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert_eq!(
+        store
+            .start_packet([1; 32], Duration::from_secs(30))
+            .await
+            .unwrap(),
+        PacketStatus::New
+    );
+}
@@ -0,0 +1,139 @@
+use super::{fixtures::*, store_helpers::*};
+use interledger_api::NodeStore;
+use interledger_ccp::CcpRoutingStore;
+use interledger_ildcp::AddressAssignmentStore;
+use interledger_packet::Address;
+use interledger_router::RouterStore;
+use interledger_service::{Account as AccountTrait, AddressStore};
+use interledger_store::account::Account;
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn routes_to_local_accounts() {
+    let (store, accs) = test_store().await;
+    let routes = store.routing_table();
+    assert_eq!(routes["example.alice"], accs[0].id());
+    assert_eq!(routes["example.alice.user1.bob"], accs[1].id());
+    assert_eq!(routes.len(), 2);
+
+    store.delete_account(accs[1].id()).await.unwrap();
+    assert_eq!(store.routing_table().len(), 1);
+}
+
+#[tokio::test]
+async fn static_routes_override_others() {
+    let (store, accs) = test_store().await;
+    store
+        .set_static_routes(vec![
+            ("example.a".to_string(), accs[0].id()),
+            ("example.b".to_string(), accs[0].id()),
+        ])
+        .await
+        .unwrap();
+
+    let account1_id = Uuid::new_v4();
+    let account1 = Account::try_from(
+        account1_id,
+        ACCOUNT_DETAILS_1.clone(),
+        store.get_ilp_address(),
+    )
+    .unwrap();
+    store
+        .clone()
+        .set_routes(vec![
+            ("example.a".to_string(), account1.clone()),
+            ("example.b".to_string(), account1.clone()),
+            ("example.c".to_string(), account1),
+        ])
+        .await
+        .unwrap();
+
+    let routes = store.routing_table();
+    assert_eq!(routes["example.a"], accs[0].id());
+    assert_eq!(routes["example.b"], accs[0].id());
+    assert_eq!(routes["example.c"], account1_id);
+    assert_eq!(routes.len(), 3);
+
+    // cannot route to accounts which do not exist
+    assert!(store
+        .set_static_route("example.d".to_string(), account1_id)
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn default_route() {
+    let (store, accs) = test_store().await;
+    store.set_default_route(accs[0].id()).await.unwrap();
+    let routes = store.routing_table();
+    assert_eq!(routes[""], accs[0].id());
+    assert_eq!(routes.len(), 3);
+}
+
+#[tokio::test]
+async fn deletes_static_and_default_routes() {
+    let (store, accs) = test_store().await;
+    store
+        .set_static_route("example.a".to_string(), accs[1].id())
+        .await
+        .unwrap();
+    store.set_default_route(accs[0].id()).await.unwrap();
+    assert_eq!(
+        store.get_static_routes().await.unwrap()["example.a"],
+        accs[1].id()
+    );
+    assert_eq!(store.get_default_route().await.unwrap(), Some(accs[0].id()));
+    assert_eq!(store.routing_table().len(), 4);
+
+    store.delete_static_route("example.a").await.unwrap();
+    store.delete_default_route().await.unwrap();
+    assert!(store.get_static_routes().await.unwrap().is_empty());
+    assert_eq!(store.get_default_route().await.unwrap(), None);
+    assert_eq!(store.routing_table().len(), 2);
+
+    // Deleting them again succeeds, so that the deletions can be replayed
+    store.delete_static_route("example.a").await.unwrap();
+    store.delete_default_route().await.unwrap();
+}
+
+#[tokio::test]
+async fn gets_accounts_to_send_and_receive_routes() {
+    let (store, accs) = test_store().await;
+    // alice is our parent and bob is our child
+    let accounts = store.get_accounts_to_send_routes_to(vec![]).await.unwrap();
+    assert_eq!(accounts.len(), 1);
+    assert_eq!(accounts[0].id(), accs[1].id());
+    let accounts = store
+        .get_accounts_to_send_routes_to(vec![accs[1].id()])
+        .await
+        .unwrap();
+    assert!(accounts.is_empty());
+    let accounts = store.get_accounts_to_receive_routes_from().await.unwrap();
+    assert_eq!(accounts.len(), 1);
+    assert_eq!(accounts[0].id(), accs[0].id());
+}
+
+#[tokio::test]
+async fn assigns_address_once() {
+    let (store, accs) = test_store().await;
+    let id = accs[1].id();
+    let parent = store.get_ilp_address();
+    assert_eq!(store.get_assigned_address(id).await.unwrap(), None);
+
+    let address = store.assign_address(id, &parent).await.unwrap();
+    assert_eq!(
+        address,
+        Address::from_str("example.alice.user1.child1").unwrap()
+    );
+    assert_eq!(store.assign_address(id, &parent).await.unwrap(), address);
+    assert_eq!(store.routing_table()[&address.to_string()], id);
+
+    // the suffix is kept when the node's address changes
+    let new_address = Address::from_str("example.new").unwrap();
+    store.set_ilp_address(new_address).await.unwrap();
+    assert_eq!(
+        store.get_assigned_address(id).await.unwrap(),
+        Some(Address::from_str("example.new.child1").unwrap())
+    );
+}
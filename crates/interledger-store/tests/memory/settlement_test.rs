@@ -0,0 +1,358 @@
+use super::store_helpers::*;
+use bytes::Bytes;
+use http::StatusCode;
+use interledger_api::NodeStore;
+use interledger_errors::SettlementStoreError;
+use interledger_service::{Account, AccountStore};
+use interledger_service_util::{BalanceStore, UsagePeriod, UsageStore};
+use interledger_settlement::core::{
+    idempotency::{IdempotentData, IdempotentStore},
+    types::{
+        LeftoversStore, PendingMessage, PendingSettlement, SettlementAccount, SettlementDirection,
+        SettlementHistoryStore, SettlementQueueStore, SettlementRecord, SettlementStore,
+    },
+};
+use interledger_store::memory::MemoryStoreBuilder;
+use num_bigint::BigUint;
+use once_cell::sync::Lazy;
+use std::time::Duration;
+use url::Url;
+use uuid::Uuid;
+
+static IDEMPOTENCY_KEY: Lazy<String> = Lazy::new(|| String::from("AJKJNUjM0oyiAN46"));
+
+#[tokio::test]
+async fn saves_gets_clears_uncredited_settlement_amount_properly() {
+    let (store, _accs) = test_store().await;
+    let amounts: Vec<(BigUint, u8)> = vec![
+        (BigUint::from(5u32), 11),   // 5
+        (BigUint::from(855u32), 12), // 905
+        (BigUint::from(1u32), 10),   // 1005 total
+    ];
+    let acc = Uuid::new_v4();
+    for a in amounts {
+        store
+            .save_uncredited_settlement_amount(acc, a)
+            .await
+            .unwrap();
+    }
+    let ret = store
+        .load_uncredited_settlement_amount(acc, 9u8)
+        .await
+        .unwrap();
+    // 1 uncredited unit for scale 9
+    assert_eq!(ret, BigUint::from(1u32));
+    // rest should be in the leftovers store
+    let ret = store.get_uncredited_settlement_amount(acc).await.unwrap();
+    assert_eq!(ret, (BigUint::from(5u32), 12));
+
+    store.clear_uncredited_settlement_amount(acc).await.unwrap();
+    let ret = store.get_uncredited_settlement_amount(acc).await.unwrap();
+    assert_eq!(ret, (BigUint::from(0u32), 0));
+}
+
+#[tokio::test]
+async fn saves_and_loads_idempotency_key_data_properly() {
+    let (store, _) = test_store().await;
+    let input_hash: [u8; 32] = Default::default();
+    store
+        .save_idempotent_data(
+            IDEMPOTENCY_KEY.clone(),
+            input_hash,
+            StatusCode::OK,
+            Bytes::from("TEST"),
+        )
+        .await
+        .unwrap();
+
+    let data = store
+        .load_idempotent_data(IDEMPOTENCY_KEY.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        data.unwrap(),
+        IdempotentData::new(StatusCode::OK, Bytes::from("TEST"), input_hash)
+    );
+
+    let data = store
+        .load_idempotent_data("asdf".to_string())
+        .await
+        .unwrap();
+    assert!(data.is_none());
+}
+
+#[tokio::test]
+async fn lists_deletes_and_expires_idempotent_data() {
+    tokio::time::pause();
+    let store = MemoryStoreBuilder::new()
+        .idempotency_key_ttl(Duration::from_millis(100))
+        .build();
+    for key in &["a", "b", "c"] {
+        store
+            .save_idempotent_data(
+                key.to_string(),
+                Default::default(),
+                StatusCode::OK,
+                Bytes::from("TEST"),
+            )
+            .await
+            .unwrap();
+    }
+    let mut keys = store.get_idempotency_keys().await.unwrap();
+    keys.sort();
+    assert_eq!(keys, vec!["a", "b", "c"]);
+
+    store
+        .delete_idempotent_data(vec!["b".to_string()])
+        .await
+        .unwrap();
+    assert!(store
+        .load_idempotent_data("b".to_string())
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(store.get_idempotency_keys().await.unwrap().len(), 2);
+    assert_eq!(store.remove_expired_idempotent_data().await.unwrap(), 0);
+
+    tokio::time::advance(Duration::from_millis(100)).await;
+    assert!(store.get_idempotency_keys().await.unwrap().is_empty());
+    assert!(store
+        .load_idempotent_data("a".to_string())
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(store.remove_expired_idempotent_data().await.unwrap(), 2);
+    assert_eq!(store.remove_expired_idempotent_data().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn idempotent_settlement_calls() {
+    let (store, accs) = test_store().await;
+    let id = accs[0].id();
+    for _ in 0..2 {
+        store
+            .update_balance_for_incoming_settlement(id, 100, Some(IDEMPOTENCY_KEY.clone()))
+            .await
+            .unwrap();
+    }
+    assert_eq!(store.get_balance(id).await.unwrap(), 100);
+}
+
+#[tokio::test]
+async fn clears_balance_owed_and_puts_remainder_as_prepaid() {
+    let (store, accs) = test_store().await;
+    let id = accs[0].id();
+    store.update_balances_for_prepare(id, 40).await.unwrap();
+    store
+        .update_balance_for_incoming_settlement(id, 100, None)
+        .await
+        .unwrap();
+    assert_eq!(store.get_balance(id).await.unwrap(), 60);
+
+    // the prepaid amount is used up before the balance
+    store.update_balances_for_prepare(id, 80).await.unwrap();
+    assert_eq!(store.get_balance(id).await.unwrap(), -20);
+
+    store.refund_settlement(id, 20).await.unwrap();
+    assert_eq!(store.get_balance(id).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn credits_settlements_up_to_the_largest_balance() {
+    let (store, accs) = test_store().await;
+    let id = accs[0].id();
+    store
+        .update_balance_for_incoming_settlement(id, 100, None)
+        .await
+        .unwrap();
+    assert_eq!(store.get_balance(id).await.unwrap(), 100);
+
+    // only as much is credited as the balance can hold, and the rest is returned
+    let uncredited = store
+        .update_balance_for_incoming_settlement(id, u64::MAX, None)
+        .await
+        .unwrap();
+    assert_eq!(uncredited, u64::MAX - (i64::MAX as u64 - 100));
+    assert_eq!(store.get_balance(id).await.unwrap(), i64::MAX);
+
+    let uncredited = store
+        .update_balance_for_incoming_settlement(id, 1, None)
+        .await
+        .unwrap();
+    assert_eq!(uncredited, 1);
+    let result = store.refund_settlement(id, u64::MAX).await;
+    assert!(matches!(result, Err(SettlementStoreError::BalanceOverflow)));
+    // the balance is left untouched
+    assert_eq!(store.get_balance(id).await.unwrap(), i64::MAX);
+}
+
+#[tokio::test]
+async fn loads_globally_configured_settlement_engine_url() {
+    let (store, accs) = test_store().await;
+    assert!(accs[0].settlement_engine_details().is_some());
+    assert!(accs[1].settlement_engine_details().is_none());
+    let account_ids = vec![accs[0].id(), accs[1].id()];
+
+    store
+        .set_settlement_engines(vec![(
+            "ABC".to_string(),
+            Url::parse("http://settle-abc.example").unwrap(),
+        )])
+        .await
+        .unwrap();
+    let accounts = store.get_accounts(account_ids).await.unwrap();
+    assert_eq!(
+        accounts[0]
+            .settlement_engine_details()
+            .unwrap()
+            .url
+            .as_str(),
+        "http://settlement.example/"
+    );
+    assert_eq!(
+        accounts[1]
+            .settlement_engine_details()
+            .unwrap()
+            .url
+            .as_str(),
+        "http://settle-abc.example/"
+    );
+}
+
+#[tokio::test]
+async fn records_and_resets_usage() {
+    let (store, accs) = test_store().await;
+    let (alice, bob) = (accs[0].id(), accs[1].id());
+    store.record_usage(alice, 100, bob, 200).await.unwrap();
+    store.record_usage(alice, 10, bob, 20).await.unwrap();
+
+    let usage = store.get_usage(alice, UsagePeriod::Daily).await.unwrap();
+    assert_eq!(usage.period, UsagePeriod::Daily.current_period_id());
+    assert_eq!(usage.packets_sent, 2);
+    assert_eq!(usage.amount_sent, 110);
+    assert_eq!(usage.packets_received, 0);
+    let usage = store.get_usage(bob, UsagePeriod::Monthly).await.unwrap();
+    assert_eq!(usage.packets_received, 2);
+    assert_eq!(usage.amount_received, 220);
+
+    store.reset_usage(bob, UsagePeriod::Monthly).await.unwrap();
+    let usage = store.get_usage(bob, UsagePeriod::Monthly).await.unwrap();
+    assert_eq!(usage.packets_received, 0);
+    let usage = store.get_usage(bob, UsagePeriod::Daily).await.unwrap();
+    assert_eq!(usage.packets_received, 2);
+}
+
+#[tokio::test]
+async fn saves_loads_and_removes_pending_settlements() {
+    let (store, accs) = test_store().await;
+    assert!(store.load_pending_settlements().await.unwrap().is_empty());
+
+    let mut settlement = PendingSettlement {
+        id: Uuid::new_v4(),
+        account_id: accs[0].id(),
+        amount: 100,
+        attempts: 0,
+        next_attempt_at: 1_600_000_000_000,
+        last_error: None,
+    };
+    store
+        .save_pending_settlement(settlement.clone())
+        .await
+        .unwrap();
+    // Saving the settlement again replaces it
+    settlement.attempts = 1;
+    settlement.last_error = Some("engine unavailable".to_string());
+    store
+        .save_pending_settlement(settlement.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        store.load_pending_settlements().await.unwrap(),
+        vec![settlement.clone()]
+    );
+
+    store
+        .remove_pending_settlement(settlement.id)
+        .await
+        .unwrap();
+    assert!(store.load_pending_settlements().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn saves_loads_and_removes_pending_messages() {
+    let (store, accs) = test_store().await;
+    assert!(store.load_pending_messages().await.unwrap().is_empty());
+
+    let message = PendingMessage {
+        id: Uuid::new_v4(),
+        account_id: accs[0].id(),
+        message: b"hello".to_vec(),
+        attempts: 1,
+        next_attempt_at: 1_600_000_000_000,
+        last_error: Some("engine unavailable".to_string()),
+    };
+    store.save_pending_message(message.clone()).await.unwrap();
+    assert_eq!(
+        store.load_pending_messages().await.unwrap(),
+        vec![message.clone()]
+    );
+
+    store.remove_pending_message(message.id).await.unwrap();
+    assert!(store.load_pending_messages().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn records_settlement_history() {
+    let (store, accs) = test_store().await;
+    let (alice, bob) = (accs[0].id(), accs[1].id());
+    let record = |timestamp, direction| SettlementRecord {
+        timestamp,
+        direction,
+        amount: 100,
+        id: None,
+    };
+    store
+        .record_settlement(alice, record(1000, SettlementDirection::Outgoing))
+        .await
+        .unwrap();
+    store
+        .record_settlement(alice, record(2000, SettlementDirection::Incoming))
+        .await
+        .unwrap();
+    store
+        .record_settlement(bob, record(3000, SettlementDirection::Refunded))
+        .await
+        .unwrap();
+
+    let history = store
+        .get_settlement_history(alice, 0, u64::MAX)
+        .await
+        .unwrap();
+    assert_eq!(
+        history,
+        vec![
+            record(1000, SettlementDirection::Outgoing),
+            record(2000, SettlementDirection::Incoming)
+        ]
+    );
+    let history = store
+        .get_settlement_history(alice, 1500, 2500)
+        .await
+        .unwrap();
+    assert_eq!(history, vec![record(2000, SettlementDirection::Incoming)]);
+
+    store.delete_account(alice).await.unwrap();
+    assert!(store
+        .get_settlement_history(alice, 0, u64::MAX)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        store
+            .get_settlement_history(bob, 0, u64::MAX)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
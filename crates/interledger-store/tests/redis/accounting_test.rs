@@ -0,0 +1,42 @@
+use super::store_helpers::*;
+
+use interledger_api::{AccountingSink, LedgerEntry, LedgerEntryKind, LedgerPosting};
+use redis_crate::Client;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn adds_ledger_entries_to_a_stream() {
+    let (store, context, _accs) = test_store().await.unwrap();
+    let posting = |ledger_account: &str| LedgerPosting {
+        ledger_account: ledger_account.to_string(),
+        amount: 100,
+        asset_code: "XYZ".to_string(),
+        asset_scale: 9,
+    };
+    let entry = LedgerEntry {
+        id: Uuid::new_v4(),
+        timestamp: 1000,
+        kind: LedgerEntryKind::SettlementReceived,
+        debit: posting("settlements:alice"),
+        credit: posting("account:alice"),
+    };
+    store.record_ledger_entry(&entry).await.unwrap();
+    store.record_ledger_entry(&entry).await.unwrap();
+
+    let client = Client::open(context.get_client_connection_info()).unwrap();
+    let mut connection = client.get_multiplexed_tokio_connection().await.unwrap();
+    let entries: Vec<(String, Vec<String>)> = redis_crate::cmd("XRANGE")
+        .arg("accounting:ledger")
+        .arg("-")
+        .arg("+")
+        .query_async(&mut connection)
+        .await
+        .unwrap();
+    assert_eq!(entries.len(), 2);
+    let fields = &entries[0].1;
+    assert_eq!(fields[0], "id");
+    assert_eq!(fields[1], entry.id.to_string());
+    assert_eq!(fields[4], "kind");
+    assert_eq!(fields[5], "settlement_received");
+    assert_eq!(fields[15], "account:alice");
+}
@@ -1,14 +1,15 @@
 use super::{fixtures::*, redis_helpers::*, store_helpers::*};
-use interledger_api::{AccountSettings, NodeStore};
+use interledger_api::{AccountSettings, NodeStore, Webhook, WebhookEventType, WebhookStore};
 use interledger_btp::BtpAccount;
 use interledger_ccp::{CcpRoutingAccount, RoutingRelation};
 use interledger_http::HttpAccount;
 use interledger_packet::Address;
 use interledger_service::Account as AccountTrait;
-use interledger_service::{AccountStore, AddressStore, Username};
+use interledger_service::{AccountId, AccountStore, AddressStore, Username};
 use interledger_service_util::BalanceStore;
+use interledger_spsp::{Contact, ContactStore};
 use interledger_store::redis::RedisStoreBuilder;
-use redis_crate::Client;
+use redis_crate::{AsyncCommands, Client};
 use secrecy::ExposeSecret;
 use secrecy::SecretString;
 use std::default::Default;
@@ -44,6 +45,44 @@ async fn picks_up_parent_during_initialization() {
     );
 }
 
+#[tokio::test]
+async fn migrates_accounts_to_a_new_db_prefix() {
+    let context = TestContext::new();
+    let store = RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+        .connect()
+        .await
+        .unwrap();
+    let account = store
+        .insert_account(ACCOUNT_DETAILS_0.clone())
+        .await
+        .unwrap();
+    drop(store);
+
+    let store = RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+        .with_db_prefix("tenant")
+        .migrate_from_db_prefix("")
+        .connect()
+        .await
+        .unwrap();
+    let accounts = store.get_all_accounts().await.unwrap();
+    assert_eq!(accounts.len(), 1);
+    assert_eq!(accounts[0].id(), account.id());
+    assert_eq!(
+        store
+            .get_account_id_from_username(account.username())
+            .await
+            .unwrap(),
+        account.id()
+    );
+
+    // Nothing is left under the old prefix
+    let store = RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+        .connect()
+        .await
+        .unwrap();
+    assert!(store.get_all_accounts().await.unwrap().is_empty());
+}
+
 #[tokio::test]
 async fn insert_accounts() {
     let (store, _context, _) = test_store().await.unwrap();
@@ -139,21 +178,19 @@ async fn update_ilp_and_children_addresses() {
 }
 
 #[tokio::test]
-async fn only_one_parent_allowed() {
+async fn multiple_parents_allowed() {
     let mut acc = ACCOUNT_DETAILS_2.clone();
     acc.routing_relation = Some("Parent".to_owned());
     acc.username = Username::from_str("another_name").unwrap();
     acc.ilp_address = Some(Address::from_str("example.another_name").unwrap());
     let (store, _context, accs) = test_store().await.unwrap();
-    let res = store.insert_account(acc.clone()).await;
-    // This should fail
-    assert!(res.is_err());
-    store.delete_account(accs[0].id()).await.unwrap();
-    // must also clear the ILP Address to indicate that we no longer
-    // have a parent account configured
-    store.clear_ilp_address().await.unwrap();
-    let res = store.insert_account(acc).await;
-    assert!(res.is_ok());
+    let parent = store.insert_account(acc).await.unwrap();
+    assert_eq!(parent.routing_relation(), RoutingRelation::Parent);
+    // The first parent keeps assigning the node's address
+    assert_eq!(
+        store.get_ilp_address(),
+        accs[0].ilp_address().with_suffix(b"user1").unwrap()
+    );
 }
 
 #[tokio::test]
@@ -182,6 +219,125 @@ async fn delete_accounts() {
     assert_eq!(err.to_string(), "Broken pipe (os error 32)");
 }
 
+#[tokio::test]
+async fn saves_and_deletes_contacts() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let id = accs[0].id();
+    let bob = Contact::new("bob".to_string(), "$example.com/bob".to_string()).unwrap();
+    let alice = Contact::new("alice".to_string(), "$example.com/alice".to_string()).unwrap();
+    store.save_contact(id, bob.clone()).await.unwrap();
+    store.save_contact(id, alice.clone()).await.unwrap();
+    assert_eq!(
+        store.get_contacts(id).await.unwrap(),
+        vec![alice, bob.clone()]
+    );
+    assert_eq!(store.get_contact(id, "bob").await.unwrap(), bob);
+
+    store.delete_contact(id, "alice").await.unwrap();
+    let err = store.get_contact(id, "alice").await.unwrap_err();
+    assert_eq!(err.to_string(), "contact `alice` was not found");
+    assert!(store.delete_contact(id, "alice").await.is_err());
+
+    store.delete_account(id).await.unwrap();
+    assert!(store.get_contacts(id).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn inserts_and_exports_accounts_in_bulk() {
+    let (store, _context, _) = test_store().await.unwrap();
+    let mut dave = ACCOUNT_DETAILS_2.clone();
+    dave.username = Username::from_str("dave").unwrap();
+
+    // charlie is in the batch twice, so none of the accounts are inserted
+    let results = store
+        .insert_accounts(
+            vec![
+                ACCOUNT_DETAILS_2.clone(),
+                dave.clone(),
+                ACCOUNT_DETAILS_2.clone(),
+            ],
+            true,
+        )
+        .await;
+    assert_eq!(
+        results[1].as_ref().unwrap_err().to_string(),
+        "account `dave` was not inserted because another account failed to be"
+    );
+    assert_eq!(
+        results[2].as_ref().unwrap_err().to_string(),
+        "account `charlie` already exists"
+    );
+    assert!(store
+        .get_account_id_from_username(&ACCOUNT_DETAILS_2.username)
+        .await
+        .is_err());
+
+    let results = store
+        .insert_accounts(vec![dave, ACCOUNT_DETAILS_1.clone()], false)
+        .await;
+    assert_eq!(results[0].as_ref().unwrap().username().to_string(), "dave");
+    assert_eq!(
+        results[1].as_ref().unwrap_err().to_string(),
+        "account `bob` already exists"
+    );
+
+    let exported = store.export_accounts().await.unwrap();
+    assert_eq!(exported.len(), 3);
+    let alice = exported
+        .iter()
+        .find(|details| details.username.to_string() == "alice")
+        .unwrap();
+    assert_eq!(
+        alice
+            .ilp_over_http_incoming_token
+            .as_ref()
+            .unwrap()
+            .expose_secret(),
+        "incoming_auth_token"
+    );
+    assert_eq!(
+        alice.settlement_engine_url.as_deref(),
+        Some("http://settlement.example/")
+    );
+}
+
+#[tokio::test]
+async fn sets_and_deletes_webhooks() {
+    let (store, context, accs) = test_store().await.unwrap();
+    let id = accs[0].id();
+    let webhook = Webhook {
+        url: "https://example.com/hook".parse().unwrap(),
+        secret: "whsec_1f0c2a".to_string(),
+        events: vec![WebhookEventType::SettlementCompleted],
+        balance_thresholds: vec![-100, 100],
+    };
+    assert!(store.get_webhook(id).await.is_err());
+    store.set_webhook(id, webhook.clone()).await.unwrap();
+    assert_eq!(store.get_webhook(id).await.unwrap(), webhook);
+
+    // The secret is only stored encrypted
+    let mut connection = context.async_connection().await.unwrap();
+    let data: String = connection.hget("webhooks", id.to_string()).await.unwrap();
+    let encrypted_secret: Vec<u8> = connection
+        .hget("webhook_secrets", id.to_string())
+        .await
+        .unwrap();
+    assert!(!data.contains("whsec_1f0c2a"));
+    assert!(!encrypted_secret
+        .windows(webhook.secret.len())
+        .any(|window| window == webhook.secret.as_bytes()));
+
+    store.delete_webhook(id).await.unwrap();
+    let err = store.get_webhook(id).await.unwrap_err();
+    assert_eq!(err.to_string(), format!("account `{}` has no webhook", id));
+    assert!(store.delete_webhook(id).await.is_err());
+
+    // The webhook is deleted along with the account
+    store.set_webhook(id, webhook).await.unwrap();
+    store.delete_account(id).await.unwrap();
+    assert!(store.get_webhook(id).await.is_err());
+}
+
 #[tokio::test]
 async fn update_accounts() {
     let (store, _context, accounts) = test_store().await.unwrap();
@@ -346,3 +502,66 @@ async fn errors_for_unknown_accounts() {
         .unwrap_err();
     assert_eq!(err.to_string(), "wrong account length (expected 2, got 0)");
 }
+
+#[tokio::test]
+async fn migrates_legacy_numeric_account_ids() {
+    let (store, context, accs) = test_store().await.unwrap();
+    let alice = accs[0].clone();
+    let id = alice.id().to_hyphenated().to_string();
+
+    // rewrite alice as if she had been saved by a version using numeric ids
+    let client = Client::open(context.get_client_connection_info()).unwrap();
+    let mut connection = client.get_multiplexed_tokio_connection().await.unwrap();
+    let _: redis_crate::Value = redis_crate::pipe()
+        .atomic()
+        .cmd("RENAME")
+        .arg(format!("accounts:{}", id))
+        .arg("accounts:7")
+        .cmd("HSET")
+        .arg("accounts:7")
+        .arg("id")
+        .arg("7")
+        .cmd("SREM")
+        .arg("accounts")
+        .arg(&id)
+        .cmd("SADD")
+        .arg("accounts")
+        .arg("7")
+        .cmd("HSET")
+        .arg("usernames")
+        .arg("alice")
+        .arg("7")
+        .cmd("HSET")
+        .arg("routes:current")
+        .arg(alice.ilp_address().as_bytes())
+        .arg("7")
+        .cmd("HSET")
+        .arg("usage:7:2020-01-31")
+        .arg("packets_sent")
+        .arg("1")
+        .query_async(&mut connection)
+        .await
+        .unwrap();
+    drop(store);
+
+    let store = RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+        .connect()
+        .await
+        .unwrap();
+    let legacy_id: Uuid = AccountId::from_legacy(7).into();
+    let account_id = store
+        .get_account_id_from_username(&Username::from_str("alice").unwrap())
+        .await
+        .unwrap();
+    assert_eq!(account_id, legacy_id);
+    let accounts = store.get_accounts(vec![legacy_id]).await.unwrap();
+    assert_eq!(accounts[0].username(), alice.username());
+    let exists: bool = redis_crate::cmd("EXISTS")
+        .arg("accounts:7")
+        .query_async(&mut connection)
+        .await
+        .unwrap();
+    assert!(!exists);
+    let usage: Vec<String> = connection.keys("usage:*").await.unwrap();
+    assert_eq!(usage, vec![format!("usage:{}:2020-01-31", legacy_id)]);
+}
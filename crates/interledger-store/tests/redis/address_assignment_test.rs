@@ -0,0 +1,99 @@
+use super::store_helpers::*;
+
+use interledger_api::NodeStore;
+use interledger_ildcp::AddressAssignmentStore;
+use interledger_packet::Address;
+use interledger_router::RouterStore;
+use interledger_service::{Account as AccountTrait, AccountStore, AddressStore};
+use interledger_store::redis::RedisStoreBuilder;
+use std::str::FromStr;
+
+#[tokio::test]
+async fn assigns_address_once() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let bob = accs[1].clone();
+    let node_address = store.get_ilp_address();
+    assert_eq!(store.get_assigned_address(bob.id()).await.unwrap(), None);
+
+    let assigned = store.assign_address(bob.id(), &node_address).await.unwrap();
+    assert_eq!(assigned, node_address.with_suffix(b"child1").unwrap());
+    assert_eq!(
+        store.get_assigned_address(bob.id()).await.unwrap(),
+        Some(assigned.clone())
+    );
+
+    // Assigning again returns the same address
+    let again = store.assign_address(bob.id(), &node_address).await.unwrap();
+    assert_eq!(again, assigned);
+
+    // The account and the routing table use the new address
+    let account = store
+        .get_accounts(vec![bob.id()])
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    assert_eq!(*account.ilp_address(), assigned);
+    let routing_table = store.routing_table();
+    assert_eq!(routing_table.get(&assigned.to_string()), Some(&bob.id()));
+    assert!(routing_table.get(&bob.ilp_address().to_string()).is_none());
+}
+
+#[tokio::test]
+async fn keeps_assigned_suffix_when_node_address_changes() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let bob = accs[1].clone();
+    let node_address = store.get_ilp_address();
+    store.assign_address(bob.id(), &node_address).await.unwrap();
+
+    let new_address = Address::from_str("example.other_parent.node").unwrap();
+    store.set_ilp_address(new_address.clone()).await.unwrap();
+    let account = store
+        .get_accounts(vec![bob.id()])
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    assert_eq!(
+        *account.ilp_address(),
+        new_address.with_suffix(b"child1").unwrap()
+    );
+}
+
+#[tokio::test]
+async fn deleting_account_removes_assigned_address() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let bob = accs[1].clone();
+    let node_address = store.get_ilp_address();
+    store.assign_address(bob.id(), &node_address).await.unwrap();
+
+    store.delete_account(bob.id()).await.unwrap();
+    assert_eq!(store.get_assigned_address(bob.id()).await.unwrap(), None);
+    assert!(store.assign_address(bob.id(), &node_address).await.is_err());
+}
+
+#[tokio::test]
+async fn saves_secondary_addresses() {
+    let (store, context, _accs) = test_store().await.unwrap();
+    assert!(store.get_secondary_ilp_addresses().is_empty());
+
+    let secondary_addresses = vec![
+        Address::from_str("example.other_parent.node").unwrap(),
+        Address::from_str("test.third_parent.node").unwrap(),
+    ];
+    store
+        .set_secondary_ilp_addresses(secondary_addresses.clone())
+        .await
+        .unwrap();
+    assert_eq!(store.get_secondary_ilp_addresses(), secondary_addresses);
+
+    // They are loaded again when the node restarts
+    let store = RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(store.get_secondary_ilp_addresses(), secondary_addresses);
+
+    store.set_secondary_ilp_addresses(Vec::new()).await.unwrap();
+    assert!(store.get_secondary_ilp_addresses().is_empty());
+}
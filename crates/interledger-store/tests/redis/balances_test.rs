@@ -1,12 +1,16 @@
-use super::{fixtures::*, store_helpers::*};
+use super::{fixtures::*, redis_helpers::*, store_helpers::*};
 
 use interledger_api::NodeStore;
+use interledger_errors::SubAccountStoreError;
 use interledger_packet::Address;
 use interledger_service::Account as AccountTrait;
 use interledger_service::{AccountStore, Username};
-use interledger_service_util::BalanceStore;
+use interledger_service_util::{BalanceHistoryStore, BalanceSample, BalanceStore, DustStore};
+use interledger_store::redis::RedisStoreBuilder;
+use interledger_stream::SubAccountStore;
 use redis_crate::AsyncCommands;
 use std::str::FromStr;
+use std::time::Duration;
 use uuid::Uuid;
 
 #[tokio::test]
@@ -336,3 +340,118 @@ async fn netting_fulfilled_balances() {
     assert_eq!(balance0, -20);
     assert_eq!(balance1, 20);
 }
+
+#[tokio::test]
+async fn credits_sub_accounts() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let mut details = ACCOUNT_DETAILS_2.clone();
+    details.asset_code = accs[1].asset_code().to_string();
+    details.asset_scale = accs[1].asset_scale();
+    let sub_account = store.insert_account(details).await.unwrap();
+
+    store
+        .credit_sub_account(accs[1].id(), sub_account.username(), 30)
+        .await
+        .unwrap();
+    assert_eq!(store.get_balance(accs[1].id()).await.unwrap(), -30);
+    assert_eq!(store.get_balance(sub_account.id()).await.unwrap(), 30);
+
+    // The sub-account must have the same asset as the receiving account
+    let err = store
+        .credit_sub_account(accs[0].id(), sub_account.username(), 30)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, SubAccountStoreError::AssetMismatch(_)));
+    let err = store
+        .credit_sub_account(accs[1].id(), &Username::from_str("nobody").unwrap(), 30)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, SubAccountStoreError::AccountNotFound(_)));
+    assert_eq!(store.get_balance(accs[1].id()).await.unwrap(), -30);
+}
+
+#[tokio::test]
+async fn batches_balance_updates() {
+    let context = TestContext::new();
+    let store = RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+        .balance_batching(Duration::from_millis(10), 4)
+        .connect()
+        .await
+        .unwrap();
+    let mut details = ACCOUNT_DETAILS_2.clone();
+    details.settle_threshold = Some(500);
+    details.settle_to = Some(0);
+    let account = store.insert_account(details).await.unwrap();
+
+    // The 6 fulfills are applied in two batches, and settlement is only triggered once
+    let results = futures::future::join_all(
+        (0..6).map(|_| store.update_balances_for_fulfill(account.id(), 100)),
+    )
+    .await;
+    let amount_to_settle: u64 = results.into_iter().map(|result| result.unwrap().1).sum();
+    assert_eq!(amount_to_settle, 500);
+    assert_eq!(store.get_balance(account.id()).await.unwrap(), 100);
+
+    futures::future::join_all((0..2).map(|_| store.update_balances_for_reject(account.id(), 50)))
+        .await
+        .into_iter()
+        .for_each(|result| result.unwrap());
+    assert_eq!(store.get_balance(account.id()).await.unwrap(), 200);
+}
+
+#[tokio::test]
+async fn keeps_the_most_recent_balance_samples() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let (alice, bob) = (accs[0].id(), accs[1].id());
+    store.record_balance_samples(1000, 2).await.unwrap();
+    store.update_balances_for_prepare(alice, 100).await.unwrap();
+    store.record_balance_samples(2000, 2).await.unwrap();
+    store.update_balances_for_reject(alice, 100).await.unwrap();
+    store.record_balance_samples(3000, 2).await.unwrap();
+
+    // The first sample was dropped to keep the history within its capacity
+    let samples = store.get_balance_history(alice, 0, u64::MAX).await.unwrap();
+    assert_eq!(
+        samples,
+        vec![
+            BalanceSample {
+                timestamp: 2000,
+                balance: -100
+            },
+            BalanceSample {
+                timestamp: 3000,
+                balance: 0
+            },
+        ]
+    );
+    let samples = store.get_balance_history(bob, 2500, 3000).await.unwrap();
+    assert_eq!(
+        samples,
+        vec![BalanceSample {
+            timestamp: 3000,
+            balance: 0
+        }]
+    );
+
+    store.delete_account(alice).await.unwrap();
+    assert!(store
+        .get_balance_history(alice, 0, u64::MAX)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn accumulates_and_takes_conversion_dust() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let id = accs[0].id();
+    store.add_dust(id, 999).await.unwrap();
+    store.add_dust(id, 500).await.unwrap();
+    assert_eq!(store.take_dust(id).await.unwrap(), 1499);
+    assert_eq!(store.take_dust(id).await.unwrap(), 0);
+
+    // The dust is deleted along with the account
+    store.add_dust(id, 1).await.unwrap();
+    store.delete_account(id).await.unwrap();
+    assert_eq!(store.take_dust(id).await.unwrap(), 0);
+}
@@ -78,3 +78,24 @@ async fn refunds_throughput_limit_for_rejected_packets() {
     let result = store.apply_rate_limits(account.clone(), 1).await;
     assert_eq!(result.unwrap_err(), RateLimitError::ThroughputLimitExceeded);
 }
+
+#[tokio::test]
+async fn token_buckets_allow_bursts() {
+    let (store, _context, _) = test_store().await.unwrap();
+    let mut details = ACCOUNT_DETAILS_2.clone();
+    details.packets_per_minute_limit = Some(1);
+    details.packets_burst_limit = Some(3);
+    details.amount_per_minute_limit = Some(1);
+    details.amount_burst_limit = Some(1000);
+    let account = Account::try_from(Uuid::new_v4(), details, store.get_ilp_address()).unwrap();
+
+    for _ in 0..2 {
+        store.apply_rate_limits(account.clone(), 400).await.unwrap();
+    }
+    // the packet is not charged to the packets bucket since it exceeds the throughput one
+    let result = store.apply_rate_limits(account.clone(), 400).await;
+    assert_eq!(result, Err(RateLimitError::ThroughputLimitExceeded));
+    store.apply_rate_limits(account.clone(), 200).await.unwrap();
+    let result = store.apply_rate_limits(account, 0).await;
+    assert_eq!(result, Err(RateLimitError::PacketLimitExceeded));
+}
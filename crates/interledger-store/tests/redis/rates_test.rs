@@ -1,6 +1,8 @@
 use super::store_helpers::*;
 
 use interledger_rates::ExchangeRateStore;
+use interledger_store::redis::RedisStoreBuilder;
+use std::time::Duration;
 
 #[tokio::test]
 async fn set_rates() {
@@ -20,3 +22,45 @@ async fn set_rates() {
     assert_eq!(rates[0].to_string(), "0.005");
     assert_eq!(rates[1].to_string(), "500");
 }
+
+#[tokio::test]
+async fn shares_rates_between_stores() {
+    let (store, context, _) = test_store().await.unwrap();
+    assert!(store.get_exchange_rates_updated_at().is_none());
+    store
+        .set_exchange_rates([("ABC".to_string(), 500.0)].iter().cloned().collect())
+        .unwrap();
+    assert!(store.get_exchange_rates_updated_at().is_some());
+
+    let other = RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+        .poll_interval(10)
+        .connect()
+        .await
+        .unwrap();
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    let rates = other.get_exchange_rates(&["ABC"]).unwrap();
+    assert_eq!(rates[0].to_string(), "500");
+    assert!(other.get_exchange_rates_updated_at().is_some());
+}
+
+#[tokio::test]
+async fn shares_rate_overrides_between_stores() {
+    let (store, context, _) = test_store().await.unwrap();
+    assert!(store
+        .set_rate_overrides([("ABC/ABC".to_string(), 2.0)].iter().cloned().collect())
+        .is_err());
+    store
+        .set_rate_overrides([("abc/xyz".to_string(), 4.0)].iter().cloned().collect())
+        .unwrap();
+    assert_eq!(store.get_rate_override("XYZ", "ABC"), Some(0.25));
+
+    let other = RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+        .poll_interval(10)
+        .connect()
+        .await
+        .unwrap();
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    let overrides = other.get_rate_overrides().unwrap();
+    assert_eq!(overrides.get("ABC/XYZ"), Some(&4.0));
+    assert_eq!(other.get_rate_override("ABC", "XYZ"), Some(4.0));
+}
@@ -1,12 +1,19 @@
+mod accounting_test;
 mod accounts_test;
+mod address_assignment_test;
 mod balances_test;
 mod btp_test;
+mod dedup_test;
 mod http_test;
 mod notifications;
 mod rate_limiting_test;
 mod rates_test;
+mod replay_test;
+mod replication_test;
 mod routing_test;
 mod settlement_test;
+mod stream_receive_test;
+mod usage_test;
 
 mod fixtures {
 
@@ -38,6 +45,17 @@ mod fixtures {
         amount_per_minute_limit: Some(1000),
         packets_per_minute_limit: Some(2),
         settlement_engine_url: Some("http://settlement.example".to_string()),
+        spread: None,
+        ilp_over_http_fallback_url: None,
+        ilp_over_http_alternate_urls: Vec::new(),
+        translate_prefix_from: None,
+        translate_prefix_to: None,
+        packets_burst_limit: None,
+        amount_burst_limit: None,
+        accept_route_prefixes: Vec::new(),
+        reject_route_prefixes: Vec::new(),
+        max_routes: None,
+        aggregate_route_prefixes: Vec::new(),
     });
     pub static ACCOUNT_DETAILS_1: Lazy<AccountDetails> = Lazy::new(|| AccountDetails {
         ilp_address: None,
@@ -60,6 +78,17 @@ mod fixtures {
         amount_per_minute_limit: Some(1000),
         packets_per_minute_limit: Some(20),
         settlement_engine_url: None,
+        spread: None,
+        ilp_over_http_fallback_url: None,
+        ilp_over_http_alternate_urls: Vec::new(),
+        translate_prefix_from: None,
+        translate_prefix_to: None,
+        packets_burst_limit: None,
+        amount_burst_limit: None,
+        accept_route_prefixes: Vec::new(),
+        reject_route_prefixes: Vec::new(),
+        max_routes: None,
+        aggregate_route_prefixes: Vec::new(),
     });
     pub static ACCOUNT_DETAILS_2: Lazy<AccountDetails> = Lazy::new(|| AccountDetails {
         ilp_address: None,
@@ -81,6 +110,17 @@ mod fixtures {
         amount_per_minute_limit: None,
         packets_per_minute_limit: None,
         settlement_engine_url: None,
+        spread: None,
+        ilp_over_http_fallback_url: None,
+        ilp_over_http_alternate_urls: Vec::new(),
+        translate_prefix_from: None,
+        translate_prefix_to: None,
+        packets_burst_limit: None,
+        amount_burst_limit: None,
+        accept_route_prefixes: Vec::new(),
+        reject_route_prefixes: Vec::new(),
+        max_routes: None,
+        aggregate_route_prefixes: Vec::new(),
     });
 }
 
@@ -0,0 +1,16 @@
+use super::store_helpers::*;
+
+use interledger_stream::StreamReplayStore;
+use std::time::Duration;
+
+#[tokio::test]
+async fn remembers_fulfilled_packets_until_they_expire() {
+    let (store, _context, _accs) = test_store().await.unwrap();
+    let ttl = Duration::from_millis(10);
+    assert!(store.record_fulfilled_packet([1; 32], ttl).await.unwrap());
+    assert!(!store.record_fulfilled_packet([1; 32], ttl).await.unwrap());
+    assert!(store.record_fulfilled_packet([2; 32], ttl).await.unwrap());
+
+    tokio::time::delay_for(Duration::from_millis(50)).await;
+    assert!(store.record_fulfilled_packet([1; 32], ttl).await.unwrap());
+}
@@ -0,0 +1,62 @@
+use super::{fixtures::*, redis_helpers::*};
+
+use interledger_api::NodeStore;
+use interledger_packet::Address;
+use interledger_router::RouterStore;
+use interledger_service::Account as AccountTrait;
+use interledger_service_util::BalanceStore;
+use interledger_store::redis::{
+    replication::{follow_replication_events, ship_replication_events},
+    RedisStoreBuilder,
+};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[tokio::test]
+async fn standby_follows_primary_changes() {
+    let context = TestContext::new();
+    let primary = RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+        .node_ilp_address(Address::from_str("example.node").unwrap())
+        .with_db_prefix("primary")
+        .connect()
+        .await
+        .unwrap();
+    let standby = RedisStoreBuilder::new(context.get_client_connection_info(), [1; 32])
+        .node_ilp_address(Address::from_str("example.node").unwrap())
+        .with_db_prefix("standby")
+        .connect()
+        .await
+        .unwrap();
+    let stream_key = "replication".to_string();
+    ship_replication_events(
+        &primary,
+        context.get_client_connection_info(),
+        stream_key.clone(),
+    )
+    .await
+    .unwrap();
+    follow_replication_events(
+        standby.clone(),
+        context.get_client_connection_info(),
+        stream_key,
+        None,
+    )
+    .await
+    .unwrap();
+
+    let account = primary
+        .insert_account(ACCOUNT_DETAILS_2.clone())
+        .await
+        .unwrap();
+    let id = account.id();
+    primary.update_balances_for_fulfill(id, 100).await.unwrap();
+    primary.set_default_route(id).await.unwrap();
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+
+    let replicated = standby.get_all_accounts().await.unwrap();
+    assert_eq!(replicated.len(), 1);
+    assert_eq!(replicated[0].id(), id);
+    assert_eq!(replicated[0].username(), account.username());
+    assert_eq!(standby.get_balance(id).await.unwrap(), 100);
+    assert_eq!(standby.routing_table()[""], id);
+}
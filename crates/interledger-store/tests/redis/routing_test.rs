@@ -52,6 +52,17 @@ async fn polls_for_route_updates() {
             amount_per_minute_limit: None,
             packets_per_minute_limit: None,
             settlement_engine_url: None,
+            spread: None,
+            ilp_over_http_fallback_url: None,
+            ilp_over_http_alternate_urls: Vec::new(),
+            translate_prefix_from: None,
+            translate_prefix_to: None,
+            packets_burst_limit: None,
+            amount_burst_limit: None,
+            accept_route_prefixes: Vec::new(),
+            reject_route_prefixes: Vec::new(),
+            max_routes: None,
+            aggregate_route_prefixes: Vec::new(),
         })
         .await
         .unwrap();
@@ -256,6 +267,33 @@ async fn default_route() {
     assert_eq!(routes.len(), 3);
 }
 
+#[tokio::test]
+async fn deletes_static_and_default_routes() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    store
+        .set_static_route("example.a".to_string(), accs[1].id())
+        .await
+        .unwrap();
+    store.set_default_route(accs[0].id()).await.unwrap();
+    assert_eq!(
+        store.get_static_routes().await.unwrap()["example.a"],
+        accs[1].id()
+    );
+    assert_eq!(store.get_default_route().await.unwrap(), Some(accs[0].id()));
+    assert_eq!(store.routing_table()["example.a"], accs[1].id());
+
+    store.delete_static_route("example.a").await.unwrap();
+    store.delete_default_route().await.unwrap();
+    assert!(store.get_static_routes().await.unwrap().is_empty());
+    assert_eq!(store.get_default_route().await.unwrap(), None);
+    assert!(!store.routing_table().contains_key("example.a"));
+    assert!(!store.routing_table().contains_key(""));
+
+    // Deleting them again succeeds, so that the deletions can be replayed
+    store.delete_static_route("example.a").await.unwrap();
+    store.delete_default_route().await.unwrap();
+}
+
 #[tokio::test]
 async fn returns_configured_routes_for_route_manager() {
     let (store, _context, accs) = test_store().await.unwrap();
@@ -1,4 +1,4 @@
-use super::store_helpers::*;
+use super::{redis_helpers::*, store_helpers::*};
 use bytes::Bytes;
 
 use http::StatusCode;
@@ -7,12 +7,17 @@ use interledger_service::{Account, AccountStore};
 use interledger_service_util::BalanceStore;
 use interledger_settlement::core::{
     idempotency::{IdempotentData, IdempotentStore},
-    types::{LeftoversStore, SettlementAccount, SettlementStore},
+    types::{
+        LeftoversStore, PendingMessage, PendingSettlement, SettlementAccount, SettlementDirection,
+        SettlementHistoryStore, SettlementQueueStore, SettlementRecord, SettlementStore,
+    },
 };
+use interledger_store::redis::RedisStoreBuilder;
 use num_bigint::BigUint;
 use once_cell::sync::Lazy;
 use redis_crate::cmd;
 use redis_crate::AsyncCommands;
+use std::time::Duration;
 use url::Url;
 use uuid::Uuid;
 
@@ -79,6 +84,70 @@ async fn saves_and_loads_idempotency_key_data_properly() {
     assert!(data2.is_none());
 }
 
+#[tokio::test]
+async fn lists_and_deletes_idempotent_data() {
+    let (store, _context, _) = test_store().await.unwrap();
+    for key in &["a", "b", "c"] {
+        store
+            .save_idempotent_data(
+                key.to_string(),
+                Default::default(),
+                StatusCode::OK,
+                Bytes::from("TEST"),
+            )
+            .await
+            .unwrap();
+    }
+    let mut keys = store.get_idempotency_keys().await.unwrap();
+    keys.sort();
+    assert_eq!(keys, vec!["a", "b", "c"]);
+
+    store
+        .delete_idempotent_data(vec!["b".to_string()])
+        .await
+        .unwrap();
+    assert!(store
+        .load_idempotent_data("b".to_string())
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(store.get_idempotency_keys().await.unwrap().len(), 2);
+    assert_eq!(store.remove_expired_idempotent_data().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn expires_idempotent_data() {
+    let context = TestContext::new();
+    // Redis expires the responses by its own clock, so this only waits for them to have
+    // expired rather than checking that they were kept until then
+    let store = RedisStoreBuilder::new(context.get_client_connection_info(), [0; 32])
+        .idempotency_key_ttl(Duration::from_millis(1))
+        .connect()
+        .await
+        .unwrap();
+    for key in &["a", "b"] {
+        store
+            .save_idempotent_data(
+                key.to_string(),
+                Default::default(),
+                StatusCode::OK,
+                Bytes::from("TEST"),
+            )
+            .await
+            .unwrap();
+    }
+
+    tokio::time::delay_for(Duration::from_millis(10)).await;
+    assert!(store.get_idempotency_keys().await.unwrap().is_empty());
+    // The responses expire natively, the expiry only cleans up the list of keys
+    assert!(store
+        .load_idempotent_data("a".to_string())
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(store.remove_expired_idempotent_data().await.unwrap(), 2);
+}
+
 #[tokio::test]
 async fn idempotent_settlement_calls() {
     let (store, _context, accs) = test_store().await.unwrap();
@@ -191,6 +260,30 @@ async fn clears_balance_owed_and_puts_remainder_as_prepaid() {
     assert_eq!(prepaid_amount, 60);
 }
 
+#[tokio::test]
+async fn credits_settlements_up_to_the_largest_balance() {
+    let (store, context, accs) = test_store().await.unwrap();
+    let id = accs[0].id();
+    let mut connection = context.shared_async_connection().await.unwrap();
+    let _balance: i64 = connection
+        .hset(format!("accounts:{}", id), "balance", 100i64)
+        .await
+        .unwrap();
+    // only as much is credited as the balance can hold, and the rest is returned
+    let uncredited = store
+        .update_balance_for_incoming_settlement(id, u64::MAX, Some(IDEMPOTENCY_KEY.clone()))
+        .await
+        .unwrap();
+    assert_eq!(uncredited, u64::MAX - (i64::MAX as u64 - 100));
+    let (balance, prepaid_amount): (i64, i64) = connection
+        .hget(format!("accounts:{}", id), &["balance", "prepaid_amount"])
+        .await
+        .unwrap();
+    assert_eq!(balance, 100);
+    assert_eq!(prepaid_amount, i64::MAX - 100);
+    assert_eq!(store.get_balance(id).await.unwrap(), i64::MAX);
+}
+
 #[tokio::test]
 async fn loads_globally_configured_settlement_engine_url() {
     let (store, _context, accs) = test_store().await.unwrap();
@@ -236,3 +329,118 @@ async fn loads_globally_configured_settlement_engine_url() {
         "http://settle-abc.example/"
     );
 }
+
+#[tokio::test]
+async fn saves_loads_and_removes_pending_settlements() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    assert!(store.load_pending_settlements().await.unwrap().is_empty());
+
+    let mut settlement = PendingSettlement {
+        id: Uuid::new_v4(),
+        account_id: accs[0].id(),
+        amount: 100,
+        attempts: 0,
+        next_attempt_at: 1_600_000_000_000,
+        last_error: None,
+    };
+    store
+        .save_pending_settlement(settlement.clone())
+        .await
+        .unwrap();
+    // Saving the settlement again replaces it
+    settlement.attempts = 1;
+    settlement.last_error = Some("engine unavailable".to_string());
+    store
+        .save_pending_settlement(settlement.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        store.load_pending_settlements().await.unwrap(),
+        vec![settlement.clone()]
+    );
+
+    store
+        .remove_pending_settlement(settlement.id)
+        .await
+        .unwrap();
+    assert!(store.load_pending_settlements().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn saves_loads_and_removes_pending_messages() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    assert!(store.load_pending_messages().await.unwrap().is_empty());
+
+    let message = PendingMessage {
+        id: Uuid::new_v4(),
+        account_id: accs[0].id(),
+        message: b"hello".to_vec(),
+        attempts: 1,
+        next_attempt_at: 1_600_000_000_000,
+        last_error: Some("engine unavailable".to_string()),
+    };
+    store.save_pending_message(message.clone()).await.unwrap();
+    assert_eq!(
+        store.load_pending_messages().await.unwrap(),
+        vec![message.clone()]
+    );
+
+    store.remove_pending_message(message.id).await.unwrap();
+    assert!(store.load_pending_messages().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn records_settlement_history() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let (alice, bob) = (accs[0].id(), accs[1].id());
+    let record = |timestamp, direction| SettlementRecord {
+        timestamp,
+        direction,
+        amount: 100,
+        id: None,
+    };
+    store
+        .record_settlement(alice, record(1000, SettlementDirection::Outgoing))
+        .await
+        .unwrap();
+    store
+        .record_settlement(alice, record(2000, SettlementDirection::Incoming))
+        .await
+        .unwrap();
+    store
+        .record_settlement(bob, record(3000, SettlementDirection::Refunded))
+        .await
+        .unwrap();
+
+    let history = store
+        .get_settlement_history(alice, 0, u64::MAX)
+        .await
+        .unwrap();
+    assert_eq!(
+        history,
+        vec![
+            record(1000, SettlementDirection::Outgoing),
+            record(2000, SettlementDirection::Incoming)
+        ]
+    );
+    let history = store
+        .get_settlement_history(alice, 1500, 2500)
+        .await
+        .unwrap();
+    assert_eq!(history, vec![record(2000, SettlementDirection::Incoming)]);
+
+    store.delete_account(alice).await.unwrap();
+    assert!(store
+        .get_settlement_history(alice, 0, u64::MAX)
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(
+        store
+            .get_settlement_history(bob, 0, u64::MAX)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
@@ -0,0 +1,92 @@
+use super::store_helpers::*;
+
+use interledger_errors::StreamReceiveStoreError;
+use interledger_service::Account;
+use interledger_stream::{ConnectionReceipts, StreamReceiveStore};
+
+#[tokio::test]
+async fn enforces_the_receive_max_of_connection_tags() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let id = accs[0].id();
+
+    // Connections without a receive max may receive any amount
+    let (receipts, added) = store
+        .add_connection_receipt(id, "invoice-1", 500)
+        .await
+        .unwrap();
+    assert!(added);
+    assert_eq!(
+        receipts,
+        ConnectionReceipts {
+            total_received: 500,
+            receive_max: None,
+        }
+    );
+
+    store
+        .set_connection_receive_max(id, "invoice-2", Some(150))
+        .await
+        .unwrap();
+    let (_, added) = store
+        .add_connection_receipt(id, "invoice-2", 100)
+        .await
+        .unwrap();
+    assert!(added);
+    let (receipts, added) = store
+        .add_connection_receipt(id, "invoice-2", 100)
+        .await
+        .unwrap();
+    assert!(!added);
+    assert_eq!(
+        receipts,
+        ConnectionReceipts {
+            total_received: 100,
+            receive_max: Some(150),
+        }
+    );
+
+    // Amounts whose packets were not fulfilled are removed
+    store
+        .remove_connection_receipt(id, "invoice-2", 100)
+        .await
+        .unwrap();
+    store
+        .set_connection_receive_max(id, "invoice-2", None)
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .get_connection_receipts(id, "invoice-2")
+            .await
+            .unwrap(),
+        ConnectionReceipts {
+            total_received: 0,
+            receive_max: None,
+        }
+    );
+}
+
+#[tokio::test]
+async fn rejects_amounts_redis_cannot_count() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let id = accs[0].id();
+    let too_large = i64::MAX as u64 + 1;
+
+    assert!(matches!(
+        store.add_connection_receipt(id, "invoice", too_large).await,
+        Err(StreamReceiveStoreError::AmountTooLarge(amount)) if amount == too_large
+    ));
+    assert!(matches!(
+        store
+            .set_connection_receive_max(id, "invoice", Some(too_large))
+            .await,
+        Err(StreamReceiveStoreError::AmountTooLarge(_))
+    ));
+    assert_eq!(
+        store.get_connection_receipts(id, "invoice").await.unwrap(),
+        ConnectionReceipts {
+            total_received: 0,
+            receive_max: None,
+        }
+    );
+}
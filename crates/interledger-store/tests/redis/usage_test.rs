@@ -0,0 +1,63 @@
+use super::store_helpers::*;
+
+use interledger_service::Account as AccountTrait;
+use interledger_service_util::{UsagePeriod, UsageStore};
+
+#[tokio::test]
+async fn records_usage_for_both_accounts() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let (alice, bob) = (accs[0].id(), accs[1].id());
+    store.record_usage(alice, 100, bob, 200).await.unwrap();
+    store.record_usage(alice, 50, bob, 100).await.unwrap();
+
+    for period in UsagePeriod::ALL.iter() {
+        let usage = store.get_usage(alice, *period).await.unwrap();
+        assert_eq!(usage.period, period.current_period_id());
+        assert_eq!(usage.packets_sent, 2);
+        assert_eq!(usage.amount_sent, 150);
+        assert_eq!(usage.packets_received, 0);
+        assert_eq!(usage.amount_received, 0);
+
+        let usage = store.get_usage(bob, *period).await.unwrap();
+        assert_eq!(usage.packets_sent, 0);
+        assert_eq!(usage.amount_sent, 0);
+        assert_eq!(usage.packets_received, 2);
+        assert_eq!(usage.amount_received, 300);
+    }
+}
+
+#[tokio::test]
+async fn resets_usage_for_one_period() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let (alice, bob) = (accs[0].id(), accs[1].id());
+    store.record_usage(alice, 100, bob, 200).await.unwrap();
+
+    store.reset_usage(alice, UsagePeriod::Daily).await.unwrap();
+    let daily = store.get_usage(alice, UsagePeriod::Daily).await.unwrap();
+    assert_eq!(daily.packets_sent, 0);
+    assert_eq!(daily.amount_sent, 0);
+    let monthly = store.get_usage(alice, UsagePeriod::Monthly).await.unwrap();
+    assert_eq!(monthly.packets_sent, 1);
+    assert_eq!(monthly.amount_sent, 100);
+}
+
+#[tokio::test]
+async fn saturates_amounts_beyond_i64() {
+    let (store, _context, accs) = test_store().await.unwrap();
+    let (alice, bob) = (accs[0].id(), accs[1].id());
+    store
+        .record_usage(alice, u64::MAX, bob, i64::MAX as u64)
+        .await
+        .unwrap();
+    store.record_usage(alice, 1, bob, 1).await.unwrap();
+
+    for period in UsagePeriod::ALL.iter() {
+        let usage = store.get_usage(alice, *period).await.unwrap();
+        assert_eq!(usage.packets_sent, 2);
+        assert_eq!(usage.amount_sent, u64::MAX);
+
+        let usage = store.get_usage(bob, *period).await.unwrap();
+        assert_eq!(usage.packets_received, 2);
+        assert_eq!(usage.amount_received, i64::MAX as u64 + 1);
+    }
+}
@@ -0,0 +1,173 @@
+//! Benchmark STREAM packet encryption and decryption, comparing the reusable encoder and
+//! decoder to building and parsing each packet in new buffers.
+//!
+//! Run with `cargo bench --features benchmarks`. The number of allocations made per packet
+//! by each approach is printed before the timings.
+
+use bytes::BytesMut;
+use criterion::{criterion_group, Criterion};
+use interledger_packet::{Address, PacketType, PrepareBuilder};
+use interledger_stream::bench::{
+    Frame, StreamMoneyFrame, StreamPacket, StreamPacketBuilder, StreamPacketDecoder,
+    StreamPacketEncoder,
+};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Counts the allocations (and reallocations) made by the benchmarks
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const SHARED_SECRET: &[u8] = &[7; 32];
+
+const FRAMES: &[Frame<'static>] = &[
+    Frame::StreamMoney(StreamMoneyFrame {
+        stream_id: 1,
+        shares: 1,
+    }),
+    Frame::StreamMoney(StreamMoneyFrame {
+        stream_id: 2,
+        shares: 3,
+    }),
+];
+
+fn packet() -> StreamPacketBuilder<'static> {
+    StreamPacketBuilder {
+        sequence: 1,
+        ilp_packet_type: PacketType::Prepare,
+        prepare_amount: 99,
+        frames: FRAMES,
+    }
+}
+
+fn prepare<'a>(destination: &Address, data: &'a [u8]) -> PrepareBuilder<'a> {
+    PrepareBuilder {
+        destination: destination.clone(),
+        amount: 100,
+        execution_condition: &[0; 32],
+        expires_at: UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+        data,
+    }
+}
+
+/// Average number of allocations made by each call of `f`, once warmed up
+fn allocations_per_call<F: FnMut()>(mut f: F) -> f64 {
+    const CALLS: usize = 1000;
+    f();
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..CALLS {
+        f();
+    }
+    (ALLOCATIONS.load(Ordering::Relaxed) - before) as f64 / CALLS as f64
+}
+
+fn print_allocations_per_packet() {
+    let destination = Address::from_str("example.receiver").unwrap();
+    let ciphertext = packet().build().into_encrypted(SHARED_SECRET);
+
+    let build = allocations_per_call(|| {
+        let data = packet().build().into_encrypted(SHARED_SECRET);
+        prepare(&destination, &data).build();
+    });
+    let mut encoder = StreamPacketEncoder::default();
+    let encode = allocations_per_call(|| {
+        encoder.encode_prepare(&packet(), SHARED_SECRET, &prepare(&destination, &[]));
+    });
+    let parse = allocations_per_call(|| {
+        StreamPacket::from_encrypted(SHARED_SECRET, BytesMut::from(&ciphertext[..])).unwrap();
+    });
+    let mut decoder = StreamPacketDecoder::default();
+    let decode = allocations_per_call(|| {
+        decoder.decode(SHARED_SECRET, &ciphertext).unwrap();
+    });
+
+    println!("Allocations per packet:");
+    println!("  build + encrypt + Prepare:  {:.1}", build);
+    println!("  StreamPacketEncoder:        {:.1}", encode);
+    println!("  from_encrypted:             {:.1}", parse);
+    println!("  StreamPacketDecoder:        {:.1}", decode);
+}
+
+fn benchmark_encrypt(c: &mut Criterion) {
+    let destination = Address::from_str("example.receiver").unwrap();
+    c.bench_function("STREAM Prepare (build + encrypt)", |b| {
+        b.iter(|| {
+            let data = packet().build().into_encrypted(SHARED_SECRET);
+            prepare(&destination, &data).build()
+        });
+    });
+
+    let mut encoder = StreamPacketEncoder::default();
+    c.bench_function("STREAM Prepare (encoder)", |b| {
+        b.iter(|| encoder.encode_prepare(&packet(), SHARED_SECRET, &prepare(&destination, &[])));
+    });
+}
+
+fn benchmark_decrypt(c: &mut Criterion) {
+    let ciphertext = packet().build().into_encrypted(SHARED_SECRET);
+    c.bench_function("STREAM packet (from_encrypted)", |b| {
+        b.iter(|| {
+            let packet =
+                StreamPacket::from_encrypted(SHARED_SECRET, BytesMut::from(&ciphertext[..]))
+                    .unwrap();
+            assert_eq!(packet.frames().count(), FRAMES.len());
+        });
+    });
+
+    let mut decoder = StreamPacketDecoder::default();
+    c.bench_function("STREAM packet (decoder)", |b| {
+        b.iter(|| {
+            let packet = decoder.decode(SHARED_SECRET, &ciphertext).unwrap();
+            assert_eq!(packet.frames().count(), FRAMES.len());
+        });
+    });
+}
+
+fn benchmark_round_trip(c: &mut Criterion) {
+    let destination = Address::from_str("example.receiver").unwrap();
+    let mut encoder = StreamPacketEncoder::default();
+    let mut decoder = StreamPacketDecoder::default();
+    c.bench_function("STREAM packet round trip", |b| {
+        b.iter(|| {
+            let ilp_prepare =
+                encoder.encode_prepare(&packet(), SHARED_SECRET, &prepare(&destination, &[]));
+            let packet = decoder.decode(SHARED_SECRET, ilp_prepare.data()).unwrap();
+            assert_eq!(packet.sequence(), 1);
+        });
+    });
+}
+
+criterion_group!(
+    benches,
+    benchmark_encrypt,
+    benchmark_decrypt,
+    benchmark_round_trip
+);
+
+fn main() {
+    print_allocations_per_packet();
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+}
@@ -2,8 +2,8 @@ use super::congestion::CongestionController;
 use super::crypto::*;
 use super::error::Error;
 use super::packet::*;
+use super::probe::PathStats;
 use bytes::Bytes;
-use bytes::BytesMut;
 use futures::stream::{FuturesUnordered, StreamExt};
 use interledger_packet::{
     Address, ErrorClass, ErrorCode as IlpErrorCode, PacketType as IlpPacketType, PrepareBuilder,
@@ -19,7 +19,7 @@ use num::traits::pow::pow;
 use num::BigInt;
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
-use tokio::time::timeout_at;
+use tokio::time::{delay_until, timeout_at};
 use tokio::time::{Duration, Instant};
 use tracing::{debug, error, warn};
 
@@ -39,6 +39,93 @@ const FAIL_FAST_MINIMUM_PACKET_ATTEMPTS: u64 = 200;
 /// Minimum rate of rejected packets in order to terminate the payment
 const FAIL_FAST_MINIMUM_FAILURE_RATE: f64 = 0.99;
 
+/// A logical money stream of a STREAM payment, which receives its `shares`
+/// out of the total shares of the amount delivered by each packet
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+pub struct MoneyStream {
+    /// Id of the stream. Streams opened by the sender must have odd ids
+    pub stream_id: u64,
+    /// Relative share of each packet's amount the stream receives
+    pub shares: u64,
+}
+
+/// Checks that the streams are valid to open from the sending side of a connection
+fn validate_streams(streams: &[MoneyStream]) -> Result<(), Error> {
+    if streams.is_empty() {
+        return Err(Error::InvalidStreams("at least one stream is required"));
+    }
+    for (i, stream) in streams.iter().enumerate() {
+        if stream.stream_id % 2 == 0 {
+            return Err(Error::InvalidStreams(
+                "streams opened by the sender must have odd ids",
+            ));
+        }
+        if stream.shares == 0 {
+            return Err(Error::InvalidStreams("every stream must have some shares"));
+        }
+        if streams[..i]
+            .iter()
+            .any(|other| other.stream_id == stream.stream_id)
+        {
+            return Err(Error::InvalidStreams("stream ids must be unique"));
+        }
+    }
+    Ok(())
+}
+
+/// Unexpected behavior of the receiver which did not prevent the payment from completing
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub enum StreamWarning {
+    /// The receiver sent asset details which contradict the ones it sent first.
+    /// [The RFC](https://github.com/interledger/rfcs/pull/551) forbids changing them,
+    /// so the first ones are still used to compute the minimum destination amounts
+    AssetDetailsChanged { asset_code: String, asset_scale: u8 },
+}
+
+/// Options of a STREAM payment beyond its destination and its amount
+#[derive(Debug, Clone)]
+pub struct SendMoneyOptions {
+    /// Maximum acceptable slippage from the exchange rate of the store, as a fraction
+    /// (`0.01` accepts 1% less than the expected destination amount)
+    pub slippage: f64,
+    /// Logical streams the amount of every packet is split between, in proportion to
+    /// their shares, as specified in [the RFC](https://interledger.org/rfcs/0029-stream/#53-frames).
+    /// The receipt accounts for the total amount across all of the streams.
+    /// Defaults to a single stream with id 1
+    pub streams: Vec<MoneyStream>,
+    /// How to pad the connection's STREAM packets, if at all
+    pub padding: Option<StreamPadding>,
+    /// Whether to terminate the payment if the receiver changes its asset details,
+    /// rather than only adding a warning to the receipt
+    pub abort_on_asset_details_change: bool,
+    /// Amount to deliver to the receiver, in its units. If set, the exchange rate is probed
+    /// first and the payment completes as soon as the receiver reported this amount as
+    /// delivered (overshooting it by at most one packet's worth), so the source amount is
+    /// only the most which may be sent
+    pub destination_amount: Option<u64>,
+    /// The exchange rate and maximum packet amount of the path, if it was probed beforehand
+    /// with [`rate_probe`](./fn.rate_probe.html). The first packets are then sized to the
+    /// maximum packet amount, and the probed rate is used to enforce the minimum destination
+    /// amounts if the store has no rate for the receiver's asset
+    pub path_stats: Option<PathStats>,
+}
+
+impl Default for SendMoneyOptions {
+    fn default() -> Self {
+        SendMoneyOptions {
+            slippage: 0.0,
+            streams: vec![MoneyStream {
+                stream_id: 1,
+                shares: 1,
+            }],
+            padding: None,
+            abort_on_asset_details_change: false,
+            destination_amount: None,
+            path_stats: None,
+        }
+    }
+}
+
 /// Receipt for STREAM payment to account for how much and what assets were sent & delivered
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct StreamDelivery {
@@ -64,6 +151,9 @@ pub struct StreamDelivery {
     /// Receiver's asset code
     /// Updated after we received a `ConnectionAssetDetails` frame.
     pub destination_asset_code: Option<String>,
+    /// Unexpected behavior of the receiver during the payment
+    #[serde(default)]
+    pub warnings: Vec<StreamWarning>,
 }
 
 impl StreamDelivery {
@@ -79,12 +169,29 @@ impl StreamDelivery {
             destination_asset_scale: None,
             destination_asset_code: None,
             delivered_amount: 0,
+            warnings: Vec::new(),
         }
     }
+
+    /// The exchange rate realized by the fulfilled packets so far, i.e. how many units of the
+    /// receiver's asset were delivered per unit of the sender's asset (after applying both
+    /// asset scales).
+    ///
+    /// Returns `None` until the receiver advised us of its asset and a packet was fulfilled.
+    pub fn realized_exchange_rate(&self) -> Option<f64> {
+        let destination_asset_scale = self.destination_asset_scale?;
+        let fulfilled_amount = self.sent_amount.saturating_sub(self.in_flight_amount);
+        if fulfilled_amount == 0 {
+            return None;
+        }
+        let delivered = self.delivered_amount as f64 / 10f64.powi(destination_asset_scale.into());
+        let sent = fulfilled_amount as f64 / 10f64.powi(self.source_asset_scale.into());
+        Some(delivered / sent)
+    }
 }
 
 /// Stream payment mutable state: amounts & assets sent and received, sequence, packet counts, and flow control parameters
-struct StreamPayment {
+pub(crate) struct StreamPayment {
     /// The [congestion controller](./../congestion/struct.CongestionController.html) to adjust flow control and the in-flight amount
     congestion_controller: CongestionController,
     /// The [StreamDelivery](./struct.StreamDelivery.html) receipt to account for the delivered amounts
@@ -101,9 +208,70 @@ struct StreamPayment {
     fail_fast_rejects: u64,
     /// Timestamp when a packet was last fulfilled for this payment
     last_fulfill_time: Instant,
+    /// Reusable buffers to serialize and encrypt the STREAM packets into the Prepares
+    encoder: StreamPacketEncoder,
+    /// Reusable buffer to decrypt the STREAM packets of the replies into
+    decoder: StreamPacketDecoder,
+    /// Amount to deliver to the receiver, in destination units, if it is fixed
+    target_delivered_amount: Option<u64>,
+    /// Destination units received per source unit by a probe packet, if any
+    probed_rate: Option<BigRational>,
+    /// Is an unfulfillable packet probing the exchange rate in-flight?
+    probe_in_flight: bool,
 }
 
 impl StreamPayment {
+    /// Payment state of a new connection, whose congestion controller starts by sending
+    /// `initial_window` source units at once (unless a probe found a lower limit)
+    pub(crate) fn new<A: Account>(
+        from_account: &A,
+        destination_account: Address,
+        source_amount: u64,
+        initial_window: u64,
+        options: SendMoneyOptions,
+    ) -> Self {
+        let path_stats = options.path_stats;
+        StreamPayment {
+            congestion_controller: CongestionController::new(
+                initial_window,
+                initial_window / 10,
+                2.0,
+            )
+            .with_max_packet_amount(
+                path_stats
+                    .as_ref()
+                    .and_then(|stats| stats.max_packet_amount),
+            )
+            .with_rtt(path_stats.as_ref().map(|stats| stats.rtt)),
+            receipt: StreamDelivery::new(from_account, destination_account, source_amount),
+            should_send_source_account: true,
+            sequence: 1,
+            fulfilled_packets: 0,
+            rejected_packets: 0,
+            fail_fast_rejects: 0,
+            last_fulfill_time: Instant::now(),
+            encoder: StreamPacketEncoder::default().with_padding(options.padding),
+            decoder: StreamPacketDecoder::default(),
+            target_delivered_amount: options.destination_amount,
+            probed_rate: path_stats
+                .and_then(|stats| BigRational::from_f64(stats.rate))
+                .filter(|rate| !rate.is_zero()),
+            probe_in_flight: false,
+        }
+    }
+
+    /// Raise the source amount of the payment, when streaming money at a rate. Since the
+    /// connection may have been idle until now, the time to get a fulfill starts over
+    pub(crate) fn add_source_amount(&mut self, amount: u64) {
+        self.receipt.source_amount = self.receipt.source_amount.saturating_add(amount);
+        self.last_fulfill_time = Instant::now();
+    }
+
+    /// The receipt of the payment so far
+    pub(crate) fn receipt(&self) -> &StreamDelivery {
+        &self.receipt
+    }
+
     /// Determine amount to load in next Prepare and account for it.
     /// Return the source packet amount and minimum destination amount
     #[inline]
@@ -119,6 +287,7 @@ impl StreamPayment {
             self.receipt.destination_asset_code.as_deref(),
             slippage,
         )
+        .or_else(|| self.get_probed_min_rate(slippage))
         .unwrap_or_else(BigRational::zero);
 
         // Margin of error is the minimum difference between our scaled rate and scaled rate of intermediaries.
@@ -144,6 +313,20 @@ impl StreamPayment {
         // (5) Amount left in window for congestion
         let mut source_amount = self.congestion_controller.get_amount_left_in_window();
 
+        // (4b) When delivering a fixed amount, the source amount needed to deliver the rest of it
+        let delivery_rate = self.get_delivery_rate();
+        if let Some(ref delivery_rate) = delivery_rate {
+            let remaining_destination_amount = self.get_remaining_destination_amount(delivery_rate);
+            let needed_source_amount =
+                (BigRational::from_integer(BigInt::from(remaining_destination_amount))
+                    / delivery_rate)
+                    .ceil()
+                    .to_integer()
+                    .to_u64()
+                    .unwrap_or(std::u64::MAX);
+            source_amount = min(source_amount, needed_source_amount);
+        }
+
         // (4) Min source amount so rounding errors don't prevent delivery
         source_amount = max(source_amount, min_source_amount);
 
@@ -154,12 +337,13 @@ impl StreamPayment {
         // If the final packet amount might be dust (less than or close in value to min packet amount),
         // distribute the final dust amount across the other remaining packets.
         // Note: If the max packet amount < min source amount, the payment may fail due to rates anyways.
+        // This does not apply when delivering a fixed amount, since the source amount is a maximum.
         let remaining_amount = self.get_amount_available_to_send();
         let estimated_num_packets = remaining_amount / source_amount;
         let estimated_final_amount = remaining_amount % source_amount;
         let possible_dust = estimated_num_packets > 0
             && estimated_final_amount < (min_source_amount as f64 * 1.2).ceil() as u64;
-        if possible_dust {
+        if possible_dust && self.target_delivered_amount.is_none() {
             // i.e. ceil(remaining_amount / estimated_num_packets)
             source_amount =
                 (remaining_amount + (estimated_num_packets - 1)) / estimated_num_packets;
@@ -179,11 +363,72 @@ impl StreamPayment {
         self.receipt.sent_amount = self.receipt.sent_amount.saturating_add(source_amount);
         self.receipt.in_flight_amount = self.receipt.in_flight_amount.saturating_add(source_amount);
 
+        // Before the exchange rate is known, a fixed amount delivery probes it with an
+        // unfulfillable packet, which the receiver rejects reporting the amount which arrived
+        if self.target_delivered_amount.is_some() && delivery_rate.is_none() {
+            self.probe_in_flight = true;
+            return (source_amount, 0);
+        }
+
         // Compute the minimum destination amount using the same rate
         let min_destination_amount = convert(source_amount, rate).unwrap_or(0);
         (source_amount, min_destination_amount)
     }
 
+    /// Destination units delivered per source unit by the fulfilled packets, or by the probe
+    /// packet if none was fulfilled yet. Returns `None` unless a fixed amount is delivered.
+    fn get_delivery_rate(&self) -> Option<BigRational> {
+        self.target_delivered_amount?;
+        let fulfilled_amount = self.get_fulfilled_amount();
+        let rate = if fulfilled_amount > 0 && self.receipt.delivered_amount > 0 {
+            BigRational::new(
+                BigInt::from(self.receipt.delivered_amount),
+                BigInt::from(fulfilled_amount),
+            )
+        } else {
+            self.probed_rate.clone()?
+        };
+        Some(rate)
+    }
+
+    /// The probed rate minus the slippage, used as the minimum rate if the store has no rate
+    /// for the receiver's asset
+    fn get_probed_min_rate(&self, slippage: f64) -> Option<BigRational> {
+        let slippage = BigRational::from_f64(slippage)?;
+        Some(self.probed_rate.clone()? * (BigRational::one() - slippage))
+    }
+
+    /// Account for the amount which arrived at the receiver for the probe packet of a fixed
+    /// amount delivery
+    fn apply_probe(&mut self, source_amount: u64, arrived_amount: u64) {
+        self.probe_in_flight = false;
+        if source_amount > 0 && arrived_amount > 0 {
+            debug!(
+                "Probed exchange rate: {} source units delivered {} destination units",
+                source_amount, arrived_amount
+            );
+            self.probed_rate = Some(BigRational::new(
+                BigInt::from(arrived_amount),
+                BigInt::from(source_amount),
+            ));
+        }
+    }
+
+    /// Destination amount which is neither delivered nor expected to be delivered by the
+    /// in-flight packets at the given rate, when delivering a fixed amount
+    fn get_remaining_destination_amount(&self, rate: &BigRational) -> u64 {
+        let in_flight_destination_amount =
+            (BigRational::from_integer(BigInt::from(self.receipt.in_flight_amount)) * rate)
+                .floor()
+                .to_integer()
+                .to_u64()
+                .unwrap_or(std::u64::MAX);
+        self.target_delivered_amount
+            .unwrap_or(0)
+            .saturating_sub(self.receipt.delivered_amount)
+            .saturating_sub(in_flight_destination_amount)
+    }
+
     /// Account for a fulfilled packet and update flow control
     #[inline]
     fn apply_fulfill(&mut self, source_amount: u64, destination_amount: u64) {
@@ -222,6 +467,37 @@ impl StreamPayment {
         }
     }
 
+    /// Apply the asset details sent by the recipient, keeping the first ones and
+    /// adding a warning to the receipt if they change
+    fn apply_asset_details(&mut self, asset_code: String, asset_scale: u8) {
+        match (
+            self.receipt.destination_asset_code.as_deref(),
+            self.receipt.destination_asset_scale,
+        ) {
+            (Some(code), Some(scale)) if code == asset_code && scale == asset_scale => {}
+            (Some(code), Some(scale)) => {
+                let warning = StreamWarning::AssetDetailsChanged {
+                    asset_code,
+                    asset_scale,
+                };
+                if !self.receipt.warnings.contains(&warning) {
+                    warn!(
+                        "Receiver changed its asset details from {} with scale {} to {:?}, keeping the former",
+                        code, scale, warning
+                    );
+                    self.receipt.warnings.push(warning);
+                }
+            }
+            _ => {
+                debug!(
+                    "Setting remote asset details ({} with scale {})",
+                    asset_code, asset_scale
+                );
+                self.set_destination_asset_details(asset_code, asset_scale);
+            }
+        }
+    }
+
     /// Save the recipient's destination asset details for calculating minimum exchange rates
     #[inline]
     fn set_destination_asset_details(&mut self, asset_code: String, asset_scale: u8) {
@@ -254,9 +530,22 @@ impl StreamPayment {
     }
 
     /// Has the entire intended source amount been fulfilled by the recipient?
+    /// When delivering a fixed amount, has the receiver reported delivering it?
     #[inline]
     fn is_complete(&self) -> bool {
-        self.get_remaining_amount() == 0
+        match self.target_delivered_amount {
+            Some(target) => self.receipt.delivered_amount >= target,
+            None => self.get_remaining_amount() == 0,
+        }
+    }
+
+    /// When delivering a fixed amount, was the maximum source amount fulfilled without
+    /// delivering it?
+    #[inline]
+    fn is_source_amount_exhausted(&self) -> bool {
+        self.target_delivered_amount.is_some()
+            && !self.is_complete()
+            && self.get_remaining_amount() == 0
     }
 
     /// Return the amount of money available to be sent in the payment (amount remaining minus in-flight)
@@ -275,6 +564,11 @@ impl StreamPayment {
     fn is_max_in_flight(&self) -> bool {
         self.congestion_controller.get_amount_left_in_window() == 0
             || self.get_amount_available_to_send() == 0
+            || self.probe_in_flight
+            || self
+                .get_delivery_rate()
+                .map(|rate| self.get_remaining_destination_amount(&rate) == 0)
+                .unwrap_or(false)
     }
 
     /// Given we've attempted sending enough packets, does the rate of rejects
@@ -303,6 +597,39 @@ where
     A: Account + Send + Sync + 'static,
     S: ExchangeRateStore + Send + Sync + 'static,
 {
+    send_money_with_options(
+        service,
+        from_account,
+        store,
+        destination_account,
+        shared_secret,
+        source_amount,
+        SendMoneyOptions {
+            slippage,
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+/// Same as [`send_money`](./fn.send_money.html), with all of the
+/// [options](./struct.SendMoneyOptions.html) of the payment, such as the streams to split
+/// it between or the padding of its packets
+pub async fn send_money_with_options<I, A, S>(
+    service: I,
+    from_account: &A,
+    store: S,
+    destination_account: Address,
+    shared_secret: Vec<u8>,
+    source_amount: u64,
+    options: SendMoneyOptions,
+) -> Result<StreamDelivery, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
+    validate_streams(&options.streams)?;
     let shared_secret = Bytes::from(shared_secret);
 
     let from = from_account.ilp_address();
@@ -314,29 +641,50 @@ where
         );
     }
 
-    let mut sender = StreamSender {
-        next: service,
-        from_account: from_account.clone(),
+    let abort_on_asset_details_change = options.abort_on_asset_details_change;
+    let mut sender = StreamSender::new(
+        service,
+        from_account.clone(),
         shared_secret,
         store,
-        slippage,
-        payment: Arc::new(Mutex::new(StreamPayment {
+        options.slippage,
+        options.streams.clone(),
+        StreamPayment::new(
+            from_account,
+            destination_account,
+            source_amount,
             // TODO Make configurable to get money flowing ASAP vs as much as possible per-packet
-            congestion_controller: CongestionController::new(
-                source_amount,
-                source_amount / 10,
-                2.0,
-            ),
-            receipt: StreamDelivery::new(from_account, destination_account, source_amount),
-            should_send_source_account: true,
-            sequence: 1,
-            fulfilled_packets: 0,
-            rejected_packets: 0,
-            fail_fast_rejects: 0,
-            last_fulfill_time: Instant::now(),
-        })),
-    };
+            source_amount,
+            options,
+        ),
+    );
+
+    send_until_complete(&mut sender, abort_on_asset_details_change).await?;
+
+    // Try to the tell the recipient the connection is closed
+    sender.try_send_connection_close().await;
+
+    // Return final receipt
+    let payment = sender.payment.lock().await;
+    debug!(
+        "Send money future finished. Delivered: {} ({} packets fulfilled, {} packets rejected)",
+        payment.receipt.delivered_amount, payment.fulfilled_packets, payment.rejected_packets,
+    );
+    Ok(payment.receipt.clone())
+}
 
+/// Send packets until the whole source amount of the payment is fulfilled (or, when
+/// delivering a fixed amount, the receiver reported delivering it), and wait for the rest
+/// of the packets in flight. The connection is left open
+pub(crate) async fn send_until_complete<I, A, S>(
+    sender: &mut StreamSender<I, A, S>,
+    abort_on_asset_details_change: bool,
+) -> Result<(), Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
     let mut pending_requests = FuturesUnordered::new();
 
     /// Actions corresponding to the state of the payment
@@ -345,12 +693,18 @@ where
         SendMoney((u64, u64)),
         /// Congestion controller limited in-flight amount: wait for pending requests until given deadline
         MaxInFlight(Instant),
-        /// Sent full source amount: close the connection and return success
-        CloseConnection,
+        /// Congestion controller paces the packets: wait until the given time to send the next one
+        Pacing(Instant),
+        /// Sent full source amount: wait for the pending requests and return success
+        Complete,
         /// Maximum timeout since last fulfill has elapsed: terminate the payment
         Timeout,
         /// Too many packets are rejected, such as if the exchange rate is too low: terminate the payment
         FailFast,
+        /// The receiver changed its asset details and the payment must be aborted
+        AssetDetailsChanged(StreamWarning),
+        /// The maximum source amount was sent without delivering the fixed destination amount
+        SourceAmountExhausted,
     }
 
     loop {
@@ -361,14 +715,23 @@ where
                 PaymentEvent::Timeout
             } else if payment.is_failing() {
                 PaymentEvent::FailFast
+            } else if let (true, Some(warning)) = (
+                abort_on_asset_details_change,
+                payment.receipt.warnings.first(),
+            ) {
+                PaymentEvent::AssetDetailsChanged(warning.clone())
             } else if payment.is_complete() {
-                PaymentEvent::CloseConnection
+                PaymentEvent::Complete
+            } else if payment.is_source_amount_exhausted() {
+                PaymentEvent::SourceAmountExhausted
             } else if payment.is_max_in_flight() {
                 let deadline = payment
                     .last_fulfill_time
                     .checked_add(MAX_TIME_SINCE_LAST_FULFILL)
                     .unwrap();
                 PaymentEvent::MaxInFlight(deadline)
+            } else if let Some(send_time) = payment.congestion_controller.get_next_send_time() {
+                PaymentEvent::Pacing(send_time)
             } else {
                 PaymentEvent::SendMoney(payment.apply_prepare(&sender.store, sender.slippage))
             }
@@ -391,22 +754,21 @@ where
                     return Err(error);
                 }
             }
-            PaymentEvent::CloseConnection => {
-                // Wait for all pending requests to complete before closing the connection
+            PaymentEvent::Pacing(send_time) => {
+                // Handle the requests which complete in the meantime, since they may fail the payment
+                if pending_requests.is_empty() {
+                    delay_until(send_time).await;
+                } else if let Ok(Ok(Err(error))) =
+                    timeout_at(send_time, pending_requests.select_next_some()).await
+                {
+                    error!("Send money stopped because of error: {:?}", error);
+                    return Err(error);
+                }
+            }
+            PaymentEvent::Complete => {
+                // Wait for all pending requests to complete before the connection is closed
                 pending_requests.map(|_| ()).collect::<()>().await;
-
-                // Try to the tell the recipient the connection is closed
-                sender.try_send_connection_close().await;
-
-                // Return final receipt
-                let payment = sender.payment.lock().await;
-                debug!(
-                    "Send money future finished. Delivered: {} ({} packets fulfilled, {} packets rejected)",
-                    payment.receipt.delivered_amount,
-                    payment.fulfilled_packets,
-                    payment.rejected_packets,
-                );
-                return Ok(payment.receipt.clone());
+                return Ok(());
             }
             PaymentEvent::Timeout => {
                 // Error if we haven't received a fulfill over a timeout period
@@ -419,13 +781,28 @@ where
                     payment.rejected_packets,
                 ));
             }
+            PaymentEvent::AssetDetailsChanged(StreamWarning::AssetDetailsChanged {
+                asset_code,
+                asset_scale,
+            }) => {
+                return Err(Error::AssetDetailsChanged(asset_code, asset_scale));
+            }
+            PaymentEvent::SourceAmountExhausted => {
+                pending_requests.map(|_| ()).collect::<()>().await;
+                sender.try_send_connection_close().await;
+                let payment = sender.payment.lock().await;
+                return Err(Error::DestinationAmountNotDelivered(
+                    payment.receipt.delivered_amount,
+                    payment.target_delivered_amount.unwrap_or(0),
+                ));
+            }
         }
     }
 }
 
 /// Sends and handles all ILP & STREAM packets, encapsulating all payment state
 #[derive(Clone)]
-struct StreamSender<I, A, S> {
+pub(crate) struct StreamSender<I, A, S> {
     /// Next service to send and forward Interledger packets to the network
     next: I,
     /// The account sending the STREAM payment
@@ -436,6 +813,8 @@ struct StreamSender<I, A, S> {
     store: S,
     /// Maximum acceptable slippage percentage below calculated minimum exchange rate
     slippage: f64,
+    /// Logical streams each packet's amount is split between
+    streams: Arc<Vec<MoneyStream>>,
     /// Mutable payment state
     payment: Arc<Mutex<StreamPayment>>,
 }
@@ -446,6 +825,31 @@ where
     A: Account,
     S: ExchangeRateStore,
 {
+    pub(crate) fn new(
+        next: I,
+        from_account: A,
+        shared_secret: Bytes,
+        store: S,
+        slippage: f64,
+        streams: Vec<MoneyStream>,
+        payment: StreamPayment,
+    ) -> Self {
+        StreamSender {
+            next,
+            from_account,
+            shared_secret,
+            store,
+            slippage,
+            streams: Arc::new(streams),
+            payment: Arc::new(Mutex::new(payment)),
+        }
+    }
+
+    /// The mutable payment state
+    pub(crate) fn payment(&self) -> &Mutex<StreamPayment> {
+        &self.payment
+    }
+
     /// Send a Prepare for the given source amount and apply the resulting Fulfill or Reject
     #[inline]
     pub async fn send_money_packet(
@@ -458,53 +862,66 @@ where
 
             // Build the STREAM packet
             let sequence = payment.next_sequence();
-            let mut frames = vec![Frame::StreamMoney(StreamMoneyFrame {
-                stream_id: 1,
-                shares: 1,
-            })];
+            let source_asset_code = payment.receipt.source_asset_code.clone();
+            let mut frames: Vec<Frame> = self
+                .streams
+                .iter()
+                .map(|stream| {
+                    Frame::StreamMoney(StreamMoneyFrame {
+                        stream_id: stream.stream_id,
+                        shares: stream.shares,
+                    })
+                })
+                .collect();
+            // Also announce our asset, so that a receiver with another asset knows to
+            // advise us of its own
             if payment.should_send_source_account {
                 frames.push(Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
                     source_account: payment.receipt.from.clone(),
                 }));
+                frames.push(Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                    source_asset_code: &source_asset_code,
+                    source_asset_scale: payment.receipt.source_asset_scale,
+                }));
             }
-            let stream_request_packet = StreamPacketBuilder {
+            debug!(
+                "Sending packet {} with amount: {} and STREAM frames: {:?}",
+                sequence, source_amount, frames
+            );
+
+            // Encrypt the STREAM packet directly into the Prepare
+            let packet = StreamPacketBuilder {
                 ilp_packet_type: IlpPacketType::Prepare,
                 prepare_amount: min_destination_amount,
                 sequence,
                 frames: &frames,
-            }
-            .build();
-
-            debug!(
-                "Sending packet {} with amount: {} and encrypted STREAM packet: {:?}",
-                sequence, source_amount, stream_request_packet
-            );
-
-            let prepare_data = stream_request_packet.into_encrypted(&self.shared_secret);
+            };
+            let prepare_builder = PrepareBuilder {
+                destination: payment.receipt.to.clone(),
+                amount: source_amount,
+                execution_condition: &[0; 32],
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: &[],
+            };
+            let mut prepare =
+                payment
+                    .encoder
+                    .encode_prepare(&packet, &self.shared_secret, &prepare_builder);
 
             // If we couldn't calculate a minimum destination amount (e.g. don't know asset details yet),
             // packet MUST be unfulfillable so no money is at risk
             let execution_condition = if min_destination_amount > 0 {
-                generate_condition(&self.shared_secret, &prepare_data)
+                generate_condition(&self.shared_secret, prepare.data())
             } else {
                 random_condition()
             };
-
-            // Build the Prepare
-            let prepare = PrepareBuilder {
-                destination: payment.receipt.to.clone(),
-                amount: source_amount,
-                execution_condition: &execution_condition,
-                expires_at: SystemTime::now() + Duration::from_secs(30),
-                // TODO Don't copy the data
-                data: &prepare_data[..],
-            }
-            .build();
+            prepare.set_execution_condition(&execution_condition);
 
             (prepare, sequence)
         };
 
         // Send it!
+        let sent_at = Instant::now();
         let reply = self
             .next
             .handle_request(IncomingRequest {
@@ -518,11 +935,10 @@ where
             Err(reject) => (IlpPacketType::Reject, reject.data()),
         };
 
-        let stream_reply_packet =
-            StreamPacket::from_encrypted(&self.shared_secret, BytesMut::from(reply_data));
-
         let mut payment = self.payment.lock().await;
 
+        let stream_reply_packet = payment.decoder.decode(&self.shared_secret, reply_data);
+
         // Parse the stream packet and determine the amount the recipient claims they received
         let claimed_amount: u64 = match stream_reply_packet {
             Ok(stream_reply_packet) => {
@@ -543,20 +959,16 @@ where
                 } else {
                     // Since we decrypted the response, the recipient read the request packet and knows our account
                     payment.should_send_source_account = false;
+                    payment.congestion_controller.record_rtt(sent_at.elapsed());
 
                     // Update the destination asset scale & code
-                    // https://github.com/interledger/rfcs/pull/551 ensures that this won't change
-                    if payment.receipt.destination_asset_scale.is_none() {
-                        for frame in stream_reply_packet.frames() {
-                            if let Frame::ConnectionAssetDetails(frame) = frame {
-                                let asset_code = frame.source_asset_code.to_string();
-                                let asset_scale = frame.source_asset_scale;
-                                debug!(
-                                    "Setting remote asset details ({} with scale {})",
-                                    asset_code, asset_scale
-                                );
-                                payment.set_destination_asset_details(asset_code, asset_scale);
-                            }
+                    // https://github.com/interledger/rfcs/pull/551 ensures that this won't change,
+                    // but buggy receivers may still change them
+                    for frame in stream_reply_packet.frames() {
+                        if let Frame::ConnectionAssetDetails(frame) = frame {
+                            let asset_code = frame.source_asset_code.to_string();
+                            let asset_scale = frame.source_asset_scale;
+                            payment.apply_asset_details(asset_code, asset_scale);
                         }
                     }
 
@@ -593,6 +1005,9 @@ where
             // Handle ILP Reject
             Err(reject) => {
                 payment.apply_reject(source_amount, &reject);
+                if payment.probe_in_flight && min_destination_amount == 0 {
+                    payment.apply_probe(source_amount, claimed_amount);
+                }
 
                 debug!(
                     "Prepare {} with amount {} was rejected with code: {} ({} left to send)",
@@ -622,7 +1037,7 @@ where
     /// Send an unfulfillable Prepare with a ConnectionClose frame to the peer
     /// There's no ACK from the recipient, so we can't confirm it closed
     #[inline]
-    async fn try_send_connection_close(&mut self) {
+    pub(crate) async fn try_send_connection_close(&mut self) {
         let prepare = {
             let mut payment = self.payment.lock().await;
             let sequence = payment.next_sequence();
@@ -635,19 +1050,19 @@ where
                     code: ErrorCode::NoError,
                     message: "",
                 })],
-            }
-            .build();
+            };
 
             // Create the ILP Prepare packet
-            let data = stream_packet.into_encrypted(&self.shared_secret);
-            PrepareBuilder {
+            let prepare_builder = PrepareBuilder {
                 destination: payment.receipt.to.clone(),
                 amount: 0,
                 execution_condition: &random_condition(),
                 expires_at: SystemTime::now() + Duration::from_secs(30),
-                data: &data[..],
-            }
-            .build()
+                data: &[],
+            };
+            payment
+                .encoder
+                .encode_prepare(&stream_packet, &self.shared_secret, &prepare_builder)
         };
 
         // Send it!
@@ -684,6 +1099,8 @@ fn get_rate<S: ExchangeRateStore>(
     // Fetch the exchange rate
     let rate: BigRational = if source_code == dest_code {
         BigRational::one()
+    } else if let Some(rate) = store.get_rate_override(source_code, dest_code) {
+        BigRational::from_f64(rate)?
     } else if let Ok(prices) = store.get_exchange_rates(&[&source_code, &dest_code]) {
         BigRational::from_f64(prices[0])? / BigRational::from_f64(prices[1])?
     } else {
@@ -724,7 +1141,8 @@ mod send_money_tests {
     use super::*;
     use crate::test_helpers::{TestAccount, TestStore, EXAMPLE_CONNECTOR};
     use async_trait::async_trait;
-    use interledger_packet::{ErrorCode as IlpErrorCode, RejectBuilder};
+    use bytes::BytesMut;
+    use interledger_packet::{ErrorCode as IlpErrorCode, FulfillBuilder, RejectBuilder};
     use interledger_service::incoming_service_fn;
     use interledger_service_util::MaxPacketAmountService;
     use parking_lot::Mutex;
@@ -772,6 +1190,269 @@ mod send_money_tests {
         assert_eq!(requests.lock().len(), 1);
     }
 
+    #[tokio::test]
+    async fn sends_money_frame_for_each_stream() {
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.destination").unwrap(),
+            max_packet_amount: None,
+        };
+        let shared_secret = vec![0; 32];
+        let streams = vec![
+            MoneyStream {
+                stream_id: 1,
+                shares: 3,
+            },
+            MoneyStream {
+                stream_id: 3,
+                shares: 1,
+            },
+        ];
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        let result = send_money_with_options(
+            incoming_service_fn(move |request| {
+                requests_clone.lock().push(request);
+                Err(RejectBuilder {
+                    code: IlpErrorCode::F00_BAD_REQUEST,
+                    message: b"just some final error",
+                    triggered_by: Some(&EXAMPLE_CONNECTOR),
+                    data: &[],
+                }
+                .build())
+            }),
+            &account,
+            TestStore {
+                route: None,
+                price_1: None,
+                price_2: None,
+            },
+            Address::from_str("example.destination").unwrap(),
+            shared_secret.clone(),
+            100,
+            SendMoneyOptions {
+                streams: streams.clone(),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(result.is_err());
+
+        let request = requests.lock().remove(0);
+        let packet =
+            StreamPacket::from_encrypted(&shared_secret, BytesMut::from(request.prepare.data()))
+                .unwrap();
+        let sent_streams: Vec<MoneyStream> = packet
+            .frames()
+            .filter_map(|frame| match frame {
+                Frame::StreamMoney(frame) => Some(MoneyStream {
+                    stream_id: frame.stream_id,
+                    shares: frame.shares,
+                }),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent_streams, streams);
+        // The first packet also announces our asset to the receiver
+        let asset_details = packet.frames().find_map(|frame| match frame {
+            Frame::ConnectionAssetDetails(frame) => Some((
+                frame.source_asset_code.to_string(),
+                frame.source_asset_scale,
+            )),
+            _ => None,
+        });
+        assert_eq!(asset_details, Some(("XYZ".to_string(), 9)));
+    }
+
+    #[test]
+    fn computes_realized_exchange_rate() {
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.sender").unwrap(),
+            max_packet_amount: None,
+        };
+        let mut receipt = StreamDelivery::new(
+            &account,
+            Address::from_str("example.receiver").unwrap(),
+            3000,
+        );
+        assert_eq!(receipt.realized_exchange_rate(), None);
+
+        receipt.destination_asset_code = Some("ABC".to_string());
+        receipt.destination_asset_scale = Some(6);
+        receipt.sent_amount = 3000;
+        receipt.in_flight_amount = 1000;
+        receipt.delivered_amount = 4;
+        // 0.000004 ABC were delivered for the 0.000002 XYZ which were fulfilled
+        let rate = receipt.realized_exchange_rate().unwrap();
+        assert!((rate - 2.0).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_streams() {
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.destination").unwrap(),
+            max_packet_amount: None,
+        };
+        let invalid_streams = vec![
+            vec![],
+            vec![MoneyStream {
+                stream_id: 2,
+                shares: 1,
+            }],
+            vec![MoneyStream {
+                stream_id: 1,
+                shares: 0,
+            }],
+            vec![
+                MoneyStream {
+                    stream_id: 1,
+                    shares: 1,
+                },
+                MoneyStream {
+                    stream_id: 1,
+                    shares: 2,
+                },
+            ],
+        ];
+        for streams in invalid_streams {
+            let result = send_money_with_options(
+                incoming_service_fn(|_| -> IlpResult { panic!("No packet should be sent") }),
+                &account,
+                TestStore {
+                    route: None,
+                    price_1: None,
+                    price_2: None,
+                },
+                Address::from_str("example.destination").unwrap(),
+                vec![0; 32],
+                100,
+                SendMoneyOptions {
+                    streams,
+                    ..Default::default()
+                },
+            )
+            .await;
+            assert!(matches!(result, Err(Error::InvalidStreams(_))));
+        }
+    }
+
+    /// Receiver which fulfills the packets it can and sends the asset details
+    /// returned by `asset_details` for each packet, given how many it received before
+    fn changing_receiver(
+        shared_secret: Vec<u8>,
+        asset_details: fn(usize) -> (&'static str, u8),
+    ) -> impl IncomingService<TestAccount> + Clone {
+        let num_requests = Arc::new(AtomicUsize::new(0));
+        incoming_service_fn(move |request| {
+            let (asset_code, asset_scale) =
+                asset_details(num_requests.fetch_add(1, Ordering::Relaxed));
+            let prepare = StreamPacket::from_encrypted(
+                &shared_secret,
+                BytesMut::from(request.prepare.data()),
+            )
+            .unwrap();
+            let fulfillment = generate_fulfillment(&shared_secret, request.prepare.data());
+            let fulfillable = generate_condition(&shared_secret, request.prepare.data())
+                == request.prepare.execution_condition();
+            let reply = StreamPacketBuilder {
+                sequence: prepare.sequence(),
+                ilp_packet_type: if fulfillable {
+                    IlpPacketType::Fulfill
+                } else {
+                    IlpPacketType::Reject
+                },
+                prepare_amount: request.prepare.amount(),
+                frames: &[Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                    source_asset_code: asset_code,
+                    source_asset_scale: asset_scale,
+                })],
+            }
+            .build()
+            .into_encrypted(&shared_secret);
+            if fulfillable {
+                Ok(FulfillBuilder {
+                    fulfillment: &fulfillment,
+                    data: &reply,
+                }
+                .build())
+            } else {
+                Err(RejectBuilder {
+                    code: IlpErrorCode::F99_APPLICATION_ERROR,
+                    message: &[],
+                    triggered_by: Some(&EXAMPLE_CONNECTOR),
+                    data: &reply,
+                }
+                .build())
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn warns_when_asset_details_change() {
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.destination").unwrap(),
+            max_packet_amount: None,
+        };
+        let store = || TestStore {
+            route: None,
+            price_1: Some(1.0),
+            price_2: Some(1.0),
+        };
+        let shared_secret = vec![0; 32];
+        let asset_details = |num_requests| match num_requests {
+            0 => ("XYZ", 9),
+            _ => ("ABC", 2),
+        };
+
+        let receipt = send_money(
+            changing_receiver(shared_secret.clone(), asset_details),
+            &account,
+            store(),
+            Address::from_str("example.destination").unwrap(),
+            shared_secret.clone(),
+            100,
+            0.0,
+        )
+        .await
+        .unwrap();
+        // The payment used the first asset details
+        assert_eq!(receipt.delivered_amount, 100);
+        assert_eq!(receipt.destination_asset_code.as_deref(), Some("XYZ"));
+        assert_eq!(
+            receipt.warnings,
+            vec![StreamWarning::AssetDetailsChanged {
+                asset_code: "ABC".to_string(),
+                asset_scale: 2,
+            }]
+        );
+
+        let result = send_money_with_options(
+            changing_receiver(shared_secret.clone(), asset_details),
+            &account,
+            store(),
+            Address::from_str("example.destination").unwrap(),
+            shared_secret,
+            100,
+            SendMoneyOptions {
+                abort_on_asset_details_change: true,
+                ..Default::default()
+            },
+        )
+        .await;
+        assert!(matches!(result, Err(Error::AssetDetailsChanged(code, 2)) if code == "ABC"));
+    }
+
     #[tokio::test]
     async fn perserveres_past_liquidity_errors() {
         let destination_address = Address::from_str("example.receiver").unwrap();
@@ -1,12 +1,26 @@
-use interledger_packet::{ErrorCode, MaxPacketAmountDetails, Reject};
+use interledger_packet::{ErrorCode, MaxPacketAmountDetails, Reject, RejectDetails};
 #[cfg(test)]
 use once_cell::sync::Lazy;
 use std::cmp::{max, min};
+use tokio::time::{Duration, Instant};
 use tracing::{debug, warn};
 
+/// Weight of each new sample in the smoothed round trip time (the same as TCP's, see RFC 6298)
+const RTT_SAMPLE_WEIGHT: f64 = 0.125;
+
+/// Packets which would only have to wait for less than this are sent right away
+const MIN_PACING_DELAY: Duration = Duration::from_millis(1);
+
+/// Fraction of the window which may be sent at once, after the pacing tokens built up
+const PACING_BURST_FRACTION: f64 = 0.25;
+
 /// A basic congestion controller that implements an
 /// Additive Increase, Multiplicative Decrease (AIMD) algorithm.
 ///
+/// Once the round trip time of the packets is known, it also paces them, spreading
+/// the window over each round trip instead of sending it in bursts, since bursts are
+/// what trips the rate limits of the nodes along the path.
+///
 /// Future implementations of this will use more advanced congestion
 /// control algorithms.
 pub struct CongestionController {
@@ -23,6 +37,13 @@ pub struct CongestionController {
     amount_in_flight: u64,
     /// The maximum allowed amount to be in flight
     max_in_flight: u64,
+    /// Smoothed round trip time of the packets the receiver answered, once measured
+    rtt: Option<Duration>,
+    /// Amount which may be sent without exceeding the pacing rate, replenished over time.
+    /// Negative while paying off a packet larger than the amount which was available
+    pacing_tokens: f64,
+    /// When the pacing tokens were last replenished
+    pacing_updated_at: Instant,
 }
 
 #[derive(PartialEq)]
@@ -41,6 +62,65 @@ impl CongestionController {
             max_packet_amount: None,
             amount_in_flight: 0,
             max_in_flight: start_amount,
+            rtt: None,
+            pacing_tokens: 0.0,
+            pacing_updated_at: Instant::now(),
+        }
+    }
+
+    /// Starts with the maximum packet amount already known, such as from probing the path
+    pub fn with_max_packet_amount(mut self, max_packet_amount: Option<u64>) -> Self {
+        self.max_packet_amount = max_packet_amount;
+        self
+    }
+
+    /// Starts with the round trip time already known, such as from probing the path
+    pub fn with_rtt(mut self, rtt: Option<Duration>) -> Self {
+        self.rtt = rtt.filter(|rtt| *rtt > Duration::from_secs(0));
+        self
+    }
+
+    /// Records the round trip time of a packet which the receiver answered
+    pub fn record_rtt(&mut self, rtt: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(smoothed) => {
+                smoothed.mul_f64(1.0 - RTT_SAMPLE_WEIGHT) + rtt.mul_f64(RTT_SAMPLE_WEIGHT)
+            }
+            None => rtt,
+        });
+    }
+
+    /// When the next packet may be sent without exceeding the pacing rate,
+    /// or `None` if it may be sent right away
+    pub fn get_next_send_time(&mut self) -> Option<Instant> {
+        self.next_send_time_at(Instant::now())
+    }
+
+    fn next_send_time_at(&mut self, now: Instant) -> Option<Instant> {
+        let rate = self.pacing_rate()?;
+        let elapsed = now.saturating_duration_since(self.pacing_updated_at);
+        self.pacing_updated_at = now;
+        self.pacing_tokens = (self.pacing_tokens + rate * elapsed.as_secs_f64())
+            .min(self.max_in_flight as f64 * PACING_BURST_FRACTION);
+
+        if self.pacing_tokens >= 0.0 {
+            return None;
+        }
+        let delay = Duration::from_secs_f64(-self.pacing_tokens / rate);
+        if delay < MIN_PACING_DELAY {
+            None
+        } else {
+            Some(now + delay)
+        }
+    }
+
+    /// Source units per second at which packets are sent: the whole window per round trip
+    fn pacing_rate(&self) -> Option<f64> {
+        let rtt = self.rtt?.as_secs_f64();
+        if rtt > 0.0 {
+            Some(self.max_in_flight as f64 / rtt)
+        } else {
+            None
         }
     }
 
@@ -58,6 +138,9 @@ impl CongestionController {
     pub fn prepare(&mut self, amount: u64) {
         if amount > 0 {
             self.amount_in_flight += amount;
+            if self.rtt.is_some() {
+                self.pacing_tokens -= amount as f64;
+            }
             debug!(
                 "Prepare packet of {}, amount in flight is now: {}",
                 amount, self.amount_in_flight
@@ -114,22 +197,27 @@ impl CongestionController {
                 debug!("Rejected packet with T04 error. Amount in flight was: {}, decreasing max in flight to: {}", self.amount_in_flight + prepare_amount, self.max_in_flight);
             }
             ErrorCode::F08_AMOUNT_TOO_LARGE => {
-                if let Ok(details) = MaxPacketAmountDetails::from_bytes(reject.data()) {
-                    let new_max_packet_amount: u64 =
-                        prepare_amount * details.max_amount() / details.amount_received();
-                    if let Some(max_packet_amount) = self.max_packet_amount {
-                        self.max_packet_amount =
-                            Some(min(max_packet_amount, new_max_packet_amount));
-                    } else {
-                        self.max_packet_amount = Some(new_max_packet_amount);
-                    }
-                } else {
-                    warn!("Got F08: Amount Too Large Error without max packet amount details attached");
-                    if let Some(max_packet_amount) = self.max_packet_amount {
-                        self.max_packet_amount =
-                            Some((max_packet_amount as f64 / self.decrease_factor) as u64);
+                let new_max_packet_amount = match max_packet_amount_from_reject(
+                    prepare_amount,
+                    reject,
+                ) {
+                    Some(max_packet_amount) => max_packet_amount,
+                    None => {
+                        warn!("Got F08: Amount Too Large Error without max packet amount details attached");
+                        // Without details, all we know is that this packet was too large
+                        let max_packet_amount = min(self.get_max_packet_amount(), prepare_amount);
+                        (max_packet_amount as f64 / self.decrease_factor) as u64
                     }
-                }
+                };
+                self.max_packet_amount = Some(min(
+                    self.get_max_packet_amount(),
+                    max(new_max_packet_amount, 1),
+                ));
+                debug!(
+                    "Rejected packet of {} with F08 error, max packet amount is now: {}",
+                    prepare_amount,
+                    self.get_max_packet_amount()
+                );
             }
             _ => {
                 // No special treatment for other errors
@@ -143,6 +231,32 @@ impl CongestionController {
     }
 }
 
+/// Computes the largest source amount the node which rejected a packet with an F08 error
+/// would have accepted, from the amounts it reported in the reject's data. The node reports
+/// them in its own units, so the ratio between them is applied to the source amount.
+///
+/// Returns `None` if the reject carries no (usable) details.
+pub(crate) fn max_packet_amount_from_reject(prepare_amount: u64, reject: &Reject) -> Option<u64> {
+    // The RFC-0027 details are read on their own, so they are used even if the bytes
+    // following them are not valid structured details
+    let details = match MaxPacketAmountDetails::from_bytes(reject.data()) {
+        Ok(details) if reject.code() == ErrorCode::F08_AMOUNT_TOO_LARGE => details,
+        _ => RejectDetails::from_reject(reject)?
+            .max_packet_amount()?
+            .clone(),
+    };
+    if details.amount_received() == 0 {
+        return None;
+    }
+    let max_packet_amount = u128::from(prepare_amount) * u128::from(details.max_amount())
+        / u128::from(details.amount_received());
+    // The packet was rejected, so the limit is below its amount even if rounding says otherwise
+    Some(min(
+        max_packet_amount,
+        u128::from(prepare_amount.saturating_sub(1)),
+    ) as u64)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -179,6 +293,9 @@ mod tests {
                 max_packet_amount: None,
                 amount_in_flight: 0,
                 max_in_flight: u64::max_value() - 1,
+                rtt: None,
+                pacing_tokens: 0.0,
+                pacing_updated_at: Instant::now(),
             };
 
             let amount = controller.get_amount_left_in_window();
@@ -306,6 +423,77 @@ mod tests {
             assert_eq!(amount, 50);
         }
 
+        #[test]
+        fn max_packet_amount_from_details_doesnt_overflow_u64() {
+            let mut controller = CongestionController::new(u64::max_value(), 1000, 2.0);
+            let amount = u64::max_value();
+            controller.prepare(amount);
+            controller.reject(
+                amount,
+                &RejectBuilder {
+                    code: ErrorCode::F08_AMOUNT_TOO_LARGE,
+                    message: &[],
+                    triggered_by: None,
+                    data: &MaxPacketAmountDetails::new(u64::max_value(), 1 << 32).to_bytes(),
+                }
+                .build(),
+            );
+            assert_eq!(controller.get_max_packet_amount(), 1 << 32);
+        }
+
+        #[test]
+        fn max_packet_amount_is_below_rejected_amount() {
+            let mut controller = CongestionController::new(1000, 1000, 2.0);
+
+            // Rounding in the connector's exchange rate makes it look like 1000 would have fit
+            controller.prepare(1000);
+            controller.reject(
+                1000,
+                &RejectBuilder {
+                    code: ErrorCode::F08_AMOUNT_TOO_LARGE,
+                    message: &[],
+                    triggered_by: None,
+                    data: &MaxPacketAmountDetails::new(2001, 2000).to_bytes(),
+                }
+                .build(),
+            );
+            assert_eq!(controller.get_max_packet_amount(), 999);
+
+            // A zero received amount can't be used
+            controller.prepare(999);
+            controller.reject(
+                999,
+                &RejectBuilder {
+                    code: ErrorCode::F08_AMOUNT_TOO_LARGE,
+                    message: &[],
+                    triggered_by: None,
+                    data: &MaxPacketAmountDetails::new(0, 10).to_bytes(),
+                }
+                .build(),
+            );
+            assert_eq!(controller.get_max_packet_amount(), 499);
+        }
+
+        #[test]
+        fn max_packet_amount_without_details() {
+            let mut controller = CongestionController::new(1000, 1000, 2.0);
+            assert_eq!(controller.get_max_packet_amount(), u64::max_value());
+
+            // Without a known max packet amount, the rejected amount is decreased
+            controller.prepare(800);
+            controller.reject(
+                800,
+                &RejectBuilder {
+                    code: ErrorCode::F08_AMOUNT_TOO_LARGE,
+                    message: &[],
+                    triggered_by: None,
+                    data: &[],
+                }
+                .build(),
+            );
+            assert_eq!(controller.get_max_packet_amount(), 400);
+        }
+
         #[test]
         fn max_packet_amount_doesnt_overflow_u64() {
             let mut controller = CongestionController::new(1000, 1000, 5.0);
@@ -326,6 +514,9 @@ mod tests {
                 max_packet_amount: None,
                 amount_in_flight: 0,
                 max_in_flight: u64::max_value() - 1,
+                rtt: None,
+                pacing_tokens: 0.0,
+                pacing_updated_at: Instant::now(),
             };
 
             let amount = controller.get_amount_left_in_window();
@@ -335,6 +526,56 @@ mod tests {
         }
     }
 
+    mod f08_details {
+        use super::*;
+        use interledger_packet::{RejectBuilder, RejectDetail};
+
+        fn reject(data: &[u8]) -> Reject {
+            RejectBuilder {
+                code: ErrorCode::F08_AMOUNT_TOO_LARGE,
+                message: &[],
+                triggered_by: None,
+                data,
+            }
+            .build()
+        }
+
+        #[test]
+        fn keeps_the_max_amount_with_trailing_bytes() {
+            let mut data = MaxPacketAmountDetails::new(100, 10).to_bytes().to_vec();
+            assert_eq!(
+                max_packet_amount_from_reject(1000, &reject(&data)),
+                Some(100)
+            );
+
+            // Opaque data after the RFC-0027 details
+            data.extend_from_slice(b"opaque");
+            assert_eq!(
+                max_packet_amount_from_reject(1000, &reject(&data)),
+                Some(100)
+            );
+
+            // Invalid structured details after the RFC-0027 details
+            let mut data = MaxPacketAmountDetails::new(100, 10).to_bytes().to_vec();
+            let structured = RejectDetails::new()
+                .with(RejectDetail::RetryAfter(std::time::Duration::from_secs(1)))
+                .to_bytes(ErrorCode::T05_RATE_LIMITED);
+            data.extend_from_slice(&structured[..structured.len() - 1]);
+            assert!(RejectDetails::from_reject(&reject(&data)).is_none());
+            assert_eq!(
+                max_packet_amount_from_reject(1000, &reject(&data)),
+                Some(100)
+            );
+        }
+
+        #[test]
+        fn ignores_unusable_details() {
+            assert_eq!(max_packet_amount_from_reject(1000, &reject(&[])), None);
+            let data = MaxPacketAmountDetails::new(0, 10).to_bytes();
+            assert_eq!(max_packet_amount_from_reject(1000, &reject(&data)), None);
+        }
+    }
+
     mod tracking_amount_in_flight {
         use super::*;
 
@@ -359,4 +600,62 @@ mod tests {
             assert_eq!(max_amount, 1000 - 600 - 100);
         }
     }
+
+    mod pacing {
+        use super::*;
+
+        #[test]
+        fn smooths_round_trip_time() {
+            let mut controller = CongestionController::new(1000, 1000, 2.0);
+            assert_eq!(controller.rtt, None);
+
+            controller.record_rtt(Duration::from_millis(100));
+            assert_eq!(controller.rtt, Some(Duration::from_millis(100)));
+            controller.record_rtt(Duration::from_millis(200));
+            let rtt = controller.rtt.unwrap();
+            assert!(rtt > Duration::from_micros(112_499) && rtt < Duration::from_micros(112_501));
+        }
+
+        #[test]
+        fn doesnt_pace_before_measuring_round_trip_time() {
+            let mut controller = CongestionController::new(1000, 1000, 2.0);
+            controller.prepare(1000);
+            assert_eq!(controller.next_send_time_at(Instant::now()), None);
+        }
+
+        #[test]
+        fn spreads_window_over_round_trip() {
+            let mut controller = CongestionController::new(1000, 1000, 2.0)
+                .with_rtt(Some(Duration::from_millis(100)));
+            let now = controller.pacing_updated_at;
+            assert_eq!(controller.next_send_time_at(now), None);
+
+            // The pacing rate is 1000 units per 100ms
+            controller.prepare(500);
+            let send_time = controller.next_send_time_at(now).unwrap();
+            let delay = send_time - now;
+            assert!(delay > Duration::from_millis(49) && delay < Duration::from_millis(51));
+            assert!(controller
+                .next_send_time_at(now + Duration::from_millis(25))
+                .is_some());
+            assert_eq!(
+                controller.next_send_time_at(now + Duration::from_millis(51)),
+                None
+            );
+        }
+
+        #[test]
+        fn limits_bursts_after_idle_periods() {
+            let mut controller = CongestionController::new(1000, 1000, 2.0)
+                .with_rtt(Some(Duration::from_millis(100)));
+            let now = controller.pacing_updated_at + Duration::from_secs(10);
+            assert_eq!(controller.next_send_time_at(now), None);
+
+            // Only a quarter of the window built up while idle
+            controller.prepare(250);
+            assert_eq!(controller.next_send_time_at(now), None);
+            controller.prepare(250);
+            assert!(controller.next_send_time_at(now).is_some());
+        }
+    }
 }
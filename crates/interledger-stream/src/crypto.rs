@@ -1,4 +1,5 @@
 use bytes::BytesMut;
+use interledger_packet::pool;
 #[cfg(test)]
 use once_cell::sync::Lazy;
 use ring::rand::{SecureRandom, SystemRandom};
@@ -7,6 +8,8 @@ use tracing::error;
 
 const NONCE_LENGTH: usize = 12;
 const AUTH_TAG_LENGTH: usize = 16;
+/// Number of bytes the encryption adds to the plaintext
+pub const ENCRYPTION_OVERHEAD: usize = NONCE_LENGTH + AUTH_TAG_LENGTH;
 
 /// Protocol specific string for encryption
 static ENCRYPTION_KEY_STRING: &[u8] = b"ilp_stream_encryption";
@@ -123,6 +126,50 @@ fn encrypt_with_nonce(
     nonce_tag_data
 }
 
+/// Encrypts a plaintext like [`encrypt`](./fn.encrypt.html) does, but writes the ciphertext
+/// into `out` instead of allocating a buffer for it.
+///
+/// `out` must be exactly [`ENCRYPTION_OVERHEAD`](./constant.ENCRYPTION_OVERHEAD.html) bytes
+/// longer than the plaintext.
+pub fn encrypt_into(shared_secret: &[u8], plaintext: &[u8], out: &mut [u8]) {
+    let mut nonce: [u8; NONCE_LENGTH] = [0; NONCE_LENGTH];
+    SystemRandom::new()
+        .fill(&mut nonce[..])
+        .expect("Failed to securely generate a random nonce!");
+
+    encrypt_into_with_nonce(shared_secret, plaintext, out, nonce)
+}
+
+fn encrypt_into_with_nonce(
+    shared_secret: &[u8],
+    plaintext: &[u8],
+    out: &mut [u8],
+    nonce: [u8; NONCE_LENGTH],
+) {
+    let key = hmac_sha256(shared_secret, ENCRYPTION_KEY_STRING);
+    let key = aead::UnboundKey::new(&aead::AES_256_GCM, &key)
+        .expect("Failed to create a new sealing key for encrypting data!");
+    let key = aead::LessSafeKey::new(key);
+
+    // The format is `nonce, auth tag, data`, in that order
+    let (nonce_out, tag_and_data) = out.split_at_mut(NONCE_LENGTH);
+    let (tag_out, data) = tag_and_data.split_at_mut(AUTH_TAG_LENGTH);
+    nonce_out.copy_from_slice(&nonce);
+    data.copy_from_slice(plaintext);
+
+    let tag = key
+        .seal_in_place_separate_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::from(&[]),
+            data,
+        )
+        .unwrap_or_else(|err| {
+            error!("Error encrypting {:?}", err);
+            panic!("Error encrypting {:?}", err);
+        });
+    tag_out.copy_from_slice(tag.as_ref());
+}
+
 /// Decrypts a AES256-GCM encrypted ciphertext.
 ///
 /// The secret key is generated deterministically by HMAC-256'ing the `shared_secret`
@@ -132,7 +179,17 @@ fn encrypt_with_nonce(
 ///
 /// The nonce and auth tag are extracted from the first 12 and 16 bytes
 /// of the ciphertext.
-pub fn decrypt(shared_secret: &[u8], mut ciphertext: BytesMut) -> Result<BytesMut, ()> {
+pub fn decrypt(shared_secret: &[u8], ciphertext: BytesMut) -> Result<BytesMut, ()> {
+    let mut plaintext = pool::take(ciphertext.len());
+    decrypt_into(shared_secret, &ciphertext, &mut plaintext)?;
+    pool::recycle(ciphertext);
+    Ok(plaintext)
+}
+
+/// Decrypts a ciphertext like [`decrypt`](./fn.decrypt.html) does, but copies it into `out`
+/// (replacing its contents) and decrypts it in place there, instead of taking ownership of it.
+/// `out` is only reallocated if it does not have the capacity for the ciphertext.
+pub fn decrypt_into(shared_secret: &[u8], ciphertext: &[u8], out: &mut BytesMut) -> Result<(), ()> {
     // ciphertext must include at least a nonce and tag
     if ciphertext.len() < NONCE_LENGTH + AUTH_TAG_LENGTH {
         return Err(());
@@ -143,24 +200,26 @@ pub fn decrypt(shared_secret: &[u8], mut ciphertext: BytesMut) -> Result<BytesMu
     let key = aead::LessSafeKey::new(key);
 
     let mut nonce: [u8; NONCE_LENGTH] = [0; NONCE_LENGTH];
-    nonce.copy_from_slice(&ciphertext.split_to(NONCE_LENGTH));
-
-    let additional_data: &[u8] = &[];
-    let auth_tag = ciphertext.split_to(AUTH_TAG_LENGTH);
+    nonce.copy_from_slice(&ciphertext[..NONCE_LENGTH]);
+    let (auth_tag, data) = ciphertext[NONCE_LENGTH..].split_at(AUTH_TAG_LENGTH);
 
     // Ring expects the tag to come after the data
-    ciphertext.unsplit(auth_tag);
+    out.clear();
+    out.reserve(data.len() + AUTH_TAG_LENGTH);
+    out.extend_from_slice(data);
+    out.extend_from_slice(auth_tag);
 
+    let additional_data: &[u8] = &[];
     let length = key
         .open_in_place(
             aead::Nonce::assume_unique_for_key(nonce),
             aead::Aad::from(additional_data),
-            &mut ciphertext,
+            &mut out[..],
         )
         .map_err(|_| ())?
         .len();
-    ciphertext.truncate(length);
-    Ok(ciphertext)
+    out.truncate(length);
+    Ok(())
 }
 
 #[cfg(test)]
@@ -216,12 +275,33 @@ mod encrypt_decrypt_test {
         assert_eq!(&encrypted[..], CIPHERTEXT);
     }
 
+    #[test]
+    fn it_encrypts_into_a_buffer_to_same_as_javascript() {
+        let mut encrypted = vec![0; PLAINTEXT.len() + ENCRYPTION_OVERHEAD];
+        encrypt_into_with_nonce(SHARED_SECRET, PLAINTEXT, &mut encrypted, NONCE);
+        assert_eq!(&encrypted[..], CIPHERTEXT);
+    }
+
     #[test]
     fn it_decrypts_javascript_ciphertext() {
         let decrypted = decrypt(SHARED_SECRET, BytesMut::from(CIPHERTEXT));
         assert_eq!(&decrypted.unwrap()[..], PLAINTEXT);
     }
 
+    #[test]
+    fn it_decrypts_into_a_reused_buffer() {
+        let mut buffer = BytesMut::with_capacity(CIPHERTEXT.len());
+        let capacity = buffer.capacity();
+        decrypt_into(SHARED_SECRET, CIPHERTEXT, &mut buffer).unwrap();
+        assert_eq!(&buffer[..], PLAINTEXT);
+        decrypt_into(SHARED_SECRET, CIPHERTEXT, &mut buffer).unwrap();
+        assert_eq!(&buffer[..], PLAINTEXT);
+        assert_eq!(buffer.capacity(), capacity);
+
+        assert!(decrypt_into(&[0; 32], CIPHERTEXT, &mut buffer).is_err());
+        assert!(decrypt_into(SHARED_SECRET, &CIPHERTEXT[..27], &mut buffer).is_err());
+    }
+
     #[test]
     fn it_losslessly_encrypts_and_decrypts() {
         let ciphertext = encrypt(SHARED_SECRET, BytesMut::from(PLAINTEXT));
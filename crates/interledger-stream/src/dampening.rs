@@ -0,0 +1,175 @@
+use metrics::{recorder, Key};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::debug;
+use uuid::Uuid;
+
+/// Maximum number of connections whose failures are remembered at once, so that a flood of
+/// packets to random addresses cannot grow the cache without bounds
+const MAX_TRACKED_CONNECTIONS: usize = 10_000;
+
+/// When the receiver stops decrypting the packets sent to a connection, because the previous
+/// ones failed to decrypt. This happens when a sender keeps retrying with the wrong shared
+/// secret, for example because of a bug in its client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DampeningPolicy {
+    /// Number of packets to a connection which may fail to decrypt before the next ones are
+    /// rejected without decrypting them
+    pub max_failures: u32,
+    /// How long failures are remembered for after the last one, and so how long the packets
+    /// to a connection are rejected once it exceeded the maximum number of failures
+    pub window: Duration,
+}
+
+impl Default for DampeningPolicy {
+    fn default() -> Self {
+        DampeningPolicy {
+            max_failures: 5,
+            window: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    expires_at: Instant,
+}
+
+/// Short-lived negative cache of the connections whose packets failed to decrypt.
+///
+/// Failures are tracked per incoming account, so that the garbage packets sent by one peer
+/// cannot get a connection dampened for the packets its legitimate sender routes via another.
+#[derive(Debug, Clone)]
+pub(crate) struct RejectDampener {
+    policy: DampeningPolicy,
+    failures: Arc<Mutex<HashMap<(Uuid, String), Failures>>>,
+}
+
+impl RejectDampener {
+    pub(crate) fn new(policy: DampeningPolicy) -> Self {
+        RejectDampener {
+            policy,
+            failures: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Whether packets to the connection coming from the account should be rejected without
+    /// decrypting them. Counted in the `stream.receiver.dampened` metric
+    pub(crate) fn is_dampened(&self, account_id: Uuid, connection: &str) -> bool {
+        self.is_dampened_at(account_id, connection, Instant::now())
+    }
+
+    fn is_dampened_at(&self, account_id: Uuid, connection: &str, now: Instant) -> bool {
+        let key = (account_id, connection.to_string());
+        let dampened = match self.failures.lock().get(&key) {
+            Some(failures) => {
+                failures.expires_at > now && failures.count >= self.policy.max_failures
+            }
+            None => false,
+        };
+        if dampened {
+            recorder().increment_counter(Key::from_name("stream.receiver.dampened"), 1);
+        }
+        dampened
+    }
+
+    /// Records that a packet to the connection coming from the account failed to decrypt
+    pub(crate) fn record_failure(&self, account_id: Uuid, connection: &str) {
+        self.record_failure_at(account_id, connection, Instant::now())
+    }
+
+    fn record_failure_at(&self, account_id: Uuid, connection: &str, now: Instant) {
+        let key = (account_id, connection.to_string());
+        let mut failures = self.failures.lock();
+        if failures.len() >= MAX_TRACKED_CONNECTIONS && !failures.contains_key(&key) {
+            failures.retain(|_, failures| failures.expires_at > now);
+            if failures.len() >= MAX_TRACKED_CONNECTIONS {
+                return;
+            }
+        }
+
+        let expires_at = now + self.policy.window;
+        let entry = failures.entry(key).or_insert(Failures {
+            count: 0,
+            expires_at,
+        });
+        if entry.expires_at <= now {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.expires_at = expires_at;
+        if entry.count == self.policy.max_failures {
+            debug!(
+                "Rejecting the packets to {} from account {} without decrypting them for {:?}, since {} of them failed to decrypt",
+                connection, account_id, self.policy.window, entry.count
+            );
+        }
+    }
+
+    /// Forgets the failures of the connection coming from the account, once one of its
+    /// packets decrypted
+    pub(crate) fn record_success(&self, account_id: Uuid, connection: &str) {
+        let mut failures = self.failures.lock();
+        if !failures.is_empty() {
+            failures.remove(&(account_id, connection.to_string()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONNECTION: &str = "example.receiver.abc";
+
+    fn dampener() -> RejectDampener {
+        RejectDampener::new(DampeningPolicy {
+            max_failures: 3,
+            window: Duration::from_secs(10),
+        })
+    }
+
+    #[test]
+    fn dampens_connections_from_an_account_after_repeated_failures() {
+        let dampener = dampener();
+        let account = Uuid::new_v4();
+        let now = Instant::now();
+        for _ in 0..2 {
+            dampener.record_failure_at(account, CONNECTION, now);
+        }
+        assert!(!dampener.is_dampened_at(account, CONNECTION, now));
+
+        dampener.record_failure_at(account, CONNECTION, now + Duration::from_secs(5));
+        assert!(dampener.is_dampened_at(account, CONNECTION, now + Duration::from_secs(5)));
+        assert!(!dampener.is_dampened_at(account, "example.receiver.other", now));
+        // Other accounts may still send packets to the connection
+        assert!(!dampener.is_dampened_at(Uuid::new_v4(), CONNECTION, now));
+
+        // The window starts over from the last failure
+        assert!(dampener.is_dampened_at(account, CONNECTION, now + Duration::from_secs(14)));
+        assert!(!dampener.is_dampened_at(account, CONNECTION, now + Duration::from_secs(15)));
+    }
+
+    #[test]
+    fn forgets_failures_once_they_expire_or_a_packet_decrypts() {
+        let dampener = dampener();
+        let account = Uuid::new_v4();
+        let now = Instant::now();
+        for _ in 0..2 {
+            dampener.record_failure_at(account, CONNECTION, now);
+        }
+        let later = now + Duration::from_secs(10);
+        dampener.record_failure_at(account, CONNECTION, later);
+        assert!(!dampener.is_dampened_at(account, CONNECTION, later));
+
+        for _ in 0..2 {
+            dampener.record_failure_at(account, CONNECTION, later);
+        }
+        assert!(dampener.is_dampened_at(account, CONNECTION, later));
+        dampener.record_success(account, CONNECTION);
+        assert!(!dampener.is_dampened_at(account, CONNECTION, later));
+    }
+}
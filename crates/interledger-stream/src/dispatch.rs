@@ -0,0 +1,314 @@
+use super::crypto::generate_fulfillment;
+use super::server::{connection_tag, ConnectionGenerator};
+use async_trait::async_trait;
+use bytes::Bytes;
+use interledger_errors::SubAccountStoreError;
+use interledger_service::{Account, IlpResult, OutgoingRequest, OutgoingService, Username};
+use std::cmp::Reverse;
+use std::sync::Arc;
+use tracing::{debug, error};
+use uuid::Uuid;
+
+/// Store which keeps track of the funds an account received on behalf of its sub-accounts
+#[async_trait]
+pub trait SubAccountStore {
+    /// Moves `amount` from the balance of the account to the balance of the sub-account
+    /// with the given username. Both accounts must have the same asset code and scale.
+    async fn credit_sub_account(
+        &self,
+        account_id: Uuid,
+        sub_account: &Username,
+        amount: u64,
+    ) -> Result<(), SubAccountStoreError>;
+}
+
+/// Maps the payments an account receives over connections with a tag starting with
+/// `tag_prefix` to one of its sub-accounts
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagRoute {
+    /// Username of the account receiving payments on behalf of the sub-account
+    pub account: Username,
+    pub tag_prefix: String,
+    /// Username of the account the payments are credited to
+    pub sub_account: Username,
+}
+
+/// # Tag Dispatch Service
+///
+/// Turns accounts into hosted receiving services: payments they receive over STREAM
+/// connections with a [connection tag](./struct.ConnectionGenerator.html#method.generate_address_and_secret_with_tag)
+/// are credited to the sub-account the longest matching tag prefix is mapped to, once the
+/// packet is fulfilled. Payments without a tag or with an unknown one stay with the
+/// receiving account.
+///
+/// This must be placed in front of the `StreamReceiverService`, and after the `BalanceService`
+/// so that the receiving account's balance is first credited with the payment.
+#[derive(Clone)]
+pub struct TagDispatchService<S, O> {
+    connection_generator: ConnectionGenerator,
+    /// Longest tag prefixes first
+    routes: Arc<Vec<TagRoute>>,
+    store: S,
+    next: O,
+}
+
+impl<S, O> TagDispatchService<S, O> {
+    /// `server_secret` must be the one used by the `StreamReceiverService`, so that only
+    /// the packets it fulfilled are credited to the sub-accounts
+    pub fn new(server_secret: Bytes, mut routes: Vec<TagRoute>, store: S, next: O) -> Self {
+        routes.sort_by_key(|route| Reverse(route.tag_prefix.len()));
+        TagDispatchService {
+            connection_generator: ConnectionGenerator::new(server_secret),
+            routes: Arc::new(routes),
+            store,
+            next,
+        }
+    }
+
+    /// Returns the sub-account which should be credited for payments with the given tag
+    fn sub_account_for_tag(&self, account: &Username, tag: &str) -> Option<&Username> {
+        self.routes
+            .iter()
+            .find(|route| &route.account == account && tag.starts_with(route.tag_prefix.as_str()))
+            .map(|route| &route.sub_account)
+    }
+}
+
+#[async_trait]
+impl<S, O, A> OutgoingService<A> for TagDispatchService<S, O>
+where
+    S: SubAccountStore + Clone + Send + Sync + 'static,
+    O: OutgoingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
+        if self.routes.is_empty() {
+            return self.next.send_request(request).await;
+        }
+        let destination = request.prepare.destination();
+        let sub_account = match connection_tag(&destination, request.to.ilp_address())
+            .and_then(|tag| self.sub_account_for_tag(request.to.username(), tag))
+        {
+            Some(sub_account) => sub_account.clone(),
+            None => return self.next.send_request(request).await,
+        };
+
+        let account_id = request.to.id();
+        let amount = request.prepare.amount();
+        let data = request.prepare.data().to_vec();
+        let result = self.next.send_request(request).await;
+
+        if let Ok(ref fulfill) = result {
+            // Only credit payments the STREAM receiver fulfilled, rather than
+            // packets which were forwarded to another node
+            let shared_secret = self.connection_generator.rederive_secret(&destination);
+            if amount > 0 && fulfill.fulfillment() == generate_fulfillment(&shared_secret, &data) {
+                debug!(
+                    "Crediting {} received by account {} to sub-account {}",
+                    amount, account_id, sub_account
+                );
+                if let Err(err) = self
+                    .store
+                    .credit_sub_account(account_id, &sub_account, amount)
+                    .await
+                {
+                    error!(
+                        "Error crediting {} received by account {} to sub-account {}: {}",
+                        amount, account_id, sub_account, err
+                    );
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::StreamPacketBuilder;
+    use crate::test_helpers::{TestAccount, EXAMPLE_RECEIVER};
+    use interledger_packet::{
+        Address, ErrorCode, FulfillBuilder, PacketType as IlpPacketType, PrepareBuilder,
+        RejectBuilder,
+    };
+    use interledger_service::outgoing_service_fn;
+    use parking_lot::Mutex;
+    use std::str::FromStr;
+    use std::time::{Duration, SystemTime};
+
+    #[derive(Clone, Default)]
+    struct TestStore {
+        credits: Arc<Mutex<Vec<(Uuid, Username, u64)>>>,
+    }
+
+    #[async_trait]
+    impl SubAccountStore for TestStore {
+        async fn credit_sub_account(
+            &self,
+            account_id: Uuid,
+            sub_account: &Username,
+            amount: u64,
+        ) -> Result<(), SubAccountStoreError> {
+            self.credits
+                .lock()
+                .push((account_id, sub_account.clone(), amount));
+            Ok(())
+        }
+    }
+
+    fn route(account: &str, tag_prefix: &str, sub_account: &str) -> TagRoute {
+        TagRoute {
+            account: Username::from_str(account).unwrap(),
+            tag_prefix: tag_prefix.to_string(),
+            sub_account: Username::from_str(sub_account).unwrap(),
+        }
+    }
+
+    fn request_to(account: &TestAccount, destination: Address) -> OutgoingRequest<TestAccount> {
+        let data = StreamPacketBuilder {
+            sequence: 1,
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            frames: &[],
+        }
+        .build()
+        .into_encrypted(&[0; 32]);
+        OutgoingRequest {
+            from: account.clone(),
+            to: account.clone(),
+            original_amount: 100,
+            prepare: PrepareBuilder {
+                destination,
+                amount: 100,
+                execution_condition: &[0; 32],
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: &data,
+            }
+            .build(),
+        }
+    }
+
+    /// Fulfills packets as the STREAM receiver with the given secret would
+    fn dispatch_service(
+        store: TestStore,
+        fulfilling_secret: [u8; 32],
+    ) -> TagDispatchService<TestStore, impl OutgoingService<TestAccount> + Clone> {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let connection_generator =
+            ConnectionGenerator::new(Bytes::copy_from_slice(&fulfilling_secret[..]));
+        TagDispatchService::new(
+            server_secret,
+            vec![
+                route("alice", "bob", "bob"),
+                route("alice", "bob-", "bobs_shop"),
+            ],
+            store,
+            outgoing_service_fn(move |request: OutgoingRequest<TestAccount>| {
+                let destination = request.prepare.destination();
+                let shared_secret = connection_generator.rederive_secret(&destination);
+                Ok(FulfillBuilder {
+                    fulfillment: &generate_fulfillment(&shared_secret, request.prepare.data()),
+                    data: &[],
+                }
+                .build())
+            }),
+        )
+    }
+
+    fn alice() -> TestAccount {
+        TestAccount {
+            id: Uuid::new_v4(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: EXAMPLE_RECEIVER.clone(),
+            max_packet_amount: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn credits_sub_account_matching_longest_tag_prefix() {
+        let store = TestStore::default();
+        let mut service = dispatch_service(store.clone(), [0; 32]);
+        let generator = ConnectionGenerator::new(Bytes::from(&[0; 32][..]));
+        let account = alice();
+
+        for (tag, expected) in &[("bob-123", "bobs_shop"), ("bob", "bob")] {
+            let (destination, _) = generator
+                .generate_address_and_secret_with_tag(&EXAMPLE_RECEIVER, tag)
+                .unwrap();
+            service
+                .send_request(request_to(&account, destination))
+                .await
+                .unwrap();
+            let (account_id, sub_account, amount) = store.credits.lock().pop().unwrap();
+            assert_eq!(account_id, account.id);
+            assert_eq!(sub_account.as_ref(), *expected);
+            assert_eq!(amount, 100);
+        }
+    }
+
+    #[tokio::test]
+    async fn ignores_untagged_and_unknown_tags() {
+        let store = TestStore::default();
+        let mut service = dispatch_service(store.clone(), [0; 32]);
+        let generator = ConnectionGenerator::new(Bytes::from(&[0; 32][..]));
+        let account = alice();
+
+        let (untagged, _) = generator.generate_address_and_secret(&EXAMPLE_RECEIVER);
+        let (unknown, _) = generator
+            .generate_address_and_secret_with_tag(&EXAMPLE_RECEIVER, "carl")
+            .unwrap();
+        for destination in [untagged, unknown].iter().cloned() {
+            service
+                .send_request(request_to(&account, destination))
+                .await
+                .unwrap();
+        }
+        assert!(store.credits.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_credits_packets_fulfilled_by_stream_receiver() {
+        let store = TestStore::default();
+        // The packets are fulfilled by a receiver with another secret (e.g. another node)
+        let mut service = dispatch_service(store.clone(), [1; 32]);
+        let generator = ConnectionGenerator::new(Bytes::from(&[1; 32][..]));
+        let (destination, _) = generator
+            .generate_address_and_secret_with_tag(&EXAMPLE_RECEIVER, "bob")
+            .unwrap();
+        service
+            .send_request(request_to(&alice(), destination))
+            .await
+            .unwrap();
+        assert!(store.credits.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn does_not_credit_rejected_packets() {
+        let store = TestStore::default();
+        let generator = ConnectionGenerator::new(Bytes::from(&[0; 32][..]));
+        let mut service = TagDispatchService::new(
+            Bytes::from(&[0; 32][..]),
+            vec![route("alice", "bob", "bob")],
+            store.clone(),
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F99_APPLICATION_ERROR,
+                    message: &[],
+                    triggered_by: None,
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let (destination, _) = generator
+            .generate_address_and_secret_with_tag(&EXAMPLE_RECEIVER, "bob")
+            .unwrap();
+        assert!(service
+            .send_request(request_to(&alice(), destination))
+            .await
+            .is_err());
+        assert!(store.credits.lock().is_empty());
+    }
+}
@@ -14,6 +14,16 @@ pub enum Error {
         "Error maximum time exceeded: Time since last fulfill exceeded the maximum time limit"
     )]
     Timeout,
+    #[error("Invalid money streams: {0}")]
+    InvalidStreams(&'static str),
+    #[error(
+        "Terminating payment since the receiver changed its asset details to {0} with scale {1}"
+    )]
+    AssetDetailsChanged(String, u8),
+    #[error("Terminating payment since the maximum source amount was sent, but only {0} of the {1} units to deliver were delivered")]
+    DestinationAmountNotDelivered(u64, u64),
+    #[error("Unable to probe the path since none of the probe packets reached the receiver")]
+    RateProbeFailed,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -24,6 +34,16 @@ pub enum StreamPacketError {
     UnsupportedVersion(u8),
     #[error("Invalid Packet: Incorrect number of frames or unable to parse all frames")]
     NotEnoughValidFrames,
+    #[error("Invalid Packet: unable to parse frame {0}: {1}")]
+    InvalidFrame(usize, Box<StreamPacketError>),
+    #[error("Invalid Packet: {0} frames exceeds the maximum of {1}")]
+    TooManyFrames(u64, u64),
+    #[error("Invalid Packet: frame of {0} bytes exceeds the maximum size of {1}")]
+    FrameTooLarge(usize, usize),
+    #[error("Unknown STREAM frame type name: {0}")]
+    UnknownFrameType(String),
+    #[error("Invalid Packet: {0} does not fit in a u64")]
+    VarUintTooLarge(&'static str),
     #[error("Trailing bytes error: Inner")]
     TrailingInnerBytes,
     #[error("Invalid Packet: {0}")]
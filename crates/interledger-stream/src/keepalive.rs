@@ -0,0 +1,387 @@
+use super::crypto::random_condition;
+use super::packet::*;
+use bytes::BytesMut;
+use futures::future::{abortable, AbortHandle};
+use interledger_packet::{Address, PacketType as IlpPacketType, PrepareBuilder};
+use interledger_service::*;
+use metrics::{recorder, Key};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::SystemTime;
+use tokio::time::{delay_until, Duration, Instant};
+use tracing::{debug, warn};
+
+/// Interval between keep-alive packets on an idle connection, if not configured otherwise
+pub const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keeps a STREAM connection which is kept open between payments alive, by sending a
+/// zero-amount packet without any frames over it whenever it has been idle for the
+/// configured interval. This way NATs, BTP sessions and any state kept along the path do
+/// not silently expire. Receivers answer such packets with a Reject right away.
+///
+/// Senders reusing the connection should call [`record_activity`](#method.record_activity)
+/// after each payment, so that keep-alives are only sent while the connection is idle.
+/// The keep-alives stop when this is dropped.
+pub struct KeepAlive {
+    last_activity: Arc<Mutex<Instant>>,
+    answered: Arc<AtomicBool>,
+    on_broken: Arc<Mutex<Option<BrokenHandler>>>,
+    abort_handle: AbortHandle,
+}
+
+/// Called once the given number of keep-alives in a row went unanswered
+#[derive(Clone)]
+struct BrokenHandler {
+    max_missed: u32,
+    callback: Arc<dyn Fn() + Send + Sync>,
+}
+
+impl KeepAlive {
+    /// Starts sending keep-alive packets to the destination of the connection through the
+    /// given service, every `interval` while the connection is idle
+    pub fn start<I, A>(
+        service: I,
+        from_account: A,
+        destination_account: Address,
+        shared_secret: Vec<u8>,
+        interval: Duration,
+    ) -> Self
+    where
+        I: IncomingService<A> + Send + Sync + 'static,
+        A: Account + Send + Sync + 'static,
+    {
+        let last_activity = Arc::new(Mutex::new(Instant::now()));
+        let answered = Arc::new(AtomicBool::new(true));
+        let on_broken = Arc::new(Mutex::new(None));
+        let (task, abort_handle) = abortable(send_keep_alives(
+            service,
+            from_account,
+            destination_account,
+            shared_secret,
+            interval,
+            last_activity.clone(),
+            answered.clone(),
+            on_broken.clone(),
+        ));
+        tokio::spawn(task);
+        KeepAlive {
+            last_activity,
+            answered,
+            on_broken,
+            abort_handle,
+        }
+    }
+
+    /// Calls `callback` when `max_missed` keep-alive packets in a row go unanswered, which
+    /// means the path to the receiver is broken. It is called again if the receiver answers
+    /// in the meantime and the keep-alives go unanswered again
+    pub fn on_broken<F>(self, max_missed: u32, callback: F) -> Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        *self.on_broken.lock() = Some(BrokenHandler {
+            max_missed: max_missed.max(1),
+            callback: Arc::new(callback),
+        });
+        self
+    }
+
+    /// Records that the connection was just used, which postpones the next keep-alive
+    pub fn record_activity(&self) {
+        *self.last_activity.lock() = Instant::now();
+    }
+
+    /// Whether the receiver answered the last keep-alive packet (or none was sent yet)
+    pub fn is_receiver_reachable(&self) -> bool {
+        self.answered.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for KeepAlive {
+    fn drop(&mut self) {
+        self.abort_handle.abort();
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_keep_alives<I, A>(
+    mut service: I,
+    from_account: A,
+    destination_account: Address,
+    shared_secret: Vec<u8>,
+    interval: Duration,
+    last_activity: Arc<Mutex<Instant>>,
+    answered: Arc<AtomicBool>,
+    on_broken: Arc<Mutex<Option<BrokenHandler>>>,
+) where
+    I: IncomingService<A>,
+    A: Account,
+{
+    let mut encoder = StreamPacketEncoder::default();
+    let mut sequence = 0;
+    let mut missed = 0;
+    loop {
+        let next_keep_alive = *last_activity.lock() + interval;
+        if Instant::now() < next_keep_alive {
+            delay_until(next_keep_alive).await;
+            continue;
+        }
+
+        sequence += 1;
+        let prepare = encoder.encode_prepare(
+            &StreamPacketBuilder {
+                ilp_packet_type: IlpPacketType::Prepare,
+                prepare_amount: 0,
+                sequence,
+                frames: &[],
+            },
+            &shared_secret,
+            &PrepareBuilder {
+                destination: destination_account.clone(),
+                amount: 0,
+                execution_condition: &random_condition(),
+                expires_at: SystemTime::now() + Duration::from_secs(30),
+                data: &[],
+            },
+        );
+        debug!("Sending keep-alive packet {}", sequence);
+        let reply = service
+            .handle_request(IncomingRequest {
+                from: from_account.clone(),
+                prepare,
+            })
+            .await;
+        *last_activity.lock() = Instant::now();
+
+        // The receiver encrypts its answer, which no node on the path could forge
+        let reply_data = match &reply {
+            Ok(fulfill) => fulfill.data(),
+            Err(reject) => reject.data(),
+        };
+        let is_answered = StreamPacket::from_encrypted(&shared_secret, BytesMut::from(reply_data))
+            .map(|packet| packet.sequence() == sequence)
+            .unwrap_or(false);
+        if !is_answered {
+            warn!(
+                "Receiver {} did not answer keep-alive packet {}",
+                destination_account, sequence
+            );
+        }
+        answered.store(is_answered, Ordering::Relaxed);
+
+        missed = if is_answered { 0 } else { missed + 1 };
+        let on_broken = on_broken.lock().clone();
+        if let Some(on_broken) = on_broken {
+            if missed == on_broken.max_missed {
+                warn!(
+                    "Connection to {} is broken, {} keep-alive packets in a row went unanswered",
+                    destination_account, missed
+                );
+                (on_broken.callback)();
+            }
+        }
+    }
+}
+
+/// Tracks, on the receiver's side, the connections whose senders keep them alive, and
+/// notifies when their keep-alive packets stop arriving, which means the path from the
+/// sender is broken.
+///
+/// Connections are tracked from their first keep-alive packet until they are closed or
+/// deemed broken, so that the connections used for a single payment are not reported.
+/// The broken connections are counted in the `stream.receiver.broken_connections` metric.
+#[derive(Clone)]
+pub struct KeepAliveMonitor {
+    timeout: Duration,
+    last_seen: Arc<Mutex<HashMap<Address, Instant>>>,
+    on_broken: Arc<dyn Fn(&Address) + Send + Sync>,
+}
+
+impl KeepAliveMonitor {
+    /// Calls `on_broken` with the destination address of each connection which is not heard
+    /// from within `timeout`, which should be a few times the senders' keep-alive interval
+    pub fn new<F>(timeout: Duration, on_broken: F) -> Self
+    where
+        F: Fn(&Address) + Send + Sync + 'static,
+    {
+        KeepAliveMonitor {
+            timeout,
+            last_seen: Arc::new(Mutex::new(HashMap::new())),
+            on_broken: Arc::new(on_broken),
+        }
+    }
+
+    /// Starts (or keeps) tracking the connection which sent a keep-alive packet
+    pub(crate) fn record_keep_alive(&self, connection: &Address) {
+        self.last_seen
+            .lock()
+            .insert(connection.clone(), Instant::now());
+    }
+
+    /// Records another packet received over the connection, if it is tracked
+    pub(crate) fn record_activity(&self, connection: &Address) {
+        if let Some(last_seen) = self.last_seen.lock().get_mut(connection) {
+            *last_seen = Instant::now();
+        }
+    }
+
+    /// Stops tracking the connection, once it is closed
+    pub(crate) fn forget(&self, connection: &Address) {
+        self.last_seen.lock().remove(connection);
+    }
+
+    /// Notifies the connections which were not heard from within the timeout as broken,
+    /// and stops tracking them
+    pub fn check(&self) {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&self, now: Instant) {
+        let timeout = self.timeout;
+        let mut broken = Vec::new();
+        self.last_seen.lock().retain(|connection, last_seen| {
+            let is_alive = now.saturating_duration_since(*last_seen) < timeout;
+            if !is_alive {
+                broken.push(connection.clone());
+            }
+            is_alive
+        });
+        for connection in broken {
+            warn!(
+                "Connection {} is broken, no keep-alive packet was received for {:?}",
+                connection, timeout
+            );
+            recorder().increment_counter(Key::from_name("stream.receiver.broken_connections"), 1);
+            (self.on_broken)(&connection);
+        }
+    }
+
+    /// Returns a future that checks the connections for broken ones every `timeout`
+    pub async fn start_checks(&self) {
+        let mut interval = tokio::time::interval(self.timeout);
+        loop {
+            interval.tick().await;
+            self.check();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{TestAccount, EXAMPLE_CONNECTOR};
+    use interledger_packet::{ErrorCode, RejectBuilder};
+    use interledger_service::incoming_service_fn;
+    use std::str::FromStr;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::time::delay_for;
+    use uuid::Uuid;
+
+    fn test_account() -> TestAccount {
+        TestAccount {
+            id: Uuid::new_v4(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            ilp_address: Address::from_str("example.sender").unwrap(),
+            max_packet_amount: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_keep_alives_while_idle() {
+        tokio::time::pause();
+        let packets = Arc::new(AtomicUsize::new(0));
+        let packets_clone = packets.clone();
+        let service = incoming_service_fn(move |request| {
+            assert_eq!(request.prepare.amount(), 0);
+            packets_clone.fetch_add(1, Ordering::SeqCst);
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: &[],
+                triggered_by: Some(&EXAMPLE_CONNECTOR),
+                data: &[],
+            }
+            .build())
+        });
+
+        let keep_alive = KeepAlive::start(
+            service,
+            test_account(),
+            Address::from_str("example.receiver").unwrap(),
+            vec![0; 32],
+            Duration::from_millis(100),
+        );
+        delay_for(Duration::from_millis(250)).await;
+        assert_eq!(packets.load(Ordering::SeqCst), 2);
+        assert!(!keep_alive.is_receiver_reachable());
+
+        // Activity postpones the next keep-alive
+        keep_alive.record_activity();
+        delay_for(Duration::from_millis(50)).await;
+        assert_eq!(packets.load(Ordering::SeqCst), 2);
+
+        drop(keep_alive);
+        delay_for(Duration::from_millis(200)).await;
+        assert_eq!(packets.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn notifies_broken_connections() {
+        tokio::time::pause();
+        let service = incoming_service_fn(move |_| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: &[],
+                triggered_by: Some(&EXAMPLE_CONNECTOR),
+                data: &[],
+            }
+            .build())
+        });
+        let broken = Arc::new(AtomicUsize::new(0));
+        let broken_clone = broken.clone();
+        let _keep_alive = KeepAlive::start(
+            service,
+            test_account(),
+            Address::from_str("example.receiver").unwrap(),
+            vec![0; 32],
+            Duration::from_millis(50),
+        )
+        .on_broken(3, move || {
+            broken_clone.fetch_add(1, Ordering::SeqCst);
+        });
+        delay_for(Duration::from_millis(125)).await;
+        assert_eq!(broken.load(Ordering::SeqCst), 0);
+        delay_for(Duration::from_millis(100)).await;
+        // Only notified once while the keep-alives keep going unanswered
+        assert_eq!(broken.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn monitors_connections_which_are_kept_alive() {
+        let broken = Arc::new(Mutex::new(Vec::new()));
+        let broken_clone = broken.clone();
+        let monitor = KeepAliveMonitor::new(Duration::from_secs(60), move |connection| {
+            broken_clone.lock().push(connection.clone())
+        });
+        let kept_alive = Address::from_str("example.receiver.kept-alive").unwrap();
+        let single_payment = Address::from_str("example.receiver.single-payment").unwrap();
+        let closed = Address::from_str("example.receiver.closed").unwrap();
+
+        monitor.record_keep_alive(&kept_alive);
+        monitor.record_activity(&single_payment);
+        monitor.record_keep_alive(&closed);
+        monitor.forget(&closed);
+
+        monitor.check();
+        assert!(broken.lock().is_empty());
+        monitor.check_at(Instant::now() + Duration::from_secs(61));
+        assert_eq!(*broken.lock(), vec![kept_alive.clone()]);
+        // Broken connections are only reported once
+        monitor.check_at(Instant::now() + Duration::from_secs(122));
+        assert_eq!(broken.lock().len(), 1);
+    }
+}
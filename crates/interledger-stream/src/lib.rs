@@ -10,20 +10,64 @@ mod client;
 mod congestion;
 /// Cryptographic utilities for generating fulfillments and encrypting/decrypting STREAM packets
 mod crypto;
+/// Negative cache of the connections whose packets repeatedly fail to decrypt
+mod dampening;
+/// Dispatch of payments received over tagged connections to sub-accounts
+mod dispatch;
 /// Stream errors
 mod error;
+/// Keep-alive packets for idle connections which are kept open between payments
+mod keepalive;
 /// Stream Packet implementation, [as specified in the RFC](https://interledger.org/rfcs/0029-stream/#5-packet-and-frame-specification)
 mod packet;
+/// Probing of the exchange rate and maximum packet amount of the path to a receiver
+mod probe;
+/// Receipts of the amounts received, signed for the verifiers the senders prove their payments to
+mod receipt;
+/// Tracking of the amounts received over tagged connections, against their receive max
+mod receive_max;
+/// Protection of the receiver against replayed packets
+mod replay;
 /// A stream server implementing an [Outgoing Service](../interledger_service/trait.OutgoingService.html) for receiving STREAM payments from peers
 mod server;
+/// Deterministic simulations of the congestion controller against modeled network paths
+#[cfg(any(test, feature = "simulation"))]
+pub mod simulation;
+/// Streaming of money at a target rate, until the payment is stopped
+mod streaming;
+
+/// Internals of the STREAM packets, exposed to the benchmarks
+#[cfg(feature = "benchmarks")]
+pub mod bench {
+    pub use super::packet::{
+        Frame, StreamMoneyFrame, StreamPacket, StreamPacketBuilder, StreamPacketDecoder,
+        StreamPacketEncoder,
+    };
+}
 
-pub use client::{send_money, StreamDelivery};
+pub use client::{
+    send_money, send_money_with_options, MoneyStream, SendMoneyOptions, StreamDelivery,
+    StreamWarning,
+};
+pub use dampening::DampeningPolicy;
+pub use dispatch::{SubAccountStore, TagDispatchService, TagRoute};
 pub use error::{Error, StreamPacketError};
+pub use keepalive::{KeepAlive, KeepAliveMonitor, DEFAULT_KEEP_ALIVE_INTERVAL};
+pub use packet::{
+    Frame, FrameType, StreamPacket, StreamPacketLimits, StreamPadding, DEFAULT_MAX_FRAMES,
+    DEFAULT_MAX_FRAME_SIZE,
+};
+pub use probe::{rate_probe, PathStats};
+pub use receipt::{ReceiptDetails, RECEIPT_NONCE_LEN, RECEIPT_SECRET_LEN};
+pub use receive_max::{ConnectionReceipts, StreamReceiveStore};
+pub use replay::{fulfilled_packet_key, StreamReplayStore};
 pub use server::{
-    ConnectionGenerator, PaymentNotification, StreamNotificationsStore, StreamReceiverService,
+    connection_tag, ConnectionGenerator, PaymentNotification, StreamNotificationsStore,
+    StreamReceiverService,
 };
+pub use streaming::{stream_money, StreamingPayment, DEFAULT_STREAMING_INTERVAL};
 
-#[cfg(fuzzing)]
+#[cfg(any(fuzzing, test))]
 pub fn fuzz_decrypted_stream_packet(data: &[u8]) {
     let b = bytes::BytesMut::from(data);
     if let Ok(pkt) = packet::StreamPacket::from_decrypted(b) {
@@ -39,6 +83,35 @@ pub fn fuzz_decrypted_stream_packet(data: &[u8]) {
     }
 }
 
+/// Reads a frame whose type is the first byte of `data` from the rest of it, checking
+/// that the frame is read back the same once serialized again
+#[cfg(any(fuzzing, test))]
+pub fn fuzz_stream_frame(data: &[u8]) {
+    if let Some((frame_type, contents)) = data.split_first() {
+        if let Ok(frame) = packet::read_frame(*frame_type, contents) {
+            let mut serialized = Vec::new();
+            let frame_type = packet::put_frame_contents(&mut serialized, &frame);
+            let other = packet::read_frame(frame_type, &serialized)
+                .expect("Serialized frame should be valid");
+
+            assert_eq!(frame, other);
+        }
+    }
+}
+
+#[cfg(any(fuzzing, test))]
+pub fn fuzz_saturating_read_var_uint(data: &[u8]) {
+    use interledger_packet::oer::BufOerExt;
+
+    if let Ok(value) = packet::saturating_read_var_uint(&mut &data[..]) {
+        // Only the integers which do not fit in a u64 saturate
+        match (&data[..]).read_var_uint() {
+            Ok(exact) => assert_eq!(value, exact),
+            Err(_) => assert_eq!(value, u64::MAX),
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod test_helpers {
     use super::*;
@@ -49,7 +122,7 @@ pub mod test_helpers {
     use interledger_rates::ExchangeRateStore;
     use interledger_router::RouterStore;
     use interledger_service::{Account, AccountStore, AddressStore, Username};
-    use interledger_service_util::MaxPacketAmountAccount;
+    use interledger_service_util::{MaxPacketAmountAccount, SpreadAccount};
     use once_cell::sync::Lazy;
     use std::collections::HashMap;
     use std::str::FromStr;
@@ -100,6 +173,8 @@ pub mod test_helpers {
         }
     }
 
+    impl SpreadAccount for TestAccount {}
+
     #[derive(Clone)]
     pub struct DummyStore;
 
@@ -199,6 +274,17 @@ pub mod test_helpers {
         fn get_all_exchange_rates(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError> {
             unimplemented!("Cannot get all exchange rates")
         }
+
+        fn set_rate_overrides(
+            &self,
+            _overrides: HashMap<String, f64>,
+        ) -> Result<(), ExchangeRateStoreError> {
+            unimplemented!("Cannot set rate overrides")
+        }
+
+        fn get_rate_overrides(&self) -> Result<HashMap<String, f64>, ExchangeRateStoreError> {
+            Ok(HashMap::new())
+        }
     }
 }
 
@@ -210,9 +296,10 @@ mod send_money_to_receiver {
     use interledger_packet::Address;
     use interledger_packet::{ErrorCode, RejectBuilder};
     use interledger_router::Router;
-    use interledger_service::outgoing_service_fn;
-    use interledger_service_util::ExchangeRateService;
+    use interledger_service::{incoming_service_fn, outgoing_service_fn};
+    use interledger_service_util::{ExchangeRateService, MaxPacketAmountService};
     use std::str::FromStr;
+    use tokio::time::{delay_for, Duration};
     use uuid::Uuid;
 
     #[tokio::test]
@@ -276,6 +363,69 @@ mod send_money_to_receiver {
         assert_eq!(receipt.delivered_amount, 100);
     }
 
+    #[tokio::test]
+    async fn send_padded_money() {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let destination_address = Address::from_str("example.receiver").unwrap();
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: destination_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+        let store = TestStore {
+            route: Some((destination_address.to_string(), account)),
+            price_1: None,
+            price_2: None,
+        };
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let server = StreamReceiverService::new(
+            server_secret,
+            DummyStore,
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let server = Router::new(store, server);
+
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&destination_address);
+
+        let receipt = send_money_with_options(
+            server,
+            &test_helpers::TestAccount {
+                id: Uuid::new_v4(),
+                asset_code: "XYZ".to_string(),
+                asset_scale: 9,
+                ilp_address: destination_address,
+                max_packet_amount: None,
+            },
+            TestStore {
+                route: None,
+                price_1: None,
+                price_2: None,
+            },
+            destination_account,
+            shared_secret.to_vec(),
+            100,
+            SendMoneyOptions {
+                padding: Some(StreamPadding::Buckets(vec![128, 256])),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(receipt.delivered_amount, 100);
+    }
+
     #[tokio::test]
     async fn payment_fails_if_large_spread() {
         let server_secret = Bytes::from(&[0; 32][..]);
@@ -342,4 +492,269 @@ mod send_money_to_receiver {
             _ => panic!("Payment should fail fast due to poor exchange rates"),
         }
     }
+
+    async fn deliver_fixed_amount(
+        destination_amount: u64,
+        max_source_amount: u64,
+    ) -> Result<StreamDelivery, Error> {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let source_address = Address::from_str("example.sender").unwrap();
+        let destination_address = Address::from_str("example.receiver").unwrap();
+
+        let sender_account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: source_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 6,
+            max_packet_amount: None,
+        };
+
+        let recipient_account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: destination_address.clone(),
+            asset_code: "ABC".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+
+        let store = TestStore {
+            route: Some((destination_address.to_string(), recipient_account)),
+            price_1: Some(1.0),
+            price_2: Some(1.0),
+        };
+
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let server = StreamReceiverService::new(
+            server_secret,
+            DummyStore,
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+
+        // The connector takes a 1% spread, so each source unit delivers 990 destination units
+        let server = ExchangeRateService::new(0.01, store.clone(), server);
+        let server = Router::new(store.clone(), server);
+
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&destination_address);
+
+        send_money_with_options(
+            server,
+            &sender_account,
+            store,
+            destination_account,
+            shared_secret.to_vec(),
+            max_source_amount,
+            SendMoneyOptions {
+                slippage: 0.015,
+                destination_amount: Some(destination_amount),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn delivers_fixed_destination_amount() {
+        let receipt = deliver_fixed_amount(500_000, 10_000).await.unwrap();
+
+        // A probe finds the rate, then a single packet delivers the amount, overshooting it by
+        // less than one source unit's worth
+        assert!(receipt.delivered_amount >= 500_000);
+        assert!(receipt.delivered_amount < 500_000 + 990);
+        assert_eq!(receipt.sent_amount, 506);
+        assert_eq!(receipt.in_flight_amount, 0);
+    }
+
+    #[tokio::test]
+    async fn fails_if_max_source_amount_cannot_deliver_destination_amount() {
+        match deliver_fixed_amount(500_000, 100).await {
+            Err(Error::DestinationAmountNotDelivered(delivered, 500_000)) => {
+                // All of the 100 source units were delivered, less the rounding of each packet
+                assert!(delivered > 98_000 && delivered <= 99_000)
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+    #[tokio::test]
+    async fn probes_the_path_before_sending() {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let destination_address = Address::from_str("example.receiver").unwrap();
+        let sender_account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: Address::from_str("example.sender").unwrap(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 6,
+            max_packet_amount: Some(1000),
+        };
+        let recipient_account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: destination_address.clone(),
+            asset_code: "ABC".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+        let store = TestStore {
+            route: Some((destination_address.to_string(), recipient_account)),
+            price_1: Some(1.0),
+            price_2: Some(1.0),
+        };
+
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let server = StreamReceiverService::new(
+            server_secret,
+            DummyStore,
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let server = ExchangeRateService::new(0.01, store.clone(), server);
+        let server = Router::new(store.clone(), server);
+        let server = MaxPacketAmountService::new(store.clone(), server);
+
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&destination_address);
+
+        let stats = rate_probe(
+            server.clone(),
+            &sender_account,
+            destination_account.clone(),
+            shared_secret.to_vec(),
+            100_000,
+        )
+        .await
+        .unwrap();
+        // The connector takes a 1% spread, so each source unit delivers 990 destination units
+        assert!(stats.rate > 989.0 && stats.rate <= 990.0);
+        assert_eq!(stats.max_packet_amount, Some(1000));
+
+        let receipt = send_money_with_options(
+            server,
+            &sender_account,
+            store,
+            destination_account,
+            shared_secret.to_vec(),
+            5000,
+            SendMoneyOptions {
+                slippage: 0.015,
+                path_stats: Some(stats),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(receipt.sent_amount, 5000);
+        assert!(receipt.delivered_amount >= 4_940_000);
+    }
+
+    #[tokio::test]
+    async fn rate_probe_fails_if_receiver_is_unreachable() {
+        let sender_account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: Address::from_str("example.sender").unwrap(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 6,
+            max_packet_amount: None,
+        };
+        let server = incoming_service_fn(|_| {
+            Err(RejectBuilder {
+                code: ErrorCode::F02_UNREACHABLE,
+                message: &[],
+                triggered_by: Some(&EXAMPLE_CONNECTOR),
+                data: &[],
+            }
+            .build())
+        });
+
+        let result = rate_probe(
+            server,
+            &sender_account,
+            Address::from_str("example.receiver").unwrap(),
+            vec![0; 32],
+            1000,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::RateProbeFailed)));
+    }
+
+    #[tokio::test]
+    async fn streams_money_at_a_rate_until_stopped() {
+        let server_secret = Bytes::from(&[0; 32][..]);
+        let destination_address = Address::from_str("example.receiver").unwrap();
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: destination_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+        let store = TestStore {
+            route: Some((destination_address.to_string(), account.clone())),
+            price_1: None,
+            price_2: None,
+        };
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let server = StreamReceiverService::new(
+            server_secret,
+            DummyStore,
+            outgoing_service_fn(|_| {
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: b"No other outgoing handler",
+                    triggered_by: Some(&EXAMPLE_RECEIVER),
+                    data: &[],
+                }
+                .build())
+            }),
+        );
+        let server = Router::new(store, server);
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&destination_address);
+
+        let payment = stream_money(
+            server,
+            &account,
+            TestStore {
+                route: None,
+                price_1: None,
+                price_2: None,
+            },
+            destination_account,
+            shared_secret.to_vec(),
+            10_000,
+            Duration::from_millis(50),
+            SendMoneyOptions::default(),
+        );
+        let mut receipts = payment.receipts();
+        delay_for(Duration::from_millis(275)).await;
+        let receipt = receipts.recv().await.unwrap();
+        assert!(receipt.delivered_amount >= 2000);
+        assert_eq!(receipt.delivered_amount, receipt.source_amount);
+
+        // Pausing stops the money from flowing, while the connection stays open
+        payment.set_rate(0);
+        delay_for(Duration::from_millis(100)).await;
+        let paused_amount = payment.receipt().delivered_amount;
+        delay_for(Duration::from_millis(150)).await;
+        assert_eq!(payment.receipt().delivered_amount, paused_amount);
+
+        payment.set_rate(20_000);
+        delay_for(Duration::from_millis(125)).await;
+        let receipt = payment.stop().await.unwrap();
+        assert!(receipt.delivered_amount >= paused_amount + 1000);
+        assert_eq!(receipt.delivered_amount, receipt.source_amount);
+    }
 }
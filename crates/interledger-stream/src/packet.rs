@@ -1,14 +1,15 @@
 use super::{
-    crypto::{decrypt, encrypt},
+    crypto::{decrypt, decrypt_into, encrypt, encrypt_into, ENCRYPTION_OVERHEAD},
     StreamPacketError,
 };
 use bytes::{Buf, BufMut, BytesMut};
 use interledger_packet::{
     oer::{self, BufOerExt, MutBufOerExt},
-    Address, OerError, PacketType as IlpPacketType,
+    Address, OerError, PacketType as IlpPacketType, Prepare, PrepareBuilder,
 };
 #[cfg(test)]
 use once_cell::sync::Lazy;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use std::{convert::TryFrom, fmt, str, u64};
 use tracing::warn;
 
@@ -18,6 +19,38 @@ const STREAM_VERSION: u8 = 1;
 /// Length of the stream protocol version on the wire
 const STREAM_VERSION_LEN: usize = 1;
 
+/// Default maximum number of frames accepted in a single Stream Packet
+pub const DEFAULT_MAX_FRAMES: u64 = 256;
+
+/// Default maximum size of the contents of a single frame. This is the maximum
+/// size of the data field of an ILP Prepare, so it will never be reached by valid packets.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 32_767;
+
+/// Limits enforced while parsing [Stream Packets](./struct.StreamPacket.html), protecting
+/// the receiver against packets claiming a huge number of frames or oversized frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamPacketLimits {
+    /// Maximum number of frames a packet may declare
+    pub max_frames: u64,
+    /// Maximum length in bytes of the contents of any single frame
+    pub max_frame_size: usize,
+    /// Whether a packet with a frame which fails to parse is rejected with the error of
+    /// that frame (see [`StreamPacketError::InvalidFrame`](./enum.StreamPacketError.html)),
+    /// and receivers answer it with an F06 error rather than passing it on as if it was
+    /// not addressed to them
+    pub strict_frames: bool,
+}
+
+impl Default for StreamPacketLimits {
+    fn default() -> Self {
+        StreamPacketLimits {
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            strict_frames: false,
+        }
+    }
+}
+
 /// Builder for [Stream Packets](https://interledger.org/rfcs/0029-stream/#52-stream-packet)
 pub struct StreamPacketBuilder<'a> {
     /// The stream packet's sequence number
@@ -35,90 +68,291 @@ pub struct StreamPacketBuilder<'a> {
 impl<'a> StreamPacketBuilder<'a> {
     /// Serializes the builder into a Stream Packet
     pub fn build(&self) -> StreamPacket {
-        let mut buffer_unencrypted = Vec::with_capacity(26);
+        let mut buffer_unencrypted = BytesMut::with_capacity(26);
+        // The contents buffer is only used, and allocated, to pad packets
+        let frames_offset = self.put_packet(&mut buffer_unencrypted, &mut Vec::new(), None);
+
+        StreamPacket {
+            buffer_unencrypted,
+            sequence: self.sequence,
+            ilp_packet_type: self.ilp_packet_type,
+            prepare_amount: self.prepare_amount,
+            frames_offset,
+        }
+    }
+
+    /// Serializes the builder into a Stream Packet padded with
+    /// padding frames, so that once encrypted it is
+    /// as long as the `padding` requires
+    pub fn build_padded(&self, padding: &StreamPadding) -> StreamPacket {
+        let mut buffer_unencrypted = BytesMut::with_capacity(26);
+        let frames_offset =
+            self.put_packet(&mut buffer_unencrypted, &mut Vec::new(), Some(padding));
+
+        StreamPacket {
+            buffer_unencrypted,
+            sequence: self.sequence,
+            ilp_packet_type: self.ilp_packet_type,
+            prepare_amount: self.prepare_amount,
+            frames_offset,
+        }
+    }
+
+    /// Appends the serialized packet to the buffer, followed by the frames padding it if
+    /// `padding` is given, in which case `contents` is used to measure the other frames.
+    /// Returns the offset at which the frames start
+    fn put_packet<B>(
+        &self,
+        buffer_unencrypted: &mut B,
+        contents: &mut Vec<u8>,
+        padding: Option<&StreamPadding>,
+    ) -> usize
+    where
+        B: BufMut + AsRef<[u8]> + AsMut<[u8]>,
+    {
+        let padding_frames = padding
+            .map(|padding| self.padding_frames(padding, contents))
+            .unwrap_or_default();
+        let num_padding_frames = padding_frames.iter().flatten().count();
 
         buffer_unencrypted.put_u8(STREAM_VERSION);
         buffer_unencrypted.put_u8(self.ilp_packet_type as u8);
         buffer_unencrypted.put_var_uint(self.sequence);
         buffer_unencrypted.put_var_uint(self.prepare_amount);
-        buffer_unencrypted.put_var_uint(self.frames.len() as u64);
-        let frames_offset = buffer_unencrypted.len();
+        buffer_unencrypted.put_var_uint((self.frames.len() + num_padding_frames) as u64);
+        let frames_offset = buffer_unencrypted.as_ref().len();
 
         for frame in self.frames {
-            let mut contents = Vec::new();
-            match frame {
-                Frame::ConnectionClose(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::ConnectionClose as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::ConnectionNewAddress(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::ConnectionNewAddress as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::ConnectionAssetDetails(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::ConnectionAssetDetails as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::ConnectionMaxData(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::ConnectionMaxData as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::ConnectionDataBlocked(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::ConnectionDataBlocked as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::ConnectionMaxStreamId(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::ConnectionMaxStreamId as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::ConnectionStreamIdBlocked(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::ConnectionStreamIdBlocked as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::StreamClose(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::StreamClose as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::StreamMoney(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::StreamMoney as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::StreamMaxMoney(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::StreamMaxMoney as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::StreamMoneyBlocked(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::StreamMoneyBlocked as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::StreamData(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::StreamData as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::StreamMaxData(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::StreamMaxData as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::StreamDataBlocked(ref frame) => {
-                    buffer_unencrypted.put_u8(FrameType::StreamDataBlocked as u8);
-                    frame.put_contents(&mut contents);
-                }
-                Frame::Unknown(ref unknown_frame) => {
-                    // The frame type u8 was stored and handled by UnknownFrameData
-                    buffer_unencrypted.put_u8(unknown_frame.frame_type);
-                    unknown_frame.put_contents(&mut contents);
-                }
-            }
-            buffer_unencrypted.put_var_octet_string(&*contents);
+            put_frame(buffer_unencrypted, frame);
+        }
+        for frame in padding_frames.iter().flatten() {
+            put_frame(buffer_unencrypted, &Frame::Padding(*frame));
         }
 
-        StreamPacket {
-            buffer_unencrypted: BytesMut::from(&buffer_unencrypted[..]),
-            sequence: self.sequence,
-            ilp_packet_type: self.ilp_packet_type,
-            prepare_amount: self.prepare_amount,
-            frames_offset,
+        frames_offset
+    }
+
+    /// Length of the serialized packet header, when it declares `num_frames` frames
+    fn header_len(&self, num_frames: usize) -> usize {
+        let var_uint_len = |value: u64| 1 + oer::predict_var_uint_size(value) as usize;
+        STREAM_VERSION_LEN
+            + 1
+            + var_uint_len(self.sequence)
+            + var_uint_len(self.prepare_amount)
+            + var_uint_len(num_frames as u64)
+    }
+
+    /// Returns the (at most two) padding frames which grow the packet to the size the
+    /// `padding` requires once encrypted
+    fn padding_frames(
+        &self,
+        padding: &StreamPadding,
+        contents: &mut Vec<u8>,
+    ) -> [Option<PaddingFrame>; 2] {
+        let mut frames_len = 0;
+        for frame in self.frames {
+            contents.clear();
+            put_frame_contents(contents, frame);
+            frames_len += 1 + oer::predict_var_octet_string(contents.len());
         }
+        let unpadded_len = |num_padding_frames: usize| {
+            self.header_len(self.frames.len() + num_padding_frames)
+                + frames_len
+                + ENCRYPTION_OVERHEAD
+        };
+
+        // Even the smallest padding frame takes 2 bytes
+        let target_len = padding.padded_len(unpadded_len(1) + 2);
+        if let Some(frame) = PaddingFrame::with_size(target_len - unpadded_len(1)) {
+            return [Some(frame), None];
+        }
+        // Some sizes cannot be taken by a single frame, because of the length prefix of its
+        // contents, but can by an empty frame followed by a larger one
+        let remaining = target_len - unpadded_len(2) - 2;
+        [
+            Some(PaddingFrame { length: 0 }),
+            PaddingFrame::with_size(remaining),
+        ]
+    }
+}
+
+/// Serializes the frame at the end of the buffer. Its contents are serialized in place and
+/// then moved to make room for their length prefix, which is only known once they are written
+fn put_frame<B>(buffer_unencrypted: &mut B, frame: &Frame)
+where
+    B: BufMut + AsRef<[u8]> + AsMut<[u8]>,
+{
+    let type_offset = buffer_unencrypted.as_ref().len();
+    buffer_unencrypted.put_u8(0);
+    let frame_type = put_frame_contents(buffer_unencrypted, frame);
+
+    let contents_offset = type_offset + 1;
+    let contents_len = buffer_unencrypted.as_ref().len() - contents_offset;
+    let prefix_len = oer::predict_var_octet_string(contents_len) - contents_len;
+    buffer_unencrypted.put_slice(&[0; 9][..prefix_len]);
+
+    let buffer = buffer_unencrypted.as_mut();
+    buffer.copy_within(
+        contents_offset..contents_offset + contents_len,
+        contents_offset + prefix_len,
+    );
+    buffer[type_offset] = frame_type;
+    (&mut buffer[contents_offset..contents_offset + prefix_len])
+        .put_var_octet_string_length(contents_len);
+}
+
+/// Appends the serialized contents of the frame to the buffer. Returns the frame's type
+pub(crate) fn put_frame_contents<B: BufMut>(contents: &mut B, frame: &Frame) -> u8 {
+    match frame {
+        Frame::ConnectionClose(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::ConnectionClose as u8
+        }
+        Frame::ConnectionNewAddress(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::ConnectionNewAddress as u8
+        }
+        Frame::ConnectionAssetDetails(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::ConnectionAssetDetails as u8
+        }
+        Frame::ConnectionMaxData(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::ConnectionMaxData as u8
+        }
+        Frame::ConnectionDataBlocked(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::ConnectionDataBlocked as u8
+        }
+        Frame::ConnectionMaxStreamId(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::ConnectionMaxStreamId as u8
+        }
+        Frame::ConnectionStreamIdBlocked(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::ConnectionStreamIdBlocked as u8
+        }
+        Frame::StreamClose(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::StreamClose as u8
+        }
+        Frame::StreamMoney(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::StreamMoney as u8
+        }
+        Frame::StreamMaxMoney(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::StreamMaxMoney as u8
+        }
+        Frame::StreamMoneyBlocked(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::StreamMoneyBlocked as u8
+        }
+        Frame::StreamData(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::StreamData as u8
+        }
+        Frame::StreamMaxData(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::StreamMaxData as u8
+        }
+        Frame::StreamDataBlocked(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::StreamDataBlocked as u8
+        }
+        Frame::StreamReceipt(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::StreamReceipt as u8
+        }
+        Frame::Padding(ref frame) => {
+            frame.put_contents(contents);
+            FrameType::Padding as u8
+        }
+        Frame::Unknown(ref unknown_frame) => {
+            // The frame type u8 was stored and handled by UnknownFrameData
+            unknown_frame.put_contents(contents);
+            unknown_frame.frame_type
+        }
+    }
+}
+
+/// Serializes and encrypts Stream Packets directly into the data of ILP Prepare packets.
+///
+/// Unlike [`StreamPacketBuilder::build`](./struct.StreamPacketBuilder.html#method.build), which
+/// allocates new buffers for every packet that are then copied into the Prepare, the encoder
+/// keeps its buffers and reuses them for all the packets of a connection.
+#[derive(Debug, Default)]
+pub struct StreamPacketEncoder {
+    /// The cleartext serialized packet
+    plaintext: Vec<u8>,
+    /// The serialized contents of the frame being written
+    contents: Vec<u8>,
+    /// How the packets are padded, if at all
+    padding: Option<StreamPadding>,
+}
+
+impl StreamPacketEncoder {
+    /// Pads the packets as the `padding` requires
+    pub fn with_padding(mut self, padding: Option<StreamPadding>) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Serializes and encrypts the packet into the data of the Prepare built by the
+    /// `prepare` builder, whose `data` is ignored
+    pub fn encode_prepare(
+        &mut self,
+        packet: &StreamPacketBuilder,
+        shared_secret: &[u8],
+        prepare: &PrepareBuilder,
+    ) -> Prepare {
+        self.plaintext.clear();
+        packet.put_packet(
+            &mut self.plaintext,
+            &mut self.contents,
+            self.padding.as_ref(),
+        );
+        let plaintext = &self.plaintext;
+        prepare.build_with_data(plaintext.len() + ENCRYPTION_OVERHEAD, |data| {
+            encrypt_into(shared_secret, plaintext, data)
+        })
+    }
+}
+
+/// Decrypts and parses Stream Packets out of the data of ILP packets.
+///
+/// Unlike [`StreamPacket::from_encrypted`](./struct.StreamPacket.html#method.from_encrypted),
+/// which takes a copy of the data and rearranges it to decrypt it, the decoder copies the data
+/// once into a buffer it keeps, and decrypts it in place there. The memory of the packets it
+/// returns is reclaimed for the next ones once they are dropped, so that decoding the packets
+/// of a connection one after the other does not allocate.
+#[derive(Debug, Default)]
+pub struct StreamPacketDecoder {
+    /// The buffer the packets are decrypted into
+    buffer: BytesMut,
+    /// Limits enforced while parsing the packets
+    limits: StreamPacketLimits,
+}
+
+impl StreamPacketDecoder {
+    /// Enforces the given [limits](./struct.StreamPacketLimits.html) while parsing the packets
+    pub fn with_limits(mut self, limits: StreamPacketLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Decrypts and parses the packet from the data of an ILP packet
+    ///
+    /// # Errors
+    /// Same as [`StreamPacket::from_encrypted_with_limits`](./struct.StreamPacket.html#method.from_encrypted_with_limits)
+    pub fn decode(
+        &mut self,
+        shared_secret: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<StreamPacket, StreamPacketError> {
+        decrypt_into(shared_secret, ciphertext, &mut self.buffer)
+            .map_err(|_| StreamPacketError::FailedToDecrypt)?;
+        StreamPacket::from_bytes_unencrypted(self.buffer.split(), &self.limits)
     }
 }
 
@@ -150,25 +384,54 @@ impl StreamPacket {
     pub fn from_encrypted(
         shared_secret: &[u8],
         ciphertext: BytesMut,
+    ) -> Result<Self, StreamPacketError> {
+        Self::from_encrypted_with_limits(shared_secret, ciphertext, &StreamPacketLimits::default())
+    }
+
+    /// Constructs a [Stream Packet](./struct.StreamPacket.html) from an encrypted buffer
+    /// and a shared secret, enforcing the given [limits](./struct.StreamPacketLimits.html)
+    ///
+    /// # Errors
+    /// Same as [`from_encrypted`](#method.from_encrypted), and additionally if the packet
+    /// exceeds any of the limits
+    pub fn from_encrypted_with_limits(
+        shared_secret: &[u8],
+        ciphertext: BytesMut,
+        limits: &StreamPacketLimits,
     ) -> Result<Self, StreamPacketError> {
         // TODO handle decryption failure
         let decrypted =
             decrypt(shared_secret, ciphertext).map_err(|_| StreamPacketError::FailedToDecrypt)?;
-        StreamPacket::from_bytes_unencrypted(decrypted)
+        StreamPacket::from_bytes_unencrypted(decrypted, limits)
     }
 
-    #[cfg(any(fuzzing, test))]
+    /// Constructs a [Stream Packet](./struct.StreamPacket.html) from an already decrypted
+    /// buffer, e.g. to inspect packets decrypted elsewhere
+    ///
+    /// # Errors
+    /// Same as [`from_encrypted`](#method.from_encrypted), except for decryption
     pub fn from_decrypted(data: BytesMut) -> Result<Self, StreamPacketError> {
-        Self::from_bytes_unencrypted(data)
+        Self::from_bytes_unencrypted(data, &StreamPacketLimits::default())
+    }
+
+    #[cfg(test)]
+    pub fn from_decrypted_with_limits(
+        data: BytesMut,
+        limits: &StreamPacketLimits,
+    ) -> Result<Self, StreamPacketError> {
+        Self::from_bytes_unencrypted(data, limits)
     }
 
     /// Constructs a [Stream Packet](./struct.StreamPacket.html) from a buffer
     ///
     /// # Errors
     /// 1. If the version of Stream Protocol doesn't match the hardcoded [stream version](constant.STREAM_VERSION.html)
+    /// 1. If the packet declares more frames or contains larger frames than the limits allow
     /// 1. If the decrypted bytes cannot be parsed to an unencrypted [Stream Packet](./struct.StreamPacket.html)
-    fn from_bytes_unencrypted(mut buffer_unencrypted: BytesMut) -> Result<Self, StreamPacketError> {
-        // TODO don't copy the whole packet again
+    fn from_bytes_unencrypted(
+        mut buffer_unencrypted: BytesMut,
+        limits: &StreamPacketLimits,
+    ) -> Result<Self, StreamPacketError> {
         let mut reader = &buffer_unencrypted[..];
 
         const MIN_LEN: usize = STREAM_VERSION_LEN
@@ -185,11 +448,17 @@ impl StreamPacket {
             return Err(StreamPacketError::UnsupportedVersion(version));
         }
         let ilp_packet_type = IlpPacketType::try_from(reader.get_u8())?;
-        let sequence = reader.read_var_uint()?;
-        let prepare_amount = reader.read_var_uint()?;
+        let sequence = read_var_uint_field(&mut reader, "sequence")?;
+        let prepare_amount = read_var_uint_field(&mut reader, "prepare_amount")?;
 
         // TODO save num_frames?
-        let num_frames = reader.read_var_uint()?;
+        let num_frames = read_var_uint_field(&mut reader, "num_frames")?;
+        if num_frames > limits.max_frames {
+            return Err(StreamPacketError::TooManyFrames(
+                num_frames,
+                limits.max_frames,
+            ));
+        }
         let frames_offset = buffer_unencrypted.len() - reader.len();
 
         let mut reader = &buffer_unencrypted[frames_offset..];
@@ -198,6 +467,13 @@ impl StreamPacket {
             // to get to junk_data.
             // First byte is the frame type
             reader.skip(1)?;
+            let frame_size = reader.peek_var_octet_string()?.len();
+            if frame_size > limits.max_frame_size {
+                return Err(StreamPacketError::FrameTooLarge(
+                    frame_size,
+                    limits.max_frame_size,
+                ));
+            }
             reader.skip_var_octet_string()?;
         }
 
@@ -206,16 +482,23 @@ impl StreamPacket {
         if junk_data_len > 0 {
             // trailing bytes are supported for future compatibility, see
             // https://github.com/interledger/rfcs/blob/master/0029-stream/0029-stream.md#52-stream-packet
-            let _ = buffer_unencrypted.split_off(buffer_unencrypted.len() - junk_data_len);
+            buffer_unencrypted.truncate(buffer_unencrypted.len() - junk_data_len);
         }
 
-        if num_frames
-            == (FrameIterator {
+        // Try reading through all the frames to make sure they can be parsed correctly
+        let valid_frames = if limits.strict_frames {
+            let mut frames = CheckedFrameIterator {
+                buffer: &buffer_unencrypted[frames_offset..],
+                index: 0,
+            };
+            frames.try_fold(0, |count, frame| frame.map(|_| count + 1))?
+        } else {
+            (FrameIterator {
                 buffer: &buffer_unencrypted[frames_offset..],
             })
             .count() as u64
-        {
-            // Try reading through all the frames to make sure they can be parsed correctly
+        };
+        if num_frames == valid_frames {
             Ok(StreamPacket {
                 buffer_unencrypted,
                 sequence,
@@ -255,6 +538,15 @@ impl StreamPacket {
             buffer: &self.buffer_unencrypted[self.frames_offset..],
         }
     }
+
+    /// Returns a [CheckedFrameIterator](./struct.CheckedFrameIterator.html) over the packet's
+    /// [frames](./enum.Frame.html), which yields the error of the first frame that fails to parse
+    pub fn frames_checked(&self) -> CheckedFrameIterator {
+        CheckedFrameIterator {
+            buffer: &self.buffer_unencrypted[self.frames_offset..],
+            index: 0,
+        }
+    }
 }
 
 impl fmt::Debug for StreamPacket {
@@ -270,69 +562,91 @@ impl fmt::Debug for StreamPacket {
     }
 }
 
+/// Prints the packet on multiple lines, one per frame, including the frames which fail
+/// to parse
+impl fmt::Display for StreamPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "STREAM {} (sequence: {}, prepare_amount: {})",
+            self.ilp_packet_type, self.sequence, self.prepare_amount
+        )?;
+        for frame in self.frames_checked() {
+            match frame {
+                Ok(frame) => writeln!(f, "  {:?}", frame)?,
+                Err(err) => writeln!(f, "  {}", err)?,
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Iterator over a serialized Frame to support zero-copy deserialization
 pub struct FrameIterator<'a> {
     buffer: &'a [u8],
 }
 
-impl<'a> FrameIterator<'a> {
-    /// Reads a u8 from the iterator's buffer, and depending on the type it returns
-    /// a [`Frame`](./enum.Frame.html)
-    fn try_read_next_frame(&mut self) -> Result<Frame<'a>, StreamPacketError> {
-        let frame_type = self.buffer.get_u8();
-        let contents: &'a [u8] = self.buffer.read_var_octet_string()?;
-        let frame: Frame<'a> = match FrameType::from(frame_type) {
-            FrameType::ConnectionClose => {
-                Frame::ConnectionClose(ConnectionCloseFrame::read_contents(&contents)?)
-            }
-            FrameType::ConnectionNewAddress => {
-                Frame::ConnectionNewAddress(ConnectionNewAddressFrame::read_contents(&contents)?)
-            }
-            FrameType::ConnectionAssetDetails => Frame::ConnectionAssetDetails(
-                ConnectionAssetDetailsFrame::read_contents(&contents)?,
-            ),
-            FrameType::ConnectionMaxData => {
-                Frame::ConnectionMaxData(ConnectionMaxDataFrame::read_contents(&contents)?)
-            }
-            FrameType::ConnectionDataBlocked => {
-                Frame::ConnectionDataBlocked(ConnectionDataBlockedFrame::read_contents(&contents)?)
-            }
-            FrameType::ConnectionMaxStreamId => {
-                Frame::ConnectionMaxStreamId(ConnectionMaxStreamIdFrame::read_contents(&contents)?)
-            }
-            FrameType::ConnectionStreamIdBlocked => Frame::ConnectionStreamIdBlocked(
-                ConnectionStreamIdBlockedFrame::read_contents(&contents)?,
-            ),
-            FrameType::StreamClose => {
-                Frame::StreamClose(StreamCloseFrame::read_contents(&contents)?)
-            }
-            FrameType::StreamMoney => {
-                Frame::StreamMoney(StreamMoneyFrame::read_contents(&contents)?)
-            }
-            FrameType::StreamMaxMoney => {
-                Frame::StreamMaxMoney(StreamMaxMoneyFrame::read_contents(&contents)?)
-            }
-            FrameType::StreamMoneyBlocked => {
-                Frame::StreamMoneyBlocked(StreamMoneyBlockedFrame::read_contents(&contents)?)
-            }
-            FrameType::StreamData => Frame::StreamData(StreamDataFrame::read_contents(&contents)?),
-            FrameType::StreamMaxData => {
-                Frame::StreamMaxData(StreamMaxDataFrame::read_contents(&contents)?)
-            }
-            FrameType::StreamDataBlocked => {
-                Frame::StreamDataBlocked(StreamDataBlockedFrame::read_contents(&contents)?)
-            }
-            FrameType::Unknown => {
-                warn!(
-                    "Ignoring unknown frame of type {}: {:x?}",
-                    frame_type, contents,
-                );
-                Frame::Unknown(UnknownFrameData::store_raw_contents(frame_type, &contents))
-            }
-        };
+/// Reads a u8 from the buffer, and depending on the type it returns
+/// a [`Frame`](./enum.Frame.html)
+fn read_next_frame<'a>(buffer: &mut &'a [u8]) -> Result<Frame<'a>, StreamPacketError> {
+    let frame_type = buffer.get_u8();
+    let contents: &'a [u8] = buffer.read_var_octet_string()?;
+    read_frame(frame_type, contents)
+}
 
-        Ok(frame)
-    }
+/// Reads the contents of a frame of the given type
+pub(crate) fn read_frame(frame_type: u8, contents: &[u8]) -> Result<Frame<'_>, StreamPacketError> {
+    let frame = match FrameType::from(frame_type) {
+        FrameType::ConnectionClose => {
+            Frame::ConnectionClose(ConnectionCloseFrame::read_contents(&contents)?)
+        }
+        FrameType::ConnectionNewAddress => {
+            Frame::ConnectionNewAddress(ConnectionNewAddressFrame::read_contents(&contents)?)
+        }
+        FrameType::ConnectionAssetDetails => {
+            Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame::read_contents(&contents)?)
+        }
+        FrameType::ConnectionMaxData => {
+            Frame::ConnectionMaxData(ConnectionMaxDataFrame::read_contents(&contents)?)
+        }
+        FrameType::ConnectionDataBlocked => {
+            Frame::ConnectionDataBlocked(ConnectionDataBlockedFrame::read_contents(&contents)?)
+        }
+        FrameType::ConnectionMaxStreamId => {
+            Frame::ConnectionMaxStreamId(ConnectionMaxStreamIdFrame::read_contents(&contents)?)
+        }
+        FrameType::ConnectionStreamIdBlocked => Frame::ConnectionStreamIdBlocked(
+            ConnectionStreamIdBlockedFrame::read_contents(&contents)?,
+        ),
+        FrameType::StreamClose => Frame::StreamClose(StreamCloseFrame::read_contents(&contents)?),
+        FrameType::StreamMoney => Frame::StreamMoney(StreamMoneyFrame::read_contents(&contents)?),
+        FrameType::StreamMaxMoney => {
+            Frame::StreamMaxMoney(StreamMaxMoneyFrame::read_contents(&contents)?)
+        }
+        FrameType::StreamMoneyBlocked => {
+            Frame::StreamMoneyBlocked(StreamMoneyBlockedFrame::read_contents(&contents)?)
+        }
+        FrameType::StreamData => Frame::StreamData(StreamDataFrame::read_contents(&contents)?),
+        FrameType::StreamMaxData => {
+            Frame::StreamMaxData(StreamMaxDataFrame::read_contents(&contents)?)
+        }
+        FrameType::StreamDataBlocked => {
+            Frame::StreamDataBlocked(StreamDataBlockedFrame::read_contents(&contents)?)
+        }
+        FrameType::StreamReceipt => {
+            Frame::StreamReceipt(StreamReceiptFrame::read_contents(&contents)?)
+        }
+        FrameType::Padding => Frame::Padding(PaddingFrame::read_contents(&contents)?),
+        FrameType::Unknown => {
+            warn!(
+                "Ignoring unknown frame of type {}: {:x?}",
+                frame_type, contents,
+            );
+            Frame::Unknown(UnknownFrameData::store_raw_contents(frame_type, &contents))
+        }
+    };
+
+    Ok(frame)
 }
 
 impl<'a> Iterator for FrameIterator<'a> {
@@ -340,7 +654,7 @@ impl<'a> Iterator for FrameIterator<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if !self.buffer.is_empty() {
-            match self.try_read_next_frame() {
+            match read_next_frame(&mut self.buffer) {
                 Ok(frame) => return Some(frame),
                 Err(err) => {
                     warn!("Error reading STREAM frame: {:?}", err);
@@ -368,6 +682,37 @@ impl<'a> fmt::Debug for FrameIterator<'a> {
     }
 }
 
+/// Iterator over the serialized Frames of a packet which, unlike
+/// [FrameIterator](./struct.FrameIterator.html), yields an error for the first frame
+/// which fails to parse instead of stopping silently. Nothing is yielded after an error.
+pub struct CheckedFrameIterator<'a> {
+    buffer: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Iterator for CheckedFrameIterator<'a> {
+    type Item = Result<Frame<'a>, StreamPacketError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        match read_next_frame(&mut self.buffer) {
+            Ok(frame) => {
+                self.index += 1;
+                Some(Ok(frame))
+            }
+            Err(err) => {
+                self.buffer = &[];
+                Some(Err(StreamPacketError::InvalidFrame(
+                    self.index,
+                    Box::new(err),
+                )))
+            }
+        }
+    }
+}
+
 /// Enum around the different Stream Frame types
 #[derive(PartialEq, Clone)]
 pub enum Frame<'a> {
@@ -385,6 +730,8 @@ pub enum Frame<'a> {
     StreamData(StreamDataFrame<'a>),
     StreamMaxData(StreamMaxDataFrame),
     StreamDataBlocked(StreamDataBlockedFrame),
+    StreamReceipt(StreamReceiptFrame<'a>),
+    Padding(PaddingFrame),
     Unknown(UnknownFrameData<'a>),
 }
 
@@ -405,15 +752,20 @@ impl<'a> fmt::Debug for Frame<'a> {
             Frame::StreamData(frame) => write!(f, "{:?}", frame),
             Frame::StreamMaxData(frame) => write!(f, "{:?}", frame),
             Frame::StreamDataBlocked(frame) => write!(f, "{:?}", frame),
+            Frame::StreamReceipt(frame) => write!(f, "{:?}", frame),
+            Frame::Padding(frame) => write!(f, "{:?}", frame),
             Frame::Unknown(unknown_data) => write!(f, "{:?}", unknown_data),
         }
     }
 }
 
-/// The Stream Frame types [as defined in the RFC](https://interledger.org/rfcs/0029-stream/#53-frames)
+/// The Stream Frame types [as defined in the RFC](https://interledger.org/rfcs/0029-stream/#53-frames),
+/// the receipt frame type [of the receipts RFC](https://interledger.org/rfcs/0039-stream-receipts/),
+/// and the padding frame type, which the RFC leaves unassigned
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
 pub enum FrameType {
+    Padding = 0x00,
     ConnectionClose = 0x01,
     ConnectionNewAddress = 0x02,
     ConnectionMaxData = 0x03,
@@ -428,12 +780,14 @@ pub enum FrameType {
     StreamData = 0x14,
     StreamMaxData = 0x15,
     StreamDataBlocked = 0x16,
+    StreamReceipt = 0x17,
     Unknown,
 }
 
 impl From<u8> for FrameType {
     fn from(num: u8) -> Self {
         match num {
+            0x00 => FrameType::Padding,
             0x01 => FrameType::ConnectionClose,
             0x02 => FrameType::ConnectionNewAddress,
             0x03 => FrameType::ConnectionMaxData,
@@ -448,11 +802,80 @@ impl From<u8> for FrameType {
             0x14 => FrameType::StreamData,
             0x15 => FrameType::StreamMaxData,
             0x16 => FrameType::StreamDataBlocked,
+            0x17 => FrameType::StreamReceipt,
             _ => FrameType::Unknown,
         }
     }
 }
 
+impl FrameType {
+    /// Every frame type, with the name `Display` prints
+    const NAMES: [(FrameType, &'static str); 17] = [
+        (FrameType::Padding, "Padding"),
+        (FrameType::ConnectionClose, "ConnectionClose"),
+        (FrameType::ConnectionNewAddress, "ConnectionNewAddress"),
+        (FrameType::ConnectionMaxData, "ConnectionMaxData"),
+        (FrameType::ConnectionDataBlocked, "ConnectionDataBlocked"),
+        (FrameType::ConnectionMaxStreamId, "ConnectionMaxStreamId"),
+        (
+            FrameType::ConnectionStreamIdBlocked,
+            "ConnectionStreamIdBlocked",
+        ),
+        (FrameType::ConnectionAssetDetails, "ConnectionAssetDetails"),
+        (FrameType::StreamClose, "StreamClose"),
+        (FrameType::StreamMoney, "StreamMoney"),
+        (FrameType::StreamMaxMoney, "StreamMaxMoney"),
+        (FrameType::StreamMoneyBlocked, "StreamMoneyBlocked"),
+        (FrameType::StreamData, "StreamData"),
+        (FrameType::StreamMaxData, "StreamMaxData"),
+        (FrameType::StreamDataBlocked, "StreamDataBlocked"),
+        (FrameType::StreamReceipt, "StreamReceipt"),
+        (FrameType::Unknown, "Unknown"),
+    ];
+}
+
+impl fmt::Display for FrameType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (_, name) = FrameType::NAMES
+            .iter()
+            .find(|(frame_type, _)| frame_type == self)
+            .expect("Every frame type has a name");
+        f.write_str(name)
+    }
+}
+
+/// Parses the names `Display` prints, ignoring case
+impl str::FromStr for FrameType {
+    type Err = StreamPacketError;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        FrameType::NAMES
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(src))
+            .map(|(frame_type, _)| *frame_type)
+            .ok_or_else(|| StreamPacketError::UnknownFrameType(src.to_string()))
+    }
+}
+
+impl<'de> Deserialize<'de> for FrameType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let string = String::deserialize(deserializer)?;
+        string.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for FrameType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
 /// The STREAM Error Codes [as defined in the RFC](https://interledger.org/rfcs/0029-stream/#54-error-codes)
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
@@ -552,6 +975,78 @@ impl<'a> SerializableFrame<'a> for ConnectionCloseFrame<'a> {
     }
 }
 
+/// Frame without any meaning, which only makes the packet longer to hide its actual length.
+/// Its contents are zeroes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PaddingFrame {
+    /// Length of the frame's contents
+    pub length: usize,
+}
+
+impl PaddingFrame {
+    /// Returns the padding frame which takes exactly `size` bytes once serialized, if any.
+    /// Because of the length prefix of the contents, no frame takes 130 or 259 bytes.
+    fn with_size(size: usize) -> Option<Self> {
+        // Type and length prefix of the contents, the prefix being 1 to 9 bytes long
+        (2..=10)
+            .filter_map(|overhead| size.checked_sub(overhead))
+            .find(|length| 1 + oer::predict_var_octet_string(*length) == size)
+            .map(|length| PaddingFrame { length })
+    }
+}
+
+impl<'a> SerializableFrame<'a> for PaddingFrame {
+    fn read_contents(reader: &'a [u8]) -> Result<Self, StreamPacketError> {
+        Ok(PaddingFrame {
+            length: reader.len(),
+        })
+    }
+
+    fn put_contents(&self, buf: &mut impl MutBufOerExt) {
+        const ZEROES: [u8; 64] = [0; 64];
+        let mut remaining = self.length;
+        while remaining > 0 {
+            let length = remaining.min(ZEROES.len());
+            buf.put_slice(&ZEROES[..length]);
+            remaining -= length;
+        }
+    }
+}
+
+/// How to pad STREAM packets with padding frames so that
+/// observers cannot infer what they contain, such as the amounts and the number of
+/// streams of a payment, from their length.
+///
+/// The sizes are those of the encrypted STREAM packets, that is, of the data of the ILP
+/// packets carrying them. Padding costs bandwidth: each packet grows by the difference
+/// between its size and the size it is padded to, and the peers and connectors forwarding
+/// the packets have to carry them in full. The payment's packets are usually below 100 bytes,
+/// so a single size of 128 bytes hides most of them at a modest cost.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamPadding {
+    /// Pad every packet to this size, or, if it is larger, to the next multiple of it
+    Fixed(usize),
+    /// Pad every packet to the smallest of these sizes which fits it. Packets larger
+    /// than all of them are padded to the next multiple of the largest one
+    Buckets(Vec<usize>),
+}
+
+impl StreamPadding {
+    /// The size a packet of `len` bytes is padded to
+    pub fn padded_len(&self, len: usize) -> usize {
+        let round_up = |size: usize| len.checked_next_multiple_of(size).unwrap_or(len);
+        match self {
+            StreamPadding::Fixed(size) => round_up(*size),
+            StreamPadding::Buckets(sizes) => sizes
+                .iter()
+                .filter(|size| **size >= len)
+                .min()
+                .copied()
+                .unwrap_or_else(|| round_up(sizes.iter().max().copied().unwrap_or(0))),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct UnknownFrameData<'a> {
     frame_type: u8,
@@ -643,7 +1138,7 @@ pub struct ConnectionMaxDataFrame {
 
 impl<'a> SerializableFrame<'a> for ConnectionMaxDataFrame {
     fn read_contents(mut reader: &[u8]) -> Result<Self, StreamPacketError> {
-        let max_offset = reader.read_var_uint()?;
+        let max_offset = saturating_read_var_uint(&mut reader)?;
         ensure_no_inner_trailing_bytes(reader)?;
 
         Ok(ConnectionMaxDataFrame { max_offset })
@@ -663,7 +1158,7 @@ pub struct ConnectionDataBlockedFrame {
 
 impl<'a> SerializableFrame<'a> for ConnectionDataBlockedFrame {
     fn read_contents(mut reader: &[u8]) -> Result<Self, StreamPacketError> {
-        let max_offset = reader.read_var_uint()?;
+        let max_offset = saturating_read_var_uint(&mut reader)?;
         ensure_no_inner_trailing_bytes(reader)?;
 
         Ok(ConnectionDataBlockedFrame { max_offset })
@@ -683,7 +1178,7 @@ pub struct ConnectionMaxStreamIdFrame {
 
 impl<'a> SerializableFrame<'a> for ConnectionMaxStreamIdFrame {
     fn read_contents(mut reader: &[u8]) -> Result<Self, StreamPacketError> {
-        let max_stream_id = reader.read_var_uint()?;
+        let max_stream_id = saturating_read_var_uint(&mut reader)?;
         ensure_no_inner_trailing_bytes(reader)?;
 
         Ok(ConnectionMaxStreamIdFrame { max_stream_id })
@@ -703,7 +1198,7 @@ pub struct ConnectionStreamIdBlockedFrame {
 
 impl<'a> SerializableFrame<'a> for ConnectionStreamIdBlockedFrame {
     fn read_contents(mut reader: &[u8]) -> Result<Self, StreamPacketError> {
-        let max_stream_id = reader.read_var_uint()?;
+        let max_stream_id = saturating_read_var_uint(&mut reader)?;
         ensure_no_inner_trailing_bytes(reader)?;
 
         Ok(ConnectionStreamIdBlockedFrame { max_stream_id })
@@ -729,7 +1224,7 @@ pub struct StreamCloseFrame<'a> {
 
 impl<'a> SerializableFrame<'a> for StreamCloseFrame<'a> {
     fn read_contents(mut reader: &'a [u8]) -> Result<Self, StreamPacketError> {
-        let stream_id = reader.read_var_uint()?;
+        let stream_id = read_var_uint_field(&mut reader, "stream_id")?;
         if reader.remaining() < 1 {
             return Err(OerError::UnexpectedEof.into());
         }
@@ -776,8 +1271,8 @@ pub struct StreamMoneyFrame {
 
 impl<'a> SerializableFrame<'a> for StreamMoneyFrame {
     fn read_contents(mut reader: &[u8]) -> Result<Self, StreamPacketError> {
-        let stream_id = reader.read_var_uint()?;
-        let shares = reader.read_var_uint()?;
+        let stream_id = read_var_uint_field(&mut reader, "stream_id")?;
+        let shares = read_var_uint_field(&mut reader, "shares")?;
         ensure_no_inner_trailing_bytes(reader)?;
 
         Ok(StreamMoneyFrame { stream_id, shares })
@@ -809,9 +1304,9 @@ pub struct StreamMaxMoneyFrame {
 
 impl<'a> SerializableFrame<'a> for StreamMaxMoneyFrame {
     fn read_contents(mut reader: &[u8]) -> Result<Self, StreamPacketError> {
-        let stream_id = reader.read_var_uint()?;
+        let stream_id = read_var_uint_field(&mut reader, "stream_id")?;
         let receive_max = saturating_read_var_uint(&mut reader)?;
-        let total_received = reader.read_var_uint()?;
+        let total_received = read_var_uint_field(&mut reader, "total_received")?;
         ensure_no_inner_trailing_bytes(reader)?;
 
         Ok(StreamMaxMoneyFrame {
@@ -843,9 +1338,9 @@ pub struct StreamMoneyBlockedFrame {
 
 impl<'a> SerializableFrame<'a> for StreamMoneyBlockedFrame {
     fn read_contents(mut reader: &[u8]) -> Result<Self, StreamPacketError> {
-        let stream_id = reader.read_var_uint()?;
+        let stream_id = read_var_uint_field(&mut reader, "stream_id")?;
         let send_max = saturating_read_var_uint(&mut reader)?;
-        let total_sent = reader.read_var_uint()?;
+        let total_sent = read_var_uint_field(&mut reader, "total_sent")?;
         ensure_no_inner_trailing_bytes(reader)?;
 
         Ok(StreamMoneyBlockedFrame {
@@ -890,8 +1385,8 @@ pub struct StreamDataFrame<'a> {
 
 impl<'a> SerializableFrame<'a> for StreamDataFrame<'a> {
     fn read_contents(mut reader: &'a [u8]) -> Result<Self, StreamPacketError> {
-        let stream_id = reader.read_var_uint()?;
-        let offset = reader.read_var_uint()?;
+        let stream_id = read_var_uint_field(&mut reader, "stream_id")?;
+        let offset = read_var_uint_field(&mut reader, "offset")?;
         let data = reader.read_var_octet_string()?;
         ensure_no_inner_trailing_bytes(reader)?;
 
@@ -920,8 +1415,8 @@ pub struct StreamMaxDataFrame {
 
 impl<'a> SerializableFrame<'a> for StreamMaxDataFrame {
     fn read_contents(mut reader: &[u8]) -> Result<Self, StreamPacketError> {
-        let stream_id = reader.read_var_uint()?;
-        let max_offset = reader.read_var_uint()?;
+        let stream_id = read_var_uint_field(&mut reader, "stream_id")?;
+        let max_offset = saturating_read_var_uint(&mut reader)?;
         ensure_no_inner_trailing_bytes(reader)?;
 
         Ok(StreamMaxDataFrame {
@@ -947,8 +1442,8 @@ pub struct StreamDataBlockedFrame {
 
 impl<'a> SerializableFrame<'a> for StreamDataBlockedFrame {
     fn read_contents(mut reader: &[u8]) -> Result<Self, StreamPacketError> {
-        let stream_id = reader.read_var_uint()?;
-        let max_offset = reader.read_var_uint()?;
+        let stream_id = read_var_uint_field(&mut reader, "stream_id")?;
+        let max_offset = saturating_read_var_uint(&mut reader)?;
         ensure_no_inner_trailing_bytes(reader)?;
 
         Ok(StreamDataBlockedFrame {
@@ -963,8 +1458,37 @@ impl<'a> SerializableFrame<'a> for StreamDataBlockedFrame {
     }
 }
 
+/// Receipt of the total amount received on a stream, which the receiver signs with the
+/// secret of the receipt verifier so that the sender can prove the payment to it
+#[derive(Debug, PartialEq, Clone)]
+pub struct StreamReceiptFrame<'a> {
+    /// Identifier of the stream this frame refers to.
+    pub stream_id: u64,
+    /// The [receipt](https://interledger.org/rfcs/0039-stream-receipts/#receipt-format)
+    pub receipt: &'a [u8],
+}
+
+impl<'a> SerializableFrame<'a> for StreamReceiptFrame<'a> {
+    fn read_contents(mut reader: &'a [u8]) -> Result<Self, StreamPacketError> {
+        let stream_id = read_var_uint_field(&mut reader, "stream_id")?;
+        let receipt = reader.read_var_octet_string()?;
+        ensure_no_inner_trailing_bytes(reader)?;
+
+        Ok(StreamReceiptFrame { stream_id, receipt })
+    }
+
+    fn put_contents(&self, buf: &mut impl MutBufOerExt) {
+        buf.put_var_uint(self.stream_id);
+        buf.put_var_octet_string(self.receipt);
+    }
+}
+
+/// Reads a varuint which is a maximum or a limit, saturating it to `u64::MAX` if it does
+/// not fit in a u64.
 /// See: https://github.com/interledger/rfcs/blob/master/0029-stream/0029-stream.md#514-maximum-varuint-size
-fn saturating_read_var_uint<'a>(reader: &mut impl BufOerExt<'a>) -> Result<u64, StreamPacketError> {
+pub(crate) fn saturating_read_var_uint<'a>(
+    reader: &mut impl BufOerExt<'a>,
+) -> Result<u64, StreamPacketError> {
     if reader.peek_var_octet_string()?.len() > 8 {
         reader.skip_var_octet_string()?;
 
@@ -982,11 +1506,48 @@ fn saturating_read_var_uint<'a>(reader: &mut impl BufOerExt<'a>) -> Result<u64,
     }
 }
 
+/// Reads a varuint which must fit in a u64, such as an identifier or a running total,
+/// naming the field in the error if it does not.
+/// See: https://github.com/interledger/rfcs/blob/master/0029-stream/0029-stream.md#514-maximum-varuint-size
+fn read_var_uint_field<'a>(
+    reader: &mut impl BufOerExt<'a>,
+    field: &'static str,
+) -> Result<u64, StreamPacketError> {
+    if reader.peek_var_octet_string()?.len() > 8 {
+        Err(StreamPacketError::VarUintTooLarge(field))
+    } else {
+        Ok(reader.read_var_uint()?)
+    }
+}
+
 #[cfg(test)]
 mod fuzzing {
     use super::{StreamPacket, StreamPacketBuilder};
     use bytes::{Buf, BytesMut};
 
+    /// Runs each input of the seed corpus of the fuzz target through it
+    fn run_seeds(target: &str, fuzz: fn(&[u8])) {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fuzz/seeds")
+            .join(target);
+        let mut seeds = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            fuzz(&std::fs::read(entry.unwrap().path()).unwrap());
+            seeds += 1;
+        }
+        assert!(seeds > 0);
+    }
+
+    #[test]
+    fn seed_corpus() {
+        run_seeds("stream_packet", crate::fuzz_decrypted_stream_packet);
+        run_seeds("stream_frame", crate::fuzz_stream_frame);
+        run_seeds(
+            "saturating_read_var_uint",
+            crate::fuzz_saturating_read_var_uint,
+        );
+    }
+
     #[test]
     fn fuzzed_0_extra_trailer_bytes() {
         // From the [RFC]'s it sounds like the at least trailer junk should be kept around,
@@ -1158,8 +1719,28 @@ mod fuzzing {
 #[cfg(test)]
 mod serialization {
     use super::*;
+    use hex_literal::hex;
     use std::str::FromStr;
 
+    #[test]
+    fn frame_type_names_roundtrip() {
+        for byte in 0..=u8::max_value() {
+            let frame_type = FrameType::from(byte);
+            assert_eq!(
+                FrameType::from_str(&frame_type.to_string()).unwrap(),
+                frame_type
+            );
+        }
+        assert_eq!(
+            FrameType::from_str("streammoney").unwrap(),
+            FrameType::StreamMoney
+        );
+        assert_eq!(
+            FrameType::from_str("StreamMony").unwrap_err().to_string(),
+            "Unknown STREAM frame type name: StreamMony"
+        );
+    }
+
     static PACKET: Lazy<StreamPacket> = Lazy::new(|| {
         StreamPacketBuilder {
             sequence: 1,
@@ -1269,7 +1850,7 @@ mod serialization {
     #[test]
     fn it_deserializes_packets_with_unknown_frame_data() {
         assert_eq!(
-            StreamPacket::from_bytes_unencrypted(SERIALIZED_UNKNOWN_FRAME_PACKET.clone()).unwrap(),
+            StreamPacket::from_decrypted(SERIALIZED_UNKNOWN_FRAME_PACKET.clone()).unwrap(),
             *UNKNOWN_FRAME_PACKET
         );
     }
@@ -1282,11 +1863,63 @@ mod serialization {
     #[test]
     fn it_deserializes_from_javascript() {
         assert_eq!(
-            StreamPacket::from_bytes_unencrypted(SERIALIZED.clone()).unwrap(),
+            StreamPacket::from_decrypted(SERIALIZED.clone()).unwrap(),
             *PACKET
         );
     }
 
+    #[test]
+    fn it_displays_one_frame_per_line() {
+        assert_eq!(
+            UNKNOWN_FRAME_PACKET.to_string(),
+            "STREAM Prepare (sequence: 1, prepare_amount: 99)\n  \
+             UnknownFrameData { frame_type: 89, content: [1, 2, 3] }\n"
+        );
+    }
+
+    #[test]
+    fn it_serializes_frames_with_long_length_prefixes() {
+        let data = [9; 300];
+        let packet = StreamPacketBuilder {
+            sequence: 1,
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            frames: &[Frame::StreamData(StreamDataFrame {
+                stream_id: 1,
+                offset: 0,
+                data: &data,
+            })],
+        }
+        .build();
+        let parsed = StreamPacket::from_decrypted(packet.buffer_unencrypted.clone()).unwrap();
+        match parsed.frames().next() {
+            Some(Frame::StreamData(frame)) => assert_eq!(frame.data, &data[..]),
+            frame => panic!("Unexpected frame: {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn it_decodes_packets_into_a_reused_buffer() {
+        let shared_secret = &[7; 32];
+        let ciphertext = PACKET.clone().into_encrypted(shared_secret);
+        let mut decoder = StreamPacketDecoder::default();
+
+        let packet = decoder.decode(shared_secret, &ciphertext).unwrap();
+        assert_eq!(packet, *PACKET);
+        let ptr = packet.buffer_unencrypted.as_ptr();
+        drop(packet);
+
+        // The memory of the dropped packet is reused
+        let packet = decoder.decode(shared_secret, &ciphertext).unwrap();
+        assert_eq!(packet, *PACKET);
+        assert_eq!(packet.buffer_unencrypted.as_ptr(), ptr);
+
+        assert!(matches!(
+            decoder.decode(&[0; 32], &ciphertext),
+            Err(StreamPacketError::FailedToDecrypt)
+        ));
+    }
+
     #[test]
     fn it_iterates_through_the_frames() {
         let mut iter = PACKET.frames();
@@ -1338,4 +1971,395 @@ mod serialization {
         let frame = StreamMoneyBlockedFrame::read_contents(&buffer).unwrap();
         assert_eq!(frame.send_max, u64::MAX);
     }
+
+    /// 2^64 as a varuint, which is how ilp-protocol-stream serializes amounts and limits
+    /// that do not fit in a u64
+    const OVERSIZED: [u8; 10] = hex!("09 01 00 00 00 00 00 00 00 00");
+
+    #[test]
+    #[cfg(not(feature = "roundtrip-only"))]
+    fn it_saturates_oversized_limits() {
+        let with_stream_id = [&[1, 7][..], &OVERSIZED[..]].concat();
+        let cases = vec![
+            (
+                read_frame(3, &OVERSIZED),
+                Frame::ConnectionMaxData(ConnectionMaxDataFrame {
+                    max_offset: u64::MAX,
+                }),
+            ),
+            (
+                read_frame(4, &OVERSIZED),
+                Frame::ConnectionDataBlocked(ConnectionDataBlockedFrame {
+                    max_offset: u64::MAX,
+                }),
+            ),
+            (
+                read_frame(5, &OVERSIZED),
+                Frame::ConnectionMaxStreamId(ConnectionMaxStreamIdFrame {
+                    max_stream_id: u64::MAX,
+                }),
+            ),
+            (
+                read_frame(6, &OVERSIZED),
+                Frame::ConnectionStreamIdBlocked(ConnectionStreamIdBlockedFrame {
+                    max_stream_id: u64::MAX,
+                }),
+            ),
+            (
+                read_frame(21, &with_stream_id),
+                Frame::StreamMaxData(StreamMaxDataFrame {
+                    stream_id: 7,
+                    max_offset: u64::MAX,
+                }),
+            ),
+            (
+                read_frame(22, &with_stream_id),
+                Frame::StreamDataBlocked(StreamDataBlockedFrame {
+                    stream_id: 7,
+                    max_offset: u64::MAX,
+                }),
+            ),
+        ];
+        for (frame, expected) in cases {
+            assert_eq!(frame.unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn it_rejects_oversized_identifiers_and_totals() {
+        let cases: Vec<(u8, Vec<u8>, &str)> = vec![
+            // StreamClose with an empty message
+            (16, [&OVERSIZED[..], &[1, 0][..]].concat(), "stream_id"),
+            (17, [&[1, 7][..], &OVERSIZED[..]].concat(), "shares"),
+            (
+                18,
+                [&[1, 7, 1, 9][..], &OVERSIZED[..]].concat(),
+                "total_received",
+            ),
+            (
+                19,
+                [&[1, 7, 1, 9][..], &OVERSIZED[..]].concat(),
+                "total_sent",
+            ),
+            // StreamData without data
+            (
+                20,
+                [&[1, 7][..], &OVERSIZED[..], &[0][..]].concat(),
+                "offset",
+            ),
+        ];
+        for (frame_type, contents, field) in cases {
+            match read_frame(frame_type, &contents) {
+                Err(StreamPacketError::VarUintTooLarge(name)) => assert_eq!(name, field),
+                result => panic!("Unexpected result for {}: {:?}", field, result),
+            }
+        }
+
+        #[rustfmt::skip]
+        let input: &[u8] = &[
+            // Version and packet type
+            1, 12,
+            // sequence: 2^64
+            9, 1, 0, 0, 0, 0, 0, 0, 0, 0,
+            // prepare amount and num frames
+            1, 99, 1, 0,
+        ];
+        let err = StreamPacket::from_decrypted(BytesMut::from(input)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid Packet: sequence does not fit in a u64"
+        );
+    }
+
+    #[test]
+    fn it_rejects_too_many_frames() {
+        let limits = StreamPacketLimits {
+            max_frames: 1,
+            ..Default::default()
+        };
+        let err =
+            StreamPacket::from_decrypted_with_limits(PACKET.buffer_unencrypted.clone(), &limits)
+                .unwrap_err();
+        assert!(matches!(err, StreamPacketError::TooManyFrames(14, 1)));
+    }
+
+    #[test]
+    fn it_rejects_frames_over_max_size() {
+        let limits = StreamPacketLimits {
+            max_frame_size: 4,
+            ..Default::default()
+        };
+        let err =
+            StreamPacket::from_decrypted_with_limits(PACKET.buffer_unencrypted.clone(), &limits)
+                .unwrap_err();
+        assert!(matches!(err, StreamPacketError::FrameTooLarge(5, 4)));
+    }
+
+    #[test]
+    fn it_rejects_huge_frame_count_without_iterating() {
+        #[rustfmt::skip]
+        let input: &[u8] = &[
+            // Version, packet type, sequence and prepare amount
+            1, 12, 1, 1, 1, 99,
+            // num frames: u64::MAX
+            8, 255, 255, 255, 255, 255, 255, 255, 255,
+        ];
+        let err = StreamPacket::from_decrypted(BytesMut::from(input)).unwrap_err();
+        assert!(matches!(
+            err,
+            StreamPacketError::TooManyFrames(u64::MAX, DEFAULT_MAX_FRAMES)
+        ));
+    }
+
+    #[test]
+    fn it_surfaces_frame_errors_in_strict_mode() {
+        #[rustfmt::skip]
+        let input: &[u8] = &[
+            // Version, packet type, sequence and prepare amount
+            1, 12, 1, 1, 1, 99,
+            // num frames
+            1, 2,
+            // ConnectionMaxData frame with a max offset of 5
+            3, 2, 1, 5,
+            // ConnectionMaxData frame without contents
+            3, 0,
+        ];
+        let err = StreamPacket::from_decrypted(BytesMut::from(input)).unwrap_err();
+        assert!(matches!(err, StreamPacketError::NotEnoughValidFrames));
+
+        let limits = StreamPacketLimits {
+            strict_frames: true,
+            ..Default::default()
+        };
+        let err =
+            StreamPacket::from_decrypted_with_limits(BytesMut::from(input), &limits).unwrap_err();
+        match err {
+            StreamPacketError::InvalidFrame(1, err) => {
+                assert!(matches!(
+                    *err,
+                    StreamPacketError::Oer(OerError::UnexpectedEof)
+                ))
+            }
+            err => panic!("Unexpected error: {:?}", err),
+        }
+
+        // Valid packets parse the same way in strict mode
+        let packet =
+            StreamPacket::from_decrypted_with_limits(PACKET.buffer_unencrypted.clone(), &limits)
+                .unwrap();
+        assert_eq!(packet.frames_checked().count(), 14);
+        assert!(packet.frames_checked().all(|frame| frame.is_ok()));
+    }
+}
+
+#[cfg(test)]
+mod padding {
+    use super::*;
+    use crate::crypto::decrypt;
+    use std::str::FromStr;
+
+    const SHARED_SECRET: &[u8] = &[7; 32];
+
+    #[test]
+    fn computes_padded_lengths() {
+        assert_eq!(StreamPadding::Fixed(128).padded_len(1), 128);
+        assert_eq!(StreamPadding::Fixed(128).padded_len(128), 128);
+        assert_eq!(StreamPadding::Fixed(128).padded_len(129), 256);
+        let buckets = StreamPadding::Buckets(vec![512, 128, 256]);
+        assert_eq!(buckets.padded_len(100), 128);
+        assert_eq!(buckets.padded_len(200), 256);
+        assert_eq!(buckets.padded_len(600), 1024);
+    }
+
+    #[test]
+    fn pads_packets_to_the_exact_size() {
+        let address = Address::from_str("example.sender").unwrap();
+        for padded_len in 60..400 {
+            let padding = StreamPadding::Fixed(padded_len);
+            for num_streams in 0..3 {
+                let mut frames: Vec<Frame> = (0..num_streams)
+                    .map(|stream_id| {
+                        Frame::StreamMoney(StreamMoneyFrame {
+                            stream_id,
+                            shares: 1,
+                        })
+                    })
+                    .collect();
+                frames.push(Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+                    source_account: address.clone(),
+                }));
+                let builder = StreamPacketBuilder {
+                    sequence: 1,
+                    ilp_packet_type: IlpPacketType::Prepare,
+                    prepare_amount: 99,
+                    frames: &frames,
+                };
+                let encrypted = builder.build_padded(&padding).into_encrypted(SHARED_SECRET);
+                assert_eq!(encrypted.len(), padding.padded_len(encrypted.len()));
+
+                let packet = StreamPacket::from_encrypted(SHARED_SECRET, encrypted).unwrap();
+                let (padding_frames, other_frames): (Vec<_>, Vec<_>) = packet
+                    .frames()
+                    .partition(|frame| matches!(frame, Frame::Padding(_)));
+                assert!(!padding_frames.is_empty());
+                assert_eq!(other_frames, frames);
+            }
+        }
+    }
+
+    #[test]
+    fn encoder_pads_prepares() {
+        let frames = [Frame::StreamMoney(StreamMoneyFrame {
+            stream_id: 1,
+            shares: 1,
+        })];
+        let packet = StreamPacketBuilder {
+            sequence: 1,
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 99,
+            frames: &frames,
+        };
+        let mut encoder =
+            StreamPacketEncoder::default().with_padding(Some(StreamPadding::Fixed(128)));
+        let prepare = encoder.encode_prepare(
+            &packet,
+            SHARED_SECRET,
+            &PrepareBuilder {
+                destination: Address::from_str("example.receiver").unwrap(),
+                amount: 100,
+                execution_condition: &[0; 32],
+                expires_at: std::time::SystemTime::now(),
+                data: &[],
+            },
+        );
+        assert_eq!(prepare.data().len(), 128);
+        let decrypted = decrypt(SHARED_SECRET, BytesMut::from(prepare.data())).unwrap();
+        assert_eq!(
+            decrypted,
+            packet
+                .build_padded(&StreamPadding::Fixed(128))
+                .buffer_unencrypted
+        );
+    }
+}
+
+#[cfg(test)]
+mod encoder {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::str::FromStr;
+    use std::time::{Duration, SystemTime};
+
+    /// Counts the allocations made by the current thread
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+        let before = ALLOCATIONS.with(|count| count.get());
+        let result = f();
+        (result, ALLOCATIONS.with(|count| count.get()) - before)
+    }
+
+    const SHARED_SECRET: &[u8] = &[7; 32];
+
+    fn frames() -> Vec<Frame<'static>> {
+        vec![
+            Frame::StreamMoney(StreamMoneyFrame {
+                stream_id: 1,
+                shares: 1,
+            }),
+            Frame::ConnectionNewAddress(ConnectionNewAddressFrame {
+                source_account: Address::from_str("example.sender").unwrap(),
+            }),
+        ]
+    }
+
+    fn prepare_builder(destination: &Address) -> PrepareBuilder<'_> {
+        PrepareBuilder {
+            destination: destination.clone(),
+            amount: 100,
+            execution_condition: &[0; 32],
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+            data: &[],
+        }
+    }
+
+    #[test]
+    fn encodes_decryptable_prepares() {
+        let frames = frames();
+        let destination = Address::from_str("example.receiver").unwrap();
+        let mut encoder = StreamPacketEncoder::default();
+        for sequence in 1..4 {
+            let packet = StreamPacketBuilder {
+                sequence,
+                ilp_packet_type: IlpPacketType::Prepare,
+                prepare_amount: 99,
+                frames: &frames,
+            };
+            let prepare =
+                encoder.encode_prepare(&packet, SHARED_SECRET, &prepare_builder(&destination));
+            let decrypted =
+                StreamPacket::from_encrypted(SHARED_SECRET, BytesMut::from(prepare.data()))
+                    .unwrap();
+            assert_eq!(decrypted, packet.build());
+            assert_eq!(prepare.destination(), destination);
+            assert_eq!(prepare.amount(), 100);
+        }
+    }
+
+    #[test]
+    fn reuses_buffers_across_packets() {
+        let frames = frames();
+        let destination = Address::from_str("example.receiver").unwrap();
+        let builder = prepare_builder(&destination);
+        let packet = StreamPacketBuilder {
+            sequence: 1,
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 99,
+            frames: &frames,
+        };
+
+        let mut encoder = StreamPacketEncoder::default();
+        // The first packet sizes the encoder's buffers
+        encoder.encode_prepare(&packet, SHARED_SECRET, &builder);
+
+        let (_, encoder_allocations) =
+            count_allocations(|| encoder.encode_prepare(&packet, SHARED_SECRET, &builder));
+        // The encoder allocates nothing besides what building the Prepare itself does
+        let (_, prepare_allocations) = count_allocations(|| builder.build());
+        assert_eq!(encoder_allocations, prepare_allocations);
+
+        let (_, builder_allocations) = count_allocations(|| {
+            let data = packet.build().into_encrypted(SHARED_SECRET);
+            PrepareBuilder {
+                data: &data[..],
+                ..builder
+            }
+            .build()
+        });
+        assert!(builder_allocations > encoder_allocations);
+    }
 }
@@ -0,0 +1,225 @@
+use super::congestion::max_packet_amount_from_reject;
+use super::crypto::random_condition;
+use super::error::Error;
+use super::packet::*;
+use bytes::BytesMut;
+use futures::future::join_all;
+use interledger_packet::{
+    Address, ErrorCode as IlpErrorCode, PacketType as IlpPacketType, PrepareBuilder,
+};
+use interledger_service::*;
+use std::cmp::{max, min};
+use std::time::SystemTime;
+use tokio::time::{Duration, Instant};
+use tracing::debug;
+
+/// What was learned about the path to a receiver by [`rate_probe`](./fn.rate_probe.html),
+/// which may be passed to [`send_money_with_options`](./fn.send_money_with_options.html)
+/// through the [`SendMoneyOptions`](./struct.SendMoneyOptions.html)
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathStats {
+    /// Units of the receiver's asset which arrived per unit of the sender's asset, before
+    /// applying either asset scale
+    pub rate: f64,
+    /// Largest amount a packet may carry, in source units, if a node on the path limits it
+    pub max_packet_amount: Option<u64>,
+    /// Round trip time of the fastest probe packet which reached the receiver
+    pub rtt: Duration,
+}
+
+/// Outcome of a single probe packet
+enum ProbeResult {
+    /// The receiver rejected the packet, telling how much of it arrived
+    Arrived {
+        source_amount: u64,
+        destination_amount: u64,
+        rtt: Duration,
+    },
+    /// A node on the path rejected the packet with an F08 error, advising of the
+    /// largest source amount it would have accepted if it could
+    TooLarge {
+        source_amount: u64,
+        max_packet_amount: Option<u64>,
+    },
+    /// The packet was rejected for any other reason
+    Failed,
+}
+
+/// The source amounts of the probe packets: every power of ten up to the maximum amount,
+/// and the maximum amount itself
+fn probe_amounts(max_amount: u64) -> Vec<u64> {
+    let mut amounts = Vec::new();
+    let mut amount = 1;
+    while amount < max_amount {
+        amounts.push(amount);
+        amount = match amount.checked_mul(10) {
+            Some(amount) => amount,
+            None => break,
+        };
+    }
+    amounts.push(max(max_amount, 1));
+    amounts
+}
+
+/// Discover the exchange rate and the maximum packet amount of the path to a receiver by
+/// sending it a series of unfulfillable packets of increasing amounts (powers of ten up to
+/// `max_amount`), so that no money is at risk. The receiver rejects each of them
+/// reporting the amount which arrived, and nodes limiting the packet amount reject the
+/// larger ones with F08 errors.
+///
+/// Fails if none of the packets reached the receiver.
+pub async fn rate_probe<I, A>(
+    service: I,
+    from_account: &A,
+    destination_account: Address,
+    shared_secret: Vec<u8>,
+    max_amount: u64,
+) -> Result<PathStats, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+{
+    let probes = probe_amounts(max_amount)
+        .into_iter()
+        .enumerate()
+        .map(|(i, source_amount)| {
+            send_probe(
+                service.clone(),
+                from_account.clone(),
+                destination_account.clone(),
+                &shared_secret,
+                i as u64 + 1,
+                source_amount,
+            )
+        });
+    let results = join_all(probes).await;
+
+    let mut largest_arrived: Option<(u64, u64)> = None;
+    let mut rtt: Option<Duration> = None;
+    let mut max_packet_amount: Option<u64> = None;
+    for result in results.iter() {
+        if let ProbeResult::Arrived {
+            source_amount,
+            destination_amount,
+            rtt: probe_rtt,
+        } = *result
+        {
+            if largest_arrived.map_or(true, |(largest, _)| source_amount > largest) {
+                largest_arrived = Some((source_amount, destination_amount));
+            }
+            rtt = Some(rtt.map_or(probe_rtt, |rtt| min(rtt, probe_rtt)));
+        }
+    }
+    for result in results.iter() {
+        if let ProbeResult::TooLarge {
+            source_amount,
+            max_packet_amount: limit,
+        } = *result
+        {
+            // Without details, the largest amount which arrived below it is the best guess
+            let limit = limit.or_else(|| {
+                largest_arrived
+                    .map(|(largest, _)| largest)
+                    .filter(|largest| *largest < source_amount)
+            });
+            if let Some(limit) = limit {
+                max_packet_amount = Some(max_packet_amount.map_or(limit, |max| min(max, limit)));
+            }
+        }
+    }
+
+    let (source_amount, destination_amount) = largest_arrived.ok_or(Error::RateProbeFailed)?;
+    let stats = PathStats {
+        rate: destination_amount as f64 / source_amount as f64,
+        max_packet_amount,
+        rtt: rtt.unwrap_or_default(),
+    };
+    debug!("Probed path to {}: {:?}", destination_account, stats);
+    Ok(stats)
+}
+
+/// Send an unfulfillable probe packet of the given source amount
+async fn send_probe<I, A>(
+    mut service: I,
+    from_account: A,
+    destination_account: Address,
+    shared_secret: &[u8],
+    sequence: u64,
+    source_amount: u64,
+) -> ProbeResult
+where
+    I: IncomingService<A>,
+    A: Account,
+{
+    let prepare = StreamPacketEncoder::default().encode_prepare(
+        &StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence,
+            frames: &[],
+        },
+        shared_secret,
+        &PrepareBuilder {
+            destination: destination_account,
+            amount: source_amount,
+            execution_condition: &random_condition(),
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+            data: &[],
+        },
+    );
+
+    let start = Instant::now();
+    let reject = match service
+        .handle_request(IncomingRequest {
+            from: from_account,
+            prepare,
+        })
+        .await
+    {
+        Ok(_) => return ProbeResult::Failed,
+        Err(reject) => reject,
+    };
+    let rtt = start.elapsed();
+
+    if reject.code() == IlpErrorCode::F08_AMOUNT_TOO_LARGE {
+        return ProbeResult::TooLarge {
+            source_amount,
+            max_packet_amount: max_packet_amount_from_reject(source_amount, &reject),
+        };
+    }
+
+    match StreamPacket::from_encrypted(shared_secret, BytesMut::from(reject.data())) {
+        Ok(packet)
+            if packet.sequence() == sequence
+                && packet.ilp_packet_type() == IlpPacketType::Reject =>
+        {
+            ProbeResult::Arrived {
+                source_amount,
+                destination_amount: packet.prepare_amount(),
+                rtt,
+            }
+        }
+        _ => {
+            debug!(
+                "Probe of {} was rejected with code {} before reaching the receiver",
+                source_amount,
+                reject.code()
+            );
+            ProbeResult::Failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probes_powers_of_ten_up_to_the_max_amount() {
+        assert_eq!(probe_amounts(0), vec![1]);
+        assert_eq!(probe_amounts(1), vec![1]);
+        assert_eq!(probe_amounts(1000), vec![1, 10, 100, 1000]);
+        assert_eq!(probe_amounts(2500), vec![1, 10, 100, 1000, 2500]);
+        assert_eq!(probe_amounts(std::u64::MAX).len(), 21);
+    }
+}
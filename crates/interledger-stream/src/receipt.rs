@@ -0,0 +1,140 @@
+use super::crypto::{decrypt, encrypt, hmac_sha256, ENCRYPTION_OVERHEAD};
+use bytes::{BufMut, BytesMut};
+use interledger_packet::{hex::HexString, oer::MutBufOerExt};
+use std::fmt;
+
+/// Version of the [receipt format](https://interledger.org/rfcs/0039-stream-receipts/#receipt-format)
+const RECEIPT_VERSION: u8 = 1;
+/// Length of the nonces receipt verifiers provide
+pub const RECEIPT_NONCE_LEN: usize = 16;
+/// Length of the secrets receipt verifiers provide
+pub const RECEIPT_SECRET_LEN: usize = 32;
+/// Length of the receipt details once sealed into a generated address
+pub(crate) const SEALED_RECEIPT_DETAILS_LEN: usize =
+    RECEIPT_NONCE_LEN + RECEIPT_SECRET_LEN + ENCRYPTION_OVERHEAD;
+
+/// The nonce and secret a receipt verifier provides along with the SPSP queries it proxies,
+/// [as defined in the RFC](https://interledger.org/rfcs/0039-stream-receipts/).
+///
+/// The receiver signs the receipts of the amounts received over the connections generated
+/// with them, which the senders can then present to the verifier as proof of payment.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ReceiptDetails {
+    /// Identifies the connection's receipts to the verifier
+    pub nonce: [u8; RECEIPT_NONCE_LEN],
+    /// Key of the HMAC signing the receipts, only known to the verifier and the receiver
+    pub secret: [u8; RECEIPT_SECRET_LEN],
+}
+
+impl fmt::Debug for ReceiptDetails {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReceiptDetails")
+            .field("nonce", &HexString(&self.nonce))
+            .field("secret", &"<redacted>")
+            .finish()
+    }
+}
+
+impl ReceiptDetails {
+    /// Returns the receipt of the total amount received on the stream, signed with the secret
+    pub fn receipt(&self, stream_id: u64, total_received: u64) -> Vec<u8> {
+        let mut receipt = Vec::with_capacity(1 + RECEIPT_NONCE_LEN + 9 + 8 + 32);
+        receipt.put_u8(RECEIPT_VERSION);
+        receipt.put_slice(&self.nonce);
+        receipt.put_var_uint(stream_id);
+        receipt.put_u64(total_received);
+        let hmac = hmac_sha256(&self.secret, &receipt);
+        receipt.put_slice(&hmac);
+        receipt
+    }
+
+    /// Encrypts the details with the key, so that they can be embedded in the addresses of
+    /// the generated connections without revealing the secret to the senders
+    pub(crate) fn seal(&self, key: &[u8]) -> BytesMut {
+        let mut plaintext = BytesMut::with_capacity(SEALED_RECEIPT_DETAILS_LEN);
+        plaintext.put_slice(&self.nonce);
+        plaintext.put_slice(&self.secret);
+        encrypt(key, plaintext)
+    }
+
+    /// Decrypts the details sealed with the same key, or returns `None` if they were not
+    pub(crate) fn open(key: &[u8], sealed: &[u8]) -> Option<Self> {
+        if sealed.len() != SEALED_RECEIPT_DETAILS_LEN {
+            return None;
+        }
+        let plaintext = decrypt(key, BytesMut::from(sealed)).ok()?;
+        let mut details = ReceiptDetails {
+            nonce: [0; RECEIPT_NONCE_LEN],
+            secret: [0; RECEIPT_SECRET_LEN],
+        };
+        details
+            .nonce
+            .copy_from_slice(&plaintext[..RECEIPT_NONCE_LEN]);
+        details
+            .secret
+            .copy_from_slice(&plaintext[RECEIPT_NONCE_LEN..]);
+        Some(details)
+    }
+
+    /// Returns the key under which the total received on the stream is tracked
+    pub(crate) fn stream_key(&self, stream_id: u64) -> String {
+        format!("receipt-{:?}-{}", HexString(&self.nonce), stream_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{Frame, StreamPacket, StreamPacketBuilder, StreamReceiptFrame};
+    use hex_literal::hex;
+    use interledger_packet::PacketType as IlpPacketType;
+
+    const DETAILS: ReceiptDetails = ReceiptDetails {
+        nonce: [1; RECEIPT_NONCE_LEN],
+        secret: [2; RECEIPT_SECRET_LEN],
+    };
+
+    #[test]
+    fn signs_receipts() {
+        let receipt = DETAILS.receipt(1, 500);
+        let (body, hmac) = receipt.split_at(receipt.len() - 32);
+        assert_eq!(
+            body,
+            &hex!("01 01010101010101010101010101010101 0101 00000000000001f4")[..]
+        );
+        assert_eq!(hmac, &hmac_sha256(&DETAILS.secret, body)[..]);
+    }
+
+    #[test]
+    fn seals_and_opens_details() {
+        let sealed = DETAILS.seal(&[3; 32]);
+        assert_eq!(sealed.len(), SEALED_RECEIPT_DETAILS_LEN);
+        assert_eq!(ReceiptDetails::open(&[3; 32], &sealed), Some(DETAILS));
+        assert_eq!(ReceiptDetails::open(&[4; 32], &sealed), None);
+        assert_eq!(ReceiptDetails::open(&[3; 32], &sealed[1..]), None);
+    }
+
+    #[test]
+    fn does_not_print_the_secret() {
+        let printed = format!("{:?}", DETAILS);
+        assert!(printed.contains("01010101"));
+        assert!(!printed.contains("0202") && !printed.contains("2, 2"));
+    }
+
+    #[test]
+    fn roundtrips_receipt_frames() {
+        let receipt = DETAILS.receipt(3, 1000);
+        let packet = StreamPacketBuilder {
+            sequence: 1,
+            ilp_packet_type: IlpPacketType::Fulfill,
+            prepare_amount: 0,
+            frames: &[Frame::StreamReceipt(StreamReceiptFrame {
+                stream_id: 3,
+                receipt: &receipt,
+            })],
+        }
+        .build();
+        let parsed = StreamPacket::from_decrypted(packet.buffer_unencrypted.clone()).unwrap();
+        assert_eq!(parsed, packet);
+    }
+}
@@ -0,0 +1,66 @@
+use async_trait::async_trait;
+use interledger_errors::StreamReceiveStoreError;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The amount received over the STREAM connections of an account with the same
+/// [connection tag](./struct.ConnectionGenerator.html#method.generate_address_and_secret_with_tag),
+/// and the most they may receive
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ConnectionReceipts {
+    pub total_received: u64,
+    /// `None` if the connections may receive any amount
+    pub receive_max: Option<u64>,
+}
+
+impl ConnectionReceipts {
+    /// Returns whether receiving `amount` more would exceed the receive max
+    pub fn exceeded_by(&self, amount: u64) -> bool {
+        match self.receive_max {
+            Some(receive_max) => self.total_received.saturating_add(amount) > receive_max,
+            None => false,
+        }
+    }
+}
+
+/// Store which keeps track of the amounts received over the tagged STREAM connections of
+/// each account, so that the receiver can enforce and advertise their receive max
+///
+/// Stores may reject amounts and receive maximums which they cannot count exactly, such as
+/// those above `i64::MAX` for Redis, with `StreamReceiveStoreError::AmountTooLarge`.
+#[async_trait]
+pub trait StreamReceiveStore {
+    /// Sets the most the account's connections with the given tag may receive in total.
+    /// `None` lets them receive any amount.
+    async fn set_connection_receive_max(
+        &self,
+        account_id: Uuid,
+        connection_tag: &str,
+        receive_max: Option<u64>,
+    ) -> Result<(), StreamReceiveStoreError>;
+
+    /// Returns the receipts of the account's connections with the given tag
+    async fn get_connection_receipts(
+        &self,
+        account_id: Uuid,
+        connection_tag: &str,
+    ) -> Result<ConnectionReceipts, StreamReceiveStoreError>;
+
+    /// Atomically adds `amount` to the total received over the account's connections with
+    /// the given tag, unless that would exceed their receive max. Returns the receipts,
+    /// including the amount if it was added, and whether it was added.
+    async fn add_connection_receipt(
+        &self,
+        account_id: Uuid,
+        connection_tag: &str,
+        amount: u64,
+    ) -> Result<(ConnectionReceipts, bool), StreamReceiveStoreError>;
+
+    /// Subtracts an amount added with `add_connection_receipt` whose packet was not fulfilled
+    async fn remove_connection_receipt(
+        &self,
+        account_id: Uuid,
+        connection_tag: &str,
+        amount: u64,
+    ) -> Result<(), StreamReceiveStoreError>;
+}
@@ -0,0 +1,36 @@
+use async_trait::async_trait;
+use interledger_errors::StreamReplayStoreError;
+use ring::digest::{Context, SHA256};
+use std::time::Duration;
+
+/// Store which remembers the STREAM packets the receiver fulfilled, so that the identical
+/// packets replayed before they expire are not fulfilled again
+#[async_trait]
+pub trait StreamReplayStore {
+    /// Records the fulfilled packet with the given key for `ttl`. Returns `false`, without
+    /// recording it again, if the packet was already recorded and has not expired yet.
+    async fn record_fulfilled_packet(
+        &self,
+        key: [u8; 32],
+        ttl: Duration,
+    ) -> Result<bool, StreamReplayStoreError>;
+}
+
+/// Returns the key identifying a fulfilled packet: the hash of its execution condition,
+/// the tag of its connection (if any) and its STREAM sequence number
+pub fn fulfilled_packet_key(
+    execution_condition: &[u8],
+    connection_tag: Option<&str>,
+    sequence: u64,
+) -> [u8; 32] {
+    let mut context = Context::new(&SHA256);
+    context.update(execution_condition);
+    // The tag is length-prefixed so that it cannot be confused with the sequence
+    let connection_tag = connection_tag.unwrap_or_default().as_bytes();
+    context.update(&(connection_tag.len() as u64).to_be_bytes());
+    context.update(connection_tag);
+    context.update(&sequence.to_be_bytes());
+    let mut key = [0; 32];
+    key.copy_from_slice(context.finish().as_ref());
+    key
+}
@@ -1,19 +1,27 @@
 use super::crypto::*;
+use super::dampening::{DampeningPolicy, RejectDampener};
+use super::error::StreamPacketError;
+use super::keepalive::KeepAliveMonitor;
 use super::packet::*;
+use super::receipt::ReceiptDetails;
+use super::receive_max::{ConnectionReceipts, StreamReceiveStore};
+use super::replay::{fulfilled_packet_key, StreamReplayStore};
 use async_trait::async_trait;
-use bytes::{Bytes, BytesMut};
+use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use futures::channel::mpsc::UnboundedSender;
 use interledger_packet::{
-    hex::HexString, Address, ErrorCode, Fulfill, FulfillBuilder, PacketType as IlpPacketType,
-    Prepare, Reject, RejectBuilder,
+    hex::HexString, Address, AddressError, ErrorCode, Fulfill, FulfillBuilder,
+    PacketType as IlpPacketType, Prepare, Reject, RejectBuilder,
 };
 use interledger_service::{Account, IlpResult, OutgoingRequest, OutgoingService, Username};
 use serde::{Deserialize, Serialize};
 use std::marker::PhantomData;
-use std::time::SystemTime;
+use std::str;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 use tokio::sync::broadcast;
-use tracing::debug;
+use tracing::{debug, error, trace};
 use uuid::Uuid;
 
 // Note we are using the same magic bytes as the Javascript
@@ -25,6 +33,12 @@ use uuid::Uuid;
 // this string is.
 const STREAM_SERVER_SECRET_GENERATOR: &[u8] = b"ilp_stream_shared_secret";
 
+/// Derives the key encrypting the receipt details embedded in the generated addresses
+const STREAM_RECEIPT_KEY_GENERATOR: &[u8] = b"ilp_stream_receipt_details";
+
+/// Separates the connection tag from the token in the last segment of generated addresses
+const CONNECTION_TAG_SEPARATOR: char = '~';
+
 /// A STREAM connection generator that creates `destination_account` and `shared_secret` values
 /// based on a single root secret.
 ///
@@ -33,6 +47,7 @@ const STREAM_SERVER_SECRET_GENERATOR: &[u8] = b"ilp_stream_shared_secret";
 #[derive(Clone)]
 pub struct ConnectionGenerator {
     secret_generator: [u8; 32],
+    receipt_key: [u8; 32],
 }
 
 impl ConnectionGenerator {
@@ -40,9 +55,11 @@ impl ConnectionGenerator {
         assert_eq!(server_secret.len(), 32, "Server secret must be 32 bytes");
 
         let secret = hmac_sha256(&server_secret[..], STREAM_SERVER_SECRET_GENERATOR);
+        let receipt_key = hmac_sha256(&server_secret[..], STREAM_RECEIPT_KEY_GENERATOR);
 
         ConnectionGenerator {
             secret_generator: secret,
+            receipt_key,
         }
     }
 
@@ -63,6 +80,71 @@ impl ConnectionGenerator {
         (destination_account, shared_secret)
     }
 
+    /// Same as `generate_address_and_secret`, but appends a connection tag to the generated
+    /// address (separated from the token by a `~`), so the receiver can tell which of the
+    /// connections packets were sent over. The tag cannot be modified by the sender because
+    /// the shared secret is derived from it too.
+    ///
+    /// The tag must only contain characters valid in an ILP address segment (letters,
+    /// digits, `_`, `~` and `-`).
+    pub fn generate_address_and_secret_with_tag(
+        &self,
+        base_address: &Address,
+        tag: &str,
+    ) -> Result<(Address, [u8; 32]), AddressError> {
+        if tag.is_empty() || tag.contains('.') {
+            return Err(AddressError::InvalidFormat);
+        }
+        let token = base64::encode_config(&generate_token(), base64::URL_SAFE_NO_PAD);
+        let local_part = format!("{}{}{}", token, CONNECTION_TAG_SEPARATOR, tag);
+        let destination_account = base_address.with_suffix(local_part.as_bytes())?;
+        let shared_secret = hmac_sha256(&self.secret_generator[..], local_part.as_bytes());
+
+        debug!("Generated address: {}", destination_account);
+        Ok((destination_account, shared_secret))
+    }
+
+    /// Same as `generate_address_and_secret_with_tag` (or `generate_address_and_secret`
+    /// without a tag), but the receiver sends the senders over the generated connection
+    /// [receipts](https://interledger.org/rfcs/0039-stream-receipts/) of the amounts it
+    /// received, signed with the secret of the given `receipt_details`.
+    ///
+    /// The receipt details are encrypted into the generated address in place of the random
+    /// token, so that the receiver can recover them from the packets without keeping any
+    /// state, while the senders cannot read the secret.
+    pub fn generate_address_and_secret_with_receipts(
+        &self,
+        base_address: &Address,
+        tag: Option<&str>,
+        receipt_details: &ReceiptDetails,
+    ) -> Result<(Address, [u8; 32]), AddressError> {
+        let token = base64::encode_config(
+            &receipt_details.seal(&self.receipt_key),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let local_part = match tag {
+            Some(tag) if tag.is_empty() || tag.contains('.') => {
+                return Err(AddressError::InvalidFormat)
+            }
+            Some(tag) => format!("{}{}{}", token, CONNECTION_TAG_SEPARATOR, tag),
+            None => token,
+        };
+        let destination_account = base_address.with_suffix(local_part.as_bytes())?;
+        let shared_secret = hmac_sha256(&self.secret_generator[..], local_part.as_bytes());
+
+        debug!("Generated address: {}", destination_account);
+        Ok((destination_account, shared_secret))
+    }
+
+    /// Returns the receipt details of a `destination_account` generated with
+    /// `generate_address_and_secret_with_receipts`, or `None` for the other addresses
+    pub fn receipt_details(&self, destination_account: &Address) -> Option<ReceiptDetails> {
+        let local_part = destination_account.segments().rev().next()?;
+        let token = local_part.split(CONNECTION_TAG_SEPARATOR).next()?;
+        let sealed = base64::decode_config(token, base64::URL_SAFE_NO_PAD).ok()?;
+        ReceiptDetails::open(&self.receipt_key, &sealed)
+    }
+
     /// Rederive the `shared_secret` from a `destination_account`.
     ///
     /// Although it is not strictly necessary, this uses the same logic as the Javascript
@@ -84,6 +166,25 @@ impl ConnectionGenerator {
     }
 }
 
+/// Returns the connection tag of a destination address under the given receiver address
+/// (see [`generate_address_and_secret_with_tag`](./struct.ConnectionGenerator.html#method.generate_address_and_secret_with_tag))
+pub fn connection_tag<'a>(destination: &'a Address, receiver_address: &Address) -> Option<&'a str> {
+    let destination: &[u8] = destination.as_ref();
+    let receiver_address: &[u8] = receiver_address.as_ref();
+    let local_part = destination
+        .strip_prefix(receiver_address)
+        .and_then(|rest| rest.strip_prefix(b"."))?;
+    // The tag is only in the last segment, after the token
+    let last_segment = local_part.rsplit(|byte| *byte == b'.').next()?;
+    let separator = last_segment
+        .iter()
+        .position(|byte| *byte == CONNECTION_TAG_SEPARATOR as u8)?;
+    // Addresses are always valid UTF-8
+    str::from_utf8(&last_segment[separator + 1..])
+        .ok()
+        .filter(|tag| !tag.is_empty())
+}
+
 /// Notification that STREAM fulfilled a packet and received a single Interledger payment, used by Pubsub API consumers
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PaymentNotification {
@@ -103,12 +204,25 @@ pub struct PaymentNotification {
     /// In that case, the PaymentNotification will have `amount: 0`
     /// and `connection_closed: true`.
     pub connection_closed: bool,
+    /// The tag of the STREAM connection the packet was received on, if it was generated with one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection_tag: Option<String>,
 }
 
 /// The Ok(ReceiveOk) variant of receive_money(...) return result
 struct ReceiveOk {
     fulfill: Fulfill,
     sequence: u64,
+    /// The amount received on each of the packet's streams, by stream id
+    stream_amounts: Vec<(u64, u64)>,
+}
+
+/// The receipts of the tagged connection a packet was sent over, and whether the packet
+/// would exceed its receive max
+#[derive(Clone, Copy, Debug)]
+struct ReceiveLimit {
+    receipts: ConnectionReceipts,
+    exceeded: bool,
 }
 
 /// The Err(ReceiveErr) variant of receive_money(...) return result
@@ -121,6 +235,10 @@ enum ReceiveErr {
     /// failures.
     InvalidPacket,
 
+    /// The packet only keeps the connection alive, and is rejected with the sender's
+    /// expected answer
+    KeepAlive(Reject),
+
     /// We definitely reject and terminate processing of this transaction.
     Rejection {
         reject: Reject,
@@ -153,13 +271,20 @@ pub trait StreamNotificationsStore {
 /// Note this does **not** maintain STREAM state, but instead fulfills
 /// all incoming packets to collect the money.
 ///
-/// This does not currently support handling data sent via STREAM.
+/// This does not currently support handling data sent via STREAM. By default, the data is
+/// silently dropped; see `with_reject_data` to close the connections which send data instead.
 #[derive(Clone)]
 pub struct StreamReceiverService<S, O: OutgoingService<A>, A: Account> {
     connection_generator: ConnectionGenerator,
     next: O,
     account_type: PhantomData<A>,
     store: S,
+    packet_limits: StreamPacketLimits,
+    reject_data: bool,
+    dampener: Option<RejectDampener>,
+    keep_alive_monitor: Option<KeepAliveMonitor>,
+    receive_store: Option<Arc<dyn StreamReceiveStore + Send + Sync>>,
+    replay_store: Option<Arc<dyn StreamReplayStore + Send + Sync>>,
 }
 
 impl<S, O, A> StreamReceiverService<S, O, A>
@@ -175,8 +300,71 @@ where
             next,
             account_type: PhantomData,
             store,
+            packet_limits: StreamPacketLimits::default(),
+            reject_data: false,
+            dampener: None,
+            keep_alive_monitor: None,
+            receive_store: None,
+            replay_store: None,
         }
     }
+
+    /// Sets the limits enforced when parsing incoming STREAM packets
+    pub fn with_packet_limits(mut self, packet_limits: StreamPacketLimits) -> Self {
+        self.packet_limits = packet_limits;
+        self
+    }
+
+    /// Sets whether packets containing `StreamData` frames are rejected, closing their
+    /// connection with a `ProtocolViolation` error, rather than fulfilled without delivering
+    /// the data anywhere. This way senders do not believe their data was delivered.
+    pub fn with_reject_data(mut self, reject_data: bool) -> Self {
+        self.reject_data = reject_data;
+        self
+    }
+
+    /// Rejects the packets to connections whose previous packets from the same account
+    /// repeatedly failed to decrypt right away, as specified by the [`policy`](./struct.DampeningPolicy.html),
+    /// rather than decrypting each of them. This protects the receiver against senders stuck
+    /// retrying with the wrong shared secret.
+    pub fn with_reject_dampening(mut self, policy: DampeningPolicy) -> Self {
+        self.dampener = Some(RejectDampener::new(policy));
+        self
+    }
+
+    /// Tracks the connections whose senders send keep-alive packets with the given
+    /// [`monitor`](./struct.KeepAliveMonitor.html), so that the receiver is notified when
+    /// the path from their sender breaks
+    pub fn with_keep_alive_monitor(mut self, monitor: KeepAliveMonitor) -> Self {
+        self.keep_alive_monitor = Some(monitor);
+        self
+    }
+
+    /// Tracks the amounts received over the tagged connections with the given `store`, so
+    /// that the packets which would exceed the receive max set for their connection tag are
+    /// rejected. The receive max and the total received are advertised to the senders in
+    /// the `StreamMaxMoney` frames (for all the streams of the connection).
+    ///
+    /// The store also tracks the totals received on the streams of the connections generated
+    /// with receipt details, which the receipts sent back to their senders are signed for.
+    /// Without a store, no receipts are sent.
+    pub fn with_receive_store<R>(mut self, store: R) -> Self
+    where
+        R: StreamReceiveStore + Send + Sync + 'static,
+    {
+        self.receive_store = Some(Arc::new(store));
+        self
+    }
+
+    /// Records the fulfilled packets with the given `store` until they expire, so that the
+    /// identical packets replayed in the meantime are rejected rather than fulfilled again
+    pub fn with_replay_protection<R>(mut self, store: R) -> Self
+    where
+        R: StreamReplayStore + Send + Sync + 'static,
+    {
+        self.replay_store = Some(Arc::new(store));
+        self
+    }
 }
 
 #[async_trait]
@@ -191,6 +379,7 @@ where
     async fn send_request(&mut self, request: OutgoingRequest<A>) -> IlpResult {
         let to_username = request.to.username().clone();
         let from_username = request.from.username().clone();
+        let from_id = request.from.id();
         let amount = request.prepare.amount();
 
         let destination = request.prepare.destination();
@@ -199,16 +388,220 @@ where
 
         // The case where the request is bound for this server
         if dest.starts_with(to_address.as_ref()) {
+            if let Some(ref dampener) = self.dampener {
+                if dampener.is_dampened(from_id, &destination) {
+                    trace!(
+                        "Rejecting packet from account {} to dampened connection {}",
+                        from_id,
+                        destination
+                    );
+                    return Err(RejectBuilder {
+                        code: ErrorCode::F06_UNEXPECTED_PAYMENT,
+                        message: b"Too many packets to this connection could not be decrypted",
+                        triggered_by: Some(to_address),
+                        data: &[],
+                    }
+                    .build());
+                }
+            }
+
             let shared_secret = self.connection_generator.rederive_secret(&destination);
-            let response = receive_money(
+            let connection_tag =
+                connection_tag(&destination, &to_address).map(|tag| tag.to_owned());
+            let account_id = request.to.id();
+            let receive_limit = match (self.receive_store.as_ref(), connection_tag.as_deref()) {
+                (Some(store), Some(tag)) => {
+                    let receipts = if amount > 0 {
+                        store
+                            .add_connection_receipt(account_id, tag, amount)
+                            .await
+                            .map(|(receipts, added)| ReceiveLimit {
+                                receipts,
+                                exceeded: !added,
+                            })
+                    } else {
+                        store
+                            .get_connection_receipts(account_id, tag)
+                            .await
+                            .map(|receipts| ReceiveLimit {
+                                receipts,
+                                exceeded: false,
+                            })
+                    };
+                    match receipts {
+                        Ok(receive_limit) => Some(receive_limit),
+                        Err(err) => {
+                            error!(
+                                "Error tracking the amount received over connection tag {}: {}",
+                                tag, err
+                            );
+                            return Err(RejectBuilder {
+                                code: ErrorCode::T00_INTERNAL_ERROR,
+                                message: b"Unable to track the amount received",
+                                triggered_by: Some(to_address),
+                                data: &[],
+                            }
+                            .build());
+                        }
+                    }
+                }
+                _ => None,
+            };
+            let mut response = receive_money(
                 &shared_secret,
                 &to_address,
                 request.to.asset_code(),
                 request.to.asset_scale(),
                 &request.prepare,
+                &self.packet_limits,
+                self.reject_data,
+                receive_limit,
+                &[],
+                None,
             );
+            let fulfilled_sequence = match response {
+                Ok(ReceiveOk { sequence, .. }) => Some(sequence),
+                _ => None,
+            };
+            if let (Some(store), Some(sequence)) = (self.replay_store.as_ref(), fulfilled_sequence)
+            {
+                let key = fulfilled_packet_key(
+                    request.prepare.execution_condition(),
+                    connection_tag.as_deref(),
+                    sequence,
+                );
+                let ttl = request
+                    .prepare
+                    .expires_at()
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+                    .max(Duration::from_millis(1));
+                let rejection: Option<(ErrorCode, &[u8])> =
+                    match store.record_fulfilled_packet(key, ttl).await {
+                        Ok(true) => None,
+                        Ok(false) => {
+                            debug!(
+                                "Rejecting replayed packet {} sent to {}",
+                                sequence, destination
+                            );
+                            Some((
+                                ErrorCode::F06_UNEXPECTED_PAYMENT,
+                                b"Packet was already fulfilled",
+                            ))
+                        }
+                        Err(err) => {
+                            error!("Error recording fulfilled packet {}: {}", sequence, err);
+                            Some((
+                                ErrorCode::T00_INTERNAL_ERROR,
+                                b"Unable to record the fulfilled packet",
+                            ))
+                        }
+                    };
+                if rejection.is_some() {
+                    // Still answers with the connection's state, so that the sender can
+                    // read it from the reject like from any other one
+                    response = receive_money(
+                        &shared_secret,
+                        to_address,
+                        request.to.asset_code(),
+                        request.to.asset_scale(),
+                        &request.prepare,
+                        &self.packet_limits,
+                        self.reject_data,
+                        receive_limit,
+                        &[],
+                        rejection,
+                    );
+                }
+            }
+            // The receipts are only signed once the packet is certain to be fulfilled, so that
+            // their totals never include amounts which were not received
+            let receipt_details = self.connection_generator.receipt_details(&destination);
+            let stream_amounts = match response {
+                Ok(ReceiveOk {
+                    ref stream_amounts, ..
+                }) => stream_amounts.clone(),
+                _ => Vec::new(),
+            };
+            if let (Some(details), Some(store)) = (receipt_details, self.receive_store.as_ref()) {
+                let mut stream_receipts = Vec::with_capacity(stream_amounts.len());
+                for (stream_id, stream_amount) in stream_amounts {
+                    let key = details.stream_key(stream_id);
+                    let receipts = if stream_amount > 0 {
+                        store
+                            .add_connection_receipt(account_id, &key, stream_amount)
+                            .await
+                            .map(|(receipts, _)| receipts)
+                    } else {
+                        store.get_connection_receipts(account_id, &key).await
+                    };
+                    match receipts {
+                        Ok(receipts) => stream_receipts.push((
+                            stream_id,
+                            details.receipt(stream_id, receipts.total_received),
+                        )),
+                        Err(err) => error!(
+                            "Error tracking the amount received on stream {} of {}: {}",
+                            stream_id, destination, err
+                        ),
+                    }
+                }
+                if !stream_receipts.is_empty() {
+                    response = receive_money(
+                        &shared_secret,
+                        &to_address,
+                        request.to.asset_code(),
+                        request.to.asset_scale(),
+                        &request.prepare,
+                        &self.packet_limits,
+                        self.reject_data,
+                        receive_limit,
+                        &stream_receipts,
+                        None,
+                    );
+                }
+            }
+            // The amount added to the connection's receipts is only kept if it was received
+            if let (Some(store), Some(tag), Some(receive_limit)) = (
+                self.receive_store.as_ref(),
+                connection_tag.as_deref(),
+                receive_limit,
+            ) {
+                if amount > 0 && !receive_limit.exceeded && response.is_err() {
+                    if let Err(err) = store
+                        .remove_connection_receipt(account_id, tag, amount)
+                        .await
+                    {
+                        error!(
+                            "Error removing the amount {} which was not received over connection tag {}: {}",
+                            amount, tag, err
+                        );
+                    }
+                }
+            }
+            if let Some(ref dampener) = self.dampener {
+                match response {
+                    Err(ReceiveErr::InvalidPacket) => {
+                        dampener.record_failure(from_id, &destination)
+                    }
+                    _ => dampener.record_success(from_id, &destination),
+                }
+            }
+            if let Some(ref monitor) = self.keep_alive_monitor {
+                match response {
+                    Err(ReceiveErr::KeepAlive(_)) => monitor.record_keep_alive(&destination),
+                    Err(ReceiveErr::Rejection {
+                        connection_closed: true,
+                        ..
+                    }) => monitor.forget(&destination),
+                    Err(ReceiveErr::InvalidPacket) => {}
+                    _ => monitor.record_activity(&destination),
+                }
+            }
             match response {
-                Ok(ReceiveOk { fulfill, sequence }) => {
+                Ok(ReceiveOk {
+                    fulfill, sequence, ..
+                }) => {
                     self.store
                         .publish_payment_notification(PaymentNotification {
                             to_username,
@@ -218,6 +611,7 @@ where
                             timestamp: DateTime::<Utc>::from(SystemTime::now()).to_rfc3339(),
                             sequence,
                             connection_closed: false,
+                            connection_tag,
                         });
                     Ok(fulfill)
                 }
@@ -233,6 +627,7 @@ where
                     // </historical_comment>
                     self.next.send_request(request).await
                 }
+                Err(ReceiveErr::KeepAlive(reject)) => Err(reject),
                 Err(ReceiveErr::Rejection {
                     reject,
                     sequence,
@@ -248,6 +643,7 @@ where
                                 timestamp: DateTime::<Utc>::from(SystemTime::now()).to_rfc3339(),
                                 sequence,
                                 connection_closed: true,
+                                connection_tag,
                             });
                     }
 
@@ -260,8 +656,7 @@ where
     }
 }
 
-// TODO send asset code and scale back to sender also
-#[allow(clippy::cognitive_complexity)]
+#[allow(clippy::cognitive_complexity, clippy::too_many_arguments)]
 fn receive_money(
     shared_secret: &[u8; 32],
     // Our node's ILP Address ( we are the receiver, so we should return that
@@ -270,34 +665,92 @@ fn receive_money(
     asset_code: &str,
     asset_scale: u8,
     prepare: &Prepare,
+    packet_limits: &StreamPacketLimits,
+    reject_data: bool,
+    receive_limit: Option<ReceiveLimit>,
+    // The receipts of the totals received on the packet's streams, by stream id
+    stream_receipts: &[(u64, Vec<u8>)],
+    // Code and message to reject the packet with even if it could be fulfilled
+    rejection: Option<(ErrorCode, &[u8])>,
 ) -> Result<ReceiveOk, ReceiveErr> {
-    // Generate fulfillment
-    let fulfillment = generate_fulfillment(&shared_secret[..], prepare.data());
-    let condition = hash_sha256(&fulfillment);
-    let is_fulfillable = condition == prepare.execution_condition();
-
     let prepare_amount = prepare.amount();
 
-    // Creating a copy for the prepare.data() cannot be avoided, as the decryption happens in place
+    // Copying the prepare.data() once cannot be avoided, as the decryption happens in place
     // while the outer Prepare needs to remain unchanged.
-    let copied_data = BytesMut::from(prepare.data());
+    let stream_packet = match StreamPacketDecoder::default()
+        .with_limits(*packet_limits)
+        .decode(shared_secret, prepare.data())
+    {
+        Ok(stream_packet) => stream_packet,
+        // The packet is addressed to us since it decrypted, but it is malformed
+        Err(err @ StreamPacketError::InvalidFrame(..)) => {
+            debug!("Rejecting packet with a malformed frame: {}", err);
+            return Err(ReceiveErr::Rejection {
+                reject: RejectBuilder {
+                    code: ErrorCode::F06_UNEXPECTED_PAYMENT,
+                    message: err.to_string().as_bytes(),
+                    triggered_by: Some(ilp_address),
+                    data: &[],
+                }
+                .build(),
+                sequence: 0,
+                connection_closed: false,
+            });
+        }
+        Err(_) => return Err(ReceiveErr::InvalidPacket),
+    };
 
-    let stream_packet = StreamPacket::from_encrypted(shared_secret, copied_data)
-        .map_err(|_| ReceiveErr::InvalidPacket)?;
+    // Zero-amount packets without any frames only keep an idle connection alive, so answer
+    // them right away, without deriving their fulfillment
+    if prepare_amount == 0 && stream_packet.frames().next().is_none() {
+        trace!("Answering keep-alive packet {}", stream_packet.sequence());
+        let response_packet = StreamPacketBuilder {
+            sequence: stream_packet.sequence(),
+            ilp_packet_type: IlpPacketType::Reject,
+            prepare_amount: 0,
+            frames: &[],
+        }
+        .build();
+        let reject = RejectBuilder {
+            code: ErrorCode::F99_APPLICATION_ERROR,
+            message: &[],
+            triggered_by: Some(&ilp_address),
+            data: &response_packet.into_encrypted(shared_secret)[..],
+        }
+        .build();
+        return Err(ReceiveErr::KeepAlive(reject));
+    }
+
+    // Generate fulfillment
+    let fulfillment = generate_fulfillment(&shared_secret[..], prepare.data());
+    let condition = hash_sha256(&fulfillment);
+    let is_fulfillable = condition == prepare.execution_condition();
 
     let mut response_frames: Vec<Frame> = Vec::new();
+    let mut stream_shares: Vec<(u64, u64)> = Vec::new();
     let mut connection_closed = false;
+    let mut padded = false;
+    let mut has_data = false;
+    let mut sends_asset_details = false;
+
+    // Without a receive limit, tell the sender the stream can handle lots of money
+    let (total_received, receive_max, exceeds_receive_max) = match receive_limit {
+        Some(ReceiveLimit { receipts, exceeded }) => (
+            receipts.total_received,
+            receipts.receive_max.unwrap_or_else(u64::max_value),
+            exceeded,
+        ),
+        None => (0, u64::max_value(), false),
+    };
 
     // Handle STREAM frames
-    // TODO reject if they send data?
     for frame in stream_packet.frames() {
-        // Tell the sender the stream can handle lots of money
         if let Frame::StreamMoney(ref frame) = frame {
+            stream_shares.push((frame.stream_id, frame.shares));
             response_frames.push(Frame::StreamMaxMoney(StreamMaxMoneyFrame {
                 stream_id: frame.stream_id,
-                // TODO will returning zero here cause problems?
-                total_received: 0,
-                receive_max: u64::max_value(),
+                total_received,
+                receive_max,
             }));
         }
 
@@ -306,10 +759,23 @@ fn receive_money(
         // ConnectionNewAddress frame once, so we expect that we will only have
         // to respond with the ConnectionAssetDetails frame only one time.
         if let Frame::ConnectionNewAddress(_) = frame {
-            response_frames.push(Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
-                source_asset_code: asset_code,
-                source_asset_scale: asset_scale,
-            }));
+            sends_asset_details = true;
+        }
+
+        // If the sender announced an asset which differs from ours, advise it of our
+        // asset with every response, so that it computes the delivered amounts in the
+        // right units even if it missed the response to its ConnectionNewAddress frame
+        if let Frame::ConnectionAssetDetails(ref frame) = frame {
+            if frame.source_asset_code != asset_code || frame.source_asset_scale != asset_scale {
+                trace!(
+                    "Sender's asset ({} with scale {}) differs from ours ({} with scale {})",
+                    frame.source_asset_code,
+                    frame.source_asset_scale,
+                    asset_code,
+                    asset_scale
+                );
+                sends_asset_details = true;
+            }
         }
 
         // The last packet contains the ConnectionClose frame;
@@ -318,17 +784,66 @@ fn receive_money(
         if let Frame::ConnectionClose(_) = frame {
             connection_closed = true;
         }
+
+        if let Frame::Padding(_) = frame {
+            padded = true;
+        }
+
+        if let Frame::StreamData(_) = frame {
+            has_data = true;
+        }
     }
 
+    if sends_asset_details {
+        response_frames.push(Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+            source_asset_code: asset_code,
+            source_asset_scale: asset_scale,
+        }));
+    }
+
+    // We cannot deliver the data anywhere, so close the connection rather than letting the
+    // sender believe it was received
+    let rejects_data = reject_data && has_data;
+    if rejects_data {
+        debug!("Closing connection because the sender sent data, which is not supported");
+        response_frames.push(Frame::ConnectionClose(ConnectionCloseFrame {
+            code: super::packet::ErrorCode::ProtocolViolation,
+            message: "Data is not supported by this receiver",
+        }));
+        connection_closed = true;
+    }
+
+    // If the sender pads its packets, pad the responses to the same size so that they
+    // do not reveal more than the requests
+    let padding = if padded {
+        Some(StreamPadding::Fixed(prepare.data().len()))
+    } else {
+        None
+    };
+    let build_response = |builder: StreamPacketBuilder| match &padding {
+        Some(padding) => builder.build_padded(padding),
+        None => builder.build(),
+    };
+
     // Return Fulfill or Reject Packet
-    if is_fulfillable && prepare_amount >= stream_packet.prepare_amount() {
-        let response_packet = StreamPacketBuilder {
+    if rejection.is_none()
+        && is_fulfillable
+        && prepare_amount >= stream_packet.prepare_amount()
+        && !rejects_data
+        && !exceeds_receive_max
+    {
+        response_frames.extend(stream_receipts.iter().map(|(stream_id, receipt)| {
+            Frame::StreamReceipt(StreamReceiptFrame {
+                stream_id: *stream_id,
+                receipt,
+            })
+        }));
+        let response_packet = build_response(StreamPacketBuilder {
             sequence: stream_packet.sequence(),
             ilp_packet_type: IlpPacketType::Fulfill,
             prepare_amount,
             frames: &response_frames,
-        }
-        .build();
+        });
         debug!(
             "Fulfilling prepare for amount {} with fulfillment: {:?} and encrypted stream packet: {:?}",
             prepare_amount,
@@ -344,15 +859,15 @@ fn receive_money(
         Ok(ReceiveOk {
             fulfill,
             sequence: stream_packet.sequence(),
+            stream_amounts: split_amount(prepare_amount, &stream_shares),
         })
     } else {
-        let response_packet = StreamPacketBuilder {
+        let response_packet = build_response(StreamPacketBuilder {
             sequence: stream_packet.sequence(),
             ilp_packet_type: IlpPacketType::Reject,
             prepare_amount,
             frames: &response_frames,
-        }
-        .build();
+        });
         if !is_fulfillable {
             debug!("Packet is unfulfillable");
         } else if prepare_amount < stream_packet.prepare_amount() {
@@ -361,15 +876,21 @@ fn receive_money(
                 prepare_amount,
                 stream_packet.prepare_amount()
             );
+        } else if exceeds_receive_max {
+            debug!(
+                "Receiving {} would exceed the receive max {} of the connection, which already received {}",
+                prepare_amount, receive_max, total_received
+            );
         }
         debug!(
             "Rejecting Prepare and including encrypted stream packet {:?}",
             response_packet
         );
         let encrypted_response = response_packet.into_encrypted(shared_secret);
+        let (code, message) = rejection.unwrap_or((ErrorCode::F99_APPLICATION_ERROR, &[]));
         let reject = RejectBuilder {
-            code: ErrorCode::F99_APPLICATION_ERROR,
-            message: &[],
+            code,
+            message,
             triggered_by: Some(&ilp_address),
             data: &encrypted_response[..],
         }
@@ -377,11 +898,39 @@ fn receive_money(
         Err(ReceiveErr::Rejection {
             reject,
             sequence: stream_packet.sequence(),
-            connection_closed,
+            // The packets rejected with another code do not close the connection: they were
+            // either fulfilled before or are sent again
+            connection_closed: connection_closed && rejection.is_none(),
         })
     }
 }
 
+/// Splits the amount between the streams in proportion to their shares. The remainder of
+/// the division goes to the last stream
+fn split_amount(amount: u64, stream_shares: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let total_shares: u128 = stream_shares
+        .iter()
+        .map(|(_, shares)| *shares as u128)
+        .sum();
+    if total_shares == 0 {
+        return Vec::new();
+    }
+    let mut remaining = amount;
+    stream_shares
+        .iter()
+        .enumerate()
+        .map(|(index, (stream_id, shares))| {
+            let stream_amount = if index == stream_shares.len() - 1 {
+                remaining
+            } else {
+                (amount as u128 * *shares as u128 / total_shares) as u64
+            };
+            remaining -= stream_amount;
+            (*stream_id, stream_amount)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod connection_generator {
     use super::*;
@@ -405,6 +954,102 @@ mod connection_generator {
             shared_secret
         );
     }
+
+    #[test]
+    fn generates_tagged_address() {
+        let receiver_address = Address::from_str("example.receiver").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[9; 32][..]));
+        let (destination_account, shared_secret) = connection_generator
+            .generate_address_and_secret_with_tag(&receiver_address, "order-12~3")
+            .unwrap();
+
+        assert_eq!(
+            connection_tag(&destination_account, &receiver_address),
+            Some("order-12~3")
+        );
+        assert_eq!(
+            connection_generator.rederive_secret(&destination_account),
+            shared_secret
+        );
+
+        // Changing the tag changes the shared secret
+        let tampered =
+            Address::from_str(&format!("{}4", &destination_account.to_string())).unwrap();
+        assert_ne!(
+            connection_generator.rederive_secret(&tampered),
+            shared_secret
+        );
+
+        for invalid_tag in &["", "with.dot", "with space"] {
+            assert!(connection_generator
+                .generate_address_and_secret_with_tag(&receiver_address, invalid_tag)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn embeds_receipt_details_in_address() {
+        let receiver_address = Address::from_str("example.receiver").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[9; 32][..]));
+        let details = ReceiptDetails {
+            nonce: [1; 16],
+            secret: [2; 32],
+        };
+        let (destination_account, shared_secret) = connection_generator
+            .generate_address_and_secret_with_receipts(
+                &receiver_address,
+                Some("order-12"),
+                &details,
+            )
+            .unwrap();
+
+        assert_eq!(
+            connection_generator.receipt_details(&destination_account),
+            Some(details)
+        );
+        assert_eq!(
+            connection_tag(&destination_account, &receiver_address),
+            Some("order-12")
+        );
+        assert_eq!(
+            connection_generator.rederive_secret(&destination_account),
+            shared_secret
+        );
+        // The secret does not appear in the address
+        let secret = base64::encode_config(&details.secret, base64::URL_SAFE_NO_PAD);
+        assert!(!destination_account.to_string().contains(&secret[..8]));
+
+        // Only the generator which sealed the details can open them
+        let other_generator = ConnectionGenerator::new(Bytes::from(&[8; 32][..]));
+        assert_eq!(other_generator.receipt_details(&destination_account), None);
+        let (untagged, _) = connection_generator.generate_address_and_secret(&receiver_address);
+        assert_eq!(connection_generator.receipt_details(&untagged), None);
+    }
+
+    #[test]
+    fn splits_amounts_between_streams() {
+        assert!(split_amount(100, &[]).is_empty());
+        assert!(split_amount(100, &[(1, 0)]).is_empty());
+        assert_eq!(split_amount(100, &[(1, 1)]), vec![(1, 100)]);
+        assert_eq!(split_amount(100, &[(1, 1), (3, 2)]), vec![(1, 33), (3, 67)]);
+        assert_eq!(
+            split_amount(u64::max_value(), &[(1, u64::max_value()), (3, 1)]),
+            vec![(1, u64::max_value() - 1), (3, 1)]
+        );
+    }
+
+    #[test]
+    fn only_finds_tags_under_receiver_address() {
+        let receiver_address = Address::from_str("example.receiver").unwrap();
+        let connection_generator = ConnectionGenerator::new(Bytes::from(&[9; 32][..]));
+        let (untagged, _) = connection_generator.generate_address_and_secret(&receiver_address);
+        assert_eq!(connection_tag(&untagged, &receiver_address), None);
+
+        let other = Address::from_str("example.receiver2.token~tag").unwrap();
+        assert_eq!(connection_tag(&other, &receiver_address), None);
+        let nested = Address::from_str("example.receiver.child.token~tag").unwrap();
+        assert_eq!(connection_tag(&nested, &receiver_address), Some("tag"));
+    }
 }
 
 #[cfg(test)]
@@ -424,6 +1069,7 @@ fn test_stream_packet() -> StreamPacket {
 #[cfg(test)]
 mod receiving_money {
     use super::*;
+    use bytes::BytesMut;
     use interledger_packet::PrepareBuilder;
     use std::convert::TryFrom;
 
@@ -452,8 +1098,219 @@ mod receiving_money {
         .build();
 
         let shared_secret = connection_generator.rederive_secret(&prepare.destination());
-        let result = receive_money(&shared_secret, &ilp_address, "ABC", 9, &prepare);
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &StreamPacketLimits::default(),
+            false,
+            None,
+            &[],
+            None,
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn pads_responses_to_padded_packets() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret);
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&ilp_address);
+        let data = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: 1,
+            frames: &[Frame::StreamMoney(StreamMoneyFrame {
+                stream_id: 1,
+                shares: 1,
+            })],
+        }
+        .build_padded(&StreamPadding::Fixed(256))
+        .into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+
+        let prepare = PrepareBuilder {
+            destination: destination_account,
+            amount: 100,
+            expires_at: UNIX_EPOCH,
+            data: &data[..],
+            execution_condition: &execution_condition,
+        }
+        .build();
+
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &StreamPacketLimits::default(),
+            false,
+            None,
+            &[],
+            None,
+        );
+        assert_eq!(result.unwrap().fulfill.data().len(), 256);
+    }
+
+    #[test]
+    fn closes_connections_which_send_data_if_configured() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret);
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&ilp_address);
+        let data = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: 1,
+            frames: &[
+                Frame::StreamMoney(StreamMoneyFrame {
+                    stream_id: 1,
+                    shares: 1,
+                }),
+                Frame::StreamData(StreamDataFrame {
+                    stream_id: 1,
+                    offset: 0,
+                    data: b"hello",
+                }),
+            ],
+        }
+        .build()
+        .into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+
+        let prepare = PrepareBuilder {
+            destination: destination_account,
+            amount: 100,
+            expires_at: UNIX_EPOCH,
+            data: &data[..],
+            execution_condition: &execution_condition,
+        }
+        .build();
+
+        // By default, the data is dropped
+        let limits = StreamPacketLimits::default();
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &limits,
+            false,
+            None,
+            &[],
+            None,
+        );
         assert!(result.is_ok());
+
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &limits,
+            true,
+            None,
+            &[],
+            None,
+        );
+        match result {
+            Err(ReceiveErr::Rejection {
+                reject,
+                connection_closed,
+                ..
+            }) => {
+                assert!(connection_closed);
+                let response =
+                    StreamPacket::from_encrypted(&shared_secret, BytesMut::from(reject.data()))
+                        .unwrap();
+                let close = response.frames().find_map(|frame| match frame {
+                    Frame::ConnectionClose(frame) => Some(frame.code),
+                    _ => None,
+                });
+                assert_eq!(close, Some(crate::packet::ErrorCode::ProtocolViolation));
+            }
+            _ => panic!("Expected the packet to be rejected"),
+        }
+    }
+
+    #[test]
+    fn advises_senders_with_other_assets_of_our_asset() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret);
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&ilp_address);
+
+        let receive_with_sender_asset = |asset_code: &str, asset_scale: u8| {
+            let data = StreamPacketBuilder {
+                ilp_packet_type: IlpPacketType::Prepare,
+                prepare_amount: 0,
+                sequence: 1,
+                frames: &[
+                    Frame::StreamMoney(StreamMoneyFrame {
+                        stream_id: 1,
+                        shares: 1,
+                    }),
+                    Frame::ConnectionAssetDetails(ConnectionAssetDetailsFrame {
+                        source_asset_code: asset_code,
+                        source_asset_scale: asset_scale,
+                    }),
+                ],
+            }
+            .build()
+            .into_encrypted(&shared_secret[..]);
+            let execution_condition = generate_condition(&shared_secret[..], &data);
+            let prepare = PrepareBuilder {
+                destination: destination_account.clone(),
+                amount: 100,
+                expires_at: UNIX_EPOCH,
+                data: &data[..],
+                execution_condition: &execution_condition,
+            }
+            .build();
+            let fulfill = receive_money(
+                &shared_secret,
+                &ilp_address,
+                "ABC",
+                9,
+                &prepare,
+                &StreamPacketLimits::default(),
+                false,
+                None,
+                &[],
+                None,
+            )
+            .unwrap()
+            .fulfill;
+            let response =
+                StreamPacket::from_encrypted(&shared_secret, BytesMut::from(fulfill.data()))
+                    .unwrap();
+            response.frames().find_map(|frame| match frame {
+                Frame::ConnectionAssetDetails(frame) => Some((
+                    frame.source_asset_code.to_string(),
+                    frame.source_asset_scale,
+                )),
+                _ => None,
+            })
+        };
+
+        assert_eq!(
+            receive_with_sender_asset("XYZ", 9),
+            Some(("ABC".to_string(), 9))
+        );
+        assert_eq!(
+            receive_with_sender_asset("ABC", 6),
+            Some(("ABC".to_string(), 9))
+        );
+        assert_eq!(receive_with_sender_asset("ABC", 9), None);
     }
 
     #[test]
@@ -477,7 +1334,18 @@ mod receiving_money {
         .build();
 
         let shared_secret = connection_generator.rederive_secret(&prepare.destination());
-        let result = receive_money(&shared_secret, &ilp_address, "ABC", 9, &prepare);
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &StreamPacketLimits::default(),
+            false,
+            None,
+            &[],
+            None,
+        );
         assert!(result.is_ok());
     }
 
@@ -503,10 +1371,73 @@ mod receiving_money {
         .build();
 
         let shared_secret = connection_generator.rederive_secret(&prepare.destination());
-        let result = receive_money(&shared_secret, &ilp_address, "ABC", 9, &prepare);
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &StreamPacketLimits::default(),
+            false,
+            None,
+            &[],
+            None,
+        );
         assert!(result.is_err());
     }
 
+    #[test]
+    fn answers_keep_alive_packets() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret);
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&ilp_address);
+
+        let data = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: 7,
+            frames: &[],
+        }
+        .build()
+        .into_encrypted(&shared_secret[..]);
+
+        let prepare = PrepareBuilder {
+            destination: destination_account,
+            amount: 0,
+            expires_at: UNIX_EPOCH,
+            data: &data[..],
+            execution_condition: &random_condition(),
+        }
+        .build();
+
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &StreamPacketLimits::default(),
+            false,
+            None,
+            &[],
+            None,
+        );
+        match result {
+            Err(ReceiveErr::KeepAlive(reject)) => {
+                assert_eq!(reject.code(), ErrorCode::F99_APPLICATION_ERROR);
+                let response =
+                    StreamPacket::from_encrypted(&shared_secret, BytesMut::from(reject.data()))
+                        .unwrap();
+                assert_eq!(response.sequence(), 7);
+                assert_eq!(response.ilp_packet_type(), IlpPacketType::Reject);
+                assert_eq!(response.frames().count(), 0);
+            }
+            _ => panic!("Keep-alive packet should be rejected"),
+        }
+    }
+
     #[test]
     fn rejects_too_little_money() {
         let ilp_address = Address::from_str("example.destination").unwrap();
@@ -539,7 +1470,18 @@ mod receiving_money {
         .build();
 
         let shared_secret = connection_generator.rederive_secret(&prepare.destination());
-        let result = receive_money(&shared_secret, &ilp_address, "ABC", 9, &prepare);
+        let result = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &StreamPacketLimits::default(),
+            false,
+            None,
+            &[],
+            None,
+        );
         assert!(result.is_err());
     }
 
@@ -557,9 +1499,20 @@ mod receiving_money {
             &hex!("b7d09d2e16e6f83c55b60e42fcd7c2b8ed49624a1df73c59b383dbe2e8690309")[..],
             "did not regenerate the same shared secret",
         );
-        let fulfill = receive_money(&shared_secret, &ilp_address, "ABC", 9, &prepare)
-            .expect("Receiver should be able to generate the fulfillment")
-            .fulfill;
+        let fulfill = receive_money(
+            &shared_secret,
+            &ilp_address,
+            "ABC",
+            9,
+            &prepare,
+            &StreamPacketLimits::default(),
+            false,
+            None,
+            &[],
+            None,
+        )
+        .expect("Receiver should be able to generate the fulfillment")
+        .fulfill;
         assert_eq!(
             &hash_sha256(fulfill.fulfillment())[..],
             &condition[..],
@@ -572,9 +1525,13 @@ mod receiving_money {
 mod stream_receiver_service {
     use super::*;
     use crate::test_helpers::*;
+    use bytes::BytesMut;
+    use interledger_errors::{StreamReceiveStoreError, StreamReplayStoreError};
     use interledger_packet::PrepareBuilder;
     use interledger_service::outgoing_service_fn;
+    use parking_lot::Mutex;
 
+    use std::collections::HashMap;
     use std::str::FromStr;
     use std::time::UNIX_EPOCH;
 
@@ -629,6 +1586,443 @@ mod stream_receiver_service {
         assert!(result.is_ok());
     }
 
+    #[derive(Clone, Default)]
+    struct RecordingStore {
+        notifications: Arc<Mutex<Vec<PaymentNotification>>>,
+    }
+
+    impl StreamNotificationsStore for RecordingStore {
+        type Account = TestAccount;
+
+        fn add_payment_notification_subscription(
+            &self,
+            _account_id: Uuid,
+            _sender: UnboundedSender<PaymentNotification>,
+        ) {
+        }
+
+        fn publish_payment_notification(&self, payment: PaymentNotification) {
+            self.notifications.lock().push(payment);
+        }
+
+        fn all_payment_subscription(&self) -> broadcast::Receiver<PaymentNotification> {
+            broadcast::channel(1).1
+        }
+    }
+
+    #[tokio::test]
+    async fn publishes_connection_tag_of_fulfilled_packets() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let (destination_account, shared_secret) = connection_generator
+            .generate_address_and_secret_with_tag(&ilp_address, "invoice-7")
+            .unwrap();
+        let stream_packet = test_stream_packet();
+        let data = stream_packet.into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+
+        let prepare = PrepareBuilder {
+            destination: destination_account.clone(),
+            amount: 100,
+            expires_at: UNIX_EPOCH,
+            data: &data[..],
+            execution_condition: &execution_condition,
+        }
+        .build();
+
+        let store = RecordingStore::default();
+        let mut service = StreamReceiverService::new(
+            server_secret.clone(),
+            store.clone(),
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> IlpResult {
+                panic!("shouldn't get here")
+            }),
+        );
+
+        let result = service
+            .send_request(OutgoingRequest {
+                from: TestAccount {
+                    id: Uuid::new_v4(),
+                    ilp_address: Address::from_str("example.sender").unwrap(),
+                    asset_code: "XYZ".to_string(),
+                    asset_scale: 9,
+                    max_packet_amount: None,
+                },
+                to: TestAccount {
+                    id: Uuid::new_v4(),
+                    ilp_address: ilp_address.clone(),
+                    asset_code: "XYZ".to_string(),
+                    asset_scale: 9,
+                    max_packet_amount: None,
+                },
+                original_amount: prepare.amount(),
+                prepare,
+            })
+            .await;
+        assert!(result.is_ok());
+
+        let notifications = store.notifications.lock();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].amount, 100);
+        assert_eq!(notifications[0].destination, destination_account);
+        assert_eq!(
+            notifications[0].connection_tag.as_deref(),
+            Some("invoice-7")
+        );
+        assert!(!notifications[0].connection_closed);
+    }
+
+    #[derive(Clone, Default)]
+    struct TestReceiveStore {
+        receipts: Arc<Mutex<HashMap<String, ConnectionReceipts>>>,
+    }
+
+    #[async_trait]
+    impl StreamReceiveStore for TestReceiveStore {
+        async fn set_connection_receive_max(
+            &self,
+            _account_id: Uuid,
+            connection_tag: &str,
+            receive_max: Option<u64>,
+        ) -> Result<(), StreamReceiveStoreError> {
+            let mut receipts = self.receipts.lock();
+            receipts
+                .entry(connection_tag.to_string())
+                .or_default()
+                .receive_max = receive_max;
+            Ok(())
+        }
+
+        async fn get_connection_receipts(
+            &self,
+            _account_id: Uuid,
+            connection_tag: &str,
+        ) -> Result<ConnectionReceipts, StreamReceiveStoreError> {
+            let receipts = self.receipts.lock();
+            Ok(receipts.get(connection_tag).cloned().unwrap_or_default())
+        }
+
+        async fn add_connection_receipt(
+            &self,
+            _account_id: Uuid,
+            connection_tag: &str,
+            amount: u64,
+        ) -> Result<(ConnectionReceipts, bool), StreamReceiveStoreError> {
+            let mut receipts = self.receipts.lock();
+            let connection = receipts.entry(connection_tag.to_string()).or_default();
+            if connection.exceeded_by(amount) {
+                return Ok((*connection, false));
+            }
+            connection.total_received += amount;
+            Ok((*connection, true))
+        }
+
+        async fn remove_connection_receipt(
+            &self,
+            _account_id: Uuid,
+            connection_tag: &str,
+            amount: u64,
+        ) -> Result<(), StreamReceiveStoreError> {
+            let mut receipts = self.receipts.lock();
+            if let Some(connection) = receipts.get_mut(connection_tag) {
+                connection.total_received -= amount;
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct TestReplayStore {
+        fulfilled: Arc<Mutex<HashMap<[u8; 32], Duration>>>,
+        unavailable: bool,
+    }
+
+    #[async_trait]
+    impl StreamReplayStore for TestReplayStore {
+        async fn record_fulfilled_packet(
+            &self,
+            key: [u8; 32],
+            ttl: Duration,
+        ) -> Result<bool, StreamReplayStoreError> {
+            if self.unavailable {
+                return Err(StreamReplayStoreError::Other(Box::new(std::fmt::Error)));
+            }
+            let mut fulfilled = self.fulfilled.lock();
+            if fulfilled.contains_key(&key) {
+                return Ok(false);
+            }
+            fulfilled.insert(key, ttl);
+            Ok(true)
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_replayed_packets() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&ilp_address);
+        let data = test_stream_packet().into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+        let prepare = PrepareBuilder {
+            destination: destination_account,
+            amount: 100,
+            expires_at: SystemTime::now() + Duration::from_secs(30),
+            data: &data[..],
+            execution_condition: &execution_condition,
+        }
+        .build();
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: ilp_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+
+        let replay_store = TestReplayStore::default();
+        let mut service = StreamReceiverService::new(
+            server_secret.clone(),
+            DummyStore,
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> IlpResult {
+                panic!("shouldn't get here")
+            }),
+        )
+        .with_replay_protection(replay_store.clone());
+
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            results.push(
+                service
+                    .send_request(OutgoingRequest {
+                        from: account.clone(),
+                        to: account.clone(),
+                        original_amount: prepare.amount(),
+                        prepare: prepare.clone(),
+                    })
+                    .await,
+            );
+        }
+        assert!(results[0].is_ok());
+        let reject = results[1].as_ref().unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F06_UNEXPECTED_PAYMENT);
+        // The sender can still read the connection's state from the reject
+        let response =
+            StreamPacket::from_encrypted(&shared_secret, BytesMut::from(reject.data())).unwrap();
+        assert_eq!(response.ilp_packet_type(), IlpPacketType::Reject);
+        assert_eq!(response.sequence(), test_stream_packet().sequence());
+
+        // The packet is remembered until it expires
+        {
+            let fulfilled = replay_store.fulfilled.lock();
+            assert_eq!(fulfilled.len(), 1);
+            let ttl = *fulfilled.values().next().unwrap();
+            assert!(ttl > Duration::from_secs(25) && ttl <= Duration::from_secs(30));
+        }
+
+        // The packets are not fulfilled while they cannot be recorded
+        let mut service = StreamReceiverService::new(
+            server_secret,
+            DummyStore,
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> IlpResult {
+                panic!("shouldn't get here")
+            }),
+        )
+        .with_replay_protection(TestReplayStore {
+            unavailable: true,
+            ..Default::default()
+        });
+        let reject = service
+            .send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account,
+                original_amount: prepare.amount(),
+                prepare,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::T00_INTERNAL_ERROR);
+        let response =
+            StreamPacket::from_encrypted(&shared_secret, BytesMut::from(reject.data())).unwrap();
+        assert_eq!(response.ilp_packet_type(), IlpPacketType::Reject);
+    }
+
+    #[tokio::test]
+    async fn enforces_and_advertises_receive_max() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let (destination_account, shared_secret) = connection_generator
+            .generate_address_and_secret_with_tag(&ilp_address, "invoice-7")
+            .unwrap();
+        let data = test_stream_packet().into_encrypted(&shared_secret[..]);
+        let execution_condition = generate_condition(&shared_secret[..], &data);
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: ilp_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+
+        let receive_store = TestReceiveStore::default();
+        receive_store
+            .set_connection_receive_max(account.id, "invoice-7", Some(150))
+            .await
+            .unwrap();
+        let mut service = StreamReceiverService::new(
+            server_secret.clone(),
+            DummyStore,
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> IlpResult {
+                panic!("shouldn't get here")
+            }),
+        )
+        .with_receive_store(receive_store.clone());
+
+        let max_money = |data: &[u8]| {
+            let response =
+                StreamPacket::from_encrypted(&shared_secret, BytesMut::from(data)).unwrap();
+            response.frames().find_map(|frame| match frame {
+                Frame::StreamMaxMoney(frame) => Some((frame.total_received, frame.receive_max)),
+                _ => None,
+            })
+        };
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            let prepare = PrepareBuilder {
+                destination: destination_account.clone(),
+                amount: 100,
+                expires_at: UNIX_EPOCH,
+                data: &data[..],
+                execution_condition: &execution_condition,
+            }
+            .build();
+            results.push(
+                service
+                    .send_request(OutgoingRequest {
+                        from: account.clone(),
+                        to: account.clone(),
+                        original_amount: prepare.amount(),
+                        prepare,
+                    })
+                    .await,
+            );
+        }
+
+        let fulfill = results[0].as_ref().unwrap();
+        assert_eq!(max_money(fulfill.data()), Some((100, 150)));
+        // The second packet would exceed the receive max
+        let reject = results[1].as_ref().unwrap_err();
+        assert_eq!(reject.code(), ErrorCode::F99_APPLICATION_ERROR);
+        assert_eq!(max_money(reject.data()), Some((100, 150)));
+        assert_eq!(
+            receive_store
+                .get_connection_receipts(account.id, "invoice-7")
+                .await
+                .unwrap()
+                .total_received,
+            100
+        );
+    }
+
+    #[tokio::test]
+    async fn signs_receipts_of_the_totals_received() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let details = ReceiptDetails {
+            nonce: [3; 16],
+            secret: [4; 32],
+        };
+        let (destination_account, shared_secret) = connection_generator
+            .generate_address_and_secret_with_receipts(&ilp_address, None, &details)
+            .unwrap();
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: ilp_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+
+        let mut service = StreamReceiverService::new(
+            server_secret.clone(),
+            DummyStore,
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> IlpResult {
+                panic!("shouldn't get here")
+            }),
+        )
+        .with_receive_store(TestReceiveStore::default());
+
+        let receipt = |data: &[u8]| {
+            let response =
+                StreamPacket::from_encrypted(&shared_secret, BytesMut::from(data)).unwrap();
+            response.frames().find_map(|frame| match frame {
+                Frame::StreamReceipt(frame) => Some((frame.stream_id, frame.receipt.to_vec())),
+                _ => None,
+            })
+        };
+        for (amount, total_received) in &[(100, 100), (50, 150)] {
+            let data = test_stream_packet().into_encrypted(&shared_secret[..]);
+            let execution_condition = generate_condition(&shared_secret[..], &data);
+            let prepare = PrepareBuilder {
+                destination: destination_account.clone(),
+                amount: *amount,
+                expires_at: UNIX_EPOCH,
+                data: &data[..],
+                execution_condition: &execution_condition,
+            }
+            .build();
+            let fulfill = service
+                .send_request(OutgoingRequest {
+                    from: account.clone(),
+                    to: account.clone(),
+                    original_amount: prepare.amount(),
+                    prepare,
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                receipt(fulfill.data()),
+                Some((1, details.receipt(1, *total_received)))
+            );
+        }
+
+        // The rejected packets do not count towards the totals, nor get receipts
+        let data = test_stream_packet().into_encrypted(&shared_secret[..]);
+        let prepare = PrepareBuilder {
+            destination: destination_account.clone(),
+            amount: 100,
+            expires_at: UNIX_EPOCH,
+            data: &data[..],
+            execution_condition: &[0; 32],
+        }
+        .build();
+        let reject = service
+            .send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account.clone(),
+                original_amount: prepare.amount(),
+                prepare,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(receipt(reject.data()), None);
+        assert_eq!(
+            service
+                .receive_store
+                .as_ref()
+                .unwrap()
+                .get_connection_receipts(account.id, &details.stream_key(1))
+                .await
+                .unwrap()
+                .total_received,
+            150
+        );
+    }
+
     #[tokio::test]
     async fn rejects_invalid_packets() {
         let ilp_address = Address::from_str("example.destination").unwrap();
@@ -690,6 +2084,150 @@ mod stream_receiver_service {
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn reports_connections_whose_keep_alives_stop() {
+        tokio::time::pause();
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let (destination_account, shared_secret) =
+            connection_generator.generate_address_and_secret(&ilp_address);
+        let data = StreamPacketBuilder {
+            ilp_packet_type: IlpPacketType::Prepare,
+            prepare_amount: 0,
+            sequence: 1,
+            frames: &[],
+        }
+        .build()
+        .into_encrypted(&shared_secret[..]);
+
+        let broken = Arc::new(Mutex::new(Vec::new()));
+        let broken_clone = broken.clone();
+        let monitor = KeepAliveMonitor::new(
+            std::time::Duration::from_millis(50),
+            move |connection: &Address| broken_clone.lock().push(connection.clone()),
+        );
+        let mut service = StreamReceiverService::new(
+            server_secret,
+            DummyStore,
+            outgoing_service_fn(|_: OutgoingRequest<TestAccount>| -> IlpResult {
+                panic!("Should not have passed on the keep-alive packet")
+            }),
+        )
+        .with_keep_alive_monitor(monitor.clone());
+
+        let prepare = PrepareBuilder {
+            destination: destination_account.clone(),
+            amount: 0,
+            expires_at: UNIX_EPOCH,
+            data: &data[..],
+            execution_condition: &random_condition(),
+        }
+        .build();
+        let account = TestAccount {
+            id: Uuid::new_v4(),
+            ilp_address: ilp_address.clone(),
+            asset_code: "XYZ".to_string(),
+            asset_scale: 9,
+            max_packet_amount: None,
+        };
+        service
+            .send_request(OutgoingRequest {
+                from: account.clone(),
+                to: account,
+                original_amount: 0,
+                prepare,
+            })
+            .await
+            .unwrap_err();
+
+        monitor.check();
+        assert!(broken.lock().is_empty());
+        tokio::time::delay_for(std::time::Duration::from_millis(60)).await;
+        monitor.check();
+        assert_eq!(*broken.lock(), vec![destination_account]);
+    }
+
+    #[tokio::test]
+    async fn dampens_connections_whose_packets_fail_to_decrypt() {
+        let ilp_address = Address::from_str("example.destination").unwrap();
+        let server_secret = Bytes::from(&[1; 32][..]);
+        let connection_generator = ConnectionGenerator::new(server_secret.clone());
+        let (destination_account, _) =
+            connection_generator.generate_address_and_secret(&ilp_address);
+        // The sender uses the wrong shared secret
+        let data = test_stream_packet().into_encrypted(&[2; 32]);
+
+        let passed_on = Arc::new(Mutex::new(0));
+        let passed_on_clone = passed_on.clone();
+        let mut service = StreamReceiverService::new(
+            server_secret,
+            DummyStore,
+            outgoing_service_fn(move |_: OutgoingRequest<TestAccount>| {
+                *passed_on_clone.lock() += 1;
+                Err(RejectBuilder {
+                    code: ErrorCode::F02_UNREACHABLE,
+                    message: &[],
+                    data: &[],
+                    triggered_by: None,
+                }
+                .build())
+            }),
+        )
+        .with_reject_dampening(DampeningPolicy {
+            max_failures: 2,
+            window: std::time::Duration::from_secs(60),
+        });
+
+        let sender_id = Uuid::new_v4();
+        let other_sender_id = Uuid::new_v4();
+        let mut codes = Vec::new();
+        for &from_id in [sender_id, sender_id, sender_id, sender_id, other_sender_id].iter() {
+            let prepare = PrepareBuilder {
+                destination: destination_account.clone(),
+                amount: 100,
+                expires_at: UNIX_EPOCH,
+                data: &data[..],
+                execution_condition: &[0; 32],
+            }
+            .build();
+            let reject = service
+                .send_request(OutgoingRequest {
+                    from: TestAccount {
+                        id: from_id,
+                        ilp_address: Address::from_str("example.sender").unwrap(),
+                        asset_code: "XYZ".to_string(),
+                        asset_scale: 9,
+                        max_packet_amount: None,
+                    },
+                    to: TestAccount {
+                        id: Uuid::new_v4(),
+                        ilp_address: ilp_address.clone(),
+                        asset_code: "XYZ".to_string(),
+                        asset_scale: 9,
+                        max_packet_amount: None,
+                    },
+                    original_amount: prepare.amount(),
+                    prepare,
+                })
+                .await
+                .unwrap_err();
+            codes.push(reject.code());
+        }
+        assert_eq!(*passed_on.lock(), 3);
+        assert_eq!(
+            codes,
+            vec![
+                ErrorCode::F02_UNREACHABLE,
+                ErrorCode::F02_UNREACHABLE,
+                ErrorCode::F06_UNEXPECTED_PAYMENT,
+                ErrorCode::F06_UNEXPECTED_PAYMENT,
+                // The packets from other accounts are still decrypted
+                ErrorCode::F02_UNREACHABLE,
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn passes_on_packets_not_for_it() {
         let ilp_address = Address::from_str("example.destination").unwrap();
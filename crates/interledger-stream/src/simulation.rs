@@ -0,0 +1,456 @@
+//! A deterministic harness for evaluating the [congestion controller](../congestion/struct.CongestionController.html)
+//! against simulated network paths, without sending any real packets.
+//!
+//! The path is modeled as a buffer which drains at a fixed bandwidth (the rate at which
+//! the path's liquidity frees up): packets which would overflow the buffer are rejected
+//! with `T04: Insufficient Liquidity`, accepted packets are fulfilled once they have been
+//! drained, and a fraction of the packets may be lost at random. Time is simulated, so a
+//! run takes microseconds and always produces the same trace for the same seed.
+//!
+//! ```ignore
+//! let result = Simulation::new(ControllerConfig::default(), PathModel::default()).run(1_000_000);
+//! println!("{}", result.to_csv());
+//! ```
+
+use crate::congestion::CongestionController;
+use interledger_packet::{ErrorCode, MaxPacketAmountDetails, Reject, RejectBuilder};
+use std::cmp::{min, Reverse};
+use std::collections::BinaryHeap;
+use std::fmt::Write;
+
+/// The parameters the congestion controller is constructed with
+#[derive(Debug, Clone, Copy)]
+pub struct ControllerConfig {
+    pub start_amount: u64,
+    pub increase_amount: u64,
+    pub decrease_factor: f64,
+}
+
+impl Default for ControllerConfig {
+    /// The parameters used by the STREAM client
+    fn default() -> Self {
+        ControllerConfig {
+            start_amount: 1000,
+            increase_amount: 1000,
+            decrease_factor: 2.0,
+        }
+    }
+}
+
+/// The model of the network path packets are sent over
+#[derive(Debug, Clone, Copy)]
+pub struct PathModel {
+    /// Amount the path can forward per second
+    pub bandwidth: u64,
+    /// Maximum amount which can be queued on the path. Packets which would exceed it are
+    /// rejected with `T04: Insufficient Liquidity`
+    pub buffer_size: u64,
+    /// Round trip time of a packet when the buffer is empty, in milliseconds
+    pub latency: u64,
+    /// Fraction (between 0 and 1) of packets which are lost and rejected with
+    /// `R00: Transfer Timed Out` after `loss_timeout` milliseconds
+    pub loss_rate: f64,
+    /// Time after which lost packets are rejected, in milliseconds
+    pub loss_timeout: u64,
+    /// Packets larger than this are rejected with `F08: Amount Too Large`
+    pub max_packet_amount: Option<u64>,
+    /// Seed of the random number generator used to pick the lost packets
+    pub seed: u64,
+}
+
+impl Default for PathModel {
+    fn default() -> Self {
+        PathModel {
+            bandwidth: 100_000,
+            buffer_size: 50_000,
+            latency: 100,
+            loss_rate: 0.0,
+            loss_timeout: 30_000,
+            max_packet_amount: None,
+            seed: 1,
+        }
+    }
+}
+
+/// How a simulated packet was resolved
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Fulfilled,
+    Rejected(ErrorCode),
+}
+
+/// One resolved packet in the trace of a simulation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceEvent {
+    /// Simulated time at which the packet was resolved, in milliseconds
+    pub time: u64,
+    pub amount: u64,
+    pub outcome: Outcome,
+    /// Time between sending and resolving the packet, in milliseconds
+    pub latency: u64,
+    /// Amount in flight after the packet was resolved
+    pub amount_in_flight: u64,
+    /// The controller's window (the maximum amount in flight) after the packet was resolved
+    pub window: u64,
+    /// Total amount fulfilled so far
+    pub delivered: u64,
+}
+
+/// The trace and totals of a simulation run
+#[derive(Debug, Clone)]
+pub struct SimulationResult {
+    pub trace: Vec<TraceEvent>,
+    pub delivered: u64,
+    /// Simulated time when the last packet was resolved, in milliseconds
+    pub duration: u64,
+    pub packets_fulfilled: usize,
+    pub packets_rejected: usize,
+}
+
+impl SimulationResult {
+    /// Average amount delivered per second
+    pub fn throughput(&self) -> f64 {
+        if self.duration == 0 {
+            0.0
+        } else {
+            self.delivered as f64 * 1000.0 / self.duration as f64
+        }
+    }
+
+    /// Average round trip time of the fulfilled packets, in milliseconds
+    pub fn average_latency(&self) -> f64 {
+        let latencies: Vec<u64> = self
+            .trace
+            .iter()
+            .filter(|event| event.outcome == Outcome::Fulfilled)
+            .map(|event| event.latency)
+            .collect();
+        if latencies.is_empty() {
+            0.0
+        } else {
+            latencies.iter().sum::<u64>() as f64 / latencies.len() as f64
+        }
+    }
+
+    /// Formats the trace as CSV, for plotting or comparing runs
+    pub fn to_csv(&self) -> String {
+        let mut csv =
+            String::from("time,amount,outcome,latency,amount_in_flight,window,delivered\n");
+        for event in self.trace.iter() {
+            let outcome = match event.outcome {
+                Outcome::Fulfilled => "fulfilled".to_string(),
+                Outcome::Rejected(code) => code.to_string(),
+            };
+            writeln!(
+                csv,
+                "{},{},{},{},{},{},{}",
+                event.time,
+                event.amount,
+                outcome,
+                event.latency,
+                event.amount_in_flight,
+                event.window,
+                event.delivered
+            )
+            .expect("Writing to a String cannot fail");
+        }
+        csv
+    }
+}
+
+/// A packet in flight, ordered by the time it is resolved (and then the order it was sent)
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct InFlight {
+    resolved_at: u64,
+    sequence: u64,
+    sent_at: u64,
+    amount: u64,
+    /// `None` if the packet is fulfilled
+    reject_code: Option<[u8; 3]>,
+}
+
+/// Runs the congestion controller against a simulated path
+pub struct Simulation {
+    controller: CongestionController,
+    path: PathModel,
+    /// Simulated time, in milliseconds
+    now: u64,
+    /// Amount queued on the path as of `queue_updated_at`
+    queued: u64,
+    queue_updated_at: u64,
+    rng_state: u64,
+    /// Maximum simulated time before the run is stopped, in milliseconds
+    time_limit: u64,
+}
+
+impl Simulation {
+    pub fn new(controller: ControllerConfig, path: PathModel) -> Self {
+        Simulation {
+            controller: CongestionController::new(
+                controller.start_amount,
+                controller.increase_amount,
+                controller.decrease_factor,
+            ),
+            path,
+            now: 0,
+            queued: 0,
+            queue_updated_at: 0,
+            // xorshift must not be seeded with 0
+            rng_state: path.seed.max(1),
+            time_limit: 3_600_000,
+        }
+    }
+
+    /// Sets the maximum simulated time (in milliseconds) before the run is stopped,
+    /// even if the whole amount was not delivered. Defaults to one hour
+    pub fn time_limit(mut self, time_limit: u64) -> Self {
+        self.time_limit = time_limit;
+        self
+    }
+
+    /// Sends `total_amount` over the path, as fast as the congestion controller allows
+    pub fn run(mut self, total_amount: u64) -> SimulationResult {
+        let mut in_flight: BinaryHeap<Reverse<InFlight>> = BinaryHeap::new();
+        let mut amount_in_flight = 0;
+        let mut delivered = 0;
+        let mut sequence = 0;
+        let mut result = SimulationResult {
+            trace: Vec::new(),
+            delivered: 0,
+            duration: 0,
+            packets_fulfilled: 0,
+            packets_rejected: 0,
+        };
+
+        loop {
+            // Send as much as the window allows at the current time
+            loop {
+                let remaining = total_amount - delivered - amount_in_flight;
+                let amount = min(
+                    min(remaining, self.controller.get_amount_left_in_window()),
+                    self.controller.get_max_packet_amount(),
+                );
+                if amount == 0 {
+                    break;
+                }
+                self.controller.prepare(amount);
+                amount_in_flight += amount;
+                sequence += 1;
+                in_flight.push(Reverse(self.send(amount, sequence)));
+            }
+
+            // Resolve the next packet
+            let packet = match in_flight.pop() {
+                Some(Reverse(packet)) if packet.resolved_at <= self.time_limit => packet,
+                _ => break,
+            };
+            self.now = packet.resolved_at;
+            amount_in_flight -= packet.amount;
+            let outcome = match packet.reject_code {
+                None => {
+                    self.controller.fulfill(packet.amount);
+                    delivered += packet.amount;
+                    result.packets_fulfilled += 1;
+                    Outcome::Fulfilled
+                }
+                Some(code) => {
+                    let reject = self.reject(code, packet.amount);
+                    self.controller.reject(packet.amount, &reject);
+                    result.packets_rejected += 1;
+                    Outcome::Rejected(reject.code())
+                }
+            };
+            result.trace.push(TraceEvent {
+                time: self.now,
+                amount: packet.amount,
+                outcome,
+                latency: packet.resolved_at - packet.sent_at,
+                amount_in_flight,
+                window: self.controller.get_amount_left_in_window() + amount_in_flight,
+                delivered,
+            });
+        }
+
+        result.delivered = delivered;
+        result.duration = self.now;
+        result
+    }
+
+    /// Determines how the path handles a packet sent now
+    fn send(&mut self, amount: u64, sequence: u64) -> InFlight {
+        let mut packet = InFlight {
+            resolved_at: self.now + self.path.latency,
+            sequence,
+            sent_at: self.now,
+            amount,
+            reject_code: None,
+        };
+
+        if amount > self.path.max_packet_amount.unwrap_or(u64::MAX) {
+            packet.reject_code = Some(ErrorCode::F08_AMOUNT_TOO_LARGE.into());
+            return packet;
+        }
+        if self.random() < self.path.loss_rate {
+            packet.resolved_at = self.now + self.path.loss_timeout;
+            packet.reject_code = Some(ErrorCode::R00_TRANSFER_TIMED_OUT.into());
+            return packet;
+        }
+
+        // Drain the buffer for the time elapsed since it was last updated
+        let drained = (self.now - self.queue_updated_at).saturating_mul(self.path.bandwidth) / 1000;
+        self.queued = self.queued.saturating_sub(drained);
+        self.queue_updated_at = self.now;
+
+        if self.queued + amount > self.path.buffer_size {
+            packet.reject_code = Some(ErrorCode::T04_INSUFFICIENT_LIQUIDITY.into());
+        } else {
+            self.queued += amount;
+            // The packet is fulfilled once everything queued before it has been drained
+            packet.resolved_at += self.queued.saturating_mul(1000) / self.path.bandwidth.max(1);
+        }
+        packet
+    }
+
+    fn reject(&self, code: [u8; 3], amount: u64) -> Reject {
+        let code = ErrorCode::new(code).expect("Simulated reject codes are valid");
+        let data = match (code, self.path.max_packet_amount) {
+            (ErrorCode::F08_AMOUNT_TOO_LARGE, Some(max_amount)) => {
+                MaxPacketAmountDetails::new(amount, max_amount)
+                    .to_bytes()
+                    .to_vec()
+            }
+            _ => Vec::new(),
+        };
+        RejectBuilder {
+            code,
+            message: &[],
+            triggered_by: None,
+            data: &data,
+        }
+        .build()
+    }
+
+    /// Returns a pseudo-random number between 0 and 1 (xorshift64*)
+    fn random(&mut self) -> f64 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        let value = self.rng_state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (value >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simulate(path: PathModel, amount: u64) -> SimulationResult {
+        Simulation::new(ControllerConfig::default(), path).run(amount)
+    }
+
+    #[test]
+    fn delivers_everything_over_uncongested_path() {
+        let result = simulate(
+            PathModel {
+                buffer_size: u64::MAX,
+                ..Default::default()
+            },
+            100_000,
+        );
+        assert_eq!(result.delivered, 100_000);
+        assert_eq!(result.packets_rejected, 0);
+        assert_eq!(result.trace.last().unwrap().delivered, 100_000);
+    }
+
+    #[test]
+    fn is_deterministic() {
+        let path = PathModel {
+            loss_rate: 0.1,
+            loss_timeout: 1000,
+            seed: 42,
+            ..Default::default()
+        };
+        let first = simulate(path, 1_000_000);
+        let second = simulate(path, 1_000_000);
+        assert_eq!(first.trace, second.trace);
+        assert_eq!(first.to_csv(), second.to_csv());
+    }
+
+    #[test]
+    fn backs_off_when_the_buffer_is_full() {
+        let path = PathModel::default();
+        let result = simulate(path, 2_000_000);
+        assert_eq!(result.delivered, 2_000_000);
+        assert!(
+            result
+                .trace
+                .iter()
+                .any(|event| event.outcome
+                    == Outcome::Rejected(ErrorCode::T04_INSUFFICIENT_LIQUIDITY))
+        );
+
+        // The path can't deliver faster than its bandwidth, but the controller
+        // should keep it reasonably busy
+        let throughput = result.throughput();
+        assert!(throughput <= path.bandwidth as f64);
+        assert!(throughput > path.bandwidth as f64 / 4.0, "{}", throughput);
+        // Queueing delay is bounded by the buffer
+        assert!(
+            result.average_latency()
+                <= (path.latency + path.buffer_size * 1000 / path.bandwidth) as f64
+        );
+    }
+
+    #[test]
+    fn keeps_sending_despite_random_loss() {
+        let result = simulate(
+            PathModel {
+                loss_rate: 0.2,
+                loss_timeout: 500,
+                ..Default::default()
+            },
+            500_000,
+        );
+        assert_eq!(result.delivered, 500_000);
+        assert!(result
+            .trace
+            .iter()
+            .any(|event| event.outcome == Outcome::Rejected(ErrorCode::R00_TRANSFER_TIMED_OUT)));
+    }
+
+    #[test]
+    fn learns_max_packet_amount() {
+        let result = simulate(
+            PathModel {
+                max_packet_amount: Some(700),
+                ..Default::default()
+            },
+            100_000,
+        );
+        assert_eq!(result.delivered, 100_000);
+        assert!(result
+            .trace
+            .iter()
+            .filter(|event| event.outcome == Outcome::Fulfilled)
+            .all(|event| event.amount <= 700));
+    }
+
+    #[test]
+    fn stops_at_time_limit() {
+        let result = Simulation::new(
+            ControllerConfig::default(),
+            PathModel {
+                bandwidth: 1000,
+                ..Default::default()
+            },
+        )
+        .time_limit(10_000)
+        .run(u64::MAX / 2);
+        assert!(result.duration <= 10_000);
+        assert!(result.delivered < u64::MAX / 2);
+
+        let csv = result.to_csv();
+        assert!(csv.starts_with("time,amount,outcome,"));
+        assert_eq!(csv.lines().count(), result.trace.len() + 1);
+    }
+}
@@ -0,0 +1,198 @@
+use super::client::{
+    send_until_complete, MoneyStream, SendMoneyOptions, StreamDelivery, StreamPayment, StreamSender,
+};
+use super::error::Error;
+use bytes::Bytes;
+use futures::channel::oneshot;
+use futures::future::{select, Either};
+use interledger_packet::Address;
+use interledger_rates::ExchangeRateStore;
+use interledger_service::*;
+use std::cmp::{max, min};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{delay_until, Duration, Instant};
+use tracing::debug;
+
+/// Interval at which the amount owed at the target rate is sent, if not configured otherwise
+pub const DEFAULT_STREAMING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Handle to a payment which streams money to a receiver at a target rate, until it is
+/// stopped, rather than sending a fixed amount (like [Web Monetization](https://webmonetization.org/)).
+///
+/// The receipts it publishes account for the amount owed so far as the `source_amount`.
+/// Dropping the handle stops the payment like [`stop`](#method.stop), in the background.
+pub struct StreamingPayment {
+    rate: Arc<AtomicU64>,
+    receipts: watch::Receiver<StreamDelivery>,
+    stop: Option<oneshot::Sender<()>>,
+    task: Option<JoinHandle<Result<StreamDelivery, Error>>>,
+}
+
+impl StreamingPayment {
+    /// The target rate, in source units per second
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// Changes the target rate from the next interval on. A rate of zero pauses the
+    /// payment while keeping the connection open
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// The receipt of the payment as of the last interval
+    pub fn receipt(&self) -> StreamDelivery {
+        self.receipts.borrow().clone()
+    }
+
+    /// Subscribes to the receipts published after each interval in which money was sent
+    pub fn receipts(&self) -> watch::Receiver<StreamDelivery> {
+        self.receipts.clone()
+    }
+
+    /// Stops sending money, waiting for the packets in flight before closing the connection.
+    /// Returns the final receipt, or the error which terminated the payment early
+    pub async fn stop(mut self) -> Result<StreamDelivery, Error> {
+        if let Some(stop) = self.stop.take() {
+            stop.send(()).ok();
+        }
+        self.task
+            .take()
+            .expect("The task is only taken when stopping")
+            .await
+            .expect("Streaming payment task panicked")
+    }
+}
+
+impl Drop for StreamingPayment {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            stop.send(()).ok();
+        }
+    }
+}
+
+/// Start streaming money to the receiver at the target `rate`, in source units per second.
+/// Every `interval`, the amount owed since the last one is sent over the connection, paced
+/// by the congestion controller like the packets of [`send_money`](./fn.send_money.html).
+///
+/// A fixed `destination_amount` in the options is ignored, since the payment has no end,
+/// and the money is sent over a single stream whatever the `streams` of the options.
+#[allow(clippy::too_many_arguments)]
+pub fn stream_money<I, A, S>(
+    service: I,
+    from_account: &A,
+    store: S,
+    destination_account: Address,
+    shared_secret: Vec<u8>,
+    rate: u64,
+    interval: Duration,
+    options: SendMoneyOptions,
+) -> StreamingPayment
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
+    let abort_on_asset_details_change = options.abort_on_asset_details_change;
+    let interval_amount = rate.saturating_mul(interval.as_millis() as u64) / 1000;
+    let payment = StreamPayment::new(
+        from_account,
+        destination_account,
+        0,
+        max(interval_amount, 1),
+        SendMoneyOptions {
+            destination_amount: None,
+            ..options
+        },
+    );
+    let (receipts_sender, receipts) = watch::channel(payment.receipt().clone());
+    let sender = StreamSender::new(
+        service,
+        from_account.clone(),
+        Bytes::from(shared_secret),
+        store,
+        options.slippage,
+        vec![MoneyStream {
+            stream_id: 1,
+            shares: 1,
+        }],
+        payment,
+    );
+
+    let rate = Arc::new(AtomicU64::new(rate));
+    let (stop, stopped) = oneshot::channel();
+    let task = tokio::spawn(stream_at_rate(
+        sender,
+        abort_on_asset_details_change,
+        rate.clone(),
+        interval,
+        receipts_sender,
+        stopped,
+    ));
+    StreamingPayment {
+        rate,
+        receipts,
+        stop: Some(stop),
+        task: Some(task),
+    }
+}
+
+async fn stream_at_rate<I, A, S>(
+    mut sender: StreamSender<I, A, S>,
+    abort_on_asset_details_change: bool,
+    rate: Arc<AtomicU64>,
+    interval: Duration,
+    receipts: watch::Sender<StreamDelivery>,
+    mut stopped: oneshot::Receiver<()>,
+) -> Result<StreamDelivery, Error>
+where
+    I: IncomingService<A> + Clone + Send + Sync + 'static,
+    A: Account + Send + Sync + 'static,
+    S: ExchangeRateStore + Send + Sync + 'static,
+{
+    let mut last_tick = Instant::now();
+    // Millionths of a source unit owed, which are carried over to the next interval
+    let mut carry: u128 = 0;
+    loop {
+        // The handle being dropped stops the payment too
+        if let Either::Right(_) = select(delay_until(last_tick + interval), &mut stopped).await {
+            break;
+        }
+
+        let now = Instant::now();
+        let owed = u128::from(rate.load(Ordering::Relaxed))
+            * now.duration_since(last_tick).as_micros()
+            + carry;
+        last_tick = now;
+        carry = owed % 1_000_000;
+        let amount = min(owed / 1_000_000, u128::from(std::u64::MAX)) as u64;
+        if amount == 0 {
+            continue;
+        }
+
+        sender.payment().lock().await.add_source_amount(amount);
+        let result = send_until_complete(&mut sender, abort_on_asset_details_change).await;
+        let receipt = sender.payment().lock().await.receipt().clone();
+        debug!(
+            "Streamed {} more units (delivered {} in total)",
+            amount, receipt.delivered_amount
+        );
+        receipts.broadcast(receipt).ok();
+        result?;
+    }
+
+    sender.try_send_connection_close().await;
+    let receipt = sender.payment().lock().await.receipt().clone();
+    debug!(
+        "Stopped streaming money. Delivered: {}",
+        receipt.delivered_amount
+    );
+    receipts.broadcast(receipt.clone()).ok();
+    Ok(receipt)
+}